@@ -68,6 +68,7 @@ let
 
   buildPackageEnv = {
     GRAPEVINE_VERSION_EXTRA = inputs.self.shortRev or inputs.self.dirtyShortRev;
+    GRAPEVINE_GIT_COMMIT = inputs.self.rev or inputs.self.dirtyRev;
   } // buildDepsOnlyEnv;
 
   commonAttrs = {
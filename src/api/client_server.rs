@@ -1,9 +1,11 @@
 mod account;
 mod alias;
+mod appservice;
 mod backup;
 mod capabilities;
 mod config;
 mod context;
+mod dehydrated_device;
 mod device;
 mod directory;
 mod filter;
@@ -11,6 +13,8 @@ mod keys;
 mod media;
 mod membership;
 mod message;
+mod openid;
+mod presence;
 mod profile;
 mod push;
 mod read_marker;
@@ -31,13 +35,16 @@ mod typing;
 mod unversioned;
 mod user_directory;
 mod voip;
+mod well_known;
 
 pub(crate) use account::*;
 pub(crate) use alias::*;
+pub(crate) use appservice::*;
 pub(crate) use backup::*;
 pub(crate) use capabilities::*;
 pub(crate) use config::*;
 pub(crate) use context::*;
+pub(crate) use dehydrated_device::*;
 pub(crate) use device::*;
 pub(crate) use directory::*;
 pub(crate) use filter::*;
@@ -45,6 +52,8 @@ pub(crate) use keys::*;
 pub(crate) use media::*;
 pub(crate) use membership::*;
 pub(crate) use message::*;
+pub(crate) use openid::*;
+pub(crate) use presence::*;
 pub(crate) use profile::*;
 pub(crate) use push::*;
 pub(crate) use read_marker::*;
@@ -65,8 +74,10 @@ pub(crate) use typing::*;
 pub(crate) use unversioned::*;
 pub(crate) use user_directory::*;
 pub(crate) use voip::*;
+pub(crate) use well_known::*;
 
 pub(crate) const DEVICE_ID_LENGTH: usize = 10;
 pub(crate) const TOKEN_LENGTH: usize = 32;
 pub(crate) const SESSION_ID_LENGTH: usize = 32;
 pub(crate) const AUTO_GEN_PASSWORD_LENGTH: usize = 15;
+pub(crate) const MXC_LENGTH: usize = 32;
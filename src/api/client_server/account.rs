@@ -1,8 +1,18 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use axum::Json;
+use hmac::{Hmac, Mac};
+use lettre::message::Mailbox;
 use register::RegistrationKind;
 use ruma::{
     api::client::{
         account::{
-            change_password, deactivate, get_3pids, get_username_availability,
+            add_3pid, change_password, deactivate, delete_3pid, get_3pids,
+            get_username_availability,
             register::{self, LoginType},
             request_3pid_management_token_via_email,
             request_3pid_management_token_via_msisdn, whoami,
@@ -12,16 +22,103 @@ use ruma::{
         uiaa::{AuthFlow, AuthType, UiaaInfo},
     },
     events::{
-        room::message::RoomMessageEventContent, GlobalAccountDataEventType,
+        room::{
+            message::RoomMessageEventContent,
+            redaction::RoomRedactionEventContent,
+        },
+        GlobalAccountDataEventType, TimelineEventType,
     },
-    push, UserId,
+    thirdparty::Medium,
+    OwnedClientSecret, OwnedDeviceId, OwnedServerName, OwnedSessionId,
+    OwnedUserId, UserId,
 };
+use serde::{Deserialize, Serialize};
+use serde_json::{json, value::to_raw_value};
+use sha1::Sha1;
 use tracing::{info, warn};
 
-use super::{DEVICE_ID_LENGTH, SESSION_ID_LENGTH, TOKEN_LENGTH};
-use crate::{api::client_server, services, utils, Ar, Error, Ra, Result};
+use super::{
+    session::issue_refresh_token, DEVICE_ID_LENGTH, SESSION_ID_LENGTH,
+    TOKEN_LENGTH,
+};
+use crate::{
+    api::client_server,
+    service::{pdu::PduBuilder, rooms::timeline::PduCount},
+    services, utils, Ar, Error, Ra, Result,
+};
 
 const RANDOM_USER_ID_LENGTH: usize = 10;
+const NONCE_LENGTH: usize = 32;
+
+/// How long a nonce for shared-secret registration can be used
+const NONCE_LIFETIME: Duration = Duration::from_secs(60);
+
+/// Most nonces for shared-secret registration that are remembered at the same
+/// time
+const MAX_NONCES: usize = 1000;
+
+/// Number of events of a room that are looked at at once when redacting the
+/// messages of an erased user
+const ERASURE_BATCH_SIZE: usize = 100;
+
+type HmacSha1 = Hmac<Sha1>;
+
+/// Response of `GET /_synapse/admin/v1/register`
+#[derive(Serialize)]
+pub(crate) struct SharedSecretNonce {
+    /// Nonce to include in the MAC of the registration request
+    nonce: String,
+}
+
+/// Request body of `POST /_synapse/admin/v1/register`
+#[derive(Deserialize)]
+pub(crate) struct SharedSecretRegistration {
+    /// A nonce from `GET /_synapse/admin/v1/register`
+    nonce: String,
+    /// Localpart of the new user
+    username: String,
+    /// Password of the new user
+    password: String,
+    /// Display name of the new user, defaults to the localpart
+    displayname: Option<String>,
+    /// Whether the new user is made an admin
+    #[serde(default)]
+    admin: bool,
+    /// Hex encoded HMAC-SHA1 of the other fields, keyed with the shared secret
+    mac: String,
+}
+
+/// Request body of `POST
+/// /_matrix/client/unstable/add_threepid/email/submit_token`
+#[derive(Deserialize)]
+pub(crate) struct SubmitThreepidToken {
+    /// ID of the validation session
+    sid: OwnedSessionId,
+    /// Client secret the session was created with
+    client_secret: OwnedClientSecret,
+    /// The token that was sent to the address
+    token: String,
+}
+
+/// Response of `POST /_matrix/client/unstable/add_threepid/email/submit_token`
+#[derive(Serialize)]
+pub(crate) struct ThreepidTokenSubmitted {
+    /// Always true, failures are returned as errors
+    success: bool,
+}
+
+/// Response of `POST /_synapse/admin/v1/register`
+#[derive(Serialize)]
+pub(crate) struct SharedSecretRegistered {
+    /// Access token of the new device
+    access_token: String,
+    /// ID of the new user
+    user_id: OwnedUserId,
+    /// Name of this server
+    home_server: OwnedServerName,
+    /// ID of the new device
+    device_id: OwnedDeviceId,
+}
 
 /// # `GET /_matrix/client/r0/register/available`
 ///
@@ -79,9 +176,13 @@ pub(crate) async fn get_register_available_route(
 /// - Only works if registration is enabled
 /// - If type is guest: ignores all parameters except
 ///   `initial_device_display_name`
-/// - If sender is not appservice: Requires UIAA (but we only use a dummy stage)
+/// - If sender is not appservice: Requires UIAA with the CAPTCHA and
+///   registration token stages that are configured, or a dummy stage if none
+///   are
 /// - If type is not guest and no username is given: Always fails after UIAA
 ///   check
+/// - Fails with `M_WEAK_PASSWORD` if the password doesn't meet
+///   `password_policy`, unless the sender is an appservice
 /// - Creates a new account and populates it with default account data
 /// - If `inhibit_login` is false: Creates a device and returns `device_id` and
 ///   `access_token`
@@ -156,47 +257,61 @@ pub(crate) async fn register_route(
         ));
     }
 
+    if let (Some(password), false, None) =
+        (&body.password, is_guest, &body.appservice_info)
+    {
+        check_password_policy(password)?;
+    }
+
     // UIAA
-    let mut uiaainfo;
-    let skip_auth = if services().globals.config.registration_token.is_some() {
-        // Registration token required
-        uiaainfo = UiaaInfo {
-            flows: vec![AuthFlow {
-                stages: vec![AuthType::RegistrationToken],
-            }],
-            completed: Vec::new(),
-            params: Box::default(),
-            session: None,
-            auth_error: None,
-        };
-        body.appservice_info.is_some()
-    } else {
-        // No registration token necessary, but clients must still go through
-        // the flow
-        uiaainfo = UiaaInfo {
-            flows: vec![AuthFlow {
-                stages: vec![AuthType::Dummy],
-            }],
-            completed: Vec::new(),
-            params: Box::default(),
-            session: None,
-            auth_error: None,
-        };
+    let mut stages = Vec::new();
+    let mut params = Box::default();
+    if let Some(captcha) = &services().globals.config.captcha {
+        stages.push(AuthType::ReCaptcha);
+        params = to_raw_value(&json!({
+            "m.login.recaptcha": {
+                "public_key": captcha.site_key,
+            },
+        }))
+        .expect("UIAA params are valid JSON");
+    }
+    if services().globals.config.registration_token.is_some() {
+        stages.push(AuthType::RegistrationToken);
+    }
+    let skip_auth = if stages.is_empty() {
+        // No CAPTCHA or registration token necessary, but clients must still
+        // go through the flow
+        stages.push(AuthType::Dummy);
         body.appservice_info.is_some() || is_guest
+    } else {
+        body.appservice_info.is_some()
+    };
+
+    let mut uiaainfo = UiaaInfo {
+        flows: vec![AuthFlow {
+            stages,
+        }],
+        completed: Vec::new(),
+        params,
+        session: None,
+        auth_error: None,
     };
 
     if !skip_auth {
         if let Some(auth) = &body.auth {
-            let (worked, uiaainfo) = services().uiaa.try_auth(
-                &UserId::parse_with_server_name(
-                    "",
-                    services().globals.server_name(),
+            let (worked, uiaainfo) = services()
+                .uiaa
+                .try_auth(
+                    &UserId::parse_with_server_name(
+                        "",
+                        services().globals.server_name(),
+                    )
+                    .expect("we know this is valid"),
+                    "".into(),
+                    auth,
+                    &uiaainfo,
                 )
-                .expect("we know this is valid"),
-                "".into(),
-                auth,
-                &uiaainfo,
-            )?;
+                .await?;
             if !worked {
                 return Err(Error::Uiaa(uiaainfo));
             }
@@ -240,7 +355,7 @@ pub(crate) async fn register_route(
         GlobalAccountDataEventType::PushRules.to_string().into(),
         &serde_json::to_value(ruma::events::push_rules::PushRulesEvent {
             content: ruma::events::push_rules::PushRulesEventContent {
-                global: push::Ruleset::server_default(&user_id),
+                global: services().globals.default_push_rules(&user_id),
             },
         })
         .expect("to json always works"),
@@ -272,10 +387,18 @@ pub(crate) async fn register_route(
     services().users.create_device(
         &user_id,
         &device_id,
-        &token,
+        Some(&token),
         body.initial_device_display_name.clone(),
     )?;
 
+    let (refresh_token, expires_in) = if body.refresh_token {
+        let (refresh_token, expires_in) =
+            issue_refresh_token(&user_id, &device_id)?;
+        (Some(refresh_token), Some(expires_in))
+    } else {
+        (None, None)
+    };
+
     info!(%user_id, "New user registered on this server");
     if body.appservice_info.is_none() && !is_guest {
         services().admin.send_message(RoomMessageEventContent::notice_plain(
@@ -304,16 +427,196 @@ pub(crate) async fn register_route(
         access_token: Some(token),
         user_id,
         device_id: Some(device_id),
-        refresh_token: None,
-        expires_in: None,
+        refresh_token,
+        expires_in,
     }))
 }
 
+/// # `GET /_synapse/admin/v1/register`
+///
+/// Issues a nonce for shared-secret registration.
+///
+/// - Nonces can be used once within a minute
+pub(crate) async fn get_shared_secret_nonce_route() -> Json<SharedSecretNonce> {
+    let nonce = utils::random_string(NONCE_LENGTH);
+
+    remember_nonce(
+        &mut services().globals.registration_nonces.lock().unwrap(),
+        nonce.clone(),
+        Instant::now(),
+    );
+
+    Json(SharedSecretNonce {
+        nonce,
+    })
+}
+
+/// Remembers a newly issued nonce, forgetting expired ones and, if there are
+/// still too many, the oldest one
+fn remember_nonce(
+    nonces: &mut HashMap<String, Instant>,
+    nonce: String,
+    now: Instant,
+) {
+    nonces.retain(|_, issued| now.duration_since(*issued) < NONCE_LIFETIME);
+
+    if nonces.len() >= MAX_NONCES {
+        let oldest = nonces
+            .iter()
+            .min_by_key(|(_, issued)| **issued)
+            .map(|(nonce, _)| nonce.clone());
+        if let Some(oldest) = oldest {
+            nonces.remove(&oldest);
+        }
+    }
+
+    nonces.insert(nonce, now);
+}
+
+/// # `POST /_synapse/admin/v1/register`
+///
+/// Registers a user without UIAA, authenticated with the shared secret from
+/// the config.
+///
+/// - The MAC covers the nonce, username, password and admin flag, separated by
+///   NUL bytes
+/// - Nonces are consumed even if the request fails
+pub(crate) async fn register_shared_secret_route(
+    Json(body): Json<SharedSecretRegistration>,
+) -> Result<Json<SharedSecretRegistered>> {
+    let Some(secret) = &services().globals.config.registration_shared_secret
+    else {
+        return Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "Shared-secret registration is disabled.",
+        ));
+    };
+
+    let nonce_issued = services()
+        .globals
+        .registration_nonces
+        .lock()
+        .unwrap()
+        .remove(&body.nonce);
+    if !nonce_issued.is_some_and(|issued| issued.elapsed() < NONCE_LIFETIME) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Unknown or expired nonce.",
+        ));
+    }
+
+    let mut mac = HmacSha1::new_from_slice(secret.as_bytes())
+        .expect("HMAC can take key of any size");
+    mac.update(body.nonce.as_bytes());
+    mac.update(b"\0");
+    mac.update(body.username.as_bytes());
+    mac.update(b"\0");
+    mac.update(body.password.as_bytes());
+    mac.update(b"\0");
+    mac.update(if body.admin {
+        b"admin".as_slice()
+    } else {
+        b"notadmin".as_slice()
+    });
+
+    let expected = decode_hex(&body.mac).ok_or(Error::BadRequest(
+        ErrorKind::InvalidParam,
+        "MAC is not valid hex.",
+    ))?;
+    if mac.verify_slice(&expected).is_err() {
+        return Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "MAC is invalid.",
+        ));
+    }
+
+    let user_id = UserId::parse_with_server_name(
+        body.username.to_lowercase(),
+        services().globals.server_name(),
+    )
+    .ok()
+    .filter(|user_id| {
+        !user_id.is_historical()
+            && user_id.server_name() == services().globals.server_name()
+    })
+    .ok_or(Error::BadRequest(
+        ErrorKind::InvalidUsername,
+        "Username is invalid.",
+    ))?;
+    if services().users.exists(&user_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::UserInUse,
+            "Desired user ID is already taken.",
+        ));
+    }
+
+    services().users.create(&user_id, Some(&body.password))?;
+
+    let displayname =
+        body.displayname.unwrap_or_else(|| user_id.localpart().to_owned());
+    services().users.set_displayname(&user_id, Some(displayname.clone()))?;
+
+    services().account_data.update(
+        None,
+        &user_id,
+        GlobalAccountDataEventType::PushRules.to_string().into(),
+        &serde_json::to_value(ruma::events::push_rules::PushRulesEvent {
+            content: ruma::events::push_rules::PushRulesEventContent {
+                global: services().globals.default_push_rules(&user_id),
+            },
+        })
+        .expect("to json always works"),
+    )?;
+
+    let device_id: OwnedDeviceId =
+        utils::random_string(DEVICE_ID_LENGTH).into();
+    let token = utils::random_string(TOKEN_LENGTH);
+    services().users.create_device(&user_id, &device_id, Some(&token), None)?;
+
+    info!(
+        %user_id,
+        admin = body.admin,
+        "New user registered via shared secret"
+    );
+    services().admin.send_message(RoomMessageEventContent::notice_plain(
+        format!("New user {user_id} registered on this server."),
+    ));
+
+    if body.admin {
+        services().admin.make_user_admin(&user_id, displayname).await?;
+    }
+
+    Ok(Json(SharedSecretRegistered {
+        access_token: token,
+        user_id,
+        home_server: services().globals.server_name().to_owned(),
+        device_id,
+    }))
+}
+
+/// Decodes a hex string, returning `None` if it is invalid
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+        })
+        .collect()
+}
+
 /// # `POST /_matrix/client/r0/account/password`
 ///
 /// Changes the password of this account.
 ///
 /// - Requires UIAA to verify user password
+/// - Fails with `M_WEAK_PASSWORD` if the new password doesn't meet
+///   `password_policy`
 /// - Changes the password of the sender user
 /// - The password hash is calculated using argon2 with 32 character salt, the
 ///   plain password is
@@ -333,6 +636,8 @@ pub(crate) async fn change_password_route(
     let sender_device =
         body.sender_device.as_ref().expect("user is authenticated");
 
+    check_password_policy(&body.new_password)?;
+
     let mut uiaainfo = UiaaInfo {
         flows: vec![AuthFlow {
             stages: vec![AuthType::Password],
@@ -344,12 +649,10 @@ pub(crate) async fn change_password_route(
     };
 
     if let Some(auth) = &body.auth {
-        let (worked, uiaainfo) = services().uiaa.try_auth(
-            sender_user,
-            sender_device,
-            auth,
-            &uiaainfo,
-        )?;
+        let (worked, uiaainfo) = services()
+            .uiaa
+            .try_auth(sender_user, sender_device, auth, &uiaainfo)
+            .await?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
@@ -408,6 +711,8 @@ pub(crate) async fn whoami_route(
 /// Deactivate sender user account.
 ///
 /// - Leaves all rooms and rejects all invitations
+/// - Redacts the user's messages in the background if `erase` is set, and only
+///   leaves the rooms afterwards
 /// - Invalidates all access tokens
 /// - Deletes all device metadata (device id, device display name, last seen ip,
 ///   last seen ts)
@@ -432,12 +737,10 @@ pub(crate) async fn deactivate_route(
     };
 
     if let Some(auth) = &body.auth {
-        let (worked, uiaainfo) = services().uiaa.try_auth(
-            sender_user,
-            sender_device,
-            auth,
-            &uiaainfo,
-        )?;
+        let (worked, uiaainfo) = services()
+            .uiaa
+            .try_auth(sender_user, sender_device, auth, &uiaainfo)
+            .await?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
@@ -450,15 +753,10 @@ pub(crate) async fn deactivate_route(
         return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
     }
 
-    // Make the user leave all rooms before deactivation
-    client_server::leave_all_rooms(sender_user).await?;
-
-    // Remove devices and mark account as deactivated
-    services().users.deactivate_account(sender_user)?;
+    let summary = deactivate_user(sender_user, true, body.erase).await?;
 
-    info!(user_id = %sender_user, "User deactivated their account");
     services().admin.send_message(RoomMessageEventContent::notice_plain(
-        format!("User {sender_user} deactivated their account."),
+        format!("User {sender_user} deactivated their account: {summary}."),
     ));
 
     Ok(Ra(deactivate::v3::Response {
@@ -466,18 +764,226 @@ pub(crate) async fn deactivate_route(
     }))
 }
 
+/// What [`deactivate_user`] cleaned up
+#[derive(Default)]
+pub(crate) struct DeactivationSummary {
+    /// Number of rooms the user left
+    pub(crate) rooms_left: usize,
+    /// Number of devices and their access tokens that were removed
+    pub(crate) devices_removed: usize,
+    /// Whether the user had a displayname, avatar or blurhash
+    pub(crate) profile_cleared: bool,
+    /// Whether the user's messages are being redacted in the background
+    pub(crate) erasing: bool,
+    /// Whether the user leaves their rooms once their messages are redacted
+    pub(crate) leaving_after_erasure: bool,
+}
+
+impl DeactivationSummary {
+    fn is_empty(&self) -> bool {
+        self.rooms_left == 0
+            && self.devices_removed == 0
+            && !self.profile_cleared
+            && !self.erasing
+    }
+}
+
+impl fmt::Display for DeactivationSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.leaving_after_erasure {
+            write!(
+                f,
+                "redacting messages before leaving rooms in the background"
+            )?;
+        } else {
+            write!(f, "left {} room(s)", self.rooms_left)?;
+            if self.erasing {
+                write!(f, ", redacting messages in the background")?;
+            }
+        }
+        write!(
+            f,
+            ", removed {} device(s), {} profile",
+            self.devices_removed,
+            if self.profile_cleared {
+                "cleared"
+            } else {
+                "no"
+            },
+        )
+    }
+}
+
+/// Tears down the account of a local user.
+///
+/// - Redacts the user's messages in the background if `erase` is set
+/// - Leaves all rooms if `leave_rooms` is set, after the messages are redacted
+///   if `erase` is set too
+/// - Removes the profile, all devices and access tokens
+/// - Marks the account as deactivated so that login fails
+///
+/// Every step only touches what is left, so running this again on a
+/// deactivated account does nothing.
+pub(crate) async fn deactivate_user(
+    user_id: &UserId,
+    leave_rooms: bool,
+    erase: bool,
+) -> Result<DeactivationSummary> {
+    let already_deactivated = services().users.is_deactivated(user_id)?;
+    let mut summary = DeactivationSummary::default();
+
+    summary.profile_cleared = services().users.clear_profile(user_id)?;
+
+    if erase {
+        // The redactions can only be sent while the user is still in the rooms
+        erase_in_background(user_id.to_owned(), leave_rooms);
+        summary.erasing = true;
+        summary.leaving_after_erasure = leave_rooms;
+    } else if leave_rooms {
+        summary.rooms_left = client_server::leave_all_rooms(user_id).await?;
+    }
+
+    // Remove devices and mark account as deactivated
+    summary.devices_removed = services().users.deactivate_account(user_id)?;
+    services().rooms.lazy_loading.lazy_load_reset_all(user_id)?;
+
+    if already_deactivated && summary.is_empty() {
+        return Ok(summary);
+    }
+
+    info!(
+        %user_id,
+        rooms_left = summary.rooms_left,
+        devices_removed = summary.devices_removed,
+        profile_cleared = summary.profile_cleared,
+        erasing = summary.erasing,
+        "User deactivated"
+    );
+
+    Ok(summary)
+}
+
+/// Redacts the messages of a user in the background, then makes them leave
+/// all rooms if `leave_rooms` is set.
+fn erase_in_background(user_id: OwnedUserId, leave_rooms: bool) {
+    tokio::spawn(async move {
+        match redact_user_events(&user_id).await {
+            Ok(redacted) => info!(%user_id, redacted, "User erased"),
+            Err(error) => warn!(%user_id, %error, "Failed to erase user"),
+        }
+
+        if leave_rooms {
+            if let Err(error) = client_server::leave_all_rooms(&user_id).await {
+                warn!(%user_id, %error, "Failed to leave rooms after erasure");
+            }
+        }
+    });
+}
+
+/// Redacts all messages a user sent to the rooms they are joined to.
+///
+/// The timeline of each room is read in batches of [`ERASURE_BATCH_SIZE`]
+/// events, and the state lock of the room is only held while the redactions
+/// of one batch are sent.
+///
+/// State events are kept because redacting them would change the state of
+/// the room. Redactions the user isn't permitted to send are skipped.
+async fn redact_user_events(user_id: &UserId) -> Result<usize> {
+    let mut redacted = 0;
+
+    let room_ids = services()
+        .rooms
+        .state_cache
+        .rooms_joined(user_id)
+        .collect::<Result<Vec<_>>>()?;
+
+    for room_id in room_ids {
+        let mut until = PduCount::MAX;
+
+        loop {
+            let batch = services()
+                .rooms
+                .timeline
+                .pdus_until(user_id, &room_id, until)?
+                .filter_map(Result::ok)
+                .take(ERASURE_BATCH_SIZE)
+                .collect::<Vec<_>>();
+            let Some(&(last_count, _)) = batch.last() else {
+                break;
+            };
+            until = last_count;
+
+            let event_ids = batch
+                .into_iter()
+                .filter(|(_, pdu)| {
+                    pdu.sender == user_id
+                        && pdu.state_key.is_none()
+                        && pdu.kind != TimelineEventType::RoomRedaction
+                        && !pdu.is_redacted()
+                })
+                .map(|(_, pdu)| pdu.event_id)
+                .collect::<Vec<_>>();
+            if event_ids.is_empty() {
+                continue;
+            }
+
+            let room_token = services()
+                .globals
+                .roomid_mutex_state
+                .lock_key(room_id.clone())
+                .await;
+
+            for event_id in event_ids {
+                let result = services()
+                    .rooms
+                    .timeline
+                    .build_and_append_pdu(
+                        PduBuilder {
+                            event_type: TimelineEventType::RoomRedaction,
+                            content: to_raw_value(&RoomRedactionEventContent {
+                                redacts: Some((*event_id).to_owned()),
+                                reason: None,
+                            })
+                            .expect("event is valid, we just created it"),
+                            unsigned: None,
+                            state_key: None,
+                            redacts: Some(event_id.clone()),
+                        },
+                        user_id,
+                        &room_token,
+                    )
+                    .await;
+
+                match result {
+                    Ok(_) => redacted += 1,
+                    Err(error) => {
+                        warn!(
+                            %user_id,
+                            %room_id,
+                            %event_id,
+                            %error,
+                            "Failed to redact event"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(redacted)
+}
+
 /// # `GET _matrix/client/v3/account/3pid`
 ///
 /// Get a list of third party identifiers associated with this account.
-///
-/// - Currently always returns empty list
 pub(crate) async fn third_party_route(
     body: Ar<get_3pids::v3::Request>,
 ) -> Result<Ra<get_3pids::v3::Response>> {
-    let _sender_user =
-        body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    Ok(Ra(get_3pids::v3::Response::new(Vec::new())))
+    Ok(Ra(get_3pids::v3::Response::new(
+        services().users.threepids(sender_user)?,
+    )))
 }
 
 /// # `POST /_matrix/client/v3/account/3pid/email/requestToken`
@@ -486,14 +992,161 @@ pub(crate) async fn third_party_route(
 /// address to an account"
 ///
 /// - 403 signals that The homeserver does not allow the third party identifier
-///   as a contact option.
+///   as a contact option, which is the case unless `smtp` and
+///   `well_known.client` are configured
+/// - The token is sent to the address and submitted by the client to the
+///   returned `submit_url`
+/// - Fails with `M_THREEPID_IN_USE` if the address is bound to any account
 pub(crate) async fn request_3pid_management_token_via_email_route(
-    _body: Ar<request_3pid_management_token_via_email::v3::Request>,
+    body: Ar<request_3pid_management_token_via_email::v3::Request>,
 ) -> Result<Ra<request_3pid_management_token_via_email::v3::Response>> {
-    Err(Error::BadRequest(
-        ErrorKind::ThreepidDenied,
-        "Third party identifier is not allowed",
-    ))
+    let (Some(mailer), Some(client_url)) = (
+        &services().globals.mailer,
+        &services().globals.config.well_known.client,
+    ) else {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidDenied,
+            "Third party identifier is not allowed",
+        ));
+    };
+
+    let address = body.email.to_lowercase();
+    let Ok(to) = address.parse::<Mailbox>() else {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Invalid email address.",
+        ));
+    };
+
+    if services().users.threepid_owner(&Medium::Email, &address)?.is_some() {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidInUse,
+            "Email address is already in use.",
+        ));
+    }
+
+    let (sid, token) = services().users.create_threepid_session(
+        &body.client_secret,
+        Medium::Email,
+        &address,
+        body.send_attempt,
+    );
+
+    if let Some(token) = token {
+        mailer
+            .send(
+                to,
+                "Verify your email address".to_owned(),
+                format!(
+                    "Your code to add this email address to your account on \
+                     {} is {token}.\n\nIf you didn't request this, you can \
+                     ignore this email.",
+                    services().globals.server_name()
+                ),
+            )
+            .await?;
+    }
+
+    let mut response =
+        request_3pid_management_token_via_email::v3::Response::new(sid);
+    response.submit_url = Some(format!(
+        "{}/_matrix/client/unstable/add_threepid/email/submit_token",
+        client_url.trim_end_matches('/')
+    ));
+
+    Ok(Ra(response))
+}
+
+/// # `POST /_matrix/client/unstable/add_threepid/email/submit_token`
+///
+/// Validates an email address with the token sent by
+/// [`request_3pid_management_token_via_email_route`].
+///
+/// - Takes the same parameters as the `submitToken` endpoint of identity
+///   servers, without authentication
+pub(crate) async fn submit_threepid_token_route(
+    Json(body): Json<SubmitThreepidToken>,
+) -> Result<Json<ThreepidTokenSubmitted>> {
+    services().users.validate_threepid_session(
+        &body.sid,
+        &body.client_secret,
+        &body.token,
+    )?;
+
+    Ok(Json(ThreepidTokenSubmitted {
+        success: true,
+    }))
+}
+
+/// # `POST /_matrix/client/v3/account/3pid/add`
+///
+/// Adds a third party identifier that was validated via
+/// [`request_3pid_management_token_via_email_route`] to the account.
+///
+/// - Requires UIAA with the password of the user
+pub(crate) async fn add_3pid_route(
+    body: Ar<add_3pid::v3::Request>,
+) -> Result<Ra<add_3pid::v3::Response>> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_device =
+        body.sender_device.as_ref().expect("user is authenticated");
+
+    let mut uiaainfo = UiaaInfo {
+        flows: vec![AuthFlow {
+            stages: vec![AuthType::Password],
+        }],
+        completed: Vec::new(),
+        params: Box::default(),
+        session: None,
+        auth_error: None,
+    };
+
+    if let Some(auth) = &body.auth {
+        let (worked, uiaainfo) = services()
+            .uiaa
+            .try_auth(sender_user, sender_device, auth, &uiaainfo)
+            .await?;
+        if !worked {
+            return Err(Error::Uiaa(uiaainfo));
+        }
+    // Success!
+    } else if let Some(json) = body.json_body {
+        uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
+        services().uiaa.create(sender_user, sender_device, &uiaainfo, &json)?;
+        return Err(Error::Uiaa(uiaainfo));
+    } else {
+        return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
+    }
+
+    services().users.add_threepid(
+        sender_user,
+        &body.sid,
+        &body.client_secret,
+    )?;
+
+    info!(user_id = %sender_user, "User added a third party identifier");
+
+    Ok(Ra(add_3pid::v3::Response::new()))
+}
+
+/// # `POST /_matrix/client/v3/account/3pid/delete`
+///
+/// Removes a third party identifier from the account.
+///
+/// - Identity servers are never contacted
+pub(crate) async fn delete_3pid_route(
+    body: Ar<delete_3pid::v3::Request>,
+) -> Result<Ra<delete_3pid::v3::Response>> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let address = if body.medium == Medium::Email {
+        body.address.to_lowercase()
+    } else {
+        body.address.clone()
+    };
+    services().users.remove_threepid(sender_user, &body.medium, &address)?;
+
+    Ok(Ra(delete_3pid::v3::Response::new(ThirdPartyIdRemovalStatus::NoSupport)))
 }
 
 /// # `POST /_matrix/client/v3/account/3pid/msisdn/requestToken`
@@ -501,13 +1154,61 @@ pub(crate) async fn request_3pid_management_token_via_email_route(
 /// "This API should be used to request validation tokens when adding an phone
 /// number to an account"
 ///
-/// - 403 signals that The homeserver does not allow the third party identifier
-///   as a contact option.
+/// - Always fails with `M_UNRECOGNIZED` since this server can't send SMS
 pub(crate) async fn request_3pid_management_token_via_msisdn_route(
     _body: Ar<request_3pid_management_token_via_msisdn::v3::Request>,
 ) -> Result<Ra<request_3pid_management_token_via_msisdn::v3::Response>> {
     Err(Error::BadRequest(
-        ErrorKind::ThreepidDenied,
-        "Third party identifier is not allowed",
+        ErrorKind::Unrecognized,
+        "Phone numbers are not supported, this server can't send SMS.",
     ))
 }
+
+/// Fails with `M_WEAK_PASSWORD` if `password` doesn't meet the configured
+/// password policy
+fn check_password_policy(password: &str) -> Result<()> {
+    services()
+        .globals
+        .config
+        .password_policy
+        .check(password)
+        .map_err(|message| Error::BadRequest(ErrorKind::WeakPassword, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    };
+
+    use super::{remember_nonce, MAX_NONCES, NONCE_LIFETIME};
+
+    #[test]
+    fn nonces_expire_and_are_bounded() {
+        let start = Instant::now();
+        let mut nonces = HashMap::new();
+
+        remember_nonce(&mut nonces, "expired".to_owned(), start);
+        let now = start + NONCE_LIFETIME;
+        for i in 0..MAX_NONCES {
+            remember_nonce(
+                &mut nonces,
+                i.to_string(),
+                now + Duration::from_millis(i.try_into().unwrap()),
+            );
+        }
+        assert!(!nonces.contains_key("expired"));
+        assert_eq!(nonces.len(), MAX_NONCES);
+
+        remember_nonce(
+            &mut nonces,
+            "new".to_owned(),
+            now + Duration::from_secs(1),
+        );
+        assert_eq!(nonces.len(), MAX_NONCES);
+        assert!(!nonces.contains_key("0"));
+        assert!(nonces.contains_key("1"));
+        assert!(nonces.contains_key("new"));
+    }
+}
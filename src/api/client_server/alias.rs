@@ -8,7 +8,7 @@ use ruma::{
         },
         federation,
     },
-    OwnedRoomAliasId,
+    OwnedRoomAliasId, RoomId, UserId,
 };
 
 use crate::{services, Ar, Error, Ra, Result};
@@ -16,6 +16,9 @@ use crate::{services, Ar, Error, Ra, Result};
 /// # `PUT /_matrix/client/r0/directory/room/{roomAlias}`
 ///
 /// Creates a new room alias on this server.
+///
+/// - The sender has to be joined to the room and is restricted further by
+///   `alias_creation`, unless it is an appservice
 pub(crate) async fn create_alias_route(
     body: Ar<create_alias::v3::Request>,
 ) -> Result<Ra<create_alias::v3::Response>> {
@@ -41,6 +44,8 @@ pub(crate) async fn create_alias_route(
             ErrorKind::Exclusive,
             "Room alias reserved by appservice.",
         ));
+    } else {
+        check_alias_policy(sender_user, &body.room_id)?;
     }
 
     if services().rooms.alias.resolve_local_alias(&body.room_alias)?.is_some() {
@@ -60,7 +65,8 @@ pub(crate) async fn create_alias_route(
 ///
 /// Deletes a room alias from this server.
 ///
-/// - TODO: additional access control checks
+/// - Server admins can delete any alias, other users are restricted like when
+///   creating aliases for the room the alias points to
 /// - TODO: Update canonical alias event
 pub(crate) async fn delete_alias_route(
     body: Ar<delete_alias::v3::Request>,
@@ -87,6 +93,12 @@ pub(crate) async fn delete_alias_route(
             ErrorKind::Exclusive,
             "Room alias reserved by appservice.",
         ));
+    } else if !services().users.is_admin(sender_user)? {
+        if let Some(room_id) =
+            services().rooms.alias.resolve_local_alias(&body.room_alias)?
+        {
+            check_alias_policy(sender_user, &room_id)?;
+        }
     }
 
     services().rooms.alias.remove_alias(&body.room_alias, sender_user)?;
@@ -96,6 +108,43 @@ pub(crate) async fn delete_alias_route(
     Ok(Ra(delete_alias::v3::Response::new()))
 }
 
+/// Checks whether `alias_creation` allows a user to manage aliases of a room
+fn check_alias_policy(user_id: &UserId, room_id: &RoomId) -> Result<()> {
+    let config = &services().globals.config.alias_creation;
+
+    if !services().rooms.state_cache.is_joined(user_id, room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "You must be joined to the room to manage its aliases.",
+        ));
+    }
+
+    if services().users.is_admin(user_id)? {
+        return Ok(());
+    }
+
+    if config.admins_only {
+        return Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "Only server admins can manage aliases on this server.",
+        ));
+    }
+
+    if let Some(min_power_level) = config.min_power_level {
+        if services().rooms.state_accessor.user_power_level(room_id, user_id)?
+            < min_power_level
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::forbidden(),
+                "Your power level in the room is too low to manage its \
+                 aliases.",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
 /// # `GET /_matrix/client/r0/directory/room/{roomAlias}`
 ///
 /// Resolve an alias locally or over federation.
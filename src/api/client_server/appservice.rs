@@ -0,0 +1,69 @@
+use std::time::Instant;
+
+use ruma::api::{
+    appservice::ping::send_ping,
+    client::{appservice::request_ping, error::ErrorKind},
+};
+
+use crate::{services, Ar, Error, Ra, Result};
+
+/// # `POST /_matrix/client/v1/appservice/{appserviceId}/ping`
+///
+/// Asks the homeserver to ping the appservice, to check that it is reachable.
+///
+/// - Only the appservice itself may request a ping
+pub(crate) async fn appservice_ping_route(
+    body: Ar<request_ping::v1::Request>,
+) -> Result<Ra<request_ping::v1::Response>> {
+    let Some(info) = body
+        .appservice_info
+        .as_ref()
+        .filter(|info| info.registration.id == body.appservice_id)
+    else {
+        return Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "Appservice ID doesn't match the access token.",
+        ));
+    };
+
+    let start = Instant::now();
+    let result = services()
+        .sending
+        .send_appservice_request(
+            info.registration.clone(),
+            send_ping::v1::Request {
+                transaction_id: body.transaction_id.clone(),
+            },
+        )
+        .await;
+    let duration = start.elapsed();
+
+    match result {
+        Ok(Some(_)) => Ok(Ra(request_ping::v1::Response {
+            duration,
+        })),
+        Ok(None) => Err(Error::BadRequest(
+            ErrorKind::UrlNotSet,
+            "Appservice has no URL configured.",
+        )),
+        Err(Error::Reqwest {
+            source,
+        }) if source.is_timeout() => Err(Error::BadRequest(
+            ErrorKind::ConnectionTimeout,
+            "Connection to the appservice timed out.",
+        )),
+        Err(Error::Reqwest {
+            ..
+        }) => Err(Error::BadRequest(
+            ErrorKind::ConnectionFailed,
+            "Could not connect to the appservice.",
+        )),
+        Err(_) => Err(Error::BadRequest(
+            ErrorKind::BadStatus {
+                status: None,
+                body: None,
+            },
+            "Appservice returned a bad response.",
+        )),
+    }
+}
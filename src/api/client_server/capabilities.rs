@@ -1,8 +1,7 @@
-use std::collections::BTreeMap;
-
 use ruma::api::client::discovery::get_capabilities::{
-    self, Capabilities, RoomVersionStability, RoomVersionsCapability,
+    self, Capabilities, RoomVersionsCapability,
 };
+use serde_json::json;
 
 use crate::{services, Ar, Ra, Result};
 
@@ -10,20 +9,36 @@ use crate::{services, Ar, Ra, Result};
 ///
 /// Get information on the supported feature set and other relevent capabilities
 /// of this server.
+///
+/// - Advertises the password policy with the keys of [MSC2000] under
+///   `org.matrix.msc2000.password_policy`, if it's enabled
+///
+/// [MSC2000]: https://github.com/matrix-org/matrix-spec-proposals/pull/2000
 pub(crate) async fn get_capabilities_route(
     _body: Ar<get_capabilities::v3::Request>,
 ) -> Result<Ra<get_capabilities::v3::Response>> {
-    let mut available = BTreeMap::new();
-    for room_version in &services().globals.stable_room_versions {
-        available.insert(room_version.clone(), RoomVersionStability::Stable);
-    }
-
     let mut capabilities = Capabilities::new();
     capabilities.room_versions = RoomVersionsCapability {
         default: services().globals.default_room_version(),
-        available,
+        available: services().globals.room_versions.clone(),
     };
 
+    let policy = &services().globals.config.password_policy;
+    if policy.enable {
+        capabilities
+            .set(
+                "org.matrix.msc2000.password_policy",
+                json!({
+                    "m.minimum_length": policy.minimum_length,
+                    "m.require_digit": policy.require_digit,
+                    "m.require_symbol": policy.require_symbol,
+                    "m.require_lowercase": policy.require_lowercase,
+                    "m.require_uppercase": policy.require_uppercase,
+                }),
+            )
+            .expect("custom capability is valid JSON");
+    }
+
     Ok(Ra(get_capabilities::v3::Response {
         capabilities,
     }))
@@ -5,10 +5,11 @@ use ruma::{
         context::get_context, error::ErrorKind, filter::LazyLoadOptions,
     },
     events::StateEventType,
-    uint,
+    uint, UserId,
 };
 use tracing::error;
 
+use super::{pdu_matches_filter, MAX_SCANNED_EVENTS};
 use crate::{services, Ar, Error, Ra, Result};
 
 /// # `GET /_matrix/client/r0/rooms/{roomId}/context`
@@ -34,7 +35,8 @@ pub(crate) async fn get_context_route(
             LazyLoadOptions::Disabled => (false, false),
         };
 
-    let mut lazy_loaded = HashSet::new();
+    // The member event of the requester is always included
+    let mut lazy_loaded = HashSet::from([sender_user.as_str().to_owned()]);
 
     let base_token =
         services().rooms.timeline.get_pdu_count(&body.event_id)?.ok_or(
@@ -58,28 +60,46 @@ pub(crate) async fn get_context_route(
         ));
     }
 
-    if !services().rooms.lazy_loading.lazy_load_was_sent_before(
-        sender_user,
-        sender_device,
-        &room_id,
-        &base_event.sender,
-    )? || lazy_load_send_redundant
-    {
-        lazy_loaded.insert(base_event.sender.as_str().to_owned());
-    }
+    // Adds the sender of a returned event to the lazy-loaded members, unless
+    // their member event was sent to the device before
+    let lazy_load = |lazy_loaded: &mut HashSet<String>, sender: &UserId| {
+        if !lazy_load_enabled || lazy_loaded.contains(sender.as_str()) {
+            return Ok(());
+        }
+
+        if lazy_load_send_redundant
+            || !services().rooms.lazy_loading.lazy_load_was_sent_before(
+                sender_user,
+                sender_device,
+                &room_id,
+                sender,
+            )?
+        {
+            lazy_loaded.insert(sender.as_str().to_owned());
+        }
+
+        Ok::<_, Error>(())
+    };
+
+    lazy_load(&mut lazy_loaded, &base_event.sender)?;
 
     // Use limit with maximum 100
-    let half_limit = usize::try_from(body.limit.min(uint!(100)) / uint!(2))
-        .expect("0-50 should fit in usize");
+    let limit =
+        body.limit.min(body.filter.limit.unwrap_or(uint!(100))).min(uint!(100));
+    let half_limit =
+        usize::try_from(limit / uint!(2)).expect("0-50 should fit in usize");
 
     let base_event = base_event.to_room_event();
 
+    let mut last_scanned_before = None;
     let events_before: Vec<_> = services()
         .rooms
         .timeline
         .pdus_until(sender_user, &room_id, base_token)?
-        .take(half_limit)
         .filter_map(Result::ok)
+        .take(MAX_SCANNED_EVENTS)
+        .inspect(|&(k, _)| last_scanned_before = Some(k))
+        .filter(|(_, pdu)| pdu_matches_filter(&body.filter, pdu))
         .filter(|(_, pdu)| {
             services()
                 .rooms
@@ -87,33 +107,27 @@ pub(crate) async fn get_context_route(
                 .user_can_see_event(sender_user, &room_id, &pdu.event_id)
                 .unwrap_or(false)
         })
+        .take(half_limit)
         .collect();
 
     for (_, event) in &events_before {
-        if !services().rooms.lazy_loading.lazy_load_was_sent_before(
-            sender_user,
-            sender_device,
-            &room_id,
-            &event.sender,
-        )? || lazy_load_send_redundant
-        {
-            lazy_loaded.insert(event.sender.as_str().to_owned());
-        }
+        lazy_load(&mut lazy_loaded, &event.sender)?;
     }
 
-    let start_token = events_before
-        .last()
-        .map_or_else(|| base_token.stringify(), |(count, _)| count.stringify());
+    let start_token = last_scanned_before.unwrap_or(base_token).stringify();
 
     let events_before: Vec<_> =
         events_before.into_iter().map(|(_, pdu)| pdu.to_room_event()).collect();
 
+    let mut last_scanned_after = None;
     let events_after: Vec<_> = services()
         .rooms
         .timeline
         .pdus_after(sender_user, &room_id, base_token)?
-        .take(half_limit)
         .filter_map(Result::ok)
+        .take(MAX_SCANNED_EVENTS)
+        .inspect(|&(k, _)| last_scanned_after = Some(k))
+        .filter(|(_, pdu)| pdu_matches_filter(&body.filter, pdu))
         .filter(|(_, pdu)| {
             services()
                 .rooms
@@ -121,18 +135,11 @@ pub(crate) async fn get_context_route(
                 .user_can_see_event(sender_user, &room_id, &pdu.event_id)
                 .unwrap_or(false)
         })
+        .take(half_limit)
         .collect();
 
     for (_, event) in &events_after {
-        if !services().rooms.lazy_loading.lazy_load_was_sent_before(
-            sender_user,
-            sender_device,
-            &room_id,
-            &event.sender,
-        )? || lazy_load_send_redundant
-        {
-            lazy_loaded.insert(event.sender.as_str().to_owned());
-        }
+        lazy_load(&mut lazy_loaded, &event.sender)?;
     }
 
     let shortstatehash =
@@ -150,9 +157,7 @@ pub(crate) async fn get_context_route(
     let state_ids =
         services().rooms.state_accessor.state_full_ids(shortstatehash).await?;
 
-    let end_token = events_after
-        .last()
-        .map_or_else(|| base_token.stringify(), |(count, _)| count.stringify());
+    let end_token = last_scanned_after.unwrap_or(base_token).stringify();
 
     let events_after: Vec<_> =
         events_after.into_iter().map(|(_, pdu)| pdu.to_room_event()).collect();
@@ -163,21 +168,20 @@ pub(crate) async fn get_context_route(
         let (event_type, state_key) =
             services().rooms.short.get_statekey_from_short(shortstatekey)?;
 
-        if event_type != StateEventType::RoomMember {
-            let Some(pdu) = services().rooms.timeline.get_pdu(&event_id)?
-            else {
-                error!(%event_id, "Event in state not found");
-                continue;
-            };
-            state.push(pdu.to_state_event());
-        } else if !lazy_load_enabled || lazy_loaded.contains(&state_key) {
-            let Some(pdu) = services().rooms.timeline.get_pdu(&event_id)?
-            else {
-                error!(%event_id, "Event in state not found");
-                continue;
-            };
-            state.push(pdu.to_state_event());
+        if !is_state_included(
+            &event_type,
+            &state_key,
+            lazy_load_enabled,
+            &lazy_loaded,
+        ) {
+            continue;
         }
+
+        let Some(pdu) = services().rooms.timeline.get_pdu(&event_id)? else {
+            error!(%event_id, "Event in state not found");
+            continue;
+        };
+        state.push(pdu.to_state_event());
     }
 
     let resp = get_context::v3::Response {
@@ -191,3 +195,81 @@ pub(crate) async fn get_context_route(
 
     Ok(Ra(resp))
 }
+
+/// Returns whether a state event is included in the response.
+///
+/// With lazy loading, member events are only included for the users in
+/// `lazy_loaded`.
+fn is_state_included(
+    event_type: &StateEventType,
+    state_key: &str,
+    lazy_load_enabled: bool,
+    lazy_loaded: &HashSet<String>,
+) -> bool {
+    *event_type != StateEventType::RoomMember
+        || !lazy_load_enabled
+        || lazy_loaded.contains(state_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::events::StateEventType;
+
+    use super::is_state_included;
+
+    const STATE: &[(StateEventType, &str)] = &[
+        (StateEventType::RoomCreate, ""),
+        (StateEventType::RoomName, ""),
+        (StateEventType::RoomMember, "@alice:example.com"),
+        (StateEventType::RoomMember, "@bob:example.com"),
+        (StateEventType::RoomMember, "@carol:example.com"),
+    ];
+
+    fn included_state(
+        lazy_load_enabled: bool,
+        lazy_loaded: &[&str],
+    ) -> Vec<(StateEventType, &'static str)> {
+        let lazy_loaded =
+            lazy_loaded.iter().map(|&user| user.to_owned()).collect();
+
+        STATE
+            .iter()
+            .filter(|(event_type, state_key)| {
+                is_state_included(
+                    event_type,
+                    state_key,
+                    lazy_load_enabled,
+                    &lazy_loaded,
+                )
+            })
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn all_members_without_lazy_loading() {
+        assert_eq!(included_state(false, &["@alice:example.com"]), STATE);
+        assert_eq!(included_state(false, &[]), STATE);
+    }
+
+    #[test]
+    fn only_lazy_loaded_members_with_lazy_loading() {
+        assert_eq!(
+            included_state(true, &["@alice:example.com", "@carol:example.com"]),
+            [
+                (StateEventType::RoomCreate, ""),
+                (StateEventType::RoomName, ""),
+                (StateEventType::RoomMember, "@alice:example.com"),
+                (StateEventType::RoomMember, "@carol:example.com"),
+            ],
+        );
+    }
+
+    #[test]
+    fn other_state_with_lazy_loading() {
+        assert_eq!(
+            included_state(true, &[]),
+            [(StateEventType::RoomCreate, ""), (StateEventType::RoomName, "")],
+        );
+    }
+}
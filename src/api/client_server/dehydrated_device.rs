@@ -0,0 +1,159 @@
+use ruma::api::client::{
+    dehydrated_device::{
+        delete_dehydrated_device, get_dehydrated_device, get_events,
+        put_dehydrated_device,
+    },
+    error::ErrorKind,
+};
+
+use crate::{services, Ar, Error, Ra, Result};
+
+/// # `PUT /_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device`
+///
+/// Uploads a dehydrated device, replacing the previous one of the sender user.
+///
+/// - The device can't be logged in to, but receives to-device messages until
+///   a client rehydrates it
+/// - Adds the device keys, one time keys and fallback keys of the device
+pub(crate) async fn put_dehydrated_device_route(
+    body: Ar<put_dehydrated_device::unstable::Request>,
+) -> Result<Ra<put_dehydrated_device::unstable::Response>> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let current_device_id =
+        services().users.dehydrated_device(sender_user)?.map(|(id, _)| id);
+    if current_device_id.as_ref() != Some(&body.device_id)
+        && services()
+            .users
+            .get_device_metadata(sender_user, &body.device_id)?
+            .is_some()
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Device ID is already in use.",
+        ));
+    }
+
+    services().users.add_device_keys(
+        sender_user,
+        &body.device_id,
+        &body.device_keys,
+    )?;
+
+    for (key_key, key_value) in &body.one_time_keys {
+        services().users.add_one_time_key(
+            sender_user,
+            &body.device_id,
+            key_key,
+            key_value,
+        )?;
+    }
+
+    for (key_key, key_value) in &body.fallback_keys {
+        services().users.add_fallback_key(
+            sender_user,
+            &body.device_id,
+            key_key,
+            key_value,
+        )?;
+    }
+
+    services().users.set_dehydrated_device(
+        sender_user,
+        &body.device_id,
+        body.initial_device_display_name.clone(),
+        &body.device_data,
+    )?;
+
+    Ok(Ra(put_dehydrated_device::unstable::Response {
+        device_id: body.device_id.clone(),
+    }))
+}
+
+/// # `GET /_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device`
+///
+/// Gets the dehydrated device of the sender user.
+pub(crate) async fn get_dehydrated_device_route(
+    body: Ar<get_dehydrated_device::unstable::Request>,
+) -> Result<Ra<get_dehydrated_device::unstable::Response>> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let (device_id, device_data) = services()
+        .users
+        .dehydrated_device(sender_user)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "No dehydrated device found.",
+        ))?;
+
+    Ok(Ra(get_dehydrated_device::unstable::Response {
+        device_id,
+        device_data,
+    }))
+}
+
+/// # `DELETE /_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device`
+///
+/// Deletes the dehydrated device of the sender user.
+pub(crate) async fn delete_dehydrated_device_route(
+    body: Ar<delete_dehydrated_device::unstable::Request>,
+) -> Result<Ra<delete_dehydrated_device::unstable::Response>> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let device_id = services()
+        .users
+        .remove_dehydrated_device(sender_user)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "No dehydrated device found.",
+        ))?;
+
+    Ok(Ra(delete_dehydrated_device::unstable::Response {
+        device_id,
+    }))
+}
+
+/// # `POST /_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device/{deviceId}/events`
+///
+/// Gets the to-device events that were sent to the dehydrated device of the
+/// sender user.
+///
+/// - Events before `next_batch` were received by the client and are removed
+pub(crate) async fn get_dehydrated_events_route(
+    body: Ar<get_events::unstable::Request>,
+) -> Result<Ra<get_events::unstable::Response>> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    if !services()
+        .users
+        .dehydrated_device(sender_user)?
+        .is_some_and(|(device_id, _)| device_id == body.device_id)
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "No dehydrated device with this ID found.",
+        ));
+    }
+
+    if let Some(next_batch) = &body.next_batch {
+        let until = next_batch.parse().map_err(|_| {
+            Error::BadRequest(ErrorKind::InvalidParam, "Invalid `next_batch`.")
+        })?;
+        services().users.remove_to_device_events(
+            sender_user,
+            &body.device_id,
+            until,
+        )?;
+    }
+
+    // Events sent after this count may also be returned, in which case the
+    // client receives them again in the next batch
+    let count = services().globals.current_count()?;
+    let events =
+        services().users.get_to_device_events(sender_user, &body.device_id)?;
+
+    Ok(Ra(get_events::unstable::Response {
+        next_batch: (!events.is_empty()).then(|| count.to_string()),
+        events,
+    }))
+}
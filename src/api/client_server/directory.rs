@@ -1,3 +1,5 @@
+use std::cmp::Reverse;
+
 use ruma::{
     api::{
         client::{
@@ -10,24 +12,10 @@ use ruma::{
         },
         federation,
     },
-    directory::{Filter, PublicRoomJoinRule, PublicRoomsChunk, RoomNetwork},
-    events::{
-        room::{
-            avatar::RoomAvatarEventContent,
-            canonical_alias::RoomCanonicalAliasEventContent,
-            create::RoomCreateEventContent,
-            guest_access::{GuestAccess, RoomGuestAccessEventContent},
-            history_visibility::{
-                HistoryVisibility, RoomHistoryVisibilityEventContent,
-            },
-            join_rules::{JoinRule, RoomJoinRulesEventContent},
-            topic::RoomTopicEventContent,
-        },
-        StateEventType,
-    },
-    uint, ServerName, UInt,
+    directory::{Filter, PublicRoomsChunk, RoomNetwork},
+    uint, RoomId, ServerName, UInt,
 };
-use tracing::{error, info, warn};
+use tracing::info;
 
 use crate::{services, Ar, Error, Ra, Result};
 
@@ -36,6 +24,9 @@ use crate::{services, Ar, Error, Ra, Result};
 /// Lists the public rooms on this server.
 ///
 /// - Rooms are ordered by the number of joined members
+/// - Room summaries are cached and refreshed periodically
+/// - Rooms match search terms if every word of the term starts a word of their
+///   name, topic or canonical alias, better matches are listed first
 pub(crate) async fn get_public_rooms_filtered_route(
     body: Ar<get_public_rooms_filtered::v3::Request>,
 ) -> Result<Ra<get_public_rooms_filtered::v3::Response>> {
@@ -55,6 +46,7 @@ pub(crate) async fn get_public_rooms_filtered_route(
 /// Lists the public rooms on this server.
 ///
 /// - Rooms are ordered by the number of joined members
+/// - Room summaries are cached and refreshed periodically
 pub(crate) async fn get_public_rooms_route(
     body: Ar<get_public_rooms::v3::Request>,
 ) -> Result<Ra<get_public_rooms::v3::Response>> {
@@ -137,7 +129,6 @@ pub(crate) async fn get_room_visibility_route(
     }))
 }
 
-#[allow(clippy::too_many_lines)]
 pub(crate) async fn get_public_rooms_filtered_helper(
     server: Option<&ServerName>,
     limit: Option<UInt>,
@@ -172,93 +163,61 @@ pub(crate) async fn get_public_rooms_filtered_helper(
         });
     }
 
-    let limit = limit.unwrap_or(uint!(10));
-    let mut num_since = UInt::MIN;
-
-    if let Some(s) = &since {
-        let mut characters = s.chars();
-        let backwards = match characters.next() {
-            Some('n') => false,
-            Some('p') => true,
-            _ => {
-                return Err(Error::BadRequest(
-                    ErrorKind::InvalidParam,
-                    "Invalid `since` token",
-                ))
-            }
-        };
-
-        num_since = characters.collect::<String>().parse().map_err(|_| {
-            Error::BadRequest(ErrorKind::InvalidParam, "Invalid `since` token.")
-        })?;
-
-        if backwards {
-            num_since = num_since.saturating_sub(limit);
-        }
-    }
-
-    let mut all_rooms: Vec<_> = services()
-        .rooms
-        .directory
-        .public_rooms()
-        .filter_map(Result::ok)
-        .map(room_id_to_chunk)
-        .filter_map(Result::ok)
-        .filter(|chunk| {
-            if let Some(query) =
-                filter.generic_search_term.as_ref().map(|q| q.to_lowercase())
-            {
-                if let Some(name) = &chunk.name {
-                    if name.as_str().to_lowercase().contains(&query) {
-                        return true;
-                    }
-                }
-
-                if let Some(topic) = &chunk.topic {
-                    if topic.to_lowercase().contains(&query) {
-                        return true;
-                    }
-                }
-
-                if let Some(canonical_alias) = &chunk.canonical_alias {
-                    if canonical_alias.as_str().to_lowercase().contains(&query)
-                    {
-                        return true;
-                    }
-                }
+    let limit = limit
+        .unwrap_or(uint!(10))
+        .try_into()
+        .expect("UInt should fit in usize");
+    let since = since.map(parse_since).transpose()?;
+
+    let summaries = services().rooms.directory.public_room_summaries()?;
+    let scores = filter
+        .generic_search_term
+        .as_deref()
+        .map(|query| services().rooms.search.search_directory(query))
+        .transpose()?
+        .flatten();
 
-                false
-            } else {
-                // No search term
-                true
-            }
+    // Rooms are listed by relevance first if there is a search term, the
+    // summaries are already sorted otherwise
+    let mut all_rooms: Vec<_> = summaries
+        .iter()
+        .filter_map(|chunk| {
+            let score = match &scores {
+                Some(scores) => *scores.get(&chunk.room_id)?,
+                None => 0,
+            };
+            Some((score, chunk))
         })
         .collect();
+    all_rooms.sort_by_key(|(score, _)| Reverse(*score));
+
+    // Tokens point at the first or last room of the previous page, so that
+    // they stay valid when rooms are added to or removed from the directory
+    let (start, end) = match since {
+        Some((false, key)) => {
+            let start =
+                all_rooms.partition_point(|room| order_key(room) <= key);
+            (start, all_rooms.len().min(start.saturating_add(limit)))
+        }
+        Some((true, key)) => {
+            let end = all_rooms.partition_point(|room| order_key(room) < key);
+            (end.saturating_sub(limit), end)
+        }
+        None => (0, all_rooms.len().min(limit)),
+    };
 
-    all_rooms.sort_by(|l, r| r.num_joined_members.cmp(&l.num_joined_members));
+    let prev_batch = (start > 0).then(|| since_token('p', &all_rooms[start]));
+    let next_batch = (end < all_rooms.len() && end > 0)
+        .then(|| since_token('n', &all_rooms[end - 1]));
 
     let total_room_count_estimate =
         all_rooms.len().try_into().unwrap_or(UInt::MAX);
 
-    let chunk: Vec<_> = all_rooms
-        .into_iter()
-        .skip(num_since.try_into().expect("UInt should fit in usize"))
-        .take(limit.try_into().expect("UInt should fit in usize"))
+    let chunk = all_rooms[start..end]
+        .iter()
+        .map(|(_, chunk)| (*chunk).clone())
         .collect();
 
-    let prev_batch = if num_since == uint!(0) {
-        None
-    } else {
-        Some(format!("p{num_since}"))
-    };
-
-    let next_batch =
-        if chunk.len() < limit.try_into().expect("UInt should fit in usize") {
-            None
-        } else {
-            Some(format!("n{}", num_since + limit))
-        };
-
     Ok(get_public_rooms_filtered::v3::Response {
         chunk,
         prev_batch,
@@ -267,145 +226,45 @@ pub(crate) async fn get_public_rooms_filtered_helper(
     })
 }
 
-#[allow(clippy::too_many_lines)]
-#[tracing::instrument]
-fn room_id_to_chunk(room_id: ruma::OwnedRoomId) -> Result<PublicRoomsChunk> {
-    let canonical_alias = services()
-        .rooms
-        .state_accessor
-        .room_state_get(&room_id, &StateEventType::RoomCanonicalAlias, "")?
-        .map_or(Ok(None), |s| {
-            serde_json::from_str(s.content.get())
-                .map(|c: RoomCanonicalAliasEventContent| c.alias)
-                .map_err(|_| {
-                    Error::bad_database(
-                        "Invalid canonical alias event in database.",
-                    )
-                })
-        })?;
-
-    let name = services().rooms.state_accessor.get_name(&room_id)?;
-
-    let num_joined_members = services()
-        .rooms
-        .state_cache
-        .room_joined_count(&room_id)?
-        .unwrap_or_else(|| {
-            warn!("Room has no member count");
-            0
-        })
-        .try_into()
-        .expect("user count should not be that big");
-
-    let topic = services()
-        .rooms
-        .state_accessor
-        .room_state_get(&room_id, &StateEventType::RoomTopic, "")?
-        .map_or(Ok(None), |s| {
-            serde_json::from_str(s.content.get())
-                .map(|c: RoomTopicEventContent| Some(c.topic))
-                .map_err(|_| {
-                    error!("Invalid room topic event in database for room",);
-                    Error::bad_database("Invalid room topic event in database.")
-                })
-        })?;
+/// A public room with its relevance to the search term, 0 if there is none
+type ScoredRoom<'a> = (u32, &'a PublicRoomsChunk);
 
-    let world_readable = services()
-        .rooms
-        .state_accessor
-        .room_state_get(&room_id, &StateEventType::RoomHistoryVisibility, "")?
-        .map_or(Ok(false), |s| {
-            serde_json::from_str(s.content.get())
-                .map(|c: RoomHistoryVisibilityEventContent| {
-                    c.history_visibility == HistoryVisibility::WorldReadable
-                })
-                .map_err(|_| {
-                    Error::bad_database(
-                        "Invalid room history visibility event in database.",
-                    )
-                })
-        })?;
+/// Position of a room in the directory
+type OrderKey<'a> = (Reverse<u32>, Reverse<UInt>, &'a RoomId);
 
-    let guest_can_join = services()
-        .rooms
-        .state_accessor
-        .room_state_get(&room_id, &StateEventType::RoomGuestAccess, "")?
-        .map_or(Ok(false), |s| {
-            serde_json::from_str(s.content.get())
-                .map(|c: RoomGuestAccessEventContent| {
-                    c.guest_access == GuestAccess::CanJoin
-                })
-                .map_err(|_| {
-                    Error::bad_database(
-                        "Invalid room guest access event in database.",
-                    )
-                })
-        })?;
+/// Returns the position of a room in the directory
+fn order_key<'a>(&(score, chunk): &ScoredRoom<'a>) -> OrderKey<'a> {
+    (Reverse(score), Reverse(chunk.num_joined_members), &chunk.room_id)
+}
 
-    let avatar_url = services()
-        .rooms
-        .state_accessor
-        .room_state_get(&room_id, &StateEventType::RoomAvatar, "")?
-        .map(|s| {
-            serde_json::from_str(s.content.get())
-                .map(|c: RoomAvatarEventContent| c.url)
-                .map_err(|_| {
-                    Error::bad_database(
-                        "Invalid room avatar event in database.",
-                    )
-                })
-        })
-        .transpose()?
-        .flatten();
+/// Creates a `since` token pointing at `room`, `direction` is `n` for tokens
+/// of the next page and `p` for tokens of the previous page
+fn since_token(direction: char, (score, chunk): &ScoredRoom<'_>) -> String {
+    format!("{direction}{score}_{}_{}", chunk.num_joined_members, chunk.room_id)
+}
 
-    let join_rule = services()
-        .rooms
-        .state_accessor
-        .room_state_get(&room_id, &StateEventType::RoomJoinRules, "")?
-        .map(|s| {
-            serde_json::from_str(s.content.get())
-                .map(|c: RoomJoinRulesEventContent| match c.join_rule {
-                    JoinRule::Public => Some(PublicRoomJoinRule::Public),
-                    JoinRule::Knock => Some(PublicRoomJoinRule::Knock),
-                    _ => None,
-                })
-                .map_err(|error| {
-                    error!(%error, "Invalid room join rule event in database");
-                    Error::BadDatabase(
-                        "Invalid room join rule event in database.",
-                    )
-                })
-        })
-        .transpose()?
-        .flatten()
-        .ok_or_else(|| {
-            Error::bad_database("Missing room join rule event for room.")
-        })?;
+/// Parses a `since` token created by [`since_token`]
+///
+/// Returns whether the token points backwards and the position it points at.
+fn parse_since(token: &str) -> Result<(bool, OrderKey<'_>)> {
+    let invalid =
+        || Error::BadRequest(ErrorKind::InvalidParam, "Invalid `since` token.");
+
+    let (backwards, token) = if let Some(token) = token.strip_prefix('n') {
+        (false, token)
+    } else if let Some(token) = token.strip_prefix('p') {
+        (true, token)
+    } else {
+        return Err(invalid());
+    };
 
-    let room_type = services()
-        .rooms
-        .state_accessor
-        .room_state_get(&room_id, &StateEventType::RoomCreate, "")?
-        .map(|s| {
-            serde_json::from_str::<RoomCreateEventContent>(s.content.get())
-                .map_err(|error| {
-                    error!(%error, "Invalid room create event in database");
-                    Error::BadDatabase("Invalid room create event in database.")
-                })
-        })
-        .transpose()?
-        .and_then(|e| e.room_type);
+    let (score, token) = token.split_once('_').ok_or_else(invalid)?;
+    let score = score.parse().map_err(|_| invalid())?;
+    let (num_joined_members, room_id) =
+        token.split_once('_').ok_or_else(invalid)?;
+    let num_joined_members =
+        num_joined_members.parse().map_err(|_| invalid())?;
+    let room_id = <&RoomId>::try_from(room_id).map_err(|_| invalid())?;
 
-    Ok(PublicRoomsChunk {
-        canonical_alias,
-        name,
-        num_joined_members,
-        room_id,
-        topic,
-        world_readable,
-        guest_can_join,
-        avatar_url,
-        join_rule,
-        room_type,
-    })
+    Ok((backwards, (Reverse(score), Reverse(num_joined_members), room_id)))
 }
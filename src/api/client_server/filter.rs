@@ -1,9 +1,14 @@
-use ruma::api::client::{
-    error::ErrorKind,
-    filter::{create_filter, get_filter},
+use ruma::{
+    api::client::{
+        error::ErrorKind,
+        filter::{create_filter, get_filter, RoomEventFilter, UrlFilter},
+    },
+    OwnedRoomId, RoomId, UserId,
 };
+use serde::{de::IgnoredAny, Deserialize};
+use serde_json::value::RawValue as RawJsonValue;
 
-use crate::{services, Ar, Error, Ra, Result};
+use crate::{services, Ar, Error, PduEvent, Ra, Result};
 
 /// # `GET /_matrix/client/r0/user/{userId}/filter/{filterId}`
 ///
@@ -37,3 +42,201 @@ pub(crate) async fn create_filter_route(
         services().users.create_filter(sender_user, &body.filter)?,
     )))
 }
+
+/// Most events that are looked at to fill a page of `/messages` or `/context`
+///
+/// Without this, a filter that matches few events would make a single request
+/// go through the entire timeline. The pagination tokens point at the last
+/// event that was looked at, so that clients continue from there.
+pub(crate) const MAX_SCANNED_EVENTS: usize = 1000;
+
+/// Returns whether an event is allowed by the event fields of a filter.
+///
+/// The `limit` and lazy-loading options are left to the caller.
+pub(crate) fn pdu_matches_filter(
+    filter: &RoomEventFilter,
+    pdu: &PduEvent,
+) -> bool {
+    event_matches_filter(
+        filter,
+        &pdu.room_id,
+        &pdu.sender,
+        &pdu.kind.to_string(),
+        &pdu.content,
+    )
+}
+
+fn event_matches_filter(
+    filter: &RoomEventFilter,
+    room_id: &RoomId,
+    sender: &UserId,
+    event_type: &str,
+    content: &RawJsonValue,
+) -> bool {
+    if !room_matches_filter(filter.rooms.as_deref(), &filter.not_rooms, room_id)
+    {
+        return false;
+    }
+
+    if filter.not_senders.iter().any(|s| &**s == sender)
+        || filter
+            .senders
+            .as_ref()
+            .is_some_and(|senders| !senders.iter().any(|s| &**s == sender))
+    {
+        return false;
+    }
+
+    if filter.not_types.iter().any(|pattern| type_matches(pattern, event_type))
+        || filter.types.as_ref().is_some_and(|types| {
+            !types.iter().any(|pattern| type_matches(pattern, event_type))
+        })
+    {
+        return false;
+    }
+
+    let Some(url_filter) = &filter.url_filter else {
+        return true;
+    };
+
+    #[derive(Deserialize)]
+    struct ExtractUrl {
+        url: Option<IgnoredAny>,
+    }
+
+    let has_url = serde_json::from_str::<ExtractUrl>(content.get())
+        .is_ok_and(|content| content.url.is_some());
+
+    match url_filter {
+        UrlFilter::EventsWithUrl => has_url,
+        UrlFilter::EventsWithoutUrl => !has_url,
+    }
+}
+
+/// Returns whether a room is allowed by the `rooms` and `not_rooms` fields of
+/// a filter
+pub(crate) fn room_matches_filter(
+    rooms: Option<&[OwnedRoomId]>,
+    not_rooms: &[OwnedRoomId],
+    room_id: &RoomId,
+) -> bool {
+    !not_rooms.iter().any(|r| &**r == room_id)
+        && rooms.map_or(true, |rooms| rooms.iter().any(|r| &**r == room_id))
+}
+
+/// Matches an event type against a pattern of `types` or `not_types`, in
+/// which `*` matches any sequence of characters
+fn type_matches(pattern: &str, event_type: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first =
+        parts.next().expect("split always returns at least one element");
+    let Some(mut rest) = event_type.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts: Vec<_> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // There is no wildcard
+        return rest.is_empty();
+    };
+
+    for part in parts {
+        let Some(i) = rest.find(part) else {
+            return false;
+        };
+        rest = &rest[i + part.len()..];
+    }
+
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::{
+        api::client::filter::{RoomEventFilter, UrlFilter},
+        room_id, user_id,
+    };
+    use serde_json::{json, value::to_raw_value};
+
+    use super::{event_matches_filter, type_matches};
+
+    fn matches(filter: &RoomEventFilter, event_type: &str) -> bool {
+        event_matches_filter(
+            filter,
+            room_id!("!room:example.com"),
+            user_id!("@alice:example.com"),
+            event_type,
+            &to_raw_value(&json!({ "body": "hi" })).unwrap(),
+        )
+    }
+
+    #[test]
+    fn not_types_excludes_membership() {
+        let filter = RoomEventFilter {
+            not_types: vec!["m.room.member".to_owned()],
+            ..Default::default()
+        };
+
+        assert!(!matches(&filter, "m.room.member"));
+        assert!(matches(&filter, "m.room.message"));
+        assert!(matches(&filter, "m.reaction"));
+    }
+
+    #[test]
+    fn not_types_wins_over_types() {
+        let filter = RoomEventFilter {
+            types: Some(vec!["m.room.*".to_owned()]),
+            not_types: vec!["m.room.member".to_owned()],
+            ..Default::default()
+        };
+
+        assert!(matches(&filter, "m.room.message"));
+        assert!(!matches(&filter, "m.room.member"));
+        assert!(!matches(&filter, "m.reaction"));
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(type_matches("*", "m.room.message"));
+        assert!(type_matches("m.room.*", "m.room.message"));
+        assert!(type_matches("m.*.message", "m.room.message"));
+        assert!(type_matches("m.room.message", "m.room.message"));
+        assert!(!type_matches("m.room", "m.room.message"));
+        assert!(!type_matches("m.*.member", "m.room.message"));
+        assert!(!type_matches("*.member", "m.room.message"));
+    }
+
+    #[test]
+    fn senders_and_rooms() {
+        let filter = RoomEventFilter {
+            senders: Some(vec![user_id!("@bob:example.com").to_owned()]),
+            ..Default::default()
+        };
+        assert!(!matches(&filter, "m.room.message"));
+
+        let filter = RoomEventFilter {
+            not_rooms: vec![room_id!("!room:example.com").to_owned()],
+            ..Default::default()
+        };
+        assert!(!matches(&filter, "m.room.message"));
+    }
+
+    #[test]
+    fn contains_url() {
+        let mut filter = RoomEventFilter {
+            url_filter: Some(UrlFilter::EventsWithUrl),
+            ..Default::default()
+        };
+        assert!(!matches(&filter, "m.room.message"));
+        assert!(event_matches_filter(
+            &filter,
+            room_id!("!room:example.com"),
+            user_id!("@alice:example.com"),
+            "m.room.message",
+            &to_raw_value(&json!({ "url": "mxc://example.com/abc" })).unwrap(),
+        ));
+
+        filter.url_filter = Some(UrlFilter::EventsWithoutUrl);
+        assert!(matches(&filter, "m.room.message"));
+    }
+}
@@ -3,6 +3,7 @@ use std::{
     time::{Duration, Instant},
 };
 
+use base64::{engine::general_purpose, Engine as _};
 use futures_util::{stream::FuturesUnordered, StreamExt};
 use ruma::{
     api::{
@@ -10,14 +11,16 @@ use ruma::{
             error::ErrorKind,
             keys::{
                 claim_keys, get_key_changes, get_keys, upload_keys,
-                upload_signatures, upload_signing_keys,
+                upload_signatures::{self, v3::FailureErrorCode},
+                upload_signing_keys,
             },
             uiaa::{AuthFlow, AuthType, UiaaInfo},
         },
         federation,
     },
     serde::Raw,
-    DeviceKeyAlgorithm, OwnedDeviceId, OwnedUserId, UserId,
+    CanonicalJsonObject, DeviceKeyAlgorithm, OwnedDeviceId, OwnedUserId,
+    UserId,
 };
 use serde_json::json;
 use tracing::debug;
@@ -30,6 +33,7 @@ use crate::{services, utils, Ar, Error, Ra, Result};
 /// Publish end-to-end encryption keys for the sender device.
 ///
 /// - Adds one time keys
+/// - Replaces fallback keys
 /// - If there are no device keys yet: Adds device keys (TODO: merge with
 ///   existing keys?)
 pub(crate) async fn upload_keys_route(
@@ -48,6 +52,15 @@ pub(crate) async fn upload_keys_route(
         )?;
     }
 
+    for (key_key, key_value) in &body.fallback_keys {
+        services().users.add_fallback_key(
+            sender_user,
+            sender_device,
+            key_key,
+            key_value,
+        )?;
+    }
+
     if let Some(device_keys) = &body.device_keys {
         // TODO: merge this and the existing event?
         // This check is needed to assure that signatures are kept
@@ -75,7 +88,8 @@ pub(crate) async fn upload_keys_route(
 ///
 /// Get end-to-end encryption keys for the given users.
 ///
-/// - Always fetches users from other servers over federation
+/// - Fetches users from other servers over federation, batched per server,
+///   unless their complete device list is cached and hasn't changed since
 /// - Gets master keys, self-signing keys, user signing keys and device keys.
 /// - The master and self-signing keys contain signatures that the user is
 ///   allowed to see
@@ -127,12 +141,10 @@ pub(crate) async fn upload_signing_keys_route(
     };
 
     if let Some(auth) = &body.auth {
-        let (worked, uiaainfo) = services().uiaa.try_auth(
-            sender_user,
-            sender_device,
-            auth,
-            &uiaainfo,
-        )?;
+        let (worked, uiaainfo) = services()
+            .uiaa
+            .try_auth(sender_user, sender_device, auth, &uiaainfo)
+            .await?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
@@ -162,51 +174,97 @@ pub(crate) async fn upload_signing_keys_route(
 /// # `POST /_matrix/client/r0/keys/signatures/upload`
 ///
 /// Uploads end-to-end key signatures from the sender user.
+///
+/// - Signatures must be made by one of the sender's cross-signing keys or
+///   devices, and must be valid for the key as it is stored on the server
+/// - Keys with missing or invalid signatures are reported as
+///   `M_INVALID_SIGNATURE` failures and unknown keys as `M_NOT_FOUND` failures,
+///   the other keys of the request are still signed
+/// - Signatures of the sender's own keys are reported to all users sharing an
+///   encrypted room with them by `/keys/changes` and sync, and are sent to
+///   their servers as `m.device_list_update` or `m.signing_key_update` EDUs
+/// - Signatures of other users' keys are only reported to the sender's own
+///   devices
 pub(crate) async fn upload_signatures_route(
     body: Ar<upload_signatures::v3::Request>,
 ) -> Result<Ra<upload_signatures::v3::Response>> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    let mut failures = BTreeMap::new();
+
     for (user_id, keys) in &body.signed_keys {
         for (key_id, key) in keys {
-            let key = serde_json::to_value(key).map_err(|_| {
-                Error::BadRequest(ErrorKind::InvalidParam, "Invalid key JSON")
-            })?;
-
-            for signature in key
-                .get("signatures")
-                .ok_or(Error::BadRequest(
-                    ErrorKind::InvalidParam,
-                    "Missing signatures field.",
-                ))?
-                .get(sender_user.to_string())
-                .ok_or(Error::BadRequest(
-                    ErrorKind::InvalidParam,
-                    "Invalid user in signatures field.",
-                ))?
-                .as_object()
-                .ok_or(Error::BadRequest(
-                    ErrorKind::InvalidParam,
-                    "Invalid signature.",
-                ))?
-                .clone()
-            {
-                // Signature validation?
-                let signature = (
-                    signature.0,
-                    signature
-                        .1
-                        .as_str()
-                        .ok_or(Error::BadRequest(
-                            ErrorKind::InvalidParam,
-                            "Invalid signature value.",
-                        ))?
-                        .to_owned(),
+            // Keys that were already signed stay signed, so problems with one
+            // key are reported as failures instead of failing the request
+            let mut fail = |errcode: FailureErrorCode, error: String| {
+                failures
+                    .entry(user_id.clone())
+                    .or_insert_with(BTreeMap::new)
+                    .insert(
+                        key_id.clone(),
+                        upload_signatures::v3::Failure {
+                            errcode,
+                            error,
+                        },
+                    );
+            };
+
+            let Some(signatures) = serde_json::to_value(key)
+                .ok()
+                .as_ref()
+                .and_then(|key| key.get("signatures"))
+                .and_then(|signatures| signatures.get(sender_user.as_str()))
+                .and_then(serde_json::Value::as_object)
+                .cloned()
+            else {
+                fail(
+                    FailureErrorCode::InvalidSignature,
+                    format!("No signatures by {sender_user}"),
+                );
+                continue;
+            };
+
+            let mut signed_key_id = user_id.as_bytes().to_vec();
+            signed_key_id.push(0xFF);
+            signed_key_id.extend_from_slice(key_id.as_bytes());
+            // Device keys and cross-signing keys are stored in the same tree
+            let Some(signed_key) = services().users.get_key(
+                &signed_key_id,
+                None,
+                user_id,
+                &|_| false,
+            )?
+            else {
+                fail(
+                    FailureErrorCode::from("M_NOT_FOUND"),
+                    "Tried to sign nonexistent key".to_owned(),
                 );
+                continue;
+            };
+            let signed_key = serde_json::from_str::<CanonicalJsonObject>(
+                signed_key.json().get(),
+            )
+            .map_err(|_| Error::bad_database("Invalid key in database."))?;
+
+            for (signing_key_id, signature) in &signatures {
+                let public_key =
+                    signing_public_key(sender_user, signing_key_id)?;
+                let Some(signature) = signature.as_str().filter(|signature| {
+                    public_key.as_ref().is_some_and(|public_key| {
+                        verify_key_signature(&signed_key, public_key, signature)
+                    })
+                }) else {
+                    fail(
+                        FailureErrorCode::InvalidSignature,
+                        format!("Invalid signature by {signing_key_id}"),
+                    );
+                    continue;
+                };
+
                 services().users.sign_key(
                     user_id,
                     key_id,
-                    signature,
+                    (signing_key_id.clone(), signature.to_owned()),
                     sender_user,
                 )?;
             }
@@ -214,11 +272,75 @@ pub(crate) async fn upload_signatures_route(
     }
 
     Ok(Ra(upload_signatures::v3::Response {
-        // TODO: integrate
-        failures: BTreeMap::new(),
+        failures,
     }))
 }
 
+/// Looks up the base64-encoded public key of one of `user_id`'s cross-signing
+/// keys or devices by its key ID, e.g. `ed25519:DEVICEID`.
+fn signing_public_key(
+    user_id: &UserId,
+    signing_key_id: &str,
+) -> Result<Option<String>> {
+    let Some((algorithm, name)) = signing_key_id.split_once(':') else {
+        return Ok(None);
+    };
+    if algorithm != "ed25519" {
+        return Ok(None);
+    }
+
+    let cross_signing_keys = [
+        services().users.get_master_key(None, user_id, &|_| false)?,
+        services().users.get_self_signing_key(None, user_id, &|_| false)?,
+        services().users.get_user_signing_key(user_id)?,
+    ];
+    let device_keys = services()
+        .users
+        .get_device_keys(user_id, name.into())?
+        .map(Raw::into_json);
+
+    Ok(cross_signing_keys
+        .into_iter()
+        .flatten()
+        .map(Raw::into_json)
+        .chain(device_keys)
+        .find_map(|key| {
+            let key =
+                serde_json::from_str::<serde_json::Value>(key.get()).ok()?;
+            key.get("keys")?
+                .get(signing_key_id)?
+                .as_str()
+                .map(ToOwned::to_owned)
+        }))
+}
+
+/// Checks that `signature` is a valid ed25519 signature of the signed JSON
+/// `object` by the base64-encoded `public_key`.
+fn verify_key_signature(
+    object: &CanonicalJsonObject,
+    public_key: &str,
+    signature: &str,
+) -> bool {
+    let Ok(canonical_json) = ruma::signatures::canonical_json(object) else {
+        return false;
+    };
+    let decode = |s: &str| {
+        general_purpose::STANDARD_NO_PAD.decode(s.trim_end_matches('='))
+    };
+    let (Ok(public_key), Ok(signature)) =
+        (decode(public_key), decode(signature))
+    else {
+        return false;
+    };
+
+    ring::signature::UnparsedPublicKey::new(
+        &ring::signature::ED25519,
+        public_key,
+    )
+    .verify(canonical_json.as_bytes(), &signature)
+    .is_ok()
+}
+
 /// # `POST /_matrix/client/r0/keys/changes`
 ///
 /// Gets a list of users who have updated their device identity keys since the
@@ -301,6 +423,26 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool>(
         let user_id: &UserId = user_id;
 
         if user_id.server_name() != services().globals.server_name() {
+            let cached = device_ids
+                .is_empty()
+                .then(|| services().users.cached_remote_device_keys(user_id))
+                .flatten();
+            if let Some(cached) = cached {
+                device_keys.insert(user_id.to_owned(), cached.device_keys);
+                if let Some(self_signing_key) = cached.self_signing_key {
+                    self_signing_keys
+                        .insert(user_id.to_owned(), self_signing_key);
+                }
+                if let Some(master_key) = services().users.get_master_key(
+                    sender_user,
+                    user_id,
+                    &allowed_signatures,
+                )? {
+                    master_keys.insert(user_id.to_owned(), master_key);
+                }
+                continue;
+            }
+
             get_over_federation
                 .entry(user_id.server_name())
                 .or_insert_with(Vec::new)
@@ -396,6 +538,11 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool>(
         }
     };
 
+    // Device list updates received while the queries are in progress make
+    // their responses unsuitable for caching
+    let device_list_generation =
+        services().users.remote_device_list_generation();
+
     let mut futures: FuturesUnordered<_> = get_over_federation
         .into_iter()
         .map(|(server, vec)| async move {
@@ -419,6 +566,7 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool>(
                     debug!(%server, %tries, ?remaining, "Backing off from server");
                     return (
                         server,
+                        Vec::new(),
                         Err(Error::BadServerResponse(
                             "bad query, still backing off",
                         )),
@@ -426,6 +574,13 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool>(
                 }
             }
 
+            // Only complete device lists can be cached
+            let complete_users: Vec<_> = vec
+                .iter()
+                .filter(|(_, keys)| keys.is_empty())
+                .map(|(user_id, _)| *user_id)
+                .collect();
+
             let mut device_keys_input_fed = BTreeMap::new();
             for (user_id, keys) in vec {
                 device_keys_input_fed.insert(user_id.to_owned(), keys.clone());
@@ -434,6 +589,7 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool>(
             // <https://github.com/rust-lang/rust/issues/70142>
             (
                 server,
+                complete_users,
                 tokio::time::timeout(
                     Duration::from_secs(25),
                     services().sending.send_federation_request(
@@ -450,7 +606,7 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool>(
         })
         .collect();
 
-    while let Some((server, response)) = futures.next().await {
+    while let Some((server, complete_users, response)) = futures.next().await {
         let response = match response {
             Ok(response) => response,
             Err(error) => {
@@ -489,6 +645,15 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool>(
             master_keys.insert(user, raw);
         }
 
+        for user_id in complete_users {
+            services().users.cache_remote_device_keys(
+                user_id,
+                device_list_generation,
+                response.device_keys.get(user_id).cloned().unwrap_or_default(),
+                response.self_signing_keys.get(user_id).cloned(),
+            );
+        }
+
         self_signing_keys.extend(response.self_signing_keys);
         device_keys.extend(response.device_keys);
     }
@@ -542,11 +707,22 @@ pub(crate) async fn claim_keys_helper(
 
         let mut container = BTreeMap::new();
         for (device_id, key_algorithm) in map {
-            if let Some(one_time_keys) = services().users.take_one_time_key(
+            // Fallback keys are only handed out once all one-time keys are
+            // exhausted
+            let one_time_keys = match services().users.take_one_time_key(
                 user_id,
                 device_id,
                 key_algorithm,
             )? {
+                Some(one_time_keys) => Some(one_time_keys),
+                None => services().users.take_fallback_key(
+                    user_id,
+                    device_id,
+                    key_algorithm,
+                )?,
+            };
+
+            if let Some(one_time_keys) = one_time_keys {
                 let mut c = BTreeMap::new();
                 c.insert(one_time_keys.0, one_time_keys.1);
                 container.insert(device_id.clone(), c);
@@ -595,3 +771,96 @@ pub(crate) async fn claim_keys_helper(
         one_time_keys,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose, Engine as _};
+    use ruma::{
+        signatures::{sign_json, Ed25519KeyPair},
+        CanonicalJsonObject, CanonicalJsonValue,
+    };
+    use serde_json::json;
+
+    use super::verify_key_signature;
+
+    fn device_keys() -> CanonicalJsonObject {
+        serde_json::from_value(json!({
+            "user_id": "@alice:remote.example.com",
+            "device_id": "ALICEDEVICE",
+            "algorithms": ["m.olm.v1.curve25519-aes-sha2"],
+            "keys": {
+                "curve25519:ALICEDEVICE": "curve25519+key",
+                "ed25519:ALICEDEVICE": "ed25519+key",
+            },
+        }))
+        .unwrap()
+    }
+
+    /// Signs `object` with a new self-signing key, returning its public key
+    /// and the signature.
+    fn self_sign(object: &mut CanonicalJsonObject) -> (String, String) {
+        let key_pair = Ed25519KeyPair::from_der(
+            &Ed25519KeyPair::generate().unwrap(),
+            "SELFSIGNING".to_owned(),
+        )
+        .unwrap();
+        sign_json("@alice:remote.example.com", &key_pair, object).unwrap();
+
+        let Some(CanonicalJsonValue::Object(signatures)) =
+            object.get("signatures")
+        else {
+            panic!("object should be signed");
+        };
+        let Some(CanonicalJsonValue::Object(user_signatures)) =
+            signatures.get("@alice:remote.example.com")
+        else {
+            panic!("object should be signed by the user");
+        };
+        let Some(CanonicalJsonValue::String(signature)) =
+            user_signatures.get("ed25519:SELFSIGNING")
+        else {
+            panic!("object should be signed by the self-signing key");
+        };
+
+        (
+            general_purpose::STANDARD_NO_PAD.encode(key_pair.public_key()),
+            signature.clone(),
+        )
+    }
+
+    /// A remote user's device cross-signed by their self-signing key, as it
+    /// would be returned by `/keys/query` over federation, is verified.
+    #[test]
+    fn cross_signed_device_verifies() {
+        let mut keys = device_keys();
+        let (public_key, signature) = self_sign(&mut keys);
+
+        assert!(verify_key_signature(&keys, &public_key, &signature));
+
+        // Only the signed content matters, not the signatures or unsigned data
+        keys.insert(
+            "unsigned".to_owned(),
+            CanonicalJsonValue::Object(CanonicalJsonObject::new()),
+        );
+        assert!(verify_key_signature(&keys, &public_key, &signature));
+        assert!(verify_key_signature(&device_keys(), &public_key, &signature));
+    }
+
+    #[test]
+    fn invalid_signatures_are_rejected() {
+        let mut keys = device_keys();
+        let (public_key, signature) = self_sign(&mut keys);
+
+        let mut tampered = device_keys();
+        tampered.insert(
+            "device_id".to_owned(),
+            CanonicalJsonValue::String("MALLORYDEVICE".to_owned()),
+        );
+        assert!(!verify_key_signature(&tampered, &public_key, &signature));
+
+        let (other_public_key, _) = self_sign(&mut device_keys());
+        assert!(!verify_key_signature(&keys, &other_public_key, &signature));
+
+        assert!(!verify_key_signature(&keys, &public_key, "not base64!"));
+    }
+}
@@ -1,24 +1,31 @@
-use std::time::Duration;
+use std::{ops::Bound, time::Duration};
 
 use axum::response::IntoResponse;
+use axum_extra::{
+    headers::{ContentRange, HeaderMapExt, Range},
+    TypedHeader,
+};
 use http::{
-    header::{CONTENT_DISPOSITION, CONTENT_SECURITY_POLICY, CONTENT_TYPE},
-    HeaderName, HeaderValue,
+    header::{
+        ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_SECURITY_POLICY,
+        CONTENT_TYPE, CROSS_ORIGIN_RESOURCE_POLICY,
+    },
+    HeaderName, HeaderValue, StatusCode,
 };
 use phf::{phf_set, Set};
 use ruma::api::client::{
     error::ErrorKind,
     media::{
         create_content, get_content, get_content_as_filename,
-        get_content_thumbnail, get_media_config,
+        get_content_thumbnail::{self, v3::Method},
+        get_media_config, get_media_preview,
     },
 };
 use tracing::error;
 
+use super::MXC_LENGTH;
 use crate::{service::media::FileMeta, services, utils, Ar, Error, Ra, Result};
 
-const MXC_LENGTH: usize = 32;
-
 /// `Content-Type`s that can be rendered inline in a browser without risking XSS
 ///
 /// Cargo-culted from Synapse. Note that SVG can contain inline JavaScript.
@@ -111,6 +118,84 @@ fn set_header_or_panic(
     response.headers_mut().insert(header_name, header_value);
 }
 
+/// Serves a `Range` request for a local file with `206 Partial Content`, or
+/// `416 Range Not Satisfiable` if none of the requested ranges exist.
+///
+/// Only the first satisfiable range is served since multipart responses aren't
+/// supported. Returns `None` if the file isn't stored locally.
+async fn get_content_range(
+    mxc: String,
+    range: &Range,
+    filename: Option<&str>,
+) -> Result<Option<axum::response::Response>> {
+    let Some(file) = services()
+        .media
+        .get_range(mxc, |len| {
+            let last = len.checked_sub(1)?;
+            let (start, end) = range.satisfiable_ranges(len).next()?;
+            let start = match start {
+                Bound::Included(start) => start,
+                Bound::Excluded(start) => start.checked_add(1)?,
+                Bound::Unbounded => 0,
+            };
+            let end = match end {
+                Bound::Included(end) => end.min(last),
+                Bound::Excluded(end) => end.checked_sub(1)?.min(last),
+                Bound::Unbounded => last,
+            };
+            (start <= end).then_some((start, end))
+        })
+        .await?
+    else {
+        return Ok(None);
+    };
+
+    let Some(((start, end), content)) = file.range else {
+        let mut response = StatusCode::RANGE_NOT_SATISFIABLE.into_response();
+        response
+            .headers_mut()
+            .typed_insert(ContentRange::unsatisfied_bytes(file.len));
+        return Ok(Some(response));
+    };
+
+    let mut response = (StatusCode::PARTIAL_CONTENT, content).into_response();
+    response.headers_mut().typed_insert(
+        ContentRange::bytes(start..=end, file.len)
+            .expect("range should be within the file"),
+    );
+
+    if let Some(content_type) =
+        file.content_type.as_deref().and_then(|x| HeaderValue::from_str(x).ok())
+    {
+        response.headers_mut().insert(CONTENT_TYPE, content_type);
+    }
+    if let Ok(content_disposition) = HeaderValue::from_str(
+        &content_disposition_for(file.content_type.as_deref(), filename),
+    ) {
+        response.headers_mut().insert(CONTENT_DISPOSITION, content_disposition);
+    }
+    response.headers_mut().insert(
+        CROSS_ORIGIN_RESOURCE_POLICY,
+        HeaderValue::from_static("cross-origin"),
+    );
+
+    Ok(Some(response))
+}
+
+/// Adds the headers all responses of the download endpoints get
+fn set_download_headers(response: &mut axum::response::Response) {
+    set_header_or_panic(
+        response,
+        CONTENT_SECURITY_POLICY,
+        content_security_policy(),
+    );
+    set_header_or_panic(
+        response,
+        ACCEPT_RANGES,
+        HeaderValue::from_static("bytes"),
+    );
+}
+
 /// # `GET /_matrix/media/r0/config`
 ///
 /// Returns max upload size.
@@ -118,19 +203,40 @@ pub(crate) async fn get_media_config_route(
     _body: Ar<get_media_config::v3::Request>,
 ) -> Result<Ra<get_media_config::v3::Response>> {
     Ok(Ra(get_media_config::v3::Response {
-        upload_size: services().globals.max_request_size().into(),
+        upload_size: services().globals.max_media_upload_size().into(),
     }))
 }
 
+/// # `GET /_matrix/media/r0/preview_url`
+///
+/// Returns the OpenGraph properties of a URL.
+///
+/// - Images on the page are downloaded into local media
+pub(crate) async fn get_media_preview_route(
+    body: Ar<get_media_preview::v3::Request>,
+) -> Result<Ra<get_media_preview::v3::Response>> {
+    let url = reqwest::Url::parse(&body.url).map_err(|_| {
+        Error::BadRequest(ErrorKind::InvalidParam, "URL is invalid.")
+    })?;
+
+    let preview = services().url_preview.get(url).await?;
+
+    Ok(Ra(get_media_preview::v3::Response::from_serialize(&*preview)
+        .expect("preview should be serializable")))
+}
+
 /// # `POST /_matrix/media/r0/upload`
 ///
 /// Permanently save media in the server.
 ///
 /// - Some metadata will be saved in the database
 /// - Media will be saved in the media/ directory
+/// - Fails if the upload would exceed the media quota of the user
 pub(crate) async fn create_content_route(
     body: Ar<create_content::v3::Request>,
 ) -> Result<Ra<create_content::v3::Response>> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
     let mxc = format!(
         "mxc://{}/{}",
         services().globals.server_name(),
@@ -139,8 +245,9 @@ pub(crate) async fn create_content_route(
 
     services()
         .media
-        .create(
+        .create_upload(
             mxc.clone(),
+            sender_user,
             body.filename
                 .as_ref()
                 .map(|filename| format!("inline; filename={filename}"))
@@ -156,14 +263,20 @@ pub(crate) async fn create_content_route(
     }))
 }
 
+/// Fetches media from a remote server and caches it
+///
+/// Fails without contacting the remote server if fetching from it failed
+/// recently, and with `M_TOO_LARGE` if the file exceeds the configured limit.
 pub(crate) async fn get_remote_content(
     mxc: &str,
     server_name: &ruma::ServerName,
     media_id: String,
 ) -> Result<get_content::v3::Response, Error> {
+    services().media.check_remote_failures(mxc, server_name)?;
+
     let content_response = services()
         .sending
-        .send_federation_request(
+        .send_limited_federation_request(
             server_name,
             get_content::v3::Request {
                 allow_remote: false,
@@ -172,8 +285,12 @@ pub(crate) async fn get_remote_content(
                 timeout_ms: Duration::from_secs(20),
                 allow_redirect: false,
             },
+            Some(services().globals.config.remote_media.max_file_size),
         )
-        .await?;
+        .await
+        .inspect_err(|error| {
+            services().media.record_remote_failure(mxc, server_name, error);
+        })?;
 
     services()
         .media
@@ -184,6 +301,7 @@ pub(crate) async fn get_remote_content(
             &content_response.file,
         )
         .await?;
+    services().media.track_remote_cached(content_response.file.len());
 
     Ok(get_content::v3::Response {
         file: content_response.file,
@@ -198,17 +316,23 @@ pub(crate) async fn get_remote_content(
 /// Load media from our server or over federation.
 ///
 /// - Only allows federation if `allow_remote` is true
+/// - Supports `Range` requests for local media
 pub(crate) async fn get_content_route(
+    range: Option<TypedHeader<Range>>,
     body: Ar<get_content::v3::Request>,
 ) -> Result<axum::response::Response> {
+    if let Some(TypedHeader(range)) = range {
+        let mxc = format!("mxc://{}/{}", body.server_name, body.media_id);
+        if let Some(mut r) = get_content_range(mxc, &range, None).await? {
+            set_download_headers(&mut r);
+            return Ok(r);
+        }
+    }
+
     get_content_route_ruma(body).await.map(|x| {
         let mut r = Ra(x).into_response();
 
-        set_header_or_panic(
-            &mut r,
-            CONTENT_SECURITY_POLICY,
-            content_security_policy(),
-        );
+        set_download_headers(&mut r);
 
         r
     })
@@ -259,17 +383,25 @@ async fn get_content_route_ruma(
 /// Load media from our server or over federation, permitting desired filename.
 ///
 /// - Only allows federation if `allow_remote` is true
+/// - Supports `Range` requests for local media
 pub(crate) async fn get_content_as_filename_route(
+    range: Option<TypedHeader<Range>>,
     body: Ar<get_content_as_filename::v3::Request>,
 ) -> Result<axum::response::Response> {
+    if let Some(TypedHeader(range)) = range {
+        let mxc = format!("mxc://{}/{}", body.server_name, body.media_id);
+        if let Some(mut r) =
+            get_content_range(mxc, &range, Some(body.filename.as_str())).await?
+        {
+            set_download_headers(&mut r);
+            return Ok(r);
+        }
+    }
+
     get_content_as_filename_route_ruma(body).await.map(|x| {
         let mut r = Ra(x).into_response();
 
-        set_header_or_panic(
-            &mut r,
-            CONTENT_SECURITY_POLICY,
-            content_security_policy(),
-        );
+        set_download_headers(&mut r);
 
         r
     })
@@ -367,8 +499,9 @@ async fn get_content_thumbnail_route_ruma(
                 Error::BadRequest(ErrorKind::InvalidParam, "Width is invalid.")
             })?,
             body.height.try_into().map_err(|_| {
-                Error::BadRequest(ErrorKind::InvalidParam, "Width is invalid.")
+                Error::BadRequest(ErrorKind::InvalidParam, "Height is invalid.")
             })?,
+            body.method.as_ref().unwrap_or(&Method::Scale),
         )
         .await?
     {
@@ -380,9 +513,11 @@ async fn get_content_thumbnail_route_ruma(
     } else if &*body.server_name != services().globals.server_name()
         && body.allow_remote
     {
+        services().media.check_remote_failures(&mxc, &body.server_name)?;
+
         let get_thumbnail_response = services()
             .sending
-            .send_federation_request(
+            .send_limited_federation_request(
                 &body.server_name,
                 get_content_thumbnail::v3::Request {
                     allow_remote: false,
@@ -394,8 +529,16 @@ async fn get_content_thumbnail_route_ruma(
                     timeout_ms: Duration::from_secs(20),
                     allow_redirect: false,
                 },
+                Some(services().globals.config.remote_media.max_file_size),
             )
-            .await?;
+            .await
+            .inspect_err(|error| {
+                services().media.record_remote_failure(
+                    &mxc,
+                    &body.server_name,
+                    error,
+                );
+            })?;
 
         services()
             .media
@@ -408,6 +551,7 @@ async fn get_content_thumbnail_route_ruma(
                 &get_thumbnail_response.file,
             )
             .await?;
+        services().media.track_remote_cached(get_thumbnail_response.file.len());
 
         Ok(get_content_thumbnail::v3::Response {
             file: get_thumbnail_response.file,
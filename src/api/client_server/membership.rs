@@ -8,6 +8,7 @@ use ruma::{
     api::{
         client::{
             error::ErrorKind,
+            knock::knock_room,
             membership::{
                 ban_user, forget_room, get_member_events, invite_user,
                 join_room_by_id, join_room_by_id_or_alias, joined_members,
@@ -15,16 +16,18 @@ use ruma::{
                 ThirdPartySigned,
             },
         },
-        federation::{self, membership::create_invite},
+        federation::{
+            self,
+            knock::{create_knock_event_template, send_knock},
+            membership::create_invite,
+        },
     },
     canonical_json::to_canonical_value,
     events::{
-        room::{
-            join_rules::{AllowRule, JoinRule, RoomJoinRulesEventContent},
-            member::{MembershipState, RoomMemberEventContent},
-        },
-        StateEventType, TimelineEventType,
+        room::member::{MembershipState, RoomMemberEventContent},
+        AnyStrippedStateEvent, StateEventType, TimelineEventType,
     },
+    serde::Raw,
     state_res, CanonicalJsonObject, CanonicalJsonValue, EventId,
     MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, OwnedServerName,
     OwnedUserId, RoomId, RoomVersionId, UserId,
@@ -36,10 +39,12 @@ use tracing::{debug, error, info, warn};
 use super::get_alias_helper;
 use crate::{
     service::{
-        globals::SigningKeys,
+        globals::{marker, SigningKeys},
         pdu::{gen_event_id_canonical_json, PduBuilder},
     },
-    services, utils, Ar, Error, PduEvent, Ra, Result,
+    services,
+    utils::{self, on_demand_hashmap::KeyToken},
+    Ar, Error, PduEvent, Ra, Result,
 };
 
 /// # `POST /_matrix/client/r0/rooms/{roomId}/join`
@@ -156,6 +161,46 @@ pub(crate) async fn join_room_by_id_or_alias_route(
     }))
 }
 
+/// # `POST /_matrix/client/v3/knock/{roomIdOrAlias}`
+///
+/// Tries to knock on a room to ask for an invite.
+///
+/// - If the server knowns about this room: creates the knock event locally
+/// - If the server does not know about the room: asks other servers over
+///   federation
+pub(crate) async fn knock_room_route(
+    body: Ar<knock_room::v3::Request>,
+) -> Result<Ra<knock_room::v3::Response>> {
+    let sender_user =
+        body.sender_user.as_deref().expect("user is authenticated");
+    let body = body.body;
+
+    let (servers, room_id) = match OwnedRoomId::try_from(body.room_id_or_alias)
+    {
+        Ok(room_id) => {
+            let mut servers = body.server_name.clone();
+            servers.push(
+                room_id
+                    .server_name()
+                    .expect("Room IDs should always have a server name")
+                    .into(),
+            );
+
+            (servers, room_id)
+        }
+        Err(room_alias) => {
+            let response = get_alias_helper(room_alias).await?;
+
+            (response.servers, response.room_id)
+        }
+    };
+
+    knock_room_by_id_helper(sender_user, &room_id, body.reason, &servers)
+        .await?;
+
+    Ok(Ra(knock_room::v3::Response::new(room_id)))
+}
+
 /// # `POST /_matrix/client/r0/rooms/{roomId}/leave`
 ///
 /// Tries to leave the sender user from a room.
@@ -524,74 +569,20 @@ async fn join_room_by_id_helper(
     {
         info!("We can join locally");
 
-        let join_rules_event = services().rooms.state_accessor.room_state_get(
-            room_id,
-            &StateEventType::RoomJoinRules,
-            "",
-        )?;
-
-        let join_rules_event_content: Option<RoomJoinRulesEventContent> =
-            join_rules_event
-                .as_ref()
-                .map(|join_rules_event| {
-                    serde_json::from_str(join_rules_event.content.get())
-                        .map_err(|error| {
-                            warn!(%error, "Invalid join rules event");
-                            Error::bad_database(
-                                "Invalid join rules event in db.",
-                            )
-                        })
-                })
-                .transpose()?;
-
-        let restriction_rooms = match join_rules_event_content {
-            Some(RoomJoinRulesEventContent {
-                join_rule:
-                    JoinRule::Restricted(restricted)
-                    | JoinRule::KnockRestricted(restricted),
-            }) => restricted
-                .allow
-                .into_iter()
-                .filter_map(|a| match a {
-                    AllowRule::RoomMembership(r) => Some(r.room_id),
-                    _ => None,
-                })
-                .collect(),
-            _ => Vec::new(),
+        // If no local user can authorize a restricted join, another server
+        // in the room might be able to
+        let (restricted, authorized_user) = match services()
+            .rooms
+            .state_accessor
+            .restricted_join_authorizer(&room_token, sender_user)
+        {
+            Ok(authorized_user) => (authorized_user.is_some(), authorized_user),
+            Err(Error::BadRequest(ErrorKind::UnableToAuthorizeJoin, _)) => {
+                (true, None)
+            }
+            Err(error) => return Err(error),
         };
 
-        let authorized_user =
-            if restriction_rooms.iter().any(|restriction_room_id| {
-                services()
-                    .rooms
-                    .state_cache
-                    .is_joined(sender_user, restriction_room_id)
-                    .unwrap_or(false)
-            }) {
-                let mut auth_user = None;
-                for user in services()
-                    .rooms
-                    .state_cache
-                    .room_members(room_id)
-                    .filter_map(Result::ok)
-                    .collect::<Vec<_>>()
-                {
-                    if user.server_name() == services().globals.server_name()
-                        && services().rooms.state_accessor.user_can_invite(
-                            &room_token,
-                            &user,
-                            sender_user,
-                        )
-                    {
-                        auth_user = Some(user);
-                        break;
-                    }
-                }
-                auth_user
-            } else {
-                None
-            };
-
         let event = RoomMemberEventContent {
             membership: MembershipState::Join,
             displayname: services().users.displayname(sender_user)?,
@@ -629,7 +620,7 @@ async fn join_room_by_id_helper(
             Err(e) => e,
         };
 
-        if restriction_rooms.is_empty()
+        if !restricted
             && servers.iter().any(|s| *s != services().globals.server_name())
         {
             return Err(error);
@@ -709,7 +700,7 @@ async fn join_room_by_id_helper(
         // needs to be present
         ruma::signatures::hash_and_sign_event(
             services().globals.server_name().as_str(),
-            services().globals.keypair(),
+            &*services().globals.keypair(),
             &mut join_event_stub,
             &room_version_id,
         )
@@ -864,7 +855,7 @@ async fn join_room_by_id_helper(
         // needs to be present
         ruma::signatures::hash_and_sign_event(
             services().globals.server_name().as_str(),
-            services().globals.keypair(),
+            &*services().globals.keypair(),
             &mut join_event_stub,
             &room_version_id,
         )
@@ -1165,6 +1156,199 @@ async fn make_join_request(
     make_join_response_and_server
 }
 
+#[allow(clippy::too_many_lines)]
+async fn knock_room_by_id_helper(
+    sender_user: &UserId,
+    room_id: &RoomId,
+    reason: Option<String>,
+    servers: &[OwnedServerName],
+) -> Result<()> {
+    let room_token = services()
+        .globals
+        .roomid_mutex_state
+        .lock_key(room_id.to_owned())
+        .await;
+
+    let event = RoomMemberEventContent {
+        membership: MembershipState::Knock,
+        displayname: services().users.displayname(sender_user)?,
+        avatar_url: services().users.avatar_url(sender_user)?,
+        is_direct: None,
+        third_party_invite: None,
+        blurhash: services().users.blurhash(sender_user)?,
+        reason,
+        join_authorized_via_users_server: None,
+    };
+
+    // Ask a remote server if we are not participating in this room
+    if services()
+        .rooms
+        .state_cache
+        .server_in_room(services().globals.server_name(), room_id)?
+    {
+        info!("We can knock locally");
+
+        if !services().rooms.state_accessor.allows_knocking(room_id)? {
+            return Err(Error::BadRequest(
+                ErrorKind::forbidden(),
+                "This room does not allow knocking.",
+            ));
+        }
+
+        services()
+            .rooms
+            .timeline
+            .build_and_append_pdu(
+                PduBuilder {
+                    event_type: TimelineEventType::RoomMember,
+                    content: to_raw_value(&event)
+                        .expect("event is valid, we just created it"),
+                    unsigned: None,
+                    state_key: Some(sender_user.to_string()),
+                    redacts: None,
+                },
+                sender_user,
+                &room_token,
+            )
+            .await?;
+
+        return Ok(());
+    }
+
+    info!("Knocking remotely");
+    let (make_knock_response, remote_server) =
+        make_knock_request(sender_user, room_id, servers).await?;
+
+    let room_version_id = make_knock_response.room_version;
+    if !services().globals.supported_room_versions().contains(&room_version_id)
+    {
+        return Err(Error::BadServerResponse("Room version is not supported"));
+    }
+
+    let mut knock_event_stub: CanonicalJsonObject = serde_json::from_str(
+        make_knock_response.event.get(),
+    )
+    .map_err(|_| {
+        Error::BadServerResponse(
+            "Invalid make_knock event json received from server.",
+        )
+    })?;
+
+    knock_event_stub.insert(
+        "origin".to_owned(),
+        CanonicalJsonValue::String(
+            services().globals.server_name().as_str().to_owned(),
+        ),
+    );
+    knock_event_stub.insert(
+        "origin_server_ts".to_owned(),
+        CanonicalJsonValue::Integer(
+            utils::millis_since_unix_epoch()
+                .try_into()
+                .expect("Timestamp is valid js_int value"),
+        ),
+    );
+    knock_event_stub.insert(
+        "content".to_owned(),
+        to_canonical_value(event).expect("event is valid, we just created it"),
+    );
+
+    // We don't leave the event id in the pdu because that's only allowed in v1
+    // or v2 rooms
+    knock_event_stub.remove("event_id");
+
+    // In order to create a compatible ref hash (EventID) the `hashes` field
+    // needs to be present
+    ruma::signatures::hash_and_sign_event(
+        services().globals.server_name().as_str(),
+        &*services().globals.keypair(),
+        &mut knock_event_stub,
+        &room_version_id,
+    )
+    .expect("event is valid, we just created it");
+
+    // Generate event id
+    let event_id = EventId::parse(format!(
+        "${}",
+        ruma::signatures::reference_hash(&knock_event_stub, &room_version_id)
+            .expect("ruma can calculate reference hashes")
+    ))
+    .expect("ruma's reference hashes are valid event ids");
+
+    // Add event_id back
+    knock_event_stub.insert(
+        "event_id".to_owned(),
+        CanonicalJsonValue::String(event_id.as_str().to_owned()),
+    );
+
+    // It has enough fields to be called a proper event now
+    let knock_event = knock_event_stub;
+
+    let send_knock_response = services()
+        .sending
+        .send_federation_request(
+            &remote_server,
+            send_knock::v1::Request {
+                room_id: room_id.to_owned(),
+                event_id,
+                pdu: PduEvent::convert_to_outgoing_federation_event(
+                    knock_event,
+                ),
+            },
+        )
+        .await?;
+
+    services().rooms.state_cache.update_membership(
+        room_id,
+        sender_user,
+        MembershipState::Knock,
+        sender_user,
+        Some(send_knock_response.knock_room_state),
+        true,
+    )?;
+
+    drop(room_token);
+
+    Ok(())
+}
+
+async fn make_knock_request(
+    sender_user: &UserId,
+    room_id: &RoomId,
+    servers: &[OwnedServerName],
+) -> Result<(create_knock_event_template::v1::Response, OwnedServerName)> {
+    let mut make_knock_response_and_server = Err(Error::BadServerResponse(
+        "No server available to assist in knocking.",
+    ));
+
+    for remote_server in servers {
+        if remote_server == services().globals.server_name() {
+            continue;
+        }
+        info!(server = %remote_server, "Asking other server for make_knock");
+        let make_knock_response = services()
+            .sending
+            .send_federation_request(
+                remote_server,
+                create_knock_event_template::v1::Request {
+                    room_id: room_id.to_owned(),
+                    user_id: sender_user.to_owned(),
+                    ver: services().globals.supported_room_versions(),
+                },
+            )
+            .await;
+
+        make_knock_response_and_server =
+            make_knock_response.map(|r| (r, remote_server.clone()));
+
+        if make_knock_response_and_server.is_ok() {
+            break;
+        }
+    }
+
+    make_knock_response_and_server
+}
+
 async fn validate_and_add_event_id(
     pdu: &RawJsonValue,
     room_version: &RoomVersionId,
@@ -1264,7 +1448,6 @@ async fn validate_and_add_event_id(
     Ok((event_id, value))
 }
 
-#[allow(clippy::too_many_lines)]
 pub(crate) async fn invite_helper(
     sender_user: &UserId,
     user_id: &UserId,
@@ -1273,148 +1456,205 @@ pub(crate) async fn invite_helper(
     is_direct: bool,
 ) -> Result<()> {
     if user_id.server_name() != services().globals.server_name() {
-        let (pdu, pdu_json, invite_room_state) = {
+        let invite = {
             let room_token = services()
                 .globals
                 .roomid_mutex_state
                 .lock_key(room_id.to_owned())
                 .await;
 
-            let content = to_raw_value(&RoomMemberEventContent {
-                avatar_url: None,
-                displayname: None,
-                is_direct: Some(is_direct),
-                membership: MembershipState::Invite,
-                third_party_invite: None,
-                blurhash: None,
+            create_remote_invite(
+                sender_user,
+                user_id,
                 reason,
-                join_authorized_via_users_server: None,
-            })
-            .expect("member event is valid value");
-
-            let (pdu, pdu_json) =
-                services().rooms.timeline.create_hash_and_sign_event(
-                    PduBuilder {
-                        event_type: TimelineEventType::RoomMember,
-                        content,
-                        unsigned: None,
-                        state_key: Some(user_id.to_string()),
-                        redacts: None,
-                    },
-                    sender_user,
-                    &room_token,
-                )?;
+                is_direct,
+                &room_token,
+            )?
+        };
 
-            let invite_room_state =
-                services().rooms.state.calculate_invite_state(&pdu)?;
+        return send_remote_invite(invite).await;
+    }
 
-            drop(room_token);
+    if !services().rooms.state_cache.is_joined(sender_user, room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "You don't have permission to view this room.",
+        ));
+    }
 
-            (pdu, pdu_json, invite_room_state)
-        };
+    let room_token = services()
+        .globals
+        .roomid_mutex_state
+        .lock_key(room_id.to_owned())
+        .await;
 
-        let room_version_id =
-            services().rooms.state.get_room_version(room_id)?;
+    invite_local_user(sender_user, user_id, reason, is_direct, &room_token)
+        .await?;
 
-        let response = services()
-            .sending
-            .send_federation_request(
-                user_id.server_name(),
-                create_invite::v2::Request {
-                    room_id: room_id.to_owned(),
-                    event_id: (*pdu.event_id).to_owned(),
-                    room_version: room_version_id.clone(),
-                    event: PduEvent::convert_to_outgoing_federation_event(
-                        pdu_json.clone(),
-                    ),
-                    invite_room_state,
-                },
-            )
-            .await?;
+    drop(room_token);
 
-        let pub_key_map = RwLock::new(BTreeMap::new());
+    Ok(())
+}
 
-        // We do not add the event_id field to the pdu here because of signature
-        // and hashes checks
-        let Ok((event_id, value)) =
-            gen_event_id_canonical_json(&response.event, &room_version_id)
-        else {
-            // Event could not be converted to canonical json
-            return Err(Error::BadRequest(
-                ErrorKind::InvalidParam,
-                "Could not convert event to canonical json.",
-            ));
-        };
+/// An invite event for a user of another server that has been created but not
+/// yet sent to it
+pub(crate) struct RemoteInvite {
+    user_id: OwnedUserId,
+    pdu: PduEvent,
+    pdu_json: CanonicalJsonObject,
+    invite_room_state: Vec<Raw<AnyStrippedStateEvent>>,
+}
 
-        if *pdu.event_id != *event_id {
-            warn!(
-                server = %user_id.server_name(),
-                our_object = ?pdu_json,
-                their_object = ?value,
-                "Other server changed invite event, that's not allowed in the \
-                 spec",
-            );
-        }
+/// Creates the invite event for `user_id`, who is on another server
+///
+/// The event is only sent with [`send_remote_invite()`], which must happen
+/// after the room's state lock is released.
+pub(crate) fn create_remote_invite(
+    sender_user: &UserId,
+    user_id: &UserId,
+    reason: Option<String>,
+    is_direct: bool,
+    room_token: &KeyToken<OwnedRoomId, marker::State>,
+) -> Result<RemoteInvite> {
+    let content = to_raw_value(&RoomMemberEventContent {
+        avatar_url: None,
+        displayname: None,
+        is_direct: Some(is_direct),
+        membership: MembershipState::Invite,
+        third_party_invite: None,
+        blurhash: None,
+        reason,
+        join_authorized_via_users_server: None,
+    })
+    .expect("member event is valid value");
+
+    let (pdu, pdu_json) =
+        services().rooms.timeline.create_hash_and_sign_event(
+            PduBuilder {
+                event_type: TimelineEventType::RoomMember,
+                content,
+                unsigned: None,
+                state_key: Some(user_id.to_string()),
+                redacts: None,
+            },
+            sender_user,
+            room_token,
+        )?;
 
-        let origin: OwnedServerName = serde_json::from_value(
-            serde_json::to_value(value.get("origin").ok_or(
-                Error::BadRequest(
-                    ErrorKind::InvalidParam,
-                    "Event needs an origin field.",
-                ),
-            )?)
-            .expect("CanonicalJson is valid json value"),
-        )
-        .map_err(|_| {
-            Error::BadRequest(
-                ErrorKind::InvalidParam,
-                "Origin field is invalid.",
-            )
-        })?;
+    let invite_room_state =
+        services().rooms.state.calculate_invite_state(&pdu)?;
 
-        let pdu_id: Vec<u8> = services()
-            .rooms
-            .event_handler
-            .handle_incoming_pdu(
-                &origin,
-                &event_id,
-                room_id,
-                value,
-                true,
-                &pub_key_map,
-            )
-            .await?
-            .ok_or(Error::BadRequest(
-                ErrorKind::InvalidParam,
-                "Could not accept incoming PDU as timeline event.",
-            ))?;
+    Ok(RemoteInvite {
+        user_id: user_id.to_owned(),
+        pdu,
+        pdu_json,
+        invite_room_state,
+    })
+}
 
-        // Bind to variable because of lifetimes
-        let servers = services()
-            .rooms
-            .state_cache
-            .room_servers(room_id)
-            .filter_map(Result::ok)
-            .filter(|server| &**server != services().globals.server_name());
+/// Sends an invite created with [`create_remote_invite()`] to the invited
+/// user's server and appends the event it signed to the room
+pub(crate) async fn send_remote_invite(invite: RemoteInvite) -> Result<()> {
+    let RemoteInvite {
+        user_id,
+        pdu,
+        pdu_json,
+        invite_room_state,
+    } = invite;
+    let room_id = &pdu.room_id;
 
-        services().sending.send_pdu(servers, &pdu_id)?;
+    let room_version_id = services().rooms.state.get_room_version(room_id)?;
 
-        return Ok(());
-    }
+    let response = services()
+        .sending
+        .send_federation_request(
+            user_id.server_name(),
+            create_invite::v2::Request {
+                room_id: room_id.to_owned(),
+                event_id: (*pdu.event_id).to_owned(),
+                room_version: room_version_id.clone(),
+                event: PduEvent::convert_to_outgoing_federation_event(
+                    pdu_json.clone(),
+                ),
+                invite_room_state,
+            },
+        )
+        .await?;
 
-    if !services().rooms.state_cache.is_joined(sender_user, room_id)? {
+    let pub_key_map = RwLock::new(BTreeMap::new());
+
+    // We do not add the event_id field to the pdu here because of signature
+    // and hashes checks
+    let Ok((event_id, value)) =
+        gen_event_id_canonical_json(&response.event, &room_version_id)
+    else {
+        // Event could not be converted to canonical json
         return Err(Error::BadRequest(
-            ErrorKind::forbidden(),
-            "You don't have permission to view this room.",
+            ErrorKind::InvalidParam,
+            "Could not convert event to canonical json.",
         ));
+    };
+
+    if *pdu.event_id != *event_id {
+        warn!(
+            server = %user_id.server_name(),
+            our_object = ?pdu_json,
+            their_object = ?value,
+            "Other server changed invite event, that's not allowed in the \
+             spec",
+        );
     }
 
-    let room_token = services()
-        .globals
-        .roomid_mutex_state
-        .lock_key(room_id.to_owned())
-        .await;
+    let origin: OwnedServerName = serde_json::from_value(
+        serde_json::to_value(value.get("origin").ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event needs an origin field.",
+        ))?)
+        .expect("CanonicalJson is valid json value"),
+    )
+    .map_err(|_| {
+        Error::BadRequest(ErrorKind::InvalidParam, "Origin field is invalid.")
+    })?;
 
+    let pdu_id: Vec<u8> = services()
+        .rooms
+        .event_handler
+        .handle_incoming_pdu(
+            &origin,
+            &event_id,
+            room_id,
+            value,
+            true,
+            &pub_key_map,
+        )
+        .await?
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Could not accept incoming PDU as timeline event.",
+        ))?;
+
+    // Bind to variable because of lifetimes
+    let servers = services()
+        .rooms
+        .state_cache
+        .room_servers(room_id)
+        .filter_map(Result::ok)
+        .filter(|server| &**server != services().globals.server_name());
+
+    services().sending.send_pdu(servers, &pdu_id)?;
+
+    Ok(())
+}
+
+/// Appends the invite event for `user_id`, who is on this server, to the
+/// room
+pub(crate) async fn invite_local_user(
+    sender_user: &UserId,
+    user_id: &UserId,
+    reason: Option<String>,
+    is_direct: bool,
+    room_token: &KeyToken<OwnedRoomId, marker::State>,
+) -> Result<()> {
     services()
         .rooms
         .timeline
@@ -1437,17 +1677,18 @@ pub(crate) async fn invite_helper(
                 redacts: None,
             },
             sender_user,
-            &room_token,
+            room_token,
         )
         .await?;
 
-    drop(room_token);
-
     Ok(())
 }
 
-// Make a user leave all their joined rooms
-pub(crate) async fn leave_all_rooms(user_id: &UserId) -> Result<()> {
+/// Makes a user leave, reject or retract all rooms they are joined, invited to
+/// or knocked on.
+///
+/// Returns the number of rooms that were left.
+pub(crate) async fn leave_all_rooms(user_id: &UserId) -> Result<usize> {
     let all_rooms = services()
         .rooms
         .state_cache
@@ -1459,19 +1700,30 @@ pub(crate) async fn leave_all_rooms(user_id: &UserId) -> Result<()> {
                 .rooms_invited(user_id)
                 .map(|t| t.map(|(r, _)| r)),
         )
+        .chain(
+            services()
+                .rooms
+                .state_cache
+                .rooms_knocked(user_id)
+                .map(|t| t.map(|(r, _)| r)),
+        )
         .collect::<Vec<_>>();
 
+    let mut left = 0;
     for room_id in all_rooms {
         let Ok(room_id) = room_id else {
             continue;
         };
 
-        if let Err(error) = leave_room(user_id, &room_id, None).await {
-            warn!(%user_id, %room_id, %error, "Failed to leave room");
+        match leave_room(user_id, &room_id, None).await {
+            Ok(()) => left += 1,
+            Err(error) => {
+                warn!(%user_id, %room_id, %error, "Failed to leave room");
+            }
         }
     }
 
-    Ok(())
+    Ok(left)
 }
 
 #[tracing::instrument(skip(reason))]
@@ -1551,12 +1803,16 @@ pub(crate) async fn leave_room(
             .rooms
             .state_cache
             .invite_state(user_id, room_id)?
+            .map_or_else(
+                || services().rooms.state_cache.knock_state(user_id, room_id),
+                |s| Ok(Some(s)),
+            )?
             .map_or_else(
                 || services().rooms.state_cache.left_state(user_id, room_id),
                 |s| Ok(Some(s)),
             )?;
 
-        // We always drop the invite, we can't rely on other servers
+        // We always drop the invite or knock, we can't rely on other servers
         services().rooms.state_cache.update_membership(
             room_id,
             user_id,
@@ -1576,19 +1832,34 @@ async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<()> {
         "No server available to assist in leaving.",
     ));
 
-    let invite_state =
-        services().rooms.state_cache.invite_state(user_id, room_id)?.ok_or(
-            Error::BadRequest(ErrorKind::BadState, "User is not invited."),
-        )?;
+    let (stripped_state, mut servers) = if let Some(invite_state) =
+        services().rooms.state_cache.invite_state(user_id, room_id)?
+    {
+        (invite_state, HashSet::new())
+    } else if let Some(knock_state) =
+        services().rooms.state_cache.knock_state(user_id, room_id)?
+    {
+        // The server of the room can be asked to rescind the knock as well
+        let servers =
+            room_id.server_name().map(ToOwned::to_owned).into_iter().collect();
 
-    let servers: HashSet<_> = invite_state
-        .iter()
-        .filter_map(|event| serde_json::from_str(event.json().get()).ok())
-        .filter_map(|event: serde_json::Value| event.get("sender").cloned())
-        .filter_map(|sender| sender.as_str().map(ToOwned::to_owned))
-        .filter_map(|sender| UserId::parse(sender).ok())
-        .map(|user| user.server_name().to_owned())
-        .collect();
+        (knock_state, servers)
+    } else {
+        return Err(Error::BadRequest(
+            ErrorKind::BadState,
+            "User is not invited or knocking.",
+        ));
+    };
+
+    servers.extend(
+        stripped_state
+            .iter()
+            .filter_map(|event| serde_json::from_str(event.json().get()).ok())
+            .filter_map(|event: serde_json::Value| event.get("sender").cloned())
+            .filter_map(|sender| sender.as_str().map(ToOwned::to_owned))
+            .filter_map(|sender| UserId::parse(sender).ok())
+            .map(|user| user.server_name().to_owned()),
+    );
 
     for remote_server in servers {
         let make_leave_response = services()
@@ -1660,7 +1931,7 @@ async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<()> {
     // needs to be present
     ruma::signatures::hash_and_sign_event(
         services().globals.server_name().as_str(),
-        services().globals.keypair(),
+        &*services().globals.keypair(),
         &mut leave_event_stub,
         &room_version_id,
     )
@@ -8,10 +8,12 @@ use ruma::{
     events::{StateEventType, TimelineEventType},
     uint,
 };
+use tracing::warn;
 
+use super::{pdu_matches_filter, MAX_SCANNED_EVENTS};
 use crate::{
     service::{pdu::PduBuilder, rooms::timeline::PduCount},
-    services, utils, Ar, Error, Ra, Result,
+    services, utils, Ar, Error, PduEvent, Ra, Result,
 };
 
 /// # `PUT /_matrix/client/r0/rooms/{roomId}/send/{eventType}/{txnId}`
@@ -150,6 +152,7 @@ pub(crate) async fn get_message_events_route(
 
     let limit = body
         .limit
+        .min(body.filter.limit.unwrap_or(uint!(100)))
         .min(uint!(100))
         .try_into()
         .expect("0-100 should fit in usize");
@@ -160,14 +163,25 @@ pub(crate) async fn get_message_events_route(
 
     let mut lazy_loaded = HashSet::new();
 
+    // Messages of ignored users are hidden, their state events are still
+    // needed to make sense of the room
+    let ignored_users = services().account_data.ignored_users(sender_user)?;
+    let is_visible = |pdu: &PduEvent| {
+        pdu.state_key.is_some() || !ignored_users.contains(&pdu.sender)
+    };
+
     match body.dir {
         ruma::api::Direction::Forward => {
+            let mut last_scanned = None;
             let events_after: Vec<_> = services()
                 .rooms
                 .timeline
                 .pdus_after(sender_user, &body.room_id, from)?
-                .take(limit)
                 .filter_map(Result::ok)
+                .take_while(|&(k, _)| Some(k) != to)
+                .take(MAX_SCANNED_EVENTS)
+                .inspect(|&(k, _)| last_scanned = Some(k))
+                .filter(|(_, pdu)| pdu_matches_filter(&body.filter, pdu))
                 .filter(|(_, pdu)| {
                     services()
                         .rooms
@@ -179,7 +193,7 @@ pub(crate) async fn get_message_events_route(
                         )
                         .unwrap_or(false)
                 })
-                .take_while(|&(k, _)| Some(k) != to)
+                .take(limit)
                 .collect();
 
             for (_, event) in &events_after {
@@ -201,10 +215,11 @@ pub(crate) async fn get_message_events_route(
                 lazy_loaded.insert(event.sender.clone());
             }
 
-            next_token = events_after.last().map(|(count, _)| count).copied();
+            next_token = last_scanned;
 
             let events_after: Vec<_> = events_after
                 .into_iter()
+                .filter(|(_, pdu)| is_visible(pdu))
                 .map(|(_, pdu)| pdu.to_room_event())
                 .collect();
 
@@ -213,17 +228,25 @@ pub(crate) async fn get_message_events_route(
             resp.chunk = events_after;
         }
         ruma::api::Direction::Backward => {
-            services()
+            // Whatever is known locally is returned if backfilling fails
+            if let Err(error) = services()
                 .rooms
                 .timeline
-                .backfill_if_required(&body.room_id, from)
-                .await?;
+                .backfill_if_required(&body.room_id, from, limit)
+                .await
+            {
+                warn!(%error, "Failed to backfill room");
+            }
+            let mut last_scanned = None;
             let events_before: Vec<_> = services()
                 .rooms
                 .timeline
                 .pdus_until(sender_user, &body.room_id, from)?
-                .take(limit)
                 .filter_map(Result::ok)
+                .take_while(|&(k, _)| Some(k) != to)
+                .take(MAX_SCANNED_EVENTS)
+                .inspect(|&(k, _)| last_scanned = Some(k))
+                .filter(|(_, pdu)| pdu_matches_filter(&body.filter, pdu))
                 .filter(|(_, pdu)| {
                     services()
                         .rooms
@@ -235,7 +258,7 @@ pub(crate) async fn get_message_events_route(
                         )
                         .unwrap_or(false)
                 })
-                .take_while(|&(k, _)| Some(k) != to)
+                .take(limit)
                 .collect();
 
             for (_, event) in &events_before {
@@ -257,10 +280,11 @@ pub(crate) async fn get_message_events_route(
                 lazy_loaded.insert(event.sender.clone());
             }
 
-            next_token = events_before.last().map(|(count, _)| count).copied();
+            next_token = last_scanned;
 
             let events_before: Vec<_> = events_before
                 .into_iter()
+                .filter(|(_, pdu)| is_visible(pdu))
                 .map(|(_, pdu)| pdu.to_room_event())
                 .collect();
 
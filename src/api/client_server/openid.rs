@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+use ruma::{
+    api::client::{account::request_openid_token, error::ErrorKind},
+    authentication::TokenType,
+};
+
+use super::TOKEN_LENGTH;
+use crate::{services, utils, Ar, Error, Ra, Result};
+
+/// How long OpenID tokens can be exchanged for the user ID
+const OPENID_TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// # `POST /_matrix/client/r0/user/{userId}/openid/request_token`
+///
+/// Creates an OpenID token that other servers can exchange for the user ID
+/// of the sender user, e.g. to identify the user to an integration.
+pub(crate) async fn request_openid_token_route(
+    body: Ar<request_openid_token::v3::Request>,
+) -> Result<Ra<request_openid_token::v3::Response>> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    if sender_user != &body.user_id {
+        return Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "Not allowed to request OpenID tokens for other users.",
+        ));
+    }
+
+    let access_token = utils::random_string(TOKEN_LENGTH);
+    services().users.create_openid_token(
+        sender_user,
+        &access_token,
+        OPENID_TOKEN_LIFETIME,
+    )?;
+
+    Ok(Ra(request_openid_token::v3::Response {
+        access_token,
+        token_type: TokenType::Bearer,
+        matrix_server_name: services().globals.server_name().to_owned(),
+        expires_in: OPENID_TOKEN_LIFETIME,
+    }))
+}
@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use ruma::api::client::{
+    error::ErrorKind,
+    presence::{get_presence, set_presence},
+};
+
+use crate::{services, Ar, Error, Ra, Result};
+
+/// # `PUT /_matrix/client/r0/presence/{userId}/status`
+///
+/// Sets the presence state of the sender user.
+pub(crate) async fn set_presence_route(
+    body: Ar<set_presence::v3::Request>,
+) -> Result<Ra<set_presence::v3::Response>> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    if *sender_user != body.user_id {
+        return Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "You cannot set the presence of another user.",
+        ));
+    }
+
+    services()
+        .rooms
+        .edus
+        .presence
+        .set_presence(
+            sender_user,
+            body.presence.clone(),
+            body.status_msg.clone(),
+        )
+        .await?;
+
+    Ok(Ra(set_presence::v3::Response {}))
+}
+
+/// # `GET /_matrix/client/r0/presence/{userId}/status`
+///
+/// Gets the presence state of the given user.
+///
+/// - Only works if you share a room with the user
+pub(crate) async fn get_presence_route(
+    body: Ar<get_presence::v3::Request>,
+) -> Result<Ra<get_presence::v3::Response>> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let shares_room = *sender_user == body.user_id
+        || services()
+            .rooms
+            .user
+            .get_shared_rooms(vec![sender_user.clone(), body.user_id.clone()])?
+            .next()
+            .is_some();
+
+    let presence = if shares_room {
+        services().rooms.edus.presence.get_presence(&body.user_id)?
+    } else {
+        None
+    };
+
+    let Some(presence) = presence else {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Presence state for this user was not found",
+        ));
+    };
+
+    Ok(Ra(get_presence::v3::Response {
+        status_msg: presence.content.status_msg,
+        currently_active: presence.content.currently_active,
+        last_active_ago: presence
+            .content
+            .last_active_ago
+            .map(|millis| Duration::from_millis(millis.into())),
+        presence: presence.content.presence,
+    }))
+}
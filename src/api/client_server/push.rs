@@ -4,7 +4,7 @@ use ruma::{
         push::{
             delete_pushrule, get_pushers, get_pushrule, get_pushrule_actions,
             get_pushrule_enabled, get_pushrules_all, set_pusher, set_pushrule,
-            set_pushrule_actions, set_pushrule_enabled, RuleScope,
+            set_pushrule_actions, set_pushrule_enabled, PusherKind, RuleScope,
         },
     },
     events::{push_rules::PushRulesEvent, GlobalAccountDataEventType},
@@ -438,12 +438,19 @@ pub(crate) async fn get_pushers_route(
 ///
 /// Adds a pusher for the sender user.
 ///
+/// - Email pushers can only be added if SMTP is configured
 /// - TODO: Handle `append`
 pub(crate) async fn set_pushers_route(
     body: Ar<set_pusher::v3::Request>,
 ) -> Result<Ra<set_pusher::v3::Response>> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    if let set_pusher::v3::PusherAction::Post(data) = &body.action {
+        if let PusherKind::Email(_) = data.pusher.kind {
+            services().pusher.check_email_pusher(&data.pusher.ids.pushkey)?;
+        }
+    }
+
     services().pusher.set_pusher(sender_user, body.action.clone())?;
 
     Ok(Ra(set_pusher::v3::Response::default()))
@@ -5,11 +5,12 @@ use ruma::{
         error::ErrorKind, read_marker::set_read_marker, receipt::create_receipt,
     },
     events::{
-        receipt::{ReceiptThread, ReceiptType},
+        receipt::{ReceiptEvent, ReceiptThread, ReceiptType},
         RoomAccountDataEventType,
     },
     MilliSecondsSinceUnixEpoch,
 };
+use tracing::warn;
 
 use crate::{
     service::rooms::timeline::PduCount, services, Ar, Error, Ra, Result,
@@ -20,8 +21,10 @@ use crate::{
 /// Sets different types of read markers.
 ///
 /// - Updates fully-read account data event to `fully_read`
-/// - If `read_receipt` is set: Update private marker and public read receipt
-///   EDU
+/// - If `read_receipt` is set: Update public read receipt EDU
+/// - If `private_read_receipt` is set: Update private marker, which is never
+///   sent over federation
+/// - If either receipt is set: Reset notification counts
 pub(crate) async fn set_read_marker_route(
     body: Ar<set_read_marker::v3::Request>,
 ) -> Result<Ra<set_read_marker::v3::Response>> {
@@ -85,16 +88,18 @@ pub(crate) async fn set_read_marker_route(
         let mut receipt_content = BTreeMap::new();
         receipt_content.insert(event.to_owned(), receipts);
 
+        let event = ruma::events::receipt::ReceiptEvent {
+            content: ruma::events::receipt::ReceiptEventContent(
+                receipt_content,
+            ),
+            room_id: body.room_id.clone(),
+        };
         services().rooms.edus.read_receipt.readreceipt_update(
             sender_user,
             &body.room_id,
-            ruma::events::receipt::ReceiptEvent {
-                content: ruma::events::receipt::ReceiptEventContent(
-                    receipt_content,
-                ),
-                room_id: body.room_id.clone(),
-            },
+            event.clone(),
         )?;
+        forward_receipt(&event).await;
     }
 
     Ok(Ra(set_read_marker::v3::Response {}))
@@ -102,21 +107,76 @@ pub(crate) async fn set_read_marker_route(
 
 /// # `POST /_matrix/client/r0/rooms/{roomId}/receipt/{receiptType}/{eventId}`
 ///
-/// Sets private read marker and public read receipt EDU.
+/// Sets fully-read marker, private read marker or public read receipt EDU.
+///
+/// Private read markers are only stored locally and are never sent over
+/// federation, but they reset notification counts like public receipts.
 pub(crate) async fn create_receipt_route(
     body: Ar<create_receipt::v3::Request>,
 ) -> Result<Ra<create_receipt::v3::Response>> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    match &body.thread {
+        ReceiptThread::Unthreaded => {}
+        ReceiptThread::Main | ReceiptThread::Thread(_)
+            if matches!(
+                body.receipt_type,
+                create_receipt::v3::ReceiptType::FullyRead
+            ) =>
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Fully read markers can't be threaded.",
+            ));
+        }
+        ReceiptThread::Main => {}
+        ReceiptThread::Thread(thread_root) => {
+            if !services()
+                .rooms
+                .timeline
+                .get_pdu(thread_root)?
+                .is_some_and(|pdu| pdu.room_id == body.room_id)
+            {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Thread root is not an event in this room.",
+                ));
+            }
+        }
+        _ => {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Unsupported thread ID.",
+            ))
+        }
+    }
+
     if matches!(
         &body.receipt_type,
         create_receipt::v3::ReceiptType::Read
             | create_receipt::v3::ReceiptType::ReadPrivate
     ) {
-        services()
-            .rooms
-            .user
-            .reset_notification_counts(sender_user, &body.room_id)?;
+        match &body.thread {
+            ReceiptThread::Thread(thread_root) => {
+                services().rooms.user.reset_thread_notification_counts(
+                    sender_user,
+                    &body.room_id,
+                    thread_root,
+                )?;
+            }
+            ReceiptThread::Main => {
+                services().rooms.user.reset_main_notification_counts(
+                    sender_user,
+                    &body.room_id,
+                )?;
+            }
+            _ => {
+                services()
+                    .rooms
+                    .user
+                    .reset_notification_counts(sender_user, &body.room_id)?;
+            }
+        }
     }
 
     match body.receipt_type {
@@ -140,7 +200,7 @@ pub(crate) async fn create_receipt_route(
                 sender_user.clone(),
                 ruma::events::receipt::Receipt {
                     ts: Some(MilliSecondsSinceUnixEpoch::now()),
-                    thread: ReceiptThread::Unthreaded,
+                    thread: body.thread.clone(),
                 },
             );
             let mut receipts = BTreeMap::new();
@@ -149,16 +209,18 @@ pub(crate) async fn create_receipt_route(
             let mut receipt_content = BTreeMap::new();
             receipt_content.insert(body.event_id.clone(), receipts);
 
+            let event = ruma::events::receipt::ReceiptEvent {
+                content: ruma::events::receipt::ReceiptEventContent(
+                    receipt_content,
+                ),
+                room_id: body.room_id.clone(),
+            };
             services().rooms.edus.read_receipt.readreceipt_update(
                 sender_user,
                 &body.room_id,
-                ruma::events::receipt::ReceiptEvent {
-                    content: ruma::events::receipt::ReceiptEventContent(
-                        receipt_content,
-                    ),
-                    room_id: body.room_id.clone(),
-                },
+                event.clone(),
             )?;
+            forward_receipt(&event).await;
         }
         create_receipt::v3::ReceiptType::ReadPrivate => {
             let count = services()
@@ -189,3 +251,18 @@ pub(crate) async fn create_receipt_route(
 
     Ok(Ra(create_receipt::v3::Response {}))
 }
+
+/// Sends a read receipt to interested appservices, after it was stored
+async fn forward_receipt(event: &ReceiptEvent) {
+    if let Err(error) = services()
+        .sending
+        .send_edu_appservices_in_room(&event.room_id, event)
+        .await
+    {
+        warn!(
+            %error,
+            room_id = %event.room_id,
+            "Failed to send read receipt to appservices",
+        );
+    }
+}
@@ -24,14 +24,19 @@ use ruma::{
     },
     int,
     serde::JsonObject,
-    CanonicalJsonObject, OwnedRoomAliasId, RoomAliasId, RoomId, RoomVersionId,
+    CanonicalJsonObject, OwnedRoomAliasId, OwnedUserId, RoomAliasId, RoomId,
+    RoomVersionId, UserId,
 };
 use serde_json::{json, value::to_raw_value};
 use tracing::{info, warn};
 
 use crate::{
-    api::client_server::invite_helper, service::pdu::PduBuilder, services, Ar,
-    Error, Ra, Result,
+    api::client_server::{
+        create_remote_invite, invite_helper, invite_local_user,
+        send_remote_invite,
+    },
+    service::pdu::PduBuilder,
+    services, Ar, Error, Ra, Result,
 };
 
 /// # `POST /_matrix/client/r0/createRoom`
@@ -541,6 +546,7 @@ pub(crate) async fn get_room_event_route(
 
     let mut event = (*event).clone();
     event.add_age()?;
+    services().rooms.pdu_metadata.bundle_aggregations(&mut event)?;
 
     Ok(Ra(get_room_event::v3::Response {
         event: event.to_room_event(),
@@ -582,9 +588,11 @@ pub(crate) async fn get_room_aliases_route(
 /// - Creates a replacement room
 /// - Sends a tombstone event into the current room
 /// - Sender user joins the room
-/// - Transfers some state events
+/// - Transfers some state events and bans
 /// - Moves local aliases
 /// - Modifies old room power levels to prevent users from speaking
+/// - Invites the members of the old room, including those of other servers
+/// - Restores the unmodified power levels in the new room
 #[allow(clippy::too_many_lines)]
 pub(crate) async fn upgrade_room_route(
     body: Ar<upgrade_room::v3::Request>,
@@ -756,6 +764,41 @@ pub(crate) async fn upgrade_room_route(
         )
         .await?;
 
+    // Get the old room power levels
+    let power_levels_event = services()
+        .rooms
+        .state_accessor
+        .room_state_get(&body.room_id, &StateEventType::RoomPowerLevels, "")?
+        .ok_or_else(|| {
+            Error::bad_database("Found room without m.room.power_levels event.")
+        })?;
+    let mut power_levels_event_content: RoomPowerLevelsEventContent =
+        serde_json::from_str(power_levels_event.content.get()).map_err(
+            |_| Error::bad_database("Invalid room event in database."),
+        )?;
+
+    // Send the old power levels with the sender raised to 100 first, so that
+    // the sender can ban and invite users. The unmodified power levels are
+    // restored once that is done.
+    let mut sender_power_levels_content = power_levels_event_content.clone();
+    sender_power_levels_content.users.insert(sender_user.clone(), int!(100));
+    services()
+        .rooms
+        .timeline
+        .build_and_append_pdu(
+            PduBuilder {
+                event_type: TimelineEventType::RoomPowerLevels,
+                content: to_raw_value(&sender_power_levels_content)
+                    .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some(String::new()),
+                redacts: None,
+            },
+            sender_user,
+            &replacement_room_token,
+        )
+        .await?;
+
     // Recommended transferable state events list from the specs
     let transferable_state_events = vec![
         StateEventType::RoomServerAcl,
@@ -766,7 +809,6 @@ pub(crate) async fn upgrade_room_route(
         StateEventType::RoomGuestAccess,
         StateEventType::RoomHistoryVisibility,
         StateEventType::RoomJoinRules,
-        StateEventType::RoomPowerLevels,
     ];
 
     // Replicate transferable state events to the new room
@@ -798,6 +840,32 @@ pub(crate) async fn upgrade_room_route(
             .await?;
     }
 
+    let members = services()
+        .rooms
+        .state_accessor
+        .room_state_full(&body.room_id)
+        .await?
+        .into_iter()
+        .filter(|((event_type, _), _)| {
+            *event_type == StateEventType::RoomMember
+        })
+        .filter_map(|((_, state_key), pdu)| {
+            let user_id = UserId::parse(state_key).ok()?;
+            let content: RoomMemberEventContent =
+                serde_json::from_str(pdu.content.get()).ok()?;
+            Some((user_id, content))
+        })
+        .collect::<Vec<_>>();
+
+    // Banned users stay banned in the new room
+    for ban in ban_events(&members) {
+        services()
+            .rooms
+            .timeline
+            .build_and_append_pdu(ban, sender_user, &replacement_room_token)
+            .await?;
+    }
+
     // Moves any local aliases to the new room
     for alias in services()
         .rooms
@@ -812,27 +880,6 @@ pub(crate) async fn upgrade_room_route(
         )?;
     }
 
-    // Get the old room power levels
-    let mut power_levels_event_content: RoomPowerLevelsEventContent =
-        serde_json::from_str(
-            services()
-                .rooms
-                .state_accessor
-                .room_state_get(
-                    &body.room_id,
-                    &StateEventType::RoomPowerLevels,
-                    "",
-                )?
-                .ok_or_else(|| {
-                    Error::bad_database(
-                        "Found room without m.room.create event.",
-                    )
-                })?
-                .content
-                .get(),
-        )
-        .map_err(|_| Error::bad_database("Invalid room event in database."))?;
-
     // Setting events_default and invite to the greater of 50 and users_default
     // + 1
     let new_level =
@@ -858,9 +905,145 @@ pub(crate) async fn upgrade_room_route(
             &original_room_token,
         )
         .await?;
+    drop(original_room_token);
+
+    // Invite everyone that was joined to the old room, including users of
+    // other servers. Invites to other servers are only sent once the state
+    // lock is released.
+    let mut remote_invites = Vec::new();
+    for (user_id, member) in &members {
+        if member.membership != MembershipState::Join || user_id == sender_user
+        {
+            continue;
+        }
+
+        let result =
+            if user_id.server_name() == services().globals.server_name() {
+                invite_local_user(
+                    sender_user,
+                    user_id,
+                    None,
+                    false,
+                    &replacement_room_token,
+                )
+                .await
+            } else {
+                create_remote_invite(
+                    sender_user,
+                    user_id,
+                    None,
+                    false,
+                    &replacement_room_token,
+                )
+                .map(|invite| remote_invites.push(invite))
+            };
+        if let Err(error) = result {
+            warn!(%user_id, %error, "Failed to invite user to upgraded room");
+        }
+    }
+
+    // Finally restore the unmodified power levels, so that the same users can
+    // moderate the new room
+    services()
+        .rooms
+        .timeline
+        .build_and_append_pdu(
+            PduBuilder {
+                event_type: TimelineEventType::RoomPowerLevels,
+                content: power_levels_event.content.clone(),
+                unsigned: None,
+                state_key: Some(String::new()),
+                redacts: None,
+            },
+            sender_user,
+            &replacement_room_token,
+        )
+        .await?;
+    drop(replacement_room_token);
+
+    for invite in remote_invites {
+        if let Err(error) = send_remote_invite(invite).await {
+            warn!(%error, "Failed to invite user to upgraded room");
+        }
+    }
 
     // Return the replacement room id
     Ok(Ra(upgrade_room::v3::Response {
         replacement_room,
     }))
 }
+
+/// Builds the ban events that carry over the bans of `members` of an upgraded
+/// room to its replacement
+fn ban_events(
+    members: &[(OwnedUserId, RoomMemberEventContent)],
+) -> Vec<PduBuilder> {
+    members
+        .iter()
+        .filter(|(_, member)| member.membership == MembershipState::Ban)
+        .map(|(user_id, member)| PduBuilder {
+            event_type: TimelineEventType::RoomMember,
+            content: to_raw_value(&RoomMemberEventContent {
+                reason: member.reason.clone(),
+                ..RoomMemberEventContent::new(MembershipState::Ban)
+            })
+            .expect("event is valid, we just created it"),
+            unsigned: None,
+            state_key: Some(user_id.to_string()),
+            redacts: None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::user_id;
+
+    use super::*;
+
+    #[test]
+    fn bans_carry_over() {
+        let member =
+            |membership, reason: Option<&str>| RoomMemberEventContent {
+                reason: reason.map(ToOwned::to_owned),
+                ..RoomMemberEventContent::new(membership)
+            };
+        let members = vec![
+            (
+                user_id!("@spammer:example.com").to_owned(),
+                member(MembershipState::Ban, Some("spam")),
+            ),
+            (
+                user_id!("@alice:example.com").to_owned(),
+                member(MembershipState::Join, None),
+            ),
+            (
+                user_id!("@bob:example.org").to_owned(),
+                member(MembershipState::Leave, Some("bye")),
+            ),
+            (
+                user_id!("@troll:example.org").to_owned(),
+                member(MembershipState::Ban, None),
+            ),
+        ];
+
+        let bans = ban_events(&members)
+            .into_iter()
+            .map(|event| {
+                assert_eq!(event.event_type, TimelineEventType::RoomMember);
+                let content: RoomMemberEventContent =
+                    serde_json::from_str(event.content.get()).unwrap();
+                assert_eq!(content.membership, MembershipState::Ban);
+                (event.state_key.unwrap(), content.reason)
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            bans,
+            [
+                ("@spammer:example.com".to_owned(), Some("spam".to_owned())),
+                ("@troll:example.org".to_owned(), None),
+            ]
+        );
+    }
+}
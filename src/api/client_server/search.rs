@@ -1,4 +1,4 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
 use ruma::{
     api::client::{
@@ -6,15 +6,19 @@ use ruma::{
         search::search_events::{
             self,
             v3::{
-                EventContextResult, ResultCategories, ResultRoomEvents,
-                SearchResult,
+                EventContextResult, OrderBy, ResultCategories,
+                ResultRoomEvents, SearchResult,
             },
         },
     },
     uint, UInt,
 };
+use serde::Deserialize;
 
-use crate::{services, Ar, Error, Ra, Result};
+use crate::{
+    service::rooms::search::{self, Match},
+    services, Ar, Error, PduEvent, Ra, Result,
+};
 
 /// # `POST /_matrix/client/r0/search`
 ///
@@ -26,6 +30,11 @@ use crate::{services, Ar, Error, Ra, Result};
 pub(crate) async fn search_events_route(
     body: Ar<search_events::v3::Request>,
 ) -> Result<Ra<search_events::v3::Response>> {
+    #[derive(Deserialize)]
+    struct ExtractBody {
+        body: Option<String>,
+    }
+
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
     let search_criteria = body.search_categories.room_events.as_ref().unwrap();
@@ -48,9 +57,33 @@ pub(crate) async fn search_events_route(
         .try_into()
         .expect("0-100 should fit in usize");
 
-    let mut searches = Vec::new();
+    let skip = match body.next_batch.as_ref().map(|s| s.parse()) {
+        Some(Ok(s)) => s,
+        Some(Err(_)) => {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Invalid next_batch token.",
+            ))
+        }
+        // Default to the start
+        None => 0,
+    };
+    let wanted = skip + limit;
+    let order_by_rank =
+        !matches!(search_criteria.order_by, Some(OrderBy::Recent));
+
+    // Only the best `wanted` results are kept while going through the
+    // matches, so memory use doesn't depend on how many events match
+    let mut best = Vec::new();
+    let mut count = 0_usize;
+    let words: Vec<_> =
+        search::tokenize(&search_criteria.search_term).collect();
 
     for room_id in room_ids {
+        if filter.not_rooms.contains(&room_id) {
+            continue;
+        }
+
         if !services().rooms.state_cache.is_joined(sender_user, &room_id)? {
             return Err(Error::BadRequest(
                 ErrorKind::forbidden(),
@@ -58,64 +91,76 @@ pub(crate) async fn search_events_route(
             ));
         }
 
-        if let Some(search) = services()
+        let Some((matches, _)) = services()
             .rooms
             .search
             .search_pdus(&room_id, &search_criteria.search_term)?
-        {
-            searches.push(search.0.peekable());
-        }
-    }
+        else {
+            continue;
+        };
 
-    let skip = match body.next_batch.as_ref().map(|s| s.parse()) {
-        Some(Ok(s)) => s,
-        Some(Err(_)) => {
-            return Err(Error::BadRequest(
-                ErrorKind::InvalidParam,
-                "Invalid next_batch token.",
-            ))
-        }
-        // Default to the start
-        None => 0,
-    };
+        for result in matches {
+            let Match {
+                pdu_id,
+                score,
+            } = result?;
 
-    let mut results = Vec::new();
-    for _ in 0..skip + limit {
-        if let Some(s) = searches
-            .iter_mut()
-            .map(|s| (s.peek().cloned(), s))
-            .max_by_key(|(peek, _)| peek.clone())
-            .and_then(|(_, i)| i.next())
-        {
-            results.push(s);
+            let Some(pdu) =
+                services().rooms.timeline.get_pdu_from_id(&pdu_id)?
+            else {
+                continue;
+            };
+            if pdu.is_redacted()
+                || filter.not_senders.contains(&pdu.sender)
+                || filter
+                    .senders
+                    .as_ref()
+                    .is_some_and(|senders| !senders.contains(&pdu.sender))
+                || !services().rooms.state_accessor.user_can_see_event(
+                    sender_user,
+                    &pdu.room_id,
+                    &pdu.event_id,
+                )?
+            {
+                continue;
+            }
+
+            count += 1;
+            let key = if order_by_rank {
+                score
+            } else {
+                f64::from(pdu.origin_server_ts)
+            };
+            best.push((key, score, pdu));
+
+            if best.len() >= 2 * wanted.max(1) {
+                sort_results(&mut best);
+                best.truncate(wanted);
+            }
         }
     }
 
-    let results: Vec<_> = results
-        .iter()
+    sort_results(&mut best);
+    let next_batch = (count > wanted).then(|| wanted.to_string());
+
+    let mut highlights = BTreeSet::new();
+    let results: Vec<_> = best
+        .into_iter()
         .skip(skip)
-        .filter_map(|result| {
-            services()
-                .rooms
-                .timeline
-                .get_pdu_from_id(result)
-                .ok()?
-                .filter(|pdu| {
-                    !pdu.is_redacted()
-                        && services()
-                            .rooms
-                            .state_accessor
-                            .user_can_see_event(
-                                sender_user,
-                                &pdu.room_id,
-                                &pdu.event_id,
-                            )
-                            .unwrap_or(false)
-                })
-                .map(|pdu| pdu.to_room_event())
-        })
-        .map(|result| {
-            Ok::<_, Error>(SearchResult {
+        .take(limit)
+        .map(|(_, score, pdu)| {
+            if let Ok(ExtractBody {
+                body: Some(body),
+            }) = serde_json::from_str(pdu.content.get())
+            {
+                highlights.extend(
+                    search::split_words(&body)
+                        .filter(|word| words.contains(&word.to_lowercase()))
+                        .map(ToOwned::to_owned),
+                );
+            }
+
+            SearchResult {
                 context: EventContextResult {
                     end: None,
                     events_after: Vec::new(),
@@ -123,37 +168,28 @@ pub(crate) async fn search_events_route(
                     profile_info: BTreeMap::new(),
                     start: None,
                 },
-                rank: None,
-                result: Some(result),
-            })
+                rank: Some(score),
+                result: Some(pdu.to_room_event()),
+            }
         })
-        .filter_map(Result::ok)
-        .take(limit)
         .collect();
-
-    let more_unloaded_results = searches.iter_mut().any(|s| s.peek().is_some());
-    let next_batch = more_unloaded_results.then(|| (skip + limit).to_string());
+    highlights.extend(words);
 
     Ok(Ra(search_events::v3::Response::new(ResultCategories {
         room_events: ResultRoomEvents {
-            // TODO(compat): this is not a good estimate of the total number of
-            // results. we should just be returning None, but
-            // element incorrectly relies on this field. Switch back
-            // to None when [1] is fixed
-            //
-            // [1]: https://github.com/element-hq/element-web/issues/27517
-            count: Some(results.len().try_into().unwrap_or(UInt::MAX)),
+            count: Some(count.try_into().unwrap_or(UInt::MAX)),
             // TODO
             groups: BTreeMap::new(),
             next_batch,
             results,
             // TODO
             state: BTreeMap::new(),
-            highlights: search_criteria
-                .search_term
-                .split_terminator(|c: char| !c.is_alphanumeric())
-                .map(str::to_lowercase)
-                .collect(),
+            highlights: highlights.into_iter().collect(),
         },
     })))
 }
+
+/// Sorts search results by their key, best first
+fn sort_results(results: &mut [(f64, f64, PduEvent)]) {
+    results.sort_by(|(a, ..), (b, ..)| b.total_cmp(a));
+}
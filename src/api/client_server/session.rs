@@ -1,41 +1,188 @@
+use std::time::Duration;
+
+use axum::{response::Redirect, Form};
+use http::{header, HeaderMap};
 use ruma::{
     api::client::{
         error::ErrorKind,
         session::{
             get_login_types::{
                 self,
-                v3::{ApplicationServiceLoginType, PasswordLoginType},
+                v3::{
+                    ApplicationServiceLoginType, IdentityProvider,
+                    PasswordLoginType, SsoLoginType, TokenLoginType,
+                },
             },
-            login, logout, logout_all,
+            login, logout, logout_all, refresh_token, sso_login,
+            sso_login_with_provider,
         },
         uiaa::UserIdentifier,
     },
-    UserId,
+    thirdparty::Medium,
+    DeviceId, OwnedUserId, ServerName, UserId,
 };
 use serde::Deserialize;
 use tracing::{info, warn};
 
 use super::{DEVICE_ID_LENGTH, TOKEN_LENGTH};
-use crate::{services, utils, Ar, Error, Ra, Result};
+use crate::{service::sso, services, utils, Ar, Error, Ra, Result};
 
 #[derive(Debug, Deserialize)]
 struct Claims {
     sub: String,
 }
 
+/// Query parameters the SSO provider redirects back to the callback with
+#[derive(Deserialize)]
+pub(crate) struct SsoCallback {
+    /// Identifies the pending login
+    state: String,
+    /// The authorization code, if the user logged in
+    code: Option<String>,
+    /// Why the provider didn't issue a code
+    error: Option<String>,
+}
+
 /// # `GET /_matrix/client/r0/login`
 ///
 /// Get the supported login types of this server. One of these should be used as
 /// the `type` field when logging in.
+///
+/// - `m.login.sso` is only advertised if an SSO provider is configured
+/// - `m.login.token` is advertised if either SSO or JWT login is configured
 pub(crate) async fn get_login_types_route(
     _body: Ar<get_login_types::v3::Request>,
 ) -> Result<Ra<get_login_types::v3::Response>> {
-    Ok(Ra(get_login_types::v3::Response::new(vec![
+    let mut flows = vec![
         get_login_types::v3::LoginType::Password(PasswordLoginType::default()),
         get_login_types::v3::LoginType::ApplicationService(
             ApplicationServiceLoginType::default(),
         ),
-    ])))
+    ];
+
+    if let Some(config) = &services().globals.config.sso {
+        let mut sso = SsoLoginType::default();
+        sso.identity_providers =
+            vec![IdentityProvider::new(config.id.clone(), config.name.clone())];
+        flows.push(get_login_types::v3::LoginType::Sso(sso));
+    }
+    if services().globals.config.sso.is_some()
+        || services().globals.jwt_decoding_key().is_some()
+    {
+        flows.push(get_login_types::v3::LoginType::Token(
+            TokenLoginType::default(),
+        ));
+    }
+
+    Ok(Ra(get_login_types::v3::Response::new(flows)))
+}
+
+/// # `GET /_matrix/client/v3/login/sso/redirect`
+///
+/// Redirects the client to the configured SSO provider.
+///
+/// - `redirectUrl` must be allowed by `sso.allowed_redirect_urls`, it is sent
+///   the login token after logging in at the provider
+pub(crate) async fn sso_login_route(
+    body: Ar<sso_login::v3::Request>,
+) -> Result<Ra<sso_login::v3::Response>> {
+    let (location, cookie) = services().sso.start(&body.redirect_url)?;
+
+    let mut response = sso_login::v3::Response::new(location);
+    response.cookie = Some(cookie);
+    Ok(Ra(response))
+}
+
+/// # `GET /_matrix/client/v3/login/sso/redirect/{idpId}`
+///
+/// Redirects the client to the SSO provider with the ID `idpId`.
+///
+/// - See [`sso_login_route`]
+pub(crate) async fn sso_login_with_provider_route(
+    body: Ar<sso_login_with_provider::v3::Request>,
+) -> Result<Ra<sso_login_with_provider::v3::Response>> {
+    if sso::Service::config()?.id != body.idp_id {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Unknown identity provider.",
+        ));
+    }
+
+    let (location, cookie) = services().sso.start(&body.redirect_url)?;
+
+    let mut response = sso_login_with_provider::v3::Response::new(location);
+    response.cookie = Some(cookie);
+    Ok(Ra(response))
+}
+
+/// # `GET /_matrix/client/unstable/login/sso/callback`
+///
+/// The SSO provider redirects users here after they logged in.
+///
+/// - Only accepts logins started in the same browser, as identified by the
+///   cookie set when redirecting to the provider
+/// - Exchanges the authorization code and maps the subject to a user, which is
+///   created if `sso.allow_registration` is set
+/// - Redirects to the `redirectUrl` of the client with a `loginToken`
+pub(crate) async fn sso_callback_route(
+    headers: HeaderMap,
+    Form(query): Form<SsoCallback>,
+) -> Result<Redirect> {
+    let Some(code) = query.code else {
+        warn!(error = ?query.error, "SSO provider did not issue a code");
+        return Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "Identity provider denied the login.",
+        ));
+    };
+
+    let cookies: Vec<_> = headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|cookie| cookie.to_str().ok())
+        .collect();
+    let redirect_url =
+        services().sso.finish(&code, &query.state, &cookies).await?;
+
+    Ok(Redirect::to(&redirect_url))
+}
+
+/// Resolves the user a password login is for
+///
+/// Returns `None` if the identifier is an email address that isn't bound to any
+/// user.
+fn password_login_user_id<F>(
+    identifier: Option<&UserIdentifier>,
+    user: Option<&str>,
+    server_name: &ServerName,
+    email_owner: F,
+) -> Result<Option<OwnedUserId>>
+where
+    F: FnOnce(&str) -> Result<Option<OwnedUserId>>,
+{
+    let user_id = if let Some(UserIdentifier::UserIdOrLocalpart(user_id)) =
+        identifier
+    {
+        UserId::parse_with_server_name(user_id.to_lowercase(), server_name)
+    } else if let Some(UserIdentifier::Email {
+        address,
+    }) = identifier
+    {
+        return email_owner(&address.to_lowercase());
+    } else if let Some(user) = user {
+        UserId::parse(user)
+    } else {
+        warn!(?identifier, "Bad login kind");
+        return Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "Bad login type.",
+        ));
+    }
+    .map_err(|_| {
+        Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid.")
+    })?;
+
+    Ok(Some(user_id))
 }
 
 /// # `POST /_matrix/client/r0/login`
@@ -43,8 +190,10 @@ pub(crate) async fn get_login_types_route(
 /// Authenticates the user and returns an access token it can use in subsequent
 /// requests.
 ///
-/// - The user needs to authenticate using their password (or if enabled using a
-///   json web token)
+/// - The user needs to authenticate using their password, a login token issued
+///   after SSO login or if enabled using a json web token
+/// - Password logins can identify the user by an email address bound to their
+///   account
 /// - If `device_id` is known: invalidates old access token of that device
 /// - If `device_id` is unknown: creates a new device
 /// - Returns access token that is associated with the user and device
@@ -68,29 +217,23 @@ pub(crate) async fn login_route(
             user,
             ..
         }) => {
-            let user_id =
-                if let Some(UserIdentifier::UserIdOrLocalpart(user_id)) =
-                    identifier
-                {
-                    UserId::parse_with_server_name(
-                        user_id.to_lowercase(),
-                        services().globals.server_name(),
-                    )
-                } else if let Some(user) = user {
-                    UserId::parse(user)
-                } else {
-                    warn!(kind = ?body.login_info, "Bad login kind");
-                    return Err(Error::BadRequest(
-                        ErrorKind::forbidden(),
-                        "Bad login type.",
-                    ));
-                }
-                .map_err(|_| {
-                    Error::BadRequest(
-                        ErrorKind::InvalidUsername,
-                        "Username is invalid.",
-                    )
-                })?;
+            let Some(user_id) = password_login_user_id(
+                identifier.as_ref(),
+                user.as_deref(),
+                services().globals.server_name(),
+                |address| {
+                    services().users.threepid_owner(&Medium::Email, address)
+                },
+            )?
+            else {
+                // Unknown addresses fail the same way and take as long as
+                // wrong passwords so that they can't be enumerated
+                utils::waste_password_verification(password);
+                return Err(Error::BadRequest(
+                    ErrorKind::forbidden(),
+                    "Wrong username or password.",
+                ));
+            };
 
             if services().appservice.is_exclusive_user_id(&user_id).await {
                 return Err(Error::BadRequest(
@@ -99,12 +242,13 @@ pub(crate) async fn login_route(
                 ));
             }
 
-            let hash = services().users.password_hash(&user_id)?.ok_or(
-                Error::BadRequest(
+            let Some(hash) = services().users.password_hash(&user_id)? else {
+                utils::waste_password_verification(password);
+                return Err(Error::BadRequest(
                     ErrorKind::forbidden(),
                     "Wrong username or password.",
-                ),
-            )?;
+                ));
+            };
 
             if hash.is_empty() {
                 return Err(Error::BadRequest(
@@ -125,7 +269,9 @@ pub(crate) async fn login_route(
         login::v3::LoginInfo::Token(login::v3::Token {
             token,
         }) => {
-            if let Some(jwt_decoding_key) =
+            if let Some(user_id) = services().users.take_login_token(token) {
+                user_id
+            } else if let Some(jwt_decoding_key) =
                 services().globals.jwt_decoding_key()
             {
                 let token = jsonwebtoken::decode::<Claims>(
@@ -159,6 +305,11 @@ pub(crate) async fn login_route(
                 }
 
                 user_id
+            } else if services().globals.config.sso.is_some() {
+                return Err(Error::BadRequest(
+                    ErrorKind::forbidden(),
+                    "Invalid login token.",
+                ));
             } else {
                 return Err(Error::BadRequest(
                     ErrorKind::Unknown,
@@ -245,11 +396,19 @@ pub(crate) async fn login_route(
         services().users.create_device(
             &user_id,
             &device_id,
-            &token,
+            Some(&token),
             body.initial_device_display_name.clone(),
         )?;
     }
 
+    let (refresh_token, expires_in) = if body.refresh_token {
+        let (refresh_token, expires_in) =
+            issue_refresh_token(&user_id, &device_id)?;
+        (Some(refresh_token), Some(expires_in))
+    } else {
+        (None, None)
+    };
+
     info!(%user_id, %device_id, "User logged in");
 
     // Homeservers are still required to send the `home_server` field
@@ -260,11 +419,70 @@ pub(crate) async fn login_route(
         home_server: Some(services().globals.server_name().to_owned()),
         device_id,
         well_known: None,
-        refresh_token: None,
-        expires_in: None,
+        refresh_token,
+        expires_in,
     }))
 }
 
+/// # `POST /_matrix/client/v3/refresh`
+///
+/// Exchanges a refresh token for a new access token and refresh token.
+///
+/// - Invalidates the previous access token
+/// - The used refresh token stays valid until the new tokens are used, so that
+///   clients can retry if they don't receive the response
+pub(crate) async fn refresh_token_route(
+    body: Ar<refresh_token::v3::Request>,
+) -> Result<Ra<refresh_token::v3::Response>> {
+    let (user_id, device_id) = services()
+        .users
+        .find_from_refresh_token(&body.refresh_token)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::UnknownToken {
+                soft_logout: false,
+            },
+            "Unknown refresh token.",
+        ))?;
+
+    let access_token = utils::random_string(TOKEN_LENGTH);
+    let refresh_token = utils::random_string(TOKEN_LENGTH);
+    let expires_in = services().globals.config.access_token_lifetime;
+    services().users.rotate_tokens(
+        &user_id,
+        &device_id,
+        &body.refresh_token,
+        &access_token,
+        &refresh_token,
+        expires_in,
+    )?;
+
+    Ok(Ra(refresh_token::v3::Response {
+        access_token,
+        refresh_token: Some(refresh_token),
+        expires_in_ms: Some(expires_in),
+    }))
+}
+
+/// Sets a new refresh token for a device that just got a new access token.
+///
+/// The access token expires after the configured lifetime. Returns the
+/// refresh token and the lifetime of the access token.
+pub(super) fn issue_refresh_token(
+    user_id: &UserId,
+    device_id: &DeviceId,
+) -> Result<(String, Duration)> {
+    let refresh_token = utils::random_string(TOKEN_LENGTH);
+    let expires_in = services().globals.config.access_token_lifetime;
+    services().users.set_refresh_token(
+        user_id,
+        device_id,
+        &refresh_token,
+        expires_in,
+    )?;
+
+    Ok((refresh_token, expires_in))
+}
+
 /// # `POST /_matrix/client/r0/logout`
 ///
 /// Log out the current device.
@@ -334,3 +552,64 @@ pub(crate) async fn logout_all_route(
 
     Ok(Ra(logout_all::v3::Response::new()))
 }
+
+#[cfg(test)]
+mod tests {
+    use ruma::{
+        api::client::uiaa::UserIdentifier, owned_user_id, server_name, user_id,
+    };
+
+    use super::password_login_user_id;
+
+    #[test]
+    fn login_by_email_resolves_owner() {
+        let identifier = UserIdentifier::Email {
+            address: "Alice@Example.COM".to_owned(),
+        };
+
+        let user_id = password_login_user_id(
+            Some(&identifier),
+            None,
+            server_name!("example.com"),
+            |address| {
+                assert_eq!(address, "alice@example.com");
+                Ok(Some(owned_user_id!("@alice:example.com")))
+            },
+        )
+        .unwrap();
+
+        assert_eq!(user_id.as_deref(), Some(user_id!("@alice:example.com")));
+    }
+
+    #[test]
+    fn login_by_unknown_email_finds_no_user() {
+        let identifier = UserIdentifier::Email {
+            address: "nobody@example.com".to_owned(),
+        };
+
+        let user_id = password_login_user_id(
+            Some(&identifier),
+            None,
+            server_name!("example.com"),
+            |_| Ok(None),
+        )
+        .unwrap();
+
+        assert_eq!(user_id, None);
+    }
+
+    #[test]
+    fn login_by_localpart_skips_email_lookup() {
+        let identifier = UserIdentifier::UserIdOrLocalpart("Alice".to_owned());
+
+        let user_id = password_login_user_id(
+            Some(&identifier),
+            None,
+            server_name!("example.com"),
+            |_| panic!("email lookup for a localpart login"),
+        )
+        .unwrap();
+
+        assert_eq!(user_id.as_deref(), Some(user_id!("@alice:example.com")));
+    }
+}
@@ -1,19 +1,26 @@
-use ruma::{api::client::space::get_hierarchy, uint};
+use ruma::{
+    api::client::{error::ErrorKind, space::get_hierarchy},
+    uint,
+};
 
-use crate::{services, Ar, Ra, Result};
+use crate::{
+    service::rooms::spaces::PaginationToken, services, Ar, Error, Ra, Result,
+};
 
 /// # `GET /_matrix/client/v1/rooms/{room_id}/hierarchy`
 ///
 /// Paginates over the space tree in a depth-first manner to locate child rooms
 /// of a given space.
+///
+/// - Children that this server doesn't know are fetched from their `via`
+///   servers
+/// - Fails with `M_INVALID_PARAM` if `max_depth` or `suggested_only` differ
+///   from the request that returned `from`
 pub(crate) async fn get_hierarchy_route(
     body: Ar<get_hierarchy::v1::Request>,
 ) -> Result<Ra<get_hierarchy::v1::Response>> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    let skip =
-        body.from.as_ref().and_then(|s| s.parse::<usize>().ok()).unwrap_or(0);
-
     let limit = body
         .limit
         .map(|x| x.min(uint!(100)))
@@ -28,17 +35,32 @@ pub(crate) async fn get_hierarchy_route(
     .expect("0-10 should fit in usize")
         + 1;
 
+    let token = if let Some(from) = &body.from {
+        let token = from.parse::<PaginationToken>().map_err(|()| {
+            Error::BadRequest(ErrorKind::InvalidParam, "Invalid from token.")
+        })?;
+        if token.max_depth != max_depth
+            || token.suggested_only != body.suggested_only
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Parameters differ from the request that returned the from \
+                 token.",
+            ));
+        }
+        token
+    } else {
+        PaginationToken {
+            skip: 0,
+            max_depth,
+            suggested_only: body.suggested_only,
+        }
+    };
+
     services()
         .rooms
         .spaces
-        .get_hierarchy(
-            sender_user,
-            &body.room_id,
-            limit,
-            skip,
-            max_depth,
-            body.suggested_only,
-        )
+        .get_hierarchy(sender_user, &body.room_id, limit, token)
         .await
         .map(Ra)
 }
@@ -14,6 +14,7 @@ use ruma::{
 };
 use tracing::log::warn;
 
+use super::get_alias_helper;
 use crate::{service::pdu::PduBuilder, services, Ar, Error, Ra, Result};
 
 /// # `PUT /_matrix/client/r0/rooms/{roomId}/state/{eventType}/{stateKey}`
@@ -23,7 +24,8 @@ use crate::{service::pdu::PduBuilder, services, Ar, Error, Ra, Result};
 /// - The only requirement for the content is that it has to be valid json
 /// - Tries to send the event into the room, auth rules will determine if it is
 ///   allowed
-/// - If event is new `canonical_alias`: Rejects if alias is incorrect
+/// - If event is new `canonical_alias`: Rejects with `M_BAD_ALIAS` unless all
+///   new aliases point to the room, remote ones are resolved over federation
 pub(crate) async fn send_state_event_for_key_route(
     body: Ar<send_state_event::v3::Request>,
 ) -> Result<Ra<send_state_event::v3::Response>> {
@@ -52,7 +54,8 @@ pub(crate) async fn send_state_event_for_key_route(
 /// - The only requirement for the content is that it has to be valid json
 /// - Tries to send the event into the room, auth rules will determine if it is
 ///   allowed
-/// - If event is new `canonical_alias`: Rejects if alias is incorrect
+/// - If event is new `canonical_alias`: Rejects with `M_BAD_ALIAS` unless all
+///   new aliases point to the room, remote ones are resolved over federation
 pub(crate) async fn send_state_event_for_empty_key_route(
     body: Ar<send_state_event::v3::Request>,
 ) -> Result<Ra<send_state_event::v3::Response>> {
@@ -210,34 +213,8 @@ async fn send_state_event_for_key_helper(
 ) -> Result<Arc<EventId>> {
     let sender_user = sender;
 
-    // TODO: Review this check, error if event is unparsable, use event type,
-    // allow alias if it previously existed
-    if let Ok(canonical_alias) = serde_json::from_str::<
-        RoomCanonicalAliasEventContent,
-    >(json.json().get())
-    {
-        let mut aliases = canonical_alias.alt_aliases.clone();
-
-        if let Some(alias) = canonical_alias.alias {
-            aliases.push(alias);
-        }
-
-        for alias in aliases {
-            if alias.server_name() != services().globals.server_name()
-                || services()
-                    .rooms
-                    .alias
-                    .resolve_local_alias(&alias)?
-                    .filter(|room| room == room_id)
-                    .is_none()
-            {
-                return Err(Error::BadRequest(
-                    ErrorKind::forbidden(),
-                    "You are only allowed to send canonical_alias events when \
-                     it's aliases already exists",
-                ));
-            }
-        }
+    if *event_type == StateEventType::RoomCanonicalAlias {
+        check_canonical_alias(room_id, json).await?;
     }
 
     let room_token = services()
@@ -265,3 +242,63 @@ async fn send_state_event_for_key_helper(
 
     Ok(event_id)
 }
+
+/// Checks that all aliases of new `m.room.canonical_alias` content point to
+/// the room
+///
+/// Aliases that are already in the current canonical alias event are not
+/// checked again, so that an event can still be sent after one of them was
+/// deleted.
+async fn check_canonical_alias(
+    room_id: &RoomId,
+    json: &Raw<AnyStateEventContent>,
+) -> Result<()> {
+    let content = serde_json::from_str::<RoomCanonicalAliasEventContent>(
+        json.json().get(),
+    )
+    .map_err(|_| {
+        Error::BadRequest(
+            ErrorKind::BadJson,
+            "Invalid m.room.canonical_alias content.",
+        )
+    })?;
+
+    let previous = services()
+        .rooms
+        .state_accessor
+        .room_state_get(room_id, &StateEventType::RoomCanonicalAlias, "")?
+        .and_then(|pdu| {
+            serde_json::from_str::<RoomCanonicalAliasEventContent>(
+                pdu.content.get(),
+            )
+            .ok()
+        });
+
+    for alias in content.alias.iter().chain(&content.alt_aliases) {
+        if previous.as_ref().is_some_and(|previous| {
+            previous.alias.as_ref() == Some(alias)
+                || previous.alt_aliases.contains(alias)
+        }) {
+            continue;
+        }
+
+        let resolved =
+            if alias.server_name() == services().globals.server_name() {
+                services().rooms.alias.resolve_local_alias(alias)?
+            } else {
+                get_alias_helper(alias.clone())
+                    .await
+                    .ok()
+                    .map(|response| response.room_id)
+            };
+
+        if resolved.as_deref() != Some(room_id) {
+            return Err(Error::BadRequest(
+                ErrorKind::BadAlias,
+                "Alias does not point to this room.",
+            ));
+        }
+    }
+
+    Ok(())
+}
@@ -5,13 +5,15 @@ use std::{
 
 use ruma::{
     api::client::{
-        filter::{FilterDefinition, LazyLoadOptions},
+        filter::{
+            FilterDefinition, LazyLoadOptions, RoomEventFilter, RoomFilter,
+        },
         sync::sync_events::{
             self,
             v3::{
                 Ephemeral, Filter, GlobalAccountData, InviteState, InvitedRoom,
-                JoinedRoom, LeftRoom, Presence, RoomAccountData, RoomSummary,
-                Rooms, State, Timeline, ToDevice,
+                JoinedRoom, KnockState, KnockedRoom, LeftRoom, Presence,
+                RoomAccountData, RoomSummary, Rooms, State, Timeline, ToDevice,
             },
             v4::SlidingOp,
             DeviceLists, UnreadNotificationsCount,
@@ -19,13 +21,20 @@ use ruma::{
         uiaa::UiaaResponse,
     },
     events::{
+        receipt::{
+            Receipt, ReceiptEventContent, ReceiptThread, ReceiptType,
+            SyncReceiptEvent,
+        },
         room::member::{MembershipState, RoomMemberEventContent},
-        StateEventType, TimelineEventType,
+        AnySyncEphemeralRoomEvent, StateEventType, TimelineEventType,
     },
-    uint, DeviceId, EventId, JsOption, OwnedUserId, RoomId, UInt, UserId,
+    serde::Raw,
+    uint, DeviceId, EventId, JsOption, MilliSecondsSinceUnixEpoch, OwnedUserId,
+    RoomId, UInt, UserId,
 };
 use tracing::{debug, error};
 
+use super::{pdu_matches_filter, room_matches_filter};
 use crate::{
     service::{pdu::EventHash, rooms::timeline::PduCount},
     services, utils, Ar, Error, PduEvent, Ra, Result,
@@ -78,6 +87,13 @@ pub(crate) async fn sync_events_route(
     let sender_device = body.sender_device.expect("user is authenticated");
     let body = body.body;
 
+    services()
+        .rooms
+        .edus
+        .presence
+        .ping_presence(&sender_user, &body.set_presence)
+        .await?;
+
     // Setup watchers, so if there's no response, we can wait for them
     let watcher = services().globals.watch(&sender_user, &sender_device);
 
@@ -105,6 +121,14 @@ pub(crate) async fn sync_events_route(
 
     let full_state = body.full_state;
 
+    let room_allowed = |room_id: &RoomId| {
+        room_matches_filter(
+            filter.room.rooms.as_deref(),
+            &filter.room.not_rooms,
+            room_id,
+        )
+    };
+
     let mut joined_rooms = BTreeMap::new();
     let since =
         body.since.as_ref().and_then(|string| string.parse().ok()).unwrap_or(0);
@@ -130,6 +154,10 @@ pub(crate) async fn sync_events_route(
         .collect::<Vec<_>>();
     for room_id in all_joined_rooms {
         let room_id = room_id?;
+        if !room_allowed(&room_id) {
+            continue;
+        }
+
         if let Ok(joined_room) = load_joined_room(
             &sender_user,
             &sender_device,
@@ -138,6 +166,7 @@ pub(crate) async fn sync_events_route(
             sincecount,
             next_batch,
             next_batchcount,
+            &filter.room,
             lazy_load_enabled,
             lazy_load_send_redundant,
             full_state,
@@ -157,6 +186,9 @@ pub(crate) async fn sync_events_route(
         services().rooms.state_cache.rooms_left(&sender_user).collect();
     for result in all_left_rooms {
         let (room_id, _) = result?;
+        if !room_allowed(&room_id) {
+            continue;
+        }
 
         {
             // Get and drop the lock to wait for remaining operations to finish
@@ -321,6 +353,9 @@ pub(crate) async fn sync_events_route(
         services().rooms.state_cache.rooms_invited(&sender_user).collect();
     for result in all_invited_rooms {
         let (room_id, invite_state_events) = result?;
+        if !room_allowed(&room_id) {
+            continue;
+        }
 
         {
             // Get and drop the lock to wait for remaining operations to finish
@@ -352,6 +387,45 @@ pub(crate) async fn sync_events_route(
         );
     }
 
+    let mut knocked_rooms = BTreeMap::new();
+    let all_knocked_rooms: Vec<_> =
+        services().rooms.state_cache.rooms_knocked(&sender_user).collect();
+    for result in all_knocked_rooms {
+        let (room_id, knock_state_events) = result?;
+        if !room_allowed(&room_id) {
+            continue;
+        }
+
+        {
+            // Get and drop the lock to wait for remaining operations to finish
+            let room_token = services()
+                .globals
+                .roomid_mutex_insert
+                .lock_key(room_id.clone())
+                .await;
+            drop(room_token);
+        }
+
+        let knock_count = services()
+            .rooms
+            .state_cache
+            .get_knock_count(&room_id, &sender_user)?;
+
+        // Knocked before last sync
+        if Some(since) >= knock_count {
+            continue;
+        }
+
+        knocked_rooms.insert(
+            room_id.clone(),
+            KnockedRoom {
+                knock_state: KnockState {
+                    events: knock_state_events,
+                },
+            },
+        );
+    }
+
     for user_id in left_encrypted_users {
         let dont_share_encrypted_room = services()
             .rooms
@@ -393,10 +467,20 @@ pub(crate) async fn sync_events_route(
             leave: left_rooms,
             join: joined_rooms,
             invite: invited_rooms,
-            // TODO
-            knock: BTreeMap::new(),
+            knock: knocked_rooms,
+        },
+        presence: Presence {
+            events: services()
+                .rooms
+                .edus
+                .presence
+                .presence_since(&sender_user, since)?
+                .into_values()
+                .map(|presence| {
+                    Raw::new(&presence).expect("presence event is valid json")
+                })
+                .collect(),
         },
-        presence: Presence::default(),
         account_data: GlobalAccountData {
             events: services()
                 .account_data
@@ -425,8 +509,11 @@ pub(crate) async fn sync_events_route(
                 .users
                 .get_to_device_events(&sender_user, &sender_device)?,
         },
-        // Fallback keys are not yet supported
-        device_unused_fallback_key_types: None,
+        device_unused_fallback_key_types: Some(
+            services()
+                .users
+                .unused_fallback_key_types(&sender_user, &sender_device)?,
+        ),
     };
 
     // TODO: Retry the endpoint instead of returning (waiting for #118)
@@ -439,10 +526,7 @@ pub(crate) async fn sync_events_route(
     {
         // Hang a few seconds so requests are not spammed
         // Stop hanging if new info arrives
-        let mut duration = body.timeout.unwrap_or_default();
-        if duration.as_secs() > 30 {
-            duration = Duration::from_secs(30);
-        }
+        let duration = clamp_sync_timeout(body.timeout.unwrap_or_default());
         match tokio::time::timeout(duration, watcher).await {
             Ok(x) => x.expect("watcher should succeed"),
             Err(error) => debug!(%error, "Timed out"),
@@ -461,6 +545,7 @@ async fn load_joined_room(
     sincecount: PduCount,
     next_batch: u64,
     next_batchcount: PduCount,
+    filter: &RoomFilter,
     lazy_load_enabled: bool,
     lazy_load_send_redundant: bool,
     full_state: bool,
@@ -478,8 +563,13 @@ async fn load_joined_room(
         drop(room_token);
     }
 
-    let (timeline_pdus, limited) =
-        load_timeline(sender_user, room_id, sincecount, 10)?;
+    let (timeline_pdus, limited) = load_timeline(
+        sender_user,
+        room_id,
+        sincecount,
+        filter.timeline.limit.map_or(10, u64::from),
+        &filter.timeline,
+    )?;
 
     let send_notification_counts = !timeline_pdus.is_empty()
         || services()
@@ -952,15 +1042,39 @@ async fn load_joined_room(
             .filter_map(Result::ok),
     );
 
-    let notification_count = send_notification_counts
+    let mut notification_count = send_notification_counts
         .then(|| services().rooms.user.notification_count(sender_user, room_id))
-        .transpose()?
-        .map(|x| x.try_into().expect("notification count can't go that high"));
+        .transpose()?;
 
-    let highlight_count = send_notification_counts
+    let mut highlight_count = send_notification_counts
         .then(|| services().rooms.user.highlight_count(sender_user, room_id))
-        .transpose()?
-        .map(|x| x.try_into().expect("highlight count can't go that high"));
+        .transpose()?;
+
+    // Clients that asked for it get the counts of threads separately, which
+    // are then left out of the counts of the room
+    let mut unread_thread_notifications = BTreeMap::new();
+    if send_notification_counts && filter.timeline.unread_thread_notifications {
+        for (thread_root, (notifications, highlights)) in services()
+            .rooms
+            .user
+            .thread_notification_counts(sender_user, room_id)?
+        {
+            notification_count =
+                notification_count.map(|x| x.saturating_sub(notifications));
+            highlight_count =
+                highlight_count.map(|x| x.saturating_sub(highlights));
+
+            unread_thread_notifications.insert(
+                thread_root,
+                UnreadNotificationsCount {
+                    highlight_count: Some(UInt::new_saturating(highlights)),
+                    notification_count: Some(UInt::new_saturating(
+                        notifications,
+                    )),
+                },
+            );
+        }
+    }
 
     let prev_batch = timeline_pdus.first().map_or(
         Ok::<_, Error>(None),
@@ -975,8 +1089,20 @@ async fn load_joined_room(
         },
     )?;
 
-    let room_events: Vec<_> =
-        timeline_pdus.iter().map(|(_, pdu)| pdu.to_sync_room_event()).collect();
+    // Messages of ignored users are hidden, their state events are still
+    // needed to make sense of the room
+    let ignored_users = services().account_data.ignored_users(sender_user)?;
+    let room_events = timeline_pdus
+        .iter()
+        .filter(|(_, pdu)| {
+            pdu.state_key.is_some() || !ignored_users.contains(&pdu.sender)
+        })
+        .map(|(_, pdu)| {
+            let mut pdu = pdu.clone();
+            services().rooms.pdu_metadata.bundle_aggregations(&mut pdu)?;
+            Ok(pdu.to_sync_room_event())
+        })
+        .collect::<Result<Vec<_>>>()?;
 
     let mut edus: Vec<_> = services()
         .rooms
@@ -987,6 +1113,12 @@ async fn load_joined_room(
         .map(|(_, _, v)| v)
         .collect();
 
+    if let Some(private_read) =
+        private_read_receipt(sender_user, room_id, since)?
+    {
+        edus.push(private_read);
+    }
+
     if services().rooms.edus.typing.last_typing_update(room_id).await? > since {
         edus.push(
             serde_json::from_str(
@@ -1031,8 +1163,8 @@ async fn load_joined_room(
                 .map(UInt::new_saturating),
         },
         unread_notifications: UnreadNotificationsCount {
-            highlight_count,
-            notification_count,
+            highlight_count: highlight_count.map(UInt::new_saturating),
+            notification_count: notification_count.map(UInt::new_saturating),
         },
         timeline: Timeline {
             limited: limited || joined_since_last_sync,
@@ -1042,13 +1174,14 @@ async fn load_joined_room(
         state: State {
             events: state_events
                 .iter()
+                .filter(|pdu| pdu_matches_filter(&filter.state, pdu))
                 .map(|pdu| pdu.to_sync_state_event())
                 .collect(),
         },
         ephemeral: Ephemeral {
             events: edus,
         },
-        unread_thread_notifications: BTreeMap::new(),
+        unread_thread_notifications,
     })
 }
 
@@ -1057,6 +1190,7 @@ fn load_timeline(
     room_id: &RoomId,
     roomsincecount: PduCount,
     limit: u64,
+    filter: &RoomEventFilter,
 ) -> Result<(Vec<(PduCount, PduEvent)>, bool), Error> {
     let timeline_pdus;
     let limited;
@@ -1074,7 +1208,8 @@ fn load_timeline(
                     None
                 }
             })
-            .take_while(|(pducount, _)| pducount > &roomsincecount);
+            .take_while(|(pducount, _)| pducount > &roomsincecount)
+            .filter(|(_, pdu)| pdu_matches_filter(filter, pdu));
 
         // Take the last events for the timeline
         timeline_pdus = non_timeline_pdus
@@ -1535,6 +1670,7 @@ pub(crate) async fn sync_events_v4_route(
             room_id,
             roomsincecount,
             *timeline_limit,
+            &RoomEventFilter::default(),
         )?;
 
         if roomsince != &0 && timeline_pdus.is_empty() {
@@ -1554,10 +1690,14 @@ pub(crate) async fn sync_events_v4_route(
             })?
             .or_else(|| (roomsince != &0).then(|| roomsince.to_string()));
 
-        let room_events: Vec<_> = timeline_pdus
+        let room_events = timeline_pdus
             .iter()
-            .map(|(_, pdu)| pdu.to_sync_room_event())
-            .collect();
+            .map(|(_, pdu)| {
+                let mut pdu = pdu.clone();
+                services().rooms.pdu_metadata.bundle_aggregations(&mut pdu)?;
+                Ok(pdu.to_sync_room_event())
+            })
+            .collect::<Result<Vec<_>>>()?;
 
         let required_state = required_state_request
             .iter()
@@ -1592,6 +1732,7 @@ pub(crate) async fn sync_events_v4_route(
                                 .displayname
                                 .unwrap_or_else(|| member.to_string()),
                             memberevent.avatar_url,
+                            member,
                         )
                     })
             })
@@ -1617,6 +1758,16 @@ pub(crate) async fn sync_events_v4_route(
             None
         };
 
+        // Count events in timeline greater than the room's sync counter
+        let num_live = timeline_pdus
+            .iter()
+            .filter(|(pdu_count, _)| *pdu_count > roomsincecount)
+            .count();
+
+        let timestamp = timeline_pdus
+            .last()
+            .map(|(_, pdu)| MilliSecondsSinceUnixEpoch(pdu.origin_server_ts));
+
         rooms.insert(
             room_id.clone(),
             sync_events::v4::SlidingSyncRoom {
@@ -1677,25 +1828,98 @@ pub(crate) async fn sync_events_v4_route(
                         .map(UInt::new_saturating)
                         .unwrap_or(uint!(0)),
                 ),
-                // Count events in timeline greater than global sync counter
-                num_live: None,
-                timestamp: None,
-                // TODO
-                heroes: None,
+                num_live: Some(UInt::try_from(num_live).unwrap_or(UInt::MAX)),
+                timestamp,
+                heroes: Some(
+                    heroes
+                        .into_iter()
+                        .map(|(name, avatar, user_id)| {
+                            sync_events::v4::SlidingSyncRoomHero {
+                                user_id,
+                                name: Some(name),
+                                avatar,
+                            }
+                        })
+                        .collect(),
+                ),
             },
         );
     }
 
+    let mut room_account_data = BTreeMap::new();
+    if body.extensions.account_data.enabled.unwrap_or(false) {
+        for room_id in todo_rooms.keys() {
+            let events: Vec<_> = services()
+                .account_data
+                .changes_since(Some(room_id), &sender_user, globalsince)?
+                .into_iter()
+                .filter_map(|(_, v)| {
+                    serde_json::from_str(v.json().get())
+                        .map_err(|_| {
+                            Error::bad_database(
+                                "Invalid account event in database.",
+                            )
+                        })
+                        .ok()
+                })
+                .collect();
+
+            if !events.is_empty() {
+                room_account_data.insert(room_id.clone(), events);
+            }
+        }
+    }
+
+    let mut receipts = BTreeMap::new();
+    if body.extensions.receipts.enabled.unwrap_or(false) {
+        for room_id in todo_rooms.keys() {
+            if let Some(receipt) = pack_receipts(
+                services()
+                    .rooms
+                    .edus
+                    .read_receipt
+                    .readreceipts_since(room_id, globalsince)
+                    .filter_map(Result::ok)
+                    .map(|(_, _, v)| v),
+            ) {
+                receipts.insert(room_id.clone(), receipt);
+            }
+        }
+    }
+
+    let mut typing = BTreeMap::new();
+    if body.extensions.typing.enabled.unwrap_or(false) {
+        for room_id in todo_rooms.keys() {
+            if services().rooms.edus.typing.last_typing_update(room_id).await?
+                > globalsince
+            {
+                typing.insert(
+                    room_id.clone(),
+                    Raw::new(
+                        &services()
+                            .rooms
+                            .edus
+                            .typing
+                            .typings_all(room_id)
+                            .await?,
+                    )
+                    .expect("event is valid, we just created it"),
+                );
+            }
+        }
+    }
+
     if rooms
         .iter()
         .all(|(_, r)| r.timeline.is_empty() && r.required_state.is_empty())
+        && receipts.is_empty()
+        && typing.is_empty()
     {
         // Hang a few seconds so requests are not spammed
         // Stop hanging if new info arrives
-        let mut duration = body.timeout.unwrap_or(Duration::from_secs(30));
-        if duration.as_secs() > 30 {
-            duration = Duration::from_secs(30);
-        }
+        let duration = clamp_sync_timeout(
+            body.timeout.unwrap_or(services().globals.config.max_sync_timeout),
+        );
         match tokio::time::timeout(duration, watcher).await {
             Ok(x) => x.expect("watcher should succeed"),
             Err(error) => debug!(%error, "Timed out"),
@@ -1732,8 +1956,12 @@ pub(crate) async fn sync_events_v4_route(
                 device_one_time_keys_count: services()
                     .users
                     .count_one_time_keys(&sender_user, &sender_device)?,
-                // Fallback keys are not yet supported
-                device_unused_fallback_key_types: None,
+                device_unused_fallback_key_types: Some(
+                    services().users.unused_fallback_key_types(
+                        &sender_user,
+                        &sender_device,
+                    )?,
+                ),
             },
             account_data: sync_events::v4::AccountData {
                 global: if body.extensions.account_data.enabled.unwrap_or(false)
@@ -1755,15 +1983,106 @@ pub(crate) async fn sync_events_v4_route(
                 } else {
                     Vec::new()
                 },
-                rooms: BTreeMap::new(),
+                rooms: room_account_data,
             },
             receipts: sync_events::v4::Receipts {
-                rooms: BTreeMap::new(),
+                rooms: receipts,
             },
             typing: sync_events::v4::Typing {
-                rooms: BTreeMap::new(),
+                rooms: typing,
             },
         },
         delta_token: None,
     }))
 }
+
+/// Limits the long-polling duration requested by a client to the configured
+/// bounds.
+fn clamp_sync_timeout(timeout: Duration) -> Duration {
+    let config = &services().globals.config;
+
+    // Not using `Duration::clamp` because it panics if the configured minimum
+    // is larger than the maximum
+    timeout.min(config.max_sync_timeout).max(config.min_sync_timeout)
+}
+
+/// Returns the user's private read receipt in a room if it changed after
+/// `since`.
+///
+/// Private read receipts are only stored locally, so they are never part of
+/// the read receipts that other users can see.
+fn private_read_receipt(
+    sender_user: &UserId,
+    room_id: &RoomId,
+    since: u64,
+) -> Result<Option<Raw<AnySyncEphemeralRoomEvent>>> {
+    let read_receipt = &services().rooms.edus.read_receipt;
+
+    if read_receipt.last_privateread_update(sender_user, room_id)? <= since {
+        return Ok(None);
+    }
+
+    let Some(count) = read_receipt.private_read_get(room_id, sender_user)?
+    else {
+        return Ok(None);
+    };
+
+    // The marker may point between events if it was set by sending a message,
+    // so use the latest event at or before it
+    let Some((_, pdu)) = services()
+        .rooms
+        .timeline
+        .pdus_until(sender_user, room_id, PduCount::Normal(count + 1))?
+        .find_map(Result::ok)
+    else {
+        return Ok(None);
+    };
+
+    let receipt = Receipt {
+        ts: None,
+        thread: ReceiptThread::Unthreaded,
+    };
+    let content = ReceiptEventContent(BTreeMap::from([(
+        pdu.event_id.as_ref().to_owned(),
+        BTreeMap::from([(
+            ReceiptType::ReadPrivate,
+            BTreeMap::from([(sender_user.to_owned(), receipt)]),
+        )]),
+    )]));
+
+    Ok(Some(
+        Raw::new(&SyncReceiptEvent {
+            content,
+        })
+        .expect("event is valid, we just created it")
+        .cast(),
+    ))
+}
+
+/// Merges the read receipts of multiple users into a single receipt event.
+fn pack_receipts<I>(receipts: I) -> Option<Raw<SyncReceiptEvent>>
+where
+    I: Iterator<Item = Raw<AnySyncEphemeralRoomEvent>>,
+{
+    let mut content = ReceiptEventContent(BTreeMap::new());
+
+    for receipt in receipts {
+        let Ok(receipt) = receipt.deserialize_as::<SyncReceiptEvent>() else {
+            continue;
+        };
+
+        for (event_id, event_receipts) in receipt.content.0 {
+            let entry = content.0.entry(event_id).or_default();
+            for (receipt_type, user_receipts) in event_receipts {
+                entry.entry(receipt_type).or_default().extend(user_receipts);
+            }
+        }
+    }
+
+    (!content.0.is_empty()).then(|| {
+        Raw::new(&SyncReceiptEvent {
+            content,
+        })
+        .expect("event is valid, we just created it")
+    })
+}
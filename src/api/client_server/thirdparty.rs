@@ -1,17 +1,262 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::{btree_map, BTreeMap},
+    fmt::Debug,
+};
 
-use ruma::api::client::thirdparty::get_protocols;
+use ruma::{
+    api::{
+        appservice::{self, Registration},
+        client::{
+            error::ErrorKind,
+            thirdparty::{
+                get_location_for_protocol, get_location_for_room_alias,
+                get_protocol, get_protocols, get_user_for_protocol,
+                get_user_for_user_identifier,
+            },
+        },
+        OutgoingRequest,
+    },
+    thirdparty::Protocol,
+};
+use tracing::warn;
 
-use crate::{Ar, Ra, Result};
+use crate::{services, Ar, Error, Ra, Result};
 
 /// # `GET /_matrix/client/r0/thirdparty/protocols`
 ///
-/// TODO: Fetches all metadata about protocols supported by the homeserver.
+/// Fetches all metadata about protocols supported by the homeserver.
+///
+/// - Protocols are provided by the appservices that list them in their
+///   registration
 pub(crate) async fn get_protocols_route(
     _body: Ar<get_protocols::v3::Request>,
 ) -> Result<Ra<get_protocols::v3::Response>> {
-    // TODO
+    let mut protocols = BTreeMap::new();
+
+    for registration in appservice_registrations().await {
+        for protocol_id in registration.protocols.iter().flatten() {
+            let Some(protocol) =
+                query_protocol(registration.clone(), protocol_id).await
+            else {
+                continue;
+            };
+
+            // Several bridges may provide instances of the same protocol
+            match protocols.entry(protocol_id.clone()) {
+                btree_map::Entry::Vacant(entry) => {
+                    entry.insert(protocol);
+                }
+                btree_map::Entry::Occupied(mut entry) => {
+                    entry.get_mut().instances.extend(protocol.instances);
+                }
+            }
+        }
+    }
+
     Ok(Ra(get_protocols::v3::Response {
-        protocols: BTreeMap::new(),
+        protocols,
+    }))
+}
+
+/// # `GET /_matrix/client/r0/thirdparty/protocol/{protocol}`
+///
+/// Fetches the metadata of a protocol from the first appservice providing it.
+pub(crate) async fn get_protocol_route(
+    body: Ar<get_protocol::v3::Request>,
+) -> Result<Ra<get_protocol::v3::Response>> {
+    for registration in protocol_registrations(&body.protocol).await {
+        if let Some(protocol) =
+            query_protocol(registration, &body.protocol).await
+        {
+            return Ok(Ra(get_protocol::v3::Response {
+                protocol,
+            }));
+        }
+    }
+
+    Err(Error::BadRequest(ErrorKind::NotFound, "Protocol not found."))
+}
+
+/// # `GET /_matrix/client/r0/thirdparty/location/{protocol}`
+///
+/// Looks up third party locations in all appservices providing the protocol.
+pub(crate) async fn get_location_for_protocol_route(
+    body: Ar<get_location_for_protocol::v3::Request>,
+) -> Result<Ra<get_location_for_protocol::v3::Response>> {
+    let mut locations = Vec::new();
+
+    for registration in protocol_registrations(&body.protocol).await {
+        let request =
+            appservice::thirdparty::get_location_for_protocol::v1::Request {
+                protocol: body.protocol.clone(),
+                fields: body.fields.clone(),
+            };
+
+        if let Some(response) = query(registration, request).await {
+            locations.extend(response.locations);
+        }
+    }
+
+    Ok(Ra(get_location_for_protocol::v3::Response {
+        locations,
+    }))
+}
+
+/// # `GET /_matrix/client/r0/thirdparty/user/{protocol}`
+///
+/// Looks up third party users in all appservices providing the protocol.
+pub(crate) async fn get_user_for_protocol_route(
+    body: Ar<get_user_for_protocol::v3::Request>,
+) -> Result<Ra<get_user_for_protocol::v3::Response>> {
+    let mut users = Vec::new();
+
+    for registration in protocol_registrations(&body.protocol).await {
+        let request =
+            appservice::thirdparty::get_user_for_protocol::v1::Request {
+                protocol: body.protocol.clone(),
+                fields: body.fields.clone(),
+            };
+
+        if let Some(response) = query(registration, request).await {
+            users.extend(response.users);
+        }
+    }
+
+    Ok(Ra(get_user_for_protocol::v3::Response {
+        users,
     }))
 }
+
+/// # `GET /_matrix/client/r0/thirdparty/location`
+///
+/// Looks up the third party locations of a room alias in the appservices
+/// whose alias namespace contains it.
+pub(crate) async fn get_location_for_room_alias_route(
+    body: Ar<get_location_for_room_alias::v3::Request>,
+) -> Result<Ra<get_location_for_room_alias::v3::Response>> {
+    let registrations: Vec<_> = services()
+        .appservice
+        .read()
+        .await
+        .values()
+        .filter(|info| info.aliases.is_match(body.alias.as_str()))
+        .map(|info| info.registration.clone())
+        .collect();
+
+    let mut locations = Vec::new();
+    for registration in registrations {
+        let request =
+            appservice::thirdparty::get_location_for_room_alias::v1::Request {
+                alias: body.alias.clone(),
+            };
+
+        if let Some(response) = query(registration, request).await {
+            locations.extend(response.locations);
+        }
+    }
+
+    Ok(Ra(get_location_for_room_alias::v3::Response {
+        locations,
+    }))
+}
+
+/// # `GET /_matrix/client/r0/thirdparty/user`
+///
+/// Looks up the third party users of a Matrix user in the appservices whose
+/// user namespace contains it.
+pub(crate) async fn get_user_for_user_identifier_route(
+    body: Ar<get_user_for_user_identifier::v3::Request>,
+) -> Result<Ra<get_user_for_user_identifier::v3::Response>> {
+    let registrations: Vec<_> = services()
+        .appservice
+        .read()
+        .await
+        .values()
+        .filter(|info| info.is_user_match(&body.userid))
+        .map(|info| info.registration.clone())
+        .collect();
+
+    let mut users = Vec::new();
+    for registration in registrations {
+        let request =
+            appservice::thirdparty::get_user_for_user_identifier::v1::Request {
+                userid: body.userid.clone(),
+            };
+
+        if let Some(response) = query(registration, request).await {
+            users.extend(response.users);
+        }
+    }
+
+    Ok(Ra(get_user_for_user_identifier::v3::Response {
+        users,
+    }))
+}
+
+/// Returns the registrations of all appservices
+async fn appservice_registrations() -> Vec<Registration> {
+    services()
+        .appservice
+        .read()
+        .await
+        .values()
+        .map(|info| info.registration.clone())
+        .collect()
+}
+
+/// Returns the registrations of the appservices that provide `protocol`
+async fn protocol_registrations(protocol: &str) -> Vec<Registration> {
+    appservice_registrations()
+        .await
+        .into_iter()
+        .filter(|registration| {
+            registration
+                .protocols
+                .iter()
+                .flatten()
+                .any(|provided| provided == protocol)
+        })
+        .collect()
+}
+
+/// Fetches the metadata of `protocol` from an appservice
+async fn query_protocol(
+    registration: Registration,
+    protocol: &str,
+) -> Option<Protocol> {
+    let request = appservice::thirdparty::get_protocol::v1::Request {
+        protocol: protocol.to_owned(),
+    };
+
+    query(registration, request).await.map(|response| response.protocol)
+}
+
+/// Sends a third party lookup to an appservice
+///
+/// Appservices that fail to answer are skipped so that one broken bridge
+/// doesn't break lookups for the others.
+async fn query<T>(
+    registration: Registration,
+    request: T,
+) -> Option<T::IncomingResponse>
+where
+    T: OutgoingRequest + Debug,
+{
+    let appservice = registration.id.clone();
+
+    match services()
+        .sending
+        .send_appservice_request(registration, request)
+        .await
+    {
+        Ok(response) => response,
+        Err(error) => {
+            warn!(
+                %error,
+                appservice,
+                "Appservice failed to answer third party lookup"
+            );
+            None
+        }
+    }
+}
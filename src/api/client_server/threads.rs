@@ -1,6 +1,6 @@
-use ruma::api::client::{error::ErrorKind, threads::get_threads};
+use ruma::api::client::threads::get_threads;
 
-use crate::{services, Ar, Error, Ra, Result};
+use crate::{service::rooms::timeline::PduCount, services, Ar, Ra, Result};
 
 /// # `GET /_matrix/client/r0/rooms/{roomId}/threads`
 pub(crate) async fn get_threads_route(
@@ -13,10 +13,9 @@ pub(crate) async fn get_threads_route(
         body.limit.and_then(|l| l.try_into().ok()).unwrap_or(10).min(100);
 
     let from = if let Some(from) = &body.from {
-        from.parse()
-            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, ""))?
+        PduCount::try_from_string(from)?
     } else {
-        u64::MAX
+        PduCount::MAX
     };
 
     let threads = services()
@@ -34,7 +33,7 @@ pub(crate) async fn get_threads_route(
         })
         .collect::<Vec<_>>();
 
-    let next_batch = threads.last().map(|(count, _)| count.to_string());
+    let next_batch = threads.last().map(|(count, _)| count.stringify());
 
     Ok(Ra(get_threads::v1::Response {
         chunk: threads
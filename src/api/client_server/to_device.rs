@@ -5,14 +5,25 @@ use ruma::{
         client::{error::ErrorKind, to_device::send_event_to_device},
         federation::{self, transactions::edu::DirectDeviceContent},
     },
+    events::{AnyToDeviceEventContent, ToDeviceEventType},
+    serde::Raw,
     to_device::DeviceIdOrAllDevices,
+    OwnedServerName, OwnedTransactionId, OwnedUserId, ServerName, UserId,
 };
 
 use crate::{services, Ar, Error, Ra, Result};
 
+type Messages = BTreeMap<
+    OwnedUserId,
+    BTreeMap<DeviceIdOrAllDevices, Raw<AnyToDeviceEventContent>>,
+>;
+
 /// # `PUT /_matrix/client/r0/sendToDevice/{eventType}/{txnId}`
 ///
 /// Send a to-device event to a set of client devices.
+///
+/// - Messages for users on other servers are sent as one
+///   `m.direct_to_device` EDU per server
 pub(crate) async fn send_event_to_device_route(
     body: Ar<send_event_to_device::v3::Request>,
 ) -> Result<Ra<send_event_to_device::v3::Response>> {
@@ -28,35 +39,32 @@ pub(crate) async fn send_event_to_device_route(
         return Ok(Ra(send_event_to_device::v3::Response {}));
     }
 
+    let count = services().globals.next_count()?;
+    for (server, edu) in remote_to_device_edus(
+        sender_user,
+        &body.event_type,
+        &body.messages,
+        services().globals.server_name(),
+        // Unique for each request, which is all that remote servers need to
+        // deduplicate retried transactions
+        count.to_string().into(),
+    ) {
+        services().sending.send_reliable_edu(
+            &server,
+            serde_json::to_vec(
+                &federation::transactions::edu::Edu::DirectToDevice(edu),
+            )
+            .expect("DirectToDevice EDU can be serialized"),
+            count,
+        )?;
+    }
+
     for (target_user_id, map) in &body.messages {
-        for (target_device_id_maybe, event) in map {
-            if target_user_id.server_name() != services().globals.server_name()
-            {
-                let mut map = BTreeMap::new();
-                map.insert(target_device_id_maybe.clone(), event.clone());
-                let mut messages = BTreeMap::new();
-                messages.insert(target_user_id.clone(), map);
-                let count = services().globals.next_count()?;
-
-                services().sending.send_reliable_edu(
-                    target_user_id.server_name(),
-                    serde_json::to_vec(
-                        &federation::transactions::edu::Edu::DirectToDevice(
-                            DirectDeviceContent {
-                                sender: sender_user.clone(),
-                                ev_type: body.event_type.clone(),
-                                message_id: count.to_string().into(),
-                                messages,
-                            },
-                        ),
-                    )
-                    .expect("DirectToDevice EDU can be serialized"),
-                    count,
-                )?;
-
-                continue;
-            }
+        if target_user_id.server_name() != services().globals.server_name() {
+            continue;
+        }
 
+        for (target_device_id_maybe, event) in map {
             match target_device_id_maybe {
                 DeviceIdOrAllDevices::DeviceId(target_device_id) => {
                     services().users.add_to_device_event(
@@ -105,3 +113,134 @@ pub(crate) async fn send_event_to_device_route(
 
     Ok(Ra(send_event_to_device::v3::Response {}))
 }
+
+/// Groups the messages for users on servers other than `local_server` into
+/// one `m.direct_to_device` EDU per server.
+fn remote_to_device_edus(
+    sender: &UserId,
+    ev_type: &ToDeviceEventType,
+    messages: &Messages,
+    local_server: &ServerName,
+    message_id: OwnedTransactionId,
+) -> BTreeMap<OwnedServerName, DirectDeviceContent> {
+    let mut edus = BTreeMap::<OwnedServerName, DirectDeviceContent>::new();
+
+    for (target_user_id, map) in messages {
+        let server = target_user_id.server_name();
+        if server == local_server {
+            continue;
+        }
+
+        edus.entry(server.to_owned())
+            .or_insert_with(|| DirectDeviceContent {
+                sender: sender.to_owned(),
+                ev_type: ev_type.clone(),
+                message_id: message_id.clone(),
+                messages: BTreeMap::new(),
+            })
+            .messages
+            .insert(target_user_id.clone(), map.clone());
+    }
+
+    edus
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, ops::Deref};
+
+    use ruma::{
+        api::federation::transactions::edu::Edu,
+        events::{AnyToDeviceEventContent, ToDeviceEventType},
+        owned_device_id,
+        serde::Raw,
+        server_name,
+        to_device::DeviceIdOrAllDevices,
+        user_id, UserId,
+    };
+    use serde_json::{json, value::to_raw_value};
+
+    use super::{remote_to_device_edus, Messages};
+
+    fn room_key() -> Raw<AnyToDeviceEventContent> {
+        Raw::from_json(
+            to_raw_value(&json!({
+                "algorithm": "m.megolm.v1.aes-sha2",
+                "room_id": "!room:a.example.com",
+                "session_id": "session",
+                "session_key": "key",
+            }))
+            .unwrap(),
+        )
+    }
+
+    /// Room keys for users on other servers are sent as a single EDU per
+    /// server, which deserializes to the same messages on the receiving side.
+    #[test]
+    fn room_keys_for_remote_users_are_grouped_by_server() {
+        let sender = user_id!("@alice:a.example.com");
+        let messages: Messages = BTreeMap::from([
+            (
+                user_id!("@bob:b.example.com").to_owned(),
+                BTreeMap::from([(
+                    DeviceIdOrAllDevices::DeviceId(owned_device_id!("BOB")),
+                    room_key(),
+                )]),
+            ),
+            (
+                user_id!("@carol:b.example.com").to_owned(),
+                BTreeMap::from([(
+                    DeviceIdOrAllDevices::AllDevices,
+                    room_key(),
+                )]),
+            ),
+            (
+                user_id!("@dave:a.example.com").to_owned(),
+                BTreeMap::from([(
+                    DeviceIdOrAllDevices::AllDevices,
+                    room_key(),
+                )]),
+            ),
+        ]);
+
+        let edus = remote_to_device_edus(
+            sender,
+            &ToDeviceEventType::RoomKey,
+            &messages,
+            server_name!("a.example.com"),
+            "1".to_owned().into(),
+        );
+
+        // Local users don't need an EDU
+        assert_eq!(edus.len(), 1);
+        let (server, edu) = edus.into_iter().next().unwrap();
+        assert_eq!(server.as_str(), "b.example.com");
+
+        // What server B receives in the transaction
+        let received: Edu = serde_json::from_slice(
+            &serde_json::to_vec(&Edu::DirectToDevice(edu)).unwrap(),
+        )
+        .unwrap();
+        let Edu::DirectToDevice(received) = received else {
+            panic!("EDU should be a to-device message");
+        };
+
+        assert_eq!(received.sender, sender.to_owned());
+        assert_eq!(received.ev_type, ToDeviceEventType::RoomKey);
+        assert_eq!(received.message_id.as_str(), "1");
+        assert_eq!(
+            received
+                .messages
+                .keys()
+                .map(Deref::deref)
+                .map(UserId::as_str)
+                .collect::<Vec<_>>(),
+            ["@bob:b.example.com", "@carol:b.example.com"]
+        );
+        let key = received.messages[user_id!("@bob:b.example.com")]
+            [&DeviceIdOrAllDevices::DeviceId(owned_device_id!("BOB"))]
+            .deserialize_as::<serde_json::Value>()
+            .unwrap();
+        assert_eq!(key["session_key"], "key");
+    }
+}
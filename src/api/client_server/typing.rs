@@ -5,6 +5,9 @@ use crate::{services, utils, Ar, Error, Ra, Result};
 /// # `PUT /_matrix/client/r0/rooms/{roomId}/typing/{userId}`
 ///
 /// Sets the typing state of the sender user.
+///
+/// The timeout is capped to the configured `max_typing_timeout`, after which
+/// the user stops being shown as typing unless they send another notification.
 pub(crate) async fn create_typing_event_route(
     body: Ar<create_typing_event::v3::Request>,
 ) -> Result<Ra<create_typing_event::v3::Response>> {
@@ -27,8 +30,13 @@ pub(crate) async fn create_typing_event_route(
             .typing_add(
                 sender_user,
                 &body.room_id,
-                duration.as_millis().try_into().unwrap_or(u64::MAX)
-                    + utils::millis_since_unix_epoch(),
+                u64::try_from(
+                    duration
+                        .min(services().globals.config.max_typing_timeout)
+                        .as_millis(),
+                )
+                .unwrap_or(u64::MAX)
+                .saturating_add(utils::millis_since_unix_epoch()),
             )
             .await?;
     } else {
@@ -1,8 +1,23 @@
 use std::{collections::BTreeMap, iter::FromIterator};
 
-use ruma::api::client::discovery::get_supported_versions;
+use axum::Json;
+use ruma::{api::client::discovery::get_supported_versions, RoomVersionId};
+use serde::Serialize;
 
-use crate::{Ar, Ra, Result};
+use crate::{services, Ar, Ra, Result};
+
+/// Response of `GET /_grapevine/version`
+#[derive(Serialize)]
+pub(crate) struct BuildInfo {
+    /// Version of the crate, with `GRAPEVINE_VERSION_EXTRA` if it was set
+    version: String,
+    /// Commit the server was built from, taken from `GRAPEVINE_GIT_COMMIT`
+    git_commit: Option<&'static str>,
+    /// Optional cargo features the server was built with
+    features: Vec<&'static str>,
+    /// Room versions this server can create and join rooms of
+    room_versions: Vec<RoomVersionId>,
+}
 
 /// # `GET /_matrix/client/versions`
 ///
@@ -37,3 +52,29 @@ pub(crate) async fn get_supported_versions_route(
 
     Ok(Ra(resp))
 }
+
+/// # `GET /_grapevine/version`
+///
+/// Returns details about the build of this server, so that admins can confirm
+/// what is deployed.
+///
+/// - Only available if `expose_build_info` is set, since the enabled features
+///   could help attackers
+pub(crate) async fn get_build_info_route() -> Json<BuildInfo> {
+    let features = [
+        ("jemalloc", cfg!(feature = "jemalloc")),
+        ("rocksdb", cfg!(feature = "rocksdb")),
+        ("sqlite", cfg!(feature = "sqlite")),
+        ("systemd", cfg!(feature = "systemd")),
+    ]
+    .into_iter()
+    .filter_map(|(feature, enabled)| enabled.then_some(feature))
+    .collect();
+
+    Json(BuildInfo {
+        version: crate::version(),
+        git_commit: option_env!("GRAPEVINE_GIT_COMMIT"),
+        features,
+        room_versions: services().globals.supported_room_versions(),
+    })
+}
@@ -0,0 +1,35 @@
+use ruma::api::client::{
+    discovery::discover_homeserver::{
+        self, HomeserverInfo, IdentityServerInfo, SlidingSyncProxyInfo,
+    },
+    error::ErrorKind,
+};
+
+use crate::{services, Ar, Error, Ra, Result};
+
+/// # `GET /.well-known/matrix/client`
+///
+/// Returns the base URLs clients should use for this server, as configured in
+/// `well_known`.
+pub(crate) async fn well_known_client_route(
+    _body: Ar<discover_homeserver::Request>,
+) -> Result<Ra<discover_homeserver::Response>> {
+    let config = &services().globals.config.well_known;
+
+    let Some(base_url) = &config.client else {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Client discovery is not configured.",
+        ));
+    };
+
+    let mut response = discover_homeserver::Response::new(HomeserverInfo::new(
+        base_url.clone(),
+    ));
+    response.identity_server =
+        config.identity_server.clone().map(IdentityServerInfo::new);
+    response.sliding_sync_proxy =
+        config.sliding_sync_proxy.clone().map(SlidingSyncProxyInfo::new);
+
+    Ok(Ra(response))
+}
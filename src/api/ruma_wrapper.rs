@@ -32,6 +32,13 @@ impl<T> Deref for Ar<T> {
     }
 }
 
+/// The limit enforced by the [`DefaultBodyLimit`] of a route, stored as a
+/// request extension so that it can be reported to clients exceeding it
+///
+/// [`DefaultBodyLimit`]: axum::extract::DefaultBodyLimit
+#[derive(Clone, Copy)]
+pub(crate) struct BodyLimit(pub(crate) u32);
+
 /// A wrapper to convert Ruma data to an Axum response
 ///
 /// Named so because this converts from **R**uma to **A**xum. See also [`Ar`],
@@ -3,7 +3,7 @@ use std::{collections::BTreeMap, iter::FromIterator, str};
 use axum::{
     async_trait,
     body::Body,
-    extract::{FromRequest, Path},
+    extract::{FromRequest, MatchedPath, Path},
     response::{IntoResponse, Response},
     RequestExt, RequestPartsExt,
 };
@@ -14,7 +14,7 @@ use axum_extra::{
 };
 use bytes::{BufMut, Bytes, BytesMut};
 use http::{Request, StatusCode};
-use http_body_util::BodyExt;
+use http_body_util::{BodyExt, LengthLimitError};
 use ruma::{
     api::{
         client::error::ErrorKind, AuthScheme, IncomingRequest, Metadata,
@@ -27,16 +27,52 @@ use ruma::{
 use serde::Deserialize;
 use tracing::{error, warn};
 
-use super::{Ar, Ra};
-use crate::{service::appservice::RegistrationInfo, services, Error, Result};
+use super::{Ar, BodyLimit, Ra};
+use crate::{
+    observability::{FederationDirection, METRICS},
+    service::appservice::RegistrationInfo,
+    services,
+    utils::{
+        client_ip::ClientIp,
+        rate_limiter::{RateLimitClass, RateLimitKey},
+    },
+    Error, Result,
+};
 
 enum Token {
     Appservice(Box<RegistrationInfo>),
     User((OwnedUserId, OwnedDeviceId)),
+    /// A known access token that has expired and needs to be refreshed
+    Expired,
     Invalid,
     None,
 }
 
+/// Rejects unknown access tokens, and expired ones unless the route doesn't
+/// need authentication.
+///
+/// Routes like `/refresh` are called by clients whose access token expired,
+/// and may be called with it.
+fn check_token(authentication: &AuthScheme, token: &Token) -> Result<()> {
+    match (authentication, token) {
+        (_, Token::Invalid) => Err(Error::BadRequest(
+            ErrorKind::UnknownToken {
+                soft_logout: false,
+            },
+            "Unknown access token.",
+        )),
+        (AuthScheme::None, Token::Expired) => Ok(()),
+        // Clients can keep their local state and use their refresh token
+        (_, Token::Expired) => Err(Error::BadRequest(
+            ErrorKind::UnknownToken {
+                soft_logout: true,
+            },
+            "Access token has expired.",
+        )),
+        _ => Ok(()),
+    }
+}
+
 /// Return value of [`ar_from_request_inner()`], used to construct an [`Ar`].
 struct ArPieces {
     sender_user: Option<OwnedUserId>,
@@ -67,8 +103,16 @@ async fn ar_from_request_inner(
         let body = body
             .collect()
             .await
-            .map_err(|_| {
-                Error::BadRequest(ErrorKind::MissingToken, "Missing token.")
+            .map_err(|error| {
+                if error.into_inner().is::<LengthLimitError>() {
+                    let BodyLimit(limit) =
+                        parts.extensions.get().copied().unwrap_or(BodyLimit(
+                            services().globals.max_request_size(),
+                        ));
+                    Error::TooLarge(limit)
+                } else {
+                    Error::BadRequest(ErrorKind::MissingToken, "Missing token.")
+                }
             })?
             .to_bytes();
         (parts, body)
@@ -90,9 +134,15 @@ async fn ar_from_request_inner(
         }
     };
 
-    let token = match &auth_header {
-        Some(TypedHeader(Authorization(bearer))) => Some(bearer.token()),
-        None => query_params.access_token.as_deref(),
+    // Federation requests never carry the access token of a user. The one of
+    // `/openid/userinfo` is an OpenID token that the route handler checks.
+    let token = if parts.uri.path().starts_with("/_matrix/federation/") {
+        None
+    } else {
+        match &auth_header {
+            Some(TypedHeader(Authorization(bearer))) => Some(bearer.token()),
+            None => query_params.access_token.as_deref(),
+        }
     };
 
     let token = if let Some(token) = token {
@@ -103,7 +153,13 @@ async fn ar_from_request_inner(
         } else if let Some((user_id, device_id)) =
             services().users.find_from_token(token)?
         {
-            Token::User((user_id, OwnedDeviceId::from(device_id)))
+            let device_id = OwnedDeviceId::from(device_id);
+            if services().users.token_expired(&user_id, &device_id)? {
+                Token::Expired
+            } else {
+                services().users.confirm_tokens(&user_id, &device_id)?;
+                Token::User((user_id, device_id))
+            }
         } else {
             Token::Invalid
         }
@@ -114,15 +170,13 @@ async fn ar_from_request_inner(
     let mut json_body =
         serde_json::from_slice::<CanonicalJsonValue>(&body).ok();
 
+    check_token(&metadata.authentication, &token)?;
+
     let (sender_user, sender_device, sender_servername, appservice_info) =
         match (metadata.authentication, token) {
-            (_, Token::Invalid) => {
-                return Err(Error::BadRequest(
-                    ErrorKind::UnknownToken {
-                        soft_logout: false,
-                    },
-                    "Unknown access token.",
-                ))
+            (AuthScheme::None, Token::Expired) => (None, None, None, None),
+            (_, Token::Invalid | Token::Expired) => {
+                unreachable!("token should have been rejected by check_token")
             }
             (AuthScheme::AccessToken, Token::Appservice(info)) => {
                 let user_id = query_params
@@ -210,6 +264,17 @@ async fn ar_from_request_inner(
                     }
                 }
 
+                if !services().globals.federation_allowed_with(&x_matrix.origin)
+                {
+                    METRICS.record_federation_blocked(
+                        FederationDirection::Inbound,
+                    );
+                    return Err(Error::BadRequest(
+                        ErrorKind::forbidden(),
+                        "Federation with this server is not allowed.",
+                    ));
+                }
+
                 let origin_signatures = BTreeMap::from_iter([(
                     x_matrix.key.to_string(),
                     CanonicalJsonValue::String(x_matrix.sig),
@@ -262,6 +327,7 @@ async fn ar_from_request_inner(
                         &x_matrix.origin,
                         vec![x_matrix.key.to_string()],
                         false,
+                        None,
                     )
                     .await;
 
@@ -340,6 +406,35 @@ async fn ar_from_request_inner(
             }
         };
 
+    let client_ip = parts.extensions.get::<ClientIp>().map(|ClientIp(ip)| *ip);
+
+    if let (Some(user_id), Some(device_id), None) =
+        (&sender_user, &sender_device, &appservice_info)
+    {
+        services()
+            .users
+            .update_device_last_seen(user_id, device_id, client_ip)?;
+    }
+
+    // Appservices and other servers are not rate limited
+    if sender_servername.is_none() && appservice_info.is_none() {
+        let key = sender_user
+            .clone()
+            .map(RateLimitKey::User)
+            .or_else(|| client_ip.map(RateLimitKey::Ip));
+
+        if let Some(key) = key {
+            let class = RateLimitClass::from_path(
+                parts
+                    .extensions
+                    .get::<MatchedPath>()
+                    .map_or_else(|| parts.uri.path(), MatchedPath::as_str),
+            );
+
+            services().globals.client_rate_limiters.check(class, key)?;
+        }
+    }
+
     let mut http_request =
         Request::builder().uri(parts.uri).method(parts.method);
     *http_request.headers_mut().unwrap() = parts.headers;
@@ -436,3 +531,67 @@ impl<T: OutgoingResponse> IntoResponse for Ra<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ruma::{
+        api::{client::error::ErrorKind, AuthScheme},
+        owned_device_id, owned_user_id,
+    };
+
+    use super::{check_token, Token};
+    use crate::Error;
+
+    fn soft_logout(authentication: &AuthScheme, token: &Token) -> Option<bool> {
+        match check_token(authentication, token) {
+            Ok(()) => None,
+            Err(Error::BadRequest(
+                ErrorKind::UnknownToken {
+                    soft_logout,
+                },
+                _,
+            )) => Some(soft_logout),
+            Err(error) => panic!("unexpected error: {error}"),
+        }
+    }
+
+    #[test]
+    fn expired_token_is_soft_logout() {
+        assert_eq!(
+            soft_logout(&AuthScheme::AccessToken, &Token::Expired),
+            Some(true)
+        );
+        assert_eq!(
+            soft_logout(&AuthScheme::AccessTokenOptional, &Token::Expired),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn unknown_token_is_hard_logout() {
+        assert_eq!(
+            soft_logout(&AuthScheme::AccessToken, &Token::Invalid),
+            Some(false)
+        );
+        assert_eq!(
+            soft_logout(&AuthScheme::None, &Token::Invalid),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn expired_token_is_ignored_without_authentication() {
+        // E.g. `/refresh`, which clients call once their token expired
+        assert_eq!(soft_logout(&AuthScheme::None, &Token::Expired), None);
+    }
+
+    #[test]
+    fn valid_token_is_accepted() {
+        let user = Token::User((
+            owned_user_id!("@alice:example.com"),
+            owned_device_id!("DEVICE"),
+        ));
+        assert_eq!(soft_logout(&AuthScheme::AccessToken, &user), None);
+        assert_eq!(soft_logout(&AuthScheme::AccessToken, &Token::None), None);
+    }
+}
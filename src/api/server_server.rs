@@ -6,11 +6,12 @@ use std::{
     mem,
     net::{IpAddr, SocketAddr},
     sync::Arc,
-    time::{Duration, Instant, SystemTime},
+    time::Instant,
 };
 
 use axum::{response::IntoResponse, Json};
 use axum_extra::headers::{Authorization, HeaderMapExt};
+use futures_util::{stream::FuturesUnordered, StreamExt};
 use get_profile_information::v1::ProfileField;
 use ruma::{
     api::{
@@ -21,21 +22,25 @@ use ruma::{
             device::get_devices::{self, v1::UserDevice},
             directory::{get_public_rooms, get_public_rooms_filtered},
             discovery::{
-                get_server_keys, get_server_version, ServerSigningKeys,
-                VerifyKey,
+                discover_homeserver, get_remote_server_keys,
+                get_remote_server_keys_batch, get_server_keys,
+                get_server_version, OldVerifyKey, ServerSigningKeys, VerifyKey,
             },
             event::{
                 get_event, get_missing_events, get_room_state,
                 get_room_state_ids,
             },
             keys::{claim_keys, get_keys},
+            knock::{create_knock_event_template, send_knock},
             membership::{
                 create_invite, create_join_event, prepare_join_event,
             },
+            openid::get_openid_userinfo,
             query::{get_profile_information, get_room_information},
+            space::get_hierarchy,
             transactions::{
                 edu::{
-                    DeviceListUpdateContent, DirectDeviceContent, Edu,
+                    DirectDeviceContent, Edu, PresenceContent,
                     SigningKeyUpdateContent,
                 },
                 send_transaction_message,
@@ -47,33 +52,37 @@ use ruma::{
     directory::{Filter, RoomNetwork},
     events::{
         receipt::{ReceiptEvent, ReceiptEventContent, ReceiptType},
-        room::{
-            join_rules::{JoinRule, RoomJoinRulesEventContent},
-            member::{MembershipState, RoomMemberEventContent},
-        },
-        StateEventType, TimelineEventType,
+        room::member::{MembershipState, RoomMemberEventContent},
+        TimelineEventType,
     },
-    serde::{Base64, JsonObject, Raw},
+    serde::{JsonObject, Raw},
     server_util::authorization::XMatrix,
     to_device::DeviceIdOrAllDevices,
     uint, user_id, CanonicalJsonObject, CanonicalJsonValue, EventId,
     MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, OwnedServerName,
     OwnedServerSigningKeyId, OwnedSigningKeyId, OwnedUserId, RoomId,
-    ServerName,
+    ServerName, UserId,
 };
 use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, Semaphore};
 use tracing::{debug, error, field, warn};
 
 use crate::{
     api::client_server::{self, claim_keys_helper, get_keys_helper},
-    observability::{FoundIn, Lookup, METRICS},
-    service::pdu::{gen_event_id_canonical_json, PduBuilder},
+    observability::{FederationDirection, FoundIn, Lookup, METRICS},
+    service::{
+        globals::SigningKeys,
+        pdu::{gen_event_id_canonical_json, PduBuilder},
+    },
     services, utils,
     utils::dbg_truncate_str,
     Ar, Error, PduEvent, Ra, Result,
 };
 
+/// Most servers whose keys can be queried from this server as a notary at
+/// once
+const MAX_NOTARY_QUERY_SERVERS: usize = 100;
+
 /// Wraps either an literal IP address plus port, or a hostname plus complement
 /// (colon-plus-port if it was specified).
 ///
@@ -130,11 +139,16 @@ impl FedDest {
     }
 }
 
+/// Sends a request to another server and parses its response.
+///
+/// Fails with `M_TOO_LARGE` if the response body is larger than
+/// `max_body_size` bytes. The body isn't read any further than that.
 #[tracing::instrument(skip(request, log_error), fields(url))]
 pub(crate) async fn send_request<T>(
     destination: &ServerName,
     request: T,
     log_error: bool,
+    max_body_size: Option<u64>,
 ) -> Result<T::IncomingResponse>
 where
     T: OutgoingRequest + Debug,
@@ -143,6 +157,13 @@ where
         return Err(Error::BadConfig("Federation is disabled."));
     }
 
+    if !services().globals.federation_allowed_with(destination) {
+        METRICS.record_federation_blocked(FederationDirection::Outbound);
+        return Err(Error::BadConfig(
+            "Federation with this server is not allowed.",
+        ));
+    }
+
     if destination == services().globals.server_name() {
         return Err(Error::bad_config(
             "Won't send federation request to ourselves",
@@ -221,7 +242,7 @@ where
 
     ruma::signatures::sign_json(
         services().globals.server_name().as_str(),
-        services().globals.keypair(),
+        &*services().globals.keypair(),
         &mut request_json,
     )
     .expect("our request json is what ruma expects");
@@ -279,10 +300,7 @@ where
 
     debug!("Getting response bytes");
     // TODO: handle timeout
-    let body = response.bytes().await.unwrap_or_else(|error| {
-        warn!(%error, "Server error");
-        Vec::new().into()
-    });
+    let body = read_body(response, max_body_size).await?;
     debug!("Got response bytes");
 
     if status != 200 {
@@ -322,6 +340,51 @@ where
     })
 }
 
+/// Reads the body of a response, failing with `M_TOO_LARGE` as soon as it is
+/// known to be larger than `max_size` bytes
+///
+/// Errors while reading the body result in an empty body.
+async fn read_body(
+    mut response: reqwest::Response,
+    max_size: Option<u64>,
+) -> Result<Vec<u8>> {
+    let too_large =
+        || Error::BadRequest(ErrorKind::TooLarge, "Response is too large.");
+
+    let Some(max_size) = max_size else {
+        return Ok(response.bytes().await.map_or_else(
+            |error| {
+                warn!(%error, "Server error");
+                Vec::new()
+            },
+            |bytes| bytes.to_vec(),
+        ));
+    };
+
+    if response.content_length().is_some_and(|length| length > max_size) {
+        return Err(too_large());
+    }
+
+    let mut body = Vec::new();
+    loop {
+        match response.chunk().await {
+            Ok(Some(chunk)) => {
+                if u64::try_from(body.len() + chunk.len())
+                    .map_or(true, |length| length > max_size)
+                {
+                    return Err(too_large());
+                }
+                body.extend_from_slice(&chunk);
+            }
+            Ok(None) => return Ok(body),
+            Err(error) => {
+                warn!(%error, "Server error");
+                return Ok(Vec::new());
+            }
+        }
+    }
+}
+
 fn get_ip_with_port(destination_str: &str) -> Option<FedDest> {
     if let Ok(destination) = destination_str.parse::<SocketAddr>() {
         Some(FedDest::Literal(destination))
@@ -557,6 +620,25 @@ pub(crate) async fn get_server_version_route(
     }))
 }
 
+/// # `GET /.well-known/matrix/server`
+///
+/// Returns the server federation traffic should be delegated to, as configured
+/// in `well_known`.
+pub(crate) async fn well_known_server_route(
+    _body: Ar<discover_homeserver::Request>,
+) -> Result<Ra<discover_homeserver::Response>> {
+    let Some(server) = &services().globals.config.well_known.server else {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Server delegation is not configured.",
+        ));
+    };
+
+    Ok(Ra(discover_homeserver::Response {
+        server: server.clone(),
+    }))
+}
+
 /// # `GET /_matrix/key/v2/server`
 ///
 /// Gets the public signing keys of this server.
@@ -567,29 +649,40 @@ pub(crate) async fn get_server_version_route(
 // Response type for this endpoint is Json because we need to calculate a
 // signature for the response
 pub(crate) async fn get_server_keys_route() -> Result<impl IntoResponse> {
-    let mut verify_keys: BTreeMap<OwnedServerSigningKeyId, VerifyKey> =
-        BTreeMap::new();
-    verify_keys.insert(
-        format!("ed25519:{}", services().globals.keypair().version())
-            .try_into()
-            .expect("found invalid server signing keys in DB"),
-        VerifyKey {
-            key: Base64::new(
-                services().globals.keypair().public_key().to_vec(),
-            ),
-        },
-    );
+    Ok(Json(own_server_keys()?))
+}
+
+/// Returns the public signing keys of this server, signed by this server
+fn own_server_keys() -> Result<CanonicalJsonObject> {
+    let SigningKeys {
+        verify_keys,
+        old_verify_keys,
+        valid_until_ts,
+    } = SigningKeys::load_own_keys()?;
+
+    let verify_keys: BTreeMap<OwnedServerSigningKeyId, VerifyKey> = verify_keys
+        .into_iter()
+        .map(|(id, key)| {
+            (
+                id.try_into().expect("found invalid server signing keys in DB"),
+                key,
+            )
+        })
+        .collect();
+    let old_verify_keys: BTreeMap<OwnedServerSigningKeyId, OldVerifyKey> =
+        old_verify_keys
+            .into_iter()
+            .filter_map(|(id, key)| Some((id.try_into().ok()?, key)))
+            .collect();
+
     let mut response = serde_json::from_slice(
         get_server_keys::v2::Response {
             server_key: Raw::new(&ServerSigningKeys {
                 server_name: services().globals.server_name().to_owned(),
                 verify_keys,
-                old_verify_keys: BTreeMap::new(),
+                old_verify_keys,
                 signatures: BTreeMap::new(),
-                valid_until_ts: MilliSecondsSinceUnixEpoch::from_system_time(
-                    SystemTime::now() + Duration::from_secs(86400 * 7),
-                )
-                .expect("time is valid"),
+                valid_until_ts,
             })
             .expect("static conversion, no errors"),
         }
@@ -601,12 +694,12 @@ pub(crate) async fn get_server_keys_route() -> Result<impl IntoResponse> {
 
     ruma::signatures::sign_json(
         services().globals.server_name().as_str(),
-        services().globals.keypair(),
+        &*services().globals.keypair(),
         &mut response,
     )
     .unwrap();
 
-    Ok(Json(response))
+    Ok(response)
 }
 
 /// # `GET /_matrix/key/v2/server/{keyId}`
@@ -620,6 +713,136 @@ pub(crate) async fn get_server_keys_deprecated_route() -> impl IntoResponse {
     get_server_keys_route().await
 }
 
+/// # `POST /_matrix/key/v2/query`
+///
+/// Gets the signing keys of other servers, signed by this server as a notary.
+///
+/// - Keys that are not cached or not valid until `minimum_valid_until_ts` are
+///   fetched from the servers
+pub(crate) async fn get_remote_server_keys_batch_route(
+    body: Ar<get_remote_server_keys_batch::v2::Request>,
+) -> Result<Ra<get_remote_server_keys_batch::v2::Response>> {
+    if body.server_keys.len() > MAX_NOTARY_QUERY_SERVERS {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Too many servers in key query.",
+        ));
+    }
+
+    let mut futures: FuturesUnordered<_> = body
+        .body
+        .server_keys
+        .into_iter()
+        .map(|(server_name, criteria)| async move {
+            let minimum_valid_until_ts = criteria
+                .values()
+                .filter_map(|criteria| criteria.minimum_valid_until_ts)
+                .max()
+                .unwrap_or_else(MilliSecondsSinceUnixEpoch::now);
+            let key_ids =
+                criteria.into_keys().map(|id| id.to_string()).collect();
+
+            notary_server_keys(&server_name, key_ids, minimum_valid_until_ts)
+                .await
+        })
+        .collect();
+
+    let mut server_keys = Vec::new();
+    while let Some(keys) = futures.next().await {
+        server_keys.extend(keys);
+    }
+
+    Ok(Ra(get_remote_server_keys_batch::v2::Response {
+        server_keys,
+    }))
+}
+
+/// # `GET /_matrix/key/v2/query/{serverName}`
+///
+/// Gets the signing keys of another server, signed by this server as a
+/// notary.
+///
+/// - Keys that are not cached or not valid until `minimum_valid_until_ts` are
+///   fetched from the server
+pub(crate) async fn get_remote_server_keys_route(
+    body: Ar<get_remote_server_keys::v2::Request>,
+) -> Result<Ra<get_remote_server_keys::v2::Response>> {
+    let server_keys = notary_server_keys(
+        &body.server_name,
+        Vec::new(),
+        body.minimum_valid_until_ts,
+    )
+    .await;
+
+    Ok(Ra(get_remote_server_keys::v2::Response {
+        server_keys: server_keys.into_iter().collect(),
+    }))
+}
+
+/// Returns the keys of `server_name` as it signed them, additionally signed by
+/// this server, if they are valid until at least `minimum_valid_until_ts`
+async fn notary_server_keys(
+    server_name: &ServerName,
+    key_ids: Vec<String>,
+    minimum_valid_until_ts: MilliSecondsSinceUnixEpoch,
+) -> Option<Raw<ServerSigningKeys>> {
+    if server_name == services().globals.server_name() {
+        let object = own_server_keys().ok()?;
+        return Some(Raw::from_json(
+            to_raw_value(&object).expect("canonical json is valid json"),
+        ));
+    }
+
+    // Fetches the keys from the server if the cached ones aren't valid for long
+    // enough, which also stores the response as the server signed it
+    if let Err(error) = services()
+        .rooms
+        .event_handler
+        .fetch_signing_keys(
+            server_name,
+            key_ids,
+            false,
+            Some(minimum_valid_until_ts),
+        )
+        .await
+    {
+        debug!(%error, %server_name, "Failed to fetch keys to notarize");
+        return None;
+    }
+
+    let server_keys = match services().globals.signed_keys_for(server_name) {
+        Ok(Some(server_keys)) => server_keys,
+        Ok(None) => return None,
+        Err(error) => {
+            debug!(%error, %server_name, "Failed to load keys to notarize");
+            return None;
+        }
+    };
+
+    let valid_until_ts = server_keys
+        .get_field::<MilliSecondsSinceUnixEpoch>("valid_until_ts")
+        .ok()
+        .flatten()?;
+    if valid_until_ts < minimum_valid_until_ts {
+        return None;
+    }
+
+    let mut object =
+        serde_json::from_str::<CanonicalJsonObject>(server_keys.json().get())
+            .ok()?;
+
+    ruma::signatures::sign_json(
+        services().globals.server_name().as_str(),
+        &*services().globals.keypair(),
+        &mut object,
+    )
+    .expect("server signing keys are what ruma expects");
+
+    Some(Raw::from_json(
+        to_raw_value(&object).expect("canonical json is valid json"),
+    ))
+}
+
 /// # `POST /_matrix/federation/v1/publicRooms`
 ///
 /// Lists the public rooms on this server.
@@ -707,11 +930,24 @@ pub(crate) async fn send_transaction_message_route(
 ) -> Result<Ra<send_transaction_message::v1::Response>> {
     let sender_servername =
         body.sender_servername.as_ref().expect("server is authenticated");
+    let transaction_start = Instant::now();
+
+    let config = &services().globals.config.federation;
+    check_transaction_size(
+        body.pdus.len(),
+        body.edus.len(),
+        config.max_transaction_pdus,
+        config.max_transaction_edus,
+    )
+    .inspect_err(|_| {
+        warn!(
+            pdus = body.pdus.len(),
+            edus = body.edus.len(),
+            "Rejecting oversized transaction",
+        );
+    })?;
 
-    let mut resolved_map = BTreeMap::new();
-
-    let pub_key_map = RwLock::new(BTreeMap::new());
-
+    let mut parsed_pdus = Vec::new();
     for pdu in &body.pdus {
         let value: CanonicalJsonObject = serde_json::from_str(pdu.get())
             .map_err(|error| {
@@ -741,36 +977,22 @@ pub(crate) async fn send_transaction_message_route(
         };
         // We do not add the event_id field to the pdu here because of signature
         // and hashes checks
+        parsed_pdus.push((room_id, event_id, value));
+    }
 
-        let federation_token = services()
-            .globals
-            .roomid_mutex_federation
-            .lock_key(room_id.clone())
-            .await;
-        let start_time = Instant::now();
-        resolved_map.insert(
-            event_id.clone(),
-            services()
-                .rooms
-                .event_handler
-                .handle_incoming_pdu(
-                    sender_servername,
-                    &event_id,
-                    &room_id,
-                    value,
-                    true,
-                    &pub_key_map,
-                )
-                .await
-                .map(|_| ()),
-        );
-        drop(federation_token);
+    // Rooms are handled concurrently, but the events of each room in the
+    // order they were sent
+    let pub_key_map = RwLock::new(BTreeMap::new());
+    let mut rooms: FuturesUnordered<_> = group_pdus_by_room(parsed_pdus)
+        .into_iter()
+        .map(|(room_id, pdus)| {
+            handle_room_pdus(sender_servername, room_id, pdus, &pub_key_map)
+        })
+        .collect();
 
-        debug!(
-            %event_id,
-            elapsed = ?start_time.elapsed(),
-            "Finished handling event",
-        );
+    let mut resolved_map = BTreeMap::new();
+    while let Some(results) = rooms.next().await {
+        resolved_map.extend(results);
     }
 
     for pdu in &resolved_map {
@@ -780,6 +1002,10 @@ pub(crate) async fn send_transaction_message_route(
             }
         }
     }
+    METRICS.record_federation_transaction(
+        body.pdus.len(),
+        transaction_start.elapsed(),
+    );
 
     for edu in body
         .edus
@@ -833,8 +1059,21 @@ pub(crate) async fn send_transaction_message_route(
                                 .edus
                                 .read_receipt
                                 .readreceipt_update(
-                                    &user_id, &room_id, event,
+                                    &user_id,
+                                    &room_id,
+                                    event.clone(),
                                 )?;
+                            if let Err(error) = services()
+                                .sending
+                                .send_edu_appservices_in_room(&room_id, &event)
+                                .await
+                            {
+                                warn!(
+                                    %error,
+                                    %room_id,
+                                    "Failed to send read receipt to appservices",
+                                );
+                            }
                         } else {
                             // TODO fetch missing events
                             debug!(
@@ -880,10 +1119,8 @@ pub(crate) async fn send_transaction_message_route(
                     }
                 }
             }
-            Edu::DeviceListUpdate(DeviceListUpdateContent {
-                user_id,
-                ..
-            }) => {
+            Edu::DeviceListUpdate(update) => {
+                let user_id = &update.user_id;
                 if user_id.server_name() != sender_servername {
                     warn!(
                         %user_id,
@@ -893,7 +1130,13 @@ pub(crate) async fn send_transaction_message_route(
                     );
                     continue;
                 }
-                services().users.mark_device_key_update(&user_id)?;
+
+                if services().users.apply_remote_device_list_update(
+                    &update,
+                    resync_remote_device_list,
+                ) {
+                    services().users.mark_device_key_update(user_id)?;
+                }
             }
             Edu::DirectToDevice(DirectDeviceContent {
                 sender,
@@ -917,7 +1160,34 @@ pub(crate) async fn send_transaction_message_route(
                     .is_none()
                 {
                     for (target_user_id, map) in &messages {
+                        if target_user_id.server_name()
+                            != services().globals.server_name()
+                        {
+                            warn!(
+                                %target_user_id,
+                                "Got to-device message for remote user, \
+                                 ignoring",
+                            );
+                            continue;
+                        }
+
                         for (target_device_id_maybe, event) in map {
+                            // An invalid message must not prevent the others
+                            // from being stored
+                            let event = match event
+                                .deserialize_as::<serde_json::Value>()
+                            {
+                                Ok(event) => event,
+                                Err(error) => {
+                                    warn!(
+                                        %error,
+                                        object = ?event.json(),
+                                        "To-Device event is invalid",
+                                    );
+                                    continue;
+                                }
+                            };
+
                             match target_device_id_maybe {
                                 DeviceIdOrAllDevices::DeviceId(
                                     target_device_id,
@@ -926,19 +1196,7 @@ pub(crate) async fn send_transaction_message_route(
                                     target_user_id,
                                     target_device_id,
                                     &ev_type.to_string(),
-                                    event.deserialize_as().map_err(
-                                        |error| {
-                                            warn!(
-                                                %error,
-                                                object = ?event.json(),
-                                                "To-Device event is invalid",
-                                            );
-                                            Error::BadRequest(
-                                                ErrorKind::InvalidParam,
-                                                "Event is invalid",
-                                            )
-                                        },
-                                    )?,
+                                    event,
                                 )?,
 
                                 DeviceIdOrAllDevices::AllDevices => {
@@ -951,14 +1209,7 @@ pub(crate) async fn send_transaction_message_route(
                                             target_user_id,
                                             &target_device_id?,
                                             &ev_type.to_string(),
-                                            event.deserialize_as().map_err(
-                                                |_| {
-                                                    Error::BadRequest(
-                                                        ErrorKind::InvalidParam,
-                                                        "Event is invalid",
-                                                    )
-                                                },
-                                            )?,
+                                            event.clone(),
                                         )?;
                                     }
                                 }
@@ -989,6 +1240,8 @@ pub(crate) async fn send_transaction_message_route(
                     );
                     continue;
                 }
+                // The cached self-signing key is outdated
+                services().users.invalidate_remote_device_keys(&user_id);
                 if let Some(master_key) = master_key {
                     services().users.add_cross_signing_keys(
                         &user_id,
@@ -999,7 +1252,34 @@ pub(crate) async fn send_transaction_message_route(
                     )?;
                 }
             }
-            Edu::_Custom(_) | Edu::Presence(_) => {}
+            Edu::Presence(PresenceContent {
+                push,
+            }) => {
+                for update in push {
+                    if update.user_id.server_name() != sender_servername {
+                        warn!(
+                            user_id = %update.user_id,
+                            %sender_servername,
+                            "Got presence EDU from incorrect homeserver, \
+                            ignoring",
+                        );
+                        continue;
+                    }
+                    services()
+                        .rooms
+                        .edus
+                        .presence
+                        .update_remote_presence(
+                            &update.user_id,
+                            update.presence,
+                            update.status_msg,
+                            update.last_active_ago,
+                            update.currently_active,
+                        )
+                        .await?;
+                }
+            }
+            Edu::_Custom(_) => {}
         }
     }
 
@@ -1011,6 +1291,129 @@ pub(crate) async fn send_transaction_message_route(
     }))
 }
 
+/// Rejects transactions with more PDUs or EDUs than allowed before any of them
+/// are parsed.
+fn check_transaction_size(
+    pdus: usize,
+    edus: usize,
+    max_pdus: usize,
+    max_edus: usize,
+) -> Result<()> {
+    if pdus > max_pdus {
+        return Err(Error::BadRequest(
+            ErrorKind::BadJson,
+            "Transaction contains too many PDUs.",
+        ));
+    }
+    if edus > max_edus {
+        return Err(Error::BadRequest(
+            ErrorKind::BadJson,
+            "Transaction contains too many EDUs.",
+        ));
+    }
+
+    Ok(())
+}
+
+/// PDUs of an incoming transaction, with their event IDs
+type RoomPdus = Vec<(OwnedEventId, CanonicalJsonObject)>;
+
+/// Groups the PDUs of an incoming transaction by room, keeping the order of
+/// each room's PDUs.
+fn group_pdus_by_room(
+    pdus: Vec<(OwnedRoomId, OwnedEventId, CanonicalJsonObject)>,
+) -> BTreeMap<OwnedRoomId, RoomPdus> {
+    let mut rooms = BTreeMap::<_, RoomPdus>::new();
+    for (room_id, event_id, value) in pdus {
+        rooms.entry(room_id).or_default().push((event_id, value));
+    }
+    rooms
+}
+
+/// Handles the PDUs of one room from an incoming transaction one after
+/// another, returning the result for each event.
+async fn handle_room_pdus(
+    origin: &ServerName,
+    room_id: OwnedRoomId,
+    pdus: RoomPdus,
+    pub_key_map: &RwLock<BTreeMap<String, SigningKeys>>,
+) -> Vec<(OwnedEventId, Result<()>)> {
+    let origin_semaphore = services()
+        .globals
+        .servername_incoming_rooms
+        .get_or_insert_with(origin.to_owned(), || {
+            Semaphore::new(
+                services()
+                    .globals
+                    .config
+                    .federation
+                    .max_concurrent_incoming_rooms_per_origin
+                    .into(),
+            )
+        })
+        .await;
+    let _origin_permit = origin_semaphore
+        .acquire()
+        .await
+        .expect("semaphore should not be closed");
+    let _permit = services()
+        .globals
+        .incoming_rooms_semaphore
+        .acquire()
+        .await
+        .expect("semaphore should not be closed");
+
+    let mut results = Vec::with_capacity(pdus.len());
+    for (event_id, value) in pdus {
+        let federation_token = services()
+            .globals
+            .roomid_mutex_federation
+            .lock_key(room_id.clone())
+            .await;
+        let start_time = Instant::now();
+        let result = services()
+            .rooms
+            .event_handler
+            .handle_incoming_pdu(
+                origin,
+                &event_id,
+                &room_id,
+                value,
+                true,
+                pub_key_map,
+            )
+            .await
+            .map(|_| ());
+        drop(federation_token);
+
+        debug!(
+            %event_id,
+            elapsed = ?start_time.elapsed(),
+            "Finished handling event",
+        );
+        results.push((event_id, result));
+    }
+
+    results
+}
+
+/// Fetches the complete device list of a remote user in the background after
+/// `m.device_list_update` EDUs were missed, which caches it again.
+fn resync_remote_device_list(user_id: OwnedUserId) {
+    tokio::spawn(async move {
+        let query = BTreeMap::from([(user_id.clone(), Vec::new())]);
+        match get_keys_helper(None, &query, |_| false).await {
+            Ok(response) if response.failures.is_empty() => {}
+            Ok(_) => {
+                warn!(%user_id, "Server unreachable for device list resync");
+            }
+            Err(error) => {
+                warn!(%error, %user_id, "Failed to resync device list");
+            }
+        }
+    });
+}
+
 /// # `GET /_matrix/federation/v1/event/{eventId}`
 ///
 /// Retrieves a single event from the server.
@@ -1446,6 +1849,10 @@ pub(crate) async fn create_join_event_template_route(
         .rooms
         .event_handler
         .acl_check(sender_servername, &body.room_id)?;
+    services()
+        .rooms
+        .metadata
+        .join_restriction_check(sender_servername, &body.room_id)?;
 
     let room_token = services()
         .globals
@@ -1453,38 +1860,10 @@ pub(crate) async fn create_join_event_template_route(
         .lock_key(body.room_id.clone())
         .await;
 
-    // TODO: Grapevine does not implement restricted join rules yet, we always
-    // reject
-    let join_rules_event = services().rooms.state_accessor.room_state_get(
-        &body.room_id,
-        &StateEventType::RoomJoinRules,
-        "",
-    )?;
-
-    let join_rules_event_content: Option<RoomJoinRulesEventContent> =
-        join_rules_event
-            .as_ref()
-            .map(|join_rules_event| {
-                serde_json::from_str(join_rules_event.content.get()).map_err(
-                    |error| {
-                        warn!(%error, "Invalid join rules event");
-                        Error::bad_database("Invalid join rules event in db.")
-                    },
-                )
-            })
-            .transpose()?;
-
-    if let Some(join_rules_event_content) = join_rules_event_content {
-        if matches!(
-            join_rules_event_content.join_rule,
-            JoinRule::Restricted { .. } | JoinRule::KnockRestricted { .. }
-        ) {
-            return Err(Error::BadRequest(
-                ErrorKind::UnableToAuthorizeJoin,
-                "Grapevine does not support restricted rooms yet.",
-            ));
-        }
-    }
+    let join_authorized_via_users_server = services()
+        .rooms
+        .state_accessor
+        .restricted_join_authorizer(&room_token, &body.user_id)?;
 
     let room_version_id =
         services().rooms.state.get_room_version(&body.room_id)?;
@@ -1505,7 +1884,7 @@ pub(crate) async fn create_join_event_template_route(
         membership: MembershipState::Join,
         third_party_invite: None,
         reason: None,
-        join_authorized_via_users_server: None,
+        join_authorized_via_users_server,
     })
     .expect("member event is valid value");
 
@@ -1547,39 +1926,10 @@ async fn create_join_event(
     }
 
     services().rooms.event_handler.acl_check(sender_servername, room_id)?;
-
-    // TODO: Grapevine does not implement restricted join rules yet, we always
-    // reject
-    let join_rules_event = services().rooms.state_accessor.room_state_get(
-        room_id,
-        &StateEventType::RoomJoinRules,
-        "",
-    )?;
-
-    let join_rules_event_content: Option<RoomJoinRulesEventContent> =
-        join_rules_event
-            .as_ref()
-            .map(|join_rules_event| {
-                serde_json::from_str(join_rules_event.content.get()).map_err(
-                    |error| {
-                        warn!(%error, "Invalid join rules event");
-                        Error::bad_database("Invalid join rules event in db.")
-                    },
-                )
-            })
-            .transpose()?;
-
-    if let Some(join_rules_event_content) = join_rules_event_content {
-        if matches!(
-            join_rules_event_content.join_rule,
-            JoinRule::Restricted { .. } | JoinRule::KnockRestricted { .. }
-        ) {
-            return Err(Error::BadRequest(
-                ErrorKind::UnableToAuthorizeJoin,
-                "Grapevine does not support restricted rooms yet.",
-            ));
-        }
-    }
+    services()
+        .rooms
+        .metadata
+        .join_restriction_check(sender_servername, room_id)?;
 
     // We need to return the state prior to joining, let's keep a reference to
     // that here
@@ -1593,7 +1943,7 @@ async fn create_join_event(
     // We do not add the event_id field to the pdu here because of signature and
     // hashes checks
     let room_version_id = services().rooms.state.get_room_version(room_id)?;
-    let Ok((event_id, value)) =
+    let Ok((event_id, mut value)) =
         gen_event_id_canonical_json(pdu, &room_version_id)
     else {
         // Event could not be converted to canonical json
@@ -1603,6 +1953,44 @@ async fn create_join_event(
         ));
     };
 
+    // Joins to restricted rooms are authorized by one of our users, which
+    // requires our signature on the event
+    let join_authorized_via_users_server = value
+        .get("content")
+        .and_then(|content| {
+            content.as_object()?.get("join_authorised_via_users_server")
+        })
+        .map(|user_id| {
+            user_id
+                .as_str()
+                .and_then(|user_id| UserId::parse(user_id).ok())
+                .ok_or(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Invalid join_authorised_via_users_server.",
+                ))
+        })
+        .transpose()?;
+    if let Some(user_id) = &join_authorized_via_users_server {
+        if user_id.server_name() != services().globals.server_name() {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Join is not authorized by a user of this server.",
+            ));
+        }
+
+        ruma::signatures::sign_json(
+            services().globals.server_name().as_str(),
+            &*services().globals.keypair(),
+            &mut value,
+        )
+        .map_err(|_| {
+            Error::BadRequest(ErrorKind::InvalidParam, "Failed to sign event.")
+        })?;
+    }
+    let signed_event = join_authorized_via_users_server
+        .is_some()
+        .then(|| PduEvent::convert_to_outgoing_federation_event(value.clone()));
+
     let origin: OwnedServerName = serde_json::from_value(
         serde_json::to_value(value.get("origin").ok_or(Error::BadRequest(
             ErrorKind::InvalidParam,
@@ -1668,8 +2056,7 @@ async fn create_join_event(
             })
             .map(PduEvent::convert_to_outgoing_federation_event)
             .collect(),
-        // TODO: handle restricted joins
-        event: None,
+        event: signed_event,
     })
 }
 
@@ -1717,6 +2104,228 @@ pub(crate) async fn create_join_event_v2_route(
     }))
 }
 
+/// # `GET /_matrix/federation/v1/make_knock/{roomId}/{userId}`
+///
+/// Creates a knock template.
+pub(crate) async fn create_knock_event_template_route(
+    body: Ar<create_knock_event_template::v1::Request>,
+) -> Result<Ra<create_knock_event_template::v1::Response>> {
+    if !services().rooms.metadata.exists(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Room is unknown to this server.",
+        ));
+    }
+
+    let sender_servername =
+        body.sender_servername.as_ref().expect("server is authenticated");
+
+    if body.user_id.server_name() != sender_servername {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "User does not belong to origin server.",
+        ));
+    }
+
+    services()
+        .rooms
+        .event_handler
+        .acl_check(sender_servername, &body.room_id)?;
+    services()
+        .rooms
+        .metadata
+        .join_restriction_check(sender_servername, &body.room_id)?;
+
+    let room_token = services()
+        .globals
+        .roomid_mutex_state
+        .lock_key(body.room_id.clone())
+        .await;
+
+    let room_version_id =
+        services().rooms.state.get_room_version(&body.room_id)?;
+    if !body.ver.contains(&room_version_id) {
+        return Err(Error::BadRequest(
+            ErrorKind::IncompatibleRoomVersion {
+                room_version: room_version_id,
+            },
+            "Room version not supported.",
+        ));
+    }
+
+    if !services().rooms.state_accessor.allows_knocking(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "This room does not allow knocking.",
+        ));
+    }
+
+    let content = to_raw_value(&RoomMemberEventContent {
+        avatar_url: None,
+        blurhash: None,
+        displayname: None,
+        is_direct: None,
+        membership: MembershipState::Knock,
+        third_party_invite: None,
+        reason: None,
+        join_authorized_via_users_server: None,
+    })
+    .expect("member event is valid value");
+
+    let (_pdu, mut pdu_json) =
+        services().rooms.timeline.create_hash_and_sign_event(
+            PduBuilder {
+                event_type: TimelineEventType::RoomMember,
+                content,
+                unsigned: None,
+                state_key: Some(body.user_id.to_string()),
+                redacts: None,
+            },
+            &body.user_id,
+            &room_token,
+        )?;
+
+    drop(room_token);
+
+    pdu_json.remove("event_id");
+
+    Ok(Ra(create_knock_event_template::v1::Response {
+        room_version: room_version_id,
+        event: to_raw_value(&pdu_json)
+            .expect("CanonicalJson can be serialized to JSON"),
+    }))
+}
+
+/// # `PUT /_matrix/federation/v1/send_knock/{roomId}/{eventId}`
+///
+/// Submits a signed knock event.
+#[allow(clippy::too_many_lines)]
+pub(crate) async fn create_knock_event_v1_route(
+    body: Ar<send_knock::v1::Request>,
+) -> Result<Ra<send_knock::v1::Response>> {
+    if !services().rooms.metadata.exists(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Room is unknown to this server.",
+        ));
+    }
+
+    let sender_servername =
+        body.sender_servername.as_ref().expect("server is authenticated");
+
+    services()
+        .rooms
+        .event_handler
+        .acl_check(sender_servername, &body.room_id)?;
+    services()
+        .rooms
+        .metadata
+        .join_restriction_check(sender_servername, &body.room_id)?;
+
+    if !services().rooms.state_accessor.allows_knocking(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "This room does not allow knocking.",
+        ));
+    }
+
+    let pub_key_map = RwLock::new(BTreeMap::new());
+
+    // We do not add the event_id field to the pdu here because of signature and
+    // hashes checks
+    let room_version_id =
+        services().rooms.state.get_room_version(&body.room_id)?;
+    let Ok((event_id, value)) =
+        gen_event_id_canonical_json(&body.pdu, &room_version_id)
+    else {
+        // Event could not be converted to canonical json
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Could not convert event to canonical json.",
+        ));
+    };
+
+    let event: RoomMemberEventContent = value
+        .get("content")
+        .and_then(|content| {
+            serde_json::from_value(
+                serde_json::to_value(content)
+                    .expect("CanonicalJson is valid json value"),
+            )
+            .ok()
+        })
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event content is invalid.",
+        ))?;
+
+    if event.membership != MembershipState::Knock {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event is not a knock event.",
+        ));
+    }
+
+    let sender = value
+        .get("sender")
+        .and_then(CanonicalJsonValue::as_str)
+        .and_then(|sender| UserId::parse(sender).ok())
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event sender is invalid.",
+        ))?;
+
+    if sender.server_name() != sender_servername {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Sender does not belong to origin server.",
+        ));
+    }
+
+    let federation_token = services()
+        .globals
+        .roomid_mutex_federation
+        .lock_key(body.room_id.clone())
+        .await;
+    let pdu_id: Vec<u8> = services()
+        .rooms
+        .event_handler
+        .handle_incoming_pdu(
+            sender_servername,
+            &event_id,
+            &body.room_id,
+            value,
+            true,
+            &pub_key_map,
+        )
+        .await?
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Could not accept incoming PDU as timeline event.",
+        ))?;
+    drop(federation_token);
+
+    let servers = services()
+        .rooms
+        .state_cache
+        .room_servers(&body.room_id)
+        .filter_map(Result::ok)
+        .filter(|server| &**server != services().globals.server_name());
+
+    services().sending.send_pdu(servers, &pdu_id)?;
+
+    let pdu = services().rooms.timeline.get_pdu(&event_id)?.ok_or(
+        Error::bad_database("Accepted knock event is missing from db."),
+    )?;
+
+    Ok(Ra(send_knock::v1::Response {
+        knock_room_state: services()
+            .rooms
+            .state
+            .calculate_invite_state(&pdu)?,
+    }))
+}
+
 /// # `PUT /_matrix/federation/v2/invite/{roomId}/{eventId}`
 ///
 /// Invites a remote user to a room.
@@ -1755,7 +2364,7 @@ pub(crate) async fn create_invite_route(
 
     ruma::signatures::hash_and_sign_event(
         services().globals.server_name().as_str(),
-        services().globals.keypair(),
+        &*services().globals.keypair(),
         &mut signed_event,
         &body.room_version,
     )
@@ -1870,7 +2479,7 @@ pub(crate) async fn get_devices_route(
         user_id: body.user_id.clone(),
         stream_id: services()
             .users
-            .get_devicelist_version(&body.user_id)?
+            .device_list_stream_id(&body.user_id)?
             .unwrap_or(0)
             .try_into()
             .expect("version will not grow that large"),
@@ -1919,6 +2528,34 @@ pub(crate) async fn get_room_information_route(
     }))
 }
 
+/// # `GET /_matrix/federation/v1/hierarchy/{roomId}`
+///
+/// Gets the summary of a space and its direct children.
+///
+/// - Only rooms the requesting server could peek into or join are summarized
+pub(crate) async fn get_hierarchy_route(
+    body: Ar<get_hierarchy::v1::Request>,
+) -> Result<Ra<get_hierarchy::v1::Response>> {
+    let sender_servername =
+        body.sender_servername.as_ref().expect("server is authenticated");
+
+    services()
+        .rooms
+        .event_handler
+        .acl_check(sender_servername, &body.room_id)?;
+
+    services()
+        .rooms
+        .spaces
+        .get_federation_hierarchy(
+            sender_servername,
+            &body.room_id,
+            body.suggested_only,
+        )
+        .await
+        .map(Ra)
+}
+
 /// # `GET /_matrix/federation/v1/query/profile`
 ///
 /// Gets information on a profile.
@@ -2013,9 +2650,73 @@ pub(crate) async fn claim_keys_route(
     }))
 }
 
+/// # `GET /_matrix/federation/v1/openid/userinfo`
+///
+/// Exchanges an OpenID token for the ID of the user it was created for.
+pub(crate) async fn get_openid_userinfo_route(
+    body: Ar<get_openid_userinfo::v1::Request>,
+) -> Result<Ra<get_openid_userinfo::v1::Response>> {
+    let sub = services()
+        .users
+        .find_from_openid_token(&body.access_token)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::UnknownToken {
+                soft_logout: false,
+            },
+            "OpenID token is unknown or expired.",
+        ))?;
+
+    Ok(Ra(get_openid_userinfo::v1::Response {
+        sub,
+    }))
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{add_port_to_hostname, get_ip_with_port, FedDest};
+    use ruma::{
+        owned_event_id, owned_room_id, CanonicalJsonObject, OwnedRoomId,
+    };
+
+    use super::{
+        add_port_to_hostname, check_transaction_size, get_ip_with_port,
+        group_pdus_by_room, FedDest,
+    };
+
+    #[test]
+    fn oversized_transactions_are_rejected() {
+        assert!(check_transaction_size(50, 100, 50, 100).is_ok());
+        assert!(check_transaction_size(0, 0, 50, 100).is_ok());
+        assert!(check_transaction_size(51, 0, 50, 100).is_err());
+        assert!(check_transaction_size(0, 101, 50, 100).is_err());
+
+        // Raised limits for trusted peers
+        assert!(check_transaction_size(500, 1000, 500, 1000).is_ok());
+    }
+
+    #[test]
+    fn pdus_keep_their_order_within_a_room() {
+        let a = owned_room_id!("!a:example.com");
+        let b = owned_room_id!("!b:example.com");
+        let pdus = vec![
+            (a.clone(), owned_event_id!("$a1"), CanonicalJsonObject::new()),
+            (b.clone(), owned_event_id!("$b1"), CanonicalJsonObject::new()),
+            (a.clone(), owned_event_id!("$a2"), CanonicalJsonObject::new()),
+            (a.clone(), owned_event_id!("$a3"), CanonicalJsonObject::new()),
+            (b.clone(), owned_event_id!("$b2"), CanonicalJsonObject::new()),
+        ];
+
+        let rooms = group_pdus_by_room(pdus);
+
+        let event_ids = |room_id: &OwnedRoomId| {
+            rooms[room_id]
+                .iter()
+                .map(|(event_id, _)| event_id.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(rooms.len(), 2);
+        assert_eq!(event_ids(&a), ["$a1", "$a2", "$a3"]);
+        assert_eq!(event_ids(&b), ["$b1", "$b2"]);
+    }
 
     #[test]
     fn ips_get_default_ports() {
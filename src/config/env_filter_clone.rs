@@ -5,7 +5,7 @@
 //! [0]: https://github.com/tokio-rs/tracing/pull/2956
 #![warn(missing_docs, clippy::missing_docs_in_private_items)]
 
-use std::str::FromStr;
+use std::{fmt, str::FromStr};
 
 use serde::{de, Deserialize, Deserializer};
 use tracing_subscriber::EnvFilter;
@@ -26,6 +26,12 @@ impl FromStr for EnvFilterClone {
     }
 }
 
+impl fmt::Display for EnvFilterClone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 impl From<&EnvFilterClone> for EnvFilter {
     fn from(other: &EnvFilterClone) -> Self {
         EnvFilter::from_str(&other.0)
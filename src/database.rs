@@ -7,7 +7,8 @@ use std::{
     io::Write,
     mem::size_of,
     path::Path,
-    sync::{Arc, Mutex, RwLock},
+    sync::{atomic::AtomicU64, Arc, Mutex, RwLock},
+    time::Instant,
 };
 
 use abstraction::{KeyValueDatabaseEngine, KvTree};
@@ -15,7 +16,7 @@ use lru_cache::LruCache;
 use ruma::{
     events::{
         push_rules::{PushRulesEvent, PushRulesEventContent},
-        room::message::RoomMessageEventContent,
+        room::{encrypted::Relation, message::RoomMessageEventContent},
         GlobalAccountDataEvent, GlobalAccountDataEventType, StateEventType,
     },
     push::Ruleset,
@@ -25,9 +26,10 @@ use ruma::{
 use tracing::{debug, error, info, info_span, warn, Instrument};
 
 use crate::{
-    config::DatabaseBackend, observability::FilterReloadHandles,
-    service::rooms::timeline::PduCount, services, utils, Config, Error,
-    PduEvent, Result, Services, SERVICES,
+    config::DatabaseBackend,
+    observability::FilterReloadHandles,
+    service::rooms::timeline::{ExtractRelatesTo, PduCount},
+    services, utils, Config, Error, PduEvent, Result, Services, SERVICES,
 };
 
 pub(crate) struct KeyValueDatabase {
@@ -36,12 +38,20 @@ pub(crate) struct KeyValueDatabase {
     // Trees "owned" by `self::key_value::globals`
     pub(super) global: Arc<dyn KvTree>,
     pub(super) server_signingkeys: Arc<dyn KvTree>,
+    /// `ServerName -> the last key response of the server, as it signed it`
+    pub(super) server_signedkeys: Arc<dyn KvTree>,
+    /// `ServerName -> 1 if added or 0 if removed as a trusted server by an
+    /// admin`
+    pub(super) servername_trusted: Arc<dyn KvTree>,
+    /// `ServerName -> 1 if allowed or 0 if denied federation by an admin`
+    pub(super) servername_federationallowed: Arc<dyn KvTree>,
 
     // Trees "owned" by `self::key_value::users`
     pub(super) userid_password: Arc<dyn KvTree>,
     pub(super) userid_displayname: Arc<dyn KvTree>,
     pub(super) userid_avatarurl: Arc<dyn KvTree>,
     pub(super) userid_blurhash: Arc<dyn KvTree>,
+    pub(super) userid_servernoticesroomid: Arc<dyn KvTree>,
     pub(super) userdeviceid_token: Arc<dyn KvTree>,
 
     // This is also used to check if a device exists
@@ -49,7 +59,16 @@ pub(crate) struct KeyValueDatabase {
 
     // DevicelistVersion = u64
     pub(super) userid_devicelistversion: Arc<dyn KvTree>,
+    /// Stream ID of the last `m.device_list_update` EDU of each local user
+    pub(super) userid_devicelistupdateid: Arc<dyn KvTree>,
     pub(super) token_userdeviceid: Arc<dyn KvTree>,
+    pub(super) userdeviceid_refreshtoken: Arc<dyn KvTree>,
+    pub(super) refreshtoken_userdeviceid: Arc<dyn KvTree>,
+    /// The refresh token a device used to get its current one, which stays
+    /// valid until the current tokens are used
+    pub(super) userdeviceid_prevrefreshtoken: Arc<dyn KvTree>,
+    // ExpiresAt = u64, in milliseconds since the unix epoch
+    pub(super) userdeviceid_tokenexpiresat: Arc<dyn KvTree>,
 
     // OneTimeKeyId = UserId + DeviceKeyId
     pub(super) onetimekeyid_onetimekeys: Arc<dyn KvTree>,
@@ -57,6 +76,10 @@ pub(crate) struct KeyValueDatabase {
     // LastOneTimeKeyUpdate = Count
     pub(super) userid_lastonetimekeyupdate: Arc<dyn KvTree>,
 
+    // FallbackKeyId = UserId + DeviceId + DeviceKeyAlgorithm
+    // FallbackKey = Used (1 byte) + (DeviceKeyId, Raw<OneTimeKey>)
+    pub(super) fallbackkeyid_fallbackkey: Arc<dyn KvTree>,
+
     // KeyChangeId = UserId/RoomId + Count
     pub(super) keychangeid_userid: Arc<dyn KvTree>,
 
@@ -68,10 +91,22 @@ pub(crate) struct KeyValueDatabase {
 
     // UserFilterId = UserId + FilterId
     pub(super) userfilterid_filter: Arc<dyn KvTree>,
+    // ExpiresAtUserId = ExpiresAt + UserId
+    pub(super) openidtoken_expiresatuserid: Arc<dyn KvTree>,
+
+    // ThreepidId = Medium + Address
+    pub(super) threepid_userid: Arc<dyn KvTree>,
+    // UserThreepidId = UserId + ThreepidId
+    pub(super) userthreepid_threepid: Arc<dyn KvTree>,
+    // SsoSubject = IdpId + Subject
+    pub(super) ssosubject_userid: Arc<dyn KvTree>,
 
     // ToDeviceId = UserId + DeviceId + Count
     pub(super) todeviceid_events: Arc<dyn KvTree>,
 
+    // DehydratedDevice = DeviceId + DehydratedDeviceData
+    pub(super) userid_dehydrateddevice: Arc<dyn KvTree>,
+
     // Trees "owned" by `self::key_value::uiaa`
     // User-interactive authentication
     pub(super) userdevicesessionid_uiaainfo: Arc<dyn KvTree>,
@@ -80,7 +115,7 @@ pub(crate) struct KeyValueDatabase {
     >,
 
     // Trees "owned" by `self::key_value::rooms::edus`
-    // ReadReceiptId = RoomId + Count + UserId
+    // ReadReceiptId = RoomId + Count + UserId (+ ThreadId)
     pub(super) readreceiptid_readreceipt: Arc<dyn KvTree>,
 
     // RoomUserId = Room + User, PrivateRead = Count
@@ -90,13 +125,9 @@ pub(crate) struct KeyValueDatabase {
     pub(super) roomuserid_lastprivatereadupdate: Arc<dyn KvTree>,
 
     // PresenceId = RoomId + Count + UserId
-    // This exists in the database already but is currently unused
-    #[allow(dead_code)]
     pub(super) presenceid_presence: Arc<dyn KvTree>,
 
     // LastPresenceUpdate = Count
-    // This exists in the database already but is currently unused
-    #[allow(dead_code)]
     pub(super) userid_lastpresenceupdate: Arc<dyn KvTree>,
 
     // Trees "owned" by `self::key_value::rooms`
@@ -114,7 +145,19 @@ pub(crate) struct KeyValueDatabase {
     pub(super) threadid_userids: Arc<dyn KvTree>,
 
     // TokenId = ShortRoomId + Token + PduIdCount
+    /// `TokenId -> Frequency of the token in the PDU`
     pub(super) tokenids: Arc<dyn KvTree>,
+    /// `PduId -> Number of tokens in the PDU`
+    pub(super) pduid_tokencount: Arc<dyn KvTree>,
+    /// `ShortRoomId + Token -> Number of PDUs in the room with the token`
+    pub(super) roomtoken_pducount: Arc<dyn KvTree>,
+    /// `ShortRoomId -> Number of indexed PDUs + Total number of their tokens`
+    pub(super) shortroomid_searchstats: Arc<dyn KvTree>,
+    /// `Token + 0xFF + RoomId -> Weight of the token in the directory entry`
+    pub(super) directorytoken_roomid: Arc<dyn KvTree>,
+    /// `RoomId -> Name + 0xFF + Topic + 0xFF + Alias indexed for the
+    /// directory`
+    pub(super) roomid_directoryfields: Arc<dyn KvTree>,
 
     /// Participating servers in a room.
     // RoomServerId = RoomId + ServerName
@@ -134,11 +177,24 @@ pub(crate) struct KeyValueDatabase {
 
     // InviteCount = Count
     pub(super) roomuserid_invitecount: Arc<dyn KvTree>,
+
+    // KnockState = Vec<Raw<Pdu>>
+    pub(super) userroomid_knockstate: Arc<dyn KvTree>,
+
+    // KnockCount = Count
+    pub(super) roomuserid_knockcount: Arc<dyn KvTree>,
     pub(super) userroomid_leftstate: Arc<dyn KvTree>,
     pub(super) roomuserid_leftcount: Arc<dyn KvTree>,
 
     // Rooms where incoming federation handling is disabled
     pub(super) disabledroomids: Arc<dyn KvTree>,
+    /// `RoomId -> Most prev events to fetch for an incoming event (u16)`
+    pub(super) roomid_maxfetchprevevents: Arc<dyn KvTree>,
+    /// `RoomId + 0xFF + ServerName -> 1 if allowed or 0 if denied joining the
+    /// room by an admin`
+    pub(super) roomserverid_joinallowed: Arc<dyn KvTree>,
+    /// `RoomId -> ()` if only allowed servers may join the room
+    pub(super) roomid_joinallowlist: Arc<dyn KvTree>,
 
     // LazyLoadedIds = UserId + DeviceId + RoomId + LazyLoadedUserId
     pub(super) lazyloadedids: Arc<dyn KvTree>,
@@ -149,6 +205,14 @@ pub(crate) struct KeyValueDatabase {
     // HightlightCount = u64
     pub(super) userroomid_highlightcount: Arc<dyn KvTree>,
 
+    /// `UserId + 0xFF + RoomId + 0xFF + ThreadRootId -> Notifications of the
+    /// thread (u64)`, which are included in `userroomid_notificationcount`
+    pub(super) userroomthreadid_notificationcount: Arc<dyn KvTree>,
+
+    /// `UserId + 0xFF + RoomId + 0xFF + ThreadRootId -> Highlights of the
+    /// thread (u64)`, which are included in `userroomid_highlightcount`
+    pub(super) userroomthreadid_highlightcount: Arc<dyn KvTree>,
+
     // LastNotificationRead = u64
     pub(super) roomuserid_lastnotificationread: Arc<dyn KvTree>,
 
@@ -187,6 +251,16 @@ pub(crate) struct KeyValueDatabase {
     /// `ShortEventId + ShortEventId -> ()`
     pub(super) tofrom_relation: Arc<dyn KvTree>,
 
+    /// `EventId + 0xFF + Key -> Count`
+    ///
+    /// Number of `m.annotation` relations with the key to the event.
+    pub(super) eventidkey_annotationcount: Arc<dyn KvTree>,
+
+    /// `EventId + 0xFF + EventId -> ()`
+    ///
+    /// Events with an `m.replace` relation to the first event.
+    pub(super) tofrom_replacement: Arc<dyn KvTree>,
+
     /// `RoomId + EventId -> Parent PDU EventId`
     pub(super) referencedevents: Arc<dyn KvTree>,
 
@@ -201,6 +275,13 @@ pub(crate) struct KeyValueDatabase {
     // MediaId = MXC + WidthHeight + ContentDisposition + ContentType
     pub(super) mediaid_file: Arc<dyn KvTree>,
 
+    // Uploader = Size + UserId
+    pub(super) mediaid_uploader: Arc<dyn KvTree>,
+
+    pub(super) userid_mediausage: Arc<dyn KvTree>,
+
+    pub(super) userid_mediaquota: Arc<dyn KvTree>,
+
     // Trees "owned" by `self::key_value::key_backups`
     // BackupId = UserId + Version(Count)
     pub(super) backupid_algorithm: Arc<dyn KvTree>,
@@ -218,6 +299,8 @@ pub(crate) struct KeyValueDatabase {
     // Trees "owned" by `self::key_value::sending`
     // EduCount: Count of last EDU sync
     pub(super) servername_educount: Arc<dyn KvTree>,
+    // Backoff = Failures + NextRetry (ms since unix epoch)
+    pub(super) servername_backoff: Arc<dyn KvTree>,
 
     // ServernameEvent = (+ / $)SenderKey / ServerName / UserId + PduId / Id
     // (for edus), Data = EDU content
@@ -235,6 +318,10 @@ pub(crate) struct KeyValueDatabase {
 
     // Uncategorized trees
     pub(super) pdu_cache: Mutex<LruCache<OwnedEventId, Arc<PduEvent>>>,
+    pub(super) missing_pdu_cache: Mutex<LruCache<OwnedEventId, Instant>>,
+    /// Incremented whenever an event is stored, only while holding
+    /// `missing_pdu_cache`
+    pub(super) stored_pdu_generation: AtomicU64,
     pub(super) shorteventid_cache: Mutex<LruCache<u64, Arc<EventId>>>,
     pub(super) auth_chain_cache: Mutex<LruCache<Vec<u64>, Arc<HashSet<u64>>>>,
     pub(super) eventidshort_cache: Mutex<LruCache<OwnedEventId, u64>>,
@@ -247,6 +334,12 @@ pub(crate) struct KeyValueDatabase {
     pub(super) appservice_in_room_cache:
         RwLock<HashMap<OwnedRoomId, HashMap<String, bool>>>,
     pub(super) lasttimelinecount_cache: Mutex<HashMap<OwnedRoomId, PduCount>>,
+    /// Held for reading while short IDs are looked up or created, and for
+    /// writing while unreferenced ones are removed
+    pub(super) shortid_gc_lock: RwLock<()>,
+    /// Short IDs that were looked up or created while unreferenced ones are
+    /// searched for removal, or `None` if no search is running
+    pub(super) shortid_gc_pinned: Mutex<Option<HashSet<u64>>>,
 }
 
 impl KeyValueDatabase {
@@ -362,16 +455,30 @@ impl KeyValueDatabase {
             userid_displayname: builder.open_tree("userid_displayname")?,
             userid_avatarurl: builder.open_tree("userid_avatarurl")?,
             userid_blurhash: builder.open_tree("userid_blurhash")?,
+            userid_servernoticesroomid: builder
+                .open_tree("userid_servernoticesroomid")?,
             userdeviceid_token: builder.open_tree("userdeviceid_token")?,
             userdeviceid_metadata: builder
                 .open_tree("userdeviceid_metadata")?,
             userid_devicelistversion: builder
                 .open_tree("userid_devicelistversion")?,
+            userid_devicelistupdateid: builder
+                .open_tree("userid_devicelistupdateid")?,
             token_userdeviceid: builder.open_tree("token_userdeviceid")?,
+            userdeviceid_refreshtoken: builder
+                .open_tree("userdeviceid_refreshtoken")?,
+            refreshtoken_userdeviceid: builder
+                .open_tree("refreshtoken_userdeviceid")?,
+            userdeviceid_prevrefreshtoken: builder
+                .open_tree("userdeviceid_prevrefreshtoken")?,
+            userdeviceid_tokenexpiresat: builder
+                .open_tree("userdeviceid_tokenexpiresat")?,
             onetimekeyid_onetimekeys: builder
                 .open_tree("onetimekeyid_onetimekeys")?,
             userid_lastonetimekeyupdate: builder
                 .open_tree("userid_lastonetimekeyupdate")?,
+            fallbackkeyid_fallbackkey: builder
+                .open_tree("fallbackkeyid_fallbackkey")?,
             keychangeid_userid: builder.open_tree("keychangeid_userid")?,
             keyid_key: builder.open_tree("keyid_key")?,
             userid_masterkeyid: builder.open_tree("userid_masterkeyid")?,
@@ -380,7 +487,15 @@ impl KeyValueDatabase {
             userid_usersigningkeyid: builder
                 .open_tree("userid_usersigningkeyid")?,
             userfilterid_filter: builder.open_tree("userfilterid_filter")?,
+            openidtoken_expiresatuserid: builder
+                .open_tree("openidtoken_expiresatuserid")?,
+            threepid_userid: builder.open_tree("threepid_userid")?,
+            userthreepid_threepid: builder
+                .open_tree("userthreepid_threepid")?,
+            ssosubject_userid: builder.open_tree("ssosubject_userid")?,
             todeviceid_events: builder.open_tree("todeviceid_events")?,
+            userid_dehydrateddevice: builder
+                .open_tree("userid_dehydrateddevice")?,
 
             userdevicesessionid_uiaainfo: builder
                 .open_tree("userdevicesessionid_uiaainfo")?,
@@ -406,6 +521,14 @@ impl KeyValueDatabase {
             threadid_userids: builder.open_tree("threadid_userids")?,
 
             tokenids: builder.open_tree("tokenids")?,
+            pduid_tokencount: builder.open_tree("pduid_tokencount")?,
+            roomtoken_pducount: builder.open_tree("roomtoken_pducount")?,
+            shortroomid_searchstats: builder
+                .open_tree("shortroomid_searchstats")?,
+            directorytoken_roomid: builder
+                .open_tree("directorytoken_roomid")?,
+            roomid_directoryfields: builder
+                .open_tree("roomid_directoryfields")?,
 
             roomserverids: builder.open_tree("roomserverids")?,
             serverroomids: builder.open_tree("serverroomids")?,
@@ -419,10 +542,19 @@ impl KeyValueDatabase {
                 .open_tree("userroomid_invitestate")?,
             roomuserid_invitecount: builder
                 .open_tree("roomuserid_invitecount")?,
+            userroomid_knockstate: builder
+                .open_tree("userroomid_knockstate")?,
+            roomuserid_knockcount: builder
+                .open_tree("roomuserid_knockcount")?,
             userroomid_leftstate: builder.open_tree("userroomid_leftstate")?,
             roomuserid_leftcount: builder.open_tree("roomuserid_leftcount")?,
 
             disabledroomids: builder.open_tree("disabledroomids")?,
+            roomid_maxfetchprevevents: builder
+                .open_tree("roomid_maxfetchprevevents")?,
+            roomserverid_joinallowed: builder
+                .open_tree("roomserverid_joinallowed")?,
+            roomid_joinallowlist: builder.open_tree("roomid_joinallowlist")?,
 
             lazyloadedids: builder.open_tree("lazyloadedids")?,
 
@@ -430,6 +562,10 @@ impl KeyValueDatabase {
                 .open_tree("userroomid_notificationcount")?,
             userroomid_highlightcount: builder
                 .open_tree("userroomid_highlightcount")?,
+            userroomthreadid_notificationcount: builder
+                .open_tree("userroomthreadid_notificationcount")?,
+            userroomthreadid_highlightcount: builder
+                .open_tree("userroomthreadid_highlightcount")?,
             roomuserid_lastnotificationread: builder
                 .open_tree("userroomid_highlightcount")?,
 
@@ -460,18 +596,25 @@ impl KeyValueDatabase {
             softfailedeventids: builder.open_tree("softfailedeventids")?,
 
             tofrom_relation: builder.open_tree("tofrom_relation")?,
+            eventidkey_annotationcount: builder
+                .open_tree("eventidkey_annotationcount")?,
+            tofrom_replacement: builder.open_tree("tofrom_replacement")?,
             referencedevents: builder.open_tree("referencedevents")?,
             roomuserdataid_accountdata: builder
                 .open_tree("roomuserdataid_accountdata")?,
             roomusertype_roomuserdataid: builder
                 .open_tree("roomusertype_roomuserdataid")?,
             mediaid_file: builder.open_tree("mediaid_file")?,
+            mediaid_uploader: builder.open_tree("mediaid_uploader")?,
+            userid_mediausage: builder.open_tree("userid_mediausage")?,
+            userid_mediaquota: builder.open_tree("userid_mediaquota")?,
             backupid_algorithm: builder.open_tree("backupid_algorithm")?,
             backupid_etag: builder.open_tree("backupid_etag")?,
             backupkeyid_backup: builder.open_tree("backupkeyid_backup")?,
             userdevicetxnid_response: builder
                 .open_tree("userdevicetxnid_response")?,
             servername_educount: builder.open_tree("servername_educount")?,
+            servername_backoff: builder.open_tree("servername_backoff")?,
             servernameevent_data: builder.open_tree("servernameevent_data")?,
             servercurrentevent_data: builder
                 .open_tree("servercurrentevent_data")?,
@@ -480,56 +623,43 @@ impl KeyValueDatabase {
             senderkey_pusher: builder.open_tree("senderkey_pusher")?,
             global: builder.open_tree("global")?,
             server_signingkeys: builder.open_tree("server_signingkeys")?,
+            server_signedkeys: builder.open_tree("server_signedkeys")?,
+            servername_trusted: builder.open_tree("servername_trusted")?,
+            servername_federationallowed: builder
+                .open_tree("servername_federationallowed")?,
 
             pdu_cache: Mutex::new(LruCache::new(
-                config
-                    .pdu_cache_capacity
-                    .try_into()
-                    .expect("pdu cache capacity fits into usize"),
+                config.cache.pdu.unwrap_or_else(|| {
+                    config
+                        .pdu_cache_capacity
+                        .try_into()
+                        .expect("pdu cache capacity fits into usize")
+                }),
+            )),
+            missing_pdu_cache: Mutex::new(LruCache::new(
+                config.cache_capacity(config.cache.missing_pdu, 10_000),
             )),
-            #[allow(
-                clippy::as_conversions,
-                clippy::cast_sign_loss,
-                clippy::cast_possible_truncation
-            )]
+            stored_pdu_generation: AtomicU64::new(0),
             auth_chain_cache: Mutex::new(LruCache::new(
-                (100_000.0 * config.cache_capacity_modifier) as usize,
+                config.cache_capacity(config.cache.auth_chain, 100_000),
             )),
-            #[allow(
-                clippy::as_conversions,
-                clippy::cast_sign_loss,
-                clippy::cast_possible_truncation
-            )]
             shorteventid_cache: Mutex::new(LruCache::new(
-                (100_000.0 * config.cache_capacity_modifier) as usize,
+                config.cache_capacity(config.cache.shorteventid, 100_000),
             )),
-            #[allow(
-                clippy::as_conversions,
-                clippy::cast_sign_loss,
-                clippy::cast_possible_truncation
-            )]
             eventidshort_cache: Mutex::new(LruCache::new(
-                (100_000.0 * config.cache_capacity_modifier) as usize,
+                config.cache_capacity(config.cache.eventidshort, 100_000),
             )),
-            #[allow(
-                clippy::as_conversions,
-                clippy::cast_sign_loss,
-                clippy::cast_possible_truncation
-            )]
             shortstatekey_cache: Mutex::new(LruCache::new(
-                (100_000.0 * config.cache_capacity_modifier) as usize,
+                config.cache_capacity(config.cache.shortstatekey, 100_000),
             )),
-            #[allow(
-                clippy::as_conversions,
-                clippy::cast_sign_loss,
-                clippy::cast_possible_truncation
-            )]
             statekeyshort_cache: Mutex::new(LruCache::new(
-                (100_000.0 * config.cache_capacity_modifier) as usize,
+                config.cache_capacity(config.cache.statekeyshort, 100_000),
             )),
             our_real_users_cache: RwLock::new(HashMap::new()),
             appservice_in_room_cache: RwLock::new(HashMap::new()),
             lasttimelinecount_cache: Mutex::new(HashMap::new()),
+            shortid_gc_lock: RwLock::new(()),
+            shortid_gc_pinned: Mutex::new(None),
         });
 
         let db = Box::leak(db_raw);
@@ -557,7 +687,7 @@ impl KeyValueDatabase {
         }
 
         // If the database has any data, perform data migrations before starting
-        let latest_database_version = 13;
+        let latest_database_version = 14;
 
         if services().users.count()? > 0 {
             // MIGRATIONS
@@ -1119,6 +1249,40 @@ impl KeyValueDatabase {
                 warn!("Migration: 12 -> 13 finished");
             }
 
+            if services().globals.database_version()? < 14 {
+                // Count the reactions to events that were received before
+                // reactions were counted
+                db.eventidkey_annotationcount.clear()?;
+
+                let room_ids = services()
+                    .rooms
+                    .metadata
+                    .iter_ids()
+                    .collect::<Result<Vec<_>>>()?;
+                for room_id in room_ids {
+                    for pdu in services().rooms.timeline.all_pdus(
+                        &services().globals.admin_bot_user_id,
+                        &room_id,
+                    )? {
+                        let (_, pdu) = pdu?;
+                        // Redacted reactions have no relation anymore
+                        if let Ok(ExtractRelatesTo {
+                            relates_to: Relation::Annotation(annotation),
+                        }) = serde_json::from_str(pdu.content.get())
+                        {
+                            services().rooms.pdu_metadata.add_annotation(
+                                &annotation.event_id,
+                                &annotation.key,
+                            )?;
+                        }
+                    }
+                }
+
+                services().globals.bump_database_version(14)?;
+
+                warn!("Migration: 13 -> 14 finished");
+            }
+
             assert_eq!(
                 services().globals.database_version().unwrap(),
                 latest_database_version,
@@ -1176,6 +1340,14 @@ impl KeyValueDatabase {
 
         services().sending.start_handler();
 
+        services().rooms.edus.presence.start_maintenance_task();
+
+        services().rooms.edus.typing.start_expiry_task();
+
+        services().media.start_retention_task();
+
+        services().rooms.directory.start_refresh_task();
+
         Self::start_cleanup_task();
 
         Ok(())
@@ -1222,6 +1394,44 @@ impl KeyValueDatabase {
                     } else {
                         debug!(elapsed = ?start.elapsed(), "cleanup: Finished");
                     }
+
+                    match services().rooms.lazy_loading.lazy_load_trim() {
+                        Ok(removed) => {
+                            debug!(
+                                removed,
+                                "cleanup: Trimmed lazy-loading records"
+                            );
+                        }
+                        Err(error) => {
+                            error!(
+                                %error,
+                                "cleanup: Failed to trim lazy-loading records"
+                            );
+                        }
+                    }
+
+                    let forgotten =
+                        services().users.forget_idle_sync_request_connections();
+                    debug!(
+                        forgotten,
+                        "cleanup: Forgot idle sliding sync connections"
+                    );
+
+                    match services().users.remove_expired_openid_tokens() {
+                        Ok(removed) => {
+                            debug!(
+                                removed,
+                                "cleanup: Removed expired OpenID tokens"
+                            );
+                        }
+                        Err(error) => {
+                            error!(
+                                %error,
+                                "cleanup: Failed to remove expired OpenID \
+                                 tokens"
+                            );
+                        }
+                    }
                 }
                 .instrument(info_span!("database_cleanup"))
                 .await;
@@ -1241,7 +1451,7 @@ fn set_emergency_access() -> Result<bool> {
     )?;
 
     let (ruleset, res) = match services().globals.emergency_password() {
-        Some(_) => (Ruleset::server_default(admin_bot), Ok(true)),
+        Some(_) => (services().globals.default_push_rules(admin_bot), Ok(true)),
         None => (Ruleset::new(), Ok(false)),
     };
 
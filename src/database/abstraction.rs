@@ -1,6 +1,6 @@
-use std::{future::Future, pin::Pin, sync::Arc};
+use std::{future::Future, path::Path, pin::Pin, sync::Arc};
 
-use crate::Result;
+use crate::{Error, Result};
 
 #[cfg(feature = "sqlite")]
 pub(crate) mod sqlite;
@@ -24,6 +24,28 @@ pub(crate) trait KeyValueDatabaseEngine: Send + Sync {
         Ok("Current database engine does not support memory usage reporting."
             .to_owned())
     }
+    /// Writes a consistent snapshot of the database to `path` while it is in
+    /// use. Returns instructions for restoring the backup.
+    fn backup(&self, _path: &Path) -> Result<&'static str> {
+        Err(Error::BadConfig(
+            "Current database engine does not support backups.",
+        ))
+    }
+    /// Compacts a tree, or the whole database if `tree` is `None`, to
+    /// reclaim disk space after large deletions. Fails if a compaction is
+    /// already running.
+    fn compact(&self, _tree: Option<&str>) -> Result<()> {
+        Err(Error::BadConfig(
+            "Current database engine does not support compaction.",
+        ))
+    }
+    /// Verifies the on-disk data without modifying it, returning a
+    /// description of each problem found
+    fn check_integrity(&self) -> Result<Vec<String>> {
+        Err(Error::BadConfig(
+            "Current database engine does not support integrity checks.",
+        ))
+    }
 }
 
 pub(crate) trait KvTree: Send + Sync {
@@ -46,6 +68,9 @@ pub(crate) trait KvTree: Send + Sync {
     ) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>;
 
     fn increment(&self, key: &[u8]) -> Result<Vec<u8>>;
+    /// Decrements a counter created by [`Self::increment`], removing it once
+    /// it reaches zero. Missing counters are left alone.
+    fn decrement(&self, key: &[u8]) -> Result<()>;
     fn increment_batch(
         &self,
         iter: &mut dyn Iterator<Item = Vec<u8>>,
@@ -1,29 +1,35 @@
 use std::{
     collections::HashSet,
     future::Future,
+    path::Path,
     pin::Pin,
     sync::{Arc, Mutex, RwLock},
 };
 
 use rocksdb::{
-    perf::get_memory_usage_stats, BlockBasedOptions, BoundColumnFamily, Cache,
-    ColumnFamilyDescriptor, DBCompactionStyle, DBCompressionType,
-    DBRecoveryMode, DBWithThreadMode, Direction, IteratorMode, MultiThreaded,
-    Options, ReadOptions, WriteOptions,
+    checkpoint::Checkpoint, perf::get_memory_usage_stats, BlockBasedOptions,
+    BoundColumnFamily, Cache, ColumnFamilyDescriptor, DBCompactionStyle,
+    DBCompressionType, DBRecoveryMode, DBWithThreadMode, Direction,
+    IteratorMode, MultiThreaded, Options, ReadOptions, WriteOptions,
 };
-use tracing::Level;
+use tracing::{info, Level};
 
 use super::{
     super::Config, watchers::Watchers, KeyValueDatabaseEngine, KvTree,
 };
-use crate::{utils, Result};
+use crate::{
+    config::{DatabaseConfig, RocksdbCompression},
+    utils, Error, Result,
+};
 
 pub(crate) struct Engine {
     rocks: DBWithThreadMode<MultiThreaded>,
-    max_open_files: i32,
+    config: DatabaseConfig,
     cache: Cache,
     old_cfs: HashSet<String>,
     new_cfs: Mutex<HashSet<&'static str>>,
+    /// Held while a manual compaction is running
+    compaction_lock: Mutex<()>,
 }
 
 pub(crate) struct RocksDbEngineTree<'a> {
@@ -33,10 +39,57 @@ pub(crate) struct RocksDbEngineTree<'a> {
     write_lock: RwLock<()>,
 }
 
-fn db_options(max_open_files: i32, rocksdb_cache: &Cache) -> Options {
+/// Rejects tuning options that RocksDB would misbehave with
+fn validate_config(config: &DatabaseConfig) -> Result<()> {
+    let rocksdb = &config.rocksdb;
+
+    if rocksdb
+        .block_cache_mb
+        .is_some_and(|size| !size.is_finite() || size < 0.0)
+    {
+        return Err(Error::BadConfig(
+            "database.rocksdb.block_cache_mb must not be negative.",
+        ));
+    }
+    if rocksdb.write_buffer_size_mb == 0 {
+        return Err(Error::BadConfig(
+            "database.rocksdb.write_buffer_size_mb must be at least 1.",
+        ));
+    }
+    if write_buffer_size(config).is_none() {
+        return Err(Error::BadConfig(
+            "database.rocksdb.write_buffer_size_mb is too large.",
+        ));
+    }
+    if rocksdb.max_background_jobs < 1 {
+        return Err(Error::BadConfig(
+            "database.rocksdb.max_background_jobs must be at least 1.",
+        ));
+    }
+    if !rocksdb.bloom_filter_bits.is_finite() || rocksdb.bloom_filter_bits < 0.0
+    {
+        return Err(Error::BadConfig(
+            "database.rocksdb.bloom_filter_bits must not be negative.",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns the size of each memtable in bytes, or `None` if it doesn't fit in a
+/// `usize`
+fn write_buffer_size(config: &DatabaseConfig) -> Option<usize> {
+    config.rocksdb.write_buffer_size_mb.checked_mul(1024 * 1024)
+}
+
+fn db_options(config: &DatabaseConfig, rocksdb_cache: &Cache) -> Options {
+    let rocksdb = &config.rocksdb;
+
     let mut block_based_options = BlockBasedOptions::default();
     block_based_options.set_block_cache(rocksdb_cache);
-    block_based_options.set_bloom_filter(10.0, false);
+    if rocksdb.bloom_filter_bits > 0.0 {
+        block_based_options.set_bloom_filter(rocksdb.bloom_filter_bits, false);
+    }
     block_based_options.set_block_size(4 * 1024);
     block_based_options.set_cache_index_and_filter_blocks(true);
     block_based_options.set_pin_l0_filter_and_index_blocks_in_cache(true);
@@ -47,14 +100,29 @@ fn db_options(max_open_files: i32, rocksdb_cache: &Cache) -> Options {
     db_opts.create_if_missing(true);
     db_opts
         .increase_parallelism(num_cpus::get().try_into().unwrap_or(i32::MAX));
-    db_opts.set_max_open_files(max_open_files);
-    db_opts.set_compression_type(DBCompressionType::Lz4);
-    db_opts.set_bottommost_compression_type(DBCompressionType::Zstd);
+    db_opts.set_max_open_files(config.rocksdb_max_open_files);
+    let (compression, bottommost_compression) = match rocksdb.compression {
+        RocksdbCompression::None => {
+            (DBCompressionType::None, DBCompressionType::None)
+        }
+        RocksdbCompression::Lz4 => {
+            (DBCompressionType::Lz4, DBCompressionType::Zstd)
+        }
+        RocksdbCompression::Zstd => {
+            (DBCompressionType::Zstd, DBCompressionType::Zstd)
+        }
+    };
+    db_opts.set_compression_type(compression);
+    db_opts.set_bottommost_compression_type(bottommost_compression);
     db_opts.set_compaction_style(DBCompactionStyle::Level);
+    db_opts.set_write_buffer_size(
+        write_buffer_size(config)
+            .expect("write buffer size should have been validated"),
+    );
 
     // https://github.com/facebook/rocksdb/wiki/Setup-Options-and-Basic-Tuning
     db_opts.set_level_compaction_dynamic_level_bytes(true);
-    db_opts.set_max_background_jobs(6);
+    db_opts.set_max_background_jobs(rocksdb.max_background_jobs);
     db_opts.set_bytes_per_sync(1_048_576);
 
     // https://github.com/facebook/rocksdb/issues/849
@@ -72,17 +140,32 @@ fn db_options(max_open_files: i32, rocksdb_cache: &Cache) -> Options {
 
 impl KeyValueDatabaseEngine for Arc<Engine> {
     fn open(config: &Config) -> Result<Self> {
+        validate_config(&config.database)?;
+
+        let block_cache_mb = config
+            .database
+            .rocksdb
+            .block_cache_mb
+            .unwrap_or(config.database.cache_capacity_mb);
         #[allow(
             clippy::as_conversions,
             clippy::cast_sign_loss,
             clippy::cast_possible_truncation
         )]
-        let cache_capacity_bytes =
-            (config.database.cache_capacity_mb * 1024.0 * 1024.0) as usize;
+        let cache_capacity_bytes = (block_cache_mb * 1024.0 * 1024.0) as usize;
         let rocksdb_cache = Cache::new_lru_cache(cache_capacity_bytes);
 
-        let db_opts =
-            db_options(config.database.rocksdb_max_open_files, &rocksdb_cache);
+        info!(
+            block_cache_mb,
+            write_buffer_size_mb = config.database.rocksdb.write_buffer_size_mb,
+            max_background_jobs = config.database.rocksdb.max_background_jobs,
+            bloom_filter_bits = config.database.rocksdb.bloom_filter_bits,
+            compression = ?config.database.rocksdb.compression,
+            max_open_files = config.database.rocksdb_max_open_files,
+            "Opening RocksDB database"
+        );
+
+        let db_opts = db_options(&config.database, &rocksdb_cache);
 
         let cfs = DBWithThreadMode::<MultiThreaded>::list_cf(
             &db_opts,
@@ -97,20 +180,18 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
             cfs.iter().map(|name| {
                 ColumnFamilyDescriptor::new(
                     name,
-                    db_options(
-                        config.database.rocksdb_max_open_files,
-                        &rocksdb_cache,
-                    ),
+                    db_options(&config.database, &rocksdb_cache),
                 )
             }),
         )?;
 
         Ok(Arc::new(Engine {
             rocks: db,
-            max_open_files: config.database.rocksdb_max_open_files,
+            config: config.database.clone(),
             cache: rocksdb_cache,
             old_cfs: cfs,
             new_cfs: Mutex::default(),
+            compaction_lock: Mutex::default(),
         }))
     }
 
@@ -132,7 +213,7 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
         if !self.old_cfs.contains(name) && !created_already {
             // Create if it didn't exist
             self.rocks
-                .create_cf(name, &db_options(self.max_open_files, &self.cache))
+                .create_cf(name, &db_options(&self.config, &self.cache))
                 .expect("should be able to create column family");
         }
 
@@ -161,6 +242,79 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
             self.cache.get_pinned_usage() as f64 / 1024.0 / 1024.0,
         ))
     }
+
+    fn backup(&self, path: &Path) -> Result<&'static str> {
+        // Flushes the mem-tables and hard-links the SST files if `path` is on
+        // the same filesystem, otherwise they are copied
+        Checkpoint::new(&self.rocks)?.create_checkpoint(path)?;
+
+        Ok("Stop the server and replace the directory at `database.path` with \
+            the backup directory.")
+    }
+
+    fn compact(&self, tree: Option<&str>) -> Result<()> {
+        let Ok(_lock) = self.compaction_lock.try_lock() else {
+            return Err(Error::BadConfig("A compaction is already running."));
+        };
+
+        let names = match tree {
+            Some(name) => vec![name.to_owned()],
+            None => self.cf_names(),
+        };
+
+        for name in names {
+            let cf = self
+                .rocks
+                .cf_handle(&name)
+                .ok_or(Error::BadConfig("No tree with this name exists."))?;
+            self.rocks.compact_range_cf(&cf, None::<&[u8]>, None::<&[u8]>);
+        }
+
+        Ok(())
+    }
+
+    fn check_integrity(&self) -> Result<Vec<String>> {
+        let mut problems = Vec::new();
+
+        for name in self.cf_names() {
+            let Some(cf) = self.rocks.cf_handle(&name) else {
+                continue;
+            };
+
+            // Reading every block with checksum verification, each iterator
+            // reads from an implicit snapshot
+            let mut readoptions = ReadOptions::default();
+            readoptions.set_verify_checksums(true);
+            readoptions.fill_cache(false);
+            for entry in self.rocks.iterator_cf_opt(
+                &cf,
+                readoptions,
+                IteratorMode::Start,
+            ) {
+                if let Err(error) = entry {
+                    problems.push(format!("{name}: {error}"));
+                    break;
+                }
+            }
+        }
+
+        Ok(problems)
+    }
+}
+
+impl Engine {
+    /// Names of all column families, including those created since the
+    /// database was opened
+    fn cf_names(&self) -> Vec<String> {
+        let new_cfs = self.new_cfs.lock().expect("lock should not be poisoned");
+        self.old_cfs
+            .iter()
+            .cloned()
+            .chain(new_cfs.iter().map(|&name| name.to_owned()))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect()
+    }
 }
 
 impl RocksDbEngineTree<'_> {
@@ -264,6 +418,28 @@ impl KvTree for RocksDbEngineTree<'_> {
         Ok(new)
     }
 
+    #[tracing::instrument(level = Level::TRACE, skip_all)]
+    fn decrement(&self, key: &[u8]) -> Result<()> {
+        let readoptions = ReadOptions::default();
+        let writeoptions = WriteOptions::default();
+
+        let lock = self.write_lock.write().unwrap();
+
+        let Some(old) =
+            self.db.rocks.get_cf_opt(&self.cf(), key, &readoptions)?
+        else {
+            return Ok(());
+        };
+        if let Some(new) = utils::decrement(&old) {
+            self.db.rocks.put_cf_opt(&self.cf(), key, new, &writeoptions)?;
+        } else {
+            self.db.rocks.delete_cf_opt(&self.cf(), key, &writeoptions)?;
+        }
+
+        drop(lock);
+        Ok(())
+    }
+
     #[tracing::instrument(level = Level::TRACE, skip_all)]
     fn increment_batch(
         &self,
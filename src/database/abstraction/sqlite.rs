@@ -4,15 +4,18 @@ use std::{
     path::{Path, PathBuf},
     pin::Pin,
     sync::Arc,
+    time::Duration,
 };
 
 use parking_lot::{Mutex, MutexGuard};
-use rusqlite::{Connection, DatabaseName::Main, OptionalExtension};
+use rusqlite::{
+    backup::Backup, Connection, DatabaseName::Main, OptionalExtension,
+};
 use thread_local::ThreadLocal;
-use tracing::debug;
+use tracing::{debug, info};
 
 use super::{watchers::Watchers, KeyValueDatabaseEngine, KvTree};
-use crate::{database::Config, Result};
+use crate::{config::SqliteConfig, database::Config, Error, Result};
 
 thread_local! {
     static READ_CONNECTION: RefCell<Option<&'static Connection>> =
@@ -58,20 +61,36 @@ impl<T> Drop for AliasableBox<T> {
 
 pub(crate) struct Engine {
     writer: Mutex<Connection>,
+    /// Held while the database is vacuumed
+    compaction_lock: Mutex<()>,
     read_conn_tls: ThreadLocal<Connection>,
     read_iterator_conn_tls: ThreadLocal<Connection>,
 
     path: PathBuf,
+    config: SqliteConfig,
     cache_size_per_thread: u32,
 }
 
 impl Engine {
-    fn prepare_conn(path: &Path, cache_size_kb: u32) -> Result<Connection> {
+    fn prepare_conn(
+        path: &Path,
+        config: &SqliteConfig,
+        cache_size_kb: u32,
+    ) -> Result<Connection> {
         let conn = Connection::open(path)?;
 
+        conn.busy_timeout(config.busy_timeout)?;
         conn.pragma_update(Some(Main), "page_size", 2048)?;
-        conn.pragma_update(Some(Main), "journal_mode", "WAL")?;
-        conn.pragma_update(Some(Main), "synchronous", "NORMAL")?;
+        conn.pragma_update(
+            Some(Main),
+            "journal_mode",
+            <&str>::from(config.journal_mode),
+        )?;
+        conn.pragma_update(
+            Some(Main),
+            "synchronous",
+            <&str>::from(config.synchronous),
+        )?;
         conn.pragma_update(
             Some(Main),
             "cache_size",
@@ -88,13 +107,23 @@ impl Engine {
 
     fn read_lock(&self) -> &Connection {
         self.read_conn_tls.get_or(|| {
-            Self::prepare_conn(&self.path, self.cache_size_per_thread).unwrap()
+            Self::prepare_conn(
+                &self.path,
+                &self.config,
+                self.cache_size_per_thread,
+            )
+            .unwrap()
         })
     }
 
     fn read_lock_iterator(&self) -> &Connection {
         self.read_iterator_conn_tls.get_or(|| {
-            Self::prepare_conn(&self.path, self.cache_size_per_thread).unwrap()
+            Self::prepare_conn(
+                &self.path,
+                &self.config,
+                self.cache_size_per_thread,
+            )
+            .unwrap()
         })
     }
 
@@ -119,6 +148,16 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
             }
         ));
 
+        let sqlite = &config.database.sqlite;
+        if sqlite
+            .cache_size_mb
+            .is_some_and(|size| !size.is_finite() || size < 0.0)
+        {
+            return Err(Error::BadConfig(
+                "database.sqlite.cache_size_mb must not be negative.",
+            ));
+        }
+
         // calculates cache-size per permanent connection
         // 1. convert MB to KiB
         // 2. divide by permanent connections + permanent iter connections +
@@ -130,18 +169,34 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
             clippy::cast_precision_loss,
             clippy::cast_sign_loss
         )]
-        let cache_size_per_thread =
-            ((config.database.cache_capacity_mb * 1024.0)
-                / ((num_cpus::get() as f64 * 2.0) + 1.0)) as u32;
+        let cache_size_per_thread = ((sqlite
+            .cache_size_mb
+            .unwrap_or(config.database.cache_capacity_mb)
+            * 1024.0)
+            / ((num_cpus::get() as f64 * 2.0) + 1.0))
+            as u32;
+
+        info!(
+            journal_mode = ?sqlite.journal_mode,
+            synchronous = ?sqlite.synchronous,
+            cache_size_per_thread_kb = cache_size_per_thread,
+            busy_timeout = ?sqlite.busy_timeout,
+            "Opening SQLite database"
+        );
 
-        let writer =
-            Mutex::new(Engine::prepare_conn(&path, cache_size_per_thread)?);
+        let writer = Mutex::new(Engine::prepare_conn(
+            &path,
+            sqlite,
+            cache_size_per_thread,
+        )?);
 
         let arc = Arc::new(Engine {
             writer,
+            compaction_lock: Mutex::new(()),
             read_conn_tls: ThreadLocal::new(),
             read_iterator_conn_tls: ThreadLocal::new(),
             path,
+            config: sqlite.clone(),
             cache_size_per_thread,
         });
 
@@ -167,6 +222,50 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
     fn cleanup(&self) -> Result<()> {
         self.flush_wal()
     }
+
+    fn backup(&self, path: &Path) -> Result<&'static str> {
+        // A separate connection, so that writes aren't blocked. Copying all
+        // pages in one step makes the backup a consistent snapshot, even if
+        // the database is written to in the meantime.
+        let source = Connection::open(&self.path)?;
+        let mut destination = Connection::open(path)?;
+        Backup::new(&source, &mut destination)?.run_to_completion(
+            -1,
+            Duration::ZERO,
+            None,
+        )?;
+
+        Ok("Stop the server, delete the `.db`, `.db-wal` and `.db-shm` files \
+            in `database.path` and copy the backup file there as \
+            `grapevine.db` (or `conduit.db` with `conduit_compat`).")
+    }
+
+    fn compact(&self, tree: Option<&str>) -> Result<()> {
+        if tree.is_some() {
+            return Err(Error::BadConfig(
+                "SQLite databases can only be compacted as a whole.",
+            ));
+        }
+        let Some(_lock) = self.compaction_lock.try_lock() else {
+            return Err(Error::BadConfig("A compaction is already running."));
+        };
+
+        self.write_lock().execute("VACUUM", [])?;
+        self.flush_wal()
+    }
+
+    fn check_integrity(&self) -> Result<Vec<String>> {
+        // A separate connection, whose read transaction is a snapshot that
+        // doesn't block writes in WAL mode
+        let conn = Connection::open(&self.path)?;
+        let mut statement = conn.prepare("PRAGMA integrity_check")?;
+        let problems = statement
+            .query_map([], |row| row.get::<_, String>(0))?
+            .filter(|result| !matches!(result, Ok(message) if message == "ok"))
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(problems)
+    }
 }
 
 pub(crate) struct SqliteTable {
@@ -379,6 +478,24 @@ impl KvTree for SqliteTable {
         Ok(new)
     }
 
+    fn decrement(&self, key: &[u8]) -> Result<()> {
+        let guard = self.engine.write_lock();
+
+        let Some(old) = self.get_with_guard(&guard, key)? else {
+            return Ok(());
+        };
+        if let Some(new) = crate::utils::decrement(&old) {
+            self.insert_with_guard(&guard, key, &new)?;
+        } else {
+            guard.execute(
+                format!("DELETE FROM {} WHERE key = ?", self.name).as_str(),
+                [key],
+            )?;
+        }
+
+        Ok(())
+    }
+
     fn scan_prefix<'a>(
         &'a self,
         prefix: Vec<u8>,
@@ -405,3 +522,103 @@ impl KvTree for SqliteTable {
         Ok(())
     }
 }
+
+/// Opens a tree in a new database in `dir`, for tests of code that reads and
+/// writes trees directly
+#[cfg(test)]
+pub(crate) fn open_test_tree(
+    dir: &Path,
+    name: &'static str,
+) -> Arc<dyn KvTree> {
+    let config = SqliteConfig::default();
+    let path = dir.join("grapevine.db");
+    let engine = Arc::new(Engine {
+        writer: Mutex::new(Engine::prepare_conn(&path, &config, 1024).unwrap()),
+        compaction_lock: Mutex::new(()),
+        read_conn_tls: ThreadLocal::new(),
+        read_iterator_conn_tls: ThreadLocal::new(),
+        path,
+        config,
+        cache_size_per_thread: 1024,
+    });
+
+    engine.open_tree(name).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use tempfile::TempDir;
+
+    use super::{open_test_tree, Engine};
+    use crate::config::SqliteConfig;
+
+    #[test]
+    fn concurrent_decrements_remove_counter() {
+        let dir = TempDir::new().unwrap();
+        let tree = open_test_tree(dir.path(), "counters");
+        for _ in 0..100 {
+            tree.increment(b"counter").unwrap();
+        }
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..25 {
+                        tree.decrement(b"counter").unwrap();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(tree.get(b"counter").unwrap(), None);
+        // Decrementing a missing counter doesn't create it
+        tree.decrement(b"counter").unwrap();
+        assert_eq!(tree.get(b"counter").unwrap(), None);
+    }
+
+    /// Writers on separate connections wait for each other instead of
+    /// failing with `SQLITE_BUSY`.
+    #[test]
+    fn concurrent_writes_dont_fail() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("grapevine.db");
+        let config = SqliteConfig::default();
+
+        Engine::prepare_conn(&path, &config, 1024)
+            .unwrap()
+            .execute(
+                "CREATE TABLE test ( \"key\" BLOB PRIMARY KEY, \"value\" \
+                 BLOB NOT NULL )",
+                [],
+            )
+            .unwrap();
+
+        let writers = (0..4_u8)
+            .map(|writer| {
+                let conn = Engine::prepare_conn(&path, &config, 1024).unwrap();
+                thread::spawn(move || {
+                    for i in 0..100_u8 {
+                        conn.execute_batch("BEGIN IMMEDIATE").unwrap();
+                        conn.execute(
+                            "INSERT INTO test (key, value) VALUES (?, ?)",
+                            [[writer, i], [i, writer]],
+                        )
+                        .unwrap();
+                        conn.execute_batch("COMMIT").unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let count: u32 = Engine::prepare_conn(&path, &config, 1024)
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM test", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 400);
+    }
+}
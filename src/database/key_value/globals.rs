@@ -1,12 +1,13 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, hash::Hash, path::Path, sync::Mutex};
 
 use async_trait::async_trait;
 use futures_util::{stream::FuturesUnordered, StreamExt};
 use lru_cache::LruCache;
 use ruma::{
     api::federation::discovery::{OldVerifyKey, ServerSigningKeys},
+    serde::Raw,
     signatures::Ed25519KeyPair,
-    DeviceId, ServerName, UserId,
+    DeviceId, OwnedServerName, ServerName, UserId,
 };
 
 use crate::{
@@ -17,6 +18,31 @@ use crate::{
 
 pub(crate) const COUNTER: &[u8] = b"c";
 
+/// Parses a keypair in the format of [`utils::generate_keypair`]
+fn parse_keypair(keypair_bytes: &[u8]) -> Result<Ed25519KeyPair> {
+    let mut parts = keypair_bytes.splitn(2, |&b| b == 0xFF);
+
+    utils::string_from_bytes(
+        // 1. version
+        parts.next().expect("splitn always returns at least one element"),
+    )
+    .map_err(|_| Error::bad_database("Invalid version bytes in keypair."))
+    .and_then(|version| {
+        // 2. key
+        parts
+            .next()
+            .ok_or_else(|| {
+                Error::bad_database("Invalid keypair format in database.")
+            })
+            .map(|key| (version, key))
+    })
+    .and_then(|(version, key)| {
+        Ed25519KeyPair::from_der(key, version).map_err(|_| {
+            Error::bad_database("Private or public keys are invalid.")
+        })
+    })
+}
+
 #[async_trait]
 impl service::globals::Data for KeyValueDatabase {
     fn next_count(&self) -> Result<u64> {
@@ -53,6 +79,7 @@ impl service::globals::Data for KeyValueDatabase {
 
         futures.push(self.userroomid_joined.watch_prefix(&userid_prefix));
         futures.push(self.userroomid_invitestate.watch_prefix(&userid_prefix));
+        futures.push(self.userroomid_knockstate.watch_prefix(&userid_prefix));
         futures.push(self.userroomid_leftstate.watch_prefix(&userid_prefix));
         futures.push(
             self.userroomid_notificationcount.watch_prefix(&userid_prefix),
@@ -98,6 +125,8 @@ impl service::globals::Data for KeyValueDatabase {
                 self.readreceiptid_readreceipt.watch_prefix(&roomid_prefix),
             );
 
+            futures.push(self.presenceid_presence.watch_prefix(&roomid_prefix));
+
             // Key changes
             futures.push(self.keychangeid_userid.watch_prefix(&roomid_prefix));
 
@@ -105,6 +134,15 @@ impl service::globals::Data for KeyValueDatabase {
             let mut roomuser_prefix = roomid_prefix.clone();
             roomuser_prefix.extend_from_slice(&userid_prefix);
 
+            // Private read receipts
+            let mut roomuser_id = roomid_prefix.clone();
+            roomuser_id.extend_from_slice(&userid_bytes);
+
+            futures.push(
+                self.roomuserid_lastprivatereadupdate
+                    .watch_prefix(&roomuser_id),
+            );
+
             futures.push(
                 self.roomusertype_roomuserdataid.watch_prefix(&roomuser_prefix),
             );
@@ -137,8 +175,54 @@ impl service::globals::Data for KeyValueDatabase {
         self.db.cleanup()
     }
 
+    fn backup(&self, path: &Path) -> Result<&'static str> {
+        self.db.backup(path)
+    }
+
+    fn compact(&self, tree: Option<&str>) -> Result<()> {
+        self.db.compact(tree)
+    }
+
+    fn check_integrity(&self) -> Result<Vec<String>> {
+        let mut problems = self.db.check_integrity()?;
+
+        // `pduid_pdu` is written before `eventid_pduid`, so events that are
+        // added concurrently aren't reported
+        for (event_id, pdu_id) in self.eventid_pduid.iter() {
+            if self.pduid_pdu.get(&pdu_id)?.is_none()
+                // Not removed concurrently
+                && self.eventid_pduid.get(&event_id)?.as_ref() == Some(&pdu_id)
+            {
+                problems.push(format!(
+                    "eventid_pduid: {} points to a missing PDU",
+                    String::from_utf8_lossy(&event_id)
+                ));
+            }
+        }
+
+        // `eventid_shorteventid` is written before `shorteventid_eventid`
+        for (shorteventid, event_id) in self.shorteventid_eventid.iter() {
+            if self.eventid_shorteventid.get(&event_id)?.as_ref()
+                != Some(&shorteventid)
+            {
+                problems.push(format!(
+                    "shorteventid_eventid: {} doesn't map back to short ID \
+                     {}",
+                    String::from_utf8_lossy(&event_id),
+                    utils::u64_from_bytes(&shorteventid).map_or_else(
+                        |_| "(invalid)".to_owned(),
+                        |short| short.to_string()
+                    )
+                ));
+            }
+        }
+
+        Ok(problems)
+    }
+
     fn memory_usage(&self) -> String {
         let pdu_cache = self.pdu_cache.lock().unwrap().len();
+        let missing_pdu_cache = self.missing_pdu_cache.lock().unwrap().len();
         let shorteventid_cache = self.shorteventid_cache.lock().unwrap().len();
         let auth_chain_cache = self.auth_chain_cache.lock().unwrap().len();
         let eventidshort_cache = self.eventidshort_cache.lock().unwrap().len();
@@ -154,6 +238,7 @@ impl service::globals::Data for KeyValueDatabase {
         let mut response = format!(
             "\
 pdu_cache: {pdu_cache}
+missing_pdu_cache: {missing_pdu_cache}
 shorteventid_cache: {shorteventid_cache}
 auth_chain_cache: {auth_chain_cache}
 eventidshort_cache: {eventidshort_cache}
@@ -169,10 +254,32 @@ lasttimelinecount_cache: {lasttimelinecount_cache}\n"
         response
     }
 
+    fn cache_sizes(&self) -> Vec<(&'static str, usize, usize)> {
+        fn size<K: Eq + Hash, V>(
+            name: &'static str,
+            cache: &Mutex<LruCache<K, V>>,
+        ) -> (&'static str, usize, usize) {
+            let cache = cache.lock().unwrap();
+            (name, cache.len(), cache.capacity())
+        }
+
+        vec![
+            size("pdu_cache", &self.pdu_cache),
+            size("missing_pdu_cache", &self.missing_pdu_cache),
+            size("shorteventid_cache", &self.shorteventid_cache),
+            size("auth_chain_cache", &self.auth_chain_cache),
+            size("eventidshort_cache", &self.eventidshort_cache),
+            size("shortstatekey_cache", &self.shortstatekey_cache),
+            size("statekeyshort_cache", &self.statekeyshort_cache),
+        ]
+    }
+
     fn clear_caches(&self, amount: u32) {
         if amount > 0 {
             let c = &mut *self.pdu_cache.lock().unwrap();
             *c = LruCache::new(c.capacity());
+            let c = &mut *self.missing_pdu_cache.lock().unwrap();
+            *c = LruCache::new(c.capacity());
         }
         if amount > 1 {
             let c = &mut *self.shorteventid_cache.lock().unwrap();
@@ -214,27 +321,15 @@ lasttimelinecount_cache: {lasttimelinecount_cache}\n"
             |s| Ok(s.clone()),
         )?;
 
-        let mut parts = keypair_bytes.splitn(2, |&b| b == 0xFF);
+        parse_keypair(&keypair_bytes)
+    }
 
-        utils::string_from_bytes(
-            // 1. version
-            parts.next().expect("splitn always returns at least one element"),
-        )
-        .map_err(|_| Error::bad_database("Invalid version bytes in keypair."))
-        .and_then(|version| {
-            // 2. key
-            parts
-                .next()
-                .ok_or_else(|| {
-                    Error::bad_database("Invalid keypair format in database.")
-                })
-                .map(|key| (version, key))
-        })
-        .and_then(|(version, key)| {
-            Ed25519KeyPair::from_der(key, version).map_err(|_| {
-                Error::bad_database("Private or public keys are invalid.")
-            })
-        })
+    fn replace_keypair(&self) -> Result<Ed25519KeyPair> {
+        let keypair_bytes = utils::generate_keypair();
+        let keypair = parse_keypair(&keypair_bytes)?;
+        self.global.insert(b"keypair", &keypair_bytes)?;
+
+        Ok(keypair)
     }
 
     fn remove_keypair(&self) -> Result<()> {
@@ -348,6 +443,84 @@ lasttimelinecount_cache: {lasttimelinecount_cache}\n"
         Ok(signingkeys)
     }
 
+    fn set_signed_keys(
+        &self,
+        origin: &ServerName,
+        keys: &Raw<ServerSigningKeys>,
+    ) -> Result<()> {
+        self.server_signedkeys
+            .insert(origin.as_bytes(), keys.json().get().as_bytes())
+    }
+
+    fn signed_keys_for(
+        &self,
+        origin: &ServerName,
+    ) -> Result<Option<Raw<ServerSigningKeys>>> {
+        self.server_signedkeys
+            .get(origin.as_bytes())?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes).map_err(|_| {
+                    Error::bad_database("Invalid signed keys in database.")
+                })
+            })
+            .transpose()
+    }
+
+    fn trusted_server_overrides(&self) -> Result<Vec<(OwnedServerName, bool)>> {
+        self.servername_trusted
+            .iter()
+            .map(|(key, value)| {
+                let server_name = utils::string_from_bytes(&key)
+                    .ok()
+                    .and_then(|s| OwnedServerName::try_from(s).ok())
+                    .ok_or_else(|| {
+                        Error::bad_database(
+                            "Invalid server name in servername_trusted.",
+                        )
+                    })?;
+
+                Ok((server_name, value.first() == Some(&1)))
+            })
+            .collect()
+    }
+
+    fn set_trusted_server_override(
+        &self,
+        server_name: &ServerName,
+        trusted: bool,
+    ) -> Result<()> {
+        self.servername_trusted
+            .insert(server_name.as_bytes(), &[u8::from(trusted)])
+    }
+
+    fn federation_overrides(&self) -> Result<Vec<(OwnedServerName, bool)>> {
+        self.servername_federationallowed
+            .iter()
+            .map(|(key, value)| {
+                let server_name = utils::string_from_bytes(&key)
+                    .ok()
+                    .and_then(|s| OwnedServerName::try_from(s).ok())
+                    .ok_or_else(|| {
+                        Error::bad_database(
+                            "Invalid server name in \
+                             servername_federationallowed.",
+                        )
+                    })?;
+
+                Ok((server_name, value.first() == Some(&1)))
+            })
+            .collect()
+    }
+
+    fn set_federation_override(
+        &self,
+        server_name: &ServerName,
+        allowed: bool,
+    ) -> Result<()> {
+        self.servername_federationallowed
+            .insert(server_name.as_bytes(), &[u8::from(allowed)])
+    }
+
     fn database_version(&self) -> Result<u64> {
         self.global.get(b"version")?.map_or(Ok(0), |version| {
             utils::u64_from_bytes(&version).map_err(|_| {
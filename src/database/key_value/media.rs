@@ -1,4 +1,6 @@
-use ruma::api::client::error::ErrorKind;
+use std::mem;
+
+use ruma::{api::client::error::ErrorKind, UserId};
 
 use crate::{database::KeyValueDatabase, service, utils, Error, Result};
 
@@ -80,4 +82,96 @@ impl service::media::Data for KeyValueDatabase {
         };
         Ok((content_disposition, content_type, key))
     }
+
+    fn all_file_metadata<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(String, Vec<u8>)>> + 'a> {
+        Box::new(self.mediaid_file.iter().map(|(key, _)| {
+            let mxc = key
+                .split(|&b| b == 0xFF)
+                .next()
+                .expect("split always returns one element");
+            let mxc = utils::string_from_bytes(mxc).map_err(|_| {
+                Error::bad_database("Media ID in mediaid_file is invalid.")
+            })?;
+
+            Ok((mxc, key))
+        }))
+    }
+
+    fn delete_file_metadata(&self, key: &[u8]) -> Result<()> {
+        self.mediaid_file.remove(key)
+    }
+
+    fn record_upload(
+        &self,
+        mxc: &str,
+        user_id: &UserId,
+        size: u64,
+    ) -> Result<()> {
+        let mut value = size.to_be_bytes().to_vec();
+        value.extend_from_slice(user_id.as_bytes());
+        self.mediaid_uploader.insert(mxc.as_bytes(), &value)?;
+
+        let usage = self.media_usage(user_id)?.saturating_add(size);
+        self.userid_mediausage.insert(user_id.as_bytes(), &usage.to_be_bytes())
+    }
+
+    fn forget_upload(&self, mxc: &str) -> Result<()> {
+        let Some(value) = self.mediaid_uploader.get(mxc.as_bytes())? else {
+            return Ok(());
+        };
+
+        if value.len() < mem::size_of::<u64>() {
+            return Err(Error::bad_database("Invalid mediaid_uploader."));
+        }
+        let (size, user_id) = value.split_at(mem::size_of::<u64>());
+        let user_id =
+            UserId::parse(utils::string_from_bytes(user_id).map_err(|_| {
+                Error::bad_database("Invalid user ID in mediaid_uploader.")
+            })?)
+            .map_err(|_| {
+                Error::bad_database("Invalid user ID in mediaid_uploader.")
+            })?;
+        let size = utils::u64_from_bytes(size).map_err(|_| {
+            Error::bad_database("Invalid size in mediaid_uploader.")
+        })?;
+
+        let usage = self.media_usage(&user_id)?.saturating_sub(size);
+        self.userid_mediausage
+            .insert(user_id.as_bytes(), &usage.to_be_bytes())?;
+        self.mediaid_uploader.remove(mxc.as_bytes())
+    }
+
+    fn media_usage(&self, user_id: &UserId) -> Result<u64> {
+        self.userid_mediausage.get(user_id.as_bytes())?.map_or(Ok(0), |bytes| {
+            utils::u64_from_bytes(&bytes).map_err(|_| {
+                Error::bad_database("Invalid usage in userid_mediausage.")
+            })
+        })
+    }
+
+    fn media_quota(&self, user_id: &UserId) -> Result<Option<u64>> {
+        self.userid_mediaquota
+            .get(user_id.as_bytes())?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("Invalid quota in userid_mediaquota.")
+                })
+            })
+            .transpose()
+    }
+
+    fn set_media_quota(
+        &self,
+        user_id: &UserId,
+        quota: Option<u64>,
+    ) -> Result<()> {
+        if let Some(quota) = quota {
+            self.userid_mediaquota
+                .insert(user_id.as_bytes(), &quota.to_be_bytes())
+        } else {
+            self.userid_mediaquota.remove(user_id.as_bytes())
+        }
+    }
 }
@@ -0,0 +1,139 @@
+use std::mem;
+
+use ruma::{
+    events::presence::PresenceEvent, OwnedRoomId, OwnedUserId, RoomId, UserId,
+};
+
+use crate::{
+    database::KeyValueDatabase, service, services, utils, Error, Result,
+};
+
+impl service::rooms::edus::presence::Data for KeyValueDatabase {
+    #[tracing::instrument(skip(self, presence))]
+    fn update_presence(
+        &self,
+        room_ids: &[OwnedRoomId],
+        presence: &PresenceEvent,
+    ) -> Result<u64> {
+        let user_id = &presence.sender;
+        let old_count = self.last_presence_update(user_id)?;
+        let count = services().globals.next_count()?;
+
+        let value = serde_json::to_vec(presence)
+            .expect("PresenceEvent::to_vec always works");
+
+        for room_id in room_ids {
+            // Remove old entry
+            if let Some(old_count) = old_count {
+                self.presenceid_presence
+                    .remove(&presence_id(room_id, old_count, user_id))?;
+            }
+
+            self.presenceid_presence
+                .insert(&presence_id(room_id, count, user_id), &value)?;
+        }
+
+        self.userid_lastpresenceupdate
+            .insert(user_id.as_bytes(), &count.to_be_bytes())?;
+
+        Ok(count)
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn last_presence_update(&self, user_id: &UserId) -> Result<Option<u64>> {
+        self.userid_lastpresenceupdate
+            .get(user_id.as_bytes())?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database(
+                        "Count in userid_lastpresenceupdate is invalid.",
+                    )
+                })
+            })
+            .transpose()
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn get_presence_event(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        count: u64,
+    ) -> Result<Option<PresenceEvent>> {
+        self.presenceid_presence
+            .get(&presence_id(room_id, count, user_id))?
+            .map(|value| parse_presence_event(&value))
+            .transpose()
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn presence_since<'a>(
+        &'a self,
+        room_id: &RoomId,
+        since: u64,
+    ) -> Box<dyn Iterator<Item = Result<(u64, PresenceEvent)>> + 'a> {
+        let mut prefix = room_id.as_bytes().to_vec();
+        prefix.push(0xFF);
+        let prefix2 = prefix.clone();
+
+        let mut first_possible_edu = prefix.clone();
+        // +1 so we don't send the event at since
+        first_possible_edu.extend_from_slice(&(since + 1).to_be_bytes());
+
+        Box::new(
+            self.presenceid_presence
+                .iter_from(&first_possible_edu, false)
+                .take_while(move |(k, _)| k.starts_with(&prefix2))
+                .map(move |(k, v)| {
+                    let count = utils::u64_from_bytes(
+                        &k[prefix.len()..prefix.len() + mem::size_of::<u64>()],
+                    )
+                    .map_err(|_| {
+                        Error::bad_database("Invalid presenceid count in db.")
+                    })?;
+
+                    Ok((count, parse_presence_event(&v)?))
+                }),
+        )
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn all_last_presence_updates<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedUserId, u64)>> + 'a> {
+        Box::new(self.userid_lastpresenceupdate.iter().map(|(k, v)| {
+            let user_id =
+                UserId::parse(utils::string_from_bytes(&k).map_err(|_| {
+                    Error::bad_database(
+                        "Invalid userid bytes in userid_lastpresenceupdate.",
+                    )
+                })?)
+                .map_err(|_| {
+                    Error::bad_database(
+                        "Invalid userid in userid_lastpresenceupdate.",
+                    )
+                })?;
+            let count = utils::u64_from_bytes(&v).map_err(|_| {
+                Error::bad_database(
+                    "Count in userid_lastpresenceupdate is invalid.",
+                )
+            })?;
+
+            Ok((user_id, count))
+        }))
+    }
+}
+
+fn presence_id(room_id: &RoomId, count: u64, user_id: &UserId) -> Vec<u8> {
+    let mut key = room_id.as_bytes().to_vec();
+    key.push(0xFF);
+    key.extend_from_slice(&count.to_be_bytes());
+    key.push(0xFF);
+    key.extend_from_slice(user_id.as_bytes());
+    key
+}
+
+fn parse_presence_event(bytes: &[u8]) -> Result<PresenceEvent> {
+    serde_json::from_slice(bytes)
+        .map_err(|_| Error::bad_database("Invalid presence event in db."))
+}
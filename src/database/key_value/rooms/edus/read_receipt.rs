@@ -23,17 +23,26 @@ impl service::rooms::edus::read_receipt::Data for KeyValueDatabase {
         let mut last_possible_key = prefix.clone();
         last_possible_key.extend_from_slice(&u64::MAX.to_be_bytes());
 
+        // Users have one receipt per thread, so the thread is part of the key
+        let thread = event
+            .content
+            .values()
+            .flat_map(|receipts| receipts.values())
+            .find_map(|users| users.get(user_id))
+            .and_then(|receipt| receipt.thread.as_str());
+        let mut user_thread = user_id.as_bytes().to_vec();
+        if let Some(thread) = thread {
+            user_thread.push(0xFF);
+            user_thread.extend_from_slice(thread.as_bytes());
+        }
+
         // Remove old entry
+        let suffix_start = prefix.len() + mem::size_of::<u64>() + 1;
         if let Some((old, _)) = self
             .readreceiptid_readreceipt
             .iter_from(&last_possible_key, true)
             .take_while(|(key, _)| key.starts_with(&prefix))
-            .find(|(key, _)| {
-                key.rsplit(|&b| b == 0xFF)
-                    .next()
-                    .expect("rsplit always returns an element")
-                    == user_id.as_bytes()
-            })
+            .find(|(key, _)| key.get(suffix_start..) == Some(&*user_thread))
         {
             // This is the old room_latest
             self.readreceiptid_readreceipt.remove(&old)?;
@@ -43,7 +52,7 @@ impl service::rooms::edus::read_receipt::Data for KeyValueDatabase {
         room_latest_id
             .extend_from_slice(&services().globals.next_count()?.to_be_bytes());
         room_latest_id.push(0xFF);
-        room_latest_id.extend_from_slice(user_id.as_bytes());
+        room_latest_id.extend_from_slice(&user_thread);
 
         self.readreceiptid_readreceipt.insert(
             &room_latest_id,
@@ -90,11 +99,13 @@ impl service::rooms::edus::read_receipt::Data for KeyValueDatabase {
                             "Invalid readreceiptid count in db.",
                         )
                     })?;
+                    // The user ID may be followed by the thread of the receipt
+                    let user_id = k[prefix.len() + mem::size_of::<u64>() + 1..]
+                        .split(|&b| b == 0xFF)
+                        .next()
+                        .expect("split always returns an element");
                     let user_id = UserId::parse(
-                        utils::string_from_bytes(
-                            &k[prefix.len() + mem::size_of::<u64>() + 1..],
-                        )
-                        .map_err(|_| {
+                        utils::string_from_bytes(user_id).map_err(|_| {
                             Error::bad_database(
                                 "Invalid readreceiptid userid bytes in db.",
                             )
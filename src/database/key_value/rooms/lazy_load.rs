@@ -1,6 +1,9 @@
-use ruma::{DeviceId, RoomId, UserId};
+use ruma::{DeviceId, OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomId, UserId};
 
-use crate::{database::KeyValueDatabase, service, Result};
+use crate::{
+    database::{abstraction::KvTree, KeyValueDatabase},
+    service, utils, Error, Result,
+};
 
 impl service::rooms::lazy_loading::Data for KeyValueDatabase {
     fn lazy_load_was_sent_before(
@@ -62,4 +65,152 @@ impl service::rooms::lazy_loading::Data for KeyValueDatabase {
 
         Ok(())
     }
+
+    fn lazy_load_reset_all(&self, user_id: &UserId) -> Result<()> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xFF);
+
+        for (key, _) in self.lazyloadedids.scan_prefix(prefix) {
+            self.lazyloadedids.remove(&key)?;
+        }
+
+        Ok(())
+    }
+
+    fn lazy_load_trim(
+        &self,
+        keep: &mut dyn FnMut(
+            &UserId,
+            &DeviceId,
+            &RoomId,
+            usize,
+        ) -> Result<bool>,
+    ) -> Result<usize> {
+        trim(&*self.lazyloadedids, keep)
+    }
+}
+
+/// Removes the records of each user, device and room in `lazyloadedids` for
+/// which `keep` returns false, returns the number of records removed
+fn trim(
+    lazyloadedids: &dyn KvTree,
+    keep: &mut dyn FnMut(&UserId, &DeviceId, &RoomId, usize) -> Result<bool>,
+) -> Result<usize> {
+    let mut counts = Vec::new();
+    let mut current: Option<(Vec<u8>, usize)> = None;
+
+    for (key, _) in lazyloadedids.iter() {
+        // Everything up to and including the separator after the room ID
+        let Some(separator) = key.iter().rposition(|&b| b == 0xFF) else {
+            return Err(Error::bad_database("Invalid key in lazyloadedids."));
+        };
+        let prefix = &key[..=separator];
+
+        match &mut current {
+            Some((current_prefix, count)) if *current_prefix == prefix => {
+                *count += 1;
+            }
+            _ => {
+                if let Some(previous) = current.replace((prefix.to_vec(), 1)) {
+                    counts.push(previous);
+                }
+            }
+        }
+    }
+    counts.extend(current);
+
+    let mut removed = 0;
+    for (prefix, count) in counts {
+        let (user_id, device_id, room_id) = parse_lazy_load_prefix(&prefix)?;
+        if keep(&user_id, &device_id, &room_id, count)? {
+            continue;
+        }
+
+        for (key, _) in lazyloadedids.scan_prefix(prefix) {
+            lazyloadedids.remove(&key)?;
+        }
+        removed += count;
+    }
+
+    Ok(removed)
+}
+
+/// Parses the user ID, device ID and room ID from the prefix of a
+/// `lazyloadedids` key
+fn parse_lazy_load_prefix(
+    prefix: &[u8],
+) -> Result<(OwnedUserId, OwnedDeviceId, OwnedRoomId)> {
+    let mut parts = prefix.split(|&b| b == 0xFF);
+
+    let mut next_part = || {
+        parts
+            .next()
+            .and_then(|part| utils::string_from_bytes(part).ok())
+            .ok_or_else(|| Error::bad_database("Invalid key in lazyloadedids."))
+    };
+
+    let user_id = UserId::parse(next_part()?).map_err(|_| {
+        Error::bad_database("Invalid user ID in lazyloadedids.")
+    })?;
+    let device_id = next_part()?.into();
+    let room_id = RoomId::parse(next_part()?).map_err(|_| {
+        Error::bad_database("Invalid room ID in lazyloadedids.")
+    })?;
+
+    Ok((user_id, device_id, room_id))
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use ruma::{device_id, room_id, user_id, RoomId};
+    use tempfile::TempDir;
+
+    use super::trim;
+    use crate::database::abstraction::sqlite::open_test_tree;
+
+    /// Removes the records of rooms the user is no longer joined to, like
+    /// the cleanup task does.
+    #[test]
+    fn lazy_load_records_are_removed_after_leaving_room() {
+        let dir = TempDir::new().unwrap();
+        let tree = open_test_tree(dir.path(), "lazyloadedids");
+
+        let alice = user_id!("@alice:example.com");
+        let left = room_id!("!left:example.com");
+        let joined = room_id!("!joined:example.com");
+        let key = |room_id: &RoomId, member: &str| {
+            [
+                alice.as_bytes(),
+                "DEVICE".as_bytes(),
+                room_id.as_bytes(),
+                member.as_bytes(),
+            ]
+            .join(&0xFF)
+        };
+        for room_id in [left, joined] {
+            for member in ["@bob:example.com", "@carol:example.com"] {
+                tree.insert(&key(room_id, member), &[]).unwrap();
+            }
+        }
+
+        let mut seen = Vec::new();
+        let removed =
+            trim(&*tree, &mut |user_id, device_id, room_id, count| {
+                assert_eq!(user_id, alice);
+                assert_eq!(device_id, device_id!("DEVICE"));
+                seen.push((room_id.to_owned(), count));
+                Ok(room_id == joined)
+            })
+            .unwrap();
+
+        assert_eq!(removed, 2);
+        assert_eq!(seen, [(joined.to_owned(), 2), (left.to_owned(), 2)]);
+        assert!(tree.get(&key(left, "@bob:example.com")).unwrap().is_none());
+        assert!(tree.get(&key(left, "@carol:example.com")).unwrap().is_none());
+        assert!(tree.get(&key(joined, "@bob:example.com")).unwrap().is_some());
+        assert!(tree
+            .get(&key(joined, "@carol:example.com"))
+            .unwrap()
+            .is_some());
+    }
 }
@@ -1,4 +1,4 @@
-use ruma::{OwnedRoomId, RoomId};
+use ruma::{OwnedRoomId, RoomId, ServerName};
 
 use crate::{
     database::KeyValueDatabase, service, services, utils, Error, Result,
@@ -49,4 +49,89 @@ impl service::rooms::metadata::Data for KeyValueDatabase {
 
         Ok(())
     }
+
+    fn iter_disabled<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a> {
+        Box::new(self.disabledroomids.iter().map(|(bytes, _)| {
+            RoomId::parse(utils::string_from_bytes(&bytes).map_err(|_| {
+                Error::bad_database(
+                    "Room ID in disabledroomids is invalid unicode.",
+                )
+            })?)
+            .map_err(|_| {
+                Error::bad_database("Room ID in disabledroomids is invalid.")
+            })
+        }))
+    }
+
+    fn max_fetch_prev_events(&self, room_id: &RoomId) -> Result<Option<u16>> {
+        self.roomid_maxfetchprevevents
+            .get(room_id.as_bytes())?
+            .map(|limit| {
+                limit.try_into().map(u16::from_be_bytes).map_err(|_| {
+                    Error::bad_database(
+                        "Invalid limit in roomid_maxfetchprevevents.",
+                    )
+                })
+            })
+            .transpose()
+    }
+
+    fn set_max_fetch_prev_events(
+        &self,
+        room_id: &RoomId,
+        limit: Option<u16>,
+    ) -> Result<()> {
+        if let Some(limit) = limit {
+            self.roomid_maxfetchprevevents
+                .insert(room_id.as_bytes(), &limit.to_be_bytes())
+        } else {
+            self.roomid_maxfetchprevevents.remove(room_id.as_bytes())
+        }
+    }
+
+    fn server_may_join(
+        &self,
+        room_id: &RoomId,
+        server_name: &ServerName,
+    ) -> Result<bool> {
+        let mut key = room_id.as_bytes().to_vec();
+        key.push(0xFF);
+        key.extend_from_slice(server_name.as_bytes());
+
+        if let Some(allowed) = self.roomserverid_joinallowed.get(&key)? {
+            return Ok(allowed.first() == Some(&1));
+        }
+
+        // Not mentioned, so it may only join if there is no allowlist
+        Ok(self.roomid_joinallowlist.get(room_id.as_bytes())?.is_none())
+    }
+
+    fn set_server_may_join(
+        &self,
+        room_id: &RoomId,
+        server_name: &ServerName,
+        allowed: bool,
+    ) -> Result<()> {
+        let mut key = room_id.as_bytes().to_vec();
+        key.push(0xFF);
+        key.extend_from_slice(server_name.as_bytes());
+
+        // Denying the last allowed server must not lift the allowlist
+        if allowed {
+            self.roomid_joinallowlist.insert(room_id.as_bytes(), &[])?;
+        }
+        self.roomserverid_joinallowed.insert(&key, &[u8::from(allowed)])
+    }
+
+    fn clear_server_join_restrictions(&self, room_id: &RoomId) -> Result<()> {
+        let mut prefix = room_id.as_bytes().to_vec();
+        prefix.push(0xFF);
+
+        for (key, _) in self.roomserverid_joinallowed.scan_prefix(prefix) {
+            self.roomserverid_joinallowed.remove(&key)?;
+        }
+        self.roomid_joinallowlist.remove(room_id.as_bytes())
+    }
 }
@@ -1,5 +1,6 @@
 use ruma::{CanonicalJsonObject, EventId};
 
+use super::timeline::forget_missing;
 use crate::{database::KeyValueDatabase, service, Error, Result};
 
 impl service::rooms::outlier::Data for KeyValueDatabase {
@@ -25,6 +26,9 @@ impl service::rooms::outlier::Data for KeyValueDatabase {
         self.eventid_outlierpdu.insert(
             event_id.as_bytes(),
             &serde_json::to_vec(&pdu).expect("CanonicalJsonObject is valid"),
-        )
+        )?;
+        forget_missing(self, event_id);
+
+        Ok(())
     }
 }
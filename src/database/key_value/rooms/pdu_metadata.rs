@@ -1,70 +1,162 @@
-use std::{mem, sync::Arc};
+use std::sync::Arc;
 
-use ruma::{EventId, RoomId, UserId};
+use ruma::{EventId, OwnedEventId, RoomId, UserId};
 
+use super::timeline::{count_to_suffix, pdu_count};
 use crate::{
-    database::KeyValueDatabase,
+    database::{abstraction::KvTree, KeyValueDatabase},
     service::{self, rooms::timeline::PduCount},
     services, utils, Error, PduEvent, Result,
 };
 
+/// Returns the key of a relation in `tofrom_relation`
+///
+/// Keys are the PDU ID suffixes of the target and the related event, so
+/// relations are sorted by count like the timeline.
+fn relation_key(from: PduCount, to: PduCount) -> Vec<u8> {
+    let mut key = count_to_suffix(to);
+    key.extend_from_slice(&count_to_suffix(from));
+    key
+}
+
+/// Returns the counts and PDU IDs of the events relating to `target`, most
+/// recent first, starting before `until`
+fn relations_until_ids(
+    tofrom_relation: &dyn KvTree,
+    shortroomid: u64,
+    target: PduCount,
+    until: PduCount,
+) -> impl Iterator<Item = Result<(PduCount, Vec<u8>)>> + '_ {
+    let prefix = count_to_suffix(target);
+    let mut current = prefix.clone();
+    current.extend_from_slice(&count_to_suffix(until.previous()));
+
+    tofrom_relation
+        .iter_from(&current, true)
+        .take_while(move |(k, _)| k.starts_with(&prefix))
+        .map(move |(tofrom, _data)| {
+            let mut pduid = shortroomid.to_be_bytes().to_vec();
+            pduid.extend_from_slice(&tofrom[prefix.len()..]);
+            let from = pdu_count(&pduid).map_err(|_| {
+                Error::bad_database("Invalid count in tofrom_relation.")
+            })?;
+            Ok((from, pduid))
+        })
+}
+
+fn annotation_key(target: &EventId, key: &str) -> Vec<u8> {
+    let mut db_key = target.as_bytes().to_vec();
+    db_key.push(0xFF);
+    db_key.extend_from_slice(key.as_bytes());
+    db_key
+}
+
 impl service::rooms::pdu_metadata::Data for KeyValueDatabase {
-    fn add_relation(&self, from: u64, to: u64) -> Result<()> {
-        let mut key = to.to_be_bytes().to_vec();
-        key.extend_from_slice(&from.to_be_bytes());
-        self.tofrom_relation.insert(&key, &[])?;
+    fn add_relation(&self, from: PduCount, to: PduCount) -> Result<()> {
+        self.tofrom_relation.insert(&relation_key(from, to), &[])?;
+        Ok(())
+    }
+
+    fn add_annotation(&self, target: &EventId, key: &str) -> Result<()> {
+        self.eventidkey_annotationcount
+            .increment(&annotation_key(target, key))?;
         Ok(())
     }
 
+    fn remove_annotation(&self, target: &EventId, key: &str) -> Result<()> {
+        self.eventidkey_annotationcount.decrement(&annotation_key(target, key))
+    }
+
+    fn annotations<'a>(
+        &'a self,
+        target: &EventId,
+    ) -> Box<dyn Iterator<Item = Result<(String, u64)>> + 'a> {
+        let mut prefix = target.as_bytes().to_vec();
+        prefix.push(0xFF);
+        let prefix_len = prefix.len();
+
+        Box::new(self.eventidkey_annotationcount.scan_prefix(prefix).map(
+            move |(key, count)| {
+                let key = utils::string_from_bytes(&key[prefix_len..])
+                    .map_err(|_| {
+                        Error::bad_database(
+                            "Invalid key in eventidkey_annotationcount.",
+                        )
+                    })?;
+                let count = utils::u64_from_bytes(&count).map_err(|_| {
+                    Error::bad_database(
+                        "Invalid count in eventidkey_annotationcount.",
+                    )
+                })?;
+                Ok((key, count))
+            },
+        ))
+    }
+
+    fn add_replacement(
+        &self,
+        target: &EventId,
+        replacement: &EventId,
+    ) -> Result<()> {
+        let mut key = target.as_bytes().to_vec();
+        key.push(0xFF);
+        key.extend_from_slice(replacement.as_bytes());
+        self.tofrom_replacement.insert(&key, &[])
+    }
+
+    fn replacements<'a>(
+        &'a self,
+        target: &EventId,
+    ) -> Box<dyn Iterator<Item = Result<OwnedEventId>> + 'a> {
+        let mut prefix = target.as_bytes().to_vec();
+        prefix.push(0xFF);
+        let prefix_len = prefix.len();
+
+        Box::new(self.tofrom_replacement.scan_prefix(prefix).map(
+            move |(key, _)| {
+                utils::string_from_bytes(&key[prefix_len..])
+                    .ok()
+                    .and_then(|event_id| EventId::parse(event_id).ok())
+                    .ok_or_else(|| {
+                        Error::bad_database(
+                            "Invalid event ID in tofrom_replacement.",
+                        )
+                    })
+            },
+        ))
+    }
+
     fn relations_until<'a>(
         &'a self,
         user_id: &'a UserId,
         shortroomid: u64,
-        target: u64,
+        target: PduCount,
         until: PduCount,
     ) -> Result<Box<dyn Iterator<Item = Result<(PduCount, PduEvent)>> + 'a>>
     {
-        let prefix = target.to_be_bytes().to_vec();
-        let mut current = prefix.clone();
-
-        let count_raw = match until {
-            PduCount::Normal(x) => x - 1,
-            PduCount::Backfilled(x) => {
-                current.extend_from_slice(&0_u64.to_be_bytes());
-                u64::MAX - x - 1
-            }
-        };
-        current.extend_from_slice(&count_raw.to_be_bytes());
-
         Ok(Box::new(
-            self.tofrom_relation
-                .iter_from(&current, true)
-                .take_while(move |(k, _)| k.starts_with(&prefix))
-                .map(move |(tofrom, _data)| {
-                    let from = utils::u64_from_bytes(
-                        &tofrom[(mem::size_of::<u64>())..],
-                    )
-                    .map_err(|_| {
-                        Error::bad_database("Invalid count in tofrom_relation.")
+            relations_until_ids(
+                &*self.tofrom_relation,
+                shortroomid,
+                target,
+                until,
+            )
+            .map(move |result| {
+                let (from, pduid) = result?;
+                let mut pdu = services()
+                    .rooms
+                    .timeline
+                    .get_pdu_from_id(&pduid)?
+                    .ok_or_else(|| {
+                        Error::bad_database(
+                            "Pdu in tofrom_relation is invalid.",
+                        )
                     })?;
-
-                    let mut pduid = shortroomid.to_be_bytes().to_vec();
-                    pduid.extend_from_slice(&from.to_be_bytes());
-
-                    let mut pdu = services()
-                        .rooms
-                        .timeline
-                        .get_pdu_from_id(&pduid)?
-                        .ok_or_else(|| {
-                            Error::bad_database(
-                                "Pdu in tofrom_relation is invalid.",
-                            )
-                        })?;
-                    if pdu.sender != user_id {
-                        pdu.remove_transaction_id()?;
-                    }
-                    Ok((PduCount::Normal(from), pdu))
-                }),
+                if pdu.sender != user_id {
+                    pdu.remove_transaction_id()?;
+                }
+                Ok((from, pdu))
+            }),
         ))
     }
 
@@ -100,3 +192,95 @@ impl service::rooms::pdu_metadata::Data for KeyValueDatabase {
         self.softfailedeventids.get(event_id.as_bytes()).map(|o| o.is_some())
     }
 }
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::{relation_key, relations_until_ids};
+    use crate::{
+        database::abstraction::sqlite::open_test_tree,
+        service::rooms::timeline::PduCount,
+    };
+
+    /// Pages through the relations of an event with both local and backfilled
+    /// replies using the tokens returned by each page, like a client would.
+    #[test]
+    fn paginate_relations_across_backfill_boundary() {
+        let dir = TempDir::new().unwrap();
+        let tree = open_test_tree(dir.path(), "tofrom_relation");
+
+        let target = PduCount::Normal(5);
+        // Most recent first, like they are returned
+        let replies = [
+            PduCount::Normal(30),
+            PduCount::Normal(20),
+            PduCount::Normal(10),
+            PduCount::Backfilled(15),
+            PduCount::Backfilled(25),
+            PduCount::Backfilled(35),
+        ];
+        for reply in replies {
+            tree.insert(&relation_key(reply, target), &[]).unwrap();
+        }
+        // Relations to other events
+        tree.insert(
+            &relation_key(PduCount::Normal(40), PduCount::Normal(6)),
+            &[],
+        )
+        .unwrap();
+        tree.insert(
+            &relation_key(PduCount::Backfilled(3), PduCount::Normal(4)),
+            &[],
+        )
+        .unwrap();
+
+        let page = |until: PduCount| {
+            relations_until_ids(&*tree, 1, target, until)
+                .take(2)
+                .map(|result| result.unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let mut token = PduCount::MAX.stringify();
+        let mut seen = Vec::new();
+        loop {
+            let chunk = page(PduCount::try_from_string(&token).unwrap());
+            let Some((last, _)) = chunk.last() else {
+                break;
+            };
+            token = last.stringify();
+            seen.extend(chunk);
+        }
+        assert_eq!(
+            seen.iter().map(|(count, _)| *count).collect::<Vec<_>>(),
+            replies
+        );
+
+        // PDU IDs are in the room the relations were looked up in
+        assert_eq!(
+            seen[0].1,
+            [1_u64.to_be_bytes(), 30_u64.to_be_bytes()].concat()
+        );
+        assert_eq!(
+            seen[3].1,
+            [
+                1_u64.to_be_bytes(),
+                0_u64.to_be_bytes(),
+                (u64::MAX - 15).to_be_bytes()
+            ]
+            .concat()
+        );
+
+        // Paging backwards again from a backfilled token continues where the
+        // previous page stopped
+        let chunk = page(
+            PduCount::try_from_string(&PduCount::Backfilled(15).stringify())
+                .unwrap(),
+        );
+        assert_eq!(
+            chunk.into_iter().map(|(count, _)| count).collect::<Vec<_>>(),
+            [PduCount::Backfilled(25), PduCount::Backfilled(35)]
+        );
+    }
+}
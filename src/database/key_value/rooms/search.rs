@@ -1,16 +1,64 @@
-use ruma::RoomId;
-
-use crate::{database::KeyValueDatabase, service, services, utils, Result};
-
-/// Splits a string into tokens used as keys in the search inverted index
-///
-/// This may be used to tokenize both message bodies (for indexing) or search
-/// queries (for querying).
-fn tokenize(body: &str) -> impl Iterator<Item = String> + '_ {
-    body.split_terminator(|c: char| !c.is_alphanumeric())
-        .filter(|s| !s.is_empty())
-        .filter(|word| word.len() <= 50)
-        .map(str::to_lowercase)
+use std::collections::{BTreeMap, BTreeSet};
+
+use ruma::{OwnedRoomId, RoomId};
+
+use crate::{
+    database::KeyValueDatabase,
+    service::{
+        self,
+        rooms::search::{tokenize, DirectoryFields},
+    },
+    services, utils, Error, Result,
+};
+
+/// Number of keys that are removed at once when removing a room from the index
+const REMOVAL_BATCH_SIZE: usize = 1000;
+
+fn token_key(shortroomid: u64, word: &str, pdu_id: &[u8]) -> Vec<u8> {
+    let mut key = shortroomid.to_be_bytes().to_vec();
+    key.extend_from_slice(word.as_bytes());
+    key.push(0xFF);
+    // TODO: currently we save the room id a second time here
+    key.extend_from_slice(pdu_id);
+    key
+}
+
+fn room_token_key(shortroomid: u64, word: &str) -> Vec<u8> {
+    let mut key = shortroomid.to_be_bytes().to_vec();
+    key.extend_from_slice(word.as_bytes());
+    key
+}
+
+fn directory_token_key(token: &str, room_id: &RoomId) -> Vec<u8> {
+    let mut key = token.as_bytes().to_vec();
+    key.push(0xFF);
+    key.extend_from_slice(room_id.as_bytes());
+    key
+}
+
+impl KeyValueDatabase {
+    /// Adds `documents` and `words` to the statistics of a room, which may be
+    /// negative for removed events
+    fn update_room_stats(
+        &self,
+        shortroomid: u64,
+        documents: i64,
+        words: i64,
+    ) -> Result<()> {
+        let key = shortroomid.to_be_bytes();
+        let (old_documents, old_words) =
+            service::rooms::search::Data::room_stats(self, shortroomid)?;
+
+        let mut value = old_documents
+            .saturating_add_signed(documents)
+            .to_be_bytes()
+            .to_vec();
+        value.extend_from_slice(
+            &old_words.saturating_add_signed(words).to_be_bytes(),
+        );
+
+        self.shortroomid_searchstats.insert(&key, &value)
+    }
 }
 
 impl service::rooms::search::Data for KeyValueDatabase {
@@ -21,16 +69,31 @@ impl service::rooms::search::Data for KeyValueDatabase {
         pdu_id: &[u8],
         message_body: &str,
     ) -> Result<()> {
-        let mut batch = tokenize(message_body).map(|word| {
-            let mut key = shortroomid.to_be_bytes().to_vec();
-            key.extend_from_slice(word.as_bytes());
-            key.push(0xFF);
-            // TODO: currently we save the room id a second time here
-            key.extend_from_slice(pdu_id);
-            (key, Vec::new())
+        if self.pduid_tokencount.get(pdu_id)?.is_some() {
+            return Ok(());
+        }
+
+        let mut frequencies = BTreeMap::<String, u32>::new();
+        let mut length = 0_u32;
+        for word in tokenize(message_body) {
+            *frequencies.entry(word).or_default() += 1;
+            length = length.saturating_add(1);
+        }
+
+        let mut batch = frequencies.iter().map(|(word, frequency)| {
+            (
+                token_key(shortroomid, word, pdu_id),
+                frequency.to_be_bytes().to_vec(),
+            )
         });
+        self.tokenids.insert_batch(&mut batch)?;
 
-        self.tokenids.insert_batch(&mut batch)
+        for word in frequencies.keys() {
+            self.roomtoken_pducount
+                .increment(&room_token_key(shortroomid, word))?;
+        }
+        self.pduid_tokencount.insert(pdu_id, &length.to_be_bytes())?;
+        self.update_room_stats(shortroomid, 1, length.into())
     }
 
     #[tracing::instrument(skip(self))]
@@ -40,20 +103,49 @@ impl service::rooms::search::Data for KeyValueDatabase {
         pdu_id: &[u8],
         message_body: &str,
     ) -> Result<()> {
-        let batch = tokenize(message_body).map(|word| {
-            let mut key = shortroomid.to_be_bytes().to_vec();
-            key.extend_from_slice(word.as_bytes());
-            key.push(0xFF);
-            // TODO: currently we save the room id a second time here
-            key.extend_from_slice(pdu_id);
-            key
-        });
+        let words: BTreeSet<_> = tokenize(message_body).collect();
 
-        for token in batch {
-            self.tokenids.remove(&token)?;
+        for word in &words {
+            self.tokenids.remove(&token_key(shortroomid, word, pdu_id))?;
         }
 
-        Ok(())
+        // Events indexed before statistics were kept aren't counted in them
+        let Some(length) = self.document_length(pdu_id)? else {
+            return Ok(());
+        };
+
+        for word in &words {
+            self.roomtoken_pducount
+                .decrement(&room_token_key(shortroomid, word))?;
+        }
+        self.pduid_tokencount.remove(pdu_id)?;
+        self.update_room_stats(shortroomid, -1, -i64::from(length))
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn deindex_room(&self, shortroomid: u64) -> Result<()> {
+        let prefix = shortroomid.to_be_bytes().to_vec();
+
+        for tree in
+            [&self.tokenids, &self.pduid_tokencount, &self.roomtoken_pducount]
+        {
+            loop {
+                let keys: Vec<_> = tree
+                    .scan_prefix(prefix.clone())
+                    .map(|(key, _)| key)
+                    .take(REMOVAL_BATCH_SIZE)
+                    .collect();
+                if keys.is_empty() {
+                    break;
+                }
+
+                for key in keys {
+                    tree.remove(&key)?;
+                }
+            }
+        }
+
+        self.shortroomid_searchstats.remove(&prefix)
     }
 
     #[tracing::instrument(skip(self))]
@@ -99,4 +191,169 @@ impl service::rooms::search::Data for KeyValueDatabase {
 
         Ok(Some((Box::new(common_elements), words)))
     }
+
+    fn term_frequency(
+        &self,
+        shortroomid: u64,
+        word: &str,
+        pdu_id: &[u8],
+    ) -> Result<u32> {
+        let Some(frequency) =
+            self.tokenids.get(&token_key(shortroomid, word, pdu_id))?
+        else {
+            return Ok(0);
+        };
+
+        // Events indexed before frequencies were kept have no value
+        if frequency.is_empty() {
+            return Ok(1);
+        }
+
+        frequency.try_into().map(u32::from_be_bytes).map_err(|_| {
+            Error::bad_database("Invalid term frequency in tokenids.")
+        })
+    }
+
+    fn document_length(&self, pdu_id: &[u8]) -> Result<Option<u32>> {
+        self.pduid_tokencount
+            .get(pdu_id)?
+            .map(|length| {
+                length.try_into().map(u32::from_be_bytes).map_err(|_| {
+                    Error::bad_database(
+                        "Invalid document length in pduid_tokencount.",
+                    )
+                })
+            })
+            .transpose()
+    }
+
+    fn document_frequency(&self, shortroomid: u64, word: &str) -> Result<u64> {
+        self.roomtoken_pducount.get(&room_token_key(shortroomid, word))?.map_or(
+            Ok(0),
+            |count| {
+                utils::u64_from_bytes(&count).map_err(|_| {
+                    Error::bad_database(
+                        "Invalid document frequency in roomtoken_pducount.",
+                    )
+                })
+            },
+        )
+    }
+
+    fn room_stats(&self, shortroomid: u64) -> Result<(u64, u64)> {
+        let Some(stats) =
+            self.shortroomid_searchstats.get(&shortroomid.to_be_bytes())?
+        else {
+            return Ok((0, 0));
+        };
+
+        let invalid =
+            || Error::bad_database("Invalid stats in shortroomid_searchstats.");
+        if stats.len() != 16 {
+            return Err(invalid());
+        }
+        let (documents, words) = stats.split_at(8);
+
+        Ok((
+            utils::u64_from_bytes(documents).map_err(|_| invalid())?,
+            utils::u64_from_bytes(words).map_err(|_| invalid())?,
+        ))
+    }
+
+    fn directory_fields(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<DirectoryFields>> {
+        let Some(fields) =
+            self.roomid_directoryfields.get(room_id.as_bytes())?
+        else {
+            return Ok(None);
+        };
+
+        let invalid =
+            || Error::bad_database("Invalid fields in roomid_directoryfields.");
+        let mut parts = fields
+            .split(|&b| b == 0xFF)
+            .map(|part| utils::string_from_bytes(part).map_err(|_| invalid()));
+        let (Some(name), Some(topic), Some(alias), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+
+        Ok(Some(DirectoryFields {
+            name: name?,
+            topic: topic?,
+            alias: alias?,
+        }))
+    }
+
+    #[tracing::instrument(skip(self, tokens))]
+    fn index_directory(
+        &self,
+        room_id: &RoomId,
+        fields: &DirectoryFields,
+        tokens: &BTreeMap<String, u32>,
+    ) -> Result<()> {
+        let mut batch = tokens.iter().map(|(token, weight)| {
+            (directory_token_key(token, room_id), weight.to_be_bytes().to_vec())
+        });
+        self.directorytoken_roomid.insert_batch(&mut batch)?;
+
+        let mut value = fields.name.as_bytes().to_vec();
+        value.push(0xFF);
+        value.extend_from_slice(fields.topic.as_bytes());
+        value.push(0xFF);
+        value.extend_from_slice(fields.alias.as_bytes());
+        self.roomid_directoryfields.insert(room_id.as_bytes(), &value)
+    }
+
+    #[tracing::instrument(skip(self, tokens))]
+    fn deindex_directory(
+        &self,
+        room_id: &RoomId,
+        tokens: &BTreeMap<String, u32>,
+    ) -> Result<()> {
+        for token in tokens.keys() {
+            self.directorytoken_roomid
+                .remove(&directory_token_key(token, room_id))?;
+        }
+
+        self.roomid_directoryfields.remove(room_id.as_bytes())
+    }
+
+    fn directory_tokens<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> Box<dyn Iterator<Item = Result<(String, OwnedRoomId, u32)>> + 'a> {
+        Box::new(
+            self.directorytoken_roomid
+                .scan_prefix(prefix.as_bytes().to_vec())
+                .map(|(key, weight)| {
+                    let invalid = || {
+                        Error::bad_database(
+                            "Invalid entry in directorytoken_roomid.",
+                        )
+                    };
+
+                    let (token, room_id) = key
+                        .iter()
+                        .position(|&b| b == 0xFF)
+                        .map(|i| (&key[..i], &key[i + 1..]))
+                        .ok_or_else(invalid)?;
+                    let token = utils::string_from_bytes(token)
+                        .map_err(|_| invalid())?;
+                    let room_id = utils::string_from_bytes(room_id)
+                        .ok()
+                        .and_then(|room_id| RoomId::parse(room_id).ok())
+                        .ok_or_else(invalid)?;
+                    let weight = weight
+                        .try_into()
+                        .map(u32::from_be_bytes)
+                        .map_err(|_| invalid())?;
+
+                    Ok((token, room_id, weight))
+                }),
+        )
+    }
 }
@@ -1,22 +1,36 @@
-use std::sync::Arc;
+use std::{collections::HashSet, mem::size_of, sync::Arc};
 
 use ruma::{events::StateEventType, EventId, RoomId};
 
 use crate::{
     database::KeyValueDatabase,
     observability::{FoundIn, Lookup, METRICS},
-    service, services, utils, Error, Result,
+    service::{
+        self,
+        rooms::state_compressor::{data::StateDiff, Data as _},
+    },
+    services, utils, Error, Result,
 };
 
+/// Keeps `short` from being removed by a running search for unreferenced short
+/// IDs, since it may be referenced once the caller is done
+fn pin_shortid(db: &KeyValueDatabase, short: u64) {
+    if let Some(pinned) = db.shortid_gc_pinned.lock().unwrap().as_mut() {
+        pinned.insert(short);
+    }
+}
+
 impl service::rooms::short::Data for KeyValueDatabase {
     #[tracing::instrument(skip(self))]
     fn get_or_create_shorteventid(&self, event_id: &EventId) -> Result<u64> {
         let lookup = Lookup::CreateEventIdToShort;
+        let _gc_guard = self.shortid_gc_lock.read().unwrap();
 
         if let Some(short) =
             self.eventidshort_cache.lock().unwrap().get_mut(event_id)
         {
             METRICS.record_lookup(lookup, FoundIn::Cache);
+            pin_shortid(self, *short);
             return Ok(*short);
         }
 
@@ -43,6 +57,7 @@ impl service::rooms::short::Data for KeyValueDatabase {
             .lock()
             .unwrap()
             .insert(event_id.to_owned(), short);
+        pin_shortid(self, short);
 
         Ok(short)
     }
@@ -54,6 +69,7 @@ impl service::rooms::short::Data for KeyValueDatabase {
         state_key: &str,
     ) -> Result<Option<u64>> {
         let lookup = Lookup::StateKeyToShort;
+        let _gc_guard = self.shortid_gc_lock.read().unwrap();
 
         if let Some(short) = self
             .statekeyshort_cache
@@ -62,6 +78,7 @@ impl service::rooms::short::Data for KeyValueDatabase {
             .get_mut(&(event_type.clone(), state_key.to_owned()))
         {
             METRICS.record_lookup(lookup, FoundIn::Cache);
+            pin_shortid(self, *short);
             return Ok(Some(*short));
         }
 
@@ -86,6 +103,7 @@ impl service::rooms::short::Data for KeyValueDatabase {
                 .lock()
                 .unwrap()
                 .insert((event_type.clone(), state_key.to_owned()), s);
+            pin_shortid(self, s);
         } else {
             METRICS.record_lookup(lookup, FoundIn::Nothing);
         }
@@ -100,6 +118,7 @@ impl service::rooms::short::Data for KeyValueDatabase {
         state_key: &str,
     ) -> Result<u64> {
         let lookup = Lookup::CreateStateKeyToShort;
+        let _gc_guard = self.shortid_gc_lock.read().unwrap();
 
         if let Some(short) = self
             .statekeyshort_cache
@@ -108,6 +127,7 @@ impl service::rooms::short::Data for KeyValueDatabase {
             .get_mut(&(event_type.clone(), state_key.to_owned()))
         {
             METRICS.record_lookup(lookup, FoundIn::Cache);
+            pin_shortid(self, *short);
             return Ok(*short);
         }
 
@@ -138,6 +158,7 @@ impl service::rooms::short::Data for KeyValueDatabase {
             .lock()
             .unwrap()
             .insert((event_type.clone(), state_key.to_owned()), short);
+        pin_shortid(self, short);
 
         Ok(short)
     }
@@ -291,4 +312,194 @@ impl service::rooms::short::Data for KeyValueDatabase {
             },
         )
     }
+
+    fn unreferenced_shortids(
+        &self,
+        max_shortid: u64,
+    ) -> Result<(Vec<u64>, Vec<u64>)> {
+        let mut referenced_events = HashSet::new();
+        let mut referenced_statekeys = HashSet::new();
+
+        for (shortstatehash, _) in self.shortstatehash_statediff.iter() {
+            let shortstatehash = utils::u64_from_bytes(&shortstatehash)
+                .map_err(|_| {
+                    Error::bad_database(
+                        "Invalid shortstatehash in shortstatehash_statediff.",
+                    )
+                })?;
+            let StateDiff {
+                added,
+                removed,
+                ..
+            } = self.get_statediff(shortstatehash)?;
+
+            for compressed_event in added.iter().chain(removed.iter()) {
+                let (shortstatekey, shorteventid) =
+                    compressed_event.split_at(size_of::<u64>());
+                referenced_statekeys.insert(
+                    utils::u64_from_bytes(shortstatekey)
+                        .expect("bytes have right length"),
+                );
+                referenced_events.insert(
+                    utils::u64_from_bytes(shorteventid)
+                        .expect("bytes have right length"),
+                );
+            }
+        }
+
+        for (shorteventid, auth_chain) in self.shorteventid_authchain.iter() {
+            referenced_events.extend(
+                shorteventid
+                    .chunks_exact(size_of::<u64>())
+                    .chain(auth_chain.chunks_exact(size_of::<u64>()))
+                    .map(|chunk| {
+                        utils::u64_from_bytes(chunk)
+                            .expect("byte length is correct")
+                    }),
+            );
+        }
+
+        for (shorteventid, _) in self.shorteventid_shortstatehash.iter() {
+            referenced_events.insert(
+                utils::u64_from_bytes(&shorteventid).map_err(|_| {
+                    Error::bad_database(
+                        "Invalid shorteventid in shorteventid_shortstatehash.",
+                    )
+                })?,
+            );
+        }
+
+        let mut shorteventids = Vec::new();
+        for (shorteventid, event_id) in self.shorteventid_eventid.iter() {
+            let shorteventid =
+                utils::u64_from_bytes(&shorteventid).map_err(|_| {
+                    Error::bad_database(
+                        "Invalid shorteventid in shorteventid_eventid.",
+                    )
+                })?;
+            if shorteventid <= max_shortid
+                && !referenced_events.contains(&shorteventid)
+                && self.eventid_pduid.get(&event_id)?.is_none()
+                && self.eventid_outlierpdu.get(&event_id)?.is_none()
+            {
+                shorteventids.push(shorteventid);
+            }
+        }
+
+        let mut shortstatekeys = Vec::new();
+        for (shortstatekey, _) in self.shortstatekey_statekey.iter() {
+            let shortstatekey =
+                utils::u64_from_bytes(&shortstatekey).map_err(|_| {
+                    Error::bad_database(
+                        "Invalid shortstatekey in shortstatekey_statekey.",
+                    )
+                })?;
+            if shortstatekey <= max_shortid
+                && !referenced_statekeys.contains(&shortstatekey)
+            {
+                shortstatekeys.push(shortstatekey);
+            }
+        }
+
+        Ok((shorteventids, shortstatekeys))
+    }
+
+    fn remove_unreferenced_shortids(
+        &self,
+        max_shortid: u64,
+    ) -> Result<(Vec<u64>, Vec<u64>)> {
+        *self.shortid_gc_pinned.lock().unwrap() = Some(HashSet::new());
+        let candidates = self.unreferenced_shortids(max_shortid);
+
+        // Blocks lookups, so that no short ID is handed out while it is
+        // removed
+        let _gc_guard = self.shortid_gc_lock.write().unwrap();
+        let pinned =
+            self.shortid_gc_pinned.lock().unwrap().take().unwrap_or_default();
+        let (shorteventids, shortstatekeys) = candidates?;
+
+        // Held while removing, so that lookups don't cache removed entries
+        let mut eventidshort_cache = self.eventidshort_cache.lock().unwrap();
+        let mut shorteventid_cache = self.shorteventid_cache.lock().unwrap();
+
+        let mut removed_shorteventids = Vec::new();
+        for shorteventid in shorteventids {
+            if pinned.contains(&shorteventid) {
+                continue;
+            }
+
+            let short_bytes = shorteventid.to_be_bytes();
+            let Some(event_id) = self.shorteventid_eventid.get(&short_bytes)?
+            else {
+                continue;
+            };
+
+            // The event may have been stored or gained state since the search
+            if self.eventid_pduid.get(&event_id)?.is_some()
+                || self.eventid_outlierpdu.get(&event_id)?.is_some()
+                || self.shorteventid_shortstatehash.get(&short_bytes)?.is_some()
+            {
+                continue;
+            }
+
+            if self.eventid_shorteventid.get(&event_id)?.as_deref()
+                == Some(&short_bytes[..])
+            {
+                self.eventid_shorteventid.remove(&event_id)?;
+            }
+            self.shorteventid_eventid.remove(&short_bytes)?;
+
+            shorteventid_cache.remove(&shorteventid);
+            // Invalid event IDs can't be in the cache
+            if let Some(event_id) = utils::string_from_bytes(&event_id)
+                .ok()
+                .and_then(|event_id| EventId::parse(event_id).ok())
+            {
+                eventidshort_cache.remove(&event_id);
+            }
+            removed_shorteventids.push(shorteventid);
+        }
+
+        let mut statekeyshort_cache = self.statekeyshort_cache.lock().unwrap();
+        let mut shortstatekey_cache = self.shortstatekey_cache.lock().unwrap();
+
+        let mut removed_shortstatekeys = Vec::new();
+        for shortstatekey in shortstatekeys {
+            if pinned.contains(&shortstatekey) {
+                continue;
+            }
+
+            let short_bytes = shortstatekey.to_be_bytes();
+            let Some(statekey) =
+                self.shortstatekey_statekey.get(&short_bytes)?
+            else {
+                continue;
+            };
+
+            if self.statekey_shortstatekey.get(&statekey)?.as_deref()
+                == Some(&short_bytes[..])
+            {
+                self.statekey_shortstatekey.remove(&statekey)?;
+            }
+            self.shortstatekey_statekey.remove(&short_bytes)?;
+
+            shortstatekey_cache.remove(&shortstatekey);
+            // Invalid state keys can't be in the cache
+            let mut parts = statekey.splitn(2, |&b| b == 0xFF);
+            let event_type = parts
+                .next()
+                .and_then(|bytes| utils::string_from_bytes(bytes).ok());
+            let state_key = parts
+                .next()
+                .and_then(|bytes| utils::string_from_bytes(bytes).ok());
+            if let (Some(event_type), Some(state_key)) = (event_type, state_key)
+            {
+                statekeyshort_cache
+                    .remove(&(StateEventType::from(event_type), state_key));
+            }
+            removed_shortstatekeys.push(shortstatekey);
+        }
+
+        Ok((removed_shorteventids, removed_shortstatekeys))
+    }
 }
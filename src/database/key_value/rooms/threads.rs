@@ -1,47 +1,56 @@
-use std::mem;
-
 use ruma::{
     api::client::threads::get_threads::v1::IncludeThreads, OwnedUserId, RoomId,
     UserId,
 };
 
+use super::timeline::{count_to_suffix, pdu_count};
 use crate::{
-    database::KeyValueDatabase, service, services, utils, Error, PduEvent,
-    Result,
+    database::{abstraction::KvTree, KeyValueDatabase},
+    service::{self, rooms::timeline::PduCount},
+    services, utils, Error, PduEvent, Result,
 };
 
+/// Returns the counts and PDU IDs of the thread roots in the room with
+/// `shortroomid`, most recent first, starting before `until`
+fn threads_until_ids(
+    threadid_userids: &dyn KvTree,
+    shortroomid: u64,
+    until: PduCount,
+) -> impl Iterator<Item = Result<(PduCount, Vec<u8>)>> + '_ {
+    let prefix = shortroomid.to_be_bytes().to_vec();
+    let mut current = prefix.clone();
+    current.extend_from_slice(&count_to_suffix(until.previous()));
+
+    threadid_userids
+        .iter_from(&current, true)
+        .take_while(move |(k, _)| k.starts_with(&prefix))
+        .map(|(pduid, _users)| {
+            let count = pdu_count(&pduid).map_err(|_| {
+                Error::bad_database("Invalid pduid in threadid_userids.")
+            })?;
+            Ok((count, pduid))
+        })
+}
+
 impl service::rooms::threads::Data for KeyValueDatabase {
     fn threads_until<'a>(
         &'a self,
         user_id: &'a UserId,
         room_id: &'a RoomId,
-        until: u64,
+        until: PduCount,
         _include: &'a IncludeThreads,
-    ) -> Result<Box<dyn Iterator<Item = Result<(u64, PduEvent)>> + 'a>> {
-        let prefix = services()
+    ) -> Result<Box<dyn Iterator<Item = Result<(PduCount, PduEvent)>> + 'a>>
+    {
+        let shortroomid = services()
             .rooms
             .short
             .get_shortroomid(room_id)?
-            .expect("room exists")
-            .to_be_bytes()
-            .to_vec();
-
-        let mut current = prefix.clone();
-        current.extend_from_slice(&(until - 1).to_be_bytes());
+            .expect("room exists");
 
         Ok(Box::new(
-            self.threadid_userids
-                .iter_from(&current, true)
-                .take_while(move |(k, _)| k.starts_with(&prefix))
-                .map(move |(pduid, _users)| {
-                    let count = utils::u64_from_bytes(
-                        &pduid[(mem::size_of::<u64>())..],
-                    )
-                    .map_err(|_| {
-                        Error::bad_database(
-                            "Invalid pduid in threadid_userids.",
-                        )
-                    })?;
+            threads_until_ids(&*self.threadid_userids, shortroomid, until).map(
+                move |result| {
+                    let (count, pduid) = result?;
                     let mut pdu = services()
                         .rooms
                         .timeline
@@ -55,7 +64,8 @@ impl service::rooms::threads::Data for KeyValueDatabase {
                         pdu.remove_transaction_id()?;
                     }
                     Ok((count, pdu))
-                }),
+                },
+            ),
         ))
     }
 
@@ -105,3 +115,64 @@ impl service::rooms::threads::Data for KeyValueDatabase {
         }
     }
 }
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::{count_to_suffix, threads_until_ids};
+    use crate::{
+        database::abstraction::sqlite::open_test_tree,
+        service::rooms::timeline::PduCount,
+    };
+
+    /// Pages through the threads of a room with both local and backfilled roots
+    /// using the tokens returned by each page, like a client would.
+    #[test]
+    fn paginate_threads_across_backfill_boundary() {
+        let dir = TempDir::new().unwrap();
+        let tree = open_test_tree(dir.path(), "threadid_userids");
+
+        let pduid = |shortroomid: u64, count| {
+            let mut pduid = shortroomid.to_be_bytes().to_vec();
+            pduid.extend_from_slice(&count_to_suffix(count));
+            pduid
+        };
+
+        // Most recent first, like they are returned
+        let roots = [
+            PduCount::Normal(30),
+            PduCount::Normal(20),
+            PduCount::Backfilled(15),
+            PduCount::Backfilled(25),
+            PduCount::Backfilled(35),
+        ];
+        for root in roots {
+            tree.insert(&pduid(1, root), b"@alice:example.com").unwrap();
+        }
+        // Threads of other rooms
+        tree.insert(&pduid(0, PduCount::Normal(40)), b"@bob:example.com")
+            .unwrap();
+        tree.insert(&pduid(2, PduCount::Backfilled(1)), b"@bob:example.com")
+            .unwrap();
+
+        let page = |until: PduCount| {
+            threads_until_ids(&*tree, 1, until)
+                .take(2)
+                .map(|result| result.unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let mut token = PduCount::MAX.stringify();
+        let mut seen = Vec::new();
+        loop {
+            let chunk = page(PduCount::try_from_string(&token).unwrap());
+            let Some((last, _)) = chunk.last() else {
+                break;
+            };
+            token = last.stringify();
+            seen.extend(chunk);
+        }
+        assert_eq!(seen, roots.map(|root| (root, pduid(1, root))).to_vec());
+    }
+}
@@ -1,4 +1,9 @@
-use std::{collections::hash_map, mem::size_of, sync::Arc};
+use std::{
+    collections::hash_map,
+    mem::size_of,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
 
 use ruma::{
     api::client::error::ErrorKind, CanonicalJsonObject, EventId, OwnedUserId,
@@ -9,7 +14,7 @@ use tracing::error;
 
 use crate::{
     database::KeyValueDatabase,
-    observability::{FoundIn, Lookup, METRICS},
+    observability::{EvictionReason, FoundIn, Lookup, METRICS},
     service, services, utils, Error, PduEvent, Result,
 };
 
@@ -103,6 +108,10 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
 
     /// Returns the pdu's id.
     fn get_pdu_id(&self, event_id: &EventId) -> Result<Option<Vec<u8>>> {
+        if check_missing(self, event_id).is_none() {
+            return Ok(None);
+        }
+
         self.eventid_pduid.get(event_id.as_bytes())
     }
 
@@ -138,6 +147,10 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
             return Ok(Some(Arc::clone(p)));
         }
 
+        let Some(generation) = check_missing(self, event_id) else {
+            return Ok(None);
+        };
+
         if let Some(pdu) = self
             .get_non_outlier_pdu(event_id)?
             .map_or_else(
@@ -163,6 +176,7 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
             Ok(Some(pdu))
         } else {
             METRICS.record_lookup(lookup, FoundIn::Nothing);
+            remember_missing(self, event_id, generation);
             Ok(None)
         }
     }
@@ -212,6 +226,7 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
 
         self.eventid_pduid.insert(pdu.event_id.as_bytes(), pdu_id)?;
         self.eventid_outlierpdu.remove(pdu.event_id.as_bytes())?;
+        forget_missing(self, &pdu.event_id);
 
         Ok(())
     }
@@ -230,6 +245,7 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
 
         self.eventid_pduid.insert(event_id.as_bytes(), pdu_id)?;
         self.eventid_outlierpdu.remove(event_id.as_bytes())?;
+        forget_missing(self, event_id);
 
         Ok(())
     }
@@ -325,34 +341,48 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
     fn increment_notification_counts(
         &self,
         room_id: &RoomId,
+        thread_root: Option<&EventId>,
         notifies: Vec<OwnedUserId>,
         highlights: Vec<OwnedUserId>,
     ) -> Result<()> {
-        let mut notifies_batch = Vec::new();
-        let mut highlights_batch = Vec::new();
-        for user in notifies {
+        let userroom_id = |user: &UserId| {
             let mut userroom_id = user.as_bytes().to_vec();
             userroom_id.push(0xFF);
             userroom_id.extend_from_slice(room_id.as_bytes());
-            notifies_batch.push(userroom_id);
-        }
-        for user in highlights {
-            let mut userroom_id = user.as_bytes().to_vec();
-            userroom_id.push(0xFF);
-            userroom_id.extend_from_slice(room_id.as_bytes());
-            highlights_batch.push(userroom_id);
+            userroom_id
+        };
+        let userroomthread_id = |user: &UserId, thread_root: &EventId| {
+            let mut userroomthread_id = userroom_id(user);
+            userroomthread_id.push(0xFF);
+            userroomthread_id.extend_from_slice(thread_root.as_bytes());
+            userroomthread_id
+        };
+
+        if let Some(thread_root) = thread_root {
+            self.userroomthreadid_notificationcount.increment_batch(
+                &mut notifies
+                    .iter()
+                    .map(|user| userroomthread_id(user, thread_root)),
+            )?;
+            self.userroomthreadid_highlightcount.increment_batch(
+                &mut highlights
+                    .iter()
+                    .map(|user| userroomthread_id(user, thread_root)),
+            )?;
         }
 
-        self.userroomid_notificationcount
-            .increment_batch(&mut notifies_batch.into_iter())?;
-        self.userroomid_highlightcount
-            .increment_batch(&mut highlights_batch.into_iter())?;
+        self.userroomid_notificationcount.increment_batch(
+            &mut notifies.iter().map(|user| userroom_id(user)),
+        )?;
+        self.userroomid_highlightcount.increment_batch(
+            &mut highlights.iter().map(|user| userroom_id(user)),
+        )?;
         Ok(())
     }
 }
 
 /// Returns the `count` of this pdu's id.
-fn pdu_count(pdu_id: &[u8]) -> Result<PduCount> {
+pub(super) fn pdu_count(pdu_id: &[u8]) -> Result<PduCount> {
     let last_u64 =
         utils::u64_from_bytes(&pdu_id[pdu_id.len() - size_of::<u64>()..])
             .map_err(|_| Error::bad_database("PDU has invalid count bytes."))?;
@@ -368,6 +398,18 @@ fn pdu_count(pdu_id: &[u8]) -> Result<PduCount> {
     }
 }
 
+/// Returns the part of a PDU ID after the `shortroomid` for a count
+pub(super) fn count_to_suffix(count: PduCount) -> Vec<u8> {
+    match count {
+        PduCount::Normal(x) => x.to_be_bytes().to_vec(),
+        PduCount::Backfilled(x) => {
+            let mut suffix = 0_u64.to_be_bytes().to_vec();
+            suffix.extend_from_slice(&(u64::MAX - x).to_be_bytes());
+            suffix
+        }
+    }
+}
+
 fn count_to_id(
     room_id: &RoomId,
     count: PduCount,
@@ -411,3 +453,75 @@ fn count_to_id(
 
     Ok((prefix, pdu_id))
 }
+
+/// Returns `None` if `event_id` was recently looked up and found neither in
+/// the timeline nor in the outliers.
+///
+/// Otherwise, returns the generation of stored events to pass to
+/// [`remember_missing`] if the event isn't found in the database either.
+fn check_missing(db: &KeyValueDatabase, event_id: &EventId) -> Option<u64> {
+    let lookup = Lookup::MissingPdu;
+    let mut cache = db.missing_pdu_cache.lock().unwrap();
+    let generation = db.stored_pdu_generation.load(Ordering::Relaxed);
+
+    let Some(missing_since) = cache.get_mut(event_id) else {
+        return Some(generation);
+    };
+
+    if missing_since.elapsed() >= missing_pdu_ttl() {
+        cache.remove(event_id);
+        METRICS.record_cache_eviction(lookup, EvictionReason::Expired);
+        return Some(generation);
+    }
+
+    METRICS.record_lookup(lookup, FoundIn::Cache);
+    None
+}
+
+/// Remembers that `event_id` is missing locally so that repeated lookups
+/// don't hit the database
+///
+/// Nothing is remembered if any event was stored since `generation` was
+/// returned by [`check_missing`], since it may have been this one.
+fn remember_missing(
+    db: &KeyValueDatabase,
+    event_id: &EventId,
+    generation: u64,
+) {
+    let lookup = Lookup::MissingPdu;
+    let mut cache = db.missing_pdu_cache.lock().unwrap();
+
+    if db.stored_pdu_generation.load(Ordering::Relaxed) != generation {
+        return;
+    }
+
+    if !cache.contains_key(event_id) && cache.len() >= cache.capacity() {
+        METRICS.record_cache_eviction(lookup, EvictionReason::Capacity);
+    }
+
+    METRICS.record_lookup(lookup, FoundIn::Nothing);
+    cache.insert(event_id.to_owned(), Instant::now());
+}
+
+/// Forgets that `event_id` was missing, must be called whenever it is
+/// added to the timeline or the outliers
+pub(super) fn forget_missing(db: &KeyValueDatabase, event_id: &EventId) {
+    let mut cache = db.missing_pdu_cache.lock().unwrap();
+    db.stored_pdu_generation.fetch_add(1, Ordering::Relaxed);
+    if cache.remove(event_id).is_some() {
+        METRICS.record_cache_eviction(
+            Lookup::MissingPdu,
+            EvictionReason::Invalidated,
+        );
+    }
+}
+
+/// How long event IDs are remembered as missing
+fn missing_pdu_ttl() -> Duration {
+    services()
+        .globals
+        .config
+        .cache
+        .missing_pdu_ttl
+        .unwrap_or(Duration::from_secs(60))
+}
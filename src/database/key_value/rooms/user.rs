@@ -1,14 +1,35 @@
-use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+use std::collections::BTreeMap;
+
+use ruma::{EventId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId};
 
 use crate::{
-    database::KeyValueDatabase, service, services, utils, Error, Result,
+    database::{abstraction::KvTree, KeyValueDatabase},
+    service, services, utils, Error, Result,
 };
 
-impl service::rooms::user::Data for KeyValueDatabase {
-    fn reset_notification_counts(
+fn userroomthread_prefix(user_id: &UserId, room_id: &RoomId) -> Vec<u8> {
+    let mut prefix = user_id.as_bytes().to_vec();
+    prefix.push(0xFF);
+    prefix.extend_from_slice(room_id.as_bytes());
+    prefix.push(0xFF);
+    prefix
+}
+
+fn count_from_bytes(bytes: &[u8]) -> Result<u64> {
+    utils::u64_from_bytes(bytes).map_err(|_| {
+        Error::bad_database("Invalid thread notification count in db.")
+    })
+}
+
+impl KeyValueDatabase {
+    /// Sets the notification counts of a room, remembering when they were
+    /// read
+    fn set_notification_counts(
         &self,
         user_id: &UserId,
         room_id: &RoomId,
+        notifications: u64,
+        highlights: u64,
     ) -> Result<()> {
         let mut userroom_id = user_id.as_bytes().to_vec();
         userroom_id.push(0xFF);
@@ -18,9 +39,9 @@ impl service::rooms::user::Data for KeyValueDatabase {
         roomuser_id.extend_from_slice(user_id.as_bytes());
 
         self.userroomid_notificationcount
-            .insert(&userroom_id, &0_u64.to_be_bytes())?;
+            .insert(&userroom_id, &notifications.to_be_bytes())?;
         self.userroomid_highlightcount
-            .insert(&userroom_id, &0_u64.to_be_bytes())?;
+            .insert(&userroom_id, &highlights.to_be_bytes())?;
 
         self.roomuserid_lastnotificationread.insert(
             &roomuser_id,
@@ -30,6 +51,121 @@ impl service::rooms::user::Data for KeyValueDatabase {
         Ok(())
     }
 
+    /// Removes the counts of all threads of a room
+    fn remove_thread_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<()> {
+        let prefix = userroomthread_prefix(user_id, room_id);
+
+        for tree in [
+            &self.userroomthreadid_notificationcount,
+            &self.userroomthreadid_highlightcount,
+        ] {
+            let keys: Vec<_> =
+                tree.scan_prefix(prefix.clone()).map(|(key, _)| key).collect();
+            for key in keys {
+                tree.remove(&key)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl service::rooms::user::Data for KeyValueDatabase {
+    fn reset_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<()> {
+        self.remove_thread_notification_counts(user_id, room_id)?;
+        self.set_notification_counts(user_id, room_id, 0, 0)
+    }
+
+    fn reset_main_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<()> {
+        let (notifications, highlights) = self
+            .thread_notification_counts(user_id, room_id)?
+            .into_values()
+            .fold((0_u64, 0_u64), |(n, h), (thread_n, thread_h)| {
+                (n.saturating_add(thread_n), h.saturating_add(thread_h))
+            });
+
+        self.set_notification_counts(
+            user_id,
+            room_id,
+            notifications,
+            highlights,
+        )
+    }
+
+    fn reset_thread_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        thread_root: &EventId,
+    ) -> Result<()> {
+        let mut key = userroomthread_prefix(user_id, room_id);
+        key.extend_from_slice(thread_root.as_bytes());
+
+        let take = |tree: &dyn KvTree| {
+            let count = tree
+                .get(&key)?
+                .map_or(Ok(0), |bytes| count_from_bytes(&bytes))?;
+            tree.remove(&key)?;
+            Ok::<_, Error>(count)
+        };
+        let thread_notifications =
+            take(&*self.userroomthreadid_notificationcount)?;
+        let thread_highlights = take(&*self.userroomthreadid_highlightcount)?;
+
+        self.set_notification_counts(
+            user_id,
+            room_id,
+            self.notification_count(user_id, room_id)?
+                .saturating_sub(thread_notifications),
+            self.highlight_count(user_id, room_id)?
+                .saturating_sub(thread_highlights),
+        )
+    }
+
+    fn thread_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<BTreeMap<OwnedEventId, (u64, u64)>> {
+        let prefix = userroomthread_prefix(user_id, room_id);
+        let thread_root = |key: &[u8]| {
+            utils::string_from_bytes(&key[prefix.len()..])
+                .ok()
+                .and_then(|thread_root| EventId::parse(thread_root).ok())
+                .ok_or_else(|| {
+                    Error::bad_database("Invalid thread root in db.")
+                })
+        };
+
+        let mut counts = BTreeMap::<_, (u64, u64)>::new();
+        for (key, value) in
+            self.userroomthreadid_notificationcount.scan_prefix(prefix.clone())
+        {
+            counts.entry(thread_root(&key)?).or_default().0 =
+                count_from_bytes(&value)?;
+        }
+        for (key, value) in
+            self.userroomthreadid_highlightcount.scan_prefix(prefix.clone())
+        {
+            counts.entry(thread_root(&key)?).or_default().1 =
+                count_from_bytes(&value)?;
+        }
+
+        Ok(counts)
+    }
+
     fn notification_count(
         &self,
         user_id: &UserId,
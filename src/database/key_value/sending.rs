@@ -1,10 +1,15 @@
-use ruma::{ServerName, UserId};
+use std::{
+    mem::size_of,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use ruma::{OwnedServerName, ServerName, UserId};
 
 use crate::{
     database::KeyValueDatabase,
     service::{
         self,
-        sending::{Destination, RequestKey, SendingEventType},
+        sending::{Backoff, Destination, RequestKey, SendingEventType},
     },
     services, utils, Error, Result,
 };
@@ -129,6 +134,68 @@ impl service::sending::Data for KeyValueDatabase {
             },
         )
     }
+
+    fn set_backoff(
+        &self,
+        server_name: &ServerName,
+        backoff: Backoff,
+    ) -> Result<()> {
+        let next_retry = backoff
+            .next_retry
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis()
+            .try_into()
+            .unwrap_or(u64::MAX);
+
+        let mut value = u64::from(backoff.failures).to_be_bytes().to_vec();
+        value.extend_from_slice(&next_retry.to_be_bytes());
+
+        self.servername_backoff.insert(server_name.as_bytes(), &value)
+    }
+
+    fn remove_backoff(&self, server_name: &ServerName) -> Result<()> {
+        self.servername_backoff.remove(server_name.as_bytes())
+    }
+
+    fn all_backoffs<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedServerName, Backoff)>> + 'a> {
+        Box::new(self.servername_backoff.iter().map(|(key, value)| {
+            let server_name = utils::string_from_bytes(&key)
+                .ok()
+                .and_then(|s| OwnedServerName::try_from(s).ok())
+                .ok_or_else(|| {
+                    Error::bad_database(
+                        "Invalid server name in servername_backoff.",
+                    )
+                })?;
+
+            if value.len() != 2 * size_of::<u64>() {
+                return Err(Error::bad_database(
+                    "Invalid value in servername_backoff.",
+                ));
+            }
+            let (failures, next_retry) = value.split_at(size_of::<u64>());
+            let failures = utils::u64_from_bytes(failures).map_err(|_| {
+                Error::bad_database("Invalid failures in servername_backoff.")
+            })?;
+            let next_retry =
+                utils::u64_from_bytes(next_retry).map_err(|_| {
+                    Error::bad_database(
+                        "Invalid timestamp in servername_backoff.",
+                    )
+                })?;
+
+            Ok((
+                server_name,
+                Backoff {
+                    failures: failures.try_into().unwrap_or(u32::MAX),
+                    next_retry: UNIX_EPOCH + Duration::from_millis(next_retry),
+                },
+            ))
+        }))
+    }
 }
 
 #[tracing::instrument(skip(key, value))]
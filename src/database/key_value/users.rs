@@ -1,17 +1,22 @@
 use std::{collections::BTreeMap, mem::size_of};
 
 use ruma::{
-    api::client::{device::Device, error::ErrorKind, filter::FilterDefinition},
+    api::client::{
+        dehydrated_device::DehydratedDeviceData, device::Device,
+        error::ErrorKind, filter::FilterDefinition,
+    },
     encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
     events::{AnyToDeviceEvent, StateEventType},
     serde::Raw,
+    thirdparty::{Medium, ThirdPartyIdentifier},
     DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch,
-    OwnedDeviceId, OwnedDeviceKeyId, OwnedMxcUri, OwnedUserId, UInt, UserId,
+    OwnedDeviceId, OwnedDeviceKeyId, OwnedMxcUri, OwnedRoomId, OwnedUserId,
+    RoomId, UInt, UserId,
 };
 use tracing::warn;
 
 use crate::{
-    database::KeyValueDatabase,
+    database::{abstraction::KvTree, KeyValueDatabase},
     service::{self, users::clean_signatures},
     services, utils, Error, Result,
 };
@@ -212,6 +217,39 @@ impl service::users::Data for KeyValueDatabase {
         Ok(())
     }
 
+    fn server_notices_room(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<OwnedRoomId>> {
+        self.userid_servernoticesroomid
+            .get(user_id.as_bytes())?
+            .map(|bytes| {
+                RoomId::parse(utils::string_from_bytes(&bytes).map_err(
+                    |_| {
+                        Error::bad_database(
+                            "Room ID in userid_servernoticesroomid is invalid \
+                             unicode.",
+                        )
+                    },
+                )?)
+                .map_err(|_| {
+                    Error::bad_database(
+                        "Room ID in userid_servernoticesroomid is invalid.",
+                    )
+                })
+            })
+            .transpose()
+    }
+
+    fn set_server_notices_room(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<()> {
+        self.userid_servernoticesroomid
+            .insert(user_id.as_bytes(), room_id.as_bytes())
+    }
+
     /// Get the `blurhash` of a user.
     fn blurhash(&self, user_id: &UserId) -> Result<Option<String>> {
         self.userid_blurhash
@@ -247,7 +285,7 @@ impl service::users::Data for KeyValueDatabase {
         &self,
         user_id: &UserId,
         device_id: &DeviceId,
-        token: &str,
+        token: Option<&str>,
         initial_device_display_name: Option<String>,
     ) -> Result<()> {
         assert!(
@@ -273,7 +311,9 @@ impl service::users::Data for KeyValueDatabase {
             .expect("Device::to_string never fails."),
         )?;
 
-        self.set_token(user_id, device_id, token)?;
+        if let Some(token) = token {
+            self.set_token(user_id, device_id, token)?;
+        }
 
         Ok(())
     }
@@ -293,17 +333,23 @@ impl service::users::Data for KeyValueDatabase {
             self.userdeviceid_token.remove(&userdeviceid)?;
             self.token_userdeviceid.remove(&old_token)?;
         }
+        remove_refresh_token(self, &userdeviceid)?;
 
         // Remove todevice events
         let mut prefix = userdeviceid.clone();
         prefix.push(0xFF);
 
-        for (key, _) in self.todeviceid_events.scan_prefix(prefix) {
+        for (key, _) in self.todeviceid_events.scan_prefix(prefix.clone()) {
             self.todeviceid_events.remove(&key)?;
         }
 
         // TODO: Remove onetimekeys
 
+        // Remove fallback keys
+        for (key, _) in self.fallbackkeyid_fallbackkey.scan_prefix(prefix) {
+            self.fallbackkeyid_fallbackkey.remove(&key)?;
+        }
+
         self.userid_devicelistversion.increment(user_id.as_bytes())?;
 
         self.userdeviceid_metadata.remove(&userdeviceid)?;
@@ -362,9 +408,152 @@ impl service::users::Data for KeyValueDatabase {
         self.userdeviceid_token.insert(&userdeviceid, token.as_bytes())?;
         self.token_userdeviceid.insert(token.as_bytes(), &userdeviceid)?;
 
+        // The new token doesn't expire unless a new refresh token is set
+        remove_refresh_token(self, &userdeviceid)?;
+
+        Ok(())
+    }
+
+    fn set_refresh_token(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        refresh_token: &str,
+        expires_at: u64,
+    ) -> Result<()> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xFF);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        if let Some(old_token) =
+            self.userdeviceid_refreshtoken.get(&userdeviceid)?
+        {
+            self.refreshtoken_userdeviceid.remove(&old_token)?;
+        }
+
+        self.userdeviceid_refreshtoken
+            .insert(&userdeviceid, refresh_token.as_bytes())?;
+        self.refreshtoken_userdeviceid
+            .insert(refresh_token.as_bytes(), &userdeviceid)?;
+        self.userdeviceid_tokenexpiresat
+            .insert(&userdeviceid, &expires_at.to_be_bytes())?;
+
+        Ok(())
+    }
+
+    fn rotate_tokens(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        used_refresh_token: &str,
+        access_token: &str,
+        refresh_token: &str,
+        expires_at: u64,
+    ) -> Result<()> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xFF);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        if let Some(old_token) = self.userdeviceid_token.get(&userdeviceid)? {
+            self.token_userdeviceid.remove(&old_token)?;
+        }
+        self.userdeviceid_token
+            .insert(&userdeviceid, access_token.as_bytes())?;
+        self.token_userdeviceid
+            .insert(access_token.as_bytes(), &userdeviceid)?;
+
+        let current = self.userdeviceid_refreshtoken.get(&userdeviceid)?;
+        if current.as_deref() == Some(used_refresh_token.as_bytes()) {
+            // The current refresh token becomes the previous one, which
+            // replaces any older one
+            if let Some(previous) =
+                self.userdeviceid_prevrefreshtoken.get(&userdeviceid)?
+            {
+                self.refreshtoken_userdeviceid.remove(&previous)?;
+            }
+            self.userdeviceid_prevrefreshtoken
+                .insert(&userdeviceid, used_refresh_token.as_bytes())?;
+        } else if let Some(current) = current {
+            // The previous refresh token was used again, so the client never
+            // received the current one
+            self.refreshtoken_userdeviceid.remove(&current)?;
+        }
+
+        self.userdeviceid_refreshtoken
+            .insert(&userdeviceid, refresh_token.as_bytes())?;
+        self.refreshtoken_userdeviceid
+            .insert(refresh_token.as_bytes(), &userdeviceid)?;
+        self.userdeviceid_tokenexpiresat
+            .insert(&userdeviceid, &expires_at.to_be_bytes())?;
+
         Ok(())
     }
 
+    fn remove_previous_refresh_token(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<()> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xFF);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        remove_previous_refresh_token(self, &userdeviceid)
+    }
+
+    fn find_from_refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<(OwnedUserId, OwnedDeviceId)>> {
+        let Some(bytes) =
+            self.refreshtoken_userdeviceid.get(refresh_token.as_bytes())?
+        else {
+            return Ok(None);
+        };
+
+        let mut parts = bytes.split(|&b| b == 0xFF);
+        let user_id = parts
+            .next()
+            .and_then(|bytes| utils::string_from_bytes(bytes).ok())
+            .and_then(|user_id| UserId::parse(user_id).ok())
+            .ok_or_else(|| {
+                Error::bad_database(
+                    "User ID in refreshtoken_userdeviceid is invalid.",
+                )
+            })?;
+        let device_id = parts
+            .next()
+            .and_then(|bytes| utils::string_from_bytes(bytes).ok())
+            .ok_or_else(|| {
+                Error::bad_database(
+                    "Device ID in refreshtoken_userdeviceid is invalid.",
+                )
+            })?;
+
+        Ok(Some((user_id, device_id.into())))
+    }
+
+    fn token_expires_at(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<u64>> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xFF);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        self.userdeviceid_tokenexpiresat
+            .get(&userdeviceid)?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database(
+                        "Invalid expiration in userdeviceid_tokenexpiresat.",
+                    )
+                })
+            })
+            .transpose()
+    }
+
     fn add_one_time_key(
         &self,
         user_id: &UserId,
@@ -489,6 +678,104 @@ impl service::users::Data for KeyValueDatabase {
         Ok(counts)
     }
 
+    fn add_fallback_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        fallback_key_key: &DeviceKeyId,
+        fallback_key_value: &Raw<OneTimeKey>,
+    ) -> Result<()> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xFF);
+        key.extend_from_slice(device_id.as_bytes());
+
+        assert!(
+            self.userdeviceid_metadata.get(&key)?.is_some(),
+            "devices should have metadata and this method should only be \
+             called with existing devices"
+        );
+
+        key.push(0xFF);
+        key.extend_from_slice(fallback_key_key.algorithm().as_ref().as_bytes());
+
+        // A newly uploaded fallback key always starts out unused
+        let mut value = vec![0];
+        value.extend_from_slice(
+            &serde_json::to_vec(&(fallback_key_key, fallback_key_value))
+                .expect("fallback key can be serialized"),
+        );
+
+        self.fallbackkeyid_fallbackkey.insert(&key, &value)?;
+
+        self.userid_lastonetimekeyupdate.insert(
+            user_id.as_bytes(),
+            &services().globals.next_count()?.to_be_bytes(),
+        )?;
+
+        Ok(())
+    }
+
+    fn take_fallback_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        key_algorithm: &DeviceKeyAlgorithm,
+    ) -> Result<Option<(OwnedDeviceKeyId, Raw<OneTimeKey>)>> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xFF);
+        key.extend_from_slice(device_id.as_bytes());
+        key.push(0xFF);
+        key.extend_from_slice(key_algorithm.as_ref().as_bytes());
+
+        let Some(mut value) = self.fallbackkeyid_fallbackkey.get(&key)? else {
+            return Ok(None);
+        };
+
+        let fallback_key = value
+            .get(1..)
+            .and_then(|bytes| serde_json::from_slice(bytes).ok())
+            .ok_or_else(|| {
+                Error::bad_database("Fallback key in db is invalid.")
+            })?;
+
+        if value[0] == 0 {
+            value[0] = 1;
+            self.fallbackkeyid_fallbackkey.insert(&key, &value)?;
+
+            self.userid_lastonetimekeyupdate.insert(
+                user_id.as_bytes(),
+                &services().globals.next_count()?.to_be_bytes(),
+            )?;
+        }
+
+        Ok(Some(fallback_key))
+    }
+
+    fn unused_fallback_key_types(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Vec<DeviceKeyAlgorithm>> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xFF);
+        prefix.extend_from_slice(device_id.as_bytes());
+        prefix.push(0xFF);
+
+        self.fallbackkeyid_fallbackkey
+            .scan_prefix(prefix.clone())
+            .filter(|(_, value)| value.first() == Some(&0))
+            .map(|(key, _)| {
+                utils::string_from_bytes(&key[prefix.len()..])
+                    .map(DeviceKeyAlgorithm::from)
+                    .map_err(|_| {
+                        Error::bad_database(
+                            "Fallback key algorithm in db is invalid.",
+                        )
+                    })
+            })
+            .collect()
+    }
+
     #[tracing::instrument(skip(self, device_keys))]
     fn add_device_keys(
         &self,
@@ -669,7 +956,18 @@ impl service::users::Data for KeyValueDatabase {
                 .expect("CrossSigningKey::to_vec always works"),
         )?;
 
-        self.mark_device_key_update(target_id)?;
+        if sender_id == target_id {
+            self.mark_device_key_update(target_id)?;
+        } else {
+            // Signatures of other users' keys are only shown to the signer, so
+            // only their devices are told that the keys changed
+            let mut key = sender_id.as_bytes().to_vec();
+            key.push(0xFF);
+            key.extend_from_slice(
+                &services().globals.next_count()?.to_be_bytes(),
+            );
+            self.keychangeid_userid.insert(&key, target_id.as_bytes())?;
+        }
 
         Ok(())
     }
@@ -765,6 +1063,29 @@ impl service::users::Data for KeyValueDatabase {
         Ok(())
     }
 
+    fn next_device_list_stream_id(&self, user_id: &UserId) -> Result<u64> {
+        let stream_id =
+            self.userid_devicelistupdateid.increment(user_id.as_bytes())?;
+        utils::u64_from_bytes(&stream_id).map_err(|_| {
+            Error::bad_database(
+                "Invalid stream ID in userid_devicelistupdateid.",
+            )
+        })
+    }
+
+    fn device_list_stream_id(&self, user_id: &UserId) -> Result<Option<u64>> {
+        self.userid_devicelistupdateid
+            .get(user_id.as_bytes())?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database(
+                        "Invalid stream ID in userid_devicelistupdateid.",
+                    )
+                })
+            })
+            .transpose()
+    }
+
     fn get_device_keys(
         &self,
         user_id: &UserId,
@@ -886,23 +1207,15 @@ impl service::users::Data for KeyValueDatabase {
         event_type: &str,
         content: serde_json::Value,
     ) -> Result<()> {
-        let mut key = target_user_id.as_bytes().to_vec();
-        key.push(0xFF);
-        key.extend_from_slice(target_device_id.as_bytes());
-        key.push(0xFF);
-        key.extend_from_slice(&services().globals.next_count()?.to_be_bytes());
-
-        let mut json = serde_json::Map::new();
-        json.insert("type".to_owned(), event_type.to_owned().into());
-        json.insert("sender".to_owned(), sender.to_string().into());
-        json.insert("content".to_owned(), content);
-
-        let value =
-            serde_json::to_vec(&json).expect("Map::to_vec always works");
-
-        self.todeviceid_events.insert(&key, &value)?;
-
-        Ok(())
+        add_to_device_event(
+            &*self.todeviceid_events,
+            services().globals.next_count()?,
+            sender,
+            target_user_id,
+            target_device_id,
+            event_type,
+            content,
+        )
     }
 
     fn get_to_device_events(
@@ -910,20 +1223,7 @@ impl service::users::Data for KeyValueDatabase {
         user_id: &UserId,
         device_id: &DeviceId,
     ) -> Result<Vec<Raw<AnyToDeviceEvent>>> {
-        let mut events = Vec::new();
-
-        let mut prefix = user_id.as_bytes().to_vec();
-        prefix.push(0xFF);
-        prefix.extend_from_slice(device_id.as_bytes());
-        prefix.push(0xFF);
-
-        for (_, value) in self.todeviceid_events.scan_prefix(prefix) {
-            events.push(serde_json::from_slice(&value).map_err(|_| {
-                Error::bad_database("Event in todeviceid_events is invalid.")
-            })?);
-        }
-
-        Ok(events)
+        get_to_device_events(&*self.todeviceid_events, user_id, device_id)
     }
 
     fn remove_to_device_events(
@@ -932,39 +1232,58 @@ impl service::users::Data for KeyValueDatabase {
         device_id: &DeviceId,
         until: u64,
     ) -> Result<()> {
-        let mut prefix = user_id.as_bytes().to_vec();
-        prefix.push(0xFF);
-        prefix.extend_from_slice(device_id.as_bytes());
-        prefix.push(0xFF);
+        remove_to_device_events(
+            &*self.todeviceid_events,
+            user_id,
+            device_id,
+            until,
+        )
+    }
 
-        let mut last = prefix.clone();
-        last.extend_from_slice(&until.to_be_bytes());
+    fn dehydrated_device(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<(OwnedDeviceId, Raw<DehydratedDeviceData>)>> {
+        let Some(bytes) =
+            self.userid_dehydrateddevice.get(user_id.as_bytes())?
+        else {
+            return Ok(None);
+        };
+
+        let mut parts = bytes.splitn(2, |&b| b == 0xFF);
+        let device_id = utils::string_from_bytes(
+            parts.next().expect("splitn always returns one element"),
+        )
+        .map_err(|_| {
+            Error::bad_database("Dehydrated device ID is invalid unicode.")
+        })?
+        .into();
+        let device_data =
+            serde_json::from_slice(parts.next().ok_or_else(|| {
+                Error::bad_database("Dehydrated device has no data.")
+            })?)
+            .map_err(|_| {
+                Error::bad_database("Dehydrated device data is invalid.")
+            })?;
 
-        // Include last
-        for (key, _) in self
-            .todeviceid_events
-            .iter_from(&last, true)
-            .take_while(move |(k, _)| k.starts_with(&prefix))
-            .map(|(key, _)| {
-                Ok::<_, Error>((
-                    key.clone(),
-                    utils::u64_from_bytes(
-                        &key[key.len() - size_of::<u64>()..key.len()],
-                    )
-                    .map_err(|_| {
-                        Error::bad_database(
-                            "ToDeviceId has invalid count bytes.",
-                        )
-                    })?,
-                ))
-            })
-            .filter_map(Result::ok)
-            .take_while(|&(_, count)| count <= until)
-        {
-            self.todeviceid_events.remove(&key)?;
-        }
+        Ok(Some((device_id, device_data)))
+    }
 
-        Ok(())
+    fn set_dehydrated_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        device_data: &Raw<DehydratedDeviceData>,
+    ) -> Result<()> {
+        let mut value = device_id.as_bytes().to_vec();
+        value.push(0xFF);
+        value.extend_from_slice(device_data.json().get().as_bytes());
+
+        self.userid_dehydrateddevice.insert(user_id.as_bytes(), &value)
+    }
+
+    fn remove_dehydrated_device(&self, user_id: &UserId) -> Result<()> {
+        self.userid_dehydrateddevice.remove(user_id.as_bytes())
     }
 
     fn update_device_metadata(
@@ -993,6 +1312,34 @@ impl service::users::Data for KeyValueDatabase {
         Ok(())
     }
 
+    fn update_device_last_seen(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        last_seen_ip: Option<String>,
+        last_seen_ts: MilliSecondsSinceUnixEpoch,
+    ) -> Result<()> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xFF);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        let Some(mut device) = self.get_device_metadata(user_id, device_id)?
+        else {
+            return Ok(());
+        };
+
+        if last_seen_ip.is_some() {
+            device.last_seen_ip = last_seen_ip;
+        }
+        device.last_seen_ts = Some(last_seen_ts);
+
+        self.userdeviceid_metadata.insert(
+            &userdeviceid,
+            &serde_json::to_vec(&device)
+                .expect("Device::to_string always works"),
+        )
+    }
+
     /// Get device metadata.
     fn get_device_metadata(
         &self,
@@ -1084,6 +1431,231 @@ impl service::users::Data for KeyValueDatabase {
             Ok(None)
         }
     }
+
+    fn create_openid_token(
+        &self,
+        user_id: &UserId,
+        token: &str,
+        expires_at: u64,
+    ) -> Result<()> {
+        let mut value = expires_at.to_be_bytes().to_vec();
+        value.extend_from_slice(user_id.as_bytes());
+
+        self.openidtoken_expiresatuserid.insert(token.as_bytes(), &value)
+    }
+
+    fn find_from_openid_token(
+        &self,
+        token: &str,
+    ) -> Result<Option<OwnedUserId>> {
+        let Some(value) =
+            self.openidtoken_expiresatuserid.get(token.as_bytes())?
+        else {
+            return Ok(None);
+        };
+
+        let (expires_at, user_id) = parse_openid_token_value(&value)?;
+        if expires_at < utils::millis_since_unix_epoch() {
+            self.openidtoken_expiresatuserid.remove(token.as_bytes())?;
+            return Ok(None);
+        }
+
+        Ok(Some(user_id))
+    }
+
+    fn remove_expired_openid_tokens(&self) -> Result<usize> {
+        let now = utils::millis_since_unix_epoch();
+
+        let mut expired = Vec::new();
+        for (token, value) in self.openidtoken_expiresatuserid.iter() {
+            let (expires_at, _) = parse_openid_token_value(&value)?;
+            if expires_at < now {
+                expired.push(token);
+            }
+        }
+
+        for token in &expired {
+            self.openidtoken_expiresatuserid.remove(token)?;
+        }
+
+        Ok(expired.len())
+    }
+
+    fn threepid_owner(
+        &self,
+        medium: &Medium,
+        address: &str,
+    ) -> Result<Option<OwnedUserId>> {
+        self.threepid_userid
+            .get(&threepid_key(medium, address))?
+            .map(|bytes| {
+                UserId::parse(utils::string_from_bytes(&bytes).map_err(
+                    |_| {
+                        Error::bad_database(
+                            "User ID in threepid_userid is invalid unicode.",
+                        )
+                    },
+                )?)
+                .map_err(|_| {
+                    Error::bad_database(
+                        "User ID in threepid_userid is invalid.",
+                    )
+                })
+            })
+            .transpose()
+    }
+
+    fn add_threepid(
+        &self,
+        user_id: &UserId,
+        threepid: &ThirdPartyIdentifier,
+    ) -> Result<()> {
+        let key = threepid_key(&threepid.medium, &threepid.address);
+
+        let mut userthreepid = user_id.as_bytes().to_vec();
+        userthreepid.push(0xFF);
+        userthreepid.extend_from_slice(&key);
+
+        self.threepid_userid.insert(&key, user_id.as_bytes())?;
+        self.userthreepid_threepid.insert(
+            &userthreepid,
+            &serde_json::to_vec(threepid)
+                .expect("ThirdPartyIdentifier::to_vec always works"),
+        )
+    }
+
+    fn remove_threepid(
+        &self,
+        user_id: &UserId,
+        medium: &Medium,
+        address: &str,
+    ) -> Result<()> {
+        let key = threepid_key(medium, address);
+
+        let mut userthreepid = user_id.as_bytes().to_vec();
+        userthreepid.push(0xFF);
+        userthreepid.extend_from_slice(&key);
+
+        self.userthreepid_threepid.remove(&userthreepid)?;
+        self.threepid_userid.remove(&key)
+    }
+
+    fn threepids<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<ThirdPartyIdentifier>> + 'a> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xFF);
+
+        Box::new(self.userthreepid_threepid.scan_prefix(prefix).map(
+            |(_, value)| {
+                serde_json::from_slice(&value).map_err(|_| {
+                    Error::bad_database(
+                        "ThirdPartyIdentifier in userthreepid_threepid is \
+                         invalid.",
+                    )
+                })
+            },
+        ))
+    }
+
+    fn sso_subject_user(
+        &self,
+        idp_id: &str,
+        subject: &str,
+    ) -> Result<Option<OwnedUserId>> {
+        let mut key = idp_id.as_bytes().to_vec();
+        key.push(0xFF);
+        key.extend_from_slice(subject.as_bytes());
+
+        self.ssosubject_userid
+            .get(&key)?
+            .map(|bytes| {
+                UserId::parse(utils::string_from_bytes(&bytes).map_err(
+                    |_| {
+                        Error::bad_database(
+                            "User ID in ssosubject_userid is invalid unicode.",
+                        )
+                    },
+                )?)
+                .map_err(|_| {
+                    Error::bad_database(
+                        "User ID in ssosubject_userid is invalid.",
+                    )
+                })
+            })
+            .transpose()
+    }
+
+    fn set_sso_subject_user(
+        &self,
+        idp_id: &str,
+        subject: &str,
+        user_id: &UserId,
+    ) -> Result<()> {
+        let mut key = idp_id.as_bytes().to_vec();
+        key.push(0xFF);
+        key.extend_from_slice(subject.as_bytes());
+
+        self.ssosubject_userid.insert(&key, user_id.as_bytes())
+    }
+}
+
+/// Builds the key of a third party identifier in `threepid_userid`
+fn threepid_key(medium: &Medium, address: &str) -> Vec<u8> {
+    let mut key = medium.as_str().as_bytes().to_vec();
+    key.push(0xFF);
+    key.extend_from_slice(address.as_bytes());
+    key
+}
+
+/// Removes the refresh token of a device and the expiration of its access
+/// token
+fn remove_refresh_token(
+    db: &KeyValueDatabase,
+    userdeviceid: &[u8],
+) -> Result<()> {
+    if let Some(old_token) = db.userdeviceid_refreshtoken.get(userdeviceid)? {
+        db.userdeviceid_refreshtoken.remove(userdeviceid)?;
+        db.refreshtoken_userdeviceid.remove(&old_token)?;
+    }
+    remove_previous_refresh_token(db, userdeviceid)?;
+    db.userdeviceid_tokenexpiresat.remove(userdeviceid)
+}
+
+/// Removes the refresh token that a device used to get its current tokens.
+fn remove_previous_refresh_token(
+    db: &KeyValueDatabase,
+    userdeviceid: &[u8],
+) -> Result<()> {
+    if let Some(previous) =
+        db.userdeviceid_prevrefreshtoken.get(userdeviceid)?
+    {
+        db.userdeviceid_prevrefreshtoken.remove(userdeviceid)?;
+        db.refreshtoken_userdeviceid.remove(&previous)?;
+    }
+
+    Ok(())
+}
+
+/// Parses the expiration timestamp and user ID of an OpenID token.
+fn parse_openid_token_value(value: &[u8]) -> Result<(u64, OwnedUserId)> {
+    let invalid =
+        || Error::bad_database("Invalid value in openidtoken_expiresatuserid.");
+
+    if value.len() < size_of::<u64>() {
+        return Err(invalid());
+    }
+
+    let (expires_at, user_id) = value.split_at(size_of::<u64>());
+    let expires_at =
+        utils::u64_from_bytes(expires_at).map_err(|_| invalid())?;
+    let user_id = UserId::parse(
+        utils::string_from_bytes(user_id).map_err(|_| invalid())?,
+    )
+    .map_err(|_| invalid())?;
+
+    Ok((expires_at, user_id))
 }
 
 /// Will only return with Some(username) if the password was not empty and the
@@ -1111,3 +1683,162 @@ fn get_username_with_valid_password(
         }
     }
 }
+
+/// Returns the key prefix of the to-device events of a device in
+/// `todeviceid_events`
+fn to_device_prefix(user_id: &UserId, device_id: &DeviceId) -> Vec<u8> {
+    let mut prefix = user_id.as_bytes().to_vec();
+    prefix.push(0xFF);
+    prefix.extend_from_slice(device_id.as_bytes());
+    prefix.push(0xFF);
+    prefix
+}
+
+/// Stores a to-device event for a device under `count` in
+/// `todeviceid_events`
+fn add_to_device_event(
+    todeviceid_events: &dyn KvTree,
+    count: u64,
+    sender: &UserId,
+    target_user_id: &UserId,
+    target_device_id: &DeviceId,
+    event_type: &str,
+    content: serde_json::Value,
+) -> Result<()> {
+    let mut key = to_device_prefix(target_user_id, target_device_id);
+    key.extend_from_slice(&count.to_be_bytes());
+
+    let mut json = serde_json::Map::new();
+    json.insert("type".to_owned(), event_type.to_owned().into());
+    json.insert("sender".to_owned(), sender.to_string().into());
+    json.insert("content".to_owned(), content);
+
+    let value = serde_json::to_vec(&json).expect("Map::to_vec always works");
+
+    todeviceid_events.insert(&key, &value)
+}
+
+/// Returns the to-device events of a device in `todeviceid_events`, oldest
+/// first
+fn get_to_device_events(
+    todeviceid_events: &dyn KvTree,
+    user_id: &UserId,
+    device_id: &DeviceId,
+) -> Result<Vec<Raw<AnyToDeviceEvent>>> {
+    let mut events = Vec::new();
+
+    for (_, value) in
+        todeviceid_events.scan_prefix(to_device_prefix(user_id, device_id))
+    {
+        events.push(serde_json::from_slice(&value).map_err(|_| {
+            Error::bad_database("Event in todeviceid_events is invalid.")
+        })?);
+    }
+
+    Ok(events)
+}
+
+/// Removes the to-device events of a device in `todeviceid_events` whose
+/// count is at most `until`
+fn remove_to_device_events(
+    todeviceid_events: &dyn KvTree,
+    user_id: &UserId,
+    device_id: &DeviceId,
+    until: u64,
+) -> Result<()> {
+    let prefix = to_device_prefix(user_id, device_id);
+
+    let mut last = prefix.clone();
+    last.extend_from_slice(&until.to_be_bytes());
+
+    // Include last
+    for (key, _) in todeviceid_events
+        .iter_from(&last, true)
+        .take_while(move |(k, _)| k.starts_with(&prefix))
+        .map(|(key, _)| {
+            Ok::<_, Error>((
+                key.clone(),
+                utils::u64_from_bytes(
+                    &key[key.len() - size_of::<u64>()..key.len()],
+                )
+                .map_err(|_| {
+                    Error::bad_database("ToDeviceId has invalid count bytes.")
+                })?,
+            ))
+        })
+        .filter_map(Result::ok)
+        .take_while(|&(_, count)| count <= until)
+    {
+        todeviceid_events.remove(&key)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use ruma::{device_id, user_id};
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    use super::{
+        add_to_device_event, get_to_device_events, remove_to_device_events,
+    };
+    use crate::database::abstraction::sqlite::open_test_tree;
+
+    /// Events added after the sync response whose `next_batch` the client
+    /// presents as `since` are not removed.
+    #[test]
+    fn undelivered_to_device_events_are_kept() {
+        let dir = TempDir::new().unwrap();
+        let tree = open_test_tree(dir.path(), "todeviceid_events");
+
+        let alice = user_id!("@alice:example.com");
+        let bob = user_id!("@bob:example.com");
+        let device = device_id!("DEVICE");
+        let other_device = device_id!("OTHER");
+        for count in [3, 9, 10, 11, 20] {
+            add_to_device_event(
+                &*tree,
+                count,
+                alice,
+                bob,
+                device,
+                "m.test",
+                json!({ "count": count }),
+            )
+            .unwrap();
+        }
+        add_to_device_event(
+            &*tree,
+            5,
+            alice,
+            bob,
+            other_device,
+            "m.test",
+            json!({ "count": 5 }),
+        )
+        .unwrap();
+
+        let next_batch = 10;
+        remove_to_device_events(&*tree, bob, device, next_batch).unwrap();
+
+        let counts = |device| {
+            get_to_device_events(&*tree, bob, device)
+                .unwrap()
+                .into_iter()
+                .map(|event| {
+                    event
+                        .get_field::<serde_json::Value>("content")
+                        .unwrap()
+                        .unwrap()["count"]
+                        .as_u64()
+                        .unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(counts(device), [11, 20]);
+        // Other devices of the user are unaffected
+        assert_eq!(counts(other_device), [5]);
+    }
+}
@@ -69,6 +69,12 @@ pub(crate) enum Observability {
     // Upstream's documentation on what this error means is very sparse
     #[error("tracing_flame error")]
     TracingFlame(#[from] tracing_flame::Error),
+
+    #[error("failed to open access log file {1:?}")]
+    AccessLog(#[source] std::io::Error, PathBuf),
+
+    #[error("failed to spawn access log writer thread")]
+    AccessLogThread(#[source] std::io::Error),
 }
 
 /// Configuration errors
@@ -85,6 +91,15 @@ pub(crate) enum Config {
 
     #[error("failed to parse configuration file {1:?}")]
     Parse(#[source] toml::de::Error, PathBuf),
+
+    #[error("push rule {0:?} in `push_rules.disable` of {1:?} does not exist")]
+    UnknownPushRule(String, PathBuf),
+
+    #[error("`{0}` in {1:?} must not be zero")]
+    Zero(&'static str, PathBuf),
+
+    #[error("`{0}` in {1:?} must be a positive number")]
+    NotPositive(&'static str, PathBuf),
 }
 
 /// Errors that can occur while searching for a config file
@@ -9,29 +9,45 @@ use std::{
     sync::{atomic, RwLock},
     time::Duration,
 };
+#[cfg(unix)]
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddrV4},
+    os::unix::fs::FileTypeExt,
+    path::Path,
+};
 
+#[cfg(unix)]
+use axum::extract::ConnectInfo;
 use axum::{
-    extract::{DefaultBodyLimit, FromRequestParts, MatchedPath},
+    extract::{DefaultBodyLimit, FromRequestParts, MatchedPath, RawPathParams},
     response::IntoResponse,
-    routing::{any, get, on, MethodFilter},
-    Router,
-};
-use axum_server::{
-    bind, bind_rustls, tls_rustls::RustlsConfig, Handle as ServerHandle,
+    routing::{any, get, on, post, MethodFilter},
+    Extension, RequestExt, Router,
 };
+use axum_server::{bind, bind_rustls, Handle as ServerHandle};
 use futures_util::FutureExt;
 use http::{
     header::{self, HeaderName},
     Method, StatusCode, Uri,
 };
+#[cfg(unix)]
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
 use ruma::api::{
     client::{
         error::{Error as RumaError, ErrorBody, ErrorKind},
+        message::send_message_event,
         uiaa::UiaaResponse,
     },
     IncomingRequest,
 };
-use tokio::{signal, task::JoinSet};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{signal, sync::watch, task::JoinSet};
 use tower::ServiceBuilder;
 use tower_http::{
     cors::{self, CorsLayer},
@@ -50,9 +66,10 @@ mod service;
 mod utils;
 
 pub(crate) use api::ruma_wrapper::{Ar, Ra};
-use api::{client_server, server_server};
+use api::{client_server, ruma_wrapper::BodyLimit, server_server};
 pub(crate) use config::{Config, ListenConfig};
 pub(crate) use database::KeyValueDatabase;
+use observability::RequestAuth;
 pub(crate) use service::{pdu::PduEvent, Services};
 #[cfg(all(not(target_env = "msvc"), feature = "jemalloc"))]
 use tikv_jemallocator::Jemalloc;
@@ -147,6 +164,8 @@ async fn run_server() -> Result<(), error::Serve> {
     let middlewares = ServiceBuilder::new()
         .sensitive_headers([header::AUTHORIZATION])
         .layer(axum::middleware::from_fn(spawn_task))
+        .layer(axum::middleware::from_fn(observability::access_log_layer))
+        .layer(axum::middleware::from_fn(utils::client_ip::client_ip_layer))
         .layer(TraceLayer::new_for_http().make_span_with(
             |request: &http::Request<_>| {
                 let endpoint = if let Some(endpoint) =
@@ -193,20 +212,21 @@ async fn run_server() -> Result<(), error::Serve> {
                 .try_into()
                 .expect("failed to convert max request size"),
         ))
+        .layer(Extension(BodyLimit(config.max_request_size)))
         .layer(axum::middleware::from_fn(observability::http_metrics_layer));
 
-    let app = routes(config).layer(middlewares).into_make_service();
+    let router = routes(config).layer(middlewares);
+    let app =
+        router.clone().into_make_service_with_connect_info::<SocketAddr>();
     let mut handles = Vec::new();
+    let (shutdown, _) = watch::channel(());
     let mut servers = JoinSet::new();
 
     let tls_config = if let Some(tls) = &config.tls {
-        Some(RustlsConfig::from_pem_file(&tls.certs, &tls.key).await.map_err(
-            |err| Error::LoadCerts {
-                certs: tls.certs.clone(),
-                key: tls.key.clone(),
-                err,
-            },
-        )?)
+        let tls_config = utils::tls::load(tls).await?;
+        #[cfg(unix)]
+        tokio::spawn(utils::tls::reload_on_sighup(tls_config.clone(), tls));
+        Some(tls_config)
     } else {
         None
     };
@@ -241,6 +261,16 @@ async fn run_server() -> Result<(), error::Serve> {
                     server.then(|result| async { (listen.clone(), result) }),
                 );
             }
+            #[cfg(unix)]
+            ListenConfig::Unix {
+                path,
+            } => {
+                let server =
+                    serve_unix(path, router.clone(), shutdown.subscribe());
+                servers.spawn(
+                    server.then(|result| async { (listen.clone(), result) }),
+                );
+            }
         }
     }
 
@@ -248,7 +278,7 @@ async fn run_server() -> Result<(), error::Serve> {
     sd_notify::notify(true, &[sd_notify::NotifyState::Ready])
         .expect("should be able to notify systemd");
 
-    tokio::spawn(shutdown_signal(handles));
+    tokio::spawn(shutdown_signal(handles, shutdown));
 
     while let Some(result) = servers.join_next().await {
         let (listen, result) =
@@ -259,20 +289,147 @@ async fn run_server() -> Result<(), error::Serve> {
     Ok(())
 }
 
+/// Serves `router` on a Unix domain socket at `path` until `shutdown` fires.
+///
+/// A socket file left behind by a previous run is replaced, but any other kind
+/// of file at `path` is an error. Like the TCP listeners, open connections get
+/// 30 seconds to finish after shutdown.
+///
+/// Peers on a Unix socket have no IP address, so requests appear to come from
+/// `127.0.0.1`. Add it to `trusted_proxies` if the socket is used by a reverse
+/// proxy.
+#[cfg(unix)]
+async fn serve_unix(
+    path: &Path,
+    router: Router,
+    mut shutdown: watch::Receiver<()>,
+) -> io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            std::fs::remove_file(path)?;
+        }
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "path exists and is not a socket",
+            ));
+        }
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+        Err(error) => return Err(error),
+    }
+    let listener = UnixListener::bind(path)?;
+    let router = router.layer(Extension(ConnectInfo(SocketAddr::V4(
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+    ))));
+    let mut connections = JoinSet::new();
+
+    loop {
+        let stream = tokio::select! {
+            result = listener.accept() => match result {
+                Ok((stream, _)) => stream,
+                Err(error) => {
+                    warn!(%error, "Failed to accept connection on Unix socket");
+                    continue;
+                }
+            },
+            _ = shutdown.changed() => break,
+        };
+
+        let service = TowerToHyperService::new(router.clone());
+        let mut shutdown = shutdown.clone();
+        connections.spawn(async move {
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let connection =
+                builder.serve_connection(TokioIo::new(stream), service);
+            tokio::pin!(connection);
+
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                _ = shutdown.changed() => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(error) = result {
+                debug!(%error, "Failed to serve connection on Unix socket");
+            }
+        });
+    }
+
+    drop(listener);
+    let drain = async { while connections.join_next().await.is_some() {} };
+    if tokio::time::timeout(Duration::from_secs(30), drain).await.is_err() {
+        warn!("Aborting connections on Unix socket after shutdown timeout");
+    }
+
+    Ok(())
+}
+
 /// Ensures the request runs in a new tokio thread.
 ///
 /// The axum request handler task gets cancelled if the connection is shut down;
 /// by spawning our own task, processing continue after the client disconnects.
 async fn spawn_task(
-    req: axum::extract::Request,
+    mut req: axum::extract::Request,
     next: axum::middleware::Next,
 ) -> std::result::Result<axum::response::Response, StatusCode> {
     if services().globals.shutdown.load(atomic::Ordering::Relaxed) {
         return Err(StatusCode::SERVICE_UNAVAILABLE);
     }
-    tokio::spawn(next.run(req))
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+    if services().globals.draining.load(atomic::Ordering::Relaxed)
+        && !allowed_while_draining(&mut req).await
+    {
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    let in_flight = observability::InFlightRequest::new();
+    tokio::spawn(async move {
+        let response = next.run(req).await;
+        drop(in_flight);
+        response
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Whether a request should still be handled while the server is draining.
+///
+/// Federation requests are still accepted so that remote servers don't have to
+/// retry them and can keep using rooms they share with us, and metrics are
+/// needed to watch the drain finish. Sending messages to the admin room stays
+/// possible so that the drain can be undone.
+async fn allowed_while_draining(req: &mut axum::extract::Request) -> bool {
+    let Some(path) = req.extensions().get::<MatchedPath>().cloned() else {
+        return false;
+    };
+    let path = path.as_str();
+
+    if path.starts_with("/_matrix/federation/")
+        || path.starts_with("/_matrix/key/")
+        || path == "/metrics"
+    {
+        return true;
+    }
+
+    if !send_message_event::v3::Request::METADATA
+        .history
+        .all_paths()
+        .any(|send_path| send_path == path)
+    {
+        return false;
+    }
+
+    let Ok(Some(admin_room)) = services().admin.get_admin_room() else {
+        return false;
+    };
+    // The parameters are percent-decoded
+    let Ok(params) = req.extract_parts::<RawPathParams>().await else {
+        return false;
+    };
+
+    params
+        .iter()
+        .any(|(name, value)| name == "room_id" && value == admin_room.as_str())
 }
 
 async fn unrecognized_method(
@@ -307,6 +464,9 @@ fn routes(config: &Config) -> Router {
         .ruma_route(c2s::register_route)
         .ruma_route(c2s::get_login_types_route)
         .ruma_route(c2s::login_route)
+        .ruma_route(c2s::sso_login_route)
+        .ruma_route(c2s::sso_login_with_provider_route)
+        .ruma_route(c2s::refresh_token_route)
         .ruma_route(c2s::whoami_route)
         .ruma_route(c2s::logout_route)
         .ruma_route(c2s::logout_all_route)
@@ -315,6 +475,8 @@ fn routes(config: &Config) -> Router {
         .ruma_route(c2s::third_party_route)
         .ruma_route(c2s::request_3pid_management_token_via_email_route)
         .ruma_route(c2s::request_3pid_management_token_via_msisdn_route)
+        .ruma_route(c2s::add_3pid_route)
+        .ruma_route(c2s::delete_3pid_route)
         .ruma_route(c2s::get_capabilities_route)
         .ruma_route(c2s::get_pushrules_all_route)
         .ruma_route(c2s::set_pushrule_route)
@@ -340,6 +502,10 @@ fn routes(config: &Config) -> Router {
         .ruma_route(c2s::upload_keys_route)
         .ruma_route(c2s::get_keys_route)
         .ruma_route(c2s::claim_keys_route)
+        .ruma_route(c2s::put_dehydrated_device_route)
+        .ruma_route(c2s::get_dehydrated_device_route)
+        .ruma_route(c2s::delete_dehydrated_device_route)
+        .ruma_route(c2s::get_dehydrated_events_route)
         .ruma_route(c2s::create_backup_version_route)
         .ruma_route(c2s::update_backup_version_route)
         .ruma_route(c2s::delete_backup_version_route)
@@ -357,6 +523,8 @@ fn routes(config: &Config) -> Router {
         .ruma_route(c2s::set_read_marker_route)
         .ruma_route(c2s::create_receipt_route)
         .ruma_route(c2s::create_typing_event_route)
+        .ruma_route(c2s::set_presence_route)
+        .ruma_route(c2s::get_presence_route)
         .ruma_route(c2s::create_room_route)
         .ruma_route(c2s::redact_event_route)
         .ruma_route(c2s::report_event_route)
@@ -365,6 +533,7 @@ fn routes(config: &Config) -> Router {
         .ruma_route(c2s::get_alias_route)
         .ruma_route(c2s::join_room_by_id_route)
         .ruma_route(c2s::join_room_by_id_or_alias_route)
+        .ruma_route(c2s::knock_room_route)
         .ruma_route(c2s::joined_members_route)
         .ruma_route(c2s::leave_room_route)
         .ruma_route(c2s::forget_room_route)
@@ -379,7 +548,13 @@ fn routes(config: &Config) -> Router {
         .ruma_route(c2s::get_public_rooms_filtered_route)
         .ruma_route(c2s::search_users_route)
         .ruma_route(c2s::get_member_events_route)
+        .ruma_route(c2s::appservice_ping_route)
         .ruma_route(c2s::get_protocols_route)
+        .ruma_route(c2s::get_protocol_route)
+        .ruma_route(c2s::get_location_for_protocol_route)
+        .ruma_route(c2s::get_user_for_protocol_route)
+        .ruma_route(c2s::get_location_for_room_alias_route)
+        .ruma_route(c2s::get_user_for_user_identifier_route)
         .ruma_route(c2s::send_message_event_route)
         .ruma_route(c2s::send_state_event_for_key_route)
         .ruma_route(c2s::get_state_events_route)
@@ -390,12 +565,13 @@ fn routes(config: &Config) -> Router {
         .ruma_route(c2s::get_message_events_route)
         .ruma_route(c2s::search_events_route)
         .ruma_route(c2s::turn_server_route)
+        .ruma_route(c2s::request_openid_token_route)
         .ruma_route(c2s::send_event_to_device_route)
         .ruma_route(c2s::get_media_config_route)
-        .ruma_route(c2s::create_content_route)
         .ruma_route(c2s::get_content_route)
         .ruma_route(c2s::get_content_as_filename_route)
         .ruma_route(c2s::get_content_thumbnail_route)
+        .ruma_route(c2s::get_media_preview_route)
         .ruma_route(c2s::get_devices_route)
         .ruma_route(c2s::get_device_route)
         .ruma_route(c2s::update_device_route)
@@ -442,6 +618,20 @@ fn routes(config: &Config) -> Router {
                 .put(c2s::send_state_event_for_empty_key_route),
         );
 
+    // Media uploads have their own limit, which replaces the global one set in
+    // `run_server`
+    let max_media_upload_size = services().globals.max_media_upload_size();
+    let router = router.merge(
+        Router::new()
+            .ruma_route(c2s::create_content_route)
+            .layer(DefaultBodyLimit::max(
+                max_media_upload_size
+                    .try_into()
+                    .expect("failed to convert max media upload size"),
+            ))
+            .layer(Extension(BodyLimit(max_media_upload_size))),
+    );
+
     let router = if config.observability.metrics.enable {
         router.route(
             "/metrics",
@@ -451,7 +641,28 @@ fn routes(config: &Config) -> Router {
         router
     };
 
+    let router = if config.expose_build_info {
+        router.route("/_grapevine/version", get(c2s::get_build_info_route))
+    } else {
+        router
+    };
+
+    let router = if config.registration_shared_secret.is_some() {
+        router.route(
+            "/_synapse/admin/v1/register",
+            get(c2s::get_shared_secret_nonce_route)
+                .post(c2s::register_shared_secret_route),
+        )
+    } else {
+        router
+    };
+
     let router = router
+        .route(
+            "/_matrix/client/unstable/add_threepid/email/submit_token",
+            post(c2s::submit_threepid_token_route),
+        )
+        .route(service::sso::CALLBACK_PATH, get(c2s::sso_callback_route))
         .route(
             "/_matrix/client/r0/rooms/:room_id/initialSync",
             get(initial_sync),
@@ -460,11 +671,13 @@ fn routes(config: &Config) -> Router {
             "/_matrix/client/v3/rooms/:room_id/initialSync",
             get(initial_sync),
         )
+        .ruma_route(c2s::well_known_client_route)
+        .ruma_route(s2s::well_known_server_route)
         .route("/", get(it_works))
         .fallback(not_found);
 
     if config.federation.enable {
-        router
+        let router = router
             .ruma_route(s2s::get_server_version_route)
             .route("/_matrix/key/v2/server", get(s2s::get_server_keys_route))
             .route(
@@ -483,12 +696,24 @@ fn routes(config: &Config) -> Router {
             .ruma_route(s2s::create_join_event_template_route)
             .ruma_route(s2s::create_join_event_v1_route)
             .ruma_route(s2s::create_join_event_v2_route)
+            .ruma_route(s2s::create_knock_event_template_route)
+            .ruma_route(s2s::create_knock_event_v1_route)
             .ruma_route(s2s::create_invite_route)
             .ruma_route(s2s::get_devices_route)
             .ruma_route(s2s::get_room_information_route)
+            .ruma_route(s2s::get_hierarchy_route)
             .ruma_route(s2s::get_profile_information_route)
             .ruma_route(s2s::get_keys_route)
             .ruma_route(s2s::claim_keys_route)
+            .ruma_route(s2s::get_openid_userinfo_route);
+
+        if config.federation.notary {
+            router
+                .ruma_route(s2s::get_remote_server_keys_batch_route)
+                .ruma_route(s2s::get_remote_server_keys_route)
+        } else {
+            router
+        }
     } else {
         router
             .route("/_matrix/federation/*path", any(federation_disabled))
@@ -496,7 +721,10 @@ fn routes(config: &Config) -> Router {
     }
 }
 
-async fn shutdown_signal(handles: Vec<ServerHandle>) {
+async fn shutdown_signal(
+    handles: Vec<ServerHandle>,
+    shutdown: watch::Sender<()>,
+) {
     let ctrl_c = async {
         signal::ctrl_c().await.expect("failed to install Ctrl+C handler");
     };
@@ -526,6 +754,7 @@ async fn shutdown_signal(handles: Vec<ServerHandle>) {
     for handle in handles {
         handle.graceful_shutdown(Some(Duration::from_secs(30)));
     }
+    shutdown.send_replace(());
 
     #[cfg(feature = "systemd")]
     sd_notify::notify(true, &[sd_notify::NotifyState::Stopping])
@@ -603,16 +832,28 @@ macro_rules! impl_ruma_handler {
                         on(
                             method_filter,
                             |$( $ty: $ty, )* req: Ar<Req>| async move {
+                                let auth = RequestAuth {
+                                    user: req.sender_user.clone(),
+                                    device: req.sender_device.clone(),
+                                    servername: req.sender_servername.clone(),
+                                    appservice_id: req.appservice_info
+                                        .as_ref()
+                                        .map(|i| i.registration.id.clone()),
+                                };
                                 let span = info_span!(
                                     "run_ruma_handler",
-                                    auth.user = ?req.sender_user,
-                                    auth.device = ?req.sender_device,
-                                    auth.servername = ?req.sender_servername,
-                                    auth.appservice_id = ?req.appservice_info
-                                        .as_ref()
-                                        .map(|i| &i.registration.id)
+                                    auth.user = ?auth.user,
+                                    auth.device = ?auth.device,
+                                    auth.servername = ?auth.servername,
+                                    auth.appservice_id = ?auth.appservice_id,
                                 );
-                                handler($($ty,)* req).instrument(span).await
+                                let mut response = handler($($ty,)* req)
+                                    .instrument(span)
+                                    .await
+                                    .into_response();
+                                // Used by the access log
+                                response.extensions_mut().insert(auth);
+                                response
                             }
                         )
                     )
@@ -1,15 +1,27 @@
 //! Facilities for observing runtime behavior
 #![warn(missing_docs, clippy::missing_docs_in_private_items)]
 
-use std::{collections::HashSet, fs::File, io::BufWriter, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, LineWriter, Write},
+    sync::{
+        atomic::{self, AtomicU64},
+        mpsc::{self, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
 
 use axum::{
+    body::HttpBody as _,
     extract::{MatchedPath, Request},
     middleware::Next,
     response::Response,
 };
-use http::Method;
-use once_cell::sync::Lazy;
+use http::{header, HeaderMap, Method};
+use once_cell::sync::{Lazy, OnceCell};
 use opentelemetry::{
     metrics::{MeterProvider, Unit},
     KeyValue,
@@ -17,10 +29,13 @@ use opentelemetry::{
 use opentelemetry_otlp::WithExportConfig;
 use opentelemetry_sdk::{
     metrics::{new_view, Aggregation, Instrument, SdkMeterProvider, Stream},
+    trace::Sampler,
     Resource,
 };
-use strum::{AsRefStr, IntoStaticStr};
+use ruma::{OwnedDeviceId, OwnedServerName, OwnedUserId, ServerName};
+use strum::{AsRefStr, EnumIter, IntoEnumIterator, IntoStaticStr};
 use tokio::time::Instant;
+use tracing::error;
 use tracing_flame::{FlameLayer, FlushGuard};
 use tracing_subscriber::{
     layer::SubscriberExt, reload, EnvFilter, Layer, Registry,
@@ -29,12 +44,34 @@ use tracing_subscriber::{
 use crate::{
     config::{Config, EnvFilterClone, LogFormat},
     error,
-    utils::error::Result,
+    utils::{self, error::Result},
 };
 
 /// Globally accessible metrics state
 pub(crate) static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
 
+/// Queue of lines for the access log writer thread, if the access log is
+/// enabled
+static ACCESS_LOG: OnceCell<SyncSender<Vec<u8>>> = OnceCell::new();
+
+/// How many access log lines may wait to be written before new ones are
+/// dropped
+const ACCESS_LOG_QUEUE: usize = 4096;
+
+/// Authentication of a request, stored in the response extensions so that it
+/// can be included in the access log
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RequestAuth {
+    /// The authenticated user
+    pub(crate) user: Option<OwnedUserId>,
+    /// The device of the authenticated user
+    pub(crate) device: Option<OwnedDeviceId>,
+    /// The server that signed a federation request
+    pub(crate) servername: Option<OwnedServerName>,
+    /// The appservice that sent the request
+    pub(crate) appservice_id: Option<String>,
+}
+
 /// Cleans up resources relating to observability when [`Drop`]ped
 pub(crate) struct Guard {
     /// Drop guard used to flush [`tracing_flame`] data on exit
@@ -73,8 +110,32 @@ impl<L, S> ReloadHandle<L> for reload::Handle<L, S> {
     }
 }
 
-/// A type-erased [reload handle][reload::Handle] for an [`EnvFilter`].
-pub(crate) type FilterReloadHandle = Box<dyn ReloadHandle<EnvFilter> + Sync>;
+/// A type-erased [reload handle][reload::Handle] for an [`EnvFilter`] that
+/// also remembers the directives of the filter currently in use.
+pub(crate) struct FilterReloadHandle {
+    /// The type-erased reload handle
+    handle: Box<dyn ReloadHandle<EnvFilter> + Sync>,
+    /// The directives of the currently active filter
+    current: Mutex<String>,
+}
+
+impl FilterReloadHandle {
+    /// Returns the directives of the currently active filter
+    pub(crate) fn current(&self) -> String {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// Replaces the active filter with `filter`
+    pub(crate) fn reload(
+        &self,
+        filter: &EnvFilterClone,
+    ) -> Result<(), reload::Error> {
+        let mut current = self.current.lock().unwrap();
+        self.handle.reload(filter.into())?;
+        *current = filter.to_string();
+        Ok(())
+    }
+}
 
 /// Collection of [`FilterReloadHandle`]s, allowing the filters for tracing
 /// backends to be changed dynamically. Handles may be [`None`] if the backend
@@ -91,7 +152,9 @@ pub(crate) struct FilterReloadHandles {
 /// See also [`Metrics::record_lookup`].
 // Keep variants sorted
 #[allow(clippy::missing_docs_in_private_items)]
-#[derive(Clone, Copy, AsRefStr, IntoStaticStr)]
+#[derive(
+    Clone, Copy, PartialEq, Eq, Hash, AsRefStr, EnumIter, IntoStaticStr,
+)]
 pub(crate) enum Lookup {
     AppserviceInRoom,
     AuthChain,
@@ -99,12 +162,16 @@ pub(crate) enum Lookup {
     CreateStateKeyToShort,
     FederationDestination,
     LastTimelineCount,
+    MissingPdu,
     OurRealUsers,
     Pdu,
+    RemoteDeviceKeys,
+    ServerAcl,
     ShortToEventId,
     ShortToStateKey,
     StateInfo,
     StateKeyToShort,
+    UrlPreview,
     VisibilityForServer,
     VisibilityForUser,
 }
@@ -125,6 +192,132 @@ pub(crate) enum FoundIn {
     Nothing,
 }
 
+/// Reasons for an entry to be removed from a cache
+#[derive(Clone, Copy, AsRefStr, IntoStaticStr)]
+pub(crate) enum EvictionReason {
+    /// The cache was full and the entry was the least recently used one
+    Capacity,
+    /// The entry was older than the cache's TTL
+    Expired,
+    /// The cached value became stale
+    Invalidated,
+}
+
+/// Outcomes of an outgoing federation request
+#[derive(Clone, Copy, AsRefStr, IntoStaticStr)]
+pub(crate) enum FederationOutcome {
+    /// The request failed, e.g. because of a network error or an error
+    /// response
+    Failure,
+    /// The request succeeded
+    Success,
+    /// The destination didn't respond in time
+    Timeout,
+}
+
+/// Directions of federation traffic
+#[derive(Clone, Copy, AsRefStr, IntoStaticStr)]
+pub(crate) enum FederationDirection {
+    /// A request from another server
+    Inbound,
+    /// A request to another server
+    Outbound,
+}
+
+/// Outcomes of a notification sent to a push gateway
+#[derive(Clone, Copy, AsRefStr, IntoStaticStr)]
+pub(crate) enum PushOutcome {
+    /// The request failed and will be retried
+    Failure,
+    /// The gateway responded with `410 Gone`
+    Gone,
+    /// The gateway rejected the pushkey of the pusher
+    Rejected,
+    /// The notification was accepted
+    Success,
+}
+
+/// Maximum number of destinations with their own label in federation request
+/// metrics. Requests to other destinations are labeled as `other`.
+const LABELED_DESTINATIONS: usize = 50;
+
+/// Number of federation destinations whose request volume is tracked
+const TRACKED_DESTINATIONS: usize = 1000;
+
+/// Request volumes of federation destinations, used to only label the
+/// destinations with the most requests
+#[derive(Default)]
+struct DestinationVolumes {
+    /// Number of requests made to each destination, for at most
+    /// [`TRACKED_DESTINATIONS`] destinations
+    requests: HashMap<OwnedServerName, u64>,
+    /// Destinations that currently have their own label
+    labeled: HashSet<OwnedServerName>,
+}
+
+impl DestinationVolumes {
+    /// Counts a request to `destination` and returns the label to use for it
+    fn label(&mut self, destination: &ServerName) -> String {
+        if !self.requests.contains_key(destination)
+            && self.requests.len() >= TRACKED_DESTINATIONS
+        {
+            // Replace the unlabeled destination with the fewest requests. The
+            // new one takes over its count, so that a destination with many
+            // requests can't be pushed out by a flood of new ones.
+            let Some((least, least_count)) = self
+                .requests
+                .iter()
+                .filter(|(d, _)| !self.labeled.contains(*d))
+                .min_by_key(|(_, c)| **c)
+                .map(|(d, c)| (d.clone(), *c))
+            else {
+                return "other".to_owned();
+            };
+            self.requests.remove(&least);
+            self.requests.insert(destination.to_owned(), least_count);
+        }
+
+        let count = self.requests.entry(destination.to_owned()).or_default();
+        *count = count.saturating_add(1);
+        let count = *count;
+
+        if self.labeled.contains(destination) {
+            return destination.to_string();
+        }
+
+        if self.labeled.len() >= LABELED_DESTINATIONS {
+            // Replace the labeled destination with the fewest requests if this
+            // one has more now
+            let Some((least, least_count)) = self
+                .labeled
+                .iter()
+                .map(|d| {
+                    (d.clone(), self.requests.get(d).copied().unwrap_or(0))
+                })
+                .min_by_key(|(_, c)| *c)
+            else {
+                return "other".to_owned();
+            };
+            if least_count >= count {
+                return "other".to_owned();
+            }
+            self.labeled.remove(&least);
+        }
+
+        self.labeled.insert(destination.to_owned());
+        destination.to_string()
+    }
+}
+
+/// Number of times a [`Lookup`] was made and found in cache
+#[derive(Default)]
+struct LookupTotals {
+    /// Lookups that were [`FoundIn::Cache`]
+    hits: AtomicU64,
+    /// All lookups
+    total: AtomicU64,
+}
+
 /// Wrapper for the creation of a `tracing` [`Layer`] and any associated opaque
 /// data.
 ///
@@ -148,9 +341,17 @@ where
         return Ok((None, None, None));
     }
 
+    let current = Mutex::new(filter.to_string());
     let (filter, handle) = reload::Layer::new(EnvFilter::from(filter));
     let (layer, data) = init()?;
-    Ok((Some(layer.with_filter(filter)), Some(Box::new(handle)), Some(data)))
+    Ok((
+        Some(layer.with_filter(filter)),
+        Some(FilterReloadHandle {
+            handle: Box::new(handle),
+            current,
+        }),
+        Some(data),
+    ))
 }
 
 /// Initialize observability
@@ -168,11 +369,20 @@ pub(crate) fn init(
             if let Some(endpoint) = &config.observability.traces.endpoint {
                 exporter = exporter.with_endpoint(endpoint);
             }
+            let traces = &config.observability.traces;
+            let resource = standard_resource().merge(&Resource::new(
+                traces.resource_attributes.iter().map(|(key, value)| {
+                    KeyValue::new(key.clone(), value.clone())
+                }),
+            ));
             let tracer = opentelemetry_otlp::new_pipeline()
                 .tracing()
                 .with_trace_config(
                     opentelemetry_sdk::trace::config()
-                        .with_resource(standard_resource()),
+                        .with_resource(resource)
+                        .with_sampler(Sampler::ParentBased(Box::new(
+                            Sampler::TraceIdRatioBased(traces.sampling_ratio),
+                        ))),
                 )
                 .with_exporter(exporter)
                 .install_batch(opentelemetry_sdk::runtime::Tokio)?;
@@ -229,6 +439,39 @@ pub(crate) fn init(
             Ok((fmt_layer, ()))
         })?;
 
+    if config.observability.access_log.enable {
+        let writer: Box<dyn Write + Send> =
+            if let Some(path) = &config.observability.access_log.file {
+                Box::new(
+                    OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .map_err(|e| {
+                            error::Observability::AccessLog(e, path.clone())
+                        })?,
+                )
+            } else {
+                Box::new(io::stdout())
+            };
+        let (sender, receiver) =
+            mpsc::sync_channel::<Vec<u8>>(ACCESS_LOG_QUEUE);
+        // Writes happen on their own thread so that slow disks or a blocked
+        // stdout don't stall request handling
+        thread::Builder::new()
+            .name("access-log".to_owned())
+            .spawn(move || {
+                let mut writer = LineWriter::new(writer);
+                for line in receiver {
+                    if let Err(error) = writer.write_all(&line) {
+                        error!(%error, "Failed to write to access log");
+                    }
+                }
+            })
+            .map_err(error::Observability::AccessLogThread)?;
+        ACCESS_LOG.get_or_init(|| sender);
+    }
+
     let subscriber = Registry::default()
         .with(traces_layer)
         .with(flame_layer)
@@ -270,9 +513,62 @@ pub(crate) struct Metrics {
     /// Counts where data is found from
     lookup: opentelemetry::metrics::Counter<u64>,
 
+    /// Counts entries removed from a cache
+    cache_evictions: opentelemetry::metrics::Counter<u64>,
+
+    /// Totals of [`Metrics::lookup`] kept in memory for the `cache-stats`
+    /// admin command
+    lookup_totals: HashMap<Lookup, LookupTotals>,
+
     /// Number of entries in an
     /// [`OnDemandHashMap`](crate::utils::on_demand_hashmap::OnDemandHashMap)
     on_demand_hashmap_size: opentelemetry::metrics::Gauge<u64>,
+
+    /// Number of HTTP requests that are currently being processed
+    http_requests_in_flight: opentelemetry::metrics::UpDownCounter<i64>,
+
+    /// Counts requests rejected by a rate limiter
+    rate_limited: opentelemetry::metrics::Counter<u64>,
+
+    /// Number of tracked token buckets of a rate limiter
+    rate_limiter_buckets: opentelemetry::metrics::Gauge<u64>,
+
+    /// Number of sliding sync connections whose state is kept in memory
+    sliding_sync_connections: opentelemetry::metrics::Gauge<u64>,
+
+    /// Time since the public room directory cache was last refreshed
+    directory_cache_age: opentelemetry::metrics::Gauge<f64>,
+
+    /// Number of events waiting to be sent to an appservice
+    appservice_queue_depth: opentelemetry::metrics::Gauge<u64>,
+
+    /// Histogram of outgoing federation requests
+    federation_requests_histogram: opentelemetry::metrics::Histogram<f64>,
+
+    /// Counts outgoing federation requests by outcome
+    federation_requests: opentelemetry::metrics::Counter<u64>,
+
+    /// Used to keep the number of destination labels of federation request
+    /// metrics bounded
+    federation_destinations: Mutex<DestinationVolumes>,
+
+    /// Counts federation requests that were blocked because federation with
+    /// the server isn't allowed
+    federation_blocked: opentelemetry::metrics::Counter<u64>,
+
+    /// Histogram of the time taken to handle incoming federation transactions
+    federation_transactions_histogram: opentelemetry::metrics::Histogram<f64>,
+
+    /// Counts notifications sent to push gateways by outcome
+    push_gateway_requests: opentelemetry::metrics::Counter<u64>,
+
+    /// Counts incoming events whose missing prev events weren't all fetched
+    /// because of `federation.max_fetch_prev_events`
+    prev_event_limit_reached: opentelemetry::metrics::Counter<u64>,
+
+    /// Counts removals of to-device events that were skipped because the
+    /// client presented a sync token that was never issued
+    to_device_removals_skipped: opentelemetry::metrics::Counter<u64>,
 }
 
 impl Metrics {
@@ -280,6 +576,14 @@ impl Metrics {
     fn new() -> Self {
         // Metric names
         let http_requests_histogram_name = "http.requests";
+        let federation_requests_histogram_name = "federation.requests";
+        let federation_transactions_histogram_name = "federation.transactions";
+
+        let latency_boundaries = vec![
+            0., 0.01, 0.02, 0.03, 0.04, 0.05, 0.06, 0.07, 0.08, 0.09, 0.1, 0.2,
+            0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1., 2., 3., 4., 5., 6., 7., 8.,
+            9., 10., 20., 30., 40., 50.,
+        ];
 
         // Set up OpenTelemetry state
         let registry = prometheus::Registry::new();
@@ -294,12 +598,32 @@ impl Metrics {
                     Instrument::new().name(http_requests_histogram_name),
                     Stream::new().aggregation(
                         Aggregation::ExplicitBucketHistogram {
-                            boundaries: vec![
-                                0., 0.01, 0.02, 0.03, 0.04, 0.05, 0.06, 0.07,
-                                0.08, 0.09, 0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7,
-                                0.8, 0.9, 1., 2., 3., 4., 5., 6., 7., 8., 9.,
-                                10., 20., 30., 40., 50.,
-                            ],
+                            boundaries: latency_boundaries.clone(),
+                            record_min_max: true,
+                        },
+                    ),
+                )
+                .expect("view should be valid"),
+            )
+            .with_view(
+                new_view(
+                    Instrument::new().name(federation_requests_histogram_name),
+                    Stream::new().aggregation(
+                        Aggregation::ExplicitBucketHistogram {
+                            boundaries: latency_boundaries.clone(),
+                            record_min_max: true,
+                        },
+                    ),
+                )
+                .expect("view should be valid"),
+            )
+            .with_view(
+                new_view(
+                    Instrument::new()
+                        .name(federation_transactions_histogram_name),
+                    Stream::new().aggregation(
+                        Aggregation::ExplicitBucketHistogram {
+                            boundaries: latency_boundaries,
                             record_min_max: true,
                         },
                     ),
@@ -323,16 +647,129 @@ impl Metrics {
             .with_description("Counts where data is found from")
             .init();
 
+        let cache_evictions = meter
+            .u64_counter("cache_evictions")
+            .with_description("Counts entries removed from a cache")
+            .init();
+
         let on_demand_hashmap_size = meter
             .u64_gauge("on_demand_hashmap_size")
             .with_description("Number of entries in OnDemandHashMap")
             .init();
 
+        let http_requests_in_flight = meter
+            .i64_up_down_counter("http.requests.in_flight")
+            .with_description("Number of HTTP requests currently in flight")
+            .init();
+
+        let rate_limited = meter
+            .u64_counter("rate_limited")
+            .with_description("Counts requests rejected by a rate limiter")
+            .init();
+
+        let rate_limiter_buckets = meter
+            .u64_gauge("rate_limiter_buckets")
+            .with_description(
+                "Number of tracked token buckets of a rate limiter",
+            )
+            .init();
+
+        let sliding_sync_connections = meter
+            .u64_gauge("sliding_sync_connections")
+            .with_description(
+                "Number of sliding sync connections kept in memory",
+            )
+            .init();
+
+        let directory_cache_age = meter
+            .f64_gauge("directory_cache_age")
+            .with_unit(Unit::new("seconds"))
+            .with_description(
+                "Time since the public room directory cache was last refreshed",
+            )
+            .init();
+
+        let appservice_queue_depth = meter
+            .u64_gauge("appservice_queue_depth")
+            .with_description(
+                "Number of events waiting to be sent to an appservice",
+            )
+            .init();
+
+        let federation_requests_histogram = meter
+            .f64_histogram(federation_requests_histogram_name)
+            .with_unit(Unit::new("seconds"))
+            .with_description("Histogram of outgoing federation requests")
+            .init();
+
+        let federation_requests = meter
+            .u64_counter("federation.requests.outcome")
+            .with_description("Counts outgoing federation requests by outcome")
+            .init();
+
+        let federation_blocked = meter
+            .u64_counter("federation.blocked")
+            .with_description(
+                "Counts federation requests blocked because federation with \
+                 the server isn't allowed",
+            )
+            .init();
+
+        let federation_transactions_histogram = meter
+            .f64_histogram(federation_transactions_histogram_name)
+            .with_unit(Unit::new("seconds"))
+            .with_description(
+                "Histogram of the time taken to handle incoming federation \
+                 transactions",
+            )
+            .init();
+
+        let push_gateway_requests = meter
+            .u64_counter("push_gateway.requests")
+            .with_description(
+                "Counts notifications sent to push gateways by outcome",
+            )
+            .init();
+
+        let prev_event_limit_reached = meter
+            .u64_counter("prev_event_limit_reached")
+            .with_description(
+                "Counts incoming events whose missing prev events weren't all \
+                 fetched because of the limit",
+            )
+            .init();
+
+        let to_device_removals_skipped = meter
+            .u64_counter("to_device.removals_skipped")
+            .with_description(
+                "Counts removals of to-device events skipped because of an \
+                 invalid sync token",
+            )
+            .init();
+
         Metrics {
             otel_state: (registry, provider),
             http_requests_histogram,
             lookup,
+            cache_evictions,
+            lookup_totals: Lookup::iter()
+                .map(|lookup| (lookup, LookupTotals::default()))
+                .collect(),
             on_demand_hashmap_size,
+            http_requests_in_flight,
+            rate_limited,
+            rate_limiter_buckets,
+            sliding_sync_connections,
+            directory_cache_age,
+            appservice_queue_depth,
+            federation_requests_histogram,
+            federation_requests,
+            federation_destinations: Mutex::new(DestinationVolumes::default()),
+            federation_blocked,
+            federation_transactions_histogram,
+            push_gateway_requests,
+            prev_event_limit_reached,
+            to_device_removals_skipped,
         }
     }
 
@@ -352,6 +789,43 @@ impl Metrics {
                 KeyValue::new("found_in", <&str>::from(found_in)),
             ],
         );
+
+        if let Some(totals) = self.lookup_totals.get(&lookup) {
+            totals.total.fetch_add(1, atomic::Ordering::Relaxed);
+            if matches!(found_in, FoundIn::Cache) {
+                totals.hits.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Record that an entry was removed from the cache of `lookup`
+    pub(crate) fn record_cache_eviction(
+        &self,
+        lookup: Lookup,
+        reason: EvictionReason,
+    ) {
+        self.cache_evictions.add(
+            1,
+            &[
+                KeyValue::new("lookup", <&str>::from(lookup)),
+                KeyValue::new("reason", <&str>::from(reason)),
+            ],
+        );
+    }
+
+    /// Returns the number of cache hits and the total number of lookups
+    /// recorded for each [`Lookup`] since startup
+    pub(crate) fn lookup_totals(&self) -> Vec<(Lookup, u64, u64)> {
+        Lookup::iter()
+            .filter_map(|lookup| {
+                let totals = self.lookup_totals.get(&lookup)?;
+                Some((
+                    lookup,
+                    totals.hits.load(atomic::Ordering::Relaxed),
+                    totals.total.load(atomic::Ordering::Relaxed),
+                ))
+            })
+            .collect()
     }
 
     /// Record size of [`OnDemandHashMap`]
@@ -367,6 +841,146 @@ impl Metrics {
             &[KeyValue::new("name", name)],
         );
     }
+
+    /// Record that a request was rejected by a rate limiter
+    pub(crate) fn record_rate_limited(&self, class: &'static str) {
+        self.rate_limited.add(1, &[KeyValue::new("class", class)]);
+    }
+
+    /// Record the number of token buckets tracked by a rate limiter
+    pub(crate) fn record_rate_limiter_buckets(
+        &self,
+        class: &'static str,
+        size: usize,
+    ) {
+        self.rate_limiter_buckets.record(
+            size.try_into().unwrap_or(u64::MAX),
+            &[KeyValue::new("class", class)],
+        );
+    }
+
+    /// Record the number of sliding sync connections kept in memory
+    pub(crate) fn record_sliding_sync_connections(&self, count: usize) {
+        self.sliding_sync_connections
+            .record(count.try_into().unwrap_or(u64::MAX), &[]);
+    }
+
+    /// Record the time since the public room directory cache was last
+    /// refreshed
+    pub(crate) fn record_directory_cache_age(&self, age: Duration) {
+        self.directory_cache_age.record(age.as_secs_f64(), &[]);
+    }
+
+    /// Record the number of events waiting to be sent to an appservice
+    pub(crate) fn record_appservice_queue_depth(
+        &self,
+        appservice: String,
+        depth: usize,
+    ) {
+        self.appservice_queue_depth.record(
+            depth.try_into().unwrap_or(u64::MAX),
+            &[KeyValue::new("appservice", appservice)],
+        );
+    }
+
+    /// Record an outgoing federation request to `endpoint`, which is the path
+    /// of the request without parameters
+    pub(crate) fn record_federation_request(
+        &self,
+        endpoint: &'static str,
+        destination: &ServerName,
+        outcome: FederationOutcome,
+        elapsed: Duration,
+    ) {
+        let destination =
+            self.federation_destinations.lock().unwrap().label(destination);
+        let attrs = &[
+            KeyValue::new("endpoint", endpoint),
+            KeyValue::new("destination", destination),
+        ];
+
+        self.federation_requests_histogram.record(elapsed.as_secs_f64(), attrs);
+        self.federation_requests.add(
+            1,
+            &[
+                attrs[0].clone(),
+                attrs[1].clone(),
+                KeyValue::new("outcome", <&str>::from(outcome)),
+            ],
+        );
+    }
+
+    /// Record a federation request that was blocked because federation with
+    /// the server isn't allowed
+    pub(crate) fn record_federation_blocked(
+        &self,
+        direction: FederationDirection,
+    ) {
+        self.federation_blocked
+            .add(1, &[KeyValue::new("direction", <&str>::from(direction))]);
+    }
+
+    /// Record the time taken to handle an incoming federation transaction with
+    /// `pdus` PDUs
+    pub(crate) fn record_federation_transaction(
+        &self,
+        pdus: usize,
+        elapsed: Duration,
+    ) {
+        // Bucketed to keep the number of label values bounded
+        let size = match pdus {
+            0 => "0",
+            1..=10 => "1-10",
+            _ => "11+",
+        };
+        self.federation_transactions_histogram
+            .record(elapsed.as_secs_f64(), &[KeyValue::new("pdus", size)]);
+    }
+
+    /// Record a notification sent to the push gateway at `gateway`, which is
+    /// the host of the gateway's URL
+    pub(crate) fn record_push_gateway_request(
+        &self,
+        gateway: String,
+        outcome: PushOutcome,
+    ) {
+        self.push_gateway_requests.add(
+            1,
+            &[
+                KeyValue::new("gateway", gateway),
+                KeyValue::new("outcome", <&str>::from(outcome)),
+            ],
+        );
+    }
+
+    /// Record that not all missing prev events of an incoming event were
+    /// fetched because of the limit
+    pub(crate) fn record_prev_event_limit_reached(&self) {
+        self.prev_event_limit_reached.add(1, &[]);
+    }
+
+    /// Record that to-device events weren't removed because the sync token
+    /// was never issued
+    pub(crate) fn record_to_device_removal_skipped(&self) {
+        self.to_device_removals_skipped.add(1, &[]);
+    }
+}
+
+/// Counts an HTTP request as in flight until this is [`Drop`]ped
+pub(crate) struct InFlightRequest(());
+
+impl InFlightRequest {
+    /// Starts counting a request as in flight
+    pub(crate) fn new() -> Self {
+        METRICS.http_requests_in_flight.add(1, &[]);
+        Self(())
+    }
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        METRICS.http_requests_in_flight.add(-1, &[]);
+    }
 }
 
 /// Track HTTP metrics by converting this into an [`axum`] layer
@@ -413,3 +1027,85 @@ pub(crate) async fn http_metrics_layer(req: Request, next: Next) -> Response {
         }
     }
 }
+
+/// Write a line to the access log for each request by converting this into an
+/// [`axum`] layer
+pub(crate) async fn access_log_layer(req: Request, next: Next) -> Response {
+    /// Parses the `Content-Length` header
+    fn content_length(headers: &HeaderMap) -> Option<u64> {
+        headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+    }
+
+    let Some(access_log) = ACCESS_LOG.get() else {
+        return next.run(req).await;
+    };
+
+    let method = req.method().to_owned();
+    // The query is left out because it may contain an access token
+    let path = req.uri().path().to_owned();
+    let bytes_received = content_length(req.headers());
+
+    let start = Instant::now();
+    let resp = next.run(req).await;
+    let elapsed = start.elapsed();
+
+    let auth =
+        resp.extensions().get::<RequestAuth>().cloned().unwrap_or_default();
+    let bytes_sent = content_length(resp.headers())
+        .or_else(|| resp.body().size_hint().exact());
+
+    let line = serde_json::json!({
+        "timestamp": utils::millis_since_unix_epoch(),
+        "method": method.as_str(),
+        "path": path,
+        "status": resp.status().as_u16(),
+        "duration_ms": elapsed.as_secs_f64() * 1000.0,
+        "user": auth.user,
+        "device": auth.device,
+        "servername": auth.servername,
+        "appservice_id": auth.appservice_id,
+        "bytes_received": bytes_received,
+        "bytes_sent": bytes_sent,
+    });
+
+    let mut line = serde_json::to_vec(&line).expect("json can be serialized");
+    line.push(b'\n');
+    match access_log.try_send(line) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => {
+            error!("Access log writer is falling behind, dropping line");
+        }
+        Err(TrySendError::Disconnected(_)) => {
+            error!("Access log writer stopped, dropping line");
+        }
+    }
+
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::ServerName;
+
+    use super::{DestinationVolumes, TRACKED_DESTINATIONS};
+
+    #[test]
+    fn destination_volumes_are_bounded() {
+        let mut volumes = DestinationVolumes::default();
+        let busy = <&ServerName>::try_from("busy.example").unwrap();
+        for _ in 0..10 {
+            assert_eq!(volumes.label(busy), "busy.example");
+        }
+
+        for i in 0..TRACKED_DESTINATIONS * 2 {
+            let name = format!("server{i}.example");
+            volumes.label(<&ServerName>::try_from(name.as_str()).unwrap());
+        }
+
+        assert_eq!(volumes.requests.len(), TRACKED_DESTINATIONS);
+        assert_eq!(volumes.label(busy), "busy.example");
+    }
+}
@@ -1,10 +1,11 @@
 use std::{
     collections::{BTreeMap, HashMap},
-    sync::{Arc, Mutex as StdMutex},
+    hash::Hash,
+    sync::{atomic::AtomicBool, Arc, Mutex as StdMutex},
 };
 
 use lru_cache::LruCache;
-use tokio::sync::{broadcast, Mutex, RwLock};
+use tokio::sync::{broadcast, Mutex, Notify, RwLock};
 
 use crate::{observability::FilterReloadHandles, Config, Result};
 
@@ -18,8 +19,10 @@ pub(crate) mod pdu;
 pub(crate) mod pusher;
 pub(crate) mod rooms;
 pub(crate) mod sending;
+pub(crate) mod sso;
 pub(crate) mod transaction_ids;
 pub(crate) mod uiaa;
+pub(crate) mod url_preview;
 pub(crate) mod users;
 
 pub(crate) struct Services {
@@ -35,6 +38,8 @@ pub(crate) struct Services {
     pub(crate) key_backups: key_backups::Service,
     pub(crate) media: media::Service,
     pub(crate) sending: Arc<sending::Service>,
+    pub(crate) sso: sso::Service,
+    pub(crate) url_preview: url_preview::Service,
 }
 
 impl Services {
@@ -60,19 +65,29 @@ impl Services {
             appservice: appservice::Service::build(db)?,
             pusher: pusher::Service {
                 db,
+                last_email: StdMutex::new(HashMap::new()),
             },
             rooms: rooms::Service {
                 alias: rooms::alias::Service::new(db),
                 auth_chain: rooms::auth_chain::Service {
                     db,
                 },
-                directory: db,
+                directory: rooms::directory::Service::new(db),
                 edus: rooms::edus::Service {
+                    presence: rooms::edus::presence::Service {
+                        db,
+                        last_active: StdMutex::new(HashMap::new()),
+                        last_written: StdMutex::new(HashMap::new()),
+                    },
                     read_receipt: db,
                     typing: rooms::edus::typing::Service {
                         typing: RwLock::new(BTreeMap::new()),
                         last_typing_update: RwLock::new(BTreeMap::new()),
                         typing_update_sender: broadcast::channel(100).0,
+                        expiries: StdMutex::new(
+                            rooms::edus::typing::ExpirySchedule::default(),
+                        ),
+                        expiry_scheduled: Notify::new(),
                     },
                 },
                 event_handler: rooms::event_handler::Service,
@@ -80,33 +95,32 @@ impl Services {
                     db,
                     lazy_load_waiting: Mutex::new(HashMap::new()),
                 },
-                metadata: db,
+                metadata: rooms::metadata::Service::new(db),
                 outlier: db,
                 pdu_metadata: rooms::pdu_metadata::Service {
                     db,
                 },
-                search: db,
+                search: rooms::search::Service {
+                    db,
+                },
                 short: db,
                 state: rooms::state::Service {
                     db,
                 },
                 state_accessor: rooms::state_accessor::Service {
                     db,
-                    #[allow(
-                        clippy::as_conversions,
-                        clippy::cast_sign_loss,
-                        clippy::cast_possible_truncation
-                    )]
                     server_visibility_cache: StdMutex::new(LruCache::new(
-                        (100.0 * config.cache_capacity_modifier) as usize,
+                        config.cache_capacity(
+                            config.cache.server_visibility,
+                            100,
+                        ),
                     )),
-                    #[allow(
-                        clippy::as_conversions,
-                        clippy::cast_sign_loss,
-                        clippy::cast_possible_truncation
-                    )]
                     user_visibility_cache: StdMutex::new(LruCache::new(
-                        (100.0 * config.cache_capacity_modifier) as usize,
+                        config
+                            .cache_capacity(config.cache.user_visibility, 100),
+                    )),
+                    server_acl_cache: StdMutex::new(LruCache::new(
+                        config.cache_capacity(config.cache.server_acl, 100),
                     )),
                 },
                 state_cache: rooms::state_cache::Service {
@@ -114,13 +128,8 @@ impl Services {
                 },
                 state_compressor: rooms::state_compressor::Service {
                     db,
-                    #[allow(
-                        clippy::as_conversions,
-                        clippy::cast_sign_loss,
-                        clippy::cast_possible_truncation
-                    )]
                     stateinfo_cache: StdMutex::new(LruCache::new(
-                        (100.0 * config.cache_capacity_modifier) as usize,
+                        config.cache_capacity(config.cache.stateinfo, 100),
                     )),
                 },
                 timeline: rooms::timeline::Service {
@@ -130,7 +139,9 @@ impl Services {
                     db,
                 },
                 spaces: rooms::spaces::Service {
-                    roomid_spacechunk_cache: Mutex::new(LruCache::new(200)),
+                    roomid_spacechunk_cache: Mutex::new(LruCache::new(
+                        config.cache.roomid_spacechunk.unwrap_or(200),
+                    )),
                 },
                 user: db,
             },
@@ -141,14 +152,37 @@ impl Services {
             users: users::Service {
                 db,
                 connections: StdMutex::new(BTreeMap::new()),
+                device_last_seen: StdMutex::new(HashMap::new()),
+                threepid_sessions: StdMutex::new(HashMap::new()),
+                threepid_lock: StdMutex::new(()),
+                login_tokens: StdMutex::new(HashMap::new()),
+                dehydrated_device_lock: StdMutex::new(()),
+                remote_device_lists: StdMutex::new(
+                    users::RemoteDeviceLists::new(config.cache_capacity(
+                        config.cache.remote_device_lists,
+                        1000,
+                    )),
+                ),
+            },
+            account_data: account_data::Service {
+                db,
+                ignored_users_cache: StdMutex::new(LruCache::new(
+                    config.cache_capacity(config.cache.ignored_users, 100),
+                )),
             },
-            account_data: db,
             admin: admin::Service::build(),
             key_backups: db,
             media: media::Service {
                 db,
+                remote_failures: StdMutex::new(HashMap::new()),
+                remote_cache_size: StdMutex::new(None),
+                budget_purge_running: AtomicBool::new(false),
+                usage_lock: StdMutex::new(()),
+                retention_positions: StdMutex::new(HashMap::new()),
             },
             sending: sending::Service::build(db, &config),
+            sso: sso::Service::new(),
+            url_preview: url_preview::Service::build(&config)?,
 
             globals: globals::Service::load(db, config, reload_handles)?,
         })
@@ -171,21 +205,68 @@ impl Services {
             .lock()
             .unwrap()
             .len();
+        let server_acl_cache =
+            self.rooms.state_accessor.server_acl_cache.lock().unwrap().len();
         let stateinfo_cache =
             self.rooms.state_compressor.stateinfo_cache.lock().unwrap().len();
         let roomid_spacechunk_cache =
             self.rooms.spaces.roomid_spacechunk_cache.lock().await.len();
+        let ignored_users_cache =
+            self.account_data.ignored_users_cache.lock().unwrap().len();
 
         format!(
             "\
 lazy_load_waiting: {lazy_load_waiting}
 server_visibility_cache: {server_visibility_cache}
 user_visibility_cache: {user_visibility_cache}
+server_acl_cache: {server_acl_cache}
 stateinfo_cache: {stateinfo_cache}
-roomid_spacechunk_cache: {roomid_spacechunk_cache}"
+roomid_spacechunk_cache: {roomid_spacechunk_cache}
+ignored_users_cache: {ignored_users_cache}"
         )
     }
 
+    /// Returns the name, number of entries and capacity of each LRU cache of
+    /// the services
+    pub(crate) async fn cache_sizes(
+        &self,
+    ) -> Vec<(&'static str, usize, usize)> {
+        fn size<K: Eq + Hash, V>(
+            name: &'static str,
+            cache: &StdMutex<LruCache<K, V>>,
+        ) -> (&'static str, usize, usize) {
+            let cache = cache.lock().unwrap();
+            (name, cache.len(), cache.capacity())
+        }
+
+        let state_accessor = &self.rooms.state_accessor;
+        let spacechunk_cache =
+            self.rooms.spaces.roomid_spacechunk_cache.lock().await;
+
+        vec![
+            size(
+                "server_visibility_cache",
+                &state_accessor.server_visibility_cache,
+            ),
+            size(
+                "user_visibility_cache",
+                &state_accessor.user_visibility_cache,
+            ),
+            size("server_acl_cache", &state_accessor.server_acl_cache),
+            size(
+                "stateinfo_cache",
+                &self.rooms.state_compressor.stateinfo_cache,
+            ),
+            (
+                "roomid_spacechunk_cache",
+                spacechunk_cache.len(),
+                spacechunk_cache.capacity(),
+            ),
+            size("ignored_users_cache", &self.account_data.ignored_users_cache),
+            size("url_preview_cache", &self.url_preview.cache),
+        ]
+    }
+
     async fn clear_caches(&self, amount: u32) {
         if amount > 0 {
             self.rooms.lazy_loading.lazy_load_waiting.lock().await.clear();
@@ -212,5 +293,11 @@ roomid_spacechunk_cache: {roomid_spacechunk_cache}"
         if amount > 5 {
             self.rooms.spaces.roomid_spacechunk_cache.lock().await.clear();
         }
+        if amount > 6 {
+            self.rooms.state_accessor.server_acl_cache.lock().unwrap().clear();
+        }
+        if amount > 7 {
+            self.account_data.ignored_users_cache.lock().unwrap().clear();
+        }
     }
 }
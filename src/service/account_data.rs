@@ -1,4 +1,127 @@
 mod data;
 
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex as StdMutex},
+};
+
 pub(crate) use data::Data;
-pub(crate) type Service = &'static dyn Data;
+use lru_cache::LruCache;
+use ruma::{
+    events::{
+        ignored_user_list::IgnoredUserListEvent, AnyEphemeralRoomEvent,
+        GlobalAccountDataEventType, RoomAccountDataEventType,
+    },
+    serde::Raw,
+    OwnedUserId, RoomId, UserId,
+};
+use tracing::warn;
+
+use crate::{Error, Result};
+
+pub(crate) struct Service {
+    pub(crate) db: &'static dyn Data,
+
+    /// Users ignored by each user, as listed in their `m.ignored_user_list`
+    pub(crate) ignored_users_cache:
+        StdMutex<LruCache<OwnedUserId, Arc<HashSet<OwnedUserId>>>>,
+}
+
+impl Service {
+    /// Places one event in the account data of the user and removes the
+    /// previous entry.
+    #[tracing::instrument(skip(self, data))]
+    pub(crate) fn update(
+        &self,
+        room_id: Option<&RoomId>,
+        user_id: &UserId,
+        event_type: RoomAccountDataEventType,
+        data: &serde_json::Value,
+    ) -> Result<()> {
+        let ignore_list_changed = room_id.is_none()
+            && event_type.to_string()
+                == GlobalAccountDataEventType::IgnoredUserList.to_string();
+
+        self.db.update(room_id, user_id, event_type, data)?;
+
+        if ignore_list_changed {
+            self.ignored_users_cache.lock().unwrap().remove(user_id);
+        }
+
+        Ok(())
+    }
+
+    /// Searches the account data for a specific kind.
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn get(
+        &self,
+        room_id: Option<&RoomId>,
+        user_id: &UserId,
+        kind: RoomAccountDataEventType,
+    ) -> Result<Option<Box<serde_json::value::RawValue>>> {
+        self.db.get(room_id, user_id, kind)
+    }
+
+    /// Returns all changes to the account data that happened after `since`.
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn changes_since(
+        &self,
+        room_id: Option<&RoomId>,
+        user_id: &UserId,
+        since: u64,
+    ) -> Result<HashMap<RoomAccountDataEventType, Raw<AnyEphemeralRoomEvent>>>
+    {
+        self.db.changes_since(room_id, user_id, since)
+    }
+
+    /// Returns the users `user_id` ignores
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn ignored_users(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Arc<HashSet<OwnedUserId>>> {
+        if let Some(ignored) =
+            self.ignored_users_cache.lock().unwrap().get_mut(user_id)
+        {
+            return Ok(Arc::clone(ignored));
+        }
+
+        let event_kind = RoomAccountDataEventType::from(
+            GlobalAccountDataEventType::IgnoredUserList.to_string(),
+        );
+        let ignored = self
+            .db
+            .get(None, user_id, event_kind.clone())?
+            .map(|event| {
+                serde_json::from_str::<IgnoredUserListEvent>(event.get())
+                    .map_err(|error| {
+                        warn!(
+                            %error,
+                            %event_kind,
+                            "Invalid account data event",
+                        );
+                        Error::BadDatabase("Invalid account data event.")
+                    })
+            })
+            .transpose()?
+            .map(|event| event.content.ignored_users.into_keys().collect())
+            .unwrap_or_default();
+
+        let ignored = Arc::new(ignored);
+        self.ignored_users_cache
+            .lock()
+            .unwrap()
+            .insert(user_id.to_owned(), Arc::clone(&ignored));
+
+        Ok(ignored)
+    }
+
+    /// Checks whether `user_id` ignores `sender`
+    pub(crate) fn is_ignored(
+        &self,
+        user_id: &UserId,
+        sender: &UserId,
+    ) -> Result<bool> {
+        Ok(self.ignored_users(user_id)?.contains(sender))
+    }
+}
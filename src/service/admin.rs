@@ -1,9 +1,16 @@
-use std::{collections::BTreeMap, fmt::Write, sync::Arc, time::Instant};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt::Write,
+    path::{Path, PathBuf},
+    sync::{atomic, Arc},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use clap::{Parser, ValueEnum};
+use humantime_serde::re::humantime;
 use regex::Regex;
 use ruma::{
-    api::appservice::Registration,
+    api::{appservice::Registration, client::error::ErrorKind},
     events::{
         push_rules::{PushRulesEvent, PushRulesEventContent},
         room::{
@@ -20,19 +27,36 @@ use ruma::{
             power_levels::RoomPowerLevelsEventContent,
             topic::RoomTopicEventContent,
         },
-        TimelineEventType,
+        tag::{TagEvent, TagEventContent, TagInfo, TagName},
+        RoomAccountDataEventType, TimelineEventType,
     },
     signatures::verify_json,
-    EventId, MilliSecondsSinceUnixEpoch, OwnedRoomId, RoomId, RoomVersionId,
-    ServerName, UserId,
+    CanonicalJsonObject, EventId, MilliSecondsSinceUnixEpoch, OwnedRoomId,
+    OwnedServerName, RoomId, RoomVersionId, ServerName, UserId,
+};
+use serde_json::{
+    json,
+    value::{to_raw_value, RawValue as RawJsonValue},
 };
-use serde_json::value::to_raw_value;
-use tokio::sync::{mpsc, Mutex, RwLock};
-use tracing::warn;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
+    sync::{mpsc, Mutex, RwLock},
+};
+use tracing::{debug, info, warn};
 
-use super::pdu::PduBuilder;
+use super::{
+    globals::SigningKeys,
+    pdu::{gen_event_id_canonical_json, PduBuilder},
+    rooms::timeline::PduCount,
+};
 use crate::{
-    api::client_server::{leave_all_rooms, AUTO_GEN_PASSWORD_LENGTH},
+    api::{
+        client_server::{deactivate_user, AUTO_GEN_PASSWORD_LENGTH},
+        server_server::parse_incoming_pdu,
+    },
+    config::EnvFilterClone,
+    observability::{FilterReloadHandle, METRICS},
     services,
     utils::{self, dbg_truncate_str},
     Error, PduEvent, Result,
@@ -86,6 +110,10 @@ enum AdminCommand {
     DeactivateUser {
         #[arg(short, long)]
         leave_rooms: bool,
+        /// Also redact the messages the user sent, which happens in the
+        /// background
+        #[arg(long)]
+        erase: bool,
         user_id: Box<UserId>,
     },
 
@@ -141,9 +169,95 @@ enum AdminCommand {
         event_id: Box<EventId>,
     },
 
+    /// Print everything the database knows about an event
+    ///
+    /// Shows whether the event is an outlier or soft-failed, the state hash
+    /// before it and its full auth chain.
+    ShowEvent {
+        /// An event ID (a $ followed by the base64 reference hash)
+        event_id: Box<EventId>,
+    },
+
+    /// Write the PDUs of a room to a file as newline-delimited JSON
+    ///
+    /// The auth chains of all exported events are included, so the export is
+    /// self-contained. Each event comes after the events of its auth chain,
+    /// and events are in the federation format.
+    ExportRoom {
+        room_id: Box<RoomId>,
+        /// The file to write to, which must not exist yet
+        path: PathBuf,
+        /// Also export the current state of the room, including state events
+        /// that aren't part of the timeline
+        #[arg(long)]
+        state: bool,
+    },
+
+    /// Import the events of a room from a file written by `export-room`
+    ///
+    /// Events go through the same checks as events received over federation,
+    /// as if they were sent by the server of their sender. Events that fail
+    /// these checks are skipped. If the room is unknown to this server, the
+    /// file has to start with its create event.
+    ///
+    /// Only available if `allow_room_import` is enabled in the config.
+    ImportRoom {
+        /// The file to read from
+        path: PathBuf,
+    },
+
+    /// List federation destinations that are being backed off from after
+    /// failed transactions
+    ListBackoffs,
+
     /// Print database memory usage statistics
     MemoryUsage,
 
+    /// Write a consistent snapshot of the database while the server keeps
+    /// running
+    ///
+    /// RocksDB databases are written as a checkpoint directory, which
+    /// hard-links files if the path is on the same filesystem as the
+    /// database. SQLite databases are written as a single file using the
+    /// online backup API. How to restore the backup is part of the output.
+    BackupDatabase {
+        /// Where to write the backup, which must not exist yet
+        path: PathBuf,
+    },
+
+    /// Compact the database to reclaim disk space, e.g. after purging rooms
+    ///
+    /// RocksDB compacts the given tree or all trees, SQLite databases are
+    /// vacuumed as a whole. Vacuuming blocks all writes to the database until
+    /// it finishes, which can take minutes for large databases. Only one
+    /// compaction can run at a time.
+    CompactDatabase {
+        /// The tree to compact, all trees if omitted
+        tree: Option<String>,
+    },
+
+    /// Check the database for corruption without modifying it
+    ///
+    /// RocksDB checksums and SQLite's `PRAGMA integrity_check` are verified,
+    /// as well as references between trees, e.g. that the PDU of each event
+    /// ID in the timeline exists. This can run while the server is in use.
+    CheckDatabase,
+
+    /// Find short event IDs and short state keys that are no longer
+    /// referenced by any state, auth chain or stored event
+    ///
+    /// Short IDs created or looked up while this runs are kept. Nothing is
+    /// removed unless `--apply` is passed, which briefly blocks event
+    /// processing while the short IDs are removed.
+    GcShortids {
+        /// Remove the unreferenced short IDs
+        #[arg(long)]
+        apply: bool,
+    },
+
+    /// Print the size of each cache and how often lookups hit a cache
+    CacheStats,
+
     /// Clears all of Grapevine's database caches with index smaller than the
     /// amount
     ClearDatabaseCaches {
@@ -170,6 +284,15 @@ enum AdminCommand {
         password: Option<String>,
     },
 
+    /// Replace the push rules of a local user with the ones new accounts are
+    /// provisioned with
+    ///
+    /// This discards all rules the user configured, including changes to
+    /// server default rules.
+    ResetPushRules {
+        user_id: Box<UserId>,
+    },
+
     /// Disables incoming federation handling for a room.
     DisableRoom {
         room_id: Box<RoomId>,
@@ -179,6 +302,130 @@ enum AdminCommand {
         room_id: Box<RoomId>,
     },
 
+    /// List all rooms with disabled federation handling
+    ListDisabledRooms,
+
+    /// Override how many missing prev events are fetched at most for an
+    /// incoming event of a room
+    ///
+    /// Raising the limit can fill gaps in the history of large rooms, but
+    /// handling an event may then take much longer. Without a limit,
+    /// `federation.max_fetch_prev_events` applies to the room again.
+    SetMaxFetchPrevEvents {
+        room_id: Box<RoomId>,
+        limit: Option<u16>,
+    },
+
+    /// Allow a server to join a room over federation
+    ///
+    /// Once any server is allowed, only allowed servers can join or knock on
+    /// the room through this server, until the restrictions are cleared. This
+    /// is checked in addition to the room's ACL and also applies to servers
+    /// without members in the room yet.
+    AllowServerInRoom {
+        room_id: Box<RoomId>,
+        server_name: Box<ServerName>,
+    },
+
+    /// Stop a server from joining a room over federation
+    ///
+    /// Members the server already has stay in the room.
+    DenyServerInRoom {
+        room_id: Box<RoomId>,
+        server_name: Box<ServerName>,
+    },
+
+    /// Let all servers join a room over federation again
+    ClearServerRestrictionsInRoom {
+        room_id: Box<RoomId>,
+    },
+
+    /// List the forward extremities of a room, the latest events no other
+    /// event references yet
+    ShowExtremities {
+        room_id: Box<RoomId>,
+    },
+
+    /// Drop the forward extremities of a room that reference events this
+    /// server doesn't have
+    ///
+    /// Such extremities can be left behind by servers that went offline
+    /// before this server fetched the events they reference, and new events
+    /// keep referencing them. Dropping them is risky: their branch of the
+    /// room history is no longer continued by this server, and the current
+    /// state is not recomputed (see `resolve-state`). At least one
+    /// extremity is always kept.
+    ///
+    /// Without --confirm, the extremities that would be dropped are only
+    /// listed.
+    PruneExtremities {
+        room_id: Box<RoomId>,
+        /// Actually drop the extremities
+        #[arg(long)]
+        confirm: bool,
+    },
+
+    /// Resolve the state of a room at its forward extremities again and make
+    /// the result its current state
+    ///
+    /// This repairs rooms whose state drifted, e.g. after a federation
+    /// incident.
+    ResolveState {
+        room_id: Box<RoomId>,
+    },
+
+    /// Rebuild the index used for searching messages from the timeline
+    ///
+    /// All rooms are reindexed if no room is given.
+    ReindexSearch {
+        room_id: Option<Box<RoomId>>,
+    },
+
+    /// Reject new client requests, allowing in-flight requests to finish
+    /// before stopping the server
+    ///
+    /// Federation requests are still accepted, and so are messages to the
+    /// admin room so that the drain can be undone with `undrain`.
+    Drain,
+
+    /// Accept new requests again after draining
+    Undrain,
+
+    /// Delete remote media that was fetched before the given duration ago,
+    /// as well as files in the media directory that are no longer tracked
+    ///
+    /// Media used as avatars or referenced by events from the last week is
+    /// kept.
+    PurgeMedia {
+        /// Minimum age of the media to delete, e.g. `30d`
+        #[arg(long, value_parser = humantime::parse_duration)]
+        before: Duration,
+    },
+
+    /// Set the media quota of a local user in bytes
+    ///
+    /// Without a quota, the override is removed and the default quota from
+    /// the config applies again.
+    SetMediaQuota {
+        user_id: Box<UserId>,
+        quota: Option<u64>,
+    },
+
+    /// Show how much media a local user uploaded and their media quota
+    ShowMediaUsage {
+        user_id: Box<UserId>,
+    },
+
+    /// Send a server notice to every local user
+    ///
+    /// Each user gets a room for server notices, which is created when the
+    /// first notice is sent. Users who left it are invited back.
+    Announce {
+        /// The message to send
+        #[arg(trailing_var_arg = true, required = true)]
+        message: Vec<String>,
+    },
+
     /// Verify json signatures
     /// [commandbody]()
     /// # ```
@@ -197,11 +444,67 @@ enum AdminCommand {
     #[allow(clippy::doc_markdown)]
     VerifyJson,
 
+    /// List the keys this server signs events and requests with, including
+    /// the ones it used before rotating them
+    ShowSigningKeys,
+
+    /// Replace the signing key of this server with a newly generated one
+    ///
+    /// The old key keeps being advertised as an old verify key, so that
+    /// other servers can still verify events signed with it. Rotate the key
+    /// if it may have been leaked.
+    RotateSigningKey,
+
+    /// List the servers signing keys of other servers are fetched from
+    ListTrustedServers,
+
+    /// Fetch signing keys of other servers from the given server
+    ///
+    /// The change is kept across restarts and takes precedence over
+    /// `federation.trusted_servers`.
+    AddTrustedServer {
+        server_name: Box<ServerName>,
+    },
+
+    /// Stop fetching signing keys of other servers from the given server
+    ///
+    /// The change is kept across restarts and takes precedence over
+    /// `federation.trusted_servers`.
+    RemoveTrustedServer {
+        server_name: Box<ServerName>,
+    },
+
+    /// List the servers that are allowed and denied federation
+    ListFederationPolicy,
+
+    /// Allow federation with the given server
+    ///
+    /// Removes it from the denied servers and adds it to the allowed servers
+    /// if `federation.allowed_servers` is set. The change is kept across
+    /// restarts and takes precedence over the config.
+    AllowFederationWith {
+        server_name: Box<ServerName>,
+    },
+
+    /// Stop federating with the given server
+    ///
+    /// Requests from the server are rejected and nothing is sent to it
+    /// anymore. The change is kept across restarts and takes precedence over
+    /// the config.
+    DenyFederationWith {
+        server_name: Box<ServerName>,
+    },
+
     /// Dynamically change a tracing backend's filter string
     SetTracingFilter {
         backend: TracingBackend,
         filter: String,
     },
+
+    /// Show a tracing backend's current filter string
+    GetTracingFilter {
+        backend: TracingBackend,
+    },
 }
 
 #[derive(Debug)]
@@ -222,6 +525,255 @@ enum TracingBackend {
     Traces,
 }
 
+impl TracingBackend {
+    fn reload_handle(&self) -> Option<&'static FilterReloadHandle> {
+        let handles = &services().globals.reload_handles;
+        match self {
+            TracingBackend::Log => handles.log.as_ref(),
+            TracingBackend::Flame => handles.flame.as_ref(),
+            TracingBackend::Traces => handles.traces.as_ref(),
+        }
+    }
+}
+
+/// Writes events to the file of `export-room`, skipping events that were
+/// already written
+struct RoomExporter<'a> {
+    room_id: &'a RoomId,
+    file: BufWriter<File>,
+    /// IDs of the events that were already written
+    written: HashSet<Arc<EventId>>,
+}
+
+impl RoomExporter<'_> {
+    /// Writes the auth chain of `event_ids` followed by the events themselves
+    ///
+    /// The auth chain is sorted by depth so that its events come after their
+    /// own auth events.
+    async fn write_with_auth_chain(
+        &mut self,
+        event_ids: Vec<Arc<EventId>>,
+    ) -> Result<()> {
+        let mut auth_chain = Vec::new();
+        for event_id in services()
+            .rooms
+            .auth_chain
+            .get_auth_chain(self.room_id, event_ids.clone())
+            .await?
+        {
+            if self.written.contains(&event_id) {
+                continue;
+            }
+            if let Some(pdu) = services().rooms.timeline.get_pdu(&event_id)? {
+                auth_chain.push((pdu.depth, event_id));
+            }
+        }
+        auth_chain.sort_unstable();
+
+        for (_, event_id) in auth_chain {
+            self.write(event_id).await?;
+        }
+        for event_id in event_ids {
+            self.write(event_id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes an event as one line of JSON, unless it was already written
+    async fn write(&mut self, event_id: Arc<EventId>) -> Result<()> {
+        if self.written.contains(&event_id) {
+            return Ok(());
+        }
+
+        let Some(pdu_json) =
+            services().rooms.timeline.get_pdu_json(&event_id)?
+        else {
+            warn!(%event_id, "Event to export not found");
+            return Ok(());
+        };
+        let pdu_json = PduEvent::convert_to_outgoing_federation_event(pdu_json);
+
+        self.file.write_all(pdu_json.get().as_bytes()).await?;
+        self.file.write_all(b"\n").await?;
+        self.written.insert(event_id);
+
+        Ok(())
+    }
+}
+
+/// Imports one event of an `import-room` file as if it was received from the
+/// server of its sender, returning the room it belongs to
+async fn import_pdu(
+    line: &str,
+    pub_key_map: &RwLock<BTreeMap<String, SigningKeys>>,
+) -> Result<OwnedRoomId> {
+    let invalid = || Error::BadRequest(ErrorKind::InvalidParam, "Invalid PDU.");
+
+    let pdu = serde_json::from_str::<Box<RawJsonValue>>(line)
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid JSON."))?;
+    let value: CanonicalJsonObject =
+        serde_json::from_str(pdu.get()).map_err(|_| invalid())?;
+
+    let room_id = value
+        .get("room_id")
+        .and_then(|id| RoomId::parse(id.as_str()?).ok())
+        .ok_or_else(invalid)?;
+    let origin = value
+        .get("sender")
+        .and_then(|id| UserId::parse(id.as_str()?).ok())
+        .map(|sender| sender.server_name().to_owned())
+        .ok_or_else(invalid)?;
+
+    let federation_token = services()
+        .globals
+        .roomid_mutex_federation
+        .lock_key(room_id.clone())
+        .await;
+
+    if services().rooms.metadata.exists(&room_id)? {
+        let (event_id, value, _) = parse_incoming_pdu(&pdu)?;
+        services()
+            .rooms
+            .event_handler
+            .handle_incoming_pdu(
+                &origin,
+                &event_id,
+                &room_id,
+                value,
+                true,
+                pub_key_map,
+            )
+            .await?;
+    } else {
+        import_create_event(&origin, &room_id, &pdu, &value, pub_key_map)
+            .await?;
+    }
+
+    drop(federation_token);
+
+    Ok(room_id)
+}
+
+/// Makes the create event of a room that's unknown to this server the first
+/// event of the room, so that the rest of an import can be handled like
+/// federation traffic
+async fn import_create_event(
+    origin: &ServerName,
+    room_id: &RoomId,
+    pdu: &RawJsonValue,
+    value: &CanonicalJsonObject,
+    pub_key_map: &RwLock<BTreeMap<String, SigningKeys>>,
+) -> Result<()> {
+    let not_create = || {
+        Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Room is unknown and the event is not its create event.",
+        )
+    };
+
+    let room_version_id = value
+        .get("content")
+        .and_then(|content| {
+            serde_json::from_value::<RoomCreateEventContent>(
+                content.clone().into(),
+            )
+            .ok()
+        })
+        .ok_or_else(not_create)?
+        .room_version;
+    let (event_id, value) = gen_event_id_canonical_json(pdu, &room_version_id)
+        .map_err(|_| not_create())?;
+    let create_event = PduEvent::from_id_val(&event_id, value.clone())
+        .map_err(|_| not_create())?;
+    if create_event.kind != TimelineEventType::RoomCreate
+        || create_event.state_key.as_deref() != Some("")
+    {
+        return Err(not_create());
+    }
+
+    let (pdu, value) = services()
+        .rooms
+        .event_handler
+        .handle_outlier_pdu(
+            origin,
+            &create_event,
+            &event_id,
+            room_id,
+            value,
+            false,
+            pub_key_map,
+        )
+        .await?;
+
+    services().rooms.short.get_or_create_shortroomid(room_id)?;
+    let room_token = services()
+        .globals
+        .roomid_mutex_state
+        .lock_key(room_id.to_owned())
+        .await;
+
+    // Same order as when joining a room: the state is updated before and
+    // after appending the PDU so it never refers to a missing event.
+    let shortstatehash = services().rooms.state.append_to_state(&pdu)?;
+    services()
+        .rooms
+        .timeline
+        .append_pdu(&pdu, value, vec![event_id], &room_token)
+        .await?;
+    services().rooms.state.set_room_state(&room_token, shortstatehash)?;
+
+    Ok(())
+}
+
+/// Formats a timestamp for admin room messages
+fn format_timestamp(ts: MilliSecondsSinceUnixEpoch) -> String {
+    ts.to_system_time().map_or_else(
+        || format!("{} ms", ts.get()),
+        |ts| humantime::format_rfc3339_seconds(ts).to_string(),
+    )
+}
+
+/// Counts the prev events of an event that aren't in the timeline of this
+/// server
+fn missing_prev_events(pdu: &PduEvent) -> Result<usize> {
+    let mut missing = 0;
+    for prev_event in &pdu.prev_events {
+        if services().rooms.timeline.get_pdu_id(prev_event)?.is_none() {
+            missing += 1;
+        }
+    }
+
+    Ok(missing)
+}
+
+/// Returns the size of a file, or of all files in a directory
+fn disk_usage(path: &Path) -> std::io::Result<u64> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        size += disk_usage(&entry?.path())?;
+    }
+
+    Ok(size)
+}
+
+/// Formats a size returned by [`disk_usage`] in MiB
+fn format_size(size: std::io::Result<u64>) -> String {
+    match size {
+        Ok(size) => {
+            #[allow(clippy::as_conversions, clippy::cast_precision_loss)]
+            let size = size as f64 / 1024.0 / 1024.0;
+            format!("{size:.1} MiB")
+        }
+        Err(error) => format!("unknown size ({error})"),
+    }
+}
+
 impl Service {
     pub(crate) fn build() -> Arc<Self> {
         let (sender, receiver) = mpsc::unbounded_channel();
@@ -640,6 +1192,45 @@ impl Service {
                     }
                 }
             }
+            AdminCommand::ShowEvent {
+                event_id,
+            } => self.show_event(event_id.into()).await?,
+            AdminCommand::ExportRoom {
+                room_id,
+                path,
+                state,
+            } => self.export_room(&room_id, &path, state).await?,
+            AdminCommand::ImportRoom {
+                path,
+            } => self.import_room(&path).await?,
+            AdminCommand::ListBackoffs => {
+                let now = SystemTime::now();
+                let backoffs = services().sending.backoffs()?;
+                let lines = backoffs
+                    .iter()
+                    .map(|(server_name, backoff)| {
+                        match backoff.next_retry.duration_since(now) {
+                            Ok(remaining) => format!(
+                                "{server_name}: {} failure(s), retrying in {}",
+                                backoff.failures,
+                                humantime::format_duration(
+                                    Duration::from_secs(remaining.as_secs())
+                                )
+                            ),
+                            Err(_) => format!(
+                                "{server_name}: {} failure(s), retrying with \
+                                 the next event",
+                                backoff.failures
+                            ),
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                RoomMessageEventContent::text_plain(format!(
+                    "Found {} backed off destination(s):\n{}",
+                    lines.len(),
+                    lines.join("\n")
+                ))
+            }
             AdminCommand::MemoryUsage => {
                 let response1 = services().memory_usage().await;
                 let response2 = services().globals.db.memory_usage();
@@ -648,6 +1239,44 @@ impl Service {
                     "Services:\n{response1}\n\nDatabase:\n{response2}"
                 ))
             }
+            AdminCommand::BackupDatabase {
+                path,
+            } => self.backup_database(path).await?,
+            AdminCommand::CompactDatabase {
+                tree,
+            } => self.compact_database(tree).await?,
+            AdminCommand::CheckDatabase => self.check_database().await?,
+            AdminCommand::GcShortids {
+                apply,
+            } => self.gc_shortids(apply).await?,
+            AdminCommand::CacheStats => {
+                let mut message = String::from("Cache sizes:\n");
+                for (name, len, capacity) in services()
+                    .cache_sizes()
+                    .await
+                    .into_iter()
+                    .chain(services().globals.db.cache_sizes())
+                {
+                    writeln!(message, "{name}: {len} / {capacity}")
+                        .expect("write to in-memory buffer should succeed");
+                }
+
+                message.push_str("\nCache hits since startup:\n");
+                for (lookup, hits, total) in METRICS.lookup_totals() {
+                    if total == 0 {
+                        continue;
+                    }
+                    writeln!(
+                        message,
+                        "{}: {}% of {total} lookups",
+                        <&str>::from(lookup),
+                        hits * 100 / total
+                    )
+                    .expect("write to in-memory buffer should succeed");
+                }
+
+                RoomMessageEventContent::text_plain(message)
+            }
             AdminCommand::ClearDatabaseCaches {
                 amount,
             } => {
@@ -763,9 +1392,9 @@ impl Service {
                         .into(),
                     &serde_json::to_value(PushRulesEvent {
                         content: PushRulesEventContent {
-                            global: ruma::push::Ruleset::server_default(
-                                &user_id,
-                            ),
+                            global: services()
+                                .globals
+                                .default_push_rules(&user_id),
                         },
                     })
                     .expect("to json value always works"),
@@ -780,47 +1409,428 @@ impl Service {
                      {password}"
                 ))
             }
+            AdminCommand::ResetPushRules {
+                user_id,
+            } => {
+                if user_id.server_name() != services().globals.server_name() {
+                    return Ok(RoomMessageEventContent::text_plain(
+                        "The specified user is not from this server!",
+                    ));
+                };
+
+                if !services().users.exists(&user_id)? {
+                    return Ok(RoomMessageEventContent::text_plain(
+                        "The specified user does not exist!",
+                    ));
+                }
+
+                services().account_data.update(
+                    None,
+                    &user_id,
+                    ruma::events::GlobalAccountDataEventType::PushRules
+                        .to_string()
+                        .into(),
+                    &serde_json::to_value(PushRulesEvent {
+                        content: PushRulesEventContent {
+                            global: services()
+                                .globals
+                                .default_push_rules(&user_id),
+                        },
+                    })
+                    .expect("to json value always works"),
+                )?;
+
+                RoomMessageEventContent::text_plain(format!(
+                    "Reset the push rules of {user_id}."
+                ))
+            }
             AdminCommand::DisableRoom {
                 room_id,
             } => {
-                services().rooms.metadata.disable_room(&room_id, true)?;
-                RoomMessageEventContent::text_plain("Room disabled.")
+                if services().rooms.metadata.is_disabled(&room_id)? {
+                    RoomMessageEventContent::text_plain(
+                        "Room is already disabled.",
+                    )
+                } else {
+                    services().rooms.metadata.disable_room(&room_id, true)?;
+                    RoomMessageEventContent::text_plain("Room disabled.")
+                }
             }
             AdminCommand::EnableRoom {
                 room_id,
             } => {
-                services().rooms.metadata.disable_room(&room_id, false)?;
-                RoomMessageEventContent::text_plain("Room enabled.")
+                if services().rooms.metadata.is_disabled(&room_id)? {
+                    services().rooms.metadata.disable_room(&room_id, false)?;
+                    RoomMessageEventContent::text_plain("Room enabled.")
+                } else {
+                    RoomMessageEventContent::text_plain(
+                        "Room is already enabled.",
+                    )
+                }
             }
-            AdminCommand::DeactivateUser {
-                leave_rooms,
-                user_id,
+            AdminCommand::ShowExtremities {
+                room_id,
             } => {
-                let user_id = Arc::<UserId>::from(user_id);
-                if !services().users.exists(&user_id)? {
-                    RoomMessageEventContent::text_plain(format!(
-                        "User {user_id} doesn't exist on this server"
-                    ))
-                } else if user_id.server_name()
-                    != services().globals.server_name()
+                let extremities =
+                    services().rooms.state.get_forward_extremities(&room_id)?;
+
+                let mut message =
+                    format!("{} forward extremities:\n", extremities.len());
+                for event_id in extremities {
+                    let Some(pdu) =
+                        services().rooms.timeline.get_pdu(&event_id)?
+                    else {
+                        writeln!(message, "{event_id}: unknown event")
+                            .expect("write to in-memory buffer should succeed");
+                        continue;
+                    };
+                    writeln!(
+                        message,
+                        "{event_id}: depth {}, origin {}, {} missing prev \
+                         events",
+                        pdu.depth,
+                        pdu.sender.server_name(),
+                        missing_prev_events(&pdu)?,
+                    )
+                    .expect("write to in-memory buffer should succeed");
+                }
+                RoomMessageEventContent::text_plain(message)
+            }
+            AdminCommand::PruneExtremities {
+                room_id,
+                confirm,
+            } => {
+                let room_token = services()
+                    .globals
+                    .roomid_mutex_state
+                    .lock_key(room_id.into())
+                    .await;
+
+                let mut keep = Vec::new();
+                let mut prune = Vec::new();
+                for event_id in services()
+                    .rooms
+                    .state
+                    .get_forward_extremities(&room_token)?
                 {
+                    let reachable =
+                        match services().rooms.timeline.get_pdu(&event_id)? {
+                            Some(pdu) => missing_prev_events(&pdu)? == 0,
+                            None => false,
+                        };
+                    if reachable {
+                        keep.push(event_id);
+                    } else {
+                        prune.push(event_id);
+                    }
+                }
+
+                let list = prune
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                if prune.is_empty() {
+                    RoomMessageEventContent::text_plain(
+                        "No forward extremities reference unknown events.",
+                    )
+                } else if keep.is_empty() {
+                    RoomMessageEventContent::text_plain(
+                        "All forward extremities reference unknown events, \
+                         refusing to drop all of them.",
+                    )
+                } else if confirm {
+                    services().rooms.state.set_forward_extremities(
+                        &room_token,
+                        keep.iter().map(|id| id.as_ref().to_owned()).collect(),
+                    )?;
+                    warn!(
+                        room_id = %*room_token,
+                        dropped = prune.len(),
+                        "Dropped forward extremities"
+                    );
                     RoomMessageEventContent::text_plain(format!(
-                        "User {user_id} is not from this server"
+                        "Dropped {} forward extremities:\n{list}",
+                        prune.len()
                     ))
                 } else {
                     RoomMessageEventContent::text_plain(format!(
-                        "Making {user_id} leave all rooms before \
-                         deactivation..."
-                    ));
-
-                    services().users.deactivate_account(&user_id)?;
-
-                    if leave_rooms {
-                        leave_all_rooms(&user_id).await?;
-                    }
+                        "Would drop {} of {} forward \
+                         extremities:\n{list}\n\nRun the command again with \
+                         --confirm to drop them.",
+                        prune.len(),
+                        prune.len() + keep.len()
+                    ))
+                }
+            }
+            AdminCommand::ResolveState {
+                room_id,
+            } => {
+                let room_token = services()
+                    .globals
+                    .roomid_mutex_state
+                    .lock_key(room_id.into())
+                    .await;
+                let (added, removed) = services()
+                    .rooms
+                    .event_handler
+                    .recompute_room_state(&room_token)
+                    .await?;
+                drop(room_token);
+
+                if added.is_empty() && removed.is_empty() {
+                    RoomMessageEventContent::text_plain(
+                        "The state of the room is unchanged.",
+                    )
+                } else {
+                    let mut message = format!(
+                        "Added {} and removed {} state events:\n",
+                        added.len(),
+                        removed.len()
+                    );
+                    for (change, event_ids) in [('+', added), ('-', removed)] {
+                        for event_id in event_ids {
+                            let key = services()
+                                .rooms
+                                .timeline
+                                .get_pdu(&event_id)?
+                                .map(|pdu| {
+                                    format!(
+                                        "{} {:?} ",
+                                        pdu.kind,
+                                        pdu.state_key
+                                            .as_deref()
+                                            .unwrap_or_default()
+                                    )
+                                })
+                                .unwrap_or_default();
+                            writeln!(message, "{change} {key}{event_id}")
+                                .expect(
+                                    "write to in-memory buffer should succeed",
+                                );
+                        }
+                    }
+                    RoomMessageEventContent::text_plain(message)
+                }
+            }
+            AdminCommand::ReindexSearch {
+                room_id,
+            } => {
+                if let Some(room_id) = room_id {
+                    let indexed =
+                        services().rooms.search.reindex_room(&room_id)?;
+                    RoomMessageEventContent::text_plain(format!(
+                        "Reindexed {indexed} messages of {room_id}."
+                    ))
+                } else {
+                    let mut rooms = 0;
+                    let mut indexed = 0;
+                    for room_id in services().rooms.metadata.iter_ids() {
+                        indexed +=
+                            services().rooms.search.reindex_room(&room_id?)?;
+                        rooms += 1;
+                    }
+                    RoomMessageEventContent::text_plain(format!(
+                        "Reindexed {indexed} messages in {rooms} rooms."
+                    ))
+                }
+            }
+            AdminCommand::Drain => {
+                services()
+                    .globals
+                    .draining
+                    .store(true, atomic::Ordering::Relaxed);
+                RoomMessageEventContent::text_plain(
+                    "Server is draining, new requests are rejected.",
+                )
+            }
+            AdminCommand::Undrain => {
+                services()
+                    .globals
+                    .draining
+                    .store(false, atomic::Ordering::Relaxed);
+                RoomMessageEventContent::text_plain(
+                    "Server is accepting new requests again.",
+                )
+            }
+            AdminCommand::PurgeMedia {
+                before,
+            } => {
+                let before =
+                    SystemTime::now().checked_sub(before).unwrap_or(UNIX_EPOCH);
+                let stats = services().media.purge(before, None).await?;
+                RoomMessageEventContent::text_plain(format!(
+                    "Deleted {} remote file(s) and {} orphaned file(s), \
+                     freeing {} bytes.",
+                    stats.files, stats.orphans, stats.bytes
+                ))
+            }
+            AdminCommand::SetMediaQuota {
+                user_id,
+                quota,
+            } => {
+                if user_id.server_name() != services().globals.server_name() {
+                    return Ok(RoomMessageEventContent::text_plain(
+                        "The specified user is not from this server!",
+                    ));
+                };
+
+                services().media.set_quota(&user_id, quota)?;
+
+                match services().media.quota(&user_id)? {
+                    Some(quota) => RoomMessageEventContent::text_plain(
+                        format!("Media quota of {user_id} is {quota} bytes."),
+                    ),
+                    None => RoomMessageEventContent::text_plain(format!(
+                        "Media quota of {user_id} is unlimited."
+                    )),
+                }
+            }
+            AdminCommand::ShowMediaUsage {
+                user_id,
+            } => {
+                if user_id.server_name() != services().globals.server_name() {
+                    return Ok(RoomMessageEventContent::text_plain(
+                        "The specified user is not from this server!",
+                    ));
+                };
+
+                let usage = services().media.usage(&user_id)?;
+                let quota = services().media.quota(&user_id)?.map_or_else(
+                    || "unlimited".to_owned(),
+                    |quota| format!("{quota} bytes"),
+                );
+
+                RoomMessageEventContent::text_plain(format!(
+                    "{user_id} uploaded {usage} bytes of media, their quota \
+                     is {quota}."
+                ))
+            }
+            AdminCommand::Announce {
+                message,
+            } => {
+                let message = message.join(" ");
+                let mut sent = 0_usize;
+                let mut failed = 0_usize;
+
+                for user_id in services().users.iter() {
+                    let user_id = user_id?;
+                    if user_id.server_name() != services().globals.server_name()
+                        || user_id == services().globals.admin_bot_user_id
+                        || services().users.is_deactivated(&user_id)?
+                    {
+                        continue;
+                    }
+
+                    match self.send_server_notice(&user_id, &message).await {
+                        Ok(()) => sent += 1,
+                        Err(error) => {
+                            warn!(%user_id, %error, "Failed to send server notice");
+                            failed += 1;
+                        }
+                    }
+                }
+
+                RoomMessageEventContent::text_plain(format!(
+                    "Sent the notice to {sent} user(s), failed for {failed}."
+                ))
+            }
+            AdminCommand::ListDisabledRooms => {
+                let room_ids = services()
+                    .rooms
+                    .metadata
+                    .iter_disabled()
+                    .filter_map(std::result::Result::ok)
+                    .map(|id| id.to_string())
+                    .collect::<Vec<_>>();
+                RoomMessageEventContent::text_plain(format!(
+                    "Found {} disabled room(s):\n{}",
+                    room_ids.len(),
+                    room_ids.join("\n")
+                ))
+            }
+            AdminCommand::SetMaxFetchPrevEvents {
+                room_id,
+                limit,
+            } => {
+                services()
+                    .rooms
+                    .metadata
+                    .set_max_fetch_prev_events(&room_id, limit)?;
+                if let Some(limit) = limit {
+                    RoomMessageEventContent::text_plain(format!(
+                        "Fetching at most {limit} prev events for incoming \
+                         events of {room_id}."
+                    ))
+                } else {
+                    RoomMessageEventContent::text_plain(format!(
+                        "Fetching at most {} prev events for incoming events \
+                         of {room_id} as configured.",
+                        services().globals.max_fetch_prev_events()
+                    ))
+                }
+            }
+            AdminCommand::AllowServerInRoom {
+                room_id,
+                server_name,
+            } => {
+                services().rooms.metadata.set_server_may_join(
+                    &room_id,
+                    &server_name,
+                    true,
+                )?;
+                RoomMessageEventContent::text_plain(format!(
+                    "{server_name} can join {room_id} now. Servers that \
+                     weren't allowed can't join it anymore."
+                ))
+            }
+            AdminCommand::DenyServerInRoom {
+                room_id,
+                server_name,
+            } => {
+                services().rooms.metadata.set_server_may_join(
+                    &room_id,
+                    &server_name,
+                    false,
+                )?;
+                RoomMessageEventContent::text_plain(format!(
+                    "{server_name} can't join {room_id} anymore."
+                ))
+            }
+            AdminCommand::ClearServerRestrictionsInRoom {
+                room_id,
+            } => {
+                services()
+                    .rooms
+                    .metadata
+                    .clear_server_join_restrictions(&room_id)?;
+                RoomMessageEventContent::text_plain(format!(
+                    "All servers that aren't denied by the ACL can join \
+                     {room_id} now."
+                ))
+            }
+            AdminCommand::DeactivateUser {
+                leave_rooms,
+                erase,
+                user_id,
+            } => {
+                let user_id = Arc::<UserId>::from(user_id);
+                if !services().users.exists(&user_id)? {
+                    RoomMessageEventContent::text_plain(format!(
+                        "User {user_id} doesn't exist on this server"
+                    ))
+                } else if user_id.server_name()
+                    != services().globals.server_name()
+                {
+                    RoomMessageEventContent::text_plain(format!(
+                        "User {user_id} is not from this server"
+                    ))
+                } else {
+                    let summary =
+                        deactivate_user(&user_id, leave_rooms, erase).await?;
 
                     RoomMessageEventContent::text_plain(format!(
-                        "User {user_id} has been deactivated"
+                        "User {user_id} has been deactivated: {summary}"
                     ))
                 }
             }
@@ -948,16 +1958,11 @@ impl Service {
                     }
 
                     for &user_id in &user_ids {
-                        if services().users.deactivate_account(user_id).is_ok()
+                        match deactivate_user(user_id, leave_rooms, false).await
                         {
-                            deactivation_count += 1;
-                        }
-                    }
-
-                    if leave_rooms {
-                        for &user_id in &user_ids {
-                            if let Err(error) = leave_all_rooms(user_id).await {
-                                warn!(%user_id, %error, "failed to leave one or more rooms");
+                            Ok(_) => deactivation_count += 1,
+                            Err(error) => {
+                                warn!(%user_id, %error, "Failed to deactivate user");
                             }
                         }
                     }
@@ -992,7 +1997,7 @@ impl Service {
                         Ok(mut value) => {
                             ruma::signatures::sign_json(
                                 services().globals.server_name().as_str(),
-                                services().globals.keypair(),
+                                &*services().globals.keypair(),
                                 &mut value,
                             )
                             .expect("our request json is what ruma expects");
@@ -1012,6 +2017,144 @@ impl Service {
                     )
                 }
             }
+            AdminCommand::ShowSigningKeys => {
+                let keys = SigningKeys::load_own_keys()?;
+
+                let mut message = String::new();
+                for (key_id, key) in &keys.verify_keys {
+                    writeln!(
+                        message,
+                        "{key_id}: {} (current, valid until {})",
+                        key.key.encode(),
+                        format_timestamp(keys.valid_until_ts),
+                    )
+                    .expect("write to in-memory buffer should succeed");
+                }
+                for (key_id, key) in &keys.old_verify_keys {
+                    writeln!(
+                        message,
+                        "{key_id}: {} (expired at {})",
+                        key.key.encode(),
+                        format_timestamp(key.expired_ts),
+                    )
+                    .expect("write to in-memory buffer should succeed");
+                }
+                RoomMessageEventContent::text_plain(message)
+            }
+            AdminCommand::RotateSigningKey => {
+                let (old_key_id, new_key_id) =
+                    services().globals.rotate_keypair()?;
+                warn!(%old_key_id, %new_key_id, "Signing key rotated");
+                RoomMessageEventContent::text_plain(format!(
+                    "Signing with {new_key_id} from now on. {old_key_id} is \
+                     still advertised as an old verify key."
+                ))
+            }
+            AdminCommand::ListTrustedServers => {
+                let trusted_servers = services().globals.trusted_servers();
+                RoomMessageEventContent::text_plain(format!(
+                    "{} trusted server(s):\n{}",
+                    trusted_servers.len(),
+                    trusted_servers
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                ))
+            }
+            AdminCommand::AddTrustedServer {
+                server_name,
+            } => {
+                if &*server_name == services().globals.server_name() {
+                    RoomMessageEventContent::text_plain(
+                        "This server can't be its own trusted server.",
+                    )
+                } else if services().globals.add_trusted_server(&server_name)? {
+                    RoomMessageEventContent::text_plain(format!(
+                        "Fetching signing keys from {server_name} from now on."
+                    ))
+                } else {
+                    RoomMessageEventContent::text_plain(format!(
+                        "{server_name} is already a trusted server."
+                    ))
+                }
+            }
+            AdminCommand::RemoveTrustedServer {
+                server_name,
+            } => {
+                if !services().globals.remove_trusted_server(&server_name)? {
+                    RoomMessageEventContent::text_plain(format!(
+                        "{server_name} is not a trusted server."
+                    ))
+                } else if services().globals.trusted_servers().is_empty() {
+                    RoomMessageEventContent::text_plain(format!(
+                        "Removed {server_name}. Warning: there are no trusted \
+                         servers left, so signing keys of servers that are \
+                         offline can't be fetched anymore."
+                    ))
+                } else {
+                    RoomMessageEventContent::text_plain(format!(
+                        "Removed {server_name} from the trusted servers."
+                    ))
+                }
+            }
+            AdminCommand::ListFederationPolicy => {
+                let (allowed, denied) = services().globals.federation_policy();
+                let list = |servers: &[OwnedServerName]| {
+                    servers.iter().map(|s| format!("\n{s}")).collect::<String>()
+                };
+                let allowed = match allowed {
+                    Some(allowed) => format!(
+                        "{} allowed server(s):{}",
+                        allowed.len(),
+                        list(&allowed)
+                    ),
+                    None => {
+                        "All servers that aren't denied are allowed.".to_owned()
+                    }
+                };
+                RoomMessageEventContent::text_plain(format!(
+                    "{allowed}\n\n{} denied server(s):{}",
+                    denied.len(),
+                    list(&denied),
+                ))
+            }
+            AdminCommand::AllowFederationWith {
+                server_name,
+            } => {
+                if services()
+                    .globals
+                    .set_federation_allowed_with(&server_name, true)?
+                {
+                    RoomMessageEventContent::text_plain(format!(
+                        "Federating with {server_name} from now on."
+                    ))
+                } else {
+                    RoomMessageEventContent::text_plain(format!(
+                        "Federation with {server_name} was already allowed."
+                    ))
+                }
+            }
+            AdminCommand::DenyFederationWith {
+                server_name,
+            } => {
+                if &*server_name == services().globals.server_name() {
+                    RoomMessageEventContent::text_plain(
+                        "This server can't deny federation with itself.",
+                    )
+                } else if services()
+                    .globals
+                    .set_federation_allowed_with(&server_name, false)?
+                {
+                    RoomMessageEventContent::text_plain(format!(
+                        "Not federating with {server_name} anymore."
+                    ))
+                } else {
+                    RoomMessageEventContent::text_plain(format!(
+                        "Federation with {server_name} was already denied."
+                    ))
+                }
+            }
             AdminCommand::VerifyJson => {
                 if body.len() > 2
                     && body[0].trim() == "```"
@@ -1091,18 +2234,12 @@ impl Service {
                 backend,
                 filter,
             } => {
-                let handles = &services().globals.reload_handles;
-                let handle = match backend {
-                    TracingBackend::Log => &handles.log,
-                    TracingBackend::Flame => &handles.flame,
-                    TracingBackend::Traces => &handles.traces,
-                };
-                let Some(handle) = handle else {
+                let Some(handle) = backend.reload_handle() else {
                     return Ok(RoomMessageEventContent::text_plain(
                         "Backend is disabled",
                     ));
                 };
-                let filter = match filter.parse() {
+                let filter = match filter.parse::<EnvFilterClone>() {
                     Ok(filter) => filter,
                     Err(e) => {
                         return Ok(RoomMessageEventContent::text_plain(
@@ -1110,7 +2247,7 @@ impl Service {
                         ));
                     }
                 };
-                if let Err(e) = handle.reload(filter) {
+                if let Err(e) = handle.reload(&filter) {
                     return Ok(RoomMessageEventContent::text_plain(format!(
                         "Failed to reload filter: {e}"
                     )));
@@ -1120,6 +2257,24 @@ impl Service {
                     "Filter reloaded",
                 ));
             }
+            AdminCommand::GetTracingFilter {
+                backend,
+            } => {
+                let Some(handle) = backend.reload_handle() else {
+                    return Ok(RoomMessageEventContent::text_plain(
+                        "Backend is disabled",
+                    ));
+                };
+                let filter = handle.current();
+
+                RoomMessageEventContent::text_html(
+                    format!("Current filter: `{filter}`"),
+                    format!(
+                        "<p>Current filter: <code>{}</code></p>",
+                        html_escape::encode_safe(&filter)
+                    ),
+                )
+            }
         };
 
         Ok(reply_message_content)
@@ -1461,6 +2616,616 @@ impl Service {
             .resolve_local_alias(&services().globals.admin_bot_room_alias_id)
     }
 
+    /// Describes the stored state of an event for `show-event`.
+    // Allowed because this function uses `services()`
+    #[allow(clippy::unused_self)]
+    async fn show_event(
+        &self,
+        event_id: Arc<EventId>,
+    ) -> Result<RoomMessageEventContent> {
+        let (pdu_json, outlier) = if let Some(json) =
+            services().rooms.timeline.get_non_outlier_pdu_json(&event_id)?
+        {
+            (json, false)
+        } else if let Some(json) =
+            services().rooms.outlier.get_outlier_pdu_json(&event_id)?
+        {
+            (json, true)
+        } else {
+            return Ok(RoomMessageEventContent::text_plain("PDU not found."));
+        };
+
+        let soft_failed =
+            services().rooms.pdu_metadata.is_event_soft_failed(&event_id)?;
+        let shortstatehash =
+            services().rooms.state_accessor.pdu_shortstatehash(&event_id)?;
+
+        let room_id = pdu_json
+            .get("room_id")
+            .and_then(|val| val.as_str())
+            .and_then(|room_id| <&RoomId>::try_from(room_id).ok())
+            .ok_or_else(|| Error::bad_database("Invalid event in database"))?;
+        let auth_chain = services()
+            .rooms
+            .auth_chain
+            .get_auth_chain(room_id, vec![event_id.clone()])
+            .await?
+            .map(|event_id| event_id.to_string())
+            .collect::<Vec<_>>();
+
+        let json_text = serde_json::to_string_pretty(&pdu_json)
+            .expect("canonical json is valid json");
+        let status = format!(
+            "Outlier: {outlier}\nSoft-failed: {soft_failed}\nShortstatehash: \
+             {}\nAuth chain ({} events):",
+            shortstatehash
+                .map_or_else(|| "none".to_owned(), |hash| hash.to_string()),
+            auth_chain.len(),
+        );
+
+        Ok(RoomMessageEventContent::text_html(
+            format!(
+                "{status}\n{}\n```json\n{json_text}\n```",
+                auth_chain.join("\n")
+            ),
+            format!(
+                "<pre>{}\n{}</pre>\n<pre><code \
+                 class=\"language-json\">{}\n</code></pre>\n",
+                html_escape::encode_safe(&status),
+                html_escape::encode_safe(&auth_chain.join("\n")),
+                html_escape::encode_safe(&json_text)
+            ),
+        ))
+    }
+
+    /// Writes a snapshot of the database to `path` for `backup-database`.
+    // Allowed because this function uses `services()`
+    #[allow(clippy::unused_self)]
+    async fn backup_database(
+        &self,
+        path: PathBuf,
+    ) -> Result<RoomMessageEventContent> {
+        if fs::try_exists(&path).await.unwrap_or(true) {
+            return Ok(RoomMessageEventContent::text_plain(format!(
+                "{} already exists.",
+                path.display()
+            )));
+        }
+
+        let start = Instant::now();
+        let backup_path = path.clone();
+        let restore = tokio::task::spawn_blocking(move || {
+            services().globals.db.backup(&backup_path)
+        })
+        .await
+        .expect("backup task should not panic")?;
+        let elapsed = start.elapsed();
+        info!(path = %path.display(), ?elapsed, "Database backed up");
+
+        Ok(RoomMessageEventContent::text_plain(format!(
+            "Backed up the database to {} in {}, {}.\n\nTo restore it: \
+             {restore}",
+            path.display(),
+            humantime::format_duration(Duration::from_millis(
+                elapsed.as_millis().try_into().unwrap_or(u64::MAX)
+            )),
+            format_size(disk_usage(&path)),
+        )))
+    }
+
+    /// Compacts one tree or the whole database for `compact-database`.
+    ///
+    /// Concurrent runs are rejected by the database engine.
+    // Allowed because this function uses `services()`
+    #[allow(clippy::unused_self)]
+    async fn compact_database(
+        &self,
+        tree: Option<String>,
+    ) -> Result<RoomMessageEventContent> {
+        let path = PathBuf::from(&services().globals.config.database.path);
+        let before = disk_usage(&path);
+
+        let start = Instant::now();
+        let compact_tree = tree.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            services().globals.db.compact(compact_tree.as_deref())
+        })
+        .await
+        .expect("compaction task should not panic");
+        if let Err(Error::BadConfig(message)) = result {
+            return Ok(RoomMessageEventContent::text_plain(message));
+        }
+        result?;
+        let elapsed = start.elapsed();
+        info!(tree = tree.as_deref(), ?elapsed, "Database compacted");
+
+        let after = disk_usage(&path);
+
+        Ok(RoomMessageEventContent::text_plain(format!(
+            "Compacted {} in {}.\n\nSize before: {}\nSize after: {}",
+            tree.map_or_else(
+                || "the database".to_owned(),
+                |tree| format!("tree {tree}")
+            ),
+            humantime::format_duration(Duration::from_millis(
+                elapsed.as_millis().try_into().unwrap_or(u64::MAX)
+            )),
+            format_size(before),
+            format_size(after),
+        )))
+    }
+
+    /// Reports problems found by the database integrity check for
+    /// `check-database`.
+    // Allowed because this function uses `services()`
+    #[allow(clippy::unused_self)]
+    async fn check_database(&self) -> Result<RoomMessageEventContent> {
+        /// Number of problems that are listed in the response
+        const MAX_LISTED: usize = 100;
+
+        let start = Instant::now();
+        let result = tokio::task::spawn_blocking(|| {
+            services().globals.db.check_integrity()
+        })
+        .await
+        .expect("integrity check task should not panic");
+        if let Err(Error::BadConfig(message)) = result {
+            return Ok(RoomMessageEventContent::text_plain(message));
+        }
+        let problems = result?;
+        let elapsed = humantime::format_duration(Duration::from_millis(
+            start.elapsed().as_millis().try_into().unwrap_or(u64::MAX),
+        ));
+
+        if problems.is_empty() {
+            return Ok(RoomMessageEventContent::text_plain(format!(
+                "No problems found in {elapsed}."
+            )));
+        }
+
+        warn!(
+            count = problems.len(),
+            "Database integrity check found problems"
+        );
+        let mut message =
+            format!("Found {} problem(s) in {elapsed}:\n", problems.len());
+        for problem in problems.iter().take(MAX_LISTED) {
+            writeln!(message, "- {problem}")
+                .expect("write to in-memory buffer should succeed");
+        }
+        if problems.len() > MAX_LISTED {
+            writeln!(message, "- ... and {} more", problems.len() - MAX_LISTED)
+                .expect("write to in-memory buffer should succeed");
+        }
+
+        Ok(RoomMessageEventContent::text_plain(message))
+    }
+
+    /// Finds and optionally removes unreferenced short IDs for `gc-shortids`.
+    // Allowed because this function uses `services()`
+    #[allow(clippy::unused_self)]
+    async fn gc_shortids(
+        &self,
+        apply: bool,
+    ) -> Result<RoomMessageEventContent> {
+        let start = Instant::now();
+        let (shorteventids, shortstatekeys) =
+            tokio::task::spawn_blocking(move || {
+                // Short IDs created after this may not be referenced yet
+                let max_shortid = services().globals.current_count()?;
+                if apply {
+                    services()
+                        .rooms
+                        .short
+                        .remove_unreferenced_shortids(max_shortid)
+                } else {
+                    services().rooms.short.unreferenced_shortids(max_shortid)
+                }
+            })
+            .await
+            .expect("short ID cleanup task should not panic")?;
+        let elapsed = start.elapsed();
+
+        if apply {
+            info!(
+                shorteventids = shorteventids.len(),
+                shortstatekeys = shortstatekeys.len(),
+                ?elapsed,
+                "Removed unreferenced short IDs"
+            );
+        }
+
+        Ok(RoomMessageEventContent::text_plain(format!(
+            "{} {} short event ID(s) and {} short state key(s) in {}.{}",
+            if apply {
+                "Removed"
+            } else {
+                "Found"
+            },
+            shorteventids.len(),
+            shortstatekeys.len(),
+            humantime::format_duration(Duration::from_millis(
+                elapsed.as_millis().try_into().unwrap_or(u64::MAX)
+            )),
+            if apply || (shorteventids.is_empty() && shortstatekeys.is_empty())
+            {
+                ""
+            } else {
+                " Run again with --apply to remove them."
+            },
+        )))
+    }
+
+    /// Writes the events of a room to a file for `export-room`.
+    ///
+    /// The timeline is exported in batches so that large rooms aren't loaded
+    /// into memory at once, only the IDs of events that were already written
+    /// are kept.
+    // Allowed because this function uses `services()`
+    #[allow(clippy::unused_self)]
+    async fn export_room(
+        &self,
+        room_id: &RoomId,
+        path: &Path,
+        state: bool,
+    ) -> Result<RoomMessageEventContent> {
+        /// Number of timeline events whose auth chains are looked up at once
+        const BATCH_SIZE: usize = 100;
+
+        if !services().rooms.metadata.exists(room_id)? {
+            return Ok(RoomMessageEventContent::text_plain("Room not found."));
+        }
+
+        let file = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .await
+        {
+            Ok(file) => file,
+            Err(error) => {
+                return Ok(RoomMessageEventContent::text_plain(format!(
+                    "Failed to create {}: {error}",
+                    path.display()
+                )));
+            }
+        };
+        let mut exporter = RoomExporter {
+            room_id,
+            file: BufWriter::new(file),
+            written: HashSet::new(),
+        };
+
+        if state {
+            let state_events = services()
+                .rooms
+                .state_accessor
+                .room_state_full(room_id)
+                .await?
+                .into_values()
+                .map(|pdu| pdu.event_id.clone())
+                .collect();
+            exporter.write_with_auth_chain(state_events).await?;
+        }
+
+        let mut from = PduCount::MIN;
+        loop {
+            let batch = services()
+                .rooms
+                .timeline
+                .pdus_after(
+                    &services().globals.admin_bot_user_id,
+                    room_id,
+                    from,
+                )?
+                .take(BATCH_SIZE)
+                .collect::<Result<Vec<_>>>()?;
+            let Some((last, _)) = batch.last() else {
+                break;
+            };
+            from = *last;
+
+            exporter
+                .write_with_auth_chain(
+                    batch.into_iter().map(|(_, pdu)| pdu.event_id).collect(),
+                )
+                .await?;
+        }
+
+        exporter.file.flush().await?;
+
+        Ok(RoomMessageEventContent::text_plain(format!(
+            "Exported {} event(s) to {}.",
+            exporter.written.len(),
+            path.display()
+        )))
+    }
+
+    /// Imports the events of a file written by `export-room` for
+    /// `import-room`.
+    // Allowed because this function uses `services()`
+    #[allow(clippy::unused_self)]
+    async fn import_room(
+        &self,
+        path: &Path,
+    ) -> Result<RoomMessageEventContent> {
+        if !services().globals.config.allow_room_import {
+            return Ok(RoomMessageEventContent::text_plain(
+                "Importing rooms is disabled. Set `allow_room_import` in the \
+                 config to enable it.",
+            ));
+        }
+
+        let file = match File::open(path).await {
+            Ok(file) => file,
+            Err(error) => {
+                return Ok(RoomMessageEventContent::text_plain(format!(
+                    "Failed to open {}: {error}",
+                    path.display()
+                )));
+            }
+        };
+
+        let pub_key_map = RwLock::new(BTreeMap::new());
+        let mut room_ids = BTreeSet::new();
+        let mut imported = 0_usize;
+        let mut skipped = 0_usize;
+
+        let mut lines = BufReader::new(file).lines();
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match import_pdu(&line, &pub_key_map).await {
+                Ok(room_id) => {
+                    imported += 1;
+                    room_ids.insert(room_id);
+                }
+                Err(error) => {
+                    warn!(%error, "Skipping event that failed to import");
+                    skipped += 1;
+                }
+            }
+        }
+
+        Ok(RoomMessageEventContent::text_plain(format!(
+            "Imported {imported} event(s) into {}. Skipped {skipped} event(s) \
+             that failed checks, see the logs for details.",
+            room_ids
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )))
+    }
+
+    /// Sends an `m.server_notice` message to the server notices room of a
+    /// local user, creating the room or joining them to it again if needed.
+    #[tracing::instrument(skip(self, body))]
+    pub(crate) async fn send_server_notice(
+        &self,
+        user_id: &UserId,
+        body: &str,
+    ) -> Result<()> {
+        let room_id = match services().users.server_notices_room(user_id)? {
+            Some(room_id) if services().rooms.metadata.exists(&room_id)? => {
+                room_id
+            }
+            _ => self.create_server_notices_room(user_id).await?,
+        };
+
+        let room_token = services()
+            .globals
+            .roomid_mutex_state
+            .lock_key(room_id.clone())
+            .await;
+
+        if !services().rooms.state_cache.is_joined(user_id, &room_id)? {
+            debug!(%user_id, "User left their server notices room, rejoining");
+            for membership in [MembershipState::Invite, MembershipState::Join] {
+                let sender = if membership == MembershipState::Invite {
+                    &*services().globals.admin_bot_user_id
+                } else {
+                    user_id
+                };
+                services()
+                    .rooms
+                    .timeline
+                    .build_and_append_pdu(
+                        PduBuilder {
+                            event_type: TimelineEventType::RoomMember,
+                            content: to_raw_value(
+                                &RoomMemberEventContent::new(membership),
+                            )
+                            .expect("event is valid, we just created it"),
+                            unsigned: None,
+                            state_key: Some(user_id.to_string()),
+                            redacts: None,
+                        },
+                        sender,
+                        &room_token,
+                    )
+                    .await?;
+            }
+        }
+
+        services()
+            .rooms
+            .timeline
+            .build_and_append_pdu(
+                PduBuilder {
+                    event_type: TimelineEventType::RoomMessage,
+                    content: to_raw_value(&json!({
+                        "msgtype": "m.server_notice",
+                        "body": body,
+                    }))
+                    .expect("event is valid, we just created it"),
+                    unsigned: None,
+                    state_key: None,
+                    redacts: None,
+                },
+                &services().globals.admin_bot_user_id,
+                &room_token,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Creates the server notices room of a local user and joins them to it.
+    ///
+    /// Only the server user can send messages in the room, and it is tagged
+    /// with `m.server_notice` for the user.
+    #[allow(clippy::too_many_lines)]
+    #[tracing::instrument(skip(self))]
+    async fn create_server_notices_room(
+        &self,
+        user_id: &UserId,
+    ) -> Result<OwnedRoomId> {
+        let room_id = RoomId::new(services().globals.server_name());
+
+        services().rooms.short.get_or_create_shortroomid(&room_id)?;
+
+        let room_token = services()
+            .globals
+            .roomid_mutex_state
+            .lock_key(room_id.clone())
+            .await;
+
+        let room_version = services().globals.default_room_version();
+        let mut content = match &room_version {
+            room_version if *room_version < RoomVersionId::V11 => {
+                RoomCreateEventContent::new_v1(
+                    services().globals.admin_bot_user_id.clone(),
+                )
+            }
+            RoomVersionId::V11 => RoomCreateEventContent::new_v11(),
+            _ => {
+                return Err(Error::BadServerResponse(
+                    "Unsupported room version.",
+                ))
+            }
+        };
+        content.federate = false;
+        content.predecessor = None;
+        content.room_version = room_version;
+
+        let mut users = BTreeMap::new();
+        users.insert(services().globals.admin_bot_user_id.clone(), 100.into());
+
+        let admin_bot = &*services().globals.admin_bot_user_id;
+        let events = [
+            (
+                TimelineEventType::RoomCreate,
+                to_raw_value(&content),
+                String::new(),
+                admin_bot,
+            ),
+            (
+                TimelineEventType::RoomMember,
+                to_raw_value(&RoomMemberEventContent::new(
+                    MembershipState::Join,
+                )),
+                admin_bot.to_string(),
+                admin_bot,
+            ),
+            (
+                TimelineEventType::RoomPowerLevels,
+                to_raw_value(&RoomPowerLevelsEventContent {
+                    users,
+                    events_default: 100.into(),
+                    ..Default::default()
+                }),
+                String::new(),
+                admin_bot,
+            ),
+            (
+                TimelineEventType::RoomJoinRules,
+                to_raw_value(&RoomJoinRulesEventContent::new(JoinRule::Invite)),
+                String::new(),
+                admin_bot,
+            ),
+            (
+                TimelineEventType::RoomHistoryVisibility,
+                to_raw_value(&RoomHistoryVisibilityEventContent::new(
+                    HistoryVisibility::Shared,
+                )),
+                String::new(),
+                admin_bot,
+            ),
+            (
+                TimelineEventType::RoomGuestAccess,
+                to_raw_value(&RoomGuestAccessEventContent::new(
+                    GuestAccess::Forbidden,
+                )),
+                String::new(),
+                admin_bot,
+            ),
+            (
+                TimelineEventType::RoomName,
+                to_raw_value(&RoomNameEventContent::new(
+                    "Server Notices".to_owned(),
+                )),
+                String::new(),
+                admin_bot,
+            ),
+            (
+                TimelineEventType::RoomMember,
+                to_raw_value(&RoomMemberEventContent::new(
+                    MembershipState::Invite,
+                )),
+                user_id.to_string(),
+                admin_bot,
+            ),
+            (
+                TimelineEventType::RoomMember,
+                to_raw_value(&RoomMemberEventContent::new(
+                    MembershipState::Join,
+                )),
+                user_id.to_string(),
+                user_id,
+            ),
+        ];
+
+        for (event_type, content, state_key, sender) in events {
+            services()
+                .rooms
+                .timeline
+                .build_and_append_pdu(
+                    PduBuilder {
+                        event_type,
+                        content: content
+                            .expect("event is valid, we just created it"),
+                        unsigned: None,
+                        state_key: Some(state_key),
+                        redacts: None,
+                    },
+                    sender,
+                    &room_token,
+                )
+                .await?;
+        }
+
+        let mut tags = BTreeMap::new();
+        tags.insert(TagName::ServerNotice, TagInfo::new());
+        services().account_data.update(
+            Some(&room_id),
+            user_id,
+            RoomAccountDataEventType::Tag,
+            &serde_json::to_value(TagEvent {
+                content: TagEventContent {
+                    tags,
+                },
+            })
+            .expect("to json value always works"),
+        )?;
+
+        services().users.set_server_notices_room(user_id, &room_id)?;
+
+        Ok(room_id)
+    }
+
     /// Invite the user to the grapevine admin room.
     ///
     /// In grapevine, this is equivalent to granting admin privileges.
@@ -1,6 +1,6 @@
 mod data;
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap},
     error::Error as StdError,
     fs,
     future::{self, Future},
@@ -9,7 +9,7 @@ use std::{
     path::PathBuf,
     sync::{
         atomic::{self, AtomicBool},
-        Arc, RwLock as StdRwLock,
+        Arc, Mutex as StdMutex, RwLock as StdRwLock,
     },
     time::{Duration, Instant},
 };
@@ -23,20 +23,32 @@ use hyper_util::{
 };
 use reqwest::dns::{Addrs, Name, Resolve, Resolving};
 use ruma::{
-    api::federation::discovery::ServerSigningKeys, serde::Base64, DeviceId,
-    MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomAliasId, OwnedRoomId,
-    OwnedServerName, OwnedUserId, RoomAliasId, RoomVersionId, ServerName,
-    UserId,
+    api::{
+        client::discovery::get_capabilities::RoomVersionStability,
+        federation::discovery::{OldVerifyKey, ServerSigningKeys},
+    },
+    push::Ruleset,
+    serde::{Base64, Raw},
+    state_res::RoomVersion,
+    DeviceId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomAliasId,
+    OwnedRoomId, OwnedServerName, OwnedUserId, RoomAliasId, RoomVersionId,
+    ServerName, UserId,
 };
-use tokio::sync::{broadcast, Mutex, RwLock, Semaphore};
+use serde_json::value::RawValue as RawJsonValue;
+use tokio::sync::{broadcast, Mutex, OnceCell, RwLock, Semaphore};
 use tracing::{error, Instrument};
 use trust_dns_resolver::TokioAsyncResolver;
 
 use crate::{
     api::server_server::FedDest,
+    config::RoomVersionOverride,
     observability::FilterReloadHandles,
     services,
-    utils::on_demand_hashmap::{OnDemandHashMap, TokenSet},
+    utils::{
+        mailer::Mailer,
+        on_demand_hashmap::{OnDemandHashMap, TokenSet},
+        rate_limiter::ClientRateLimiters,
+    },
     Config, Error, Result,
 };
 
@@ -54,6 +66,44 @@ pub(crate) mod marker {
     pub(crate) enum Federation {}
 }
 
+/// The servers this server federates with
+#[derive(Debug, Default)]
+pub(crate) struct FederationPolicy {
+    /// If set, only these servers are federated with
+    pub(crate) allowed: Option<BTreeSet<OwnedServerName>>,
+    /// Servers that are never federated with, even if they are allowed
+    pub(crate) denied: BTreeSet<OwnedServerName>,
+}
+
+impl FederationPolicy {
+    /// Returns whether federation with the server is allowed.
+    pub(crate) fn allows(&self, server_name: &ServerName) -> bool {
+        !self.denied.contains(server_name)
+            && self
+                .allowed
+                .as_ref()
+                .map_or(true, |allowed| allowed.contains(server_name))
+    }
+
+    /// Allows or denies federation with the server. Returns whether this
+    /// changed the policy.
+    fn set(&mut self, server_name: &ServerName, allow: bool) -> bool {
+        if allow {
+            let mut changed = self.denied.remove(server_name);
+            if let Some(allowed) = &mut self.allowed {
+                changed |= allowed.insert(server_name.to_owned());
+            }
+            changed
+        } else {
+            let mut changed = self.denied.insert(server_name.to_owned());
+            if let Some(allowed) = &mut self.allowed {
+                changed |= allowed.remove(server_name);
+            }
+            changed
+        }
+    }
+}
+
 pub(crate) struct Service {
     pub(crate) db: &'static dyn Data,
     pub(crate) reload_handles: FilterReloadHandles,
@@ -62,22 +112,49 @@ pub(crate) struct Service {
     pub(crate) actual_destination_cache: Arc<RwLock<WellKnownMap>>,
     pub(crate) tls_name_override: Arc<StdRwLock<TlsNameMap>>,
     pub(crate) config: Config,
-    keypair: Arc<ruma::signatures::Ed25519KeyPair>,
+    keypair: StdRwLock<Arc<ruma::signatures::Ed25519KeyPair>>,
+    /// `federation.trusted_servers` with the changes made by admins applied
+    trusted_servers: StdRwLock<Vec<OwnedServerName>>,
+    /// `federation.allowed_servers` and `federation.denied_servers` with the
+    /// changes made by admins applied
+    federation_policy: StdRwLock<FederationPolicy>,
     dns_resolver: TokioAsyncResolver,
     jwt_decoding_key: Option<jsonwebtoken::DecodingKey>,
     federation_client: reqwest::Client,
     default_client: reqwest::Client,
-    pub(crate) stable_room_versions: Vec<RoomVersionId>,
+    pub(crate) room_versions: BTreeMap<RoomVersionId, RoomVersionStability>,
     pub(crate) admin_bot_user_id: OwnedUserId,
     pub(crate) admin_bot_room_alias_id: OwnedRoomAliasId,
+    pub(crate) client_rate_limiters: ClientRateLimiters,
+    /// Used to send emails, if SMTP is configured
+    pub(crate) mailer: Option<Mailer>,
+    /// Nonces for shared-secret registration that haven't been used yet and
+    /// when they were issued
+    pub(crate) registration_nonces: StdMutex<HashMap<String, Instant>>,
     pub(crate) bad_event_ratelimiter:
         Arc<RwLock<HashMap<OwnedEventId, RateLimitState>>>,
     pub(crate) bad_signature_ratelimiter:
         Arc<RwLock<HashMap<Vec<String>, RateLimitState>>>,
     pub(crate) bad_query_ratelimiter:
         Arc<RwLock<HashMap<OwnedServerName, RateLimitState>>>,
+    /// Rooms where backfilling added no events
+    pub(crate) bad_backfill_ratelimiter:
+        Arc<RwLock<HashMap<OwnedRoomId, RateLimitState>>>,
     pub(crate) servername_ratelimiter:
         OnDemandHashMap<OwnedServerName, Semaphore>,
+    /// Limits how many rooms events from incoming transactions are handled
+    /// for at the same time
+    pub(crate) incoming_rooms_semaphore: Semaphore,
+    /// Limits how many rooms events from incoming transactions of each server
+    /// are handled for at the same time
+    pub(crate) servername_incoming_rooms:
+        OnDemandHashMap<OwnedServerName, Semaphore>,
+    /// Results of federation requests for events, shared by all tasks
+    /// fetching the same event from the same server at the same time
+    pub(crate) eventid_fetch: OnDemandHashMap<
+        (OwnedServerName, OwnedEventId),
+        OnceCell<Option<Box<RawJsonValue>>>,
+    >,
     pub(crate) roomid_mutex_insert: TokenSet<OwnedRoomId, marker::Insert>,
     pub(crate) roomid_mutex_state: TokenSet<OwnedRoomId, marker::State>,
 
@@ -90,6 +167,8 @@ pub(crate) struct Service {
     pub(crate) rotate: RotationHandler,
 
     pub(crate) shutdown: AtomicBool,
+    /// Whether new requests other than federation transactions are rejected
+    pub(crate) draining: AtomicBool,
 }
 
 /// Handles "rotation" of long-polling requests. "Rotation" in this context is
@@ -203,6 +282,26 @@ impl Service {
             }
         };
 
+        let mut trusted_servers = config.federation.trusted_servers.clone();
+        for (server_name, trusted) in db.trusted_server_overrides()? {
+            trusted_servers.retain(|s| s != &server_name);
+            if trusted {
+                trusted_servers.push(server_name);
+            }
+        }
+
+        let mut federation_policy = FederationPolicy {
+            allowed: config
+                .federation
+                .allowed_servers
+                .as_ref()
+                .map(|servers| servers.iter().cloned().collect()),
+            denied: config.federation.denied_servers.iter().cloned().collect(),
+        };
+        for (server_name, allowed) in db.federation_overrides()? {
+            federation_policy.set(&server_name, allowed);
+        }
+
         let tls_name_override = Arc::new(StdRwLock::new(TlsNameMap::new()));
 
         let jwt_decoding_key = config.jwt_secret.as_ref().map(|secret| {
@@ -214,15 +313,10 @@ impl Service {
             .dns_resolver(Arc::new(Resolver::new(tls_name_override.clone())))
             .build()?;
 
-        // Supported and stable room versions
-        let stable_room_versions = vec![
-            RoomVersionId::V6,
-            RoomVersionId::V7,
-            RoomVersionId::V8,
-            RoomVersionId::V9,
-            RoomVersionId::V10,
-            RoomVersionId::V11,
-        ];
+        let room_versions = available_room_versions(&config);
+        let client_rate_limiters =
+            ClientRateLimiters::new(&config.rate_limiting);
+        let mailer = config.smtp.as_ref().map(Mailer::new).transpose()?;
 
         let admin_bot_user_id = UserId::parse(format!(
             "@{}:{}",
@@ -239,11 +333,17 @@ impl Service {
             RoomAliasId::parse(format!("#admins:{}", config.server_name))
                 .expect("admin bot room alias ID should be valid");
 
+        let incoming_rooms_semaphore = Semaphore::new(
+            config.federation.max_concurrent_incoming_rooms.into(),
+        );
+
         let mut s = Self {
             db,
             config,
             reload_handles,
-            keypair: Arc::new(keypair),
+            keypair: StdRwLock::new(Arc::new(keypair)),
+            trusted_servers: StdRwLock::new(trusted_servers),
+            federation_policy: StdRwLock::new(federation_policy),
             dns_resolver: TokioAsyncResolver::tokio_from_system_conf()
                 .map_err(|e| {
                     error!(
@@ -263,15 +363,24 @@ impl Service {
             federation_client,
             default_client,
             jwt_decoding_key,
-            stable_room_versions,
+            room_versions,
             admin_bot_user_id,
             admin_bot_room_alias_id,
+            client_rate_limiters,
+            mailer,
+            registration_nonces: StdMutex::new(HashMap::new()),
             bad_event_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
             bad_signature_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
             bad_query_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
+            bad_backfill_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
             servername_ratelimiter: OnDemandHashMap::new(
                 "servername_ratelimiter".to_owned(),
             ),
+            incoming_rooms_semaphore,
+            servername_incoming_rooms: OnDemandHashMap::new(
+                "servername_incoming_rooms".to_owned(),
+            ),
+            eventid_fetch: OnDemandHashMap::new("eventid_fetch".to_owned()),
             roomid_mutex_state: TokenSet::new("roomid_mutex_state".to_owned()),
             roomid_mutex_insert: TokenSet::new(
                 "roomid_mutex_insert".to_owned(),
@@ -283,6 +392,7 @@ impl Service {
             stateres_mutex: Arc::new(Mutex::new(())),
             rotate: RotationHandler::new(),
             shutdown: AtomicBool::new(false),
+            draining: AtomicBool::new(false),
         };
 
         fs::create_dir_all(s.get_media_folder())?;
@@ -298,8 +408,48 @@ impl Service {
     }
 
     /// Returns this server's keypair.
-    pub(crate) fn keypair(&self) -> &ruma::signatures::Ed25519KeyPair {
-        &self.keypair
+    pub(crate) fn keypair(&self) -> Arc<ruma::signatures::Ed25519KeyPair> {
+        Arc::clone(&self.keypair.read().unwrap())
+    }
+
+    /// Replaces this server's keypair with a newly generated one, returning
+    /// the IDs of the old and the new key.
+    ///
+    /// The old key stays advertised in `old_verify_keys`, so that events
+    /// signed with it can still be verified by other servers.
+    pub(crate) fn rotate_keypair(&self) -> Result<(String, String)> {
+        let mut keypair = self.keypair.write().unwrap();
+        let old_key_id = format!("ed25519:{}", keypair.version());
+
+        let mut old_verify_keys = BTreeMap::new();
+        old_verify_keys.insert(
+            old_key_id
+                .clone()
+                .try_into()
+                .expect("key ID of our keypair should be valid"),
+            OldVerifyKey::new(
+                MilliSecondsSinceUnixEpoch::now(),
+                Base64::new(keypair.public_key().to_vec()),
+            ),
+        );
+
+        // Store the old key first, so that it isn't lost if replacing the
+        // keypair fails
+        self.db.add_signing_key_from_trusted_server(
+            self.server_name(),
+            ServerSigningKeys {
+                server_name: self.server_name().to_owned(),
+                verify_keys: BTreeMap::new(),
+                old_verify_keys,
+                signatures: BTreeMap::new(),
+                valid_until_ts: MilliSecondsSinceUnixEpoch::now(),
+            },
+        )?;
+
+        *keypair = Arc::new(self.db.replace_keypair()?);
+        let new_key_id = format!("ed25519:{}", keypair.version());
+
+        Ok((old_key_id, new_key_id))
     }
 
     /// Returns a reqwest client which can be used to send requests
@@ -344,6 +494,17 @@ impl Service {
         self.config.max_request_size
     }
 
+    pub(crate) fn max_media_upload_size(&self) -> u32 {
+        self.config
+            .max_media_upload_size
+            .unwrap_or(self.config.max_request_size)
+    }
+
+    /// Returns the push rules a new account of `user_id` is provisioned with
+    pub(crate) fn default_push_rules(&self, user_id: &UserId) -> Ruleset {
+        self.config.push_rules.ruleset(user_id)
+    }
+
     pub(crate) fn max_fetch_prev_events(&self) -> u16 {
         self.config.federation.max_fetch_prev_events
     }
@@ -368,8 +529,73 @@ impl Service {
         self.config.default_room_version.clone()
     }
 
-    pub(crate) fn trusted_servers(&self) -> &[OwnedServerName] {
-        &self.config.federation.trusted_servers
+    pub(crate) fn trusted_servers(&self) -> Vec<OwnedServerName> {
+        self.trusted_servers.read().unwrap().clone()
+    }
+
+    /// Adds a server to the trusted servers, persisting the change. Returns
+    /// whether it wasn't trusted before.
+    pub(crate) fn add_trusted_server(
+        &self,
+        server_name: &ServerName,
+    ) -> Result<bool> {
+        let mut trusted_servers = self.trusted_servers.write().unwrap();
+        self.db.set_trusted_server_override(server_name, true)?;
+
+        if trusted_servers.iter().any(|s| &**s == server_name) {
+            return Ok(false);
+        }
+        trusted_servers.push(server_name.to_owned());
+
+        Ok(true)
+    }
+
+    /// Removes a server from the trusted servers, persisting the change.
+    /// Returns whether it was trusted before.
+    pub(crate) fn remove_trusted_server(
+        &self,
+        server_name: &ServerName,
+    ) -> Result<bool> {
+        let mut trusted_servers = self.trusted_servers.write().unwrap();
+        self.db.set_trusted_server_override(server_name, false)?;
+
+        let len = trusted_servers.len();
+        trusted_servers.retain(|s| &**s != server_name);
+
+        Ok(trusted_servers.len() != len)
+    }
+
+    /// Returns whether federation is enabled and allowed with the server.
+    pub(crate) fn federation_allowed_with(
+        &self,
+        server_name: &ServerName,
+    ) -> bool {
+        self.allow_federation()
+            && self.federation_policy.read().unwrap().allows(server_name)
+    }
+
+    /// Returns the servers that are allowed and denied federation.
+    pub(crate) fn federation_policy(
+        &self,
+    ) -> (Option<Vec<OwnedServerName>>, Vec<OwnedServerName>) {
+        let policy = self.federation_policy.read().unwrap();
+        (
+            policy.allowed.as_ref().map(|s| s.iter().cloned().collect()),
+            policy.denied.iter().cloned().collect(),
+        )
+    }
+
+    /// Allows or denies federation with a server, persisting the change.
+    /// Returns whether this changed the policy.
+    pub(crate) fn set_federation_allowed_with(
+        &self,
+        server_name: &ServerName,
+        allow: bool,
+    ) -> Result<bool> {
+        let mut policy = self.federation_policy.write().unwrap();
+        self.db.set_federation_override(server_name, allow)?;
+
+        Ok(policy.set(server_name, allow))
     }
 
     pub(crate) fn dns_resolver(&self) -> &TokioAsyncResolver {
@@ -407,7 +633,7 @@ impl Service {
     }
 
     pub(crate) fn supported_room_versions(&self) -> Vec<RoomVersionId> {
-        self.stable_room_versions.clone()
+        self.room_versions.keys().cloned().collect()
     }
 
     /// This doesn't actually check that the keys provided are newer than the
@@ -417,6 +643,11 @@ impl Service {
         origin: &ServerName,
         new_keys: ServerSigningKeys,
     ) -> Result<SigningKeys> {
+        // Other servers must not be able to add keys for this server
+        if origin == self.server_name() {
+            return SigningKeys::load_own_keys();
+        }
+
         self.db.add_signing_key_from_trusted_server(origin, new_keys)
     }
 
@@ -427,6 +658,10 @@ impl Service {
         origin: &ServerName,
         new_keys: ServerSigningKeys,
     ) -> Result<SigningKeys> {
+        if origin == self.server_name() {
+            return SigningKeys::load_own_keys();
+        }
+
         self.db.add_signing_key_from_origin(origin, new_keys)
     }
 
@@ -435,9 +670,33 @@ impl Service {
         &self,
         origin: &ServerName,
     ) -> Result<Option<SigningKeys>> {
-        Ok(self.db.signing_keys_for(origin)?.or_else(|| {
-            (origin == self.server_name()).then(SigningKeys::load_own_keys)
-        }))
+        if origin == self.server_name() {
+            return SigningKeys::load_own_keys().map(Some);
+        }
+
+        self.db.signing_keys_for(origin)
+    }
+
+    /// Stores the key response of `origin` as it was received and signed by
+    /// `origin`
+    pub(crate) fn set_signed_keys(
+        &self,
+        origin: &ServerName,
+        keys: &Raw<ServerSigningKeys>,
+    ) -> Result<()> {
+        if origin == self.server_name() {
+            return Ok(());
+        }
+
+        self.db.set_signed_keys(origin, keys)
+    }
+
+    /// Returns the last key response of `origin` as it was signed by `origin`
+    pub(crate) fn signed_keys_for(
+        &self,
+        origin: &ServerName,
+    ) -> Result<Option<Raw<ServerSigningKeys>>> {
+        self.db.signed_keys_for(origin)
     }
 
     /// Filters the key map of multiple servers down to keys that should be
@@ -532,3 +791,77 @@ fn reqwest_client_builder(config: &Config) -> Result<reqwest::ClientBuilder> {
 
     Ok(reqwest_client_builder)
 }
+
+/// Enumerates the room versions supported by this server and their stability,
+/// taking the overrides from the config into account
+fn available_room_versions(
+    config: &Config,
+) -> BTreeMap<RoomVersionId, RoomVersionStability> {
+    [
+        RoomVersionId::V6,
+        RoomVersionId::V7,
+        RoomVersionId::V8,
+        RoomVersionId::V9,
+        RoomVersionId::V10,
+        RoomVersionId::V11,
+    ]
+    .into_iter()
+    // Only versions that state resolution knows about can be supported
+    .filter(|version| RoomVersion::new(version).is_ok())
+    .filter_map(|version| {
+        let stability = match config.room_version_overrides.get(&version) {
+            None => RoomVersionStability::Stable,
+            Some(RoomVersionOverride::Unstable) => {
+                RoomVersionStability::Unstable
+            }
+            Some(RoomVersionOverride::Disabled) => return None,
+        };
+
+        Some((version, stability))
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::server_name;
+
+    use super::FederationPolicy;
+
+    #[test]
+    fn denied_servers_are_not_federated_with() {
+        let mut policy = FederationPolicy::default();
+        assert!(policy.allows(server_name!("a.example.com")));
+
+        assert!(policy.set(server_name!("a.example.com"), false));
+        assert!(!policy.allows(server_name!("a.example.com")));
+        assert!(policy.allows(server_name!("b.example.com")));
+
+        assert!(policy.set(server_name!("a.example.com"), true));
+        assert!(policy.allows(server_name!("a.example.com")));
+        assert!(!policy.set(server_name!("a.example.com"), true));
+    }
+
+    #[test]
+    fn only_allowed_servers_are_federated_with() {
+        let mut policy = FederationPolicy {
+            allowed: Some([server_name!("a.example.com").to_owned()].into()),
+            denied: [server_name!("b.example.com").to_owned()].into(),
+        };
+        assert!(policy.allows(server_name!("a.example.com")));
+        assert!(!policy.allows(server_name!("b.example.com")));
+        assert!(!policy.allows(server_name!("c.example.com")));
+
+        // Allowing a denied server also adds it to the allowlist
+        assert!(policy.set(server_name!("b.example.com"), true));
+        assert!(policy.allows(server_name!("b.example.com")));
+
+        // Denying an allowed server also removes it from the allowlist
+        assert!(policy.set(server_name!("a.example.com"), false));
+        assert!(!policy.allows(server_name!("a.example.com")));
+        assert!(!policy
+            .allowed
+            .unwrap()
+            .contains(server_name!("a.example.com")));
+    }
+}
@@ -1,14 +1,15 @@
 use std::{
     collections::BTreeMap,
+    path::Path,
     time::{Duration, SystemTime},
 };
 
 use async_trait::async_trait;
 use ruma::{
     api::federation::discovery::{OldVerifyKey, ServerSigningKeys, VerifyKey},
-    serde::Base64,
+    serde::{Base64, Raw},
     signatures::Ed25519KeyPair,
-    DeviceId, MilliSecondsSinceUnixEpoch, ServerName, UserId,
+    DeviceId, MilliSecondsSinceUnixEpoch, OwnedServerName, ServerName, UserId,
 };
 use serde::Deserialize;
 
@@ -24,11 +25,20 @@ pub(crate) struct SigningKeys {
 }
 
 impl SigningKeys {
-    /// Creates the `SigningKeys` struct, using the keys of the current server
-    pub(crate) fn load_own_keys() -> Self {
+    /// Creates the `SigningKeys` struct, using the current key of this server
+    /// and the keys it used before they were rotated
+    pub(crate) fn load_own_keys() -> Result<Self> {
+        let old_verify_keys = services()
+            .globals
+            .db
+            .signing_keys_for(services().globals.server_name())?
+            .map(|keys| keys.old_verify_keys)
+            .unwrap_or_default();
+
+        let keypair = services().globals.keypair();
         let mut keys = Self {
             verify_keys: BTreeMap::new(),
-            old_verify_keys: BTreeMap::new(),
+            old_verify_keys,
             valid_until_ts: MilliSecondsSinceUnixEpoch::from_system_time(
                 SystemTime::now() + Duration::from_secs(7 * 86400),
             )
@@ -36,15 +46,13 @@ impl SigningKeys {
         };
 
         keys.verify_keys.insert(
-            format!("ed25519:{}", services().globals.keypair().version()),
+            format!("ed25519:{}", keypair.version()),
             VerifyKey {
-                key: Base64::new(
-                    services().globals.keypair.public_key().to_vec(),
-                ),
+                key: Base64::new(keypair.public_key().to_vec()),
             },
         );
 
-        keys
+        Ok(keys)
     }
 }
 
@@ -79,8 +87,20 @@ pub(crate) trait Data: Send + Sync {
         -> Result<()>;
     fn cleanup(&self) -> Result<()>;
     fn memory_usage(&self) -> String;
+    /// Writes a consistent snapshot of the database to `path`, returning
+    /// instructions for restoring it
+    fn backup(&self, path: &Path) -> Result<&'static str>;
+    /// Compacts `tree`, or all trees if it is `None`
+    fn compact(&self, tree: Option<&str>) -> Result<()>;
+    /// Verifies the database engine's data and the consistency between
+    /// trees, returning a description of each problem found
+    fn check_integrity(&self) -> Result<Vec<String>>;
+    /// Returns the name, number of entries and capacity of each LRU cache
+    fn cache_sizes(&self) -> Vec<(&'static str, usize, usize)>;
     fn clear_caches(&self, amount: u32);
     fn load_keypair(&self) -> Result<Ed25519KeyPair>;
+    /// Generates a new keypair and stores it in place of the current one
+    fn replace_keypair(&self) -> Result<Ed25519KeyPair>;
     fn remove_keypair(&self) -> Result<()>;
     /// Only extends the cached keys, not moving any verify_keys to
     /// old_verify_keys, as if we suddenly recieve requests from the origin
@@ -105,6 +125,34 @@ pub(crate) trait Data: Send + Sync {
         &self,
         origin: &ServerName,
     ) -> Result<Option<SigningKeys>>;
+    /// Stores the key response of `origin` as it was received, so that it can
+    /// be served with the signatures of `origin` as a notary
+    fn set_signed_keys(
+        &self,
+        origin: &ServerName,
+        keys: &Raw<ServerSigningKeys>,
+    ) -> Result<()>;
+    fn signed_keys_for(
+        &self,
+        origin: &ServerName,
+    ) -> Result<Option<Raw<ServerSigningKeys>>>;
+    /// Returns the servers that were added (`true`) or removed (`false`) as
+    /// trusted servers, overriding `federation.trusted_servers`
+    fn trusted_server_overrides(&self) -> Result<Vec<(OwnedServerName, bool)>>;
+    fn set_trusted_server_override(
+        &self,
+        server_name: &ServerName,
+        trusted: bool,
+    ) -> Result<()>;
+    /// Returns the servers that were allowed (`true`) or denied (`false`)
+    /// federation, overriding `federation.allowed_servers` and
+    /// `federation.denied_servers`
+    fn federation_overrides(&self) -> Result<Vec<(OwnedServerName, bool)>>;
+    fn set_federation_override(
+        &self,
+        server_name: &ServerName,
+        allowed: bool,
+    ) -> Result<()>;
     fn database_version(&self) -> Result<u64>;
     fn bump_database_version(&self, new_version: u64) -> Result<()>;
 }
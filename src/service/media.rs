@@ -1,13 +1,33 @@
-use std::io::Cursor;
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Cursor},
+    path::Path,
+    sync::{
+        atomic::{self, AtomicBool},
+        Mutex as StdMutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use image::imageops::FilterType;
+use image::{
+    imageops::FilterType, io::Reader as ImageReader, DynamicImage, ImageError,
+    Limits,
+};
+use ruma::{
+    api::client::{error::ErrorKind, media::get_content_thumbnail::v3::Method},
+    events::room::avatar::RoomAvatarEventContent,
+    JsOption, OwnedRoomId, OwnedServerName, ServerName, UserId,
+};
 use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncWriteExt},
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
 };
-use tracing::{debug, warn};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
-use crate::{services, Result};
+use crate::{service::rooms::timeline::PduCount, services, Error, Result};
+
+/// Media referenced by events this recent is never purged
+const RECENT_EVENTS: Duration = Duration::from_secs(7 * 24 * 60 * 60);
 
 mod data;
 
@@ -25,8 +45,54 @@ pub(crate) struct FileMeta {
     pub(crate) file: Vec<u8>,
 }
 
+/// Part of a file read by [`Service::get_range`]
+pub(crate) struct FileRange {
+    pub(crate) content_type: Option<String>,
+    /// Size of the whole file
+    pub(crate) len: u64,
+    /// The first and last byte of the selected range and the content in
+    /// between, or `None` if the requested range can't be satisfied
+    pub(crate) range: Option<((u64, u64), Vec<u8>)>,
+}
+
+/// What was deleted by [`Service::purge`]
+#[derive(Debug, Default)]
+pub(crate) struct PurgeStats {
+    /// Number of remote files and thumbnails that were deleted
+    pub(crate) files: usize,
+    /// Number of files without metadata that were deleted
+    pub(crate) orphans: usize,
+    /// Total size of all deleted files
+    pub(crate) bytes: u64,
+}
+
+/// Something a remote media fetch failed for
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum RemoteFailure {
+    /// The remote server could not be reached
+    Server(OwnedServerName),
+    /// The remote server did not return this media
+    Media(String),
+}
+
 pub(crate) struct Service {
     pub(crate) db: &'static dyn Data,
+
+    /// Recent failures to fetch remote media and when they happened
+    pub(crate) remote_failures: StdMutex<HashMap<RemoteFailure, Instant>>,
+
+    /// Total size of all cached remote media, if it has been computed yet
+    pub(crate) remote_cache_size: StdMutex<Option<u64>>,
+
+    /// Whether a purge is running to get back under the remote cache budget
+    pub(crate) budget_purge_running: AtomicBool,
+
+    /// Held while the media usage of a user is checked and updated
+    pub(crate) usage_lock: StdMutex<()>,
+
+    /// Count of the newest event of each room that was older than the cutoff
+    /// of a purge, so that later purges only look at the events after it
+    pub(crate) retention_positions: StdMutex<HashMap<OwnedRoomId, PduCount>>,
 }
 
 impl Service {
@@ -54,6 +120,94 @@ impl Service {
         Ok(())
     }
 
+    /// Uploads a file of a local user, counting it towards their media quota.
+    ///
+    /// Fails with `M_RESOURCE_LIMIT_EXCEEDED` if the upload would exceed the
+    /// quota.
+    #[tracing::instrument(skip(self, file))]
+    pub(crate) async fn create_upload(
+        &self,
+        mxc: String,
+        user_id: &UserId,
+        content_disposition: Option<&str>,
+        content_type: Option<&str>,
+        file: &[u8],
+    ) -> Result<()> {
+        let size = u64::try_from(file.len()).unwrap_or(u64::MAX);
+
+        {
+            let _guard = self.usage_lock.lock().unwrap();
+
+            if let Some(quota) = self.quota(user_id)? {
+                if self.db.media_usage(user_id)?.saturating_add(size) > quota {
+                    let admin_contact = services()
+                        .globals
+                        .config
+                        .media_quota
+                        .admin_contact
+                        .clone()
+                        .unwrap_or_else(|| {
+                            services()
+                                .globals
+                                .admin_bot_user_id
+                                .matrix_uri(false)
+                                .to_string()
+                        });
+
+                    return Err(Error::BadRequest(
+                        ErrorKind::ResourceLimitExceeded {
+                            admin_contact,
+                        },
+                        "Upload would exceed your media quota.",
+                    ));
+                }
+            }
+
+            self.db.record_upload(&mxc, user_id, size)?;
+        }
+
+        if let Err(error) = self
+            .create(mxc.clone(), content_disposition, content_type, file)
+            .await
+        {
+            self.forget_upload(&mxc)?;
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    /// Returns how much media `user_id` uploaded, in bytes.
+    pub(crate) fn usage(&self, user_id: &UserId) -> Result<u64> {
+        self.db.media_usage(user_id)
+    }
+
+    /// Returns the media quota of `user_id` in bytes, or `None` if it is
+    /// unlimited.
+    pub(crate) fn quota(&self, user_id: &UserId) -> Result<Option<u64>> {
+        Ok(self.db.media_quota(user_id)?.or(services()
+            .globals
+            .config
+            .media_quota
+            .default))
+    }
+
+    /// Sets the media quota override of `user_id`, or removes it so that the
+    /// default quota applies.
+    pub(crate) fn set_quota(
+        &self,
+        user_id: &UserId,
+        quota: Option<u64>,
+    ) -> Result<()> {
+        self.db.set_media_quota(user_id, quota)
+    }
+
+    /// Stops counting `mxc` towards the media usage of its uploader.
+    fn forget_upload(&self, mxc: &str) -> Result<()> {
+        let _guard = self.usage_lock.lock().unwrap();
+        self.db.forget_upload(mxc)
+    }
+
     /// Uploads or replaces a file thumbnail.
     #[allow(clippy::too_many_arguments)]
     #[tracing::instrument(skip(self, file))]
@@ -105,22 +259,94 @@ impl Service {
         }
     }
 
+    /// Reads part of a local file without loading the rest of it into memory.
+    ///
+    /// `select` is called with the size of the file and returns the first and
+    /// last byte to read, or `None` if the requested range can't be satisfied.
+    pub(crate) async fn get_range<F>(
+        &self,
+        mxc: String,
+        select: F,
+    ) -> Result<Option<FileRange>>
+    where
+        F: FnOnce(u64) -> Option<(u64, u64)>,
+    {
+        let Ok((_, content_type, key)) =
+            self.db.search_file_metadata(mxc, 0, 0)
+        else {
+            return Ok(None);
+        };
+
+        let path = services().globals.get_media_file(&key);
+        let Ok(mut file) = File::open(path).await else {
+            return Ok(None);
+        };
+        let len = file.metadata().await?.len();
+
+        let range = match select(len) {
+            Some((start, end)) => {
+                file.seek(io::SeekFrom::Start(start)).await?;
+                let mut content = Vec::new();
+                file.take(end.saturating_sub(start).saturating_add(1))
+                    .read_to_end(&mut content)
+                    .await?;
+                Some(((start, end), content))
+            }
+            None => None,
+        };
+
+        Ok(Some(FileRange {
+            content_type,
+            len,
+            range,
+        }))
+    }
+
     /// Returns width, height of the thumbnail and whether it should be cropped.
     /// Returns None when the server should send the original file.
+    ///
+    /// Cropped thumbnails are always square and scaled ones never are, so the
+    /// dimensions alone identify the method in the database.
     fn thumbnail_properties(
         width: u32,
         height: u32,
+        method: &Method,
     ) -> Option<(u32, u32, bool)> {
-        match (width, height) {
-            (0..=32, 0..=32) => Some((32, 32, true)),
-            (0..=96, 0..=96) => Some((96, 96, true)),
-            (0..=320, 0..=240) => Some((320, 240, false)),
-            (0..=640, 0..=480) => Some((640, 480, false)),
-            (0..=800, 0..=600) => Some((800, 600, false)),
-            _ => None,
+        match method {
+            Method::Crop => match width.max(height) {
+                0..=32 => Some((32, 32, true)),
+                0..=96 => Some((96, 96, true)),
+                0..=320 => Some((320, 320, true)),
+                0..=640 => Some((640, 640, true)),
+                0..=800 => Some((800, 800, true)),
+                _ => None,
+            },
+            _ => match (width, height) {
+                (0..=320, 0..=240) => Some((320, 240, false)),
+                (0..=640, 0..=480) => Some((640, 480, false)),
+                (0..=800, 0..=600) => Some((800, 600, false)),
+                _ => None,
+            },
         }
     }
 
+    /// Decodes an image, refusing images larger than the configured limit
+    /// before allocating memory for them.
+    fn decode_image(file: &[u8]) -> image::ImageResult<DynamicImage> {
+        let max_dimension =
+            services().globals.config.media_thumbnails.max_source_dimension;
+
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(max_dimension);
+        limits.max_image_height = Some(max_dimension);
+
+        let mut reader = ImageReader::new(Cursor::new(file))
+            .with_guessed_format()
+            .map_err(ImageError::IoError)?;
+        reader.limits(limits);
+        reader.decode()
+    }
+
     /// Generates a thumbnail from the given image file contents. Returns
     /// `Ok(None)` if the input image should be used as-is.
     #[tracing::instrument(
@@ -133,7 +359,7 @@ impl Service {
         height: u32,
         crop: bool,
     ) -> Result<Option<Vec<u8>>> {
-        let image = match image::load_from_memory(file) {
+        let image = match Self::decode_image(file) {
             Ok(image) => image,
             Err(error) => {
                 warn!(%error, "Failed to parse source image");
@@ -223,10 +449,12 @@ impl Service {
         mxc: String,
         width: u32,
         height: u32,
+        method: &Method,
     ) -> Result<Option<FileMeta>> {
         // 0, 0 because that's the original file
         let (width, height, crop) =
-            Self::thumbnail_properties(width, height).unwrap_or((0, 0, false));
+            Self::thumbnail_properties(width, height, method)
+                .unwrap_or((0, 0, false));
 
         if let Ok((content_disposition, content_type, key)) =
             self.db.search_file_metadata(mxc.clone(), width, height)
@@ -254,6 +482,15 @@ impl Service {
         let mut file = Vec::new();
         File::open(path).await?.read_to_end(&mut file).await?;
 
+        if !services().globals.config.media_thumbnails.enable {
+            debug!("Returning source image as-is");
+            return Ok(Some(FileMeta {
+                content_disposition,
+                content_type,
+                file,
+            }));
+        }
+
         debug!("Generating thumbnail");
         let thumbnail_result = {
             let file = file.clone();
@@ -299,4 +536,331 @@ impl Service {
             file: thumbnail_bytes.clone(),
         }))
     }
+
+    /// Fails if fetching `mxc` from `server_name` failed recently.
+    pub(crate) fn check_remote_failures(
+        &self,
+        mxc: &str,
+        server_name: &ServerName,
+    ) -> Result<()> {
+        let ttl = services().globals.config.remote_media.failure_cache_duration;
+        let mut failures = self.remote_failures.lock().unwrap();
+        failures.retain(|_, failed_at| failed_at.elapsed() < ttl);
+
+        if failures.contains_key(&RemoteFailure::Server(server_name.to_owned()))
+            || failures.contains_key(&RemoteFailure::Media(mxc.to_owned()))
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::NotFound,
+                "Remote media failed to be fetched recently.",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Remembers that fetching `mxc` from `server_name` failed with `error`.
+    pub(crate) fn record_remote_failure(
+        &self,
+        mxc: &str,
+        server_name: &ServerName,
+        error: &Error,
+    ) {
+        let failure = if let Error::Federation(..) = error {
+            // The server responded, so only this media is unavailable
+            RemoteFailure::Media(mxc.to_owned())
+        } else {
+            RemoteFailure::Server(server_name.to_owned())
+        };
+
+        self.remote_failures.lock().unwrap().insert(failure, Instant::now());
+    }
+
+    /// Accounts `size` bytes of newly cached remote media against the
+    /// remote cache budget, deleting old remote media if it is exceeded.
+    pub(crate) fn track_remote_cached(&'static self, size: usize) {
+        let Some(budget) =
+            services().globals.config.remote_media.max_cache_size
+        else {
+            return;
+        };
+
+        let over_budget = {
+            let mut total = self.remote_cache_size.lock().unwrap();
+            total.as_mut().map_or(true, |total| {
+                *total += u64::try_from(size).unwrap_or(u64::MAX);
+                *total > budget
+            })
+        };
+
+        if !over_budget
+            || self.budget_purge_running.swap(true, atomic::Ordering::AcqRel)
+        {
+            return;
+        }
+
+        tokio::spawn(
+            async move {
+                match self.purge(UNIX_EPOCH, Some(budget)).await {
+                    Ok(stats) => debug!(
+                        files = stats.files,
+                        bytes = stats.bytes,
+                        "Remote media cache is within budget again",
+                    ),
+                    Err(error) => {
+                        error!(%error, "Failed to enforce remote media budget");
+                    }
+                }
+                self.budget_purge_running
+                    .store(false, atomic::Ordering::Release);
+            }
+            .instrument(info_span!("remote_media_budget")),
+        );
+    }
+
+    /// Deletes remote media that was fetched before `before`, and the oldest
+    /// remaining remote media until all remote media takes up at most
+    /// `max_total_size` bytes.
+    ///
+    /// Files in the media directory without metadata are deleted as well.
+    /// Media that is used as an avatar or referenced by an event from the
+    /// last week is never deleted.
+    #[tracing::instrument(skip(self))]
+    pub(crate) async fn purge(
+        &self,
+        before: SystemTime,
+        max_total_size: Option<u64>,
+    ) -> Result<PurgeStats> {
+        // Files created after this point may not be visible in the metadata
+        // we read below yet, so they must not be considered orphaned
+        let start = SystemTime::now();
+        let referenced = self.referenced_media(
+            start.checked_sub(RECENT_EVENTS).unwrap_or(UNIX_EPOCH),
+        )?;
+        let server_name = services().globals.server_name();
+
+        let mut stats = PurgeStats::default();
+        let mut known_files = HashSet::new();
+        let mut candidates = Vec::new();
+        let mut remote_size = 0;
+
+        let entries = self.db.all_file_metadata().collect::<Vec<_>>();
+        for entry in entries {
+            let (mxc, key) = entry?;
+            let path = services().globals.get_media_file(&key);
+            known_files.insert(path.clone());
+
+            let metadata = match fs::metadata(&path).await {
+                Ok(metadata) => metadata,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                    continue;
+                }
+                Err(error) => return Err(error.into()),
+            };
+
+            let is_remote = mxc
+                .strip_prefix("mxc://")
+                .and_then(|rest| rest.split_once('/'))
+                .is_some_and(|(origin, _)| origin != server_name.as_str());
+            if !is_remote {
+                continue;
+            }
+
+            remote_size += metadata.len();
+            if referenced.contains(&mxc) {
+                continue;
+            }
+
+            candidates.push((
+                metadata.modified()?,
+                metadata.len(),
+                mxc,
+                key,
+                path,
+            ));
+        }
+
+        // Oldest first, so that the size limit deletes the oldest media
+        candidates.sort_by_key(|(modified, ..)| *modified);
+
+        for (modified, size, mxc, key, path) in candidates {
+            let expired = modified < before;
+            let too_large = max_total_size.is_some_and(|max| remote_size > max);
+            if !expired && !too_large {
+                break;
+            }
+
+            debug!(path = %path.display(), "Deleting remote media");
+            self.db.delete_file_metadata(&key)?;
+            self.forget_upload(&mxc)?;
+            remove_file_if_exists(&path).await?;
+
+            remote_size = remote_size.saturating_sub(size);
+            stats.files += 1;
+            stats.bytes += size;
+        }
+
+        // Remote media cached while this purge ran is not accounted for, but
+        // will be picked up by the next one
+        *self.remote_cache_size.lock().unwrap() = Some(remote_size);
+
+        let mut dir =
+            fs::read_dir(services().globals.get_media_folder()).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if known_files.contains(&path) {
+                continue;
+            }
+
+            let metadata = entry.metadata().await?;
+            if !metadata.is_file() || metadata.modified()? >= start {
+                continue;
+            }
+
+            debug!(path = %path.display(), "Deleting orphaned media file");
+            remove_file_if_exists(&path).await?;
+
+            stats.orphans += 1;
+            stats.bytes += metadata.len();
+        }
+
+        Ok(stats)
+    }
+
+    /// Starts a task that periodically applies the configured media
+    /// retention policy.
+    pub(crate) fn start_retention_task(&'static self) {
+        let config = &services().globals.config.media_retention;
+        if config.max_age.is_none() && config.max_total_size.is_none() {
+            return;
+        }
+
+        tokio::spawn(async move {
+            let mut i = tokio::time::interval(config.interval);
+
+            loop {
+                i.tick().await;
+
+                async {
+                    let start = Instant::now();
+                    let before = config.max_age.map_or(UNIX_EPOCH, |max_age| {
+                        SystemTime::now()
+                            .checked_sub(max_age)
+                            .unwrap_or(UNIX_EPOCH)
+                    });
+
+                    match self.purge(before, config.max_total_size).await {
+                        Ok(stats) => info!(
+                            files = stats.files,
+                            orphans = stats.orphans,
+                            bytes = stats.bytes,
+                            elapsed = ?start.elapsed(),
+                            "media_retention: Finished",
+                        ),
+                        Err(error) => error!(%error, "media_retention: Error"),
+                    }
+                }
+                .instrument(info_span!("media_retention"))
+                .await;
+            }
+        });
+    }
+
+    /// Collects the MXC URIs of all local user avatars, room avatars, and of
+    /// media referenced by events sent after `since`.
+    fn referenced_media(&self, since: SystemTime) -> Result<HashSet<String>> {
+        let since = u64::try_from(
+            since
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or(Duration::ZERO)
+                .as_millis(),
+        )
+        .unwrap_or(u64::MAX);
+
+        let mut referenced = HashSet::new();
+
+        for user_id in services().users.iter() {
+            if let Some(avatar_url) = services().users.avatar_url(&user_id?)? {
+                referenced.insert(avatar_url.to_string());
+            }
+        }
+
+        let room_ids = services()
+            .rooms
+            .metadata
+            .iter_ids()
+            .collect::<Result<Vec<OwnedRoomId>>>()?;
+        for room_id in room_ids {
+            if let JsOption::Some(RoomAvatarEventContent {
+                url: Some(url),
+                ..
+            }) = services().rooms.state_accessor.get_avatar(&room_id)?
+            {
+                referenced.insert(url.to_string());
+            }
+
+            // Events are walked oldest first, starting after the last one
+            // that was older than the cutoff. Once the cutoff is reached every
+            // later event is checked, even if its timestamp is older.
+            let mut start = self
+                .retention_positions
+                .lock()
+                .unwrap()
+                .get(&room_id)
+                .copied()
+                .unwrap_or(PduCount::MIN);
+            let mut reached_cutoff = false;
+            for pdu in services().rooms.timeline.pdus_after(
+                &services().globals.admin_bot_user_id,
+                &room_id,
+                start,
+            )? {
+                let (count, pdu) = pdu?;
+                if !reached_cutoff {
+                    if u64::from(pdu.origin_server_ts) < since {
+                        start = count;
+                        continue;
+                    }
+                    reached_cutoff = true;
+                }
+
+                if let Ok(content) =
+                    serde_json::from_str::<serde_json::Value>(pdu.content.get())
+                {
+                    collect_mxc_uris(&content, &mut referenced);
+                }
+            }
+            self.retention_positions.lock().unwrap().insert(room_id, start);
+        }
+
+        Ok(referenced)
+    }
+}
+
+/// Adds all strings in `value` that look like MXC URIs to `uris`.
+fn collect_mxc_uris(value: &serde_json::Value, uris: &mut HashSet<String>) {
+    match value {
+        serde_json::Value::String(s) if s.starts_with("mxc://") => {
+            uris.insert(s.clone());
+        }
+        serde_json::Value::Array(values) => {
+            for value in values {
+                collect_mxc_uris(value, uris);
+            }
+        }
+        serde_json::Value::Object(map) => {
+            for value in map.values() {
+                collect_mxc_uris(value, uris);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Removes a file, ignoring it if it has already been removed.
+async fn remove_file_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path).await {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
 }
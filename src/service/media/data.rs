@@ -1,3 +1,5 @@
+use ruma::UserId;
+
 use crate::Result;
 
 pub(crate) trait Data: Send + Sync {
@@ -17,4 +19,39 @@ pub(crate) trait Data: Send + Sync {
         width: u32,
         height: u32,
     ) -> Result<(Option<String>, Option<String>, Vec<u8>)>;
+
+    /// Returns an iterator over the MXC URI and `metadata` key of all files
+    /// and thumbnails.
+    fn all_file_metadata<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(String, Vec<u8>)>> + 'a>;
+
+    /// Removes the metadata of a file or thumbnail by its `metadata` key.
+    fn delete_file_metadata(&self, key: &[u8]) -> Result<()>;
+
+    /// Records `user_id` as the uploader of `mxc` and adds `size` to their
+    /// media usage.
+    fn record_upload(
+        &self,
+        mxc: &str,
+        user_id: &UserId,
+        size: u64,
+    ) -> Result<()>;
+
+    /// Forgets the uploader of `mxc` and subtracts the size of the upload
+    /// from their media usage. Does nothing if the uploader isn't known.
+    fn forget_upload(&self, mxc: &str) -> Result<()>;
+
+    /// Returns the total size of the media uploaded by `user_id`.
+    fn media_usage(&self, user_id: &UserId) -> Result<u64>;
+
+    /// Returns the media quota override of `user_id`.
+    fn media_quota(&self, user_id: &UserId) -> Result<Option<u64>>;
+
+    /// Sets or removes the media quota override of `user_id`.
+    fn set_media_quota(
+        &self,
+        user_id: &UserId,
+        quota: Option<u64>,
+    ) -> Result<()>;
 }
@@ -108,6 +108,19 @@ impl PduEvent {
         unsigned.redacted_because.is_some()
     }
 
+    /// Returns the state key of the `m.room.third_party_invite` event this
+    /// membership event claims to be authorized by, if any
+    pub(crate) fn third_party_invite_token(&self) -> Option<String> {
+        if self.kind != TimelineEventType::RoomMember {
+            return None;
+        }
+
+        let content: RoomMemberEventContent =
+            serde_json::from_str(self.content.get()).ok()?;
+
+        content.third_party_invite.map(|invite| invite.signed.token)
+    }
+
     pub(crate) fn remove_transaction_id(&mut self) -> crate::Result<()> {
         if let Some(unsigned) = &self.unsigned {
             let mut unsigned: BTreeMap<String, Box<RawJsonValue>> =
@@ -1,11 +1,22 @@
 mod data;
-use std::{fmt::Debug, mem};
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Write as _},
+    mem,
+    sync::Mutex as StdMutex,
+    time::Instant,
+};
 
 use bytes::BytesMut;
 pub(crate) use data::Data;
+use http::StatusCode;
+use lettre::message::Mailbox;
 use ruma::{
     api::{
-        client::push::{set_pusher, Pusher, PusherKind},
+        client::{
+            error::ErrorKind,
+            push::{set_pusher, Pusher, PusherKind},
+        },
         push_gateway::send_event_notification::{
             self,
             v1::{
@@ -18,22 +29,50 @@ use ruma::{
         room::power_levels::RoomPowerLevelsEventContent, AnySyncTimelineEvent,
         StateEventType, TimelineEventType,
     },
+    presence::PresenceState,
     push::{
         Action, PushConditionPowerLevelsCtx, PushConditionRoomCtx, PushFormat,
         Ruleset, Tweak,
     },
     serde::Raw,
-    uint, RoomId, UInt, UserId,
+    uint, OwnedUserId, RoomId, UInt, UserId,
 };
+use serde::Deserialize;
 use tracing::warn;
 
-use crate::{services, utils, Error, PduEvent, Result};
+use crate::{
+    observability::{PushOutcome, METRICS},
+    services, utils, Error, PduEvent, Result,
+};
 
 pub(crate) struct Service {
     pub(crate) db: &'static dyn Data,
+
+    /// When each user was last sent a notification email
+    pub(crate) last_email: StdMutex<HashMap<OwnedUserId, Instant>>,
 }
 
 impl Service {
+    /// Checks that an email pusher can be added for `pushkey`, which must be
+    /// an email address
+    pub(crate) fn check_email_pusher(&self, pushkey: &str) -> Result<()> {
+        if services().globals.mailer.is_none() {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "This server can't send notification emails.",
+            ));
+        }
+
+        if pushkey.parse::<Mailbox>().is_err() {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Pushkey of email pushers must be an email address.",
+            ));
+        }
+
+        Ok(())
+    }
+
     pub(crate) fn set_pusher(
         &self,
         sender: &UserId,
@@ -61,12 +100,16 @@ impl Service {
         self.db.get_pushkeys(sender)
     }
 
+    /// Sends a request to a push gateway
+    ///
+    /// Returns `None` if the gateway responded with `410 Gone`, which means
+    /// that it will never accept requests for this pusher again.
     #[tracing::instrument(skip(self, destination, request))]
     pub(crate) async fn send_request<T>(
         &self,
         destination: &str,
         request: T,
-    ) -> Result<T::IncomingResponse>
+    ) -> Result<Option<T::IncomingResponse>>
     where
         T: OutgoingRequest + Debug,
     {
@@ -110,6 +153,10 @@ impl Service {
                     Vec::new().into()
                 });
 
+                if status == StatusCode::GONE {
+                    return Ok(None);
+                }
+
                 if status != 200 {
                     warn!(
                         push_gateway = %destination,
@@ -128,7 +175,7 @@ impl Service {
                         .body(body)
                         .expect("reqwest body is valid http body"),
                 );
-                response.map_err(|error| {
+                response.map(Some).map_err(|error| {
                     warn!(
                         %error,
                         appservice = %destination,
@@ -201,7 +248,7 @@ impl Service {
         }
 
         if notify == Some(true) {
-            self.send_notice(unread, pusher, tweaks, pdu).await?;
+            self.send_notice(user, unread, pusher, tweaks, pdu).await?;
         }
         // Else the event triggered no actions
 
@@ -238,22 +285,24 @@ impl Service {
         Ok(ruleset.get_actions(pdu, &ctx))
     }
 
+    /// Sends a notification about `event` to a pusher
+    ///
+    /// Pushers whose pushkey is rejected by the gateway are removed, as are
+    /// pushers of gateways that are gone.
     #[tracing::instrument(skip(self, unread, pusher, tweaks, event))]
     async fn send_notice(
         &self,
+        user: &UserId,
         unread: UInt,
         pusher: &Pusher,
         tweaks: Vec<Tweak>,
         event: &PduEvent,
     ) -> Result<()> {
-        // TODO: email
         match &pusher.kind {
             PusherKind::Http(http) => {
-                // TODO:
-                // Two problems with this
-                // 1. if "event_id_only" is the only format kind it seems we
-                //    should never add more info
-                // 2. can pusher/devices have conflicting formats
+                // With the `event_id_only` format, only the IDs of the event
+                // and the counts are sent and gateways fetch the rest
+                // themselves
                 let event_id_only =
                     http.format == Some(PushFormat::EventIdOnly);
 
@@ -286,13 +335,7 @@ impl Service {
                     notifi.prio = NotificationPriority::High;
                 }
 
-                if event_id_only {
-                    self.send_request(
-                        &http.url,
-                        send_event_notification::v1::Request::new(notifi),
-                    )
-                    .await?;
-                } else {
+                if !event_id_only {
                     notifi.sender = Some(event.sender.clone());
                     notifi.event_type = Some(event.kind.clone());
                     notifi.content =
@@ -310,18 +353,126 @@ impl Service {
                         .rooms
                         .state_accessor
                         .get_name(&event.room_id)?;
+                }
 
-                    self.send_request(
+                let response = self
+                    .send_request(
                         &http.url,
                         send_event_notification::v1::Request::new(notifi),
                     )
-                    .await?;
+                    .await;
+
+                let outcome = match &response {
+                    Ok(Some(response))
+                        if response.rejected.contains(&pusher.ids.pushkey) =>
+                    {
+                        PushOutcome::Rejected
+                    }
+                    Ok(Some(_)) => PushOutcome::Success,
+                    Ok(None) => PushOutcome::Gone,
+                    Err(_) => PushOutcome::Failure,
+                };
+
+                let gateway = reqwest::Url::parse(&http.url)
+                    .ok()
+                    .and_then(|url| url.host_str().map(ToOwned::to_owned))
+                    .unwrap_or_else(|| "invalid".to_owned());
+                METRICS.record_push_gateway_request(gateway, outcome);
+
+                if let PushOutcome::Rejected | PushOutcome::Gone = outcome {
+                    warn!(
+                        push_gateway = %http.url,
+                        outcome = <&str>::from(outcome),
+                        "Removing pusher that the push gateway doesn't accept",
+                    );
+                    self.set_pusher(
+                        user,
+                        set_pusher::v3::PusherAction::Delete(
+                            pusher.ids.clone(),
+                        ),
+                    )?;
                 }
 
-                Ok(())
+                response.map(|_| ())
+            }
+            PusherKind::Email(_) => {
+                self.send_email_notice(user, unread, pusher, event).await
             }
-            // TODO: Handle email
             _ => Ok(()),
         }
     }
+
+    /// Sends an email about `event` to an email pusher
+    ///
+    /// Nothing is sent if the user is online or was already sent an email
+    /// within the digest interval.
+    #[tracing::instrument(skip(self, unread, pusher, event))]
+    async fn send_email_notice(
+        &self,
+        user: &UserId,
+        unread: UInt,
+        pusher: &Pusher,
+        event: &PduEvent,
+    ) -> Result<()> {
+        #[derive(Deserialize)]
+        struct ExtractBody {
+            body: String,
+        }
+
+        let (Some(mailer), Some(smtp)) =
+            (&services().globals.mailer, &services().globals.config.smtp)
+        else {
+            return Ok(());
+        };
+
+        let online =
+            services().rooms.edus.presence.get_presence(user)?.is_some_and(
+                |presence| presence.content.presence == PresenceState::Online,
+            );
+        if online {
+            return Ok(());
+        }
+
+        if self
+            .last_email
+            .lock()
+            .unwrap()
+            .get(user)
+            .is_some_and(|sent| sent.elapsed() < smtp.digest_interval)
+        {
+            return Ok(());
+        }
+
+        let to = pusher.ids.pushkey.parse::<Mailbox>().map_err(|_| {
+            Error::bad_database("Pushkey of email pusher is invalid.")
+        })?;
+
+        let sender = services()
+            .users
+            .displayname(&event.sender)?
+            .unwrap_or_else(|| event.sender.to_string());
+        let room = services()
+            .rooms
+            .state_accessor
+            .get_name(&event.room_id)?
+            .unwrap_or_else(|| event.room_id.to_string());
+
+        let mut text = format!("{sender} sent a message in {room}");
+        if let Ok(content) =
+            serde_json::from_str::<ExtractBody>(event.content.get())
+        {
+            write!(text, ":\n\n{}", content.body)
+                .expect("writing to a String never fails");
+        } else {
+            text.push('.');
+        }
+        write!(text, "\n\nYou have {unread} unread notifications in {room}.")
+            .expect("writing to a String never fails");
+
+        mailer.send(to, format!("New messages in {room}"), text).await?;
+
+        self.last_email.lock().unwrap().insert(user.to_owned(), Instant::now());
+
+        Ok(())
+    }
 }
@@ -1,4 +1,359 @@
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use ruma::{
+    directory::{PublicRoomJoinRule, PublicRoomsChunk},
+    events::{
+        room::{
+            avatar::RoomAvatarEventContent,
+            canonical_alias::RoomCanonicalAliasEventContent,
+            create::RoomCreateEventContent,
+            guest_access::{GuestAccess, RoomGuestAccessEventContent},
+            history_visibility::{
+                HistoryVisibility, RoomHistoryVisibilityEventContent,
+            },
+            join_rules::{JoinRule, RoomJoinRulesEventContent},
+            topic::RoomTopicEventContent,
+        },
+        StateEventType,
+    },
+    OwnedRoomId, RoomId,
+};
+use tracing::{debug, error, info_span, warn, Instrument};
+
+use crate::{observability::METRICS, services, Error, Result};
+
 mod data;
 
 pub(crate) use data::Data;
-pub(crate) type Service = &'static dyn Data;
+
+/// In-memory summaries of the rooms in the public room directory
+#[derive(Default)]
+struct DirectoryCache {
+    /// Summary of every public room
+    summaries: HashMap<OwnedRoomId, PublicRoomsChunk>,
+    /// Rooms whose summary needs to be recomputed before it is used
+    stale: HashSet<OwnedRoomId>,
+    /// `summaries` in the order they are listed in, `None` if it needs to be
+    /// rebuilt
+    sorted: Option<Arc<Vec<PublicRoomsChunk>>>,
+    /// When all summaries were last recomputed, `None` if never
+    refreshed: Option<Instant>,
+}
+
+pub(crate) struct Service {
+    db: &'static dyn Data,
+    cache: Mutex<DirectoryCache>,
+}
+
+impl Service {
+    pub(crate) fn new<D>(db: &'static D) -> Self
+    where
+        D: Data,
+    {
+        Self {
+            db,
+            cache: Mutex::new(DirectoryCache::default()),
+        }
+    }
+
+    /// Adds the room to the public room directory
+    pub(crate) fn set_public(&self, room_id: &RoomId) -> Result<()> {
+        self.db.set_public(room_id)?;
+        self.invalidate_summary(room_id);
+
+        Ok(())
+    }
+
+    /// Removes the room from the public room directory.
+    pub(crate) fn set_not_public(&self, room_id: &RoomId) -> Result<()> {
+        self.db.set_not_public(room_id)?;
+        self.invalidate_summary(room_id);
+
+        Ok(())
+    }
+
+    /// Returns true if the room is in the public room directory.
+    pub(crate) fn is_public_room(&self, room_id: &RoomId) -> Result<bool> {
+        self.db.is_public_room(room_id)
+    }
+
+    /// Returns the unsorted public room directory
+    pub(crate) fn public_rooms(
+        &self,
+    ) -> impl Iterator<Item = Result<OwnedRoomId>> + '_ {
+        self.db.public_rooms()
+    }
+
+    /// Marks the summary of a room as outdated, e.g. because its name or
+    /// visibility changed
+    pub(crate) fn invalidate_summary(&self, room_id: &RoomId) {
+        let mut cache = self.cache.lock().unwrap();
+        cache.stale.insert(room_id.to_owned());
+        cache.sorted = None;
+    }
+
+    /// Returns the summaries of all public rooms, ordered by the number of
+    /// joined members and then by room ID.
+    ///
+    /// Summaries are only recomputed periodically and when they are
+    /// invalidated, so member counts may be slightly out of date.
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn public_room_summaries(
+        &self,
+    ) -> Result<Arc<Vec<PublicRoomsChunk>>> {
+        if self.cache.lock().unwrap().refreshed.is_none() {
+            self.refresh()?;
+        }
+
+        let mut cache = self.cache.lock().unwrap();
+
+        for room_id in mem::take(&mut cache.stale) {
+            let summary = if self.db.is_public_room(&room_id)? {
+                Self::summarize(room_id.clone())
+            } else {
+                None
+            };
+
+            if let Some(summary) = summary {
+                services().rooms.search.index_room_directory(&summary)?;
+                cache.summaries.insert(room_id, summary);
+            } else {
+                services().rooms.search.deindex_room_directory(&room_id)?;
+                cache.summaries.remove(&room_id);
+            }
+        }
+
+        if let Some(refreshed) = cache.refreshed {
+            METRICS.record_directory_cache_age(refreshed.elapsed());
+        }
+
+        if let Some(sorted) = &cache.sorted {
+            return Ok(Arc::clone(sorted));
+        }
+
+        let mut sorted: Vec<_> = cache.summaries.values().cloned().collect();
+        sorted.sort_by(|l, r| {
+            r.num_joined_members
+                .cmp(&l.num_joined_members)
+                .then_with(|| l.room_id.cmp(&r.room_id))
+        });
+        let sorted = Arc::new(sorted);
+        cache.sorted = Some(Arc::clone(&sorted));
+
+        Ok(sorted)
+    }
+
+    /// Recomputes the summaries of all public rooms and updates their entries
+    /// in the search index
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn refresh(&self) -> Result<()> {
+        let mut summaries = HashMap::new();
+        for room_id in self.db.public_rooms() {
+            let room_id = room_id?;
+            if let Some(summary) = Self::summarize(room_id.clone()) {
+                services().rooms.search.index_room_directory(&summary)?;
+                summaries.insert(room_id, summary);
+            }
+        }
+
+        let mut cache = self.cache.lock().unwrap();
+        for room_id in cache.summaries.keys() {
+            if !summaries.contains_key(room_id) {
+                services().rooms.search.deindex_room_directory(room_id)?;
+            }
+        }
+        cache.summaries = summaries;
+        cache.sorted = None;
+        cache.refreshed = Some(Instant::now());
+
+        Ok(())
+    }
+
+    /// Collects the summary of a public room, rooms that can't be summarized
+    /// are left out of the directory
+    fn summarize(room_id: OwnedRoomId) -> Option<PublicRoomsChunk> {
+        room_summary(room_id.clone())
+            .inspect_err(|error| {
+                debug!(%room_id, %error, "Failed to summarize public room");
+            })
+            .ok()
+    }
+
+    /// Starts a task that periodically recomputes the summaries of all public
+    /// rooms.
+    pub(crate) fn start_refresh_task(&'static self) {
+        let timer_interval =
+            services().globals.config.directory_cache_refresh_interval;
+
+        tokio::spawn(async move {
+            let mut i = tokio::time::interval(timer_interval);
+
+            loop {
+                i.tick().await;
+
+                async {
+                    let start = Instant::now();
+                    if let Err(error) = self.refresh() {
+                        error!(%error, "directory_refresh: Error");
+                    } else {
+                        debug!(
+                            elapsed = ?start.elapsed(),
+                            "directory_refresh: Finished"
+                        );
+                    }
+                }
+                .instrument(info_span!("directory_refresh"))
+                .await;
+            }
+        });
+    }
+}
+
+/// Collects the summary of a room shown in the public room directory
+#[allow(clippy::too_many_lines)]
+#[tracing::instrument]
+fn room_summary(room_id: OwnedRoomId) -> Result<PublicRoomsChunk> {
+    let canonical_alias = services()
+        .rooms
+        .state_accessor
+        .room_state_get(&room_id, &StateEventType::RoomCanonicalAlias, "")?
+        .map_or(Ok(None), |s| {
+            serde_json::from_str(s.content.get())
+                .map(|c: RoomCanonicalAliasEventContent| c.alias)
+                .map_err(|_| {
+                    Error::bad_database(
+                        "Invalid canonical alias event in database.",
+                    )
+                })
+        })?;
+
+    let name = services().rooms.state_accessor.get_name(&room_id)?;
+
+    let num_joined_members = services()
+        .rooms
+        .state_cache
+        .room_joined_count(&room_id)?
+        .unwrap_or_else(|| {
+            warn!("Room has no member count");
+            0
+        })
+        .try_into()
+        .expect("user count should not be that big");
+
+    let topic = services()
+        .rooms
+        .state_accessor
+        .room_state_get(&room_id, &StateEventType::RoomTopic, "")?
+        .map_or(Ok(None), |s| {
+            serde_json::from_str(s.content.get())
+                .map(|c: RoomTopicEventContent| Some(c.topic))
+                .map_err(|_| {
+                    error!("Invalid room topic event in database for room",);
+                    Error::bad_database("Invalid room topic event in database.")
+                })
+        })?;
+
+    let world_readable = services()
+        .rooms
+        .state_accessor
+        .room_state_get(&room_id, &StateEventType::RoomHistoryVisibility, "")?
+        .map_or(Ok(false), |s| {
+            serde_json::from_str(s.content.get())
+                .map(|c: RoomHistoryVisibilityEventContent| {
+                    c.history_visibility == HistoryVisibility::WorldReadable
+                })
+                .map_err(|_| {
+                    Error::bad_database(
+                        "Invalid room history visibility event in database.",
+                    )
+                })
+        })?;
+
+    let guest_can_join = services()
+        .rooms
+        .state_accessor
+        .room_state_get(&room_id, &StateEventType::RoomGuestAccess, "")?
+        .map_or(Ok(false), |s| {
+            serde_json::from_str(s.content.get())
+                .map(|c: RoomGuestAccessEventContent| {
+                    c.guest_access == GuestAccess::CanJoin
+                })
+                .map_err(|_| {
+                    Error::bad_database(
+                        "Invalid room guest access event in database.",
+                    )
+                })
+        })?;
+
+    let avatar_url = services()
+        .rooms
+        .state_accessor
+        .room_state_get(&room_id, &StateEventType::RoomAvatar, "")?
+        .map(|s| {
+            serde_json::from_str(s.content.get())
+                .map(|c: RoomAvatarEventContent| c.url)
+                .map_err(|_| {
+                    Error::bad_database(
+                        "Invalid room avatar event in database.",
+                    )
+                })
+        })
+        .transpose()?
+        .flatten();
+
+    let join_rule = services()
+        .rooms
+        .state_accessor
+        .room_state_get(&room_id, &StateEventType::RoomJoinRules, "")?
+        .map(|s| {
+            serde_json::from_str(s.content.get())
+                .map(|c: RoomJoinRulesEventContent| match c.join_rule {
+                    JoinRule::Public => Some(PublicRoomJoinRule::Public),
+                    JoinRule::Knock => Some(PublicRoomJoinRule::Knock),
+                    _ => None,
+                })
+                .map_err(|error| {
+                    error!(%error, "Invalid room join rule event in database");
+                    Error::BadDatabase(
+                        "Invalid room join rule event in database.",
+                    )
+                })
+        })
+        .transpose()?
+        .flatten()
+        .ok_or_else(|| {
+            Error::bad_database("Missing room join rule event for room.")
+        })?;
+
+    let room_type = services()
+        .rooms
+        .state_accessor
+        .room_state_get(&room_id, &StateEventType::RoomCreate, "")?
+        .map(|s| {
+            serde_json::from_str::<RoomCreateEventContent>(s.content.get())
+                .map_err(|error| {
+                    error!(%error, "Invalid room create event in database");
+                    Error::BadDatabase("Invalid room create event in database.")
+                })
+        })
+        .transpose()?
+        .and_then(|e| e.room_type);
+
+    Ok(PublicRoomsChunk {
+        canonical_alias,
+        name,
+        num_joined_members,
+        room_id,
+        topic,
+        world_readable,
+        guest_can_join,
+        avatar_url,
+        join_rule,
+        room_type,
+    })
+}
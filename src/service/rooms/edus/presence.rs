@@ -0,0 +1,455 @@
+mod data;
+
+use std::{
+    collections::HashMap,
+    sync::Mutex as StdMutex,
+    time::{Duration, Instant},
+};
+
+pub(crate) use data::Data;
+use ruma::{
+    events::presence::{PresenceEvent, PresenceEventContent},
+    presence::PresenceState,
+    OwnedRoomId, OwnedUserId, RoomId, UInt, UserId,
+};
+use tracing::{debug, error, info_span, warn, Instrument};
+
+use crate::{services, utils, Result};
+
+pub(crate) struct Service {
+    pub(crate) db: &'static dyn Data,
+
+    /// Timestamp of the last activity of local users, tracked in memory so
+    /// that every sync request doesn't have to write a new presence update
+    pub(crate) last_active: StdMutex<HashMap<OwnedUserId, u64>>,
+
+    /// When the presence of local users was last written
+    pub(crate) last_written: StdMutex<HashMap<OwnedUserId, u64>>,
+}
+
+/// How long an unchanged presence of a local user isn't written again, so that
+/// other servers still see it as current
+const PRESENCE_WRITE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Returns whether a presence update of a local user needs to be written,
+/// because it differs from the `current` one or the last one was written
+/// longer than [`PRESENCE_WRITE_INTERVAL`] ago
+fn needs_write(
+    current: Option<&PresenceEventContent>,
+    presence: &PresenceState,
+    status_msg: Option<&str>,
+    last_written: Option<u64>,
+    now: u64,
+) -> bool {
+    let unchanged = current.is_some_and(|current| {
+        current.presence == *presence
+            && current.status_msg.as_deref() == status_msg
+            && current.currently_active
+                == Some(*presence == PresenceState::Online)
+    });
+    let recently_written = last_written.is_some_and(|last_written| {
+        Duration::from_millis(now.saturating_sub(last_written))
+            < PRESENCE_WRITE_INTERVAL
+    });
+
+    !(unchanged && recently_written)
+}
+
+impl Service {
+    /// Sets the presence of a local user and sends it to all rooms they are
+    /// joined to.
+    ///
+    /// A new presence update is only written if the presence of the user
+    /// changed or the last one is getting old, otherwise only the last
+    /// activity timestamp is refreshed.
+    #[tracing::instrument(skip(self))]
+    pub(crate) async fn set_presence(
+        &self,
+        user_id: &UserId,
+        presence: PresenceState,
+        status_msg: Option<String>,
+    ) -> Result<()> {
+        let current = self.get_presence(user_id)?;
+        let now = utils::millis_since_unix_epoch();
+        self.last_active.lock().unwrap().insert(user_id.to_owned(), now);
+
+        let last_written =
+            self.last_written.lock().unwrap().get(user_id).copied();
+        if !needs_write(
+            current.as_ref().map(|current| &current.content),
+            &presence,
+            status_msg.as_deref(),
+            last_written,
+            now,
+        ) {
+            return Ok(());
+        }
+
+        self.update(user_id, presence, status_msg, now).await
+    }
+
+    /// Marks a local user as active in response to a sync request, keeping
+    /// their status message.
+    #[tracing::instrument(skip(self))]
+    pub(crate) async fn ping_presence(
+        &self,
+        user_id: &UserId,
+        presence: &PresenceState,
+    ) -> Result<()> {
+        // Clients polling with `set_presence=offline` don't want to be marked
+        // as online
+        if *presence == PresenceState::Offline {
+            return Ok(());
+        }
+
+        let status_msg = self
+            .get_presence(user_id)?
+            .and_then(|current| current.content.status_msg);
+
+        self.set_presence(user_id, presence.clone(), status_msg).await
+    }
+
+    /// Stores a presence update received from a remote server.
+    #[tracing::instrument(skip(self, status_msg))]
+    pub(crate) async fn update_remote_presence(
+        &self,
+        user_id: &UserId,
+        presence: PresenceState,
+        status_msg: Option<String>,
+        last_active_ago: UInt,
+        currently_active: bool,
+    ) -> Result<()> {
+        let room_ids = services()
+            .rooms
+            .state_cache
+            .rooms_joined(user_id)
+            .collect::<Result<Vec<_>>>()?;
+
+        if room_ids.is_empty() {
+            debug!("User is not in any rooms we know about, ignoring");
+            return Ok(());
+        }
+
+        let last_active = utils::millis_since_unix_epoch()
+            .saturating_sub(last_active_ago.into());
+
+        self.store(
+            &room_ids,
+            PresenceEvent {
+                content: PresenceEventContent {
+                    avatar_url: None,
+                    currently_active: Some(currently_active),
+                    displayname: None,
+                    last_active_ago: Some(
+                        last_active.try_into().expect("time is valid"),
+                    ),
+                    presence,
+                    status_msg,
+                },
+                sender: user_id.to_owned(),
+            },
+        )
+        .await
+    }
+
+    /// Returns the current presence of a user, with `last_active_ago`
+    /// converted to a duration.
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn get_presence(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<PresenceEvent>> {
+        let Some(count) = self.db.last_presence_update(user_id)? else {
+            return Ok(None);
+        };
+
+        for room_id in services().rooms.state_cache.rooms_joined(user_id) {
+            if let Some(presence) =
+                self.db.get_presence_event(&room_id?, user_id, count)?
+            {
+                return Ok(Some(self.to_client_presence(presence)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the latest presence updates that happened after `since` of
+    /// all users sharing a room with `user_id`.
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn presence_since(
+        &self,
+        user_id: &UserId,
+        since: u64,
+    ) -> Result<HashMap<OwnedUserId, PresenceEvent>> {
+        let mut updates = HashMap::<OwnedUserId, (u64, PresenceEvent)>::new();
+
+        for room_id in services().rooms.state_cache.rooms_joined(user_id) {
+            let room_id = room_id?;
+
+            for update in self.db.presence_since(&room_id, since) {
+                let (count, presence) = update?;
+                let sender = presence.sender.clone();
+
+                if updates.get(&sender).is_some_and(|(c, _)| *c >= count) {
+                    continue;
+                }
+
+                // Updates of users that left the room since are not deleted
+                if !services().rooms.state_cache.is_joined(&sender, &room_id)? {
+                    continue;
+                }
+
+                updates.insert(sender, (count, presence));
+            }
+        }
+
+        Ok(updates
+            .into_iter()
+            .map(|(sender, (_, presence))| {
+                (sender, self.to_client_presence(presence))
+            })
+            .collect())
+    }
+
+    /// Returns the presence updates of local users in a room that happened
+    /// after `since` and their counts, ready to be sent to other servers.
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn local_presence_since(
+        &self,
+        room_id: &RoomId,
+        since: u64,
+    ) -> Result<Vec<(u64, PresenceEvent)>> {
+        let mut updates = Vec::new();
+
+        for update in self.db.presence_since(room_id, since) {
+            let (count, presence) = update?;
+            if presence.sender.server_name() != services().globals.server_name()
+            {
+                continue;
+            }
+
+            updates.push((count, self.to_client_presence(presence)));
+        }
+
+        Ok(updates)
+    }
+
+    /// Marks local users that have been inactive for longer than the
+    /// configured idle timeout as unavailable.
+    #[tracing::instrument(skip(self))]
+    pub(crate) async fn presence_maintain(&self) -> Result<()> {
+        let idle_timeout = services().globals.config.presence_idle_timeout;
+        let now = utils::millis_since_unix_epoch();
+
+        let users = self
+            .db
+            .all_last_presence_updates()
+            .filter_map(Result::ok)
+            .filter(|(user_id, _)| {
+                user_id.server_name() == services().globals.server_name()
+            })
+            .collect::<Vec<_>>();
+
+        for (user_id, _) in users {
+            let Some(presence) = self.get_presence(&user_id)? else {
+                continue;
+            };
+
+            if presence.content.presence != PresenceState::Online {
+                continue;
+            }
+
+            let last_active_ago =
+                presence.content.last_active_ago.map_or(0, u64::from);
+            if Duration::from_millis(last_active_ago) < idle_timeout {
+                continue;
+            }
+
+            debug!(%user_id, "Marking idle user as unavailable");
+            self.last_active.lock().unwrap().remove(&user_id);
+            self.update(
+                &user_id,
+                PresenceState::Unavailable,
+                presence.content.status_msg,
+                now.saturating_sub(last_active_ago),
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts a task that periodically marks idle users as unavailable.
+    pub(crate) fn start_maintenance_task(&'static self) {
+        let timer_interval = services()
+            .globals
+            .config
+            .presence_idle_timeout
+            .clamp(Duration::from_secs(1), Duration::from_secs(60));
+
+        tokio::spawn(async move {
+            let mut i = tokio::time::interval(timer_interval);
+
+            loop {
+                i.tick().await;
+
+                async {
+                    let start = Instant::now();
+                    if let Err(error) = self.presence_maintain().await {
+                        error!(%error, "presence: Error");
+                    } else {
+                        debug!(elapsed = ?start.elapsed(), "presence: Finished");
+                    }
+                }
+                .instrument(info_span!("presence_maintain"))
+                .await;
+            }
+        });
+    }
+
+    /// Writes a new presence update of a local user to all rooms they are
+    /// joined to.
+    async fn update(
+        &self,
+        user_id: &UserId,
+        presence: PresenceState,
+        status_msg: Option<String>,
+        last_active: u64,
+    ) -> Result<()> {
+        let room_ids = services()
+            .rooms
+            .state_cache
+            .rooms_joined(user_id)
+            .collect::<Result<Vec<OwnedRoomId>>>()?;
+
+        let currently_active = presence == PresenceState::Online;
+        self.last_written
+            .lock()
+            .unwrap()
+            .insert(user_id.to_owned(), utils::millis_since_unix_epoch());
+
+        self.store(
+            &room_ids,
+            PresenceEvent {
+                content: PresenceEventContent {
+                    avatar_url: services().users.avatar_url(user_id)?,
+                    currently_active: Some(currently_active),
+                    displayname: services().users.displayname(user_id)?,
+                    last_active_ago: Some(
+                        last_active.try_into().expect("time is valid"),
+                    ),
+                    presence,
+                    status_msg,
+                },
+                sender: user_id.to_owned(),
+            },
+        )
+        .await
+    }
+
+    /// Stores a presence update in all rooms the user is joined to and sends
+    /// it to interested appservices.
+    async fn store(
+        &self,
+        room_ids: &[OwnedRoomId],
+        presence: PresenceEvent,
+    ) -> Result<()> {
+        self.db.update_presence(room_ids, &presence)?;
+
+        if let Err(error) = services()
+            .sending
+            .send_presence_appservices(
+                &self.to_client_presence(presence),
+                room_ids,
+            )
+            .await
+        {
+            warn!(%error, "Failed to send presence update to appservices");
+        }
+
+        Ok(())
+    }
+
+    /// Converts the stored last activity timestamp into the duration clients
+    /// expect in `last_active_ago`.
+    fn to_client_presence(&self, mut presence: PresenceEvent) -> PresenceEvent {
+        let now = utils::millis_since_unix_epoch();
+
+        let mut last_active = presence.content.last_active_ago.map(u64::from);
+        if let Some(tracked) =
+            self.last_active.lock().unwrap().get(&presence.sender)
+        {
+            last_active = last_active.max(Some(*tracked));
+        }
+
+        presence.content.last_active_ago = last_active.map(|last_active| {
+            now.saturating_sub(last_active).try_into().unwrap_or(UInt::MAX)
+        });
+
+        presence
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::{
+        events::presence::PresenceEventContent, presence::PresenceState,
+    };
+
+    use super::needs_write;
+
+    fn content(presence: PresenceState) -> PresenceEventContent {
+        PresenceEventContent {
+            avatar_url: None,
+            currently_active: Some(presence == PresenceState::Online),
+            displayname: None,
+            last_active_ago: None,
+            presence,
+            status_msg: Some("Working".to_owned()),
+        }
+    }
+
+    #[test]
+    fn unchanged_presence_is_written_after_interval() {
+        let online = content(PresenceState::Online);
+        let now = 1_000_000;
+
+        // Nothing written yet
+        assert!(needs_write(None, &PresenceState::Online, None, None, now));
+
+        // Unchanged and written recently
+        assert!(!needs_write(
+            Some(&online),
+            &PresenceState::Online,
+            Some("Working"),
+            Some(now - 30_000),
+            now,
+        ));
+
+        // Unchanged, but written long ago
+        assert!(needs_write(
+            Some(&online),
+            &PresenceState::Online,
+            Some("Working"),
+            Some(now - 60_000),
+            now,
+        ));
+
+        // Changed state or status message
+        assert!(needs_write(
+            Some(&online),
+            &PresenceState::Unavailable,
+            Some("Working"),
+            Some(now),
+            now,
+        ));
+        assert!(needs_write(
+            Some(&online),
+            &PresenceState::Online,
+            None,
+            Some(now),
+            now,
+        ));
+    }
+}
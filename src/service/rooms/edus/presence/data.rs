@@ -0,0 +1,44 @@
+use ruma::{
+    events::presence::PresenceEvent, OwnedRoomId, OwnedUserId, RoomId, UserId,
+};
+
+use crate::Result;
+
+pub(crate) trait Data: Send + Sync {
+    /// Replaces the previous presence of `presence.sender` in the given rooms
+    /// and returns the count of the update.
+    ///
+    /// `last_active_ago` is stored as a timestamp in milliseconds since the
+    /// unix epoch.
+    fn update_presence(
+        &self,
+        room_ids: &[OwnedRoomId],
+        presence: &PresenceEvent,
+    ) -> Result<u64>;
+
+    /// Returns the count of the last presence update of this user.
+    fn last_presence_update(&self, user_id: &UserId) -> Result<Option<u64>>;
+
+    /// Returns the presence event of this user that was stored in the room at
+    /// `count`.
+    fn get_presence_event(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        count: u64,
+    ) -> Result<Option<PresenceEvent>>;
+
+    /// Returns an iterator over the presence updates in a room that happened
+    /// after the update with count `since`.
+    fn presence_since<'a>(
+        &'a self,
+        room_id: &RoomId,
+        since: u64,
+    ) -> Box<dyn Iterator<Item = Result<(u64, PresenceEvent)>> + 'a>;
+
+    /// Returns an iterator over all users with a presence state and the count
+    /// of their last presence update.
+    fn all_last_presence_updates<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedUserId, u64)>> + 'a>;
+}
@@ -39,17 +39,14 @@ pub(crate) trait Data: Send + Sync {
     ) -> Result<()>;
 
     /// Returns the private read marker.
-    // TODO: Implement MSC2285
-    #[allow(dead_code)]
     fn private_read_get(
         &self,
         room_id: &RoomId,
         user_id: &UserId,
     ) -> Result<Option<u64>>;
 
-    /// Returns the count of the last typing update in this room.
-    // TODO: Implement MSC2285
-    #[allow(dead_code)]
+    /// Returns the count of the last private read marker update in this
+    /// room.
     fn last_privateread_update(
         &self,
         user_id: &UserId,
@@ -1,11 +1,18 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Mutex as StdMutex,
+    time::Duration,
+};
 
 use ruma::{
-    events::{typing::TypingEventContent, SyncEphemeralRoomEvent},
+    events::{
+        typing::{TypingEvent, TypingEventContent},
+        SyncEphemeralRoomEvent,
+    },
     OwnedRoomId, OwnedUserId, RoomId, UserId,
 };
-use tokio::sync::{broadcast, RwLock};
-use tracing::trace;
+use tokio::sync::{broadcast, Notify, RwLock};
+use tracing::{error, trace, warn};
 
 use crate::{services, utils, Result};
 
@@ -16,11 +23,51 @@ pub(crate) struct Service {
     // timestamp of the last change to typing users
     pub(crate) last_typing_update: RwLock<BTreeMap<OwnedRoomId, u64>>,
     pub(crate) typing_update_sender: broadcast::Sender<OwnedRoomId>,
+    /// Rooms with typing users, by when the notification of one of them times
+    /// out
+    pub(crate) expiries: StdMutex<ExpirySchedule>,
+    /// Wakes up the expiry task when an earlier expiry was scheduled
+    pub(crate) expiry_scheduled: Notify,
+}
+
+/// When rooms have to be checked for timed out typing notifications
+#[derive(Default)]
+pub(crate) struct ExpirySchedule {
+    expiries: BTreeSet<(u64, OwnedRoomId)>,
+}
+
+impl ExpirySchedule {
+    /// Schedules a check of `room_id` at `timeout`, returns whether it is the
+    /// earliest check now.
+    fn add(&mut self, room_id: &RoomId, timeout: u64) -> bool {
+        self.expiries.insert((timeout, room_id.to_owned()));
+        self.next() == Some(timeout)
+    }
+
+    /// Returns when the next room has to be checked.
+    fn next(&self) -> Option<u64> {
+        self.expiries.first().map(|(timeout, _)| *timeout)
+    }
+
+    /// Removes and returns the rooms that have to be checked at `now`.
+    fn take_due(&mut self, now: u64) -> BTreeSet<OwnedRoomId> {
+        let mut due = BTreeSet::new();
+        while self.next().is_some_and(|timeout| timeout <= now) {
+            let (_, room_id) =
+                self.expiries.pop_first().expect("next expiry exists");
+            due.insert(room_id);
+        }
+        due
+    }
 }
 
 impl Service {
     /// Sets a user as typing until the timeout timestamp is reached or
     /// `roomtyping_remove` is called.
+    ///
+    /// Once the timeout is reached, the user is removed and sync requests
+    /// waiting for this room are woken up, even if the user doesn't stop
+    /// typing explicitly.
     #[tracing::instrument(skip(self))]
     pub(crate) async fn typing_add(
         &self,
@@ -34,6 +81,7 @@ impl Service {
             .entry(room_id.to_owned())
             .or_default()
             .insert(user_id.to_owned(), timeout);
+        self.schedule_expiry(room_id, timeout);
         self.last_typing_update
             .write()
             .await
@@ -44,6 +92,7 @@ impl Service {
                  interested"
             );
         }
+        self.send_appservices(room_id).await;
         Ok(())
     }
 
@@ -70,6 +119,7 @@ impl Service {
                  interested"
             );
         }
+        self.send_appservices(room_id).await;
         Ok(())
     }
 
@@ -85,29 +135,67 @@ impl Service {
         Ok(())
     }
 
+    /// Makes the expiry task remove expired typing notifications in a room
+    /// once `timeout` is reached.
+    fn schedule_expiry(&self, room_id: &RoomId, timeout: u64) {
+        if self.expiries.lock().unwrap().add(room_id, timeout) {
+            self.expiry_scheduled.notify_one();
+        }
+    }
+
+    /// Starts the task that removes typing notifications once they time out,
+    /// so that sync requests waiting for the room are woken up.
+    pub(crate) fn start_expiry_task(&'static self) {
+        tokio::spawn(async move {
+            loop {
+                let next = self.expiries.lock().unwrap().next();
+                let Some(next) = next else {
+                    self.expiry_scheduled.notified().await;
+                    continue;
+                };
+
+                let delay = Duration::from_millis(
+                    next.saturating_sub(utils::millis_since_unix_epoch()),
+                );
+                tokio::select! {
+                    () = tokio::time::sleep(delay) => {}
+                    // An earlier expiry may have been scheduled
+                    () = self.expiry_scheduled.notified() => continue,
+                }
+
+                let due = self
+                    .expiries
+                    .lock()
+                    .unwrap()
+                    .take_due(utils::millis_since_unix_epoch());
+                for room_id in due {
+                    if let Err(error) = self.typings_maintain(&room_id).await {
+                        error!(
+                            %error,
+                            %room_id,
+                            "Failed to expire typing users",
+                        );
+                    }
+                }
+            }
+        });
+    }
+
     /// Makes sure that typing events with old timestamps get removed.
     #[tracing::instrument(skip(self, room_id))]
     async fn typings_maintain(&self, room_id: &RoomId) -> Result<()> {
-        let current_timestamp = utils::millis_since_unix_epoch();
-        let mut removable = Vec::new();
+        let has_expired =
+            self.typing.read().await.get(room_id).is_some_and(|room| {
+                !expired_users(room, utils::millis_since_unix_epoch())
+                    .is_empty()
+            });
+        if has_expired
+            && remove_expired(
+                &mut *self.typing.write().await,
+                room_id,
+                utils::millis_since_unix_epoch(),
+            )
         {
-            let typing = self.typing.read().await;
-            let Some(room) = typing.get(room_id) else {
-                return Ok(());
-            };
-            for (user, timeout) in room {
-                if *timeout < current_timestamp {
-                    removable.push(user.clone());
-                }
-            }
-            drop(typing);
-        }
-        if !removable.is_empty() {
-            let typing = &mut self.typing.write().await;
-            let room = typing.entry(room_id.to_owned()).or_default();
-            for user in removable {
-                room.remove(&user);
-            }
             self.last_typing_update
                 .write()
                 .await
@@ -118,6 +206,7 @@ impl Service {
                      interested"
                 );
             }
+            self.send_appservices(room_id).await;
         }
         Ok(())
     }
@@ -144,16 +233,142 @@ impl Service {
         &self,
         room_id: &RoomId,
     ) -> Result<SyncEphemeralRoomEvent<TypingEventContent>> {
+        self.typings_maintain(room_id).await?;
         Ok(SyncEphemeralRoomEvent {
-            content: TypingEventContent {
-                user_ids: self
-                    .typing
-                    .read()
-                    .await
-                    .get(room_id)
-                    .map(|m| m.keys().cloned().collect())
-                    .unwrap_or_default(),
-            },
+            content: self.typing_content(room_id).await,
         })
     }
+
+    /// Returns the users in the typing map of a room, without removing
+    /// expired ones first.
+    async fn typing_content(&self, room_id: &RoomId) -> TypingEventContent {
+        TypingEventContent {
+            user_ids: self
+                .typing
+                .read()
+                .await
+                .get(room_id)
+                .map(|m| m.keys().cloned().collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Sends the users currently typing in a room to interested appservices.
+    async fn send_appservices(&self, room_id: &RoomId) {
+        let event = TypingEvent {
+            content: self.typing_content(room_id).await,
+            room_id: room_id.to_owned(),
+        };
+
+        if let Err(error) = services()
+            .sending
+            .send_edu_appservices_in_room(room_id, &event)
+            .await
+        {
+            warn!(
+                %error,
+                %room_id,
+                "Failed to send typing notification to appservices",
+            );
+        }
+    }
+}
+
+/// Removes the users whose typing notification timed out at `now` from a room,
+/// returns whether any were removed.
+fn remove_expired(
+    typing: &mut BTreeMap<OwnedRoomId, BTreeMap<OwnedUserId, u64>>,
+    room_id: &RoomId,
+    now: u64,
+) -> bool {
+    let Some(room) = typing.get_mut(room_id) else {
+        return false;
+    };
+    let expired = expired_users(room, now);
+    for user in &expired {
+        room.remove(user);
+    }
+    if room.is_empty() {
+        typing.remove(room_id);
+    }
+
+    !expired.is_empty()
+}
+
+/// Returns the users whose typing notification timed out at `now`.
+fn expired_users(
+    room: &BTreeMap<OwnedUserId, u64>,
+    now: u64,
+) -> Vec<OwnedUserId> {
+    room.iter()
+        .filter(|(_, timeout)| **timeout <= now)
+        .map(|(user, _)| user.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use ruma::{room_id, user_id};
+
+    use super::{expired_users, remove_expired, ExpirySchedule};
+
+    #[test]
+    fn typing_expires_after_timeout() {
+        let alice = user_id!("@alice:example.com").to_owned();
+        let bob = user_id!("@bob:example.com").to_owned();
+        let room =
+            BTreeMap::from([(alice.clone(), 1_000), (bob.clone(), 5_000)]);
+
+        assert!(expired_users(&room, 999).is_empty());
+        // Neither user stopped typing explicitly
+        assert_eq!(expired_users(&room, 1_000), vec![alice.clone()]);
+        assert_eq!(expired_users(&room, 5_000), vec![alice, bob]);
+    }
+
+    #[test]
+    fn expiry_schedule_returns_due_rooms() {
+        let a = room_id!("!a:example.com");
+        let b = room_id!("!b:example.com");
+        let mut schedule = ExpirySchedule::default();
+
+        assert!(schedule.add(b, 5_000));
+        assert!(schedule.add(a, 1_000));
+        // Not earlier than the next check, so the task doesn't need waking
+        assert!(!schedule.add(a, 3_000));
+        assert_eq!(schedule.next(), Some(1_000));
+
+        assert!(schedule.take_due(999).is_empty());
+        assert_eq!(
+            schedule.take_due(3_000).into_iter().collect::<Vec<_>>(),
+            [a.to_owned()]
+        );
+        assert_eq!(schedule.next(), Some(5_000));
+        assert_eq!(
+            schedule.take_due(9_000).into_iter().collect::<Vec<_>>(),
+            [b.to_owned()]
+        );
+        assert_eq!(schedule.next(), None);
+    }
+
+    #[test]
+    fn expired_typing_is_removed_from_room() {
+        let room = room_id!("!room:example.com");
+        let alice = user_id!("@alice:example.com").to_owned();
+        let bob = user_id!("@bob:example.com").to_owned();
+        let mut typing = BTreeMap::from([(
+            room.to_owned(),
+            BTreeMap::from([(alice, 1_000), (bob.clone(), 5_000)]),
+        )]);
+
+        assert!(!remove_expired(&mut typing, room, 999));
+        // Sync is told about the change, and no longer sees alice typing
+        assert!(remove_expired(&mut typing, room, 1_000));
+        assert_eq!(typing[room].keys().collect::<Vec<_>>(), [&bob]);
+
+        assert!(remove_expired(&mut typing, room, 5_000));
+        assert!(!typing.contains_key(room));
+        assert!(!remove_expired(&mut typing, room, 9_000));
+    }
 }
@@ -8,7 +8,7 @@ use std::{
     time::{Duration, Instant, SystemTime},
 };
 
-use futures_util::{stream::FuturesUnordered, Future, StreamExt};
+use futures_util::{stream::FuturesUnordered, Future, StreamExt, TryStreamExt};
 use ruma::{
     api::{
         client::error::ErrorKind,
@@ -26,25 +26,28 @@ use ruma::{
         room::{
             create::RoomCreateEventContent,
             redaction::RoomRedactionEventContent,
-            server_acl::RoomServerAclEventContent,
         },
         StateEventType, TimelineEventType,
     },
     int,
     state_res::{self, RoomVersion, StateMap},
     uint, CanonicalJsonObject, CanonicalJsonValue, EventId,
-    MilliSecondsSinceUnixEpoch, OwnedServerName, OwnedServerSigningKeyId,
-    RoomId, RoomVersionId, ServerName,
+    MilliSecondsSinceUnixEpoch, OwnedRoomId, OwnedServerName,
+    OwnedServerSigningKeyId, RoomId, RoomVersionId, ServerName,
 };
 use serde_json::value::RawValue as RawJsonValue;
-use tokio::sync::{RwLock, RwLockWriteGuard, Semaphore};
+use tokio::sync::{OnceCell, RwLock, RwLockWriteGuard, Semaphore};
 use tracing::{debug, error, info, trace, warn};
 
 use super::state_compressor::CompressedStateEvent;
 use crate::{
-    service::{globals::SigningKeys, pdu},
+    observability::METRICS,
+    service::{
+        globals::{marker, SigningKeys},
+        pdu,
+    },
     services,
-    utils::debug_slice_truncated,
+    utils::{debug_slice_truncated, on_demand_hashmap::KeyToken},
     Error, PduEvent, Result,
 };
 
@@ -313,7 +316,7 @@ impl Service {
 
     #[allow(clippy::type_complexity, clippy::too_many_arguments)]
     #[tracing::instrument(skip(self, origin, room_id, value, pub_key_map))]
-    fn handle_outlier_pdu<'a>(
+    pub(crate) fn handle_outlier_pdu<'a>(
         &'a self,
         origin: &'a ServerName,
         create_event: &'a PduEvent,
@@ -528,12 +531,10 @@ impl Service {
                 ));
             }
 
-            if !state_res::event_auth::auth_check(
+            if !auth_check_with_auth_events(
                 &room_version,
                 &incoming_pdu,
-                // TODO: third party invite
-                None::<PduEvent>,
-                |k, s| auth_events.get(&(k.to_string().into(), s.to_owned())),
+                &auth_events,
             )
             .map_err(|_e| {
                 Error::BadRequest(ErrorKind::InvalidParam, "Auth check failed")
@@ -691,7 +692,7 @@ impl Service {
             if okay {
                 let mut fork_states =
                     Vec::with_capacity(extremity_sstatehashes.len());
-                let mut auth_chain_sets =
+                let mut fork_starting_events =
                     Vec::with_capacity(extremity_sstatehashes.len());
 
                 for (sstatehash, prev_event) in extremity_sstatehashes {
@@ -736,18 +737,13 @@ impl Service {
                         starting_events.push(id);
                     }
 
-                    auth_chain_sets.push(
-                        services()
-                            .rooms
-                            .auth_chain
-                            .get_auth_chain(room_id, starting_events)
-                            .await?
-                            .collect(),
-                    );
-
+                    fork_starting_events.push(starting_events);
                     fork_states.push(state);
                 }
 
+                let auth_chain_sets =
+                    get_auth_chain_sets(room_id, fork_starting_events).await?;
+
                 let lock = services().globals.stateres_mutex.lock();
 
                 let result = state_res::resolve(
@@ -886,30 +882,30 @@ impl Service {
         debug!("Starting auth check");
         // 11. Check the auth of the event passes based on the state of the
         //     event
+        let state_event = |k: &StateEventType, s: &str| {
+            services()
+                .rooms
+                .short
+                .get_shortstatekey(&k.to_string().into(), s)
+                .ok()
+                .flatten()
+                .and_then(|shortstatekey| {
+                    state_at_incoming_event.get(&shortstatekey)
+                })
+                .and_then(|event_id| {
+                    services().rooms.timeline.get_pdu(event_id).ok().flatten()
+                })
+        };
+        let third_party_invite =
+            incoming_pdu.third_party_invite_token().and_then(|token| {
+                state_event(&StateEventType::RoomThirdPartyInvite, &token)
+            });
+
         let check_result = state_res::event_auth::auth_check(
             &room_version,
             &incoming_pdu,
-            // TODO: third party invite
-            None::<PduEvent>,
-            |k, s| {
-                services()
-                    .rooms
-                    .short
-                    .get_shortstatekey(&k.to_string().into(), s)
-                    .ok()
-                    .flatten()
-                    .and_then(|shortstatekey| {
-                        state_at_incoming_event.get(&shortstatekey)
-                    })
-                    .and_then(|event_id| {
-                        services()
-                            .rooms
-                            .timeline
-                            .get_pdu(event_id)
-                            .ok()
-                            .flatten()
-                    })
-            },
+            third_party_invite,
+            state_event,
         )
         .map_err(|_e| {
             Error::BadRequest(ErrorKind::InvalidParam, "Auth check failed.")
@@ -931,11 +927,10 @@ impl Service {
             &incoming_pdu.content,
         )?;
 
-        let soft_fail = !state_res::event_auth::auth_check(
+        let soft_fail = !auth_check_with_auth_events(
             &room_version,
             &incoming_pdu,
-            None::<PduEvent>,
-            |k, s| auth_events.get(&(k.clone(), s.to_owned())),
+            &auth_events,
         )
         .map_err(|_e| {
             Error::BadRequest(ErrorKind::InvalidParam, "Auth check failed.")
@@ -1140,22 +1135,30 @@ impl Service {
             .state_full_ids(current_sstatehash)
             .await?;
 
-        let fork_states = [current_state_ids, incoming_state];
+        self.resolve_fork_states(
+            room_id,
+            room_version_id,
+            vec![current_state_ids, incoming_state],
+        )
+        .await
+    }
 
-        let mut auth_chain_sets = Vec::new();
-        for state in &fork_states {
-            auth_chain_sets.push(
-                services()
-                    .rooms
-                    .auth_chain
-                    .get_auth_chain(
-                        room_id,
-                        state.iter().map(|(_, id)| id.clone()).collect(),
-                    )
-                    .await?
-                    .collect(),
-            );
-        }
+    /// Resolves the given states of a room into one
+    #[tracing::instrument(skip(self, room_version_id, fork_states))]
+    async fn resolve_fork_states(
+        &self,
+        room_id: &RoomId,
+        room_version_id: &RoomVersionId,
+        fork_states: Vec<HashMap<u64, Arc<EventId>>>,
+    ) -> Result<Arc<HashSet<CompressedStateEvent>>> {
+        let auth_chain_sets = get_auth_chain_sets(
+            room_id,
+            fork_states
+                .iter()
+                .map(|state| state.values().cloned().collect())
+                .collect(),
+        )
+        .await?;
 
         debug!("Loading fork states");
 
@@ -1222,6 +1225,95 @@ impl Service {
         Ok(Arc::new(new_room_state))
     }
 
+    /// Resolves the states after the forward extremities of a room again and
+    /// makes the result the current state of the room.
+    ///
+    /// This repairs rooms whose current state drifted from what their
+    /// timeline implies. Returns the IDs of the state events that were added
+    /// to and removed from the current state.
+    #[tracing::instrument(skip(self))]
+    pub(crate) async fn recompute_room_state(
+        &self,
+        room_id: &KeyToken<OwnedRoomId, marker::State>,
+    ) -> Result<(Vec<Arc<EventId>>, Vec<Arc<EventId>>)> {
+        let room_version_id =
+            services().rooms.state.get_room_version(room_id)?;
+
+        let mut fork_states = Vec::new();
+        for extremity in
+            services().rooms.state.get_forward_extremities(room_id)?
+        {
+            let Some(shortstatehash) = services()
+                .rooms
+                .state_accessor
+                .pdu_shortstatehash(&extremity)?
+            else {
+                warn!(event_id = %extremity, "Forward extremity has no state");
+                continue;
+            };
+            let mut state = services()
+                .rooms
+                .state_accessor
+                .state_full_ids(shortstatehash)
+                .await?;
+
+            // The stored state is the one before the event
+            let pdu =
+                services().rooms.timeline.get_pdu(&extremity)?.ok_or_else(
+                    || Error::bad_database("Forward extremity not found."),
+                )?;
+            if let Some(state_key) = &pdu.state_key {
+                let shortstatekey =
+                    services().rooms.short.get_or_create_shortstatekey(
+                        &pdu.kind.to_string().into(),
+                        state_key,
+                    )?;
+                state.insert(shortstatekey, Arc::clone(&pdu.event_id));
+            }
+
+            fork_states.push(state);
+        }
+
+        if fork_states.is_empty() {
+            return Err(Error::BadRequest(
+                ErrorKind::NotFound,
+                "Room has no forward extremities with known state.",
+            ));
+        }
+
+        let new_room_state = self
+            .resolve_fork_states(room_id, &room_version_id, fork_states)
+            .await?;
+
+        let (sstatehash, new, removed) = services()
+            .rooms
+            .state_compressor
+            .save_state(room_id, new_room_state)?;
+
+        let event_ids = |events: &HashSet<CompressedStateEvent>| {
+            events
+                .iter()
+                .map(|event| {
+                    services()
+                        .rooms
+                        .state_compressor
+                        .parse_compressed_state_event(event)
+                        .map(|(_, event_id)| event_id)
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        let added = event_ids(&new)?;
+        let removed_ids = event_ids(&removed)?;
+
+        services()
+            .rooms
+            .state
+            .force_state(room_id, sstatehash, new, removed)
+            .await?;
+
+        Ok((added, removed_ids))
+    }
+
     /// Find the event and auth it. Once the event is validated (steps 1 - 8)
     /// it is appended to the outliers Tree.
     ///
@@ -1326,24 +1418,12 @@ impl Service {
                         continue;
                     }
 
-                    info!(
-                        event_id = %next_id,
-                        "Fetching event over federation",
-                    );
-                    if let Ok(res) = services()
-                        .sending
-                        .send_federation_request(
-                            origin,
-                            get_event::v1::Request {
-                                event_id: (*next_id).to_owned(),
-                            },
-                        )
-                        .await
+                    if let Some(pdu) = Self::fetch_event(origin, &next_id).await
                     {
                         info!(event_id = %next_id, "Got event over federation");
                         let Ok((calculated_event_id, value)) =
                             pdu::gen_event_id_canonical_json(
-                                &res.pdu,
+                                &pdu,
                                 room_version_id,
                             )
                         else {
@@ -1444,6 +1524,47 @@ impl Service {
         })
     }
 
+    /// Fetches an event from `origin`.
+    ///
+    /// Concurrent fetches of the same event from the same server share one
+    /// federation request, and all of them get `None` if it fails. Fetches
+    /// from other servers and later fetches make a new request, so that one
+    /// unresponsive server doesn't fail fetches from the others.
+    async fn fetch_event(
+        origin: &ServerName,
+        event_id: &EventId,
+    ) -> Option<Box<RawJsonValue>> {
+        let fetch = services()
+            .globals
+            .eventid_fetch
+            .get_or_insert_with(
+                (origin.to_owned(), event_id.to_owned()),
+                OnceCell::new,
+            )
+            .await;
+
+        fetch
+            .get_or_init(|| async {
+                info!(%event_id, "Fetching event over federation");
+                services()
+                    .sending
+                    .send_federation_request(
+                        origin,
+                        get_event::v1::Request {
+                            event_id: event_id.to_owned(),
+                        },
+                    )
+                    .await
+                    .inspect_err(|error| {
+                        debug!(%event_id, %error, "Federation request failed");
+                    })
+                    .ok()
+                    .map(|response| response.pdu)
+            })
+            .await
+            .clone()
+    }
+
     #[tracing::instrument(skip_all)]
     #[allow(clippy::type_complexity)]
     async fn fetch_unknown_prev_events(
@@ -1470,9 +1591,35 @@ impl Service {
                 || Error::bad_database("Failed to find first pdu in db."),
             )?;
 
+        let max_fetch_prev_events = services()
+            .rooms
+            .metadata
+            .max_fetch_prev_events(room_id)?
+            .unwrap_or_else(|| services().globals.max_fetch_prev_events());
         let mut amount = 0;
+        let mut limit_reached = false;
 
         while let Some(prev_event_id) = todo_outlier_stack.pop() {
+            // Events can be referenced by several events on the stack
+            if graph.contains_key(&prev_event_id) {
+                continue;
+            }
+
+            // Checked before fetching, so that only the events that are used
+            // are kept in memory
+            if amount > max_fetch_prev_events {
+                if !limit_reached {
+                    warn!(
+                        max_fetch_prev_events,
+                        "Max prev event limit reached!"
+                    );
+                    METRICS.record_prev_event_limit_reached();
+                    limit_reached = true;
+                }
+                graph.insert(prev_event_id.clone(), HashSet::new());
+                continue;
+            }
+
             if let Some((pdu, json_opt)) = self
                 .fetch_and_handle_outliers(
                     origin,
@@ -1487,13 +1634,6 @@ impl Service {
             {
                 Self::check_room_id(room_id, &pdu)?;
 
-                if amount > services().globals.max_fetch_prev_events() {
-                    // Max limit reached
-                    warn!("Max prev event limit reached!");
-                    graph.insert(prev_event_id.clone(), HashSet::new());
-                    continue;
-                }
-
                 if let Some(json) = json_opt.or_else(|| {
                     services()
                         .rooms
@@ -1588,6 +1728,7 @@ impl Service {
                     })?,
                     signature_ids,
                     true,
+                    None,
                 )
                 .await;
 
@@ -1757,7 +1898,7 @@ impl Service {
             return Ok(());
         }
 
-        for server in services().globals.trusted_servers() {
+        for server in &services().globals.trusted_servers() {
             info!(%server, "Asking batch signing keys from trusted server");
             if let Ok(keys) = services()
                 .sending
@@ -1852,32 +1993,11 @@ impl Service {
         server_name: &ServerName,
         room_id: &RoomId,
     ) -> Result<()> {
-        let Some(acl_event) = services().rooms.state_accessor.room_state_get(
-            room_id,
-            &StateEventType::RoomServerAcl,
-            "",
-        )?
-        else {
-            return Ok(());
-        };
-
-        let acl_event_content = match serde_json::from_str::<
-            RoomServerAclEventContent,
-        >(acl_event.content.get())
+        if services()
+            .rooms
+            .state_accessor
+            .server_allowed_by_acl(server_name, room_id)?
         {
-            Ok(x) => x,
-            Err(error) => {
-                warn!(%error, "Invalid ACL event");
-                return Ok(());
-            }
-        };
-
-        if acl_event_content.allow.is_empty() {
-            // Ignore broken acl events
-            return Ok(());
-        }
-
-        if acl_event_content.is_allowed(server_name) {
             Ok(())
         } else {
             info!(
@@ -1894,6 +2014,9 @@ impl Service {
 
     /// Search the DB for the signing keys of the given server, if we don't have
     /// them fetch them from the server and save to our DB.
+    ///
+    /// Cached keys are also fetched again if they aren't valid until
+    /// `minimum_valid_until_ts`.
     #[tracing::instrument(
         skip(self, signature_ids),
         fields(signature_ids = debug_slice_truncated(&signature_ids, 3))
@@ -1905,6 +2028,7 @@ impl Service {
         // Whether to ask for keys from trusted servers. Should be false when
         // getting keys for validating requests, as per MSC4029
         query_via_trusted_servers: bool,
+        minimum_valid_until_ts: Option<MilliSecondsSinceUnixEpoch>,
     ) -> Result<SigningKeys> {
         let contains_all_ids = |keys: &SigningKeys| {
             signature_ids.iter().all(|id| {
@@ -1988,7 +2112,10 @@ impl Service {
             if contains_all_ids(&result) {
                 // We want to ensure that the keys remain valid by the time the
                 // other functions that handle signatures reach them
-                if result.valid_until_ts > ts_threshold {
+                if result.valid_until_ts > ts_threshold
+                    && minimum_valid_until_ts
+                        .map_or(true, |min| result.valid_until_ts >= min)
+                {
                     debug!(
                         origin = %origin,
                         valid_until_ts = %result.valid_until_ts.get(),
@@ -2016,7 +2143,7 @@ impl Service {
 
         debug!("Fetching signing keys over federation");
 
-        if let Some(mut server_key) = services()
+        if let Some((signed_server_key, mut server_key)) = services()
             .sending
             .send_federation_request(
                 origin,
@@ -2024,8 +2151,13 @@ impl Service {
             )
             .await
             .ok()
-            .and_then(|resp| resp.server_key.deserialize().ok())
+            .and_then(|resp| {
+                let server_key = resp.server_key.deserialize().ok()?;
+                Some((resp.server_key, server_key))
+            })
         {
+            services().globals.set_signed_keys(origin, &signed_server_key)?;
+
             // Keys should only be valid for a maximum of seven days
             server_key.valid_until_ts = server_key.valid_until_ts.min(
                 MilliSecondsSinceUnixEpoch::from_system_time(
@@ -2061,7 +2193,7 @@ impl Service {
         }
 
         if query_via_trusted_servers {
-            for server in services().globals.trusted_servers() {
+            for server in &services().globals.trusted_servers() {
                 debug!(
                     trusted_server = %server,
                     origin = %origin,
@@ -2186,3 +2318,272 @@ impl Service {
         Ok(())
     }
 }
+
+/// Checks whether `pdu` passes auth based on `auth_events`, including the
+/// `m.room.third_party_invite` event a membership event refers to
+fn auth_check_with_auth_events<E: state_res::Event>(
+    room_version: &RoomVersion,
+    pdu: &PduEvent,
+    auth_events: &StateMap<E>,
+) -> Result<bool, state_res::Error> {
+    let third_party_invite = pdu.third_party_invite_token().and_then(|token| {
+        auth_events.get(&(StateEventType::RoomThirdPartyInvite, token))
+    });
+
+    state_res::event_auth::auth_check(
+        room_version,
+        pdu,
+        third_party_invite,
+        |k, s| auth_events.get(&(k.clone(), s.to_owned())),
+    )
+}
+
+/// Loads the auth chains of the fork states for state resolution
+/// concurrently.
+///
+/// The sets are returned in no particular order, which state resolution
+/// doesn't depend on.
+async fn get_auth_chain_sets(
+    room_id: &RoomId,
+    fork_starting_events: Vec<Vec<Arc<EventId>>>,
+) -> Result<Vec<HashSet<Arc<EventId>>>> {
+    load_concurrently(fork_starting_events, |starting_events| async move {
+        Ok(services()
+            .rooms
+            .auth_chain
+            .get_auth_chain(room_id, starting_events)
+            .await?
+            .collect())
+    })
+    .await
+}
+
+/// Runs `load` for all `inputs` at the same time, returns the outputs in the
+/// order they finished.
+async fn load_concurrently<I, O, F, Fut>(
+    inputs: Vec<I>,
+    load: F,
+) -> Result<Vec<O>>
+where
+    F: Fn(I) -> Fut,
+    Fut: Future<Output = Result<O>>,
+{
+    inputs
+        .into_iter()
+        .map(load)
+        .collect::<FuturesUnordered<_>>()
+        .try_collect()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    };
+
+    use ruma::{events::StateEventType, state_res::RoomVersion};
+    use serde_json::json;
+
+    use super::{auth_check_with_auth_events, load_concurrently};
+    use crate::{Error, PduEvent, Result};
+
+    /// The public key of the identity server, which ruma expects the invite
+    /// token to be
+    const TOKEN: &str = "6F2ZCEZYr1hEOB6LhI2cUnK9mnVCUnwd6ISyw1Wyq5w";
+
+    fn pdu(
+        event_id: &str,
+        sender: &str,
+        kind: &str,
+        state_key: &str,
+        content: serde_json::Value,
+    ) -> PduEvent {
+        serde_json::from_str(
+            &json!({
+                "event_id": event_id,
+                "room_id": "!room:a.example",
+                "sender": sender,
+                "origin_server_ts": 0,
+                "type": kind,
+                "content": content,
+                "state_key": state_key,
+                "prev_events": [],
+                "depth": 0,
+                "auth_events": [],
+                "hashes": { "sha256": "" },
+            })
+            .to_string(),
+        )
+        .unwrap()
+    }
+
+    fn state_map(
+        events: &[&PduEvent],
+    ) -> HashMap<(StateEventType, String), PduEvent> {
+        events
+            .iter()
+            .map(|event| {
+                (
+                    (
+                        event.kind.to_string().into(),
+                        event.state_key.clone().unwrap(),
+                    ),
+                    (*event).clone(),
+                )
+            })
+            .collect()
+    }
+
+    /// A server invites a user on behalf of `@alice:a.example` after they
+    /// accepted the third-party invite, and then the user joins.
+    #[test]
+    fn federated_join_authorized_by_third_party_invite() {
+        let alice = "@alice:a.example";
+        let bob = "@bob:b.example";
+        let create = pdu(
+            "$create",
+            alice,
+            "m.room.create",
+            "",
+            json!({ "creator": alice, "room_version": "6" }),
+        );
+        let alice_join = pdu(
+            "$alice_join",
+            alice,
+            "m.room.member",
+            alice,
+            json!({ "membership": "join" }),
+        );
+        let power_levels = pdu(
+            "$power_levels",
+            alice,
+            "m.room.power_levels",
+            "",
+            json!({ "users": { alice: 100 } }),
+        );
+        let join_rules = pdu(
+            "$join_rules",
+            alice,
+            "m.room.join_rules",
+            "",
+            json!({ "join_rule": "invite" }),
+        );
+        let third_party_invite = pdu(
+            "$third_party_invite",
+            alice,
+            "m.room.third_party_invite",
+            TOKEN,
+            json!({
+                "display_name": "b...@example.org",
+                "key_validity_url": "https://id.example.org/isvalid",
+                "public_key": TOKEN,
+            }),
+        );
+        let bob_invite = pdu(
+            "$bob_invite",
+            alice,
+            "m.room.member",
+            bob,
+            json!({
+                "membership": "invite",
+                "third_party_invite": {
+                    "display_name": "b...@example.org",
+                    "signed": {
+                        "mxid": bob,
+                        "token": TOKEN,
+                        "signatures": {
+                            "id.example.org": { "ed25519:0": "signature" },
+                        },
+                    },
+                },
+            }),
+        );
+        let bob_join = pdu(
+            "$bob_join",
+            bob,
+            "m.room.member",
+            bob,
+            json!({ "membership": "join" }),
+        );
+        let room_version = RoomVersion::V6;
+
+        let auth_events = state_map(&[
+            &create,
+            &alice_join,
+            &power_levels,
+            &join_rules,
+            &third_party_invite,
+        ]);
+        assert!(auth_check_with_auth_events(
+            &room_version,
+            &bob_invite,
+            &auth_events,
+        )
+        .unwrap());
+
+        // Without the third-party invite event the invite isn't authorized
+        let auth_events =
+            state_map(&[&create, &alice_join, &power_levels, &join_rules]);
+        assert!(!auth_check_with_auth_events(
+            &room_version,
+            &bob_invite,
+            &auth_events,
+        )
+        .unwrap());
+
+        let auth_events =
+            state_map(&[&create, &power_levels, &join_rules, &bob_invite]);
+        assert!(auth_check_with_auth_events(
+            &room_version,
+            &bob_join,
+            &auth_events,
+        )
+        .unwrap());
+    }
+
+    #[tokio::test]
+    async fn auth_chains_of_many_extremities_load_concurrently() {
+        const EXTREMITIES: u64 = 16;
+        const LOAD_TIME: Duration = Duration::from_millis(50);
+
+        let start = Instant::now();
+        let mut loaded = load_concurrently(
+            (0..EXTREMITIES).collect(),
+            |extremity| async move {
+                // Stands in for the database reads of one auth chain
+                tokio::time::sleep(LOAD_TIME).await;
+                Ok::<_, Error>(extremity)
+            },
+        )
+        .await
+        .unwrap();
+        let elapsed = start.elapsed();
+
+        loaded.sort_unstable();
+        assert_eq!(loaded, (0..EXTREMITIES).collect::<Vec<_>>());
+        // Loading them one after another would take
+        // `EXTREMITIES * LOAD_TIME`
+        assert!(
+            elapsed < LOAD_TIME * 4,
+            "loading took {elapsed:?}, longer than for 4 extremities \
+             one after another",
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_auth_chain_load_fails_all() {
+        let result: Result<Vec<()>> =
+            load_concurrently(vec![true, false, true], |ok| async move {
+                if ok {
+                    Ok(())
+                } else {
+                    Err(Error::bad_database("Auth chain missing."))
+                }
+            })
+            .await;
+
+        assert!(result.is_err());
+    }
+}
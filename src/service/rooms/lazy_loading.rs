@@ -4,9 +4,10 @@ use std::collections::{HashMap, HashSet};
 pub(crate) use data::Data;
 use ruma::{DeviceId, OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomId, UserId};
 use tokio::sync::Mutex;
+use tracing::debug;
 
 use super::timeline::PduCount;
-use crate::Result;
+use crate::{services, Result};
 
 pub(crate) struct Service {
     pub(crate) db: &'static dyn Data,
@@ -86,4 +87,39 @@ impl Service {
     ) -> Result<()> {
         self.db.lazy_load_reset(user_id, device_id, room_id)
     }
+
+    /// Removes the lazy-loading records of all devices of a user in all rooms
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn lazy_load_reset_all(&self, user_id: &UserId) -> Result<()> {
+        self.db.lazy_load_reset_all(user_id)
+    }
+
+    /// Removes the lazy-loading records of rooms users have left, and of
+    /// rooms with more records than `lazy_load_max_entries`.
+    ///
+    /// Clients are simply sent the member events again when needed.
+    ///
+    /// Returns the number of records removed.
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn lazy_load_trim(&self) -> Result<usize> {
+        let max_entries = services().globals.config.lazy_load_max_entries;
+
+        self.db.lazy_load_trim(&mut |user_id, device_id, room_id, count| {
+            let too_many = max_entries.is_some_and(|max| count > max);
+            if !too_many
+                && services().rooms.state_cache.is_joined(user_id, room_id)?
+            {
+                return Ok(true);
+            }
+
+            debug!(
+                %user_id,
+                %device_id,
+                %room_id,
+                count,
+                "Removing lazy-loading records"
+            );
+            Ok(false)
+        })
+    }
 }
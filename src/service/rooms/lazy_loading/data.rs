@@ -25,4 +25,20 @@ pub(crate) trait Data: Send + Sync {
         device_id: &DeviceId,
         room_id: &RoomId,
     ) -> Result<()>;
+
+    /// Removes the lazy-loading records of all devices of a user in all rooms
+    fn lazy_load_reset_all(&self, user_id: &UserId) -> Result<()>;
+
+    /// Removes the lazy-loading records of each user, device and room for
+    /// which `keep` returns false when given the number of records, returns
+    /// the number of records removed
+    fn lazy_load_trim(
+        &self,
+        keep: &mut dyn FnMut(
+            &UserId,
+            &DeviceId,
+            &RoomId,
+            usize,
+        ) -> Result<bool>,
+    ) -> Result<usize>;
 }
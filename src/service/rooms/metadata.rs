@@ -1,4 +1,113 @@
+use ruma::{api::client::error::ErrorKind, OwnedRoomId, RoomId, ServerName};
+use tracing::info;
+
+use crate::{Error, Result};
+
 mod data;
 
 pub(crate) use data::Data;
-pub(crate) type Service = &'static dyn Data;
+
+pub(crate) struct Service {
+    db: &'static dyn Data,
+}
+
+impl Service {
+    pub(crate) fn new<D>(db: &'static D) -> Self
+    where
+        D: Data,
+    {
+        Self {
+            db,
+        }
+    }
+
+    /// Checks if a room exists.
+    pub(crate) fn exists(&self, room_id: &RoomId) -> Result<bool> {
+        self.db.exists(room_id)
+    }
+
+    pub(crate) fn iter_ids<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a> {
+        self.db.iter_ids()
+    }
+
+    pub(crate) fn is_disabled(&self, room_id: &RoomId) -> Result<bool> {
+        self.db.is_disabled(room_id)
+    }
+
+    pub(crate) fn disable_room(
+        &self,
+        room_id: &RoomId,
+        disabled: bool,
+    ) -> Result<()> {
+        self.db.disable_room(room_id, disabled)
+    }
+
+    /// Returns an iterator over all rooms with disabled federation handling.
+    pub(crate) fn iter_disabled<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a> {
+        self.db.iter_disabled()
+    }
+
+    /// Returns how many prev events are fetched at most for an incoming event
+    /// of a room, if it was overridden
+    pub(crate) fn max_fetch_prev_events(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<u16>> {
+        self.db.max_fetch_prev_events(room_id)
+    }
+
+    /// Overrides `federation.max_fetch_prev_events` for a room, `None`
+    /// removes the override
+    pub(crate) fn set_max_fetch_prev_events(
+        &self,
+        room_id: &RoomId,
+        limit: Option<u16>,
+    ) -> Result<()> {
+        self.db.set_max_fetch_prev_events(room_id, limit)
+    }
+
+    /// Allows or denies a server joining a room over federation
+    pub(crate) fn set_server_may_join(
+        &self,
+        room_id: &RoomId,
+        server_name: &ServerName,
+        allowed: bool,
+    ) -> Result<()> {
+        self.db.set_server_may_join(room_id, server_name, allowed)
+    }
+
+    /// Forgets which servers were allowed or denied joining a room, so that
+    /// all servers may join it again
+    pub(crate) fn clear_server_join_restrictions(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<()> {
+        self.db.clear_server_join_restrictions(room_id)
+    }
+
+    /// Returns an error if an admin restricted which servers may join or knock
+    /// on the room and the server isn't one of them.
+    pub(crate) fn join_restriction_check(
+        &self,
+        server_name: &ServerName,
+        room_id: &RoomId,
+    ) -> Result<()> {
+        if self.db.server_may_join(room_id, server_name)? {
+            Ok(())
+        } else {
+            info!(
+                server = %server_name,
+                %room_id,
+                "Other server is not allowed to join room",
+            );
+            Err(Error::BadRequest(
+                ErrorKind::forbidden(),
+                "Server is not allowed to join this room",
+            ))
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use ruma::{OwnedRoomId, RoomId};
+use ruma::{OwnedRoomId, RoomId, ServerName};
 
 use crate::Result;
 
@@ -10,4 +10,37 @@ pub(crate) trait Data: Send + Sync {
     ) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a>;
     fn is_disabled(&self, room_id: &RoomId) -> Result<bool>;
     fn disable_room(&self, room_id: &RoomId, disabled: bool) -> Result<()>;
+    /// Returns an iterator over all rooms with disabled federation handling.
+    fn iter_disabled<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a>;
+    /// Returns how many prev events are fetched at most for an incoming event
+    /// of a room, if it was overridden
+    fn max_fetch_prev_events(&self, room_id: &RoomId) -> Result<Option<u16>>;
+    /// Overrides `federation.max_fetch_prev_events` for a room, `None`
+    /// removes the override
+    fn set_max_fetch_prev_events(
+        &self,
+        room_id: &RoomId,
+        limit: Option<u16>,
+    ) -> Result<()>;
+    /// Returns whether a server may join a room over federation.
+    ///
+    /// Once any server was allowed to join the room, only allowed servers may
+    /// join until the restrictions are cleared. Denied servers may never join.
+    fn server_may_join(
+        &self,
+        room_id: &RoomId,
+        server_name: &ServerName,
+    ) -> Result<bool>;
+    /// Allows or denies a server joining a room over federation
+    fn set_server_may_join(
+        &self,
+        room_id: &RoomId,
+        server_name: &ServerName,
+        allowed: bool,
+    ) -> Result<()>;
+    /// Forgets which servers were allowed or denied joining a room, so that
+    /// all servers may join it again
+    fn clear_server_join_restrictions(&self, room_id: &RoomId) -> Result<()>;
 }
@@ -1,5 +1,5 @@
 mod data;
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc};
 
 pub(crate) use data::Data;
 use ruma::{
@@ -8,9 +8,10 @@ use ruma::{
     EventId, RoomId, UserId,
 };
 use serde::Deserialize;
+use serde_json::{json, value::to_raw_value, Value as JsonValue};
 
 use super::timeline::PduCount;
-use crate::{services, PduEvent, Result};
+use crate::{services, Error, PduEvent, Result};
 
 pub(crate) struct Service {
     pub(crate) db: &'static dyn Data,
@@ -33,16 +34,146 @@ impl Service {
         from: PduCount,
         to: PduCount,
     ) -> Result<()> {
-        match (from, to) {
-            (PduCount::Normal(f), PduCount::Normal(t)) => {
-                self.db.add_relation(f, t)
+        self.db.add_relation(from, to)
+    }
+
+    /// Counts a reaction with `key` to `target`
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn add_annotation(
+        &self,
+        target: &EventId,
+        key: &str,
+    ) -> Result<()> {
+        self.db.add_annotation(target, key)
+    }
+
+    /// Stops counting a reaction with `key` to `target`, because it was
+    /// redacted
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn remove_annotation(
+        &self,
+        target: &EventId,
+        key: &str,
+    ) -> Result<()> {
+        self.db.remove_annotation(target, key)
+    }
+
+    /// Remembers that `replacement` is an edit of `target`
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn add_replacement(
+        &self,
+        target: &EventId,
+        replacement: &EventId,
+    ) -> Result<()> {
+        self.db.add_replacement(target, replacement)
+    }
+
+    /// Adds the reaction counts and the latest edit of `pdu` to the
+    /// `m.relations` in its unsigned data as bundled aggregations
+    #[tracing::instrument(skip_all, fields(event_id = %pdu.event_id))]
+    pub(crate) fn bundle_aggregations(&self, pdu: &mut PduEvent) -> Result<()> {
+        let annotations = self.annotation_chunk(&pdu.event_id)?;
+        let replacement = self.latest_replacement(pdu)?;
+        if annotations.is_empty() && replacement.is_none() {
+            return Ok(());
+        }
+
+        let mut unsigned: BTreeMap<String, JsonValue> = pdu
+            .unsigned
+            .as_ref()
+            .map_or_else(
+                || Ok(BTreeMap::new()),
+                |u| serde_json::from_str(u.get()),
+            )
+            .map_err(|_| {
+                Error::bad_database("Invalid unsigned in pdu event")
+            })?;
+
+        let relations = unsigned
+            .entry("m.relations".to_owned())
+            .or_insert_with(|| json!({}));
+        if let Some(relations) = relations.as_object_mut() {
+            if !annotations.is_empty() {
+                relations.insert(
+                    "m.annotation".to_owned(),
+                    json!({ "chunk": annotations }),
+                );
+            }
+            if let Some(replacement) = replacement {
+                relations.insert(
+                    "m.replace".to_owned(),
+                    serde_json::to_value(replacement.to_message_like_event())
+                        .expect("to_value always works"),
+                );
+            }
+        }
+
+        pdu.unsigned =
+            Some(to_raw_value(&unsigned).expect("unsigned is valid"));
+
+        Ok(())
+    }
+
+    /// Returns the reaction counts of an event, most used keys first
+    fn annotation_chunk(&self, event_id: &EventId) -> Result<Vec<JsonValue>> {
+        let mut annotations =
+            self.db.annotations(event_id).collect::<Result<Vec<_>>>()?;
+
+        annotations.sort_by(|(a_key, a_count), (b_key, b_count)| {
+            b_count.cmp(a_count).then_with(|| a_key.cmp(b_key))
+        });
+
+        Ok(annotations
+            .into_iter()
+            .map(|(key, count)| {
+                json!({
+                    "type": TimelineEventType::Reaction,
+                    "key": key,
+                    "count": count,
+                })
+            })
+            .collect())
+    }
+
+    /// Returns the most recent edit of `pdu` by its own sender, ordered by
+    /// `origin_server_ts` and then event ID.
+    ///
+    /// Redacted edits are skipped, so that redacting the latest edit falls
+    /// back to the one before it.
+    fn latest_replacement(
+        &self,
+        pdu: &PduEvent,
+    ) -> Result<Option<Arc<PduEvent>>> {
+        if pdu.is_redacted() || pdu.state_key.is_some() {
+            return Ok(None);
+        }
+
+        let mut latest: Option<Arc<PduEvent>> = None;
+        for event_id in self.db.replacements(&pdu.event_id) {
+            let Some(replacement) =
+                services().rooms.timeline.get_pdu(&event_id?)?
+            else {
+                continue;
+            };
+
+            if replacement.sender != pdu.sender
+                || replacement.kind != pdu.kind
+                || replacement.state_key.is_some()
+                || replacement.is_redacted()
+            {
+                continue;
             }
-            _ => {
-                // TODO: Relations with backfilled pdus
 
-                Ok(())
+            let is_newer = latest.as_ref().map_or(true, |latest| {
+                (replacement.origin_server_ts, &replacement.event_id)
+                    > (latest.origin_server_ts, &latest.event_id)
+            });
+            if is_newer {
+                latest = Some(replacement);
             }
         }
+
+        Ok(latest)
     }
 
     #[allow(
@@ -193,13 +324,16 @@ impl Service {
     ) -> Result<impl Iterator<Item = Result<(PduCount, PduEvent)>> + 'a> {
         let room_id =
             services().rooms.short.get_or_create_shortroomid(room_id)?;
-        let target = match services().rooms.timeline.get_pdu_count(target)? {
-            Some(PduCount::Normal(c)) => c,
-            // TODO: Support backfilled relations
-            // This will result in an empty iterator
-            _ => 0,
-        };
-        self.db.relations_until(user_id, room_id, target, until)
+        // Events that aren't stored have no stored relations either
+        let relations = services()
+            .rooms
+            .timeline
+            .get_pdu_count(target)?
+            .map(|target| {
+                self.db.relations_until(user_id, room_id, target, until)
+            })
+            .transpose()?;
+        Ok(relations.into_iter().flatten())
     }
 
     #[tracing::instrument(skip(self, room_id, event_ids))]
@@ -1,17 +1,35 @@
 use std::sync::Arc;
 
-use ruma::{EventId, RoomId, UserId};
+use ruma::{EventId, OwnedEventId, RoomId, UserId};
 
 use crate::{service::rooms::timeline::PduCount, PduEvent, Result};
 
 pub(crate) trait Data: Send + Sync {
-    fn add_relation(&self, from: u64, to: u64) -> Result<()>;
+    fn add_relation(&self, from: PduCount, to: PduCount) -> Result<()>;
+    fn add_annotation(&self, target: &EventId, key: &str) -> Result<()>;
+    fn remove_annotation(&self, target: &EventId, key: &str) -> Result<()>;
+    /// Returns the keys of the `m.annotation` relations to `target` and how
+    /// often each is used
+    fn annotations<'a>(
+        &'a self,
+        target: &EventId,
+    ) -> Box<dyn Iterator<Item = Result<(String, u64)>> + 'a>;
+    fn add_replacement(
+        &self,
+        target: &EventId,
+        replacement: &EventId,
+    ) -> Result<()>;
+    /// Returns the IDs of all events with an `m.replace` relation to `target`
+    fn replacements<'a>(
+        &'a self,
+        target: &EventId,
+    ) -> Box<dyn Iterator<Item = Result<OwnedEventId>> + 'a>;
     #[allow(clippy::type_complexity)]
     fn relations_until<'a>(
         &'a self,
         user_id: &'a UserId,
         room_id: u64,
-        target: u64,
+        target: PduCount,
         until: PduCount,
     ) -> Result<Box<dyn Iterator<Item = Result<(PduCount, PduEvent)>> + 'a>>;
     fn mark_as_referenced(
@@ -1,4 +1,546 @@
 mod data;
 
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
 pub(crate) use data::Data;
-pub(crate) type Service = &'static dyn Data;
+use ruma::{
+    directory::PublicRoomsChunk, events::TimelineEventType, OwnedRoomId, RoomId,
+};
+use serde::Deserialize;
+use tracing::debug;
+
+use super::timeline::PduCount;
+use crate::{services, Error, Result};
+
+/// How quickly the score of an event saturates with the frequency of a word
+const BM25_K1: f64 = 1.2;
+/// How much the score of an event is normalized by its length
+const BM25_B: f64 = 0.75;
+
+/// Weight of words in the name of a room when searching the directory
+const NAME_WEIGHT: u32 = 3;
+/// Weight of words in the canonical alias of a room when searching the
+/// directory
+const ALIAS_WEIGHT: u32 = 2;
+/// Weight of words in the topic of a room when searching the directory
+const TOPIC_WEIGHT: u32 = 1;
+
+/// Splits a string into the words that are indexed, keeping their case
+pub(crate) fn split_words(body: &str) -> impl Iterator<Item = &str> + '_ {
+    body.split_terminator(|c: char| !c.is_alphanumeric())
+        .filter(|s| !s.is_empty())
+        .filter(|word| word.len() <= 50)
+}
+
+/// Splits a string into tokens used as keys in the search inverted index
+///
+/// This may be used to tokenize both message bodies (for indexing) or search
+/// queries (for querying).
+pub(crate) fn tokenize(body: &str) -> impl Iterator<Item = String> + '_ {
+    split_words(body).map(str::to_lowercase)
+}
+
+/// Statistics of a word in a room and a matching event, used for ranking
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TermStats {
+    /// How often the word occurs in the event
+    pub(crate) frequency: u32,
+    /// Number of events in the room containing the word
+    pub(crate) document_frequency: u64,
+}
+
+/// Scores an event containing the words of a query using Okapi BM25.
+///
+/// `documents` is the number of indexed events in the room, `length` the
+/// number of words of the event and `average_length` the average number of
+/// words of the events in the room.
+#[allow(clippy::cast_precision_loss)]
+pub(crate) fn bm25(
+    terms: &[TermStats],
+    documents: u64,
+    length: f64,
+    average_length: f64,
+) -> f64 {
+    let documents = documents as f64;
+    let normalized_length = if average_length > 0.0 {
+        length / average_length
+    } else {
+        1.0
+    };
+
+    terms
+        .iter()
+        .map(|term| {
+            let document_frequency = term.document_frequency as f64;
+            // Clamped for words that were counted in events indexed before
+            // the room statistics were kept
+            let idf = ((documents - document_frequency + 0.5)
+                / (document_frequency + 0.5))
+                .max(0.0)
+                .ln_1p();
+            let frequency = f64::from(term.frequency);
+
+            idf * frequency * (BM25_K1 + 1.0)
+                / (frequency
+                    + BM25_K1 * (1.0 - BM25_B + BM25_B * normalized_length))
+        })
+        .sum()
+}
+
+/// Fields of a room that can be searched for in the room directory, empty if
+/// the room doesn't have them
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct DirectoryFields {
+    pub(crate) name: String,
+    pub(crate) topic: String,
+    pub(crate) alias: String,
+}
+
+impl DirectoryFields {
+    fn from_chunk(chunk: &PublicRoomsChunk) -> Self {
+        Self {
+            name: chunk.name.clone().unwrap_or_default(),
+            topic: chunk.topic.clone().unwrap_or_default(),
+            alias: chunk
+                .canonical_alias
+                .as_ref()
+                .map(ToString::to_string)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Returns the tokens of all fields with their weights, tokens occurring
+    /// in several fields get the sum of their weights
+    pub(crate) fn tokens(&self) -> BTreeMap<String, u32> {
+        let mut tokens = BTreeMap::new();
+        for (field, weight) in [
+            (&self.name, NAME_WEIGHT),
+            (&self.alias, ALIAS_WEIGHT),
+            (&self.topic, TOPIC_WEIGHT),
+        ] {
+            for token in tokenize(field).collect::<BTreeSet<_>>() {
+                *tokens.entry(token).or_default() += weight;
+            }
+        }
+        tokens
+    }
+}
+
+/// Scores a directory token matching a word of a query, whole words count
+/// twice as much as words the token only starts with
+fn directory_term_score(word: &str, token: &str, weight: u32) -> u32 {
+    if token == word {
+        2 * weight
+    } else if token.starts_with(word) {
+        weight
+    } else {
+        0
+    }
+}
+
+/// Sums the scores of the words of a query per room, leaving out rooms that
+/// don't match all words
+///
+/// `word_scores` contains the best score of each room for each word.
+fn combine_directory_scores(
+    word_scores: Vec<HashMap<OwnedRoomId, u32>>,
+) -> HashMap<OwnedRoomId, u32> {
+    let mut word_scores = word_scores.into_iter();
+    let Some(mut scores) = word_scores.next() else {
+        return HashMap::new();
+    };
+
+    for word_scores in word_scores {
+        scores.retain(|room_id, score| {
+            let Some(word_score) = word_scores.get(room_id) else {
+                return false;
+            };
+            *score += word_score;
+            true
+        });
+    }
+
+    scores
+}
+
+/// An event matching a search query
+pub(crate) struct Match {
+    pub(crate) pdu_id: Vec<u8>,
+    /// Relevance of the event according to BM25
+    pub(crate) score: f64,
+}
+
+pub(crate) struct Service {
+    pub(crate) db: &'static dyn Data,
+}
+
+impl Service {
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn index_pdu(
+        &self,
+        shortroomid: u64,
+        pdu_id: &[u8],
+        message_body: &str,
+    ) -> Result<()> {
+        self.db.index_pdu(shortroomid, pdu_id, message_body)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn deindex_pdu(
+        &self,
+        shortroomid: u64,
+        pdu_id: &[u8],
+        message_body: &str,
+    ) -> Result<()> {
+        self.db.deindex_pdu(shortroomid, pdu_id, message_body)
+    }
+
+    /// Returns the events of a room containing all words of `search_string`
+    /// with their scores, newest first, and the words.
+    ///
+    /// Events are streamed from the index, so that callers can rank them
+    /// without loading all of them.
+    #[allow(clippy::type_complexity)]
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn search_pdus<'a>(
+        &'a self,
+        room_id: &RoomId,
+        search_string: &str,
+    ) -> Result<
+        Option<(Box<dyn Iterator<Item = Result<Match>> + 'a>, Vec<String>)>,
+    > {
+        let Some(shortroomid) =
+            services().rooms.short.get_shortroomid(room_id)?
+        else {
+            return Ok(None);
+        };
+        let Some((pdu_ids, words)) =
+            self.db.search_pdus(room_id, search_string)?
+        else {
+            return Ok(None);
+        };
+
+        let (documents, total_length) = self.db.room_stats(shortroomid)?;
+        #[allow(clippy::cast_precision_loss)]
+        let average_length = if documents == 0 {
+            0.0
+        } else {
+            total_length as f64 / documents as f64
+        };
+        let document_frequencies = words
+            .iter()
+            .map(|word| self.db.document_frequency(shortroomid, word))
+            .collect::<Result<Vec<_>>>()?;
+
+        let matches_words = words.clone();
+        let matches = pdu_ids.map(move |pdu_id| {
+            let terms = matches_words
+                .iter()
+                .zip(&document_frequencies)
+                .map(|(word, &document_frequency)| {
+                    Ok(TermStats {
+                        frequency: self.db.term_frequency(
+                            shortroomid,
+                            word,
+                            &pdu_id,
+                        )?,
+                        document_frequency,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            // Events indexed before lengths were kept are assumed to be of
+            // average length
+            let length = self
+                .db
+                .document_length(&pdu_id)?
+                .map_or(average_length, f64::from);
+
+            Ok(Match {
+                score: bm25(&terms, documents, length, average_length),
+                pdu_id,
+            })
+        });
+
+        Ok(Some((Box::new(matches), words)))
+    }
+
+    /// Rebuilds the search index of a room from its timeline.
+    ///
+    /// Returns the number of indexed events.
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn reindex_room(&self, room_id: &RoomId) -> Result<usize> {
+        #[derive(Deserialize)]
+        struct ExtractBody {
+            body: Option<String>,
+        }
+
+        let Some(shortroomid) =
+            services().rooms.short.get_shortroomid(room_id)?
+        else {
+            return Ok(0);
+        };
+
+        self.db.deindex_room(shortroomid)?;
+
+        let mut indexed = 0;
+        for pdu in services().rooms.timeline.pdus_after(
+            &services().globals.admin_bot_user_id,
+            room_id,
+            PduCount::MIN,
+        )? {
+            let (_, pdu) = pdu?;
+            if pdu.kind != TimelineEventType::RoomMessage || pdu.is_redacted() {
+                continue;
+            }
+
+            let Ok(ExtractBody {
+                body: Some(body),
+            }) = serde_json::from_str(pdu.content.get())
+            else {
+                continue;
+            };
+
+            let pdu_id = services()
+                .rooms
+                .timeline
+                .get_pdu_id(&pdu.event_id)?
+                .ok_or_else(|| {
+                Error::bad_database("Timeline event has no PDU ID.")
+            })?;
+            self.db.index_pdu(shortroomid, &pdu_id, &body)?;
+            indexed += 1;
+        }
+
+        debug!(indexed, "Reindexed room");
+
+        Ok(indexed)
+    }
+
+    /// Updates the directory entry of a public room in the index, unless the
+    /// room is excluded by `directory_search_exclude_rooms`
+    #[tracing::instrument(skip(self, chunk), fields(room_id = %chunk.room_id))]
+    pub(crate) fn index_room_directory(
+        &self,
+        chunk: &PublicRoomsChunk,
+    ) -> Result<()> {
+        if services()
+            .globals
+            .config
+            .directory_search_exclude_rooms
+            .contains(&chunk.room_id)
+        {
+            return self.deindex_room_directory(&chunk.room_id);
+        }
+
+        let fields = DirectoryFields::from_chunk(chunk);
+        let old_fields = self.db.directory_fields(&chunk.room_id)?;
+        if old_fields.as_ref() == Some(&fields) {
+            return Ok(());
+        }
+
+        if let Some(old_fields) = old_fields {
+            self.db.deindex_directory(&chunk.room_id, &old_fields.tokens())?;
+        }
+        self.db.index_directory(&chunk.room_id, &fields, &fields.tokens())
+    }
+
+    /// Removes the directory entry of a room from the index
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn deindex_room_directory(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<()> {
+        let Some(fields) = self.db.directory_fields(room_id)? else {
+            return Ok(());
+        };
+
+        self.db.deindex_directory(room_id, &fields.tokens())
+    }
+
+    /// Returns the scores of the rooms whose name, topic or canonical alias
+    /// contain words starting with every word of `query`, `None` if the query
+    /// has no words.
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn search_directory(
+        &self,
+        query: &str,
+    ) -> Result<Option<HashMap<OwnedRoomId, u32>>> {
+        let words: BTreeSet<_> = tokenize(query).collect();
+        if words.is_empty() {
+            return Ok(None);
+        }
+
+        let mut word_scores = Vec::new();
+        for word in &words {
+            let mut scores = HashMap::<_, u32>::new();
+            for token in self.db.directory_tokens(word) {
+                let (token, room_id, weight) = token?;
+                let score = scores.entry(room_id).or_default();
+                *score =
+                    (*score).max(directory_term_score(word, &token, weight));
+            }
+            word_scores.push(scores);
+        }
+
+        Ok(Some(combine_directory_scores(word_scores)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::room_id;
+
+    use super::*;
+
+    fn term(frequency: u32, document_frequency: u64) -> TermStats {
+        TermStats {
+            frequency,
+            document_frequency,
+        }
+    }
+
+    #[test]
+    fn tokenize_lowercases() {
+        assert_eq!(
+            tokenize("Hello, World! héllo").collect::<Vec<_>>(),
+            ["hello", "world", "héllo"]
+        );
+        assert_eq!(
+            split_words("Hello, World!").collect::<Vec<_>>(),
+            ["Hello", "World"]
+        );
+    }
+
+    #[test]
+    fn rare_words_rank_higher() {
+        let rare = bm25(&[term(1, 2)], 100, 10.0, 10.0);
+        let common = bm25(&[term(1, 90)], 100, 10.0, 10.0);
+
+        assert!(rare > common);
+    }
+
+    #[test]
+    fn frequent_words_rank_higher() {
+        let once = bm25(&[term(1, 10)], 100, 10.0, 10.0);
+        let twice = bm25(&[term(2, 10)], 100, 10.0, 10.0);
+
+        assert!(twice > once);
+    }
+
+    #[test]
+    fn short_events_rank_higher() {
+        let short = bm25(&[term(1, 10)], 100, 5.0, 10.0);
+        let long = bm25(&[term(1, 10)], 100, 50.0, 10.0);
+
+        assert!(short > long);
+    }
+
+    /// Finds the best score of each room for every word of `query`, like
+    /// [`Service::search_directory`] does with the index
+    fn search_directory(
+        rooms: &[(&str, DirectoryFields)],
+        query: &str,
+    ) -> HashMap<OwnedRoomId, u32> {
+        let word_scores = tokenize(query)
+            .map(|word| {
+                rooms
+                    .iter()
+                    .filter_map(|(room_id, fields)| {
+                        let score = fields
+                            .tokens()
+                            .iter()
+                            .map(|(token, weight)| {
+                                directory_term_score(&word, token, *weight)
+                            })
+                            .max()
+                            .filter(|score| *score > 0)?;
+                        Some((RoomId::parse(room_id).unwrap(), score))
+                    })
+                    .collect()
+            })
+            .collect();
+
+        combine_directory_scores(word_scores)
+    }
+
+    fn directory_rooms() -> Vec<(&'static str, DirectoryFields)> {
+        vec![
+            (
+                "!rust:example.org",
+                DirectoryFields {
+                    name: "Rust Programming".to_owned(),
+                    topic: "Talk about systems languages".to_owned(),
+                    alias: "#rust:example.org".to_owned(),
+                },
+            ),
+            (
+                "!python:example.org",
+                DirectoryFields {
+                    name: "Python".to_owned(),
+                    topic: "Programming in Python, no Rust allowed".to_owned(),
+                    alias: String::new(),
+                },
+            ),
+            (
+                "!cooking:example.org",
+                DirectoryFields {
+                    name: "Cooking".to_owned(),
+                    topic: "Recipes and programming snacks".to_owned(),
+                    alias: String::new(),
+                },
+            ),
+        ]
+    }
+
+    #[test]
+    fn directory_matches_all_words() {
+        let scores = search_directory(&directory_rooms(), "rust lang");
+
+        assert_eq!(scores.len(), 1);
+        assert!(scores.contains_key(room_id!("!rust:example.org")));
+    }
+
+    #[test]
+    fn directory_matches_name_and_topic() {
+        let scores = search_directory(&directory_rooms(), "python programming");
+
+        assert_eq!(scores.len(), 1);
+        assert!(scores.contains_key(room_id!("!python:example.org")));
+    }
+
+    #[test]
+    fn directory_ranks_names_higher() {
+        let scores = search_directory(&directory_rooms(), "program");
+
+        assert_eq!(scores.len(), 3);
+        assert!(
+            scores[room_id!("!rust:example.org")]
+                > scores[room_id!("!python:example.org")]
+        );
+        assert_eq!(
+            scores[room_id!("!python:example.org")],
+            scores[room_id!("!cooking:example.org")]
+        );
+    }
+
+    #[test]
+    fn directory_ranks_whole_words_higher() {
+        let rooms = directory_rooms();
+        let whole = search_directory(&rooms, "rust");
+        let partial = search_directory(&rooms, "rus");
+
+        assert!(
+            whole[room_id!("!rust:example.org")]
+                > partial[room_id!("!rust:example.org")]
+        );
+    }
+
+    #[test]
+    fn empty_rooms_score() {
+        let score = bm25(&[term(1, 0)], 0, 0.0, 0.0);
+
+        assert!(score.is_finite());
+        assert!(score > 0.0);
+    }
+}
@@ -1,8 +1,13 @@
-use ruma::RoomId;
+use std::collections::BTreeMap;
 
+use ruma::{OwnedRoomId, RoomId};
+
+use super::DirectoryFields;
 use crate::Result;
 
 pub(crate) trait Data: Send + Sync {
+    /// Adds the words of `message_body` to the index, unless the event is
+    /// indexed already
     fn index_pdu(
         &self,
         shortroomid: u64,
@@ -17,10 +22,63 @@ pub(crate) trait Data: Send + Sync {
         message_body: &str,
     ) -> Result<()>;
 
+    /// Removes all events of a room from the index
+    fn deindex_room(&self, shortroomid: u64) -> Result<()>;
+
+    /// Returns the IDs of the events containing all words of `search_string`,
+    /// newest first, and the words
     #[allow(clippy::type_complexity)]
     fn search_pdus<'a>(
         &'a self,
         room_id: &RoomId,
         search_string: &str,
     ) -> Result<Option<(Box<dyn Iterator<Item = Vec<u8>> + 'a>, Vec<String>)>>;
+
+    /// Returns how often `word` occurs in an indexed event
+    fn term_frequency(
+        &self,
+        shortroomid: u64,
+        word: &str,
+        pdu_id: &[u8],
+    ) -> Result<u32>;
+
+    /// Returns the number of words of an indexed event, if known
+    fn document_length(&self, pdu_id: &[u8]) -> Result<Option<u32>>;
+
+    /// Returns the number of indexed events of a room containing `word`
+    fn document_frequency(&self, shortroomid: u64, word: &str) -> Result<u64>;
+
+    /// Returns the number of indexed events of a room and the total number of
+    /// words in them
+    fn room_stats(&self, shortroomid: u64) -> Result<(u64, u64)>;
+
+    /// Returns the fields of a room that are indexed for the room directory
+    fn directory_fields(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<DirectoryFields>>;
+
+    /// Adds the directory entry of a room to the index with the weights of
+    /// its tokens
+    fn index_directory(
+        &self,
+        room_id: &RoomId,
+        fields: &DirectoryFields,
+        tokens: &BTreeMap<String, u32>,
+    ) -> Result<()>;
+
+    /// Removes the directory entry of a room with the given tokens from the
+    /// index
+    fn deindex_directory(
+        &self,
+        room_id: &RoomId,
+        tokens: &BTreeMap<String, u32>,
+    ) -> Result<()>;
+
+    /// Returns the indexed directory tokens starting with `prefix`, with the
+    /// rooms they occur in and their weights
+    fn directory_tokens<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> Box<dyn Iterator<Item = Result<(String, OwnedRoomId, u32)>> + 'a>;
 }
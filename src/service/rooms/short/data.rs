@@ -36,4 +36,23 @@ pub(crate) trait Data: Send + Sync {
     fn get_shortroomid(&self, room_id: &RoomId) -> Result<Option<u64>>;
 
     fn get_or_create_shortroomid(&self, room_id: &RoomId) -> Result<u64>;
+
+    /// Returns `(shorteventids, shortstatekeys)` up to `max_shortid` that
+    /// aren't referenced by any state or auth chain, and whose events aren't
+    /// stored
+    fn unreferenced_shortids(
+        &self,
+        max_shortid: u64,
+    ) -> Result<(Vec<u64>, Vec<u64>)>;
+
+    /// Removes the short IDs that [`Data::unreferenced_shortids`] returns and
+    /// their cache entries, returning the removed ones
+    ///
+    /// Short IDs that are looked up or created while the references are
+    /// searched are kept. Lookups are blocked while the remaining ones are
+    /// checked again and removed.
+    fn remove_unreferenced_shortids(
+        &self,
+        max_shortid: u64,
+    ) -> Result<(Vec<u64>, Vec<u64>)>;
 }
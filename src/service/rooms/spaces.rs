@@ -1,5 +1,13 @@
-use std::sync::Arc;
+use std::{
+    cmp::Ordering,
+    collections::HashSet,
+    fmt,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use async_trait::async_trait;
 use lru_cache::LruCache;
 use ruma::{
     api::{
@@ -7,7 +15,10 @@ use ruma::{
             error::ErrorKind,
             space::{get_hierarchy, SpaceHierarchyRoomsChunk},
         },
-        federation,
+        federation::{
+            self,
+            space::{SpaceHierarchyChildSummary, SpaceHierarchyParentSummary},
+        },
     },
     events::{
         room::{
@@ -23,316 +34,490 @@ use ruma::{
             },
             topic::RoomTopicEventContent,
         },
-        space::child::SpaceChildEventContent,
+        space::child::{HierarchySpaceChildEvent, SpaceChildEventContent},
         StateEventType,
     },
+    room::RoomType,
     space::SpaceRoomJoinRule,
-    OwnedRoomId, RoomId, UserId,
+    OwnedRoomId, OwnedServerName, RoomId, ServerName, UserId,
 };
 use tokio::sync::Mutex;
 use tracing::{debug, error, warn};
 
 use crate::{services, Error, PduEvent, Result};
 
+/// How long summaries of rooms fetched from other servers are cached, since
+/// they aren't invalidated by state changes like local ones
+const REMOTE_CHUNK_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Clone)]
 pub(crate) enum CachedJoinRule {
     Full(JoinRule),
 }
 
+/// A child of a space, from an `m.space.child` event
+#[derive(Clone, Debug)]
+pub(crate) struct SpaceChild {
+    room_id: OwnedRoomId,
+    /// Servers the child can be fetched from
+    via: Vec<OwnedServerName>,
+    suggested: bool,
+    order: Option<String>,
+    /// Timestamp of the `m.space.child` event
+    origin_server_ts: u64,
+}
+
+impl SpaceChild {
+    /// Returns `None` if the child event is invalid or has no `via` servers,
+    /// which means it was removed
+    fn new(
+        room_id: OwnedRoomId,
+        content: SpaceChildEventContent,
+        origin_server_ts: u64,
+    ) -> Option<Self> {
+        if content.via.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            room_id,
+            via: content.via,
+            suggested: content.suggested,
+            order: content.order,
+            origin_server_ts,
+        })
+    }
+
+    /// Returns `order` if it is valid according to the spec
+    fn valid_order(&self) -> Option<&str> {
+        self.order.as_deref().filter(|order| {
+            order.len() <= 50
+                && order.bytes().all(|byte| (0x20..=0x7E).contains(&byte))
+        })
+    }
+}
+
+/// Sorts children in the order recommended by the spec: children with a
+/// valid `order` first, then by the timestamp of their `m.space.child` event,
+/// then by room ID
+fn sort_children(children: &mut [SpaceChild]) {
+    children.sort_by(|a, b| {
+        match (a.valid_order(), b.valid_order()) {
+            (Some(a), Some(b)) => a.cmp(b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+        .then(a.origin_server_ts.cmp(&b.origin_server_ts))
+        .then_with(|| a.room_id.cmp(&b.room_id))
+    });
+}
+
+#[derive(Clone)]
 pub(crate) struct CachedSpaceChunk {
     chunk: SpaceHierarchyRoomsChunk,
-    children: Vec<OwnedRoomId>,
+    /// Sorted children of the room
+    children: Vec<SpaceChild>,
     join_rule: CachedJoinRule,
 }
 
+pub(crate) struct CachedSpaceEntry {
+    /// `None` if the room is unknown
+    chunk: Option<CachedSpaceChunk>,
+    /// When the entry of a remote room has to be fetched again
+    expires_at: Option<Instant>,
+}
+
+/// Result of looking up the summary of a room
+enum ChunkLookup {
+    Found(CachedSpaceChunk),
+    Unknown,
+    /// The room would have to be fetched from another server, which wasn't
+    /// allowed
+    Remote,
+}
+
+/// Position in a hierarchy, along with the parameters of the request that
+/// started the pagination so that they can't change in between
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct PaginationToken {
+    /// Number of visible rooms that were already returned
+    pub(crate) skip: usize,
+    pub(crate) max_depth: usize,
+    pub(crate) suggested_only: bool,
+}
+
+impl FromStr for PaginationToken {
+    type Err = ();
+
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
+        let mut parts = token.split('_');
+        let (Some(skip), Some(max_depth), Some(suggested_only), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(());
+        };
+
+        Ok(Self {
+            skip: skip.parse().map_err(|_| ())?,
+            max_depth: max_depth.parse().map_err(|_| ())?,
+            suggested_only: match suggested_only {
+                "0" => false,
+                "1" => true,
+                _ => return Err(()),
+            },
+        })
+    }
+}
+
+impl fmt::Display for PaginationToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}_{}_{}",
+            self.skip,
+            self.max_depth,
+            u8::from(self.suggested_only)
+        )
+    }
+}
+
 pub(crate) struct Service {
     pub(crate) roomid_spacechunk_cache:
-        Mutex<LruCache<OwnedRoomId, Option<CachedSpaceChunk>>>,
+        Mutex<LruCache<OwnedRoomId, CachedSpaceEntry>>,
 }
 
 impl Service {
-    #[allow(clippy::too_many_lines)]
+    /// Paginates over the rooms of a space in depth-first order, skipping
+    /// rooms the user isn't allowed to see and their children
     #[tracing::instrument(skip(self))]
     pub(crate) async fn get_hierarchy(
         &self,
         sender_user: &UserId,
         room_id: &RoomId,
         limit: usize,
-        skip: usize,
-        max_depth: usize,
-        suggested_only: bool,
+        token: PaginationToken,
     ) -> Result<get_hierarchy::v1::Response> {
-        let mut left_to_skip = skip;
+        walk_hierarchy(
+            &UserView {
+                service: self,
+                sender_user,
+            },
+            room_id,
+            limit,
+            token,
+        )
+        .await
+    }
+
+    /// Returns the summary of a local room and its children for another
+    /// server
+    ///
+    /// Only children that are known locally are summarized, children the
+    /// server can't see are listed as inaccessible instead.
+    #[tracing::instrument(skip(self))]
+    pub(crate) async fn get_federation_hierarchy(
+        &self,
+        server: &ServerName,
+        room_id: &RoomId,
+        suggested_only: bool,
+    ) -> Result<federation::space::get_hierarchy::v1::Response> {
+        let not_found = Error::BadRequest(
+            ErrorKind::NotFound,
+            "Room is unknown or not accessible.",
+        );
 
-        let mut rooms_in_path = Vec::new();
-        let mut stack = vec![vec![room_id.to_owned()]];
-        let mut results = Vec::new();
+        let Some(cached) = self.local_lookup(room_id).await? else {
+            return Err(not_found);
+        };
+        let CachedJoinRule::Full(join_rule) = &cached.join_rule;
+        if !Self::server_can_see(server, room_id, &cached.chunk)? {
+            return Err(not_found);
+        }
 
-        while let Some(current_room) = {
-            while stack.last().map_or(false, Vec::is_empty) {
-                stack.pop();
-            }
-            if stack.is_empty() {
-                None
-            } else {
-                stack.last_mut().and_then(Vec::pop)
+        let allowed_room_ids = allowed_room_ids(join_rule);
+        let chunk = cached.chunk;
+        let room = SpaceHierarchyParentSummary {
+            canonical_alias: chunk.canonical_alias,
+            name: chunk.name,
+            num_joined_members: chunk.num_joined_members,
+            room_id: chunk.room_id,
+            topic: chunk.topic,
+            world_readable: chunk.world_readable,
+            guest_can_join: chunk.guest_can_join,
+            avatar_url: chunk.avatar_url,
+            join_rule: chunk.join_rule,
+            room_type: chunk.room_type,
+            children_state: chunk.children_state,
+            allowed_room_ids,
+        };
+
+        let mut children = Vec::new();
+        let mut inaccessible_children = Vec::new();
+        for child in cached.children {
+            if suggested_only && !child.suggested {
+                continue;
             }
-        } {
-            rooms_in_path.push(current_room.clone());
-            if results.len() >= limit {
-                break;
+
+            let Some(cached) = self.local_lookup(&child.room_id).await? else {
+                continue;
+            };
+
+            if !Self::server_can_see(server, &child.room_id, &cached.chunk)? {
+                inaccessible_children.push(child.room_id);
+                continue;
             }
 
-            if let Some(cached) = self
-                .roomid_spacechunk_cache
-                .lock()
-                .await
-                .get_mut(&current_room.clone())
-                .as_ref()
+            let CachedJoinRule::Full(join_rule) = &cached.join_rule;
+            let allowed_room_ids = allowed_room_ids(join_rule);
+            let chunk = cached.chunk;
+            children.push(SpaceHierarchyChildSummary {
+                canonical_alias: chunk.canonical_alias,
+                name: chunk.name,
+                num_joined_members: chunk.num_joined_members,
+                room_id: chunk.room_id,
+                topic: chunk.topic,
+                world_readable: chunk.world_readable,
+                guest_can_join: chunk.guest_can_join,
+                avatar_url: chunk.avatar_url,
+                join_rule: chunk.join_rule,
+                room_type: chunk.room_type,
+                allowed_room_ids,
+            });
+        }
+
+        Ok(federation::space::get_hierarchy::v1::Response {
+            room,
+            children,
+            inaccessible_children,
+        })
+    }
+
+    /// Checks whether another server may see the summary of a local room
+    fn server_can_see(
+        server: &ServerName,
+        room_id: &RoomId,
+        chunk: &SpaceHierarchyRoomsChunk,
+    ) -> Result<bool> {
+        Ok(chunk.world_readable
+            || matches!(
+                chunk.join_rule,
+                SpaceRoomJoinRule::Public
+                    | SpaceRoomJoinRule::Knock
+                    | SpaceRoomJoinRule::KnockRestricted
+                    | SpaceRoomJoinRule::Restricted
+            )
+            || services().rooms.state_cache.server_in_room(server, room_id)?)
+    }
+
+    /// Returns the summary of a room from the cache, from local state or, if
+    /// `allow_remote` is set, from the `via` servers of the child
+    async fn lookup(
+        &self,
+        child: &SpaceChild,
+        allow_remote: bool,
+    ) -> Result<ChunkLookup> {
+        if let Some(entry) =
+            self.roomid_spacechunk_cache.lock().await.get_mut(&child.room_id)
+        {
+            if entry
+                .expires_at
+                .map_or(true, |expires_at| expires_at > Instant::now())
             {
-                if let Some(cached) = cached {
-                    let allowed = match &cached.join_rule {
-                        CachedJoinRule::Full(f) => self.handle_join_rule(
-                            f,
-                            sender_user,
-                            &current_room,
-                        )?,
-                    };
-                    if allowed {
-                        if left_to_skip > 0 {
-                            left_to_skip -= 1;
-                        } else {
-                            results.push(cached.chunk.clone());
-                        }
-                        if rooms_in_path.len() < max_depth {
-                            stack.push(cached.children.clone());
-                        }
-                    }
-                }
+                return Ok(entry
+                    .chunk
+                    .clone()
+                    .map_or(ChunkLookup::Unknown, ChunkLookup::Found));
+            }
+        }
+
+        if let Some(cached) = self.local_lookup(&child.room_id).await? {
+            return Ok(ChunkLookup::Found(cached));
+        }
+
+        if !allow_remote {
+            return Ok(ChunkLookup::Remote);
+        }
+
+        let cached = self.fetch_remote_chunk(child).await;
+        self.roomid_spacechunk_cache.lock().await.insert(
+            child.room_id.clone(),
+            CachedSpaceEntry {
+                chunk: cached.clone(),
+                expires_at: Some(Instant::now() + REMOTE_CHUNK_LIFETIME),
+            },
+        );
+
+        Ok(cached.map_or(ChunkLookup::Unknown, ChunkLookup::Found))
+    }
+
+    /// Returns the summary of a room this server has the state of, from the
+    /// cache if possible
+    async fn local_lookup(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<CachedSpaceChunk>> {
+        let Some(current_shortstatehash) =
+            services().rooms.state.get_room_shortstatehash(room_id)?
+        else {
+            return Ok(None);
+        };
+
+        if let Some(entry) =
+            self.roomid_spacechunk_cache.lock().await.get_mut(room_id)
+        {
+            if entry.expires_at.is_none() {
+                return Ok(entry.chunk.clone());
+            }
+        }
+
+        let state = services()
+            .rooms
+            .state_accessor
+            .state_full_ids(current_shortstatehash)
+            .await?;
+
+        let mut children = Vec::new();
+        let mut children_pdus = Vec::new();
+        for (key, id) in state {
+            let (event_type, state_key) =
+                services().rooms.short.get_statekey_from_short(key)?;
+            if event_type != StateEventType::SpaceChild {
                 continue;
             }
 
-            if let Some(current_shortstatehash) =
-                services().rooms.state.get_room_shortstatehash(&current_room)?
+            let pdu =
+                services().rooms.timeline.get_pdu(&id)?.ok_or_else(|| {
+                    Error::bad_database("Event in space state not found")
+                })?;
+
+            let Ok(content) = serde_json::from_str::<SpaceChildEventContent>(
+                pdu.content.get(),
+            ) else {
+                continue;
+            };
+
+            let Ok(child_id) = OwnedRoomId::try_from(state_key) else {
+                continue;
+            };
+
+            if let Some(child) =
+                SpaceChild::new(child_id, content, pdu.origin_server_ts.into())
             {
-                let state = services()
-                    .rooms
-                    .state_accessor
-                    .state_full_ids(current_shortstatehash)
-                    .await?;
-
-                let mut children_ids = Vec::new();
-                let mut children_pdus = Vec::new();
-                for (key, id) in state {
-                    let (event_type, state_key) =
-                        services().rooms.short.get_statekey_from_short(key)?;
-                    if event_type != StateEventType::SpaceChild {
-                        continue;
-                    }
+                children.push(child);
+                children_pdus.push(pdu);
+            }
+        }
 
-                    let pdu =
-                        services().rooms.timeline.get_pdu(&id)?.ok_or_else(
-                            || {
-                                Error::bad_database(
-                                    "Event in space state not found",
-                                )
-                            },
-                        )?;
-
-                    if serde_json::from_str::<SpaceChildEventContent>(
-                        pdu.content.get(),
-                    )
-                    .ok()
-                    .map(|c| c.via)
-                    .map_or(true, |v| v.is_empty())
-                    {
-                        continue;
-                    }
+        sort_children(&mut children);
 
-                    if let Ok(room_id) = OwnedRoomId::try_from(state_key) {
-                        children_ids.push(room_id);
-                        children_pdus.push(pdu);
-                    }
-                }
+        let join_rule = services()
+            .rooms
+            .state_accessor
+            .room_state_get(room_id, &StateEventType::RoomJoinRules, "")?
+            .map(|s| {
+                serde_json::from_str(s.content.get())
+                    .map(|c: RoomJoinRulesEventContent| c.join_rule)
+                    .map_err(|error| {
+                        error!(%error, "Invalid room join rule event");
+                        Error::BadDatabase(
+                            "Invalid room join rule event in database.",
+                        )
+                    })
+            })
+            .transpose()?
+            .unwrap_or(JoinRule::Invite);
 
-                // TODO: Sort children
-                children_ids.reverse();
+        let cached = CachedSpaceChunk {
+            chunk: Self::get_room_chunk(room_id, children_pdus, &join_rule)?,
+            children,
+            join_rule: CachedJoinRule::Full(join_rule),
+        };
 
-                let chunk = self.get_room_chunk(
-                    sender_user,
-                    &current_room,
-                    children_pdus,
-                );
-                if let Ok(chunk) = chunk {
-                    if left_to_skip > 0 {
-                        left_to_skip -= 1;
-                    } else {
-                        results.push(chunk.clone());
-                    }
-                    let join_rule = services()
-                        .rooms
-                        .state_accessor
-                        .room_state_get(
-                            &current_room,
-                            &StateEventType::RoomJoinRules,
-                            "",
-                        )?
-                        .map(|s| {
-                            serde_json::from_str(s.content.get())
-                                .map(|c: RoomJoinRulesEventContent| c.join_rule)
-                                .map_err(|error| {
-                                    error!(
-                                        %error,
-                                        "Invalid room join rule event"
-                                    );
-                                    Error::BadDatabase(
-                                        "Invalid room join rule event in \
-                                         database.",
-                                    )
-                                })
-                        })
-                        .transpose()?
-                        .unwrap_or(JoinRule::Invite);
-
-                    self.roomid_spacechunk_cache.lock().await.insert(
-                        current_room.clone(),
-                        Some(CachedSpaceChunk {
-                            chunk,
-                            children: children_ids.clone(),
-                            join_rule: CachedJoinRule::Full(join_rule),
-                        }),
-                    );
-                }
+        self.roomid_spacechunk_cache.lock().await.insert(
+            room_id.to_owned(),
+            CachedSpaceEntry {
+                chunk: Some(cached.clone()),
+                expires_at: None,
+            },
+        );
 
-                if rooms_in_path.len() < max_depth {
-                    stack.push(children_ids);
-                }
-            } else {
-                let server = current_room
-                    .server_name()
-                    .expect("Room IDs should always have a server name");
-                if server == services().globals.server_name() {
+        Ok(Some(cached))
+    }
+
+    /// Asks the `via` servers of a child for its summary
+    ///
+    /// Summaries of children of the room that aren't spaces are cached too,
+    /// since they don't have children of their own.
+    async fn fetch_remote_chunk(
+        &self,
+        child: &SpaceChild,
+    ) -> Option<CachedSpaceChunk> {
+        let mut servers = child.via.clone();
+        if let Some(server) = child.room_id.server_name() {
+            servers.push(server.to_owned());
+        }
+        let mut seen = HashSet::new();
+        servers.retain(|server| {
+            server != services().globals.server_name()
+                && seen.insert(server.clone())
+        });
+
+        for server in servers {
+            debug!(%server, "Asking other server for /hierarchy");
+            let response = match services()
+                .sending
+                .send_federation_request(
+                    &server,
+                    federation::space::get_hierarchy::v1::Request {
+                        room_id: child.room_id.clone(),
+                        // Filtered locally so that the cache can be used
+                        // independently of `suggested_only`
+                        suggested_only: false,
+                    },
+                )
+                .await
+            {
+                Ok(response) => response,
+                Err(error) => {
+                    warn!(%server, %error, "Failed to fetch /hierarchy");
                     continue;
                 }
-                if !results.is_empty() {
-                    // Early return so the client can see some data already
-                    break;
-                }
-                debug!(%server, "Asking other server for /hierarchy");
-                if let Ok(response) = services()
-                    .sending
-                    .send_federation_request(
-                        server,
-                        federation::space::get_hierarchy::v1::Request {
-                            room_id: current_room.clone(),
-                            suggested_only,
-                        },
-                    )
-                    .await
-                {
-                    warn!(
-                        %server,
-                        ?response,
-                        "Got response from other server for /hierarchy",
-                    );
-                    let chunk = SpaceHierarchyRoomsChunk {
-                        canonical_alias: response.room.canonical_alias,
-                        name: response.room.name,
-                        num_joined_members: response.room.num_joined_members,
-                        room_id: response.room.room_id,
-                        topic: response.room.topic,
-                        world_readable: response.room.world_readable,
-                        guest_can_join: response.room.guest_can_join,
-                        avatar_url: response.room.avatar_url,
-                        join_rule: response.room.join_rule.clone(),
-                        room_type: response.room.room_type,
-                        children_state: response.room.children_state,
-                    };
-                    let children = response
-                        .children
-                        .iter()
-                        .map(|c| c.room_id.clone())
-                        .collect::<Vec<_>>();
-
-                    let join_rule = match response.room.join_rule {
-                        SpaceRoomJoinRule::Invite => JoinRule::Invite,
-                        SpaceRoomJoinRule::Knock => JoinRule::Knock,
-                        SpaceRoomJoinRule::Private => JoinRule::Private,
-                        SpaceRoomJoinRule::Restricted => {
-                            JoinRule::Restricted(join_rules::Restricted {
-                                allow: response
-                                    .room
-                                    .allowed_room_ids
-                                    .into_iter()
-                                    .map(AllowRule::room_membership)
-                                    .collect(),
-                            })
-                        }
-                        SpaceRoomJoinRule::KnockRestricted => {
-                            JoinRule::KnockRestricted(join_rules::Restricted {
-                                allow: response
-                                    .room
-                                    .allowed_room_ids
-                                    .into_iter()
-                                    .map(AllowRule::room_membership)
-                                    .collect(),
-                            })
-                        }
-                        SpaceRoomJoinRule::Public => JoinRule::Public,
-                        _ => {
-                            return Err(Error::BadServerResponse(
-                                "Unknown join rule",
-                            ))
-                        }
-                    };
-                    if self.handle_join_rule(
-                        &join_rule,
-                        sender_user,
-                        &current_room,
-                    )? {
-                        if left_to_skip > 0 {
-                            left_to_skip -= 1;
-                        } else {
-                            results.push(chunk.clone());
-                        }
-                        if rooms_in_path.len() < max_depth {
-                            stack.push(children.clone());
-                        }
-                    }
+            };
 
-                    self.roomid_spacechunk_cache.lock().await.insert(
-                        current_room.clone(),
-                        Some(CachedSpaceChunk {
-                            chunk,
-                            children,
-                            join_rule: CachedJoinRule::Full(join_rule),
-                        }),
-                    );
-                } else {
-                    self.roomid_spacechunk_cache
-                        .lock()
-                        .await
-                        .insert(current_room.clone(), None);
+            let Some((cached, child_chunks)) = chunks_from_response(response)
+            else {
+                warn!(%server, "Unknown join rule in /hierarchy response");
+                continue;
+            };
+
+            let expires_at = Some(Instant::now() + REMOTE_CHUNK_LIFETIME);
+            let mut cache = self.roomid_spacechunk_cache.lock().await;
+            for chunk in child_chunks {
+                if cache.contains_key(&chunk.chunk.room_id) {
+                    continue;
                 }
+                cache.insert(
+                    chunk.chunk.room_id.clone(),
+                    CachedSpaceEntry {
+                        chunk: Some(chunk),
+                        expires_at,
+                    },
+                );
             }
+
+            return Some(cached);
         }
 
-        Ok(get_hierarchy::v1::Response {
-            next_batch: if results.is_empty() {
-                None
-            } else {
-                Some((skip + results.len()).to_string())
-            },
-            rooms: results,
-        })
+        None
     }
 
     #[allow(clippy::too_many_lines)]
-    #[tracing::instrument(skip(self, children))]
+    #[tracing::instrument(skip(children))]
     fn get_room_chunk(
-        &self,
-        sender_user: &UserId,
         room_id: &RoomId,
         children: Vec<Arc<PduEvent>>,
+        join_rule: &JoinRule,
     ) -> Result<SpaceHierarchyRoomsChunk> {
         Ok(SpaceHierarchyRoomsChunk {
             canonical_alias: services()
@@ -453,43 +638,7 @@ impl Service {
                 })
                 .transpose()?
                 .flatten(),
-            join_rule: {
-                let join_rule = services()
-                    .rooms
-                    .state_accessor
-                    .room_state_get(
-                        room_id,
-                        &StateEventType::RoomJoinRules,
-                        "",
-                    )?
-                    .map(|s| {
-                        serde_json::from_str(s.content.get())
-                            .map(|c: RoomJoinRulesEventContent| c.join_rule)
-                            .map_err(|error| {
-                                error!(
-                                    %error,
-                                    event_id = %s.event_id,
-                                    "Invalid room join rule event",
-                                );
-                                Error::BadDatabase(
-                                    "Invalid room join rule event in database.",
-                                )
-                            })
-                    })
-                    .transpose()?
-                    .unwrap_or(JoinRule::Invite);
-
-                if !self.handle_join_rule(&join_rule, sender_user, room_id)? {
-                    debug!("User is not allowed to see room");
-                    // This error will be caught later
-                    return Err(Error::BadRequest(
-                        ErrorKind::forbidden(),
-                        "User is not allowed to see the room",
-                    ));
-                }
-
-                Self::translate_joinrule(&join_rule)?
-            },
+            join_rule: Self::translate_joinrule(join_rule)?,
             room_type: services()
                 .rooms
                 .state_accessor
@@ -590,3 +739,683 @@ impl Service {
         }
     }
 }
+
+/// Source of the summaries of the rooms in a hierarchy
+#[async_trait]
+trait ChunkSource: Sync {
+    /// Returns the summary of a room, fetching it from other servers only if
+    /// `allow_remote` is set
+    async fn lookup(
+        &self,
+        child: &SpaceChild,
+        allow_remote: bool,
+    ) -> Result<ChunkLookup>;
+
+    /// Checks whether the room may be included in the hierarchy
+    fn can_see(&self, join_rule: &JoinRule, room_id: &RoomId) -> Result<bool>;
+}
+
+/// The hierarchy as seen by a local user
+struct UserView<'a> {
+    service: &'a Service,
+    sender_user: &'a UserId,
+}
+
+#[async_trait]
+impl ChunkSource for UserView<'_> {
+    async fn lookup(
+        &self,
+        child: &SpaceChild,
+        allow_remote: bool,
+    ) -> Result<ChunkLookup> {
+        self.service.lookup(child, allow_remote).await
+    }
+
+    fn can_see(&self, join_rule: &JoinRule, room_id: &RoomId) -> Result<bool> {
+        self.service.handle_join_rule(join_rule, self.sender_user, room_id)
+    }
+}
+
+/// Paginates over the rooms of a space in depth-first order, skipping rooms
+/// that can't be seen and their children
+async fn walk_hierarchy<S>(
+    source: &S,
+    room_id: &RoomId,
+    limit: usize,
+    token: PaginationToken,
+) -> Result<get_hierarchy::v1::Response>
+where
+    S: ChunkSource,
+{
+    let mut left_to_skip = token.skip;
+
+    let mut visited = HashSet::new();
+    let mut stack = vec![vec![SpaceChild {
+        room_id: room_id.to_owned(),
+        via: Vec::new(),
+        suggested: true,
+        order: None,
+        origin_server_ts: 0,
+    }]];
+    let mut results = Vec::new();
+    let mut more = false;
+
+    loop {
+        while stack.last().is_some_and(Vec::is_empty) {
+            stack.pop();
+        }
+        let depth = stack.len();
+        let Some(current) = stack.last_mut().and_then(Vec::pop) else {
+            break;
+        };
+
+        if results.len() >= limit {
+            more = true;
+            break;
+        }
+
+        if !visited.insert(current.room_id.clone()) {
+            continue;
+        }
+
+        let cached = match source.lookup(&current, results.is_empty()).await? {
+            ChunkLookup::Found(cached) => cached,
+            ChunkLookup::Unknown => continue,
+            ChunkLookup::Remote => {
+                // Early return so the client can see some data already
+                more = true;
+                break;
+            }
+        };
+
+        let CachedJoinRule::Full(join_rule) = &cached.join_rule;
+        if !source.can_see(join_rule, &current.room_id)? {
+            continue;
+        }
+
+        if left_to_skip > 0 {
+            left_to_skip -= 1;
+        } else {
+            results.push(cached.chunk);
+        }
+
+        if depth < token.max_depth {
+            stack.push(
+                cached
+                    .children
+                    .into_iter()
+                    .rev()
+                    .filter(|child| !token.suggested_only || child.suggested)
+                    .collect(),
+            );
+        }
+    }
+
+    Ok(get_hierarchy::v1::Response {
+        next_batch: more.then(|| {
+            PaginationToken {
+                skip: token.skip + results.len(),
+                ..token
+            }
+            .to_string()
+        }),
+        rooms: results,
+    })
+}
+
+/// Converts the `/hierarchy` response of another server into the summary of
+/// the requested room and the summaries of its children that aren't spaces
+///
+/// Returns `None` if the join rule of the requested room is unknown.
+fn chunks_from_response(
+    response: federation::space::get_hierarchy::v1::Response,
+) -> Option<(CachedSpaceChunk, Vec<CachedSpaceChunk>)> {
+    let room = response.room;
+    let join_rule =
+        join_rule_from_summary(&room.join_rule, room.allowed_room_ids).ok()?;
+
+    let mut children: Vec<_> = room
+        .children_state
+        .iter()
+        .filter_map(|event| {
+            let event: HierarchySpaceChildEvent = event.deserialize().ok()?;
+            SpaceChild::new(
+                event.state_key,
+                event.content,
+                event.origin_server_ts.get().into(),
+            )
+        })
+        .collect();
+    sort_children(&mut children);
+
+    let child_chunks = response
+        .children
+        .into_iter()
+        .filter(|summary| summary.room_type != Some(RoomType::Space))
+        .filter_map(|summary| {
+            let join_rule = join_rule_from_summary(
+                &summary.join_rule,
+                summary.allowed_room_ids,
+            )
+            .ok()?;
+
+            Some(CachedSpaceChunk {
+                chunk: SpaceHierarchyRoomsChunk {
+                    canonical_alias: summary.canonical_alias,
+                    name: summary.name,
+                    num_joined_members: summary.num_joined_members,
+                    room_id: summary.room_id,
+                    topic: summary.topic,
+                    world_readable: summary.world_readable,
+                    guest_can_join: summary.guest_can_join,
+                    avatar_url: summary.avatar_url,
+                    join_rule: summary.join_rule,
+                    room_type: summary.room_type,
+                    children_state: Vec::new(),
+                },
+                children: Vec::new(),
+                join_rule: CachedJoinRule::Full(join_rule),
+            })
+        })
+        .collect();
+
+    let cached = CachedSpaceChunk {
+        chunk: SpaceHierarchyRoomsChunk {
+            canonical_alias: room.canonical_alias,
+            name: room.name,
+            num_joined_members: room.num_joined_members,
+            room_id: room.room_id,
+            topic: room.topic,
+            world_readable: room.world_readable,
+            guest_can_join: room.guest_can_join,
+            avatar_url: room.avatar_url,
+            join_rule: room.join_rule,
+            room_type: room.room_type,
+            children_state: room.children_state,
+        },
+        children,
+        join_rule: CachedJoinRule::Full(join_rule),
+    };
+
+    Some((cached, child_chunks))
+}
+
+/// Returns the rooms whose members may join a room with `join_rule`
+fn allowed_room_ids(join_rule: &JoinRule) -> Vec<OwnedRoomId> {
+    match join_rule {
+        JoinRule::Restricted(restricted)
+        | JoinRule::KnockRestricted(restricted) => restricted
+            .allow
+            .iter()
+            .filter_map(|rule| match rule {
+                AllowRule::RoomMembership(membership) => {
+                    Some(membership.room_id.clone())
+                }
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Reconstructs the join rule of a room from its summary
+fn join_rule_from_summary(
+    join_rule: &SpaceRoomJoinRule,
+    allowed_room_ids: Vec<OwnedRoomId>,
+) -> Result<JoinRule> {
+    let restricted = || join_rules::Restricted {
+        allow: allowed_room_ids
+            .into_iter()
+            .map(AllowRule::room_membership)
+            .collect(),
+    };
+
+    Ok(match join_rule {
+        SpaceRoomJoinRule::Invite => JoinRule::Invite,
+        SpaceRoomJoinRule::Knock => JoinRule::Knock,
+        SpaceRoomJoinRule::Private => JoinRule::Private,
+        SpaceRoomJoinRule::Restricted => JoinRule::Restricted(restricted()),
+        SpaceRoomJoinRule::KnockRestricted => {
+            JoinRule::KnockRestricted(restricted())
+        }
+        SpaceRoomJoinRule::Public => JoinRule::Public,
+        _ => return Err(Error::BadServerResponse("Unknown join rule")),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use ruma::{owned_room_id, owned_server_name, room_id, serde::Raw, uint};
+    use serde_json::json;
+
+    use super::*;
+
+    /// Rooms of this server and `/hierarchy` responses of other servers
+    #[derive(Default)]
+    struct MockFederation {
+        local: HashMap<OwnedRoomId, CachedSpaceChunk>,
+        remote: HashMap<
+            (OwnedServerName, OwnedRoomId),
+            federation::space::get_hierarchy::v1::Response,
+        >,
+        /// Summaries fetched from other servers, like `roomid_spacechunk_cache`
+        cache: std::sync::Mutex<HashMap<OwnedRoomId, CachedSpaceChunk>>,
+    }
+
+    #[async_trait]
+    impl ChunkSource for MockFederation {
+        async fn lookup(
+            &self,
+            child: &SpaceChild,
+            allow_remote: bool,
+        ) -> Result<ChunkLookup> {
+            if let Some(cached) = self.cache.lock().unwrap().get(&child.room_id)
+            {
+                return Ok(ChunkLookup::Found(cached.clone()));
+            }
+            if let Some(cached) = self.local.get(&child.room_id) {
+                return Ok(ChunkLookup::Found(cached.clone()));
+            }
+            if !allow_remote {
+                return Ok(ChunkLookup::Remote);
+            }
+
+            for server in &child.via {
+                let Some(response) =
+                    self.remote.get(&(server.clone(), child.room_id.clone()))
+                else {
+                    continue;
+                };
+                let (cached, child_chunks) =
+                    chunks_from_response(response.clone()).unwrap();
+
+                let mut cache = self.cache.lock().unwrap();
+                for chunk in child_chunks {
+                    cache.entry(chunk.chunk.room_id.clone()).or_insert(chunk);
+                }
+                cache.insert(child.room_id.clone(), cached.clone());
+
+                return Ok(ChunkLookup::Found(cached));
+            }
+
+            Ok(ChunkLookup::Unknown)
+        }
+
+        fn can_see(
+            &self,
+            join_rule: &JoinRule,
+            _room_id: &RoomId,
+        ) -> Result<bool> {
+            Ok(!matches!(join_rule, JoinRule::Invite))
+        }
+    }
+
+    fn child_event(
+        room_id: &str,
+        via: &str,
+        ts: u64,
+    ) -> Raw<HierarchySpaceChildEvent> {
+        serde_json::from_value(json!({
+            "type": "m.space.child",
+            "state_key": room_id,
+            "sender": "@admin:b.example",
+            "origin_server_ts": ts,
+            "content": { "via": [via] },
+        }))
+        .unwrap()
+    }
+
+    fn rooms_chunk(
+        room_id: OwnedRoomId,
+        room_type: Option<RoomType>,
+        join_rule: SpaceRoomJoinRule,
+        children_state: Vec<Raw<HierarchySpaceChildEvent>>,
+    ) -> SpaceHierarchyRoomsChunk {
+        SpaceHierarchyRoomsChunk {
+            canonical_alias: None,
+            name: None,
+            num_joined_members: uint!(1),
+            room_id,
+            topic: None,
+            world_readable: false,
+            guest_can_join: false,
+            avatar_url: None,
+            join_rule,
+            room_type,
+            children_state,
+        }
+    }
+
+    fn parent_summary(
+        room_id: OwnedRoomId,
+        children_state: Vec<Raw<HierarchySpaceChildEvent>>,
+    ) -> SpaceHierarchyParentSummary {
+        let chunk = rooms_chunk(
+            room_id,
+            Some(RoomType::Space),
+            SpaceRoomJoinRule::Public,
+            children_state,
+        );
+        SpaceHierarchyParentSummary {
+            canonical_alias: chunk.canonical_alias,
+            name: chunk.name,
+            num_joined_members: chunk.num_joined_members,
+            room_id: chunk.room_id,
+            topic: chunk.topic,
+            world_readable: chunk.world_readable,
+            guest_can_join: chunk.guest_can_join,
+            avatar_url: chunk.avatar_url,
+            join_rule: chunk.join_rule,
+            room_type: chunk.room_type,
+            children_state: chunk.children_state,
+            allowed_room_ids: Vec::new(),
+        }
+    }
+
+    fn child_summary(
+        room_id: OwnedRoomId,
+        room_type: Option<RoomType>,
+        join_rule: SpaceRoomJoinRule,
+    ) -> SpaceHierarchyChildSummary {
+        let chunk = rooms_chunk(room_id, room_type, join_rule, Vec::new());
+        SpaceHierarchyChildSummary {
+            canonical_alias: chunk.canonical_alias,
+            name: chunk.name,
+            num_joined_members: chunk.num_joined_members,
+            room_id: chunk.room_id,
+            topic: chunk.topic,
+            world_readable: chunk.world_readable,
+            guest_can_join: chunk.guest_can_join,
+            avatar_url: chunk.avatar_url,
+            join_rule: chunk.join_rule,
+            room_type: chunk.room_type,
+            allowed_room_ids: Vec::new(),
+        }
+    }
+
+    /// A space on `a.example` with a local room and a subspace on
+    /// `b.example`, which has a room, an invite-only room and another
+    /// subspace with a room of its own
+    fn two_servers() -> MockFederation {
+        let mut federation = MockFederation::default();
+
+        let root = CachedSpaceChunk {
+            chunk: rooms_chunk(
+                owned_room_id!("!root:a.example"),
+                Some(RoomType::Space),
+                SpaceRoomJoinRule::Public,
+                Vec::new(),
+            ),
+            children: vec![
+                SpaceChild {
+                    room_id: owned_room_id!("!sub:b.example"),
+                    via: vec![owned_server_name!("b.example")],
+                    suggested: true,
+                    order: Some("a".to_owned()),
+                    origin_server_ts: 0,
+                },
+                SpaceChild {
+                    room_id: owned_room_id!("!lobby:a.example"),
+                    via: vec![owned_server_name!("a.example")],
+                    suggested: false,
+                    order: Some("b".to_owned()),
+                    origin_server_ts: 0,
+                },
+            ],
+            join_rule: CachedJoinRule::Full(JoinRule::Public),
+        };
+        let lobby = CachedSpaceChunk {
+            chunk: rooms_chunk(
+                owned_room_id!("!lobby:a.example"),
+                None,
+                SpaceRoomJoinRule::Public,
+                Vec::new(),
+            ),
+            children: Vec::new(),
+            join_rule: CachedJoinRule::Full(JoinRule::Public),
+        };
+        for cached in [root, lobby] {
+            federation.local.insert(cached.chunk.room_id.clone(), cached);
+        }
+
+        federation.remote.insert(
+            (owned_server_name!("b.example"), owned_room_id!("!sub:b.example")),
+            federation::space::get_hierarchy::v1::Response {
+                room: parent_summary(
+                    owned_room_id!("!sub:b.example"),
+                    vec![
+                        child_event("!chat:b.example", "b.example", 3),
+                        child_event("!deep:b.example", "b.example", 1),
+                        child_event("!private:b.example", "b.example", 2),
+                    ],
+                ),
+                children: vec![
+                    child_summary(
+                        owned_room_id!("!chat:b.example"),
+                        None,
+                        SpaceRoomJoinRule::Public,
+                    ),
+                    child_summary(
+                        owned_room_id!("!deep:b.example"),
+                        Some(RoomType::Space),
+                        SpaceRoomJoinRule::Public,
+                    ),
+                    child_summary(
+                        owned_room_id!("!private:b.example"),
+                        None,
+                        SpaceRoomJoinRule::Invite,
+                    ),
+                ],
+                inaccessible_children: Vec::new(),
+            },
+        );
+        federation.remote.insert(
+            (
+                owned_server_name!("b.example"),
+                owned_room_id!("!deep:b.example"),
+            ),
+            federation::space::get_hierarchy::v1::Response {
+                room: parent_summary(
+                    owned_room_id!("!deep:b.example"),
+                    vec![child_event("!leaf:b.example", "b.example", 1)],
+                ),
+                children: vec![child_summary(
+                    owned_room_id!("!leaf:b.example"),
+                    None,
+                    SpaceRoomJoinRule::Public,
+                )],
+                inaccessible_children: Vec::new(),
+            },
+        );
+
+        federation
+    }
+
+    /// Follows `next_batch` until the end and returns the room IDs of every
+    /// page
+    async fn all_pages(
+        federation: &MockFederation,
+        limit: usize,
+        max_depth: usize,
+        suggested_only: bool,
+    ) -> Vec<Vec<String>> {
+        let mut token = PaginationToken {
+            skip: 0,
+            max_depth,
+            suggested_only,
+        };
+        let mut pages = Vec::new();
+        loop {
+            let response = walk_hierarchy(
+                federation,
+                room_id!("!root:a.example"),
+                limit,
+                token,
+            )
+            .await
+            .unwrap();
+            pages.push(
+                response
+                    .rooms
+                    .iter()
+                    .map(|chunk| chunk.room_id.to_string())
+                    .collect(),
+            );
+
+            let Some(next_batch) = response.next_batch else {
+                return pages;
+            };
+            token = next_batch.parse().unwrap();
+        }
+    }
+
+    fn child(room_id: OwnedRoomId, order: Option<&str>, ts: u64) -> SpaceChild {
+        SpaceChild {
+            room_id,
+            via: vec![owned_server_name!("example.com")],
+            suggested: false,
+            order: order.map(ToOwned::to_owned),
+            origin_server_ts: ts,
+        }
+    }
+
+    #[test]
+    fn tokens_round_trip() {
+        for token in [
+            PaginationToken {
+                skip: 0,
+                max_depth: 4,
+                suggested_only: false,
+            },
+            PaginationToken {
+                skip: 42,
+                max_depth: 1,
+                suggested_only: true,
+            },
+        ] {
+            assert_eq!(token.to_string().parse::<PaginationToken>(), Ok(token));
+        }
+    }
+
+    #[test]
+    fn invalid_tokens() {
+        for token in ["", "10", "10_4", "10_4_2", "10_4_1_0", "a_4_1"] {
+            assert_eq!(token.parse::<PaginationToken>(), Err(()));
+        }
+    }
+
+    #[test]
+    fn children_order() {
+        let mut children = vec![
+            child(owned_room_id!("!d:example.com"), None, 2),
+            child(owned_room_id!("!c:example.com"), None, 1),
+            child(owned_room_id!("!b:example.com"), Some("b"), 5),
+            child(owned_room_id!("!a:example.com"), Some("a"), 9),
+            // Invalid orders are ignored
+            child(owned_room_id!("!e:example.com"), Some("\n"), 0),
+            child(owned_room_id!("!f:example.com"), None, 1),
+        ];
+
+        sort_children(&mut children);
+
+        assert_eq!(
+            children.iter().map(|c| c.room_id.as_str()).collect::<Vec<_>>(),
+            [
+                "!a:example.com",
+                "!b:example.com",
+                "!e:example.com",
+                "!c:example.com",
+                "!f:example.com",
+                "!d:example.com",
+            ]
+        );
+    }
+
+    #[test]
+    fn remote_response_caches_rooms_but_not_spaces() {
+        let federation = two_servers();
+        let response = federation.remote[&(
+            owned_server_name!("b.example"),
+            owned_room_id!("!sub:b.example"),
+        )]
+            .clone();
+
+        let (cached, child_chunks) = chunks_from_response(response).unwrap();
+
+        assert_eq!(cached.chunk.room_id, "!sub:b.example");
+        assert_eq!(
+            cached
+                .children
+                .iter()
+                .map(|c| c.room_id.as_str())
+                .collect::<Vec<_>>(),
+            ["!deep:b.example", "!private:b.example", "!chat:b.example"]
+        );
+        assert_eq!(
+            child_chunks
+                .iter()
+                .map(|c| c.chunk.room_id.as_str())
+                .collect::<Vec<_>>(),
+            ["!chat:b.example", "!private:b.example"]
+        );
+        let CachedJoinRule::Full(join_rule) = &child_chunks[1].join_rule;
+        assert!(matches!(join_rule, JoinRule::Invite));
+    }
+
+    #[tokio::test]
+    async fn hierarchy_spans_two_servers() {
+        let federation = two_servers();
+
+        let pages = all_pages(&federation, 10, 10, false).await;
+
+        // Pages end early whenever a space has to be fetched from b.example
+        assert_eq!(
+            pages,
+            [
+                vec!["!root:a.example"],
+                vec!["!sub:b.example"],
+                vec![
+                    "!deep:b.example",
+                    "!leaf:b.example",
+                    "!chat:b.example",
+                    "!lobby:a.example",
+                ],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn hierarchy_respects_depth_and_suggestions() {
+        let federation = two_servers();
+
+        let pages = all_pages(&federation, 10, 2, false).await;
+        assert_eq!(
+            pages.concat(),
+            ["!root:a.example", "!sub:b.example", "!lobby:a.example"]
+        );
+
+        // Only `!sub` is suggested, none of its children are
+        let pages = all_pages(&federation, 10, 10, true).await;
+        assert_eq!(pages.concat(), ["!root:a.example", "!sub:b.example"]);
+    }
+
+    #[tokio::test]
+    async fn hierarchy_pages_by_limit() {
+        let federation = two_servers();
+        // Fetch both spaces from b.example first, so pages aren't cut short
+        all_pages(&federation, 10, 10, false).await;
+
+        let pages = all_pages(&federation, 2, 10, false).await;
+
+        assert_eq!(
+            pages,
+            [
+                vec!["!root:a.example", "!sub:b.example"],
+                vec!["!deep:b.example", "!leaf:b.example"],
+                vec!["!chat:b.example", "!lobby:a.example"],
+            ]
+        );
+    }
+}
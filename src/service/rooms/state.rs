@@ -96,7 +96,8 @@ impl Service {
                         false,
                     )?;
                 }
-                TimelineEventType::SpaceChild => {
+                TimelineEventType::SpaceChild
+                | TimelineEventType::SpaceParent => {
                     services()
                         .rooms
                         .spaces
@@ -304,12 +305,17 @@ impl Service {
         )? {
             state.push(e.to_stripped_state_event());
         }
-        if let Some(e) = services().rooms.state_accessor.room_state_get(
-            &invite_event.room_id,
-            &StateEventType::RoomMember,
-            invite_event.sender.as_str(),
-        )? {
-            state.push(e.to_stripped_state_event());
+        // The sender's membership is the event itself when knocking
+        if invite_event.state_key.as_deref()
+            != Some(invite_event.sender.as_str())
+        {
+            if let Some(e) = services().rooms.state_accessor.room_state_get(
+                &invite_event.room_id,
+                &StateEventType::RoomMember,
+                invite_event.sender.as_str(),
+            )? {
+                state.push(e.to_stripped_state_event());
+            }
         }
 
         state.push(invite_event.to_stripped_state_event());
@@ -7,20 +7,23 @@ use std::{
 pub(crate) use data::Data;
 use lru_cache::LruCache;
 use ruma::{
+    api::client::error::ErrorKind,
     events::{
         room::{
             avatar::RoomAvatarEventContent,
             history_visibility::{
                 HistoryVisibility, RoomHistoryVisibilityEventContent,
             },
+            join_rules::{AllowRule, JoinRule, RoomJoinRulesEventContent},
             member::{MembershipState, RoomMemberEventContent},
             name::RoomNameEventContent,
             power_levels::{RoomPowerLevels, RoomPowerLevelsEventContent},
+            server_acl::RoomServerAclEventContent,
         },
         StateEventType,
     },
-    state_res::Event,
-    EventId, JsOption, OwnedRoomId, OwnedServerName, OwnedUserId, RoomId,
+    state_res::{Event, RoomVersion},
+    EventId, Int, JsOption, OwnedRoomId, OwnedServerName, OwnedUserId, RoomId,
     ServerName, UserId,
 };
 use serde_json::value::to_raw_value;
@@ -39,6 +42,7 @@ pub(crate) struct Service {
     pub(crate) server_visibility_cache:
         Mutex<LruCache<(OwnedServerName, u64), bool>>,
     pub(crate) user_visibility_cache: Mutex<LruCache<(OwnedUserId, u64), bool>>,
+    pub(crate) server_acl_cache: Mutex<LruCache<(OwnedServerName, u64), bool>>,
 }
 
 impl Service {
@@ -204,6 +208,63 @@ impl Service {
         Ok(visibility)
     }
 
+    /// Whether a server is allowed to participate in a room according to the
+    /// room's current `m.room.server_acl`.
+    ///
+    /// Missing and broken ACL events allow all servers.
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn server_allowed_by_acl(
+        &self,
+        server_name: &ServerName,
+        room_id: &RoomId,
+    ) -> Result<bool> {
+        let lookup = Lookup::ServerAcl;
+
+        let Some(shortstatehash) =
+            services().rooms.state.get_room_shortstatehash(room_id)?
+        else {
+            return Ok(true);
+        };
+
+        if let Some(allowed) = self
+            .server_acl_cache
+            .lock()
+            .unwrap()
+            .get_mut(&(server_name.to_owned(), shortstatehash))
+        {
+            METRICS.record_lookup(lookup, FoundIn::Cache);
+            return Ok(*allowed);
+        }
+
+        let allowed = match self.state_get(
+            shortstatehash,
+            &StateEventType::RoomServerAcl,
+            "",
+        )? {
+            Some(acl_event) => match serde_json::from_str::<
+                RoomServerAclEventContent,
+            >(acl_event.content.get())
+            {
+                // Ignore broken acl events
+                Ok(acl) if acl.allow.is_empty() => true,
+                Ok(acl) => acl.is_allowed(server_name),
+                Err(error) => {
+                    warn!(%error, "Invalid ACL event");
+                    true
+                }
+            },
+            None => true,
+        };
+
+        METRICS.record_lookup(lookup, FoundIn::Database);
+        self.server_acl_cache
+            .lock()
+            .unwrap()
+            .insert((server_name.to_owned(), shortstatehash), allowed);
+
+        Ok(allowed)
+    }
+
     /// Whether a user is allowed to see an event, based on
     /// the room's history_visibility at that event's state.
     #[tracing::instrument(skip(self))]
@@ -414,6 +475,115 @@ impl Service {
             .is_ok()
     }
 
+    /// Picks a local user that can authorize `user_id` joining a room with
+    /// `restricted` or `knock_restricted` join rules, for
+    /// `join_authorised_via_users_server`.
+    ///
+    /// Returns `None` if the room isn't restricted or `user_id` is already
+    /// invited or joined. Fails with [`Error::RestrictedJoin`] if `user_id` is
+    /// in none of the allowed rooms, and with `M_UNABLE_TO_AUTHORISE_JOIN` if
+    /// no local user can invite them.
+    #[tracing::instrument(skip(self), ret(level = "trace"))]
+    pub(crate) fn restricted_join_authorizer(
+        &self,
+        room_id: &KeyToken<OwnedRoomId, marker::State>,
+        user_id: &UserId,
+    ) -> Result<Option<OwnedUserId>> {
+        let join_rule = self
+            .room_state_get(room_id, &StateEventType::RoomJoinRules, "")?
+            .map(|s| {
+                serde_json::from_str(s.content.get())
+                    .map(|c: RoomJoinRulesEventContent| c.join_rule)
+                    .map_err(|error| {
+                        warn!(%error, "Invalid join rules event");
+                        Error::bad_database("Invalid join rules event in db.")
+                    })
+            })
+            .transpose()?;
+
+        let Some(
+            JoinRule::Restricted(restricted)
+            | JoinRule::KnockRestricted(restricted),
+        ) = join_rule
+        else {
+            return Ok(None);
+        };
+
+        if self.get_member(room_id, user_id)?.is_some_and(|member| {
+            matches!(
+                member.membership,
+                MembershipState::Join | MembershipState::Invite
+            )
+        }) {
+            return Ok(None);
+        }
+
+        let allowed_rooms = restricted
+            .allow
+            .into_iter()
+            .filter_map(|rule| match rule {
+                AllowRule::RoomMembership(membership) => {
+                    Some(membership.room_id)
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        if !allowed_rooms.iter().any(|allowed_room| {
+            services()
+                .rooms
+                .state_cache
+                .is_joined(user_id, allowed_room)
+                .unwrap_or(false)
+        }) {
+            return Err(Error::RestrictedJoin(allowed_rooms));
+        }
+
+        for member in services().rooms.state_cache.room_members(room_id) {
+            let member = member?;
+            if member.server_name() == services().globals.server_name()
+                && self.user_can_invite(room_id, &member, user_id)
+            {
+                return Ok(Some(member));
+            }
+        }
+
+        Err(Error::BadRequest(
+            ErrorKind::UnableToAuthorizeJoin,
+            "No user on this server can authorize the join.",
+        ))
+    }
+
+    /// Checks whether the room version and join rules of a room allow users
+    /// to knock on it.
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn allows_knocking(&self, room_id: &RoomId) -> Result<bool> {
+        let room_version_id =
+            services().rooms.state.get_room_version(room_id)?;
+        if !RoomVersion::new(&room_version_id)
+            .is_ok_and(|room_version| room_version.allow_knocking)
+        {
+            return Ok(false);
+        }
+
+        let join_rule = self
+            .room_state_get(room_id, &StateEventType::RoomJoinRules, "")?
+            .map(|s| {
+                serde_json::from_str(s.content.get())
+                    .map(|c: RoomJoinRulesEventContent| c.join_rule)
+                    .map_err(|error| {
+                        warn!(%error, "Invalid join rules event");
+                        Error::bad_database("Invalid join rules event in db.")
+                    })
+            })
+            .transpose()?;
+
+        Ok(matches!(
+            join_rule,
+            Some(JoinRule::Knock | JoinRule::KnockRestricted(_))
+        ))
+    }
+
     #[tracing::instrument(skip(self))]
     pub(crate) fn get_member(
         &self,
@@ -432,6 +602,43 @@ impl Service {
         })
     }
 
+    /// Returns the power level of a user in a room
+    ///
+    /// Without an `m.room.power_levels` event, the creator of the room has a
+    /// power level of 100 and everyone else 0.
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn user_power_level(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> Result<Int> {
+        if let Some(pdu) =
+            self.room_state_get(room_id, &StateEventType::RoomPowerLevels, "")?
+        {
+            let power_levels: RoomPowerLevels = serde_json::from_str::<
+                RoomPowerLevelsEventContent,
+            >(pdu.content.get())
+            .map_err(|_| {
+                Error::bad_database(
+                    "Invalid m.room.power_levels event in database",
+                )
+            })?
+            .into();
+
+            return Ok(power_levels.for_user(user_id));
+        }
+
+        let is_creator = self
+            .room_state_get(room_id, &StateEventType::RoomCreate, "")?
+            .is_some_and(|pdu| pdu.sender == user_id);
+
+        Ok(if is_creator {
+            Int::from(100)
+        } else {
+            Int::from(0)
+        })
+    }
+
     /// Checks if a given user can redact a given event
     ///
     /// If `federation` is `true`, it allows redaction events from any user of
@@ -4,7 +4,6 @@ use std::{collections::HashSet, sync::Arc};
 pub(crate) use data::Data;
 use ruma::{
     events::{
-        ignored_user_list::IgnoredUserListEvent,
         room::{create::RoomCreateEventContent, member::MembershipState},
         AnyStrippedStateEvent, AnySyncStateEvent, GlobalAccountDataEventType,
         RoomAccountDataEventType, StateEventType,
@@ -14,7 +13,7 @@ use ruma::{
 };
 use tracing::warn;
 
-use crate::{service::appservice::RegistrationInfo, services, Error, Result};
+use crate::{service::appservice::RegistrationInfo, services, Result};
 
 pub(crate) struct Service {
     pub(crate) db: &'static dyn Data,
@@ -75,41 +74,9 @@ impl Service {
                 self.db.mark_as_joined(user_id, room_id)?;
             }
             MembershipState::Invite => {
-                let event_kind = RoomAccountDataEventType::from(
-                    GlobalAccountDataEventType::IgnoredUserList.to_string(),
-                );
-
                 // We want to know if the sender is ignored by the receiver
-                let is_ignored = services()
-                    .account_data
-                    .get(
-                        // Ignored users are in global account data
-                        None,
-                        // Receiver
-                        user_id,
-                        event_kind.clone(),
-                    )?
-                    .map(|event| {
-                        serde_json::from_str::<IgnoredUserListEvent>(
-                            event.get(),
-                        )
-                        .map_err(|error| {
-                            warn!(
-                                %error,
-                                %event_kind,
-                                "Invalid account data event",
-                            );
-                            Error::BadDatabase("Invalid account data event.")
-                        })
-                    })
-                    .transpose()?
-                    .map_or(false, |ignored| {
-                        ignored
-                            .content
-                            .ignored_users
-                            .iter()
-                            .any(|(user, _details)| user == sender)
-                    });
+                let is_ignored =
+                    services().account_data.is_ignored(user_id, sender)?;
 
                 if is_ignored {
                     return Ok(());
@@ -117,6 +84,9 @@ impl Service {
 
                 self.db.mark_as_invited(user_id, room_id, last_state)?;
             }
+            MembershipState::Knock => {
+                self.db.mark_as_knocked(user_id, room_id, last_state)?;
+            }
             MembershipState::Leave | MembershipState::Ban => {
                 self.db.mark_as_left(user_id, room_id)?;
             }
@@ -383,6 +353,15 @@ impl Service {
         self.db.get_invite_count(room_id, user_id)
     }
 
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn get_knock_count(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> Result<Option<u64>> {
+        self.db.get_knock_count(room_id, user_id)
+    }
+
     #[tracing::instrument(skip(self))]
     pub(crate) fn get_left_count(
         &self,
@@ -421,6 +400,26 @@ impl Service {
         self.db.invite_state(user_id, room_id)
     }
 
+    /// Returns an iterator over all rooms a user knocked on.
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn rooms_knocked<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> impl Iterator<
+        Item = Result<(OwnedRoomId, Vec<Raw<AnyStrippedStateEvent>>)>,
+    > + 'a {
+        self.db.rooms_knocked(user_id)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn knock_state(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<Option<Vec<Raw<AnyStrippedStateEvent>>>> {
+        self.db.knock_state(user_id, room_id)
+    }
+
     #[tracing::instrument(skip(self))]
     pub(crate) fn left_state(
         &self,
@@ -8,6 +8,7 @@ use ruma::{
 };
 use serde_json::json;
 
+use super::timeline::PduCount;
 use crate::{services, Error, PduEvent, Result};
 
 pub(crate) struct Service {
@@ -19,9 +20,9 @@ impl Service {
         &'a self,
         user_id: &'a UserId,
         room_id: &'a RoomId,
-        until: u64,
+        until: PduCount,
         include: &'a IncludeThreads,
-    ) -> Result<impl Iterator<Item = Result<(u64, PduEvent)>> + 'a> {
+    ) -> Result<impl Iterator<Item = Result<(PduCount, PduEvent)>> + 'a> {
         self.db.threads_until(user_id, room_id, until, include)
     }
 
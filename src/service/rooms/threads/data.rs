@@ -3,7 +3,7 @@ use ruma::{
     UserId,
 };
 
-use crate::{PduEvent, Result};
+use crate::{service::rooms::timeline::PduCount, PduEvent, Result};
 
 pub(crate) trait Data: Send + Sync {
     #[allow(clippy::type_complexity)]
@@ -11,9 +11,9 @@ pub(crate) trait Data: Send + Sync {
         &'a self,
         user_id: &'a UserId,
         room_id: &'a RoomId,
-        until: u64,
+        until: PduCount,
         include: &'a IncludeThreads,
-    ) -> Result<Box<dyn Iterator<Item = Result<(u64, PduEvent)>> + 'a>>;
+    ) -> Result<Box<dyn Iterator<Item = Result<(PduCount, PduEvent)>> + 'a>>;
 
     fn update_participants(
         &self,
@@ -2,8 +2,9 @@ mod data;
 
 use std::{
     cmp::Ordering,
-    collections::{BTreeMap, HashSet},
+    collections::{hash_map, BTreeMap, HashSet},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 pub(crate) use data::Data;
@@ -19,7 +20,7 @@ use ruma::{
         },
         GlobalAccountDataEventType, StateEventType, TimelineEventType,
     },
-    push::{Action, Ruleset, Tweak},
+    push::{Action, Tweak},
     state_res::{self, Event, RoomVersion},
     uint, user_id, CanonicalJsonObject, CanonicalJsonValue, EventId,
     OwnedEventId, OwnedRoomId, OwnedServerName, RoomId, RoomVersionId,
@@ -28,7 +29,7 @@ use ruma::{
 use serde::Deserialize;
 use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
 use tokio::sync::RwLock;
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 
 use super::state_compressor::CompressedStateEvent;
 use crate::{
@@ -73,6 +74,21 @@ impl PduCount {
             PduCount::Normal(x) => x.to_string(),
         }
     }
+
+    /// Returns the count directly before this one, crossing over from normal
+    /// to backfilled counts.
+    ///
+    /// Used to exclude the event a pagination token points at when iterating
+    /// backwards from it.
+    pub(crate) fn previous(self) -> Self {
+        match self {
+            PduCount::Normal(0) => PduCount::Backfilled(0),
+            PduCount::Normal(x) => PduCount::Normal(x - 1),
+            PduCount::Backfilled(x) => {
+                PduCount::Backfilled(x.saturating_add(1))
+            }
+        }
+    }
 }
 
 impl PartialOrd for PduCount {
@@ -327,6 +343,11 @@ impl Service {
                 continue;
             }
 
+            // Nor of events of users they ignore
+            if services().account_data.is_ignored(user, &pdu.sender)? {
+                continue;
+            }
+
             let rules_for_user = services()
                 .account_data
                 .get(
@@ -345,7 +366,7 @@ impl Service {
                 })
                 .transpose()?
                 .map_or_else(
-                    || Ruleset::server_default(user),
+                    || services().globals.default_push_rules(user),
                     |ev: PushRulesEvent| ev.content.global,
                 );
 
@@ -381,8 +402,19 @@ impl Service {
             }
         }
 
+        // Events in threads are also counted per thread for clients that
+        // receive threaded read receipts
+        let thread_root =
+            match serde_json::from_str::<ExtractRelatesTo>(pdu.content.get()) {
+                Ok(ExtractRelatesTo {
+                    relates_to: Relation::Thread(thread),
+                }) => Some(thread.event_id),
+                _ => None,
+            };
+
         self.db.increment_notification_counts(
             &pdu.room_id,
+            thread_root.as_deref(),
             notifies,
             highlights,
         )?;
@@ -432,7 +464,25 @@ impl Service {
                     }
                 };
             }
-            TimelineEventType::SpaceChild => {
+            TimelineEventType::RoomAvatar
+            | TimelineEventType::RoomCanonicalAlias
+            | TimelineEventType::RoomGuestAccess
+            | TimelineEventType::RoomHistoryVisibility
+            | TimelineEventType::RoomJoinRules
+            | TimelineEventType::RoomName
+            | TimelineEventType::RoomTopic => {
+                if pdu.state_key.is_some() {
+                    services().rooms.directory.invalidate_summary(&pdu.room_id);
+                    services()
+                        .rooms
+                        .spaces
+                        .roomid_spacechunk_cache
+                        .lock()
+                        .await
+                        .remove(&pdu.room_id);
+                }
+            }
+            TimelineEventType::SpaceChild | TimelineEventType::SpaceParent => {
                 if let Some(_state_key) = &pdu.state_key {
                     services()
                         .rooms
@@ -462,7 +512,7 @@ impl Service {
                     })?;
 
                     let invite_state = match content.membership {
-                        MembershipState::Invite => {
+                        MembershipState::Invite | MembershipState::Knock => {
                             let state = services()
                                 .rooms
                                 .state
@@ -583,6 +633,18 @@ impl Service {
                         .threads
                         .add_to_thread(&thread.event_id, pdu)?;
                 }
+                Relation::Replacement(replacement) => {
+                    services().rooms.pdu_metadata.add_replacement(
+                        &replacement.event_id,
+                        &pdu.event_id,
+                    )?;
+                }
+                Relation::Annotation(annotation) => {
+                    services().rooms.pdu_metadata.add_annotation(
+                        &annotation.event_id,
+                        &annotation.key,
+                    )?;
+                }
                 // TODO: Aggregate other types
                 _ => {}
             }
@@ -765,11 +827,15 @@ impl Service {
             signatures: None,
         };
 
+        let third_party_invite =
+            pdu.third_party_invite_token().and_then(|token| {
+                auth_events.get(&(StateEventType::RoomThirdPartyInvite, token))
+            });
+
         let auth_check = state_res::auth_check(
             &room_version,
             &pdu,
-            // TODO: third_party_invite
-            None::<PduEvent>,
+            third_party_invite,
             |k, s| auth_events.get(&(k.clone(), s.to_owned())),
         )
         .map_err(|error| {
@@ -799,7 +865,7 @@ impl Service {
 
         match ruma::signatures::hash_and_sign_event(
             services().globals.server_name().as_str(),
-            services().globals.keypair(),
+            &*services().globals.keypair(),
             &mut pdu_json,
             &room_version_id,
         ) {
@@ -1176,6 +1242,20 @@ impl Service {
                 )?;
             }
 
+            // Redacted reactions are not counted anymore. Their content is
+            // gone after the first redaction, so they are only removed once.
+            if pdu.kind == TimelineEventType::Reaction {
+                if let Ok(ExtractRelatesTo {
+                    relates_to: Relation::Annotation(annotation),
+                }) = serde_json::from_str(pdu.content.get())
+                {
+                    services().rooms.pdu_metadata.remove_annotation(
+                        &annotation.event_id,
+                        &annotation.key,
+                    )?;
+                }
+            }
+
             let room_version_id =
                 services().rooms.state.get_room_version(&pdu.room_id)?;
             pdu.redact(room_version_id, reason)?;
@@ -1190,22 +1270,83 @@ impl Service {
         Ok(())
     }
 
+    /// Fetches earlier events of a room from other servers if fewer than
+    /// `limit` events before `from` are known.
+    ///
+    /// Rooms where backfilling added no events are backed off from
+    /// exponentially, so that clients paginating past the known history
+    /// don't cause requests over and over.
     #[tracing::instrument(skip(self, room_id))]
     pub(crate) async fn backfill_if_required(
         &self,
         room_id: &RoomId,
         from: PduCount,
+        limit: usize,
     ) -> Result<()> {
+        let user_id = user_id!("@doesntmatter:grapevine");
+        if self.pdus_until(user_id, room_id, from)?.take(limit).count() >= limit
+        {
+            // No backfill required, there are still enough events before
+            return Ok(());
+        }
+
         let first_pdu = self
-            .all_pdus(user_id!("@doesntmatter:grapevine"), room_id)?
+            .all_pdus(user_id, room_id)?
             .next()
             .expect("Room is not empty")?;
 
-        if first_pdu.0 < from {
-            // No backfill required, there are still events between them
+        if first_pdu.1.kind == TimelineEventType::RoomCreate {
+            // There is no history before the creation of the room
             return Ok(());
         }
 
+        if let Some((time, tries)) = services()
+            .globals
+            .bad_backfill_ratelimiter
+            .read()
+            .await
+            .get(room_id)
+        {
+            // Exponential backoff
+            let mut min_elapsed_duration =
+                Duration::from_secs(30) * (*tries) * (*tries);
+            if min_elapsed_duration > Duration::from_secs(60 * 60 * 24) {
+                min_elapsed_duration = Duration::from_secs(60 * 60 * 24);
+            }
+
+            if time.elapsed() < min_elapsed_duration {
+                debug!(%tries, "Backing off from backfilling room");
+                return Ok(());
+            }
+        }
+
+        let added = self.backfill(room_id, &first_pdu.1.event_id).await?;
+
+        let mut ratelimiter =
+            services().globals.bad_backfill_ratelimiter.write().await;
+        if added == 0 {
+            match ratelimiter.entry(room_id.to_owned()) {
+                hash_map::Entry::Vacant(e) => {
+                    e.insert((Instant::now(), 1));
+                }
+                hash_map::Entry::Occupied(mut e) => {
+                    *e.get_mut() = (Instant::now(), e.get().1 + 1);
+                }
+            }
+        } else {
+            ratelimiter.remove(room_id);
+        }
+
+        Ok(())
+    }
+
+    /// Asks other servers in the room for the events before `event_id`,
+    /// returning how many were added
+    async fn backfill(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<usize> {
         let power_levels: RoomPowerLevelsEventContent = services()
             .rooms
             .state_accessor
@@ -1221,12 +1362,23 @@ impl Service {
             .users
             .iter()
             .filter(|(_, level)| **level > power_levels.users_default)
-            .map(|(user_id, _)| user_id.server_name())
+            .map(|(user_id, _)| user_id.server_name().to_owned())
             .collect::<HashSet<_>>();
         admin_servers.remove(services().globals.server_name());
 
+        if admin_servers.is_empty() {
+            // Any server in the room may have the history
+            admin_servers = services()
+                .rooms
+                .state_cache
+                .room_servers(room_id)
+                .filter_map(Result::ok)
+                .filter(|server| &**server != services().globals.server_name())
+                .collect();
+        }
+
         // Request backfill
-        for backfill_server in admin_servers {
+        for backfill_server in &admin_servers {
             info!(server = %backfill_server, "Asking server for backfill");
             let response = services()
                 .sending
@@ -1234,7 +1386,7 @@ impl Service {
                     backfill_server,
                     federation::backfill::get_backfill::v1::Request {
                         room_id: room_id.to_owned(),
-                        v: vec![first_pdu.1.event_id.as_ref().to_owned()],
+                        v: vec![event_id.to_owned()],
                         limit: uint!(100),
                     },
                 )
@@ -1242,15 +1394,20 @@ impl Service {
             match response {
                 Ok(response) => {
                     let pub_key_map = RwLock::new(BTreeMap::new());
+                    let mut added = 0;
                     for pdu in response.pdus {
-                        if let Err(error) = self
+                        match self
                             .backfill_pdu(backfill_server, pdu, &pub_key_map)
                             .await
                         {
-                            warn!(%error, "Failed to add backfilled pdu");
+                            Ok(true) => added += 1,
+                            Ok(false) => {}
+                            Err(error) => {
+                                warn!(%error, "Failed to add backfilled pdu");
+                            }
                         }
                     }
-                    return Ok(());
+                    return Ok(added);
                 }
                 Err(error) => {
                     warn!(
@@ -1263,16 +1420,18 @@ impl Service {
         }
 
         info!("No servers could backfill");
-        Ok(())
+        Ok(0)
     }
 
+    /// Adds an event to the backfilled timeline, returning whether it wasn't
+    /// in the timeline yet
     #[tracing::instrument(skip(self, pdu))]
     pub(crate) async fn backfill_pdu(
         &self,
         origin: &ServerName,
         pdu: Box<RawJsonValue>,
         pub_key_map: &RwLock<BTreeMap<String, SigningKeys>>,
-    ) -> Result<()> {
+    ) -> Result<bool> {
         let (event_id, value, room_id) =
             server_server::parse_incoming_pdu(&pdu)?;
 
@@ -1286,7 +1445,7 @@ impl Service {
         // Skip the PDU if we already have it as a timeline event
         if let Some(pdu_id) = services().rooms.timeline.get_pdu_id(&event_id)? {
             info!(%event_id, ?pdu_id, "We already know this event");
-            return Ok(());
+            return Ok(false);
         }
 
         services()
@@ -1327,6 +1486,34 @@ impl Service {
 
         drop(insert_token);
 
+        // Update Relationships, so that backfilled replies can be paginated
+        // like local ones
+        let related_event_id = if let Ok(content) =
+            serde_json::from_str::<ExtractRelatesToEventId>(pdu.content.get())
+        {
+            Some(content.relates_to.event_id)
+        } else if let Ok(ExtractRelatesTo {
+            relates_to:
+                Relation::Reply {
+                    in_reply_to,
+                },
+        }) = serde_json::from_str(pdu.content.get())
+        {
+            Some(in_reply_to.event_id)
+        } else {
+            None
+        };
+        if let Some(related_event_id) = related_event_id {
+            if let Some(related_pducount) =
+                self.get_pdu_count(&related_event_id)?
+            {
+                services().rooms.pdu_metadata.add_relation(
+                    PduCount::Backfilled(count),
+                    related_pducount,
+                )?;
+            }
+        }
+
         if pdu.kind == TimelineEventType::RoomMessage {
             #[derive(Deserialize)]
             struct ExtractBody {
@@ -1350,14 +1537,14 @@ impl Service {
         drop(federation_token);
 
         info!("Prepended backfill pdu");
-        Ok(())
+        Ok(true)
     }
 }
 
 #[derive(Deserialize)]
-struct ExtractRelatesTo {
+pub(crate) struct ExtractRelatesTo {
     #[serde(rename = "m.relates_to")]
-    relates_to: Relation,
+    pub(crate) relates_to: Relation,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -1381,4 +1568,35 @@ mod tests {
         assert!(PduCount::Normal(1) > PduCount::Backfilled(1));
         assert!(PduCount::Backfilled(1) < PduCount::Normal(1));
     }
+
+    #[test]
+    fn tokens_round_trip() {
+        for count in [
+            PduCount::Normal(0),
+            PduCount::Normal(42),
+            PduCount::MAX,
+            PduCount::Backfilled(0),
+            PduCount::Backfilled(42),
+            PduCount::MIN,
+        ] {
+            assert_eq!(
+                PduCount::try_from_string(&count.stringify()).unwrap(),
+                count
+            );
+        }
+    }
+
+    #[test]
+    fn previous() {
+        assert_eq!(PduCount::Normal(5).previous(), PduCount::Normal(4));
+        assert_eq!(PduCount::Normal(0).previous(), PduCount::Backfilled(0));
+        assert_eq!(PduCount::Backfilled(5).previous(), PduCount::Backfilled(6));
+        assert_eq!(PduCount::MIN.previous(), PduCount::MIN);
+
+        for count in
+            [PduCount::Normal(5), PduCount::Normal(0), PduCount::Backfilled(5)]
+        {
+            assert!(count.previous() < count);
+        }
+    }
 }
@@ -100,9 +100,12 @@ pub(crate) trait Data: Send + Sync {
         from: PduCount,
     ) -> Result<Box<dyn Iterator<Item = Result<(PduCount, PduEvent)>> + 'a>>;
 
+    /// Increments the notification counts of a room, and of the thread with
+    /// the root `thread_root` if the event is in one
     fn increment_notification_counts(
         &self,
         room_id: &RoomId,
+        thread_root: Option<&EventId>,
         notifies: Vec<OwnedUserId>,
         highlights: Vec<OwnedUserId>,
     ) -> Result<()>;
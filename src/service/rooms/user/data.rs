@@ -1,14 +1,42 @@
-use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+use std::collections::BTreeMap;
+
+use ruma::{EventId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId};
 
 use crate::Result;
 
 pub(crate) trait Data: Send + Sync {
+    /// Resets the notification counts of a room, including its threads
     fn reset_notification_counts(
         &self,
         user_id: &UserId,
         room_id: &RoomId,
     ) -> Result<()>;
 
+    /// Resets the notification counts of a room, except for the ones of its
+    /// threads
+    fn reset_main_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<()>;
+
+    /// Resets the notification counts of a thread, which are also removed
+    /// from the counts of the room
+    fn reset_thread_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        thread_root: &EventId,
+    ) -> Result<()>;
+
+    /// Returns the notification and highlight counts of each thread of a room
+    /// with unread notifications
+    fn thread_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<BTreeMap<OwnedEventId, (u64, u64)>>;
+
     fn notification_count(
         &self,
         user_id: &UserId,
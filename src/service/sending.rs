@@ -1,10 +1,10 @@
 mod data;
 
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
+    collections::{BTreeMap, HashMap},
     fmt::Debug,
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
 use base64::{engine::general_purpose, Engine as _};
@@ -13,24 +13,29 @@ use federation::transactions::send_transaction_message;
 use futures_util::{stream::FuturesUnordered, StreamExt};
 use ruma::{
     api::{
-        appservice::{self, Registration},
+        appservice::{
+            self, event::push_events::v1::EphemeralData, Registration,
+        },
         federation::{
             self,
             transactions::edu::{
-                DeviceListUpdateContent, Edu, ReceiptContent, ReceiptData,
-                ReceiptMap,
+                Edu, PresenceContent, PresenceUpdate, ReceiptContent,
+                ReceiptData, ReceiptMap,
             },
         },
         OutgoingRequest,
     },
-    device_id,
     events::{
-        push_rules::PushRulesEvent, receipt::ReceiptType,
-        AnySyncEphemeralRoomEvent, GlobalAccountDataEventType,
+        presence::PresenceEvent,
+        push_rules::PushRulesEvent,
+        receipt::{ReceiptEventContent, ReceiptType},
+        AnyEphemeralRoomEvent, AnySyncEphemeralRoomEvent,
+        GlobalAccountDataEventType,
     },
-    push, uint, MilliSecondsSinceUnixEpoch, OwnedServerName, OwnedUserId,
-    ServerName, UInt, UserId,
+    MilliSecondsSinceUnixEpoch, OwnedRoomId, OwnedServerName, OwnedUserId,
+    RoomId, ServerName, UInt, UserId,
 };
+use serde::Serialize;
 use tokio::{
     select,
     sync::{mpsc, Mutex, Semaphore},
@@ -39,6 +44,7 @@ use tracing::{debug, error, warn, Span};
 
 use crate::{
     api::{appservice_server, server_server},
+    observability::{FederationDirection, FederationOutcome, METRICS},
     services,
     utils::{calculate_hash, debug_slice_truncated},
     Config, Error, PduEvent, Result,
@@ -109,6 +115,15 @@ pub(crate) struct RequestData {
     requester_span: Span,
 }
 
+/// Persisted backoff state of a federation destination
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Backoff {
+    /// Number of transactions that failed in a row
+    pub(crate) failures: u32,
+    /// When to send the next transaction at the earliest
+    pub(crate) next_retry: SystemTime,
+}
+
 pub(crate) struct Service {
     db: &'static dyn Data,
 
@@ -116,12 +131,14 @@ pub(crate) struct Service {
     pub(super) maximum_requests: Arc<Semaphore>,
     pub(crate) sender: mpsc::UnboundedSender<RequestData>,
     receiver: Mutex<mpsc::UnboundedReceiver<RequestData>>,
+    /// Upper bound of the delay between retries to a failing destination
+    max_backoff: Duration,
 }
 
 #[derive(Debug)]
 enum TransactionStatus {
     Running,
-    // number of times failed, time of last failure
+    // number of times failed, earliest time of the next retry
     Failed(u32, Instant),
     // number of times failed
     Retrying(u32),
@@ -161,9 +178,24 @@ impl Service {
             maximum_requests: Arc::new(Semaphore::new(
                 config.federation.max_concurrent_requests.into(),
             )),
+            max_backoff: config.federation.max_backoff,
         })
     }
 
+    /// Returns the federation destinations that are currently backed off
+    /// from, as of the last failed transaction.
+    pub(crate) fn backoffs(&self) -> Result<Vec<(OwnedServerName, Backoff)>> {
+        self.db.all_backoffs().collect()
+    }
+
+    /// How long to wait before retrying a destination after `failures`
+    /// failed transactions in a row (exponential backoff)
+    fn backoff_duration(&self, failures: u32) -> Duration {
+        Duration::from_secs(30)
+            .saturating_mul(failures.saturating_mul(failures))
+            .min(self.max_backoff)
+    }
+
     pub(crate) fn start_handler(self: &Arc<Self>) {
         let self2 = Arc::clone(self);
         tokio::spawn(async move {
@@ -178,6 +210,20 @@ impl Service {
 
         let mut current_transaction_status = TransactionStatusMap::new();
 
+        // Restore the backoff of destinations that failed before the restart
+        for entry in self.db.all_backoffs() {
+            let (server_name, backoff) = entry?;
+            let next_retry = Instant::now()
+                + backoff
+                    .next_retry
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default();
+            current_transaction_status.insert(
+                Destination::Normal(server_name),
+                TransactionStatus::Failed(backoff.failures, next_retry),
+            );
+        }
+
         // Retry requests we could not finish yet
         let mut initial_transactions =
             HashMap::<Destination, Vec<SendingEventType>>::new();
@@ -203,8 +249,17 @@ impl Service {
         }
 
         for (destination, events) in initial_transactions {
-            current_transaction_status
-                .insert(destination.clone(), TransactionStatus::Running);
+            let status = match current_transaction_status.get(&destination) {
+                Some(TransactionStatus::Failed(tries, next_retry)) => {
+                    if Instant::now() < *next_retry {
+                        // Retried once new events come in after the backoff
+                        continue;
+                    }
+                    TransactionStatus::Retrying(*tries)
+                }
+                _ => TransactionStatus::Running,
+            };
+            current_transaction_status.insert(destination.clone(), status);
             futures.push(handle_events(HandlerInputs {
                 destination: destination.clone(),
                 events,
@@ -212,15 +267,72 @@ impl Service {
             }));
         }
 
+        // Send events that were queued for appservices but not sent yet
+        for id in services().appservice.iter_ids().await {
+            let destination = Destination::Appservice(id);
+            if current_transaction_status.contains_key(&destination) {
+                continue;
+            }
+
+            match self.select_events(
+                &destination,
+                Vec::new(),
+                &mut current_transaction_status,
+            ) {
+                Ok(SelectedEvents::New(events)) if !events.is_empty() => {
+                    futures.push(handle_events(HandlerInputs {
+                        destination,
+                        events,
+                        requester_span: None,
+                    }));
+                }
+                Ok(_) => {
+                    current_transaction_status.remove(&destination);
+                }
+                Err(error) => {
+                    current_transaction_status.remove(&destination);
+                    error!(%error, "Failed to select queued appservice events");
+                }
+            }
+        }
+
+        // Failed appservice and push gateway transactions are retried once
+        // their backoff is over, federation destinations only when new events
+        // are sent to them
+        let mut retries = FuturesUnordered::new();
+
         loop {
             select! {
                 Some(response) = futures.next() => {
+                    let destination = response.destination.clone();
                     if let Some(inputs) = self.handle_response(
                         response,
                         &mut current_transaction_status,
                     )? {
                         futures.push(handle_events(inputs));
                     }
+
+                    if let (
+                        Destination::Appservice(_) | Destination::Push(..),
+                        Some(TransactionStatus::Failed(_, next_retry)),
+                    ) = (
+                        &destination,
+                        current_transaction_status.get(&destination),
+                    ) {
+                        retries.push(wait_for_retry(
+                            destination.clone(),
+                            *next_retry,
+                        ));
+                    }
+                    self.record_queue_depth(&destination);
+                }
+                Some(destination) = retries.next() => {
+                    if let Some(inputs) = self.retry_transaction(
+                        destination,
+                        &mut current_transaction_status,
+                    ) {
+                        futures.push(handle_events(inputs));
+                    }
                 }
                 Some(data) = receiver.recv() => {
                     if let Some(inputs) = self.handle_receiver(
@@ -260,21 +372,40 @@ impl Service {
 
         if let Err(error) = result {
             warn!(%error, "Marking transaction as failed");
-            current_transaction_status.entry(destination).and_modify(|e| {
-                use TransactionStatus::{Failed, Retrying, Running};
-
-                *e = match e {
-                    Running => Failed(1, Instant::now()),
-                    Retrying(n) => Failed(*n + 1, Instant::now()),
-                    Failed(..) => {
-                        error!("Request that was not even running failed?!");
-                        return;
-                    }
+            let failures = match current_transaction_status.get(&destination) {
+                Some(TransactionStatus::Running) => 1,
+                Some(TransactionStatus::Retrying(n)) => n.saturating_add(1),
+                Some(TransactionStatus::Failed(..)) | None => {
+                    error!("Request that was not even running failed?!");
+                    return Ok(None);
                 }
-            });
+            };
+
+            let backoff = self.backoff_duration(failures);
+            current_transaction_status.insert(
+                destination.clone(),
+                TransactionStatus::Failed(failures, Instant::now() + backoff),
+            );
+            if let Destination::Normal(server_name) = &destination {
+                self.db.set_backoff(
+                    server_name,
+                    Backoff {
+                        failures,
+                        next_retry: SystemTime::now() + backoff,
+                    },
+                )?;
+            }
             return Ok(None);
         }
 
+        if let (
+            Destination::Normal(server_name),
+            Some(TransactionStatus::Retrying(_)),
+        ) = (&destination, current_transaction_status.get(&destination))
+        {
+            self.db.remove_backoff(server_name)?;
+        }
+
         self.db.delete_all_active_requests_for(&destination)?;
 
         // Find events that have been added since starting the
@@ -301,6 +432,54 @@ impl Service {
         }))
     }
 
+    /// Retries the failed transaction of an appservice or push gateway once
+    /// its backoff is over
+    #[tracing::instrument(skip(self, current_transaction_status))]
+    fn retry_transaction(
+        &self,
+        destination: Destination,
+        current_transaction_status: &mut TransactionStatusMap,
+    ) -> Option<HandlerInputs> {
+        if !matches!(
+            current_transaction_status.get(&destination),
+            Some(TransactionStatus::Failed(..))
+        ) {
+            // Already retried because new events were sent
+            return None;
+        }
+
+        match self.select_events(
+            &destination,
+            Vec::new(),
+            current_transaction_status,
+        ) {
+            Ok(SelectedEvents::Retries(events)) => {
+                debug!("Retrying failed transaction");
+                Some(HandlerInputs {
+                    destination,
+                    events,
+                    requester_span: None,
+                })
+            }
+            Ok(SelectedEvents::New(_) | SelectedEvents::None) => None,
+            Err(error) => {
+                error!(%error, "Failed to select events to retry");
+                None
+            }
+        }
+    }
+
+    /// Records the number of events waiting to be sent to an appservice
+    fn record_queue_depth(&self, destination: &Destination) {
+        let Destination::Appservice(id) = destination else {
+            return;
+        };
+
+        let depth = self.db.active_requests_for(destination).count()
+            + self.db.queued_requests(destination).count();
+        METRICS.record_appservice_queue_depth(id.clone(), depth);
+    }
+
     #[tracing::instrument(
         skip(self, event_type, key, requester_span, current_transaction_status),
         fields(
@@ -380,18 +559,10 @@ impl Service {
                     // already running
                     allow = false;
                 }
-                TransactionStatus::Failed(tries, time) => {
+                TransactionStatus::Failed(tries, next_retry) => {
                     // Fail if a request has failed recently (exponential
                     // backoff)
-                    let mut min_elapsed_duration =
-                        Duration::from_secs(30) * (*tries) * (*tries);
-                    if min_elapsed_duration > Duration::from_secs(60 * 60 * 24)
-                    {
-                        min_elapsed_duration =
-                            Duration::from_secs(60 * 60 * 24);
-                    }
-
-                    if time.elapsed() < min_elapsed_duration {
+                    if Instant::now() < *next_retry {
                         allow = false;
                     } else {
                         retry = true;
@@ -418,6 +589,18 @@ impl Service {
         } else {
             let mut events = Vec::new();
 
+            // Appservices must receive events in order, so events that are
+            // still queued, e.g. from before a restart, are sent first
+            let new_events = if let Destination::Appservice(_) = destination {
+                self.db
+                    .queued_requests(destination)
+                    .filter_map(Result::ok)
+                    .take(30)
+                    .collect()
+            } else {
+                new_events
+            };
+
             self.db.mark_as_active(&new_events)?;
             for (e, _) in new_events {
                 events.push(e);
@@ -448,23 +631,43 @@ impl Service {
         let since = self.db.get_latest_educount(server_name)?;
         let mut events = Vec::new();
         let mut max_edu_count = since;
-        let mut device_list_changes = HashSet::new();
+        let mut presence_updates = HashMap::new();
 
         'outer: for room_id in
             services().rooms.state_cache.server_rooms(server_name)
         {
             let room_id = room_id?;
-            // Look for device list updates in this room
-            device_list_changes.extend(
-                services()
-                    .users
-                    .keys_changed(room_id.as_ref(), since, None)
-                    .filter_map(Result::ok)
-                    .filter(|user_id| {
-                        user_id.server_name()
-                            == services().globals.server_name()
-                    }),
-            );
+            if !services()
+                .rooms
+                .state_accessor
+                .server_allowed_by_acl(server_name, &room_id)?
+            {
+                continue;
+            }
+
+            // Look for presence updates in this room
+            if services().globals.config.allow_outgoing_presence {
+                for (count, presence) in services()
+                    .rooms
+                    .edus
+                    .presence
+                    .local_presence_since(&room_id, since)?
+                {
+                    if count > max_edu_count {
+                        max_edu_count = count;
+                    }
+
+                    // Only the latest state of each user is sent, even if it
+                    // changed several times since the last transaction
+                    if presence_updates
+                        .get(&presence.sender)
+                        .map_or(true, |(c, _)| *c < count)
+                    {
+                        presence_updates
+                            .insert(presence.sender.clone(), (count, presence));
+                    }
+                }
+            }
 
             // Look for read receipts in this room
             for r in services()
@@ -489,46 +692,15 @@ impl Service {
                 .map_err(|_| {
                     Error::bad_database("Invalid edu event in read_receipts.")
                 })?;
-                let federation_event =
-                    if let AnySyncEphemeralRoomEvent::Receipt(r) = event {
-                        let mut read = BTreeMap::new();
-
-                        let (event_id, mut receipt) =
-                            r.content.0.into_iter().next().expect(
-                                "we only use one event per read receipt",
-                            );
-                        let receipt = receipt
-                            .remove(&ReceiptType::Read)
-                            .expect("our read receipts always set this")
-                            .remove(&user_id)
-                            .expect(
-                                "our read receipts always have the user here",
-                            );
-
-                        read.insert(
-                            user_id,
-                            ReceiptData {
-                                data: receipt.clone(),
-                                event_ids: vec![event_id.clone()],
-                            },
-                        );
-
-                        let receipt_map = ReceiptMap {
-                            read,
-                        };
-
-                        let mut receipts = BTreeMap::new();
-                        receipts.insert(room_id.clone(), receipt_map);
-
-                        Edu::Receipt(ReceiptContent {
-                            receipts,
-                        })
-                    } else {
-                        Error::bad_database(
-                            "Invalid event type in read_receipts",
-                        );
-                        continue;
-                    };
+                let AnySyncEphemeralRoomEvent::Receipt(r) = event else {
+                    Error::bad_database("Invalid event type in read_receipts");
+                    continue;
+                };
+                let Some(federation_event) =
+                    receipt_edu(&room_id, &user_id, r.content)
+                else {
+                    continue;
+                };
 
                 events.push(
                     serde_json::to_vec(&federation_event)
@@ -541,19 +713,23 @@ impl Service {
             }
         }
 
-        for user_id in device_list_changes {
-            // Empty prev id forces synapse to resync: https://github.com/matrix-org/synapse/blob/98aec1cc9da2bd6b8e34ffb282c85abf9b8b42ca/synapse/handlers/device.py#L767
-            // Because synapse resyncs, we can just insert dummy data
-            let edu = Edu::DeviceListUpdate(DeviceListUpdateContent {
-                user_id,
-                device_id: device_id!("dummy").to_owned(),
-                device_display_name: Some("Dummy".to_owned()),
-                stream_id: uint!(1),
-                prev_id: Vec::new(),
-                deleted: None,
-                keys: None,
-            });
+        if !presence_updates.is_empty() {
+            let push = presence_updates
+                .into_values()
+                .map(|(_, presence)| {
+                    let mut update = PresenceUpdate::new(
+                        presence.sender,
+                        presence.content.presence,
+                        presence.content.last_active_ago.unwrap_or(UInt::MIN),
+                    );
+                    update.status_msg = presence.content.status_msg;
+                    update.currently_active =
+                        presence.content.currently_active.unwrap_or(false);
+                    update
+                })
+                .collect();
 
+            let edu = Edu::Presence(PresenceContent::new(push));
             events.push(
                 serde_json::to_vec(&edu).expect("json can be serialized"),
             );
@@ -593,6 +769,7 @@ impl Service {
     ) -> Result<()> {
         let requests = servers
             .into_iter()
+            .filter(|server| services().globals.federation_allowed_with(server))
             .map(|server| {
                 (
                     Destination::Normal(server),
@@ -624,6 +801,10 @@ impl Service {
         serialized: Vec<u8>,
         id: u64,
     ) -> Result<()> {
+        if !services().globals.federation_allowed_with(server) {
+            return Ok(());
+        }
+
         let destination = Destination::Normal(server.to_owned());
         let event_type = SendingEventType::Edu(serialized);
         let keys =
@@ -662,28 +843,160 @@ impl Service {
         Ok(())
     }
 
-    #[tracing::instrument(skip(self, request))]
+    /// Queues an ephemeral room event, like a typing notification or a read
+    /// receipt, for all appservices in the room that want to receive
+    /// ephemeral data
+    #[tracing::instrument(skip(self, event))]
+    pub(crate) async fn send_edu_appservices_in_room<T: Serialize>(
+        &self,
+        room_id: &RoomId,
+        event: &T,
+    ) -> Result<()> {
+        let mut appservice_ids = Vec::new();
+        for (id, appservice) in services().appservice.read().await.iter() {
+            if appservice.registration.receive_ephemeral
+                && services()
+                    .rooms
+                    .state_cache
+                    .appservice_in_room(room_id, appservice)?
+            {
+                appservice_ids.push(id.clone());
+            }
+        }
+
+        self.send_edu_appservices(appservice_ids, event)
+    }
+
+    /// Queues a presence update for all appservices that want to receive
+    /// ephemeral data and are interested in the user or one of the rooms
+    /// they are joined to
+    #[tracing::instrument(skip(self, presence))]
+    pub(crate) async fn send_presence_appservices(
+        &self,
+        presence: &PresenceEvent,
+        room_ids: &[OwnedRoomId],
+    ) -> Result<()> {
+        let mut appservice_ids = Vec::new();
+        'appservices: for (id, appservice) in
+            services().appservice.read().await.iter()
+        {
+            if !appservice.registration.receive_ephemeral {
+                continue;
+            }
+
+            if appservice.is_user_match(&presence.sender) {
+                appservice_ids.push(id.clone());
+                continue;
+            }
+
+            for room_id in room_ids {
+                if services()
+                    .rooms
+                    .state_cache
+                    .appservice_in_room(room_id, appservice)?
+                {
+                    appservice_ids.push(id.clone());
+                    continue 'appservices;
+                }
+            }
+        }
+
+        self.send_edu_appservices(appservice_ids, presence)
+    }
+
+    /// Queues an ephemeral event for the given appservices
+    fn send_edu_appservices<T: Serialize>(
+        &self,
+        appservice_ids: Vec<String>,
+        event: &T,
+    ) -> Result<()> {
+        if appservice_ids.is_empty() {
+            return Ok(());
+        }
+
+        let serialized =
+            serde_json::to_vec(event).expect("json can be serialized");
+        let requests = appservice_ids
+            .into_iter()
+            .map(|id| {
+                (
+                    Destination::Appservice(id),
+                    SendingEventType::Edu(serialized.clone()),
+                )
+            })
+            .collect::<Vec<_>>();
+        let keys = self.db.queue_requests(
+            &requests.iter().map(|(o, e)| (o, e.clone())).collect::<Vec<_>>(),
+        )?;
+        for ((destination, event_type), key) in requests.into_iter().zip(keys) {
+            self.sender
+                .send(RequestData {
+                    destination,
+                    event_type,
+                    key,
+                    requester_span: Span::current(),
+                })
+                .unwrap();
+        }
+
+        Ok(())
+    }
+
     pub(crate) async fn send_federation_request<T>(
         &self,
         destination: &ServerName,
         request: T,
     ) -> Result<T::IncomingResponse>
+    where
+        T: OutgoingRequest + Debug,
+    {
+        self.send_limited_federation_request(destination, request, None).await
+    }
+
+    /// Like [`Self::send_federation_request`], but fails with `M_TOO_LARGE`
+    /// instead of reading more than `max_body_size` bytes of the response
+    #[tracing::instrument(skip(self, request))]
+    pub(crate) async fn send_limited_federation_request<T>(
+        &self,
+        destination: &ServerName,
+        request: T,
+        max_body_size: Option<u64>,
+    ) -> Result<T::IncomingResponse>
     where
         T: OutgoingRequest + Debug,
     {
         debug!("Waiting for permit");
         let permit = self.maximum_requests.acquire().await;
         debug!("Got permit");
+        let start = Instant::now();
         let response = tokio::time::timeout(
             Duration::from_secs(2 * 60),
-            server_server::send_request(destination, request, true),
+            server_server::send_request(
+                destination,
+                request,
+                true,
+                max_body_size,
+            ),
         )
-        .await
-        .map_err(|_| {
+        .await;
+        drop(permit);
+
+        let outcome = match &response {
+            Ok(Ok(_)) => FederationOutcome::Success,
+            Ok(Err(_)) => FederationOutcome::Failure,
+            Err(_) => FederationOutcome::Timeout,
+        };
+        METRICS.record_federation_request(
+            T::METADATA.history.all_paths().last().unwrap_or("unknown"),
+            destination,
+            outcome,
+            start.elapsed(),
+        );
+
+        let response = response.map_err(|_| {
             warn!("Timeout waiting for server response");
             Error::BadServerResponse("Timeout waiting for server response")
         })?;
-        drop(permit);
 
         response
     }
@@ -713,12 +1026,84 @@ impl Service {
     }
 }
 
+/// Parses an ephemeral event queued by [`Service::send_edu_appservices`]
+fn parse_ephemeral_data(edu: &[u8]) -> Result<EphemeralData> {
+    let invalid =
+        |_| Error::bad_database("Invalid ephemeral event in appservice queue.");
+
+    let value: serde_json::Value =
+        serde_json::from_slice(edu).map_err(invalid)?;
+    if value.get("type").and_then(serde_json::Value::as_str)
+        == Some("m.presence")
+    {
+        return Ok(EphemeralData::Presence(
+            serde_json::from_value(value).map_err(invalid)?,
+        ));
+    }
+
+    match serde_json::from_value(value).map_err(invalid)? {
+        AnyEphemeralRoomEvent::Receipt(receipt) => {
+            Ok(EphemeralData::Receipt(receipt))
+        }
+        AnyEphemeralRoomEvent::Typing(typing) => {
+            Ok(EphemeralData::Typing(typing))
+        }
+        _ => Err(Error::bad_database(
+            "Unexpected ephemeral event type in appservice queue.",
+        )),
+    }
+}
+
+/// Converts a local user's read receipt into a receipt EDU for federation.
+///
+/// Only public `m.read` receipts are federated, so this returns `None` for
+/// receipts that don't contain one, e.g. `m.read.private`.
+fn receipt_edu(
+    room_id: &RoomId,
+    user_id: &UserId,
+    content: ReceiptEventContent,
+) -> Option<Edu> {
+    let (event_id, receipt) =
+        content.0.into_iter().find_map(|(event_id, mut receipts)| {
+            let receipt =
+                receipts.remove(&ReceiptType::Read)?.remove(user_id)?;
+            Some((event_id, receipt))
+        })?;
+
+    let read = BTreeMap::from([(
+        user_id.to_owned(),
+        ReceiptData {
+            data: receipt,
+            event_ids: vec![event_id],
+        },
+    )]);
+
+    Some(Edu::Receipt(ReceiptContent {
+        receipts: BTreeMap::from([(
+            room_id.to_owned(),
+            ReceiptMap {
+                read,
+            },
+        )]),
+    }))
+}
+
+/// Resolves to `destination` once `next_retry` is reached
+async fn wait_for_retry(
+    destination: Destination,
+    next_retry: Instant,
+) -> Destination {
+    tokio::time::sleep_until(next_retry.into()).await;
+    destination
+}
+
 #[tracing::instrument(skip(events))]
 async fn handle_appservice_event(
     id: &str,
     events: Vec<SendingEventType>,
 ) -> Result<()> {
     let mut pdu_jsons = Vec::new();
+    let mut ephemeral = Vec::new();
 
     for event in &events {
         match event {
@@ -737,8 +1122,8 @@ async fn handle_appservice_event(
                         .to_room_event(),
                 );
             }
-            SendingEventType::Edu(_) => {
-                // Appservices don't need EDUs (?)
+            SendingEventType::Edu(edu) => {
+                ephemeral.push(parse_ephemeral_data(edu)?);
             }
         }
     }
@@ -753,6 +1138,9 @@ async fn handle_appservice_event(
         })?,
         appservice::event::push_events::v1::Request {
             events: pdu_jsons,
+            ephemeral,
+            // Derived from the events so that retries of a transaction reuse
+            // its ID and can be deduplicated by the appservice
             txn_id: general_purpose::URL_SAFE_NO_PAD
                 .encode(calculate_hash(
                     &events
@@ -832,7 +1220,7 @@ async fn handle_push_event(
                 serde_json::from_str::<PushRulesEvent>(event.get()).ok()
             })
             .map_or_else(
-                || push::Ruleset::server_default(userid),
+                || services().globals.default_push_rules(userid),
                 |ev: PushRulesEvent| ev.content.global,
             );
 
@@ -861,26 +1249,53 @@ async fn handle_federation_event(
     server: &ServerName,
     events: Vec<SendingEventType>,
 ) -> Result<()> {
+    if !services().globals.federation_allowed_with(server) {
+        // Queued before federation with the server was denied, so they are
+        // dropped instead of being retried forever
+        debug!("Federation with server is not allowed, dropping events");
+        METRICS.record_federation_blocked(FederationDirection::Outbound);
+        return Ok(());
+    }
+
     let mut edu_jsons = Vec::new();
     let mut pdu_jsons = Vec::new();
 
     for event in &events {
         match event {
             SendingEventType::Pdu(pdu_id) => {
+                let pdu_json = services()
+                    .rooms
+                    .timeline
+                    .get_pdu_json_from_id(pdu_id)?
+                    .ok_or_else(|| {
+                        error!(pdu_id = ?pdu_id, "PDU not found");
+                        Error::bad_database(
+                            "[Normal] Event in servernamevent_datas not found \
+                             in db.",
+                        )
+                    })?;
+
+                // Don't leak events to servers the room has banned
+                let room_id = pdu_json
+                    .get("room_id")
+                    .and_then(|room_id| room_id.as_str())
+                    .and_then(|room_id| <&RoomId>::try_from(room_id).ok())
+                    .ok_or_else(|| {
+                        Error::bad_database("Invalid room id in PDU.")
+                    })?;
+                if !services()
+                    .rooms
+                    .state_accessor
+                    .server_allowed_by_acl(server, room_id)?
+                {
+                    debug!(%room_id, "Not sending PDU to server denied by ACL");
+                    continue;
+                }
+
                 // TODO: check room version and remove event_id if
                 // needed
                 pdu_jsons.push(PduEvent::convert_to_outgoing_federation_event(
-                    services()
-                        .rooms
-                        .timeline
-                        .get_pdu_json_from_id(pdu_id)?
-                        .ok_or_else(|| {
-                            error!(pdu_id = ?pdu_id, "PDU not found");
-                            Error::bad_database(
-                                "[Normal] Event in servernamevent_datas not \
-                                 found in db.",
-                            )
-                        })?,
+                    pdu_json,
                 ));
             }
             SendingEventType::Edu(edu) => {
@@ -891,6 +1306,10 @@ async fn handle_federation_event(
         }
     }
 
+    if pdu_jsons.is_empty() && edu_jsons.is_empty() {
+        return Ok(());
+    }
+
     let permit = services().sending.maximum_requests.acquire().await;
 
     let response = server_server::send_request(
@@ -913,6 +1332,7 @@ async fn handle_federation_event(
                 .into(),
         },
         false,
+        None,
     )
     .await?;
 
@@ -958,3 +1378,69 @@ async fn handle_events(
         handler_span: Span::current(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use ruma::{
+        api::federation::transactions::edu::Edu,
+        events::receipt::{
+            Receipt, ReceiptEventContent, ReceiptThread, ReceiptType,
+        },
+        owned_event_id, room_id, user_id, MilliSecondsSinceUnixEpoch,
+    };
+
+    use super::receipt_edu;
+
+    fn receipt_content(receipt_type: ReceiptType) -> ReceiptEventContent {
+        ReceiptEventContent(BTreeMap::from([(
+            owned_event_id!("$event:example.com"),
+            BTreeMap::from([(
+                receipt_type,
+                BTreeMap::from([(
+                    user_id!("@alice:example.com").to_owned(),
+                    Receipt {
+                        ts: Some(MilliSecondsSinceUnixEpoch::now()),
+                        thread: ReceiptThread::Unthreaded,
+                    },
+                )]),
+            )]),
+        )]))
+    }
+
+    #[test]
+    fn public_receipts_are_federated() {
+        let room_id = room_id!("!room:example.com");
+        let user_id = user_id!("@alice:example.com");
+
+        let Some(Edu::Receipt(edu)) =
+            receipt_edu(room_id, user_id, receipt_content(ReceiptType::Read))
+        else {
+            panic!("read receipt should be federated");
+        };
+
+        let read = &edu.receipts[room_id].read[user_id];
+        assert_eq!(read.event_ids, vec![owned_event_id!("$event:example.com")]);
+    }
+
+    #[test]
+    fn private_receipts_are_not_federated() {
+        assert!(receipt_edu(
+            room_id!("!room:example.com"),
+            user_id!("@alice:example.com"),
+            receipt_content(ReceiptType::ReadPrivate),
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn receipts_of_other_users_are_not_federated() {
+        assert!(receipt_edu(
+            room_id!("!room:example.com"),
+            user_id!("@bob:example.com"),
+            receipt_content(ReceiptType::Read),
+        )
+        .is_none());
+    }
+}
@@ -1,6 +1,6 @@
-use ruma::ServerName;
+use ruma::{OwnedServerName, ServerName};
 
-use super::{Destination, RequestKey, SendingEventType};
+use super::{Backoff, Destination, RequestKey, SendingEventType};
 use crate::Result;
 
 pub(crate) trait Data: Send + Sync {
@@ -38,4 +38,13 @@ pub(crate) trait Data: Send + Sync {
         educount: u64,
     ) -> Result<()>;
     fn get_latest_educount(&self, server_name: &ServerName) -> Result<u64>;
+    fn set_backoff(
+        &self,
+        server_name: &ServerName,
+        backoff: Backoff,
+    ) -> Result<()>;
+    fn remove_backoff(&self, server_name: &ServerName) -> Result<()>;
+    fn all_backoffs<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedServerName, Backoff)>> + 'a>;
 }
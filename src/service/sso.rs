@@ -0,0 +1,439 @@
+//! Logging in via an OpenID Connect provider
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex as StdMutex,
+    time::{Duration, Instant},
+};
+
+use reqwest::{header::AUTHORIZATION, Url};
+use ruma::{
+    api::client::error::ErrorKind,
+    events::{
+        room::message::RoomMessageEventContent, GlobalAccountDataEventType,
+    },
+    OwnedUserId, UserId,
+};
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use tracing::{info, warn};
+
+use crate::{config::SsoConfig, services, utils, Error, Result};
+
+/// How long users have to log in at the provider
+const PENDING_LOGIN_LIFETIME: Duration = Duration::from_secs(10 * 60);
+const STATE_LENGTH: usize = 32;
+/// Length of the random password of users created via SSO, which is never
+/// shown to anyone
+const PASSWORD_LENGTH: usize = 64;
+
+/// Path of the callback the provider redirects to, relative to
+/// `well_known.client`
+pub(crate) const CALLBACK_PATH: &str =
+    "/_matrix/client/unstable/login/sso/callback";
+
+/// Name of the cookie that binds a pending login to the browser that started
+/// it
+const STATE_COOKIE: &str = "grapevine_sso_state";
+
+/// A login that was redirected to the provider but hasn't come back yet
+struct PendingLogin {
+    /// Where to send the client with the login token afterwards
+    redirect_url: Url,
+    created: Instant,
+}
+
+/// Response of the token endpoint of the provider
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+/// Response of the userinfo endpoint of the provider
+#[derive(Deserialize)]
+struct UserInfo {
+    sub: String,
+    #[serde(flatten)]
+    claims: BTreeMap<String, JsonValue>,
+}
+
+pub(crate) struct Service {
+    /// Pending logins by their `state` parameter
+    pending: StdMutex<HashMap<String, PendingLogin>>,
+}
+
+impl Service {
+    pub(crate) fn new() -> Self {
+        Self {
+            pending: StdMutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the configured provider, failing with `M_UNRECOGNIZED` if
+    /// there is none
+    pub(crate) fn config() -> Result<&'static SsoConfig> {
+        services().globals.config.sso.as_ref().ok_or(Error::BadRequest(
+            ErrorKind::Unrecognized,
+            "SSO login is not configured.",
+        ))
+    }
+
+    /// Starts a login, returning the URL of the provider to redirect the
+    /// client to and the `Set-Cookie` header for the browser.
+    ///
+    /// The cookie holds the `state` parameter, so that only the browser that
+    /// started the login can finish it. Otherwise, a user could be sent to the
+    /// callback with an attacker's code and be logged in to their account.
+    ///
+    /// Fails if `redirect_url` isn't allowed by `allowed_redirect_urls`.
+    pub(crate) fn start(&self, redirect_url: &str) -> Result<(String, String)> {
+        let config = Self::config()?;
+
+        let redirect_url = Url::parse(redirect_url)
+            .ok()
+            .filter(|url| {
+                is_redirect_allowed(&config.allowed_redirect_urls, url)
+            })
+            .ok_or(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Redirect URL is not allowed.",
+            ))?;
+
+        let mut url =
+            Url::parse(&config.authorization_endpoint).map_err(|_| {
+                Error::bad_config("Invalid sso.authorization_endpoint.")
+            })?;
+
+        let state = utils::random_string(STATE_LENGTH);
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &config.client_id)
+            .append_pair("redirect_uri", &callback_url()?)
+            .append_pair("scope", &config.scopes.join(" "))
+            .append_pair("state", &state);
+
+        // The provider redirects to the callback with a top-level navigation,
+        // which SameSite=Lax cookies are sent with
+        let cookie = format!(
+            "{STATE_COOKIE}={state}; Path={CALLBACK_PATH}; Max-Age={}; \
+             HttpOnly; Secure; SameSite=Lax",
+            PENDING_LOGIN_LIFETIME.as_secs()
+        );
+
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, login| {
+            login.created.elapsed() < PENDING_LOGIN_LIFETIME
+        });
+        pending.insert(
+            state,
+            PendingLogin {
+                redirect_url,
+                created: Instant::now(),
+            },
+        );
+
+        Ok((url.into(), cookie))
+    }
+
+    /// Finishes a login after the provider redirected back with `code`,
+    /// returning the URL to redirect the client to with a login token.
+    ///
+    /// `cookies` are the `Cookie` headers of the request, which must contain
+    /// the state cookie set by [`Self::start`].
+    pub(crate) async fn finish(
+        &self,
+        code: &str,
+        state: &str,
+        cookies: &[&str],
+    ) -> Result<String> {
+        let config = Self::config()?;
+
+        if cookies.iter().find_map(|cookie| state_from_cookie(cookie))
+            != Some(state)
+        {
+            warn!("SSO callback without matching state cookie");
+            return Err(Error::BadRequest(
+                ErrorKind::forbidden(),
+                "SSO login was started in another browser.",
+            ));
+        }
+
+        let Some(PendingLogin {
+            mut redirect_url,
+            ..
+        }) =
+            self.pending.lock().unwrap().remove(state).filter(|login| {
+                login.created.elapsed() < PENDING_LOGIN_LIFETIME
+            })
+        else {
+            return Err(Error::BadRequest(
+                ErrorKind::forbidden(),
+                "Unknown or expired SSO login.",
+            ));
+        };
+
+        let user_info = fetch_user_info(config, code).await?;
+
+        let user_id = if let Some(user_id) =
+            services().users.sso_subject_user(&config.id, &user_info.sub)?
+        {
+            if services().users.is_deactivated(&user_id)? {
+                return Err(Error::BadRequest(
+                    ErrorKind::UserDeactivated,
+                    "The user has been deactivated",
+                ));
+            }
+            user_id
+        } else if config.allow_registration {
+            let user_id = provision_user(config, &user_info).await?;
+            services().users.set_sso_subject_user(
+                &config.id,
+                &user_info.sub,
+                &user_id,
+            )?;
+            user_id
+        } else {
+            return Err(Error::BadRequest(
+                ErrorKind::forbidden(),
+                "There is no account for this identity.",
+            ));
+        };
+
+        let token = services().users.create_login_token(&user_id);
+        redirect_url.query_pairs_mut().append_pair("loginToken", &token);
+
+        info!(%user_id, "User logged in via SSO");
+
+        Ok(redirect_url.into())
+    }
+}
+
+/// Returns the URL the provider redirects back to
+fn callback_url() -> Result<String> {
+    let Some(client_url) = &services().globals.config.well_known.client else {
+        return Err(Error::bad_config(
+            "SSO login requires well_known.client to be set.",
+        ));
+    };
+
+    Ok(format!("{}{CALLBACK_PATH}", client_url.trim_end_matches('/')))
+}
+
+/// Returns the value of the state cookie in a `Cookie` header
+fn state_from_cookie(cookie: &str) -> Option<&str> {
+    cookie.split(';').find_map(|pair| {
+        let (name, value) = pair.trim().split_once('=')?;
+        (name == STATE_COOKIE).then_some(value)
+    })
+}
+
+/// Checks that `url` has the same origin as one of the allowed redirect URLs
+/// and that its path is the allowed path or below it
+fn is_redirect_allowed(allowed_urls: &[String], url: &Url) -> bool {
+    allowed_urls.iter().any(|allowed| {
+        let Ok(allowed) = Url::parse(allowed) else {
+            warn!(url = %allowed, "Invalid URL in sso.allowed_redirect_urls");
+            return false;
+        };
+
+        // Origins of custom schemes used by mobile clients are opaque and
+        // never equal, so they are compared by hand
+        allowed.scheme() == url.scheme()
+            && allowed.host_str() == url.host_str()
+            && allowed.port_or_known_default() == url.port_or_known_default()
+            && is_path_below(url.path(), allowed.path())
+    })
+}
+
+/// Checks whether `path` is `base` or below it, comparing whole segments so
+/// that `/app` doesn't allow `/application`
+fn is_path_below(path: &str, base: &str) -> bool {
+    let Some(rest) = path.strip_prefix(base) else {
+        return false;
+    };
+
+    base.ends_with('/') || rest.is_empty() || rest.starts_with('/')
+}
+
+/// Exchanges the authorization code for an access token and uses it to fetch
+/// the claims about the user
+async fn fetch_user_info(config: &SsoConfig, code: &str) -> Result<UserInfo> {
+    let redirect_uri = callback_url()?;
+    let response = services()
+        .globals
+        .default_client()
+        .post(&config.token_endpoint)
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri.as_str()),
+            ("client_id", config.client_id.as_str()),
+            ("client_secret", config.client_secret.as_str()),
+        ])
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        warn!(status = %response.status(), "SSO token request failed");
+        return Err(Error::BadServerResponse(
+            "Identity provider rejected the authorization code.",
+        ));
+    }
+
+    let token: TokenResponse = serde_json::from_slice(&response.bytes().await?)
+        .map_err(|_| {
+            Error::BadServerResponse(
+                "Invalid token response from identity provider.",
+            )
+        })?;
+
+    let response = services()
+        .globals
+        .default_client()
+        .get(&config.userinfo_endpoint)
+        .header(AUTHORIZATION, format!("Bearer {}", token.access_token))
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        warn!(status = %response.status(), "SSO userinfo request failed");
+        return Err(Error::BadServerResponse(
+            "Identity provider did not return user info.",
+        ));
+    }
+
+    serde_json::from_slice(&response.bytes().await?).map_err(|_| {
+        Error::BadServerResponse(
+            "Invalid userinfo response from identity provider.",
+        )
+    })
+}
+
+/// Creates a user for a subject that logged in for the first time.
+///
+/// The localpart is taken from the configured claim and must not be taken
+/// already, so that a provider can't be used to log in to accounts that
+/// weren't created via SSO.
+async fn provision_user(
+    config: &SsoConfig,
+    user_info: &UserInfo,
+) -> Result<OwnedUserId> {
+    let user_id = user_info
+        .claims
+        .get(&config.localpart_claim)
+        .and_then(JsonValue::as_str)
+        .and_then(|localpart| {
+            UserId::parse_with_server_name(
+                localpart.to_lowercase(),
+                services().globals.server_name(),
+            )
+            .ok()
+        })
+        .filter(|user_id| {
+            !user_id.is_historical()
+                && user_id.server_name() == services().globals.server_name()
+        })
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidUsername,
+            "Identity provider returned no valid username.",
+        ))?;
+
+    if services().users.exists(&user_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::UserInUse,
+            "Desired user ID is already taken.",
+        ));
+    }
+    if services().appservice.is_exclusive_user_id(&user_id).await {
+        return Err(Error::BadRequest(
+            ErrorKind::Exclusive,
+            "User id reserved by appservice.",
+        ));
+    }
+
+    // Users without a password would be considered deactivated
+    services()
+        .users
+        .create(&user_id, Some(&utils::random_string(PASSWORD_LENGTH)))?;
+
+    let displayname = user_info
+        .claims
+        .get("name")
+        .and_then(JsonValue::as_str)
+        .map_or_else(|| user_id.localpart().to_owned(), ToOwned::to_owned);
+    services().users.set_displayname(&user_id, Some(displayname))?;
+
+    services().account_data.update(
+        None,
+        &user_id,
+        GlobalAccountDataEventType::PushRules.to_string().into(),
+        &serde_json::to_value(ruma::events::push_rules::PushRulesEvent {
+            content: ruma::events::push_rules::PushRulesEventContent {
+                global: services().globals.default_push_rules(&user_id),
+            },
+        })
+        .expect("to json always works"),
+    )?;
+
+    info!(%user_id, "New user registered via SSO");
+    services().admin.send_message(RoomMessageEventContent::notice_plain(
+        format!("New user {user_id} registered on this server."),
+    ));
+
+    Ok(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+
+    use super::{is_redirect_allowed, state_from_cookie};
+
+    fn allowed(url: &str) -> bool {
+        is_redirect_allowed(
+            &[
+                "https://app.example.com/login".to_owned(),
+                "https://other.example.com/".to_owned(),
+                "im.example.app:/callback".to_owned(),
+            ],
+            &Url::parse(url).unwrap(),
+        )
+    }
+
+    #[test]
+    fn redirect_below_allowed_path_is_allowed() {
+        assert!(allowed("https://app.example.com/login"));
+        assert!(allowed("https://app.example.com/login/done?x=1"));
+        assert!(allowed("https://other.example.com/anything"));
+        assert!(allowed("im.example.app:/callback"));
+    }
+
+    #[test]
+    fn redirect_path_is_compared_by_segment() {
+        assert!(!allowed("https://app.example.com/loginevil"));
+        assert!(!allowed("https://app.example.com/login-other/done"));
+        assert!(!allowed("https://app.example.com/"));
+        assert!(!allowed("https://app.example.com/login/../admin"));
+        assert!(!allowed("im.example.app:/callbackevil"));
+    }
+
+    #[test]
+    fn redirect_to_other_origin_is_denied() {
+        assert!(!allowed("http://app.example.com/login"));
+        assert!(!allowed("https://app.example.com:8443/login"));
+        assert!(!allowed("https://evil.example.com/login"));
+        assert!(!allowed("https://app.example.com.evil.com/login"));
+        assert!(!allowed("other.example.app:/callback"));
+    }
+
+    #[test]
+    fn state_is_read_from_cookie() {
+        assert_eq!(state_from_cookie("grapevine_sso_state=abc"), Some("abc"));
+        assert_eq!(
+            state_from_cookie("a=b; grapevine_sso_state=abc; c=d"),
+            Some("abc")
+        );
+        assert_eq!(state_from_cookie("a=b; not_grapevine_sso_state=abc"), None);
+        assert_eq!(state_from_cookie(""), None);
+    }
+}
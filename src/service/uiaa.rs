@@ -4,11 +4,14 @@ pub(crate) use data::Data;
 use ruma::{
     api::client::{
         error::ErrorKind,
-        uiaa::{AuthData, AuthType, Password, UiaaInfo, UserIdentifier},
+        uiaa::{
+            AuthData, AuthType, Password, ReCaptcha, UiaaInfo, UserIdentifier,
+        },
     },
     CanonicalJsonValue, DeviceId, UserId,
 };
-use tracing::error;
+use serde::Deserialize;
+use tracing::{error, warn};
 
 use crate::{
     api::client_server::SESSION_ID_LENGTH, services, utils, Error, Result,
@@ -18,6 +21,12 @@ pub(crate) struct Service {
     pub(crate) db: &'static dyn Data,
 }
 
+/// Response of the verification endpoint of a CAPTCHA provider
+#[derive(Deserialize)]
+struct CaptchaVerification {
+    success: bool,
+}
+
 impl Service {
     /// Creates a new Uiaa session. Make sure the session token is unique.
     pub(crate) fn create(
@@ -43,7 +52,7 @@ impl Service {
         )
     }
 
-    pub(crate) fn try_auth(
+    pub(crate) async fn try_auth(
         &self,
         user_id: &UserId,
         device_id: &DeviceId,
@@ -115,6 +124,21 @@ impl Service {
                     return Ok((false, uiaainfo));
                 }
             }
+            AuthData::ReCaptcha(ReCaptcha {
+                response,
+                ..
+            }) => {
+                if verify_captcha(response).await? {
+                    uiaainfo.completed.push(AuthType::ReCaptcha);
+                } else {
+                    uiaainfo.auth_error =
+                        Some(ruma::api::client::error::StandardErrorBody {
+                            kind: ErrorKind::forbidden(),
+                            message: "Invalid CAPTCHA response.".to_owned(),
+                        });
+                    return Ok((false, uiaainfo));
+                }
+            }
             AuthData::Dummy(_) => {
                 uiaainfo.completed.push(AuthType::Dummy);
             }
@@ -162,3 +186,35 @@ impl Service {
         self.db.get_uiaa_request(user_id, device_id, session)
     }
 }
+
+/// Checks a CAPTCHA response with the configured provider
+async fn verify_captcha(response: &str) -> Result<bool> {
+    let Some(config) = &services().globals.config.captcha else {
+        return Ok(false);
+    };
+
+    let verification = services()
+        .globals
+        .default_client()
+        .post(config.provider.verify_url())
+        .form(&[("secret", config.secret_key.as_str()), ("response", response)])
+        .send()
+        .await?;
+
+    if !verification.status().is_success() {
+        warn!(
+            status = %verification.status(),
+            "CAPTCHA verification request failed",
+        );
+        return Err(Error::BadServerResponse(
+            "Failed to verify CAPTCHA response.",
+        ));
+    }
+
+    let verification: CaptchaVerification =
+        serde_json::from_slice(&verification.bytes().await?).map_err(|_| {
+            Error::BadServerResponse("Invalid response from CAPTCHA provider.")
+        })?;
+
+    Ok(verification.success)
+}
@@ -0,0 +1,651 @@
+use std::{
+    collections::BTreeMap,
+    error::Error as StdError,
+    io::Cursor,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, Instant},
+};
+
+use futures_util::FutureExt;
+use hyper::service::Service as _;
+use hyper_util::{
+    client::legacy::connect::dns::GaiResolver, service::TowerToHyperService,
+};
+use image::io::Reader as ImageReader;
+use ipnet::IpNet;
+use lru_cache::LruCache;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    header::CONTENT_TYPE,
+    redirect, Url,
+};
+use ruma::api::client::{
+    error::ErrorKind, media::get_content_thumbnail::v3::Method,
+};
+use serde_json::Value as JsonValue;
+use tracing::{debug, warn, Instrument};
+
+use crate::{
+    api::client_server::MXC_LENGTH,
+    config::UrlPreviewConfig,
+    observability::{FoundIn, Lookup, METRICS},
+    services, utils, Config, Error, Result,
+};
+
+/// OpenGraph properties of a URL, as returned to clients
+pub(crate) type UrlPreview = BTreeMap<String, JsonValue>;
+
+/// Maximum number of redirects followed when fetching a URL
+const MAX_REDIRECTS: usize = 10;
+
+/// IP ranges that are never connected to unless explicitly allowed
+///
+/// Cargo-culted from Synapse.
+const DENIED_IP_RANGES: &[&str] = &[
+    // Keep sorted
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "2001:db8::/32",
+    "203.0.113.0/24",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// Matches `<meta>` tags
+static META_TAG: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?is)<meta\s[^>]*>").expect("regex should be valid")
+});
+
+/// Matches quoted attributes of HTML tags
+static ATTRIBUTE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?is)([a-z:_-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#)
+        .expect("regex should be valid")
+});
+
+/// Matches the `<title>` tag
+static TITLE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?is)<title[^>]*>(.*?)</title>")
+        .expect("regex should be valid")
+});
+
+/// Decides which IP addresses may be connected to
+pub(crate) struct IpFilter {
+    /// Ranges that are allowed even if they are in `deny`
+    allow: Vec<IpNet>,
+    /// Ranges that are never connected to
+    deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    /// Creates a filter denying [`DENIED_IP_RANGES`] and the ranges from the
+    /// config
+    fn new(config: &UrlPreviewConfig) -> Self {
+        let mut deny = DENIED_IP_RANGES
+            .iter()
+            .map(|range| {
+                range.parse().expect("hardcoded range should be valid")
+            })
+            .collect::<Vec<IpNet>>();
+        deny.extend_from_slice(&config.ip_range_denylist);
+
+        Self {
+            allow: config.ip_range_allowlist.clone(),
+            deny,
+        }
+    }
+
+    /// Whether `ip` may be connected to
+    pub(crate) fn is_allowed(&self, ip: IpAddr) -> bool {
+        // Otherwise IPv4-mapped addresses would bypass the IPv4 ranges
+        let ip = match ip {
+            IpAddr::V6(ip) => {
+                ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4)
+            }
+            IpAddr::V4(_) => ip,
+        };
+
+        self.allow.iter().any(|range| range.contains(&ip))
+            || !self.deny.iter().any(|range| range.contains(&ip))
+    }
+
+    /// Whether `url` may be requested
+    ///
+    /// Domain names are checked when they are resolved by [`Resolver`].
+    fn is_url_allowed(&self, url: &Url) -> bool {
+        if !matches!(url.scheme(), "http" | "https") {
+            return false;
+        }
+
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+
+        host.trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .map_or(true, |ip| self.is_allowed(ip))
+    }
+
+    /// Whether a redirect to `url` may be followed after `previous`
+    /// redirects
+    fn check_redirect(
+        &self,
+        previous: usize,
+        url: &Url,
+    ) -> Result<(), &'static str> {
+        if previous >= MAX_REDIRECTS {
+            Err("too many redirects")
+        } else if !self.is_url_allowed(url) {
+            Err("refusing to follow redirect to denied URL")
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Resolves domain names, dropping addresses denied by an [`IpFilter`]
+struct Resolver {
+    /// The resolver doing the actual work
+    inner: GaiResolver,
+    /// The addresses to drop
+    filter: Arc<IpFilter>,
+}
+
+impl Resolve for Resolver {
+    #[tracing::instrument(skip(self))]
+    fn resolve(&self, name: Name) -> Resolving {
+        // This should never fail because reqwest's type is a wrapper around
+        // hyper-utils' type
+        let name = name.as_str().parse().expect("name should be valid");
+        let filter = Arc::clone(&self.filter);
+
+        Box::pin(
+            TowerToHyperService::new(self.inner.clone())
+                .call(name)
+                .map(move |result| {
+                    let addrs = result
+                        .map_err(|err| -> Box<dyn StdError + Send + Sync> {
+                            Box::new(err)
+                        })?
+                        .filter(|addr| filter.is_allowed(addr.ip()))
+                        .collect::<Vec<SocketAddr>>();
+
+                    if addrs.is_empty() {
+                        return Err("refusing to connect to a denied IP \
+                                    address"
+                            .into());
+                    }
+
+                    let addrs: Addrs = Box::new(addrs.into_iter());
+                    Ok(addrs)
+                })
+                .in_current_span(),
+        )
+    }
+}
+
+pub(crate) struct Service {
+    /// Client that refuses to connect to denied IP addresses
+    ///
+    /// The configured proxy is not used, because it would resolve names
+    /// without the filter.
+    client: reqwest::Client,
+
+    /// Filter used by `client`
+    filter: Arc<IpFilter>,
+
+    /// Previews by URL and when they were fetched
+    pub(crate) cache: StdMutex<LruCache<String, (Instant, Arc<UrlPreview>)>>,
+}
+
+impl Service {
+    pub(crate) fn build(config: &Config) -> Result<Self> {
+        let filter = Arc::new(IpFilter::new(&config.url_preview));
+
+        let redirect_filter = Arc::clone(&filter);
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .timeout(Duration::from_secs(30))
+            // Proxies from the environment would resolve names without the
+            // filter too
+            .no_proxy()
+            .dns_resolver(Arc::new(Resolver {
+                inner: GaiResolver::new(),
+                filter: Arc::clone(&filter),
+            }))
+            .redirect(redirect::Policy::custom(move |attempt| {
+                match redirect_filter
+                    .check_redirect(attempt.previous().len(), attempt.url())
+                {
+                    Ok(()) => attempt.follow(),
+                    Err(error) => attempt.error(error),
+                }
+            }))
+            .build()?;
+
+        Ok(Self {
+            client,
+            filter,
+            cache: StdMutex::new(LruCache::new(
+                config.cache_capacity(config.cache.url_preview, 1000),
+            )),
+        })
+    }
+
+    /// Returns the preview of `url`, fetching it if it isn't cached.
+    #[tracing::instrument(skip(self, url), fields(%url))]
+    pub(crate) async fn get(&self, url: Url) -> Result<Arc<UrlPreview>> {
+        let config = &services().globals.config.url_preview;
+        if !config.enable {
+            return Err(Error::BadRequest(
+                ErrorKind::forbidden(),
+                "URL previews are disabled.",
+            ));
+        }
+
+        if !self.filter.is_url_allowed(&url) {
+            return Err(Error::BadRequest(
+                ErrorKind::forbidden(),
+                "Previewing this URL is not allowed.",
+            ));
+        }
+
+        let cached = {
+            let mut cache = self.cache.lock().unwrap();
+            match cache.get_mut(url.as_str()) {
+                Some((fetched_at, preview))
+                    if fetched_at.elapsed() < config.cache_duration =>
+                {
+                    Some(Arc::clone(preview))
+                }
+                Some(_) => {
+                    cache.remove(url.as_str());
+                    None
+                }
+                None => None,
+            }
+        };
+        if let Some(preview) = cached {
+            METRICS.record_lookup(Lookup::UrlPreview, FoundIn::Cache);
+            return Ok(preview);
+        }
+
+        let preview = match self.fetch(url.clone()).await {
+            Ok(preview) => Arc::new(preview),
+            Err(error) => {
+                METRICS.record_lookup(Lookup::UrlPreview, FoundIn::Nothing);
+                return Err(error);
+            }
+        };
+        METRICS.record_lookup(Lookup::UrlPreview, FoundIn::Remote);
+
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(url.into(), (Instant::now(), Arc::clone(&preview)));
+
+        Ok(preview)
+    }
+
+    /// Downloads `url` and builds a preview from its OpenGraph properties.
+    async fn fetch(&self, url: Url) -> Result<UrlPreview> {
+        let (final_url, content_type, body) = self.download(url).await?;
+
+        if content_type.as_deref().is_some_and(|x| x.starts_with("image/")) {
+            let mut preview = UrlPreview::new();
+            self.store_image(&mut preview, content_type, body).await?;
+            return Ok(preview);
+        }
+
+        if !content_type.as_deref().is_some_and(|x| x.starts_with("text/html"))
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::Unknown,
+                "URL does not point to an HTML page or image.",
+            ));
+        }
+
+        let html = String::from_utf8_lossy(&body);
+        let mut preview = parse_html(&html);
+
+        let image_url = preview
+            .remove("og:image")
+            .and_then(|x| x.as_str().and_then(|x| final_url.join(x).ok()));
+        if let Some(image_url) = image_url {
+            match self.download(image_url).await {
+                Ok((_, content_type, body))
+                    if content_type
+                        .as_deref()
+                        .is_some_and(|x| x.starts_with("image/")) =>
+                {
+                    self.store_image(&mut preview, content_type, body).await?;
+                }
+                Ok(_) => debug!("og:image is not an image"),
+                Err(error) => warn!(%error, "Failed to download og:image"),
+            }
+        }
+
+        Ok(preview)
+    }
+
+    /// Downloads `url`, returning the URL after redirects, the content type
+    /// and the body.
+    async fn download(
+        &self,
+        url: Url,
+    ) -> Result<(Url, Option<String>, Vec<u8>)> {
+        let max_size = services().globals.config.url_preview.max_response_size;
+
+        let mut response =
+            self.client.get(url).send().await.map_err(|error| {
+                debug!(%error, "Failed to fetch URL");
+                Error::BadServerResponse("Failed to fetch URL.")
+            })?;
+
+        if !response.status().is_success() {
+            return Err(Error::BadServerResponse(
+                "URL returned an error status.",
+            ));
+        }
+
+        let too_large =
+            || Error::BadRequest(ErrorKind::TooLarge, "Content is too large.");
+        if response
+            .content_length()
+            .is_some_and(|x| usize::try_from(x).map_or(true, |x| x > max_size))
+        {
+            return Err(too_large());
+        }
+
+        let final_url = response.url().clone();
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|x| x.to_str().ok())
+            .map(ToOwned::to_owned);
+
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            body.extend_from_slice(&chunk);
+            if body.len() > max_size {
+                return Err(too_large());
+            }
+        }
+
+        Ok((final_url, content_type, body))
+    }
+
+    /// Stores an image as local media and adds its properties to `preview`.
+    // Allowed because this function uses `services()`
+    #[allow(clippy::unused_self)]
+    async fn store_image(
+        &self,
+        preview: &mut UrlPreview,
+        content_type: Option<String>,
+        body: Vec<u8>,
+    ) -> Result<()> {
+        let mxc = format!(
+            "mxc://{}/{}",
+            services().globals.server_name(),
+            utils::random_string(MXC_LENGTH)
+        );
+
+        services()
+            .media
+            .create(mxc.clone(), None, content_type.as_deref(), &body)
+            .await?;
+
+        // Generate the thumbnail clients are most likely to ask for right away
+        if let Err(error) = services()
+            .media
+            .get_thumbnail(mxc.clone(), 800, 600, &Method::Scale)
+            .await
+        {
+            warn!(%error, "Failed to generate thumbnail of preview image");
+        }
+
+        if let Ok((width, height)) = ImageReader::new(Cursor::new(&body))
+            .with_guessed_format()
+            .map_err(image::ImageError::IoError)
+            .and_then(ImageReader::into_dimensions)
+        {
+            preview.insert("og:image:width".to_owned(), width.into());
+            preview.insert("og:image:height".to_owned(), height.into());
+        }
+        if let Some(content_type) = content_type {
+            preview.insert("og:image:type".to_owned(), content_type.into());
+        }
+        preview.insert("matrix:image:size".to_owned(), body.len().into());
+        preview.insert("og:image".to_owned(), mxc.into());
+
+        Ok(())
+    }
+}
+
+/// Extracts the OpenGraph properties from an HTML page
+///
+/// Falls back to `<title>` and `<meta name="description">` if the page has no
+/// title or description properties.
+fn parse_html(html: &str) -> UrlPreview {
+    let mut preview = UrlPreview::new();
+    let mut description = None;
+
+    for tag in META_TAG.find_iter(html) {
+        let mut key = None;
+        let mut content = None;
+
+        for attribute in ATTRIBUTE.captures_iter(tag.as_str()) {
+            let value = attribute
+                .get(2)
+                .or_else(|| attribute.get(3))
+                .map(|x| html_escape::decode_html_entities(x.as_str()));
+            match attribute[1].to_ascii_lowercase().as_str() {
+                "property" | "name" => key = value,
+                "content" => content = value,
+                _ => {}
+            }
+        }
+
+        let (Some(key), Some(content)) = (key, content) else {
+            continue;
+        };
+
+        if key.starts_with("og:") {
+            preview
+                .entry(key.into_owned())
+                .or_insert_with(|| content.into_owned().into());
+        } else if key.eq_ignore_ascii_case("description") {
+            description.get_or_insert_with(|| content.into_owned());
+        }
+    }
+
+    if !preview.contains_key("og:title") {
+        if let Some(title) = TITLE.captures(html).and_then(|x| x.get(1)) {
+            let title = html_escape::decode_html_entities(title.as_str());
+            preview.insert("og:title".to_owned(), title.trim().into());
+        }
+    }
+
+    if let Some(description) = description {
+        preview
+            .entry("og:description".to_owned())
+            .or_insert_with(|| description.into());
+    }
+
+    preview
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use reqwest::Url;
+    use serde_json::json;
+
+    use super::{parse_html, IpFilter, MAX_REDIRECTS};
+    use crate::config::UrlPreviewConfig;
+
+    fn ip_filter(deny: &[&str], allow: &[&str]) -> IpFilter {
+        IpFilter::new(&UrlPreviewConfig {
+            ip_range_denylist: deny
+                .iter()
+                .map(|x| x.parse().unwrap())
+                .collect(),
+            ip_range_allowlist: allow
+                .iter()
+                .map(|x| x.parse().unwrap())
+                .collect(),
+            ..UrlPreviewConfig::default()
+        })
+    }
+
+    fn is_allowed(filter: &IpFilter, ip: &str) -> bool {
+        filter.is_allowed(ip.parse::<IpAddr>().unwrap())
+    }
+
+    fn is_url_allowed(filter: &IpFilter, url: &str) -> bool {
+        filter.is_url_allowed(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn internal_addresses_are_denied() {
+        let filter = ip_filter(&[], &[]);
+
+        for ip in [
+            "127.0.0.1",
+            "::1",
+            "169.254.169.254",
+            "fe80::1",
+            "10.1.2.3",
+            "192.168.1.1",
+            "fd00::1",
+            "0.0.0.0",
+            "::",
+        ] {
+            assert!(!is_allowed(&filter, ip), "{ip} should be denied");
+        }
+        for ip in ["93.184.216.34", "2606:2800:220:1::1"] {
+            assert!(is_allowed(&filter, ip), "{ip} should be allowed");
+        }
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_use_ipv4_ranges() {
+        let filter = ip_filter(&[], &[]);
+
+        assert!(!is_allowed(&filter, "::ffff:127.0.0.1"));
+        assert!(!is_allowed(&filter, "::ffff:10.0.0.1"));
+        assert!(is_allowed(&filter, "::ffff:93.184.216.34"));
+    }
+
+    #[test]
+    fn configured_ranges_are_applied() {
+        let filter = ip_filter(&["93.184.216.0/24"], &["10.1.0.0/16"]);
+
+        assert!(!is_allowed(&filter, "93.184.216.34"));
+        assert!(!is_allowed(&filter, "::ffff:93.184.216.34"));
+        // The allowlist overrides both the built-in and the configured ranges
+        assert!(is_allowed(&filter, "10.1.2.3"));
+        assert!(is_allowed(&filter, "::ffff:10.1.2.3"));
+        assert!(!is_allowed(&filter, "10.2.0.1"));
+
+        let filter = ip_filter(&["93.184.216.0/24"], &["93.184.216.34/32"]);
+        assert!(is_allowed(&filter, "93.184.216.34"));
+        assert!(!is_allowed(&filter, "93.184.216.35"));
+    }
+
+    #[test]
+    fn only_http_urls_to_allowed_addresses_are_allowed() {
+        let filter = ip_filter(&[], &[]);
+
+        assert!(is_url_allowed(&filter, "https://example.com/page"));
+        assert!(is_url_allowed(&filter, "http://93.184.216.34/"));
+        assert!(is_url_allowed(&filter, "http://[2606:2800:220:1::1]/"));
+
+        for url in [
+            "ftp://example.com/file",
+            "file:///etc/passwd",
+            "gopher://example.com/",
+            "data:text/html,<title>x</title>",
+            "http://127.0.0.1:8008/_matrix",
+            "http://[::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "http://169.254.169.254/latest/meta-data/",
+        ] {
+            assert!(!is_url_allowed(&filter, url), "{url} should be denied");
+        }
+    }
+
+    #[test]
+    fn redirects_are_checked() {
+        let filter = ip_filter(&[], &[]);
+        let url = |url| Url::parse(url).unwrap();
+
+        assert!(filter.check_redirect(0, &url("https://example.com/")).is_ok());
+        assert!(filter
+            .check_redirect(MAX_REDIRECTS - 1, &url("https://example.com/"))
+            .is_ok());
+        assert!(filter
+            .check_redirect(MAX_REDIRECTS, &url("https://example.com/"))
+            .is_err());
+        assert!(filter
+            .check_redirect(0, &url("http://169.254.169.254/latest/"))
+            .is_err());
+        assert!(filter.check_redirect(0, &url("http://[::1]:8008/")).is_err());
+        assert!(filter.check_redirect(0, &url("file:///etc/passwd")).is_err());
+    }
+
+    #[test]
+    fn opengraph_properties_are_parsed() {
+        let preview = parse_html(
+            r#"<html><head>
+            <title>Page title</title>
+            <meta property="og:title" content="OpenGraph &amp; title">
+            <meta content='OpenGraph description' property='og:description'>
+            <meta property="og:title" content="Second title">
+            <meta name="description" content="Meta description">
+            <meta property="og:image" content="/image.png">
+            </head></html>"#,
+        );
+
+        assert_eq!(preview["og:title"], json!("OpenGraph & title"));
+        assert_eq!(preview["og:description"], json!("OpenGraph description"));
+        assert_eq!(preview["og:image"], json!("/image.png"));
+        assert_eq!(preview.len(), 3);
+    }
+
+    #[test]
+    fn title_and_description_fall_back_to_html() {
+        let preview = parse_html(
+            r#"<html><head>
+            <TITLE>
+                Page &lt;title&gt;
+            </TITLE>
+            <META NAME="Description" CONTENT="Meta description">
+            </head></html>"#,
+        );
+
+        assert_eq!(preview["og:title"], json!("Page <title>"));
+        assert_eq!(preview["og:description"], json!("Meta description"));
+        assert_eq!(preview.len(), 2);
+
+        assert!(parse_html("<html><body>No metadata</body></html>").is_empty());
+    }
+}
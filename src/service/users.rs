@@ -1,30 +1,253 @@
 mod data;
 use std::{
-    collections::{BTreeMap, BTreeSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     mem,
+    net::IpAddr,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 pub(crate) use data::Data;
+use lru_cache::LruCache;
 use ruma::{
-    api::client::{
-        device::Device,
-        filter::FilterDefinition,
-        sync::sync_events::{
-            self,
-            v4::{ExtensionsConfig, SyncRequestList},
+    api::{
+        client::{
+            dehydrated_device::DehydratedDeviceData,
+            device::Device,
+            error::ErrorKind,
+            filter::FilterDefinition,
+            sync::sync_events::{
+                self,
+                v4::{ExtensionsConfig, SyncRequestList},
+            },
+        },
+        federation::transactions::edu::{
+            DeviceListUpdateContent, Edu, SigningKeyUpdateContent,
         },
     },
     encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
-    events::AnyToDeviceEvent,
+    events::{AnyToDeviceEvent, StateEventType},
     serde::Raw,
-    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, OwnedDeviceId, OwnedDeviceKeyId,
-    OwnedMxcUri, OwnedRoomId, OwnedUserId, UInt, UserId,
+    thirdparty::{Medium, ThirdPartyIdentifier},
+    ClientSecret, DeviceId, DeviceKeyAlgorithm, DeviceKeyId,
+    MilliSecondsSinceUnixEpoch, OwnedClientSecret, OwnedDeviceId,
+    OwnedDeviceKeyId, OwnedMxcUri, OwnedRoomId, OwnedServerName,
+    OwnedSessionId, OwnedUserId, RoomId, SessionId, UInt, UserId,
+};
+
+use tracing::{debug, warn};
+
+use crate::{
+    observability::{EvictionReason, FoundIn, Lookup, METRICS},
+    services, utils, Error, Result,
 };
 
-use crate::{services, Error, Result};
+/// How long the device list of a remote user is used without fetching it
+/// again, in case an `m.device_list_update` EDU was missed
+const REMOTE_DEVICE_LIST_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// How often the last seen timestamp of a device is written to the database
+/// if it keeps being used from the same IP address
+const LAST_SEEN_UPDATE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How long a third party identifier validation session can be used
+const THREEPID_SESSION_LIFETIME: Duration = Duration::from_secs(60 * 60);
+const THREEPID_SESSION_ID_LENGTH: usize = 32;
+const THREEPID_TOKEN_LENGTH: usize = 8;
+/// How many wrong tokens can be submitted before a third party identifier
+/// validation session is ended
+const THREEPID_MAX_FAILED_ATTEMPTS: u8 = 5;
+
+/// How long a login token issued after an SSO login can be used
+const LOGIN_TOKEN_LIFETIME: Duration = Duration::from_secs(2 * 60);
+const LOGIN_TOKEN_LENGTH: usize = 32;
+
+/// A pending validation of a third party identifier
+pub(crate) struct ThreepidSession {
+    client_secret: OwnedClientSecret,
+    medium: Medium,
+    address: String,
+    /// The token sent to the address
+    token: String,
+    /// The highest `send_attempt` of the client so far
+    send_attempt: UInt,
+    /// How many wrong tokens were submitted
+    failed_attempts: u8,
+    /// When the token was submitted, if it was
+    validated_at: Option<MilliSecondsSinceUnixEpoch>,
+    created: Instant,
+}
+
+/// The device list of a user on another server
+#[derive(Default)]
+pub(crate) struct RemoteDeviceList {
+    /// The `stream_id` of the last `m.device_list_update` EDU received for the
+    /// user
+    stream_id: Option<UInt>,
+    /// The keys as last fetched over federation, if they are still current
+    keys: Option<RemoteDeviceKeys>,
+    /// The [`RemoteDeviceLists::generation`] at which the device list last
+    /// changed
+    changed: u64,
+}
+
+/// The cached device lists of users on other servers
+pub(crate) struct RemoteDeviceLists {
+    lists: LruCache<OwnedUserId, RemoteDeviceList>,
+    /// Incremented whenever a device list changes, so that key queries can
+    /// tell whether the keys they fetched are still current
+    generation: u64,
+    /// The highest [`RemoteDeviceList::changed`] of the evicted device lists
+    evicted_generation: u64,
+}
+
+impl RemoteDeviceLists {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            lists: LruCache::new(capacity),
+            generation: 0,
+            evicted_generation: 0,
+        }
+    }
+
+    /// Returns the device list of a user, adding an empty one if there is
+    /// none.
+    fn get_or_insert(&mut self, user_id: &UserId) -> &mut RemoteDeviceList {
+        if !self.lists.contains_key(user_id) {
+            if self.lists.len() >= self.lists.capacity() {
+                if let Some((_, evicted)) = self.lists.remove_lru() {
+                    self.evicted_generation =
+                        self.evicted_generation.max(evicted.changed);
+                    METRICS.record_cache_eviction(
+                        Lookup::RemoteDeviceKeys,
+                        EvictionReason::Capacity,
+                    );
+                }
+            }
+            self.lists.insert(user_id.to_owned(), RemoteDeviceList::default());
+        }
+
+        self.lists.get_mut(user_id).expect("device list was just inserted")
+    }
+
+    /// Applies an `m.device_list_update` EDU to the device list of the user.
+    ///
+    /// If updates were missed, the cached keys are dropped and `resync` is
+    /// called to fetch the whole device list again. Returns whether the device
+    /// list changed.
+    fn apply_update<F>(
+        &mut self,
+        update: &DeviceListUpdateContent,
+        resync: F,
+    ) -> bool
+    where
+        F: FnOnce(OwnedUserId),
+    {
+        let list = self.get_or_insert(&update.user_id);
+        let had_keys = list.keys.is_some();
+
+        match list.update(update) {
+            DeviceListUpdateAction::Ignore => return false,
+            DeviceListUpdateAction::Apply => {}
+            DeviceListUpdateAction::Resync => {
+                if had_keys {
+                    METRICS.record_cache_eviction(
+                        Lookup::RemoteDeviceKeys,
+                        EvictionReason::Invalidated,
+                    );
+                }
+                resync(update.user_id.clone());
+            }
+        }
+        self.mark_changed(&update.user_id);
+
+        true
+    }
+
+    /// Records that the device list of a user changed.
+    fn mark_changed(&mut self, user_id: &UserId) {
+        self.generation += 1;
+        let generation = self.generation;
+        self.get_or_insert(user_id).changed = generation;
+    }
+
+    /// Returns whether the device list of a user may have changed after
+    /// `generation`.
+    ///
+    /// Evicted device lists are not remembered, so this is also the case if
+    /// any device list that changed after `generation` was evicted.
+    fn changed_since(&mut self, user_id: &UserId, generation: u64) -> bool {
+        if self.evicted_generation > generation {
+            return true;
+        }
+
+        self.lists
+            .get_mut(user_id)
+            .is_some_and(|list| list.changed > generation)
+    }
+}
+
+/// What to do with the cached device list of a remote user after receiving an
+/// `m.device_list_update` EDU
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DeviceListUpdateAction {
+    /// The update was already received
+    Ignore,
+    /// The update follows the last one received and was applied to the cache
+    Apply,
+    /// Updates were missed, so the whole device list has to be fetched again
+    Resync,
+}
+
+impl RemoteDeviceList {
+    /// Applies an `m.device_list_update` EDU to the device list.
+    ///
+    /// The update is only applied if all updates in its `prev_id` were
+    /// received, otherwise the cached keys are dropped.
+    fn update(
+        &mut self,
+        update: &DeviceListUpdateContent,
+    ) -> DeviceListUpdateAction {
+        if self.stream_id.is_some_and(|known| known >= update.stream_id) {
+            return DeviceListUpdateAction::Ignore;
+        }
+
+        // Only the last stream ID is remembered, so any earlier `prev_id` must
+        // have been received before it. An empty `prev_id` says nothing about
+        // missed updates.
+        let in_sequence = self.stream_id.is_some()
+            && update.prev_id.iter().max() == self.stream_id.as_ref();
+        self.stream_id = Some(update.stream_id);
+
+        if !in_sequence {
+            self.keys = None;
+            return DeviceListUpdateAction::Resync;
+        }
+
+        if let Some(keys) = &mut self.keys {
+            if update.deleted == Some(true) {
+                keys.device_keys.remove(&update.device_id);
+            } else if let Some(device_keys) = &update.keys {
+                keys.device_keys
+                    .insert(update.device_id.clone(), device_keys.clone());
+            }
+        }
+
+        DeviceListUpdateAction::Apply
+    }
+}
+
+/// Keys of a remote user's devices, as returned by a federation key query
+#[derive(Clone)]
+pub(crate) struct RemoteDeviceKeys {
+    pub(crate) device_keys: BTreeMap<OwnedDeviceId, Raw<DeviceKeys>>,
+    pub(crate) self_signing_key: Option<Raw<CrossSigningKey>>,
+    fetched_at: Instant,
+}
 
 pub(crate) struct SlidingSyncCache {
+    /// When the connection was last used by a request
+    last_seen: Instant,
     lists: BTreeMap<String, SyncRequestList>,
     subscriptions: BTreeMap<OwnedRoomId, sync_events::v4::RoomSubscription>,
     // For every room, the roomsince number
@@ -41,6 +264,26 @@ pub(crate) struct Service {
             Arc<Mutex<SlidingSyncCache>>,
         >,
     >,
+    /// When and from where the last seen metadata of each device was last
+    /// written to the database
+    #[allow(clippy::type_complexity)]
+    pub(crate) device_last_seen:
+        Mutex<HashMap<(OwnedUserId, OwnedDeviceId), (Instant, Option<IpAddr>)>>,
+    /// Pending validations of third party identifiers
+    pub(crate) threepid_sessions:
+        Mutex<HashMap<OwnedSessionId, ThreepidSession>>,
+    /// Held while a third party identifier is bound or unbound, so that each
+    /// is bound to at most one user
+    pub(crate) threepid_lock: Mutex<()>,
+    /// Unused `m.login.token` tokens, the user they log in and when they were
+    /// issued
+    pub(crate) login_tokens: Mutex<HashMap<String, (OwnedUserId, Instant)>>,
+    /// Held while a dehydrated device is replaced, so that each user has at
+    /// most one
+    pub(crate) dehydrated_device_lock: Mutex<()>,
+    /// Device lists of remote users, to avoid querying their server for every
+    /// key query
+    pub(crate) remote_device_lists: Mutex<RemoteDeviceLists>,
 }
 
 impl Service {
@@ -55,9 +298,61 @@ impl Service {
         device_id: OwnedDeviceId,
         conn_id: String,
     ) {
-        self.connections.lock().unwrap().remove(&(user_id, device_id, conn_id));
+        let mut connections = self.connections.lock().unwrap();
+        connections.remove(&(user_id, device_id, conn_id));
+        METRICS.record_sliding_sync_connections(connections.len());
     }
 
+    /// Forgets sliding sync connections that haven't been used for longer
+    /// than `sliding_sync_connection_ttl`.
+    ///
+    /// Returns the number of connections forgotten.
+    pub(crate) fn forget_idle_sync_request_connections(&self) -> usize {
+        let ttl = services().globals.config.sliding_sync_connection_ttl;
+
+        let mut connections = self.connections.lock().unwrap();
+        let before = connections.len();
+        connections.retain(|_, cached| {
+            cached.lock().unwrap().last_seen.elapsed() < ttl
+        });
+        METRICS.record_sliding_sync_connections(connections.len());
+
+        before - connections.len()
+    }
+
+    /// Returns the state of a sliding sync connection, creating it if it
+    /// doesn't exist yet, and marks it as used.
+    fn sync_request_connection(
+        &self,
+        user_id: OwnedUserId,
+        device_id: OwnedDeviceId,
+        conn_id: String,
+    ) -> Arc<Mutex<SlidingSyncCache>> {
+        let mut connections = self.connections.lock().unwrap();
+        let cached = Arc::clone(
+            connections.entry((user_id, device_id, conn_id)).or_insert_with(
+                || {
+                    Arc::new(Mutex::new(SlidingSyncCache {
+                        last_seen: Instant::now(),
+                        lists: BTreeMap::new(),
+                        subscriptions: BTreeMap::new(),
+                        known_rooms: BTreeMap::new(),
+                        extensions: ExtensionsConfig::default(),
+                    }))
+                },
+            ),
+        );
+        METRICS.record_sliding_sync_connections(connections.len());
+        cached.lock().unwrap().last_seen = Instant::now();
+
+        cached
+    }
+
+    /// Applies the sticky parameters of the request's connection to `request`
+    /// and remembers the new ones.
+    ///
+    /// Requests without a `conn_id` have no sticky parameters, and nothing is
+    /// remembered for them.
     #[allow(clippy::too_many_lines)]
     pub(crate) fn update_sync_request_with_cache(
         &self,
@@ -69,19 +364,8 @@ impl Service {
             return BTreeMap::new();
         };
 
-        let mut cache = self.connections.lock().unwrap();
-        let cached = Arc::clone(
-            cache.entry((user_id, device_id, conn_id)).or_insert_with(|| {
-                Arc::new(Mutex::new(SlidingSyncCache {
-                    lists: BTreeMap::new(),
-                    subscriptions: BTreeMap::new(),
-                    known_rooms: BTreeMap::new(),
-                    extensions: ExtensionsConfig::default(),
-                }))
-            }),
-        );
+        let cached = self.sync_request_connection(user_id, device_id, conn_id);
         let cached = &mut cached.lock().unwrap();
-        drop(cache);
 
         for (list_id, list) in &mut request.lists {
             if let Some(cached_list) = cached.lists.get(list_id) {
@@ -196,19 +480,8 @@ impl Service {
         conn_id: String,
         subscriptions: BTreeMap<OwnedRoomId, sync_events::v4::RoomSubscription>,
     ) {
-        let mut cache = self.connections.lock().unwrap();
-        let cached = Arc::clone(
-            cache.entry((user_id, device_id, conn_id)).or_insert_with(|| {
-                Arc::new(Mutex::new(SlidingSyncCache {
-                    lists: BTreeMap::new(),
-                    subscriptions: BTreeMap::new(),
-                    known_rooms: BTreeMap::new(),
-                    extensions: ExtensionsConfig::default(),
-                }))
-            }),
-        );
+        let cached = self.sync_request_connection(user_id, device_id, conn_id);
         let cached = &mut cached.lock().unwrap();
-        drop(cache);
 
         cached.subscriptions = subscriptions;
     }
@@ -222,19 +495,8 @@ impl Service {
         new_cached_rooms: BTreeSet<OwnedRoomId>,
         globalsince: u64,
     ) {
-        let mut cache = self.connections.lock().unwrap();
-        let cached = Arc::clone(
-            cache.entry((user_id, device_id, conn_id)).or_insert_with(|| {
-                Arc::new(Mutex::new(SlidingSyncCache {
-                    lists: BTreeMap::new(),
-                    subscriptions: BTreeMap::new(),
-                    known_rooms: BTreeMap::new(),
-                    extensions: ExtensionsConfig::default(),
-                }))
-            }),
-        );
+        let cached = self.sync_request_connection(user_id, device_id, conn_id);
         let cached = &mut cached.lock().unwrap();
-        drop(cache);
 
         for (roomid, lastsince) in
             cached.known_rooms.entry(list_id.clone()).or_default().iter_mut()
@@ -344,6 +606,23 @@ impl Service {
         self.db.avatar_url(user_id)
     }
 
+    /// Returns the room server notices are sent to for a local user.
+    pub(crate) fn server_notices_room(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<OwnedRoomId>> {
+        self.db.server_notices_room(user_id)
+    }
+
+    /// Sets the room server notices are sent to for a local user.
+    pub(crate) fn set_server_notices_room(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<()> {
+        self.db.set_server_notices_room(user_id, room_id)
+    }
+
     /// Sets a new `avatar_url` or removes it if `avatar_url` is `None`.
     pub(crate) fn set_avatar_url(
         &self,
@@ -368,11 +647,14 @@ impl Service {
     }
 
     /// Adds a new device to a user.
+    ///
+    /// Devices without an access token can't be logged in to, which is used for
+    /// dehydrated devices.
     pub(crate) fn create_device(
         &self,
         user_id: &UserId,
         device_id: &DeviceId,
-        token: &str,
+        token: Option<&str>,
         initial_device_display_name: Option<String>,
     ) -> Result<()> {
         self.db.create_device(
@@ -389,7 +671,18 @@ impl Service {
         user_id: &UserId,
         device_id: &DeviceId,
     ) -> Result<()> {
-        self.db.remove_device(user_id, device_id)
+        self.device_last_seen
+            .lock()
+            .unwrap()
+            .remove(&(user_id.to_owned(), device_id.to_owned()));
+        if self.db.dehydrated_device(user_id)?.is_some_and(
+            |(dehydrated_device_id, _)| dehydrated_device_id == device_id,
+        ) {
+            self.db.remove_dehydrated_device(user_id)?;
+        }
+        self.db.remove_device(user_id, device_id)?;
+        self.db.mark_device_key_update(user_id)?;
+        self.send_device_list_update(user_id, device_id)
     }
 
     /// Returns an iterator over all device ids of this user.
@@ -410,6 +703,80 @@ impl Service {
         self.db.set_token(user_id, device_id, token)
     }
 
+    /// Replaces the refresh token of one device and makes its current access
+    /// token expire after `expires_in`.
+    pub(crate) fn set_refresh_token(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        refresh_token: &str,
+        expires_in: Duration,
+    ) -> Result<()> {
+        let expires_at = utils::millis_since_unix_epoch().saturating_add(
+            expires_in.as_millis().try_into().unwrap_or(u64::MAX),
+        );
+
+        self.db.set_refresh_token(user_id, device_id, refresh_token, expires_at)
+    }
+
+    /// Replaces the access and refresh token of one device after
+    /// `used_refresh_token` was used, and makes the new access token expire
+    /// after `expires_in`.
+    ///
+    /// The used refresh token stays valid until the client uses one of the new
+    /// tokens, see [`Self::confirm_tokens`].
+    pub(crate) fn rotate_tokens(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        used_refresh_token: &str,
+        access_token: &str,
+        refresh_token: &str,
+        expires_in: Duration,
+    ) -> Result<()> {
+        let expires_at = utils::millis_since_unix_epoch().saturating_add(
+            expires_in.as_millis().try_into().unwrap_or(u64::MAX),
+        );
+
+        self.db.rotate_tokens(
+            user_id,
+            device_id,
+            used_refresh_token,
+            access_token,
+            refresh_token,
+            expires_at,
+        )
+    }
+
+    /// Invalidates the refresh token that was used to get the current tokens
+    /// of a device, must be called when the current access token is used.
+    pub(crate) fn confirm_tokens(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<()> {
+        self.db.remove_previous_refresh_token(user_id, device_id)
+    }
+
+    /// Find out which device a refresh token belongs to.
+    pub(crate) fn find_from_refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<(OwnedUserId, OwnedDeviceId)>> {
+        self.db.find_from_refresh_token(refresh_token)
+    }
+
+    /// Checks whether the access token of a device has expired.
+    pub(crate) fn token_expired(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<bool> {
+        Ok(self.db.token_expires_at(user_id, device_id)?.is_some_and(
+            |expires_at| expires_at < utils::millis_since_unix_epoch(),
+        ))
+    }
+
     pub(crate) fn add_one_time_key(
         &self,
         user_id: &UserId,
@@ -442,13 +809,46 @@ impl Service {
         self.db.count_one_time_keys(user_id, device_id)
     }
 
+    pub(crate) fn add_fallback_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        fallback_key_key: &DeviceKeyId,
+        fallback_key_value: &Raw<OneTimeKey>,
+    ) -> Result<()> {
+        self.db.add_fallback_key(
+            user_id,
+            device_id,
+            fallback_key_key,
+            fallback_key_value,
+        )
+    }
+
+    pub(crate) fn take_fallback_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        key_algorithm: &DeviceKeyAlgorithm,
+    ) -> Result<Option<(OwnedDeviceKeyId, Raw<OneTimeKey>)>> {
+        self.db.take_fallback_key(user_id, device_id, key_algorithm)
+    }
+
+    pub(crate) fn unused_fallback_key_types(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Vec<DeviceKeyAlgorithm>> {
+        self.db.unused_fallback_key_types(user_id, device_id)
+    }
+
     pub(crate) fn add_device_keys(
         &self,
         user_id: &UserId,
         device_id: &DeviceId,
         device_keys: &Raw<DeviceKeys>,
     ) -> Result<()> {
-        self.db.add_device_keys(user_id, device_id, device_keys)
+        self.db.add_device_keys(user_id, device_id, device_keys)?;
+        self.send_device_list_update(user_id, device_id)
     }
 
     pub(crate) fn add_cross_signing_keys(
@@ -465,7 +865,13 @@ impl Service {
             self_signing_key,
             user_signing_key,
             notify,
-        )
+        )?;
+
+        if notify && user_id.server_name() == services().globals.server_name() {
+            self.send_signing_key_update(user_id)?;
+        }
+
+        Ok(())
     }
 
     pub(crate) fn sign_key(
@@ -475,7 +881,21 @@ impl Service {
         signature: (String, String),
         sender_id: &UserId,
     ) -> Result<()> {
-        self.db.sign_key(target_id, key_id, signature, sender_id)
+        self.db.sign_key(target_id, key_id, signature, sender_id)?;
+
+        // Only signatures of a user's own keys are sent to other servers
+        if target_id != sender_id
+            || target_id.server_name() != services().globals.server_name()
+        {
+            return Ok(());
+        }
+        // The signed key is either a device key or a cross-signing key
+        let device_id = <&DeviceId>::from(key_id);
+        if self.db.get_device_keys(target_id, device_id)?.is_some() {
+            self.send_device_list_update(target_id, device_id)
+        } else {
+            self.send_signing_key_update(target_id)
+        }
     }
 
     pub(crate) fn keys_changed<'a>(
@@ -570,15 +990,305 @@ impl Service {
         self.db.get_to_device_events(user_id, device_id)
     }
 
+    /// Removes the to-device events that were delivered in a sync response
+    /// whose `next_batch` was `until`.
+    ///
+    /// Events that were added after that response are kept, so that they are
+    /// delivered in the next one.
     pub(crate) fn remove_to_device_events(
         &self,
         user_id: &UserId,
         device_id: &DeviceId,
         until: u64,
     ) -> Result<()> {
+        // A token from the future would remove events that were never
+        // delivered, e.g. after the database was restored from a backup
+        if until > services().globals.current_count()? {
+            warn!(
+                %user_id,
+                %device_id,
+                until,
+                "Not removing to-device events for sync token that was never \
+                 issued",
+            );
+            METRICS.record_to_device_removal_skipped();
+            return Ok(());
+        }
+
         self.db.remove_to_device_events(user_id, device_id, until)
     }
 
+    /// Returns the cached keys of all devices of a remote user, if they are
+    /// still current.
+    pub(crate) fn cached_remote_device_keys(
+        &self,
+        user_id: &UserId,
+    ) -> Option<RemoteDeviceKeys> {
+        let lookup = Lookup::RemoteDeviceKeys;
+        let mut device_lists = self.remote_device_lists.lock().unwrap();
+
+        let list = device_lists.lists.get_mut(user_id)?;
+        let keys = list.keys.as_ref()?;
+        if keys.fetched_at.elapsed() >= REMOTE_DEVICE_LIST_MAX_AGE {
+            list.keys = None;
+            METRICS.record_cache_eviction(lookup, EvictionReason::Expired);
+            return None;
+        }
+
+        METRICS.record_lookup(lookup, FoundIn::Cache);
+        Some(keys.clone())
+    }
+
+    /// Returns the current generation of the remote device lists.
+    ///
+    /// Must be read before querying keys over federation and passed to
+    /// [`Self::cache_remote_device_keys`].
+    pub(crate) fn remote_device_list_generation(&self) -> u64 {
+        self.remote_device_lists.lock().unwrap().generation
+    }
+
+    /// Caches the keys of all devices of a remote user after fetching them
+    /// over federation.
+    ///
+    /// `generation` is the [`Self::remote_device_list_generation`] from before
+    /// the keys were requested. If the device list changed since then, the
+    /// response may predate the change and is not cached.
+    pub(crate) fn cache_remote_device_keys(
+        &self,
+        user_id: &UserId,
+        generation: u64,
+        device_keys: BTreeMap<OwnedDeviceId, Raw<DeviceKeys>>,
+        self_signing_key: Option<Raw<CrossSigningKey>>,
+    ) {
+        METRICS.record_lookup(Lookup::RemoteDeviceKeys, FoundIn::Remote);
+        let mut device_lists = self.remote_device_lists.lock().unwrap();
+        if device_lists.changed_since(user_id, generation) {
+            debug!(
+                %user_id,
+                "Not caching device keys, device list changed during query",
+            );
+            return;
+        }
+
+        device_lists.get_or_insert(user_id).keys = Some(RemoteDeviceKeys {
+            device_keys,
+            self_signing_key,
+            fetched_at: Instant::now(),
+        });
+    }
+
+    /// Forgets the cached keys of a remote user because their keys changed,
+    /// so they are fetched again when they are needed next.
+    pub(crate) fn invalidate_remote_device_keys(&self, user_id: &UserId) {
+        let mut device_lists = self.remote_device_lists.lock().unwrap();
+
+        // Also marks device lists that aren't cached yet, in case a key query
+        // for the user is in progress
+        device_lists.mark_changed(user_id);
+        if device_lists.get_or_insert(user_id).keys.take().is_some() {
+            METRICS.record_cache_eviction(
+                Lookup::RemoteDeviceKeys,
+                EvictionReason::Invalidated,
+            );
+        }
+    }
+
+    /// Applies an `m.device_list_update` EDU from another server to the cached
+    /// device list of the user, see [`RemoteDeviceLists::apply_update`].
+    pub(crate) fn apply_remote_device_list_update<F>(
+        &self,
+        update: &DeviceListUpdateContent,
+        resync: F,
+    ) -> bool
+    where
+        F: FnOnce(OwnedUserId),
+    {
+        self.remote_device_lists.lock().unwrap().apply_update(update, resync)
+    }
+
+    /// Sends an `m.device_list_update` EDU for a local device to all servers
+    /// that share an encrypted room with the user.
+    ///
+    /// Each user has their own stream of these updates, so that other servers
+    /// can tell from `prev_id` whether they missed any. Changes to
+    /// cross-signing keys are sent as `m.signing_key_update` instead and
+    /// don't advance it.
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn send_device_list_update(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<()> {
+        // Advanced even if no server is sent the update, so that servers that
+        // share a room with the user again later notice the gap
+        let stream_id = self.db.next_device_list_stream_id(user_id)?;
+
+        let servers = self.servers_sharing_encrypted_rooms(user_id)?;
+        if servers.is_empty() {
+            return Ok(());
+        }
+
+        // Removed devices have no metadata, but their keys are kept
+        let metadata = self.db.get_device_metadata(user_id, device_id)?;
+        let keys = match &metadata {
+            Some(_) => self.db.get_device_keys(user_id, device_id)?,
+            None => None,
+        };
+        let edu = Edu::DeviceListUpdate(DeviceListUpdateContent {
+            user_id: user_id.to_owned(),
+            device_id: device_id.to_owned(),
+            stream_id: UInt::new_saturating(stream_id),
+            prev_id: stream_id
+                .checked_sub(1)
+                .filter(|prev_id| *prev_id > 0)
+                .map(UInt::new_saturating)
+                .into_iter()
+                .collect(),
+            deleted: metadata.is_none().then_some(true),
+            device_display_name: metadata.and_then(|m| m.display_name),
+            keys,
+        });
+        let edu = serde_json::to_vec(&edu).expect("json can be serialized");
+
+        for server in servers {
+            services().sending.send_reliable_edu(
+                &server,
+                edu.clone(),
+                stream_id,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends an `m.signing_key_update` EDU with the current cross-signing keys
+    /// of a local user to all servers that share an encrypted room with the
+    /// user.
+    #[tracing::instrument(skip(self))]
+    pub(crate) fn send_signing_key_update(
+        &self,
+        user_id: &UserId,
+    ) -> Result<()> {
+        let servers = self.servers_sharing_encrypted_rooms(user_id)?;
+        if servers.is_empty() {
+            return Ok(());
+        }
+
+        let edu = Edu::SigningKeyUpdate(SigningKeyUpdateContent {
+            user_id: user_id.to_owned(),
+            master_key: self.db.get_master_key(None, user_id, &|_| false)?,
+            self_signing_key: self.db.get_self_signing_key(
+                None,
+                user_id,
+                &|_| false,
+            )?,
+        });
+        let edu = serde_json::to_vec(&edu).expect("json can be serialized");
+
+        let count = services().globals.next_count()?;
+        for server in servers {
+            services().sending.send_reliable_edu(
+                &server,
+                edu.clone(),
+                count,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the other servers in encrypted rooms the user is joined to,
+    /// which need to know about changes to the user's keys.
+    fn servers_sharing_encrypted_rooms(
+        &self,
+        user_id: &UserId,
+    ) -> Result<HashSet<OwnedServerName>> {
+        let mut servers = HashSet::new();
+        for room_id in services()
+            .rooms
+            .state_cache
+            .rooms_joined(user_id)
+            .filter_map(Result::ok)
+        {
+            if services()
+                .rooms
+                .state_accessor
+                .room_state_get(&room_id, &StateEventType::RoomEncryption, "")?
+                .is_none()
+            {
+                continue;
+            }
+
+            servers.extend(
+                services()
+                    .rooms
+                    .state_cache
+                    .room_servers(&room_id)
+                    .filter_map(Result::ok),
+            );
+        }
+        servers.remove(services().globals.server_name());
+
+        Ok(servers)
+    }
+
+    /// Returns the ID and encrypted data of the user's dehydrated device.
+    pub(crate) fn dehydrated_device(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<(OwnedDeviceId, Raw<DehydratedDeviceData>)>> {
+        self.db.dehydrated_device(user_id)
+    }
+
+    /// Replaces the user's dehydrated device with a new device.
+    ///
+    /// The new device is created before the old one is removed, so there is
+    /// always a device that to-device messages can be sent to.
+    pub(crate) fn set_dehydrated_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        initial_device_display_name: Option<String>,
+        device_data: &Raw<DehydratedDeviceData>,
+    ) -> Result<()> {
+        let _guard = self.dehydrated_device_lock.lock().unwrap();
+
+        let old_device = self.db.dehydrated_device(user_id)?;
+
+        self.create_device(
+            user_id,
+            device_id,
+            None,
+            initial_device_display_name,
+        )?;
+        self.db.set_dehydrated_device(user_id, device_id, device_data)?;
+
+        if let Some((old_device_id, _)) = old_device {
+            if old_device_id != device_id {
+                self.remove_device(user_id, &old_device_id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes the user's dehydrated device, returning its ID.
+    pub(crate) fn remove_dehydrated_device(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<OwnedDeviceId>> {
+        let _guard = self.dehydrated_device_lock.lock().unwrap();
+
+        let Some((device_id, _)) = self.db.dehydrated_device(user_id)? else {
+            return Ok(None);
+        };
+
+        // Also forgets the dehydrated device
+        self.remove_device(user_id, &device_id)?;
+
+        Ok(Some(device_id))
+    }
+
     pub(crate) fn update_device_metadata(
         &self,
         user_id: &UserId,
@@ -588,6 +1298,38 @@ impl Service {
         self.db.update_device_metadata(user_id, device_id, device)
     }
 
+    /// Records that a device was just used from `ip`.
+    ///
+    /// To avoid a write per request, the metadata is only updated if the IP
+    /// address changed or [`LAST_SEEN_UPDATE_INTERVAL`] has passed since the
+    /// last update.
+    pub(crate) fn update_device_last_seen(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        ip: Option<IpAddr>,
+    ) -> Result<()> {
+        let now = Instant::now();
+        let key = (user_id.to_owned(), device_id.to_owned());
+        {
+            let mut device_last_seen = self.device_last_seen.lock().unwrap();
+            if device_last_seen.get(&key).is_some_and(|(updated, last_ip)| {
+                *last_ip == ip
+                    && now.duration_since(*updated) < LAST_SEEN_UPDATE_INTERVAL
+            }) {
+                return Ok(());
+            }
+            device_last_seen.insert(key, (now, ip));
+        }
+
+        self.db.update_device_last_seen(
+            user_id,
+            device_id,
+            ip.map(|ip| ip.to_string()),
+            MilliSecondsSinceUnixEpoch::now(),
+        )
+    }
+
     /// Get device metadata.
     pub(crate) fn get_device_metadata(
         &self,
@@ -604,6 +1346,15 @@ impl Service {
         self.db.get_devicelist_version(user_id)
     }
 
+    /// Returns the stream ID of the last `m.device_list_update` EDU of a local
+    /// user.
+    pub(crate) fn device_list_stream_id(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<u64>> {
+        self.db.device_list_stream_id(user_id)
+    }
+
     pub(crate) fn all_devices_metadata<'a>(
         &'a self,
         user_id: &UserId,
@@ -612,10 +1363,14 @@ impl Service {
     }
 
     /// Deactivate account
-    pub(crate) fn deactivate_account(&self, user_id: &UserId) -> Result<()> {
+    ///
+    /// Returns the number of devices that were removed.
+    pub(crate) fn deactivate_account(&self, user_id: &UserId) -> Result<usize> {
         // Remove all associated devices
-        for device_id in self.all_device_ids(user_id) {
-            self.remove_device(user_id, &device_id?)?;
+        let device_ids =
+            self.all_device_ids(user_id).collect::<Result<Vec<_>>>()?;
+        for device_id in &device_ids {
+            self.remove_device(user_id, device_id)?;
         }
 
         // Set the password to "" to indicate a deactivated account. Hashes will
@@ -624,8 +1379,30 @@ impl Service {
         // logging in should check if the account is deactivated.
         self.db.set_password(user_id, None)?;
 
-        // TODO: Unhook 3PID
-        Ok(())
+        for threepid in self.threepids(user_id)? {
+            self.db.remove_threepid(
+                user_id,
+                &threepid.medium,
+                &threepid.address,
+            )?;
+        }
+
+        Ok(device_ids.len())
+    }
+
+    /// Removes the displayname, avatar and blurhash of a user.
+    ///
+    /// Returns whether any of them was set.
+    pub(crate) fn clear_profile(&self, user_id: &UserId) -> Result<bool> {
+        let had_profile = self.displayname(user_id)?.is_some()
+            || self.avatar_url(user_id)?.is_some()
+            || self.blurhash(user_id)?.is_some();
+
+        self.set_displayname(user_id, None)?;
+        self.set_avatar_url(user_id, None)?;
+        self.set_blurhash(user_id, None)?;
+
+        Ok(had_profile)
     }
 
     /// Creates a new sync filter. Returns the filter id.
@@ -644,6 +1421,268 @@ impl Service {
     ) -> Result<Option<FilterDefinition>> {
         self.db.get_filter(user_id, filter_id)
     }
+
+    /// Stores an OpenID token for a user that is valid for `expires_in`.
+    pub(crate) fn create_openid_token(
+        &self,
+        user_id: &UserId,
+        token: &str,
+        expires_in: Duration,
+    ) -> Result<()> {
+        let expires_at = utils::millis_since_unix_epoch().saturating_add(
+            expires_in.as_millis().try_into().unwrap_or(u64::MAX),
+        );
+
+        self.db.create_openid_token(user_id, token, expires_at)
+    }
+
+    /// Find out which user an OpenID token belongs to, if it hasn't expired
+    /// yet.
+    pub(crate) fn find_from_openid_token(
+        &self,
+        token: &str,
+    ) -> Result<Option<OwnedUserId>> {
+        self.db.find_from_openid_token(token)
+    }
+
+    /// Removes all expired OpenID tokens. Returns the number of removed
+    /// tokens.
+    pub(crate) fn remove_expired_openid_tokens(&self) -> Result<usize> {
+        self.db.remove_expired_openid_tokens()
+    }
+
+    /// Starts validating a third party identifier, or continues the session
+    /// of the same client secret and address.
+    ///
+    /// Returns the session ID and the token to send to the address, or `None`
+    /// if the client already made this `send_attempt`.
+    pub(crate) fn create_threepid_session(
+        &self,
+        client_secret: &ClientSecret,
+        medium: Medium,
+        address: &str,
+        send_attempt: UInt,
+    ) -> (OwnedSessionId, Option<String>) {
+        let mut sessions = self.threepid_sessions.lock().unwrap();
+        sessions.retain(|_, session| {
+            session.created.elapsed() < THREEPID_SESSION_LIFETIME
+        });
+
+        if let Some((sid, session)) =
+            sessions.iter_mut().find(|(_, session)| {
+                session.client_secret.as_str() == client_secret.as_str()
+                    && session.medium == medium
+                    && session.address == address
+            })
+        {
+            if send_attempt <= session.send_attempt {
+                return (sid.clone(), None);
+            }
+
+            session.send_attempt = send_attempt;
+            return (sid.clone(), Some(session.token.clone()));
+        }
+
+        let sid = OwnedSessionId::try_from(utils::random_string(
+            THREEPID_SESSION_ID_LENGTH,
+        ))
+        .expect("random string should be a valid session ID");
+        let token = utils::random_string(THREEPID_TOKEN_LENGTH);
+
+        sessions.insert(
+            sid.clone(),
+            ThreepidSession {
+                client_secret: client_secret.to_owned(),
+                medium,
+                address: address.to_owned(),
+                token: token.clone(),
+                send_attempt,
+                failed_attempts: 0,
+                validated_at: None,
+                created: Instant::now(),
+            },
+        );
+
+        (sid, Some(token))
+    }
+
+    /// Marks a third party identifier validation session as validated if
+    /// `token` is the one that was sent to the address.
+    ///
+    /// The session ends after [`THREEPID_MAX_FAILED_ATTEMPTS`] wrong tokens,
+    /// since the token is short enough to be guessed otherwise.
+    pub(crate) fn validate_threepid_session(
+        &self,
+        sid: &SessionId,
+        client_secret: &ClientSecret,
+        token: &str,
+    ) -> Result<()> {
+        let mut sessions = self.threepid_sessions.lock().unwrap();
+        let Some(session) = sessions.get_mut(sid).filter(|session| {
+            session.client_secret.as_str() == client_secret.as_str()
+                && session.created.elapsed() < THREEPID_SESSION_LIFETIME
+        }) else {
+            return Err(Error::BadRequest(
+                ErrorKind::ThreepidAuthFailed,
+                "Unknown validation session.",
+            ));
+        };
+
+        if session.token != token {
+            session.failed_attempts += 1;
+            if session.failed_attempts >= THREEPID_MAX_FAILED_ATTEMPTS {
+                sessions.remove(sid);
+                return Err(Error::BadRequest(
+                    ErrorKind::ThreepidAuthFailed,
+                    "Too many invalid validation tokens, request a new one.",
+                ));
+            }
+
+            return Err(Error::BadRequest(
+                ErrorKind::ThreepidAuthFailed,
+                "Invalid validation token.",
+            ));
+        }
+
+        session
+            .validated_at
+            .get_or_insert_with(MilliSecondsSinceUnixEpoch::now);
+
+        Ok(())
+    }
+
+    /// Binds the third party identifier of a validated session to a user and
+    /// ends the session.
+    ///
+    /// Fails with `M_THREEPID_IN_USE` if it is bound to another user.
+    pub(crate) fn add_threepid(
+        &self,
+        user_id: &UserId,
+        sid: &SessionId,
+        client_secret: &ClientSecret,
+    ) -> Result<()> {
+        let session = {
+            let mut sessions = self.threepid_sessions.lock().unwrap();
+            match sessions.get(sid) {
+                Some(session)
+                    if session.client_secret.as_str()
+                        == client_secret.as_str()
+                        && session.validated_at.is_some() =>
+                {
+                    sessions.remove(sid).expect("session exists")
+                }
+                _ => {
+                    return Err(Error::BadRequest(
+                        ErrorKind::ThreepidAuthFailed,
+                        "Third party identifier has not been validated.",
+                    ));
+                }
+            }
+        };
+
+        let _guard = self.threepid_lock.lock().unwrap();
+        if self
+            .db
+            .threepid_owner(&session.medium, &session.address)?
+            .is_some_and(|owner| &*owner != user_id)
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::ThreepidInUse,
+                "Third party identifier is already in use.",
+            ));
+        }
+
+        self.db.add_threepid(
+            user_id,
+            &ThirdPartyIdentifier {
+                address: session.address,
+                medium: session.medium,
+                validated_at: session
+                    .validated_at
+                    .expect("session was validated"),
+                added_at: MilliSecondsSinceUnixEpoch::now(),
+            },
+        )
+    }
+
+    /// Returns the user a third party identifier is bound to.
+    pub(crate) fn threepid_owner(
+        &self,
+        medium: &Medium,
+        address: &str,
+    ) -> Result<Option<OwnedUserId>> {
+        self.db.threepid_owner(medium, address)
+    }
+
+    /// Unbinds a third party identifier from a user.
+    ///
+    /// Fails with `M_THREEPID_NOT_FOUND` if it isn't bound to the user.
+    pub(crate) fn remove_threepid(
+        &self,
+        user_id: &UserId,
+        medium: &Medium,
+        address: &str,
+    ) -> Result<()> {
+        let _guard = self.threepid_lock.lock().unwrap();
+        if self.db.threepid_owner(medium, address)?.as_deref() != Some(user_id)
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::ThreepidNotFound,
+                "Third party identifier is not bound to this account.",
+            ));
+        }
+
+        self.db.remove_threepid(user_id, medium, address)
+    }
+
+    /// Returns all third party identifiers bound to a user.
+    pub(crate) fn threepids(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<ThirdPartyIdentifier>> {
+        self.db.threepids(user_id).collect()
+    }
+
+    /// Issues a short-lived token that can be used once to log in as
+    /// `user_id` via `m.login.token`.
+    pub(crate) fn create_login_token(&self, user_id: &UserId) -> String {
+        let token = utils::random_string(LOGIN_TOKEN_LENGTH);
+
+        let mut tokens = self.login_tokens.lock().unwrap();
+        tokens.retain(|_, (_, issued)| issued.elapsed() < LOGIN_TOKEN_LIFETIME);
+        tokens.insert(token.clone(), (user_id.to_owned(), Instant::now()));
+
+        token
+    }
+
+    /// Consumes a login token, returning the user it logs in if it is valid.
+    pub(crate) fn take_login_token(&self, token: &str) -> Option<OwnedUserId> {
+        self.login_tokens
+            .lock()
+            .unwrap()
+            .remove(token)
+            .filter(|(_, issued)| issued.elapsed() < LOGIN_TOKEN_LIFETIME)
+            .map(|(user_id, _)| user_id)
+    }
+
+    /// Returns the user the subject of an SSO identity provider is mapped to.
+    pub(crate) fn sso_subject_user(
+        &self,
+        idp_id: &str,
+        subject: &str,
+    ) -> Result<Option<OwnedUserId>> {
+        self.db.sso_subject_user(idp_id, subject)
+    }
+
+    /// Maps the subject of an SSO identity provider to a user.
+    pub(crate) fn set_sso_subject_user(
+        &self,
+        idp_id: &str,
+        subject: &str,
+        user_id: &UserId,
+    ) -> Result<()> {
+        self.db.set_sso_subject_user(idp_id, subject, user_id)
+    }
 }
 
 /// Ensure that a user only sees signatures from themselves and the target user
@@ -677,3 +1716,197 @@ pub(crate) fn clean_signatures<F: Fn(&UserId) -> bool>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, ops::Deref, time::Instant};
+
+    use ruma::{
+        api::federation::transactions::edu::DeviceListUpdateContent,
+        owned_device_id, serde::Raw, uint, user_id, DeviceId, UInt,
+    };
+    use serde_json::{json, value::to_raw_value};
+
+    use super::{
+        DeviceListUpdateAction, RemoteDeviceKeys, RemoteDeviceList,
+        RemoteDeviceLists,
+    };
+
+    fn update(stream_id: UInt, prev_id: &[UInt]) -> DeviceListUpdateContent {
+        DeviceListUpdateContent {
+            user_id: user_id!("@bob:b.example.com").to_owned(),
+            device_id: owned_device_id!("NEW"),
+            device_display_name: None,
+            stream_id,
+            prev_id: prev_id.to_vec(),
+            deleted: None,
+            keys: Some(Raw::from_json(
+                to_raw_value(&json!({ "device_id": "NEW" })).unwrap(),
+            )),
+        }
+    }
+
+    fn cached_list(stream_id: UInt) -> RemoteDeviceList {
+        RemoteDeviceList {
+            stream_id: Some(stream_id),
+            keys: Some(RemoteDeviceKeys {
+                device_keys: BTreeMap::from([(
+                    owned_device_id!("OLD"),
+                    Raw::from_json(
+                        to_raw_value(&json!({ "device_id": "OLD" })).unwrap(),
+                    ),
+                )]),
+                self_signing_key: None,
+                fetched_at: Instant::now(),
+            }),
+            changed: 0,
+        }
+    }
+
+    #[test]
+    fn device_list_update_in_sequence_is_applied() {
+        let mut list = cached_list(uint!(5));
+
+        assert_eq!(
+            list.update(&update(uint!(7), &[uint!(5)])),
+            DeviceListUpdateAction::Apply
+        );
+        assert_eq!(list.stream_id, Some(uint!(7)));
+        let keys = list.keys.unwrap();
+        assert_eq!(
+            keys.device_keys
+                .keys()
+                .map(Deref::deref)
+                .map(DeviceId::as_str)
+                .collect::<Vec<_>>(),
+            ["NEW", "OLD"]
+        );
+    }
+
+    #[test]
+    fn deleted_device_is_removed_from_cache() {
+        let mut list = cached_list(uint!(5));
+        let mut deletion = update(uint!(6), &[uint!(5)]);
+        deletion.device_id = owned_device_id!("OLD");
+        deletion.deleted = Some(true);
+        deletion.keys = None;
+
+        assert_eq!(list.update(&deletion), DeviceListUpdateAction::Apply);
+        assert!(list.keys.unwrap().device_keys.is_empty());
+    }
+
+    #[test]
+    fn device_list_update_after_gap_resyncs() {
+        let mut list = cached_list(uint!(5));
+
+        // The update with stream ID 6 was missed
+        assert_eq!(
+            list.update(&update(uint!(7), &[uint!(6)])),
+            DeviceListUpdateAction::Resync
+        );
+        assert_eq!(list.stream_id, Some(uint!(7)));
+        assert!(list.keys.is_none());
+
+        // Later updates continue from the resynced stream ID
+        assert_eq!(
+            list.update(&update(uint!(8), &[uint!(7)])),
+            DeviceListUpdateAction::Apply
+        );
+    }
+
+    #[test]
+    fn device_list_update_without_known_stream_id_resyncs() {
+        let mut list = RemoteDeviceList::default();
+        assert_eq!(
+            list.update(&update(uint!(3), &[uint!(2)])),
+            DeviceListUpdateAction::Resync
+        );
+
+        let mut list = cached_list(uint!(5));
+        assert_eq!(
+            list.update(&update(uint!(6), &[])),
+            DeviceListUpdateAction::Resync
+        );
+    }
+
+    #[test]
+    fn old_device_list_update_is_ignored() {
+        let mut list = cached_list(uint!(5));
+
+        assert_eq!(
+            list.update(&update(uint!(5), &[uint!(4)])),
+            DeviceListUpdateAction::Ignore
+        );
+        assert_eq!(list.stream_id, Some(uint!(5)));
+        assert_eq!(list.keys.unwrap().device_keys.len(), 1);
+    }
+
+    #[test]
+    fn missed_device_list_update_triggers_resync() {
+        let bob = user_id!("@bob:b.example.com");
+        let mut lists = RemoteDeviceLists::new(10);
+        *lists.get_or_insert(bob) = cached_list(uint!(5));
+        let mut resynced = Vec::new();
+
+        assert!(lists.apply_update(
+            &update(uint!(6), &[uint!(5)]),
+            |user_id| {
+                resynced.push(user_id);
+            }
+        ));
+        assert!(resynced.is_empty());
+
+        // The update with stream ID 7 was missed
+        assert!(lists.apply_update(
+            &update(uint!(8), &[uint!(7)]),
+            |user_id| {
+                resynced.push(user_id);
+            }
+        ));
+        assert_eq!(resynced, [bob.to_owned()]);
+        assert!(lists.get_or_insert(bob).keys.is_none());
+
+        // Updates that were already received change nothing
+        assert!(!lists.apply_update(
+            &update(uint!(8), &[uint!(7)]),
+            |user_id| {
+                resynced.push(user_id);
+            }
+        ));
+        assert_eq!(resynced.len(), 1);
+    }
+
+    #[test]
+    fn device_list_changed_during_query_is_detected() {
+        let alice = user_id!("@alice:a.example.com");
+        let bob = user_id!("@bob:b.example.com");
+        let mut lists = RemoteDeviceLists::new(10);
+
+        let generation = lists.generation;
+        assert!(!lists.changed_since(bob, generation));
+
+        lists.mark_changed(alice);
+        assert!(!lists.changed_since(bob, generation));
+        lists.mark_changed(bob);
+        assert!(lists.changed_since(bob, generation));
+
+        // Queries started after the change can be cached
+        assert!(!lists.changed_since(bob, lists.generation));
+    }
+
+    #[test]
+    fn evicted_device_list_changes_are_not_forgotten() {
+        let alice = user_id!("@alice:a.example.com");
+        let bob = user_id!("@bob:b.example.com");
+        let mut lists = RemoteDeviceLists::new(1);
+
+        let generation = lists.generation;
+        lists.mark_changed(bob);
+        // Evicts bob's device list
+        lists.mark_changed(alice);
+        assert_eq!(lists.lists.len(), 1);
+
+        assert!(lists.changed_since(bob, generation));
+        assert!(!lists.changed_since(bob, lists.generation));
+    }
+}
@@ -1,12 +1,17 @@
 use std::collections::BTreeMap;
 
 use ruma::{
-    api::client::{device::Device, filter::FilterDefinition},
+    api::client::{
+        dehydrated_device::DehydratedDeviceData, device::Device,
+        filter::FilterDefinition,
+    },
     encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
     events::AnyToDeviceEvent,
     serde::Raw,
-    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, OwnedDeviceId, OwnedDeviceKeyId,
-    OwnedMxcUri, OwnedUserId, UInt, UserId,
+    thirdparty::{Medium, ThirdPartyIdentifier},
+    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch,
+    OwnedDeviceId, OwnedDeviceKeyId, OwnedMxcUri, OwnedRoomId, OwnedUserId,
+    RoomId, UInt, UserId,
 };
 
 use crate::Result;
@@ -78,12 +83,25 @@ pub(crate) trait Data: Send + Sync {
         blurhash: Option<String>,
     ) -> Result<()>;
 
+    /// Returns the room server notices are sent to for a local user.
+    fn server_notices_room(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<OwnedRoomId>>;
+
+    /// Sets the room server notices are sent to for a local user.
+    fn set_server_notices_room(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<()>;
+
     /// Adds a new device to a user.
     fn create_device(
         &self,
         user_id: &UserId,
         device_id: &DeviceId,
-        token: &str,
+        token: Option<&str>,
         initial_device_display_name: Option<String>,
     ) -> Result<()>;
 
@@ -100,7 +118,8 @@ pub(crate) trait Data: Send + Sync {
         user_id: &UserId,
     ) -> Box<dyn Iterator<Item = Result<OwnedDeviceId>> + 'a>;
 
-    /// Replaces the access token of one device.
+    /// Replaces the access token of one device. This also removes its
+    /// refresh token and the expiration of the access token.
     fn set_token(
         &self,
         user_id: &UserId,
@@ -108,6 +127,55 @@ pub(crate) trait Data: Send + Sync {
         token: &str,
     ) -> Result<()>;
 
+    /// Replaces the refresh token of one device and makes its current access
+    /// token expire at `expires_at`, in milliseconds since the unix epoch.
+    fn set_refresh_token(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        refresh_token: &str,
+        expires_at: u64,
+    ) -> Result<()>;
+
+    /// Replaces the access and refresh token of one device after
+    /// `used_refresh_token` was used, and makes the new access token expire at
+    /// `expires_at`.
+    ///
+    /// The used refresh token stays valid until the new tokens are used, in
+    /// case the client never received them. Using it again replaces the
+    /// unused tokens.
+    fn rotate_tokens(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        used_refresh_token: &str,
+        access_token: &str,
+        refresh_token: &str,
+        expires_at: u64,
+    ) -> Result<()>;
+
+    /// Invalidates the refresh token that was used to get the current tokens
+    /// of a device, if there is one.
+    fn remove_previous_refresh_token(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<()>;
+
+    /// Find out which device a refresh token belongs to.
+    fn find_from_refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<(OwnedUserId, OwnedDeviceId)>>;
+
+    /// Returns when the access token of a device expires, in milliseconds
+    /// since the unix epoch, if it expires at all.
+    fn token_expires_at(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<u64>>;
+
     fn add_one_time_key(
         &self,
         user_id: &UserId,
@@ -129,6 +197,33 @@ pub(crate) trait Data: Send + Sync {
         device_id: &DeviceId,
     ) -> Result<BTreeMap<DeviceKeyAlgorithm, UInt>>;
 
+    /// Stores the fallback key of a device, replacing any previous fallback
+    /// key with the same algorithm.
+    fn add_fallback_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        fallback_key_key: &DeviceKeyId,
+        fallback_key_value: &Raw<OneTimeKey>,
+    ) -> Result<()>;
+
+    /// Returns the fallback key of a device and marks it as used. Unlike
+    /// one-time keys, fallback keys are kept until they are replaced.
+    fn take_fallback_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        key_algorithm: &DeviceKeyAlgorithm,
+    ) -> Result<Option<(OwnedDeviceKeyId, Raw<OneTimeKey>)>>;
+
+    /// Returns the algorithms of the fallback keys of a device that haven't
+    /// been claimed yet.
+    fn unused_fallback_key_types(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Vec<DeviceKeyAlgorithm>>;
+
     fn add_device_keys(
         &self,
         user_id: &UserId,
@@ -162,6 +257,13 @@ pub(crate) trait Data: Send + Sync {
 
     fn mark_device_key_update(&self, user_id: &UserId) -> Result<()>;
 
+    /// Advances the `m.device_list_update` stream of a user and returns the
+    /// new stream ID.
+    fn next_device_list_stream_id(&self, user_id: &UserId) -> Result<u64>;
+
+    /// Returns the stream ID of the last `m.device_list_update` of a user.
+    fn device_list_stream_id(&self, user_id: &UserId) -> Result<Option<u64>>;
+
     fn get_device_keys(
         &self,
         user_id: &UserId,
@@ -223,6 +325,23 @@ pub(crate) trait Data: Send + Sync {
         until: u64,
     ) -> Result<()>;
 
+    /// Returns the ID and encrypted data of the user's dehydrated device.
+    fn dehydrated_device(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<(OwnedDeviceId, Raw<DehydratedDeviceData>)>>;
+
+    /// Replaces the user's dehydrated device.
+    fn set_dehydrated_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        device_data: &Raw<DehydratedDeviceData>,
+    ) -> Result<()>;
+
+    /// Forgets the user's dehydrated device.
+    fn remove_dehydrated_device(&self, user_id: &UserId) -> Result<()>;
+
     fn update_device_metadata(
         &self,
         user_id: &UserId,
@@ -230,6 +349,17 @@ pub(crate) trait Data: Send + Sync {
         device: &Device,
     ) -> Result<()>;
 
+    /// Sets when and from where a device was last seen. Unlike
+    /// [`Data::update_device_metadata`], this doesn't trigger a device list
+    /// update.
+    fn update_device_last_seen(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        last_seen_ip: Option<String>,
+        last_seen_ts: MilliSecondsSinceUnixEpoch,
+    ) -> Result<()>;
+
     /// Get device metadata.
     fn get_device_metadata(
         &self,
@@ -256,4 +386,67 @@ pub(crate) trait Data: Send + Sync {
         user_id: &UserId,
         filter_id: &str,
     ) -> Result<Option<FilterDefinition>>;
+
+    /// Stores an OpenID token that is valid until `expires_at`, in
+    /// milliseconds since the unix epoch.
+    fn create_openid_token(
+        &self,
+        user_id: &UserId,
+        token: &str,
+        expires_at: u64,
+    ) -> Result<()>;
+
+    /// Find out which user an OpenID token belongs to. Expired tokens are
+    /// removed and not returned.
+    fn find_from_openid_token(
+        &self,
+        token: &str,
+    ) -> Result<Option<OwnedUserId>>;
+
+    /// Removes all expired OpenID tokens. Returns the number of removed
+    /// tokens.
+    fn remove_expired_openid_tokens(&self) -> Result<usize>;
+
+    /// Returns the user a third party identifier is bound to.
+    fn threepid_owner(
+        &self,
+        medium: &Medium,
+        address: &str,
+    ) -> Result<Option<OwnedUserId>>;
+
+    /// Binds a third party identifier to a user.
+    fn add_threepid(
+        &self,
+        user_id: &UserId,
+        threepid: &ThirdPartyIdentifier,
+    ) -> Result<()>;
+
+    /// Unbinds a third party identifier from a user.
+    fn remove_threepid(
+        &self,
+        user_id: &UserId,
+        medium: &Medium,
+        address: &str,
+    ) -> Result<()>;
+
+    /// Returns all third party identifiers bound to a user.
+    fn threepids<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<ThirdPartyIdentifier>> + 'a>;
+
+    /// Returns the user the subject of an SSO identity provider is mapped to.
+    fn sso_subject_user(
+        &self,
+        idp_id: &str,
+        subject: &str,
+    ) -> Result<Option<OwnedUserId>>;
+
+    /// Maps the subject of an SSO identity provider to a user.
+    fn set_sso_subject_user(
+        &self,
+        idp_id: &str,
+        subject: &str,
+        user_id: &UserId,
+    ) -> Result<()>;
 }
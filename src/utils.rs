@@ -1,5 +1,9 @@
+pub(crate) mod client_ip;
 pub(crate) mod error;
+pub(crate) mod mailer;
 pub(crate) mod on_demand_hashmap;
+pub(crate) mod rate_limiter;
+pub(crate) mod tls;
 
 use std::{
     borrow::Cow,
@@ -10,6 +14,7 @@ use std::{
 
 use argon2::{password_hash, Argon2, PasswordHasher, PasswordVerifier};
 use cmp::Ordering;
+use once_cell::sync::Lazy;
 use rand::{prelude::*, rngs::OsRng};
 use ring::digest;
 use ruma::{
@@ -39,6 +44,15 @@ pub(crate) fn increment(old: Option<&[u8]>) -> Vec<u8> {
     number.to_be_bytes().to_vec()
 }
 
+/// Returns the counter after `old`, or `None` if it reaches zero
+pub(crate) fn decrement(old: &[u8]) -> Option<Vec<u8>> {
+    let number = u64::from_be_bytes(old.try_into().ok()?);
+    number
+        .checked_sub(1)
+        .filter(|number| *number > 0)
+        .map(|number| number.to_be_bytes().to_vec())
+}
+
 pub(crate) fn generate_keypair() -> Vec<u8> {
     let mut value = random_string(8).as_bytes().to_vec();
     value.push(0xFF);
@@ -102,6 +116,24 @@ where
     Argon2::default().verify_password(password.as_ref(), &hash).is_ok()
 }
 
+/// Verify a password against a throwaway hash
+///
+/// Used when a login fails before there is a real hash to compare to, so that
+/// the failure takes as long as a wrong password.
+pub(crate) fn waste_password_verification<B>(password: B)
+where
+    B: AsRef<[u8]>,
+{
+    static DUMMY_HASH: Lazy<String> = Lazy::new(|| {
+        hash_password("grapevine-dummy-password")
+            .expect("hashing a constant password should work")
+            .as_str()
+            .to_owned()
+    });
+
+    verify_password(&*DUMMY_HASH, password);
+}
+
 #[tracing::instrument(skip(keys))]
 pub(crate) fn calculate_hash(keys: &[&[u8]]) -> Vec<u8> {
     // We only hash the pdu's event ids, not the whole pdu
@@ -0,0 +1,226 @@
+//! Resolving the IP address of clients behind trusted reverse proxies
+
+use std::net::{IpAddr, SocketAddr};
+
+use axum::extract::ConnectInfo;
+use http::{header, HeaderMap};
+use ipnet::IpNet;
+
+use crate::services;
+
+/// The IP address of the client that sent a request, stored in the request
+/// extensions by [`client_ip_layer`]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ClientIp(pub(crate) IpAddr);
+
+/// Resolves the IP address of the client and stores it as [`ClientIp`] in the
+/// request extensions
+///
+/// The `X-Forwarded-For` and `Forwarded` headers are only used if the peer is
+/// one of the configured trusted proxies.
+pub(crate) async fn client_ip_layer(
+    mut req: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    if let Some(ConnectInfo(peer)) =
+        req.extensions().get::<ConnectInfo<SocketAddr>>()
+    {
+        // IPv4 clients of dual-stack listeners show up as mapped IPv6
+        // addresses
+        let ip = resolve(
+            peer.ip().to_canonical(),
+            req.headers(),
+            &services().globals.config.trusted_proxies,
+        );
+        req.extensions_mut().insert(ClientIp(ip));
+    }
+
+    next.run(req).await
+}
+
+/// Walks the chain of forwarding addresses from the peer towards the client
+/// and returns the first address that isn't a trusted proxy
+///
+/// The headers are only used if `peer` is trusted. The walk stops at the last
+/// trusted address if an address in the chain can't be parsed, since anything
+/// before it can't be attributed to a trusted proxy.
+fn resolve(peer: IpAddr, headers: &HeaderMap, trusted: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+
+    if !is_trusted(&peer) {
+        return peer;
+    }
+
+    let mut chain = forwarded_for(headers);
+    if chain.is_empty() {
+        chain = x_forwarded_for(headers);
+    }
+
+    let mut client = peer;
+    for ip in chain.into_iter().rev() {
+        let Some(ip) = ip else {
+            break;
+        };
+        client = ip.to_canonical();
+        if !is_trusted(&client) {
+            break;
+        }
+    }
+
+    client
+}
+
+/// Parses the `for` parameters of all `Forwarded` headers, see RFC 7239
+///
+/// Elements without a `for` parameter or with one that isn't an address are
+/// `None`.
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all(header::FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.trim().eq_ignore_ascii_case("for").then_some(value)
+            })
+        })
+        .map(|node| node.and_then(parse_node))
+        .collect()
+}
+
+/// Parses the addresses of all `X-Forwarded-For` headers
+///
+/// Entries that aren't addresses are `None`.
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(parse_node)
+        .collect()
+}
+
+/// Parses a single forwarding address, which may be quoted, have a port or be
+/// an IPv6 address in brackets
+///
+/// Obfuscated identifiers like `unknown` aren't addresses.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            node.strip_prefix('[')
+                .and_then(|node| node.strip_suffix(']'))
+                .and_then(|node| node.parse().ok())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use http::{HeaderMap, HeaderValue};
+    use ipnet::IpNet;
+
+    use super::resolve;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn header_map(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, HeaderValue::from_static(*value));
+        }
+        map
+    }
+
+    fn trusted() -> Vec<IpNet> {
+        vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()]
+    }
+
+    #[test]
+    fn untrusted_peers_are_used_as_is() {
+        let headers = header_map(&[
+            ("x-forwarded-for", "203.0.113.1"),
+            ("forwarded", "for=203.0.113.2"),
+        ]);
+
+        assert_eq!(
+            resolve(ip("198.51.100.1"), &headers, &trusted()),
+            ip("198.51.100.1")
+        );
+        assert_eq!(
+            resolve(ip("198.51.100.1"), &headers, &[]),
+            ip("198.51.100.1")
+        );
+    }
+
+    #[test]
+    fn trusted_proxies_are_skipped() {
+        let headers = header_map(&[(
+            "x-forwarded-for",
+            "203.0.113.9, 203.0.113.1, 10.0.0.2",
+        )]);
+
+        // The spoofed address the client sent itself is never reached
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &headers, &trusted()),
+            ip("203.0.113.1")
+        );
+    }
+
+    #[test]
+    fn forwarded_takes_precedence() {
+        let headers = header_map(&[
+            ("x-forwarded-for", "203.0.113.1"),
+            (
+                "forwarded",
+                "for=\"[2001:db8::1]:4711\";proto=https, for=10.0.0.2",
+            ),
+        ]);
+
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &headers, &trusted()),
+            ip("2001:db8::1")
+        );
+    }
+
+    #[test]
+    fn unparseable_entries_stop_the_walk() {
+        let headers = header_map(&[(
+            "x-forwarded-for",
+            "203.0.113.9, unknown, 10.0.0.2",
+        )]);
+
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &headers, &trusted()),
+            ip("10.0.0.2")
+        );
+
+        let headers =
+            header_map(&[("forwarded", "for=203.0.113.9, by=10.0.0.3")]);
+
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &headers, &trusted()),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn mapped_addresses_are_canonicalized() {
+        let headers =
+            header_map(&[("x-forwarded-for", "203.0.113.1, ::ffff:10.0.0.2")]);
+
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &headers, &trusted()),
+            ip("203.0.113.1")
+        );
+    }
+}
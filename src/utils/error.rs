@@ -69,6 +69,11 @@ pub(crate) enum Error {
     Uiaa(UiaaInfo),
     #[error("{0}: {1}")]
     BadRequest(ErrorKind, &'static str),
+    #[error(
+        "{}: Request body is larger than the limit of {0} bytes",
+        ErrorKind::TooLarge
+    )]
+    TooLarge(u32),
     // This is only needed for when a room alias already exists
     #[error("{0}")]
     Conflict(&'static str),
@@ -82,6 +87,12 @@ pub(crate) enum Error {
     Redaction(OwnedServerName, ruma::canonical_json::RedactionError),
     #[error("{0} in {1}")]
     InconsistentRoomState(&'static str, ruma::OwnedRoomId),
+    #[error(
+        "{}: You need to join one of these rooms first: {}",
+        ErrorKind::forbidden(),
+        .0.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+    )]
+    RestrictedJoin(Vec<ruma::OwnedRoomId>),
 }
 
 impl Error {
@@ -97,10 +108,11 @@ impl Error {
 
     pub(crate) fn to_response(&self) -> Ra<UiaaResponse> {
         use ErrorKind::{
-            Forbidden, GuestAccessForbidden, LimitExceeded, MissingToken,
-            NotFound, NotYetUploaded, ThreepidAuthFailed, ThreepidDenied,
-            TooLarge, Unauthorized, Unknown, UnknownToken, Unrecognized,
-            UserDeactivated, WrongRoomKeysVersion,
+            BadStatus, ConnectionFailed, ConnectionTimeout, Forbidden,
+            GuestAccessForbidden, LimitExceeded, MissingToken, NotFound,
+            NotYetUploaded, ThreepidAuthFailed, ThreepidDenied, TooLarge,
+            Unauthorized, Unknown, UnknownToken, Unrecognized, UserDeactivated,
+            WrongRoomKeysVersion,
         };
 
         if let Self::Uiaa(uiaainfo) = self {
@@ -142,10 +154,20 @@ impl Error {
                         ..
                     } => StatusCode::TOO_MANY_REQUESTS,
                     TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
-                    NotYetUploaded => StatusCode::GATEWAY_TIMEOUT,
+                    NotYetUploaded | ConnectionTimeout => {
+                        StatusCode::GATEWAY_TIMEOUT
+                    }
+                    ConnectionFailed
+                    | BadStatus {
+                        ..
+                    } => StatusCode::BAD_GATEWAY,
                     _ => StatusCode::BAD_REQUEST,
                 },
             ),
+            Self::TooLarge(_) => (TooLarge, StatusCode::PAYLOAD_TOO_LARGE),
+            Self::RestrictedJoin(_) => {
+                (ErrorKind::forbidden(), StatusCode::FORBIDDEN)
+            }
             Self::Conflict(_) => (Unknown, StatusCode::CONFLICT),
             _ => (Unknown, StatusCode::INTERNAL_SERVER_ERROR),
         };
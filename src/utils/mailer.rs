@@ -0,0 +1,61 @@
+//! Sending emails via the SMTP server from the config
+
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use tracing::warn;
+
+use crate::{config::SmtpConfig, Error, Result};
+
+/// Sends plain text emails from the configured sender address
+pub(crate) struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    /// Sender of all emails
+    from: Mailbox,
+}
+
+impl Mailer {
+    /// Sets up the SMTP transport, without connecting to the server yet
+    pub(crate) fn new(config: &SmtpConfig) -> Result<Self> {
+        let transport =
+            AsyncSmtpTransport::<Tokio1Executor>::from_url(&config.url)
+                .map_err(|_| Error::bad_config("Invalid SMTP URL."))?
+                .build();
+
+        let from = config.from.parse().map_err(|_| {
+            Error::bad_config("Invalid sender address of emails in smtp.from.")
+        })?;
+
+        Ok(Self {
+            transport,
+            from,
+        })
+    }
+
+    /// Sends a plain text email to `to`
+    pub(crate) async fn send(
+        &self,
+        to: Mailbox,
+        subject: String,
+        text: String,
+    ) -> Result<()> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(text)
+            .map_err(|error| {
+                warn!(%error, "Failed to build email");
+                Error::BadServerResponse("Failed to build email.")
+            })?;
+
+        self.transport.send(message).await.map_err(|error| {
+            warn!(%error, "Failed to send email");
+            Error::BadServerResponse("Failed to send email.")
+        })?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,344 @@
+//! Token bucket rate limiting for client requests
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use ruma::{
+    api::client::error::{ErrorKind, RetryAfter},
+    OwnedUserId,
+};
+use strum::IntoStaticStr;
+
+use crate::{
+    config::{RateLimitBucketConfig, RateLimitingConfig},
+    observability::METRICS,
+    Error, Result,
+};
+
+/// How often buckets that are full again are forgotten
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Groups of endpoints that are limited independently of each other
+// Keep variants sorted
+#[derive(Clone, Copy, Debug, IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum RateLimitClass {
+    /// Any client endpoint not covered by another class
+    Client,
+    /// `POST /login`
+    Login,
+    /// Uploading media
+    MediaUpload,
+    /// `POST /register`
+    Registration,
+}
+
+impl RateLimitClass {
+    /// Picks the class of a request from its matched route
+    pub(crate) fn from_path(path: &str) -> Self {
+        if path.ends_with("/login") {
+            Self::Login
+        } else if path.ends_with("/register") {
+            Self::Registration
+        } else if path.starts_with("/_matrix/media/")
+            && path.contains("/upload")
+        {
+            Self::MediaUpload
+        } else {
+            Self::Client
+        }
+    }
+}
+
+/// Who a request is accounted to
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum RateLimitKey {
+    /// An authenticated user
+    User(OwnedUserId),
+    /// The remote address of an unauthenticated request
+    Ip(IpAddr),
+}
+
+/// State of a single token bucket
+struct Bucket {
+    /// Number of requests that can be made right now
+    tokens: f64,
+    /// When `tokens` was last updated
+    updated: Instant,
+}
+
+/// Buckets of one [`RateLimitClass`]
+struct Buckets {
+    /// Buckets that are not full
+    buckets: HashMap<RateLimitKey, Bucket>,
+    /// When full buckets were last removed from `buckets`
+    pruned: Instant,
+}
+
+/// Map of token buckets sharing the same limits
+struct RateLimiter {
+    /// The class this limiter is used for, used for metrics
+    class: RateLimitClass,
+    /// Number of tokens added to each bucket per second
+    per_second: f64,
+    /// Maximum number of tokens in each bucket
+    burst: f64,
+    /// The actual buckets
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter with the limits from the config
+    fn new(class: RateLimitClass, config: RateLimitBucketConfig) -> Self {
+        Self {
+            class,
+            per_second: config.per_second,
+            burst: f64::from(config.burst),
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token from the bucket of `key` at `now`
+    ///
+    /// Returns how long to wait until the next request is allowed if the
+    /// bucket is empty.
+    fn check(&self, key: RateLimitKey, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if now.duration_since(buckets.pruned) > PRUNE_INTERVAL {
+            let (per_second, burst) = (self.per_second, self.burst);
+            buckets.buckets.retain(|_, bucket| {
+                bucket.tokens
+                    + now.duration_since(bucket.updated).as_secs_f64()
+                        * per_second
+                    < burst
+            });
+            buckets.pruned = now;
+        }
+
+        let bucket = buckets.buckets.entry(key).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+
+        bucket.tokens = (bucket.tokens
+            + now.duration_since(bucket.updated).as_secs_f64()
+                * self.per_second)
+            .min(self.burst);
+        bucket.updated = now;
+
+        let result = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::try_from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_second,
+            )
+            .unwrap_or(Duration::MAX))
+        };
+
+        METRICS.record_rate_limiter_buckets(
+            self.class.into(),
+            buckets.buckets.len(),
+        );
+
+        result
+    }
+}
+
+/// Rate limiters for all [`RateLimitClass`]es
+pub(crate) struct ClientRateLimiters {
+    /// Whether rate limiting is enabled at all
+    enable: bool,
+    /// Limiter for [`RateLimitClass::Client`]
+    client: RateLimiter,
+    /// Limiter for [`RateLimitClass::Login`]
+    login: RateLimiter,
+    /// Limiter for [`RateLimitClass::MediaUpload`]
+    media_upload: RateLimiter,
+    /// Limiter for [`RateLimitClass::Registration`]
+    registration: RateLimiter,
+}
+
+impl ClientRateLimiters {
+    /// Creates the rate limiters with the limits from the config
+    pub(crate) fn new(config: &RateLimitingConfig) -> Self {
+        Self {
+            enable: config.enable,
+            client: RateLimiter::new(RateLimitClass::Client, config.client),
+            login: RateLimiter::new(RateLimitClass::Login, config.login),
+            media_upload: RateLimiter::new(
+                RateLimitClass::MediaUpload,
+                config.media_upload,
+            ),
+            registration: RateLimiter::new(
+                RateLimitClass::Registration,
+                config.registration,
+            ),
+        }
+    }
+
+    /// Accounts a request of `key` to the bucket of `class`
+    ///
+    /// Fails with `M_LIMIT_EXCEEDED` if the bucket is empty.
+    pub(crate) fn check(
+        &self,
+        class: RateLimitClass,
+        key: RateLimitKey,
+    ) -> Result<()> {
+        if !self.enable {
+            return Ok(());
+        }
+
+        let limiter = match class {
+            RateLimitClass::Client => &self.client,
+            RateLimitClass::Login => &self.login,
+            RateLimitClass::MediaUpload => &self.media_upload,
+            RateLimitClass::Registration => &self.registration,
+        };
+
+        limiter.check(key, Instant::now()).map_err(|retry_after| {
+            METRICS.record_rate_limited(class.into());
+            Error::BadRequest(
+                ErrorKind::LimitExceeded {
+                    retry_after: Some(RetryAfter::Delay(retry_after)),
+                },
+                "Too many requests.",
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::{Duration, Instant},
+    };
+
+    use ruma::user_id;
+
+    use super::{RateLimitClass, RateLimitKey, RateLimiter, PRUNE_INTERVAL};
+    use crate::config::RateLimitBucketConfig;
+
+    fn limiter(per_second: f64, burst: u32) -> RateLimiter {
+        RateLimiter::new(
+            RateLimitClass::Client,
+            RateLimitBucketConfig {
+                per_second,
+                burst,
+            },
+        )
+    }
+
+    fn alice() -> RateLimitKey {
+        RateLimitKey::User(user_id!("@alice:example.com").to_owned())
+    }
+
+    fn ip() -> RateLimitKey {
+        RateLimitKey::Ip(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)))
+    }
+
+    #[test]
+    fn burst_is_allowed_at_once() {
+        let limiter = limiter(1.0, 3);
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check(alice(), start), Ok(()));
+        }
+        assert_eq!(limiter.check(alice(), start), Err(Duration::from_secs(1)));
+
+        // Other keys have their own bucket
+        assert_eq!(limiter.check(ip(), start), Ok(()));
+    }
+
+    #[test]
+    fn tokens_are_refilled_over_time() {
+        let limiter = limiter(2.0, 2);
+        let start = Instant::now();
+
+        assert_eq!(limiter.check(alice(), start), Ok(()));
+        assert_eq!(limiter.check(alice(), start), Ok(()));
+        assert_eq!(
+            limiter.check(alice(), start),
+            Err(Duration::from_millis(500))
+        );
+
+        // Half a token was added
+        let later = start + Duration::from_millis(250);
+        assert_eq!(
+            limiter.check(alice(), later),
+            Err(Duration::from_millis(250))
+        );
+
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.check(alice(), later), Ok(()));
+        assert!(limiter.check(alice(), later).is_err());
+    }
+
+    #[test]
+    fn tokens_are_capped_at_burst() {
+        let limiter = limiter(1.0, 3);
+        let start = Instant::now();
+        assert_eq!(limiter.check(alice(), start), Ok(()));
+
+        let later = start + Duration::from_secs(3600);
+        for _ in 0..3 {
+            assert_eq!(limiter.check(alice(), later), Ok(()));
+        }
+        assert_eq!(limiter.check(alice(), later), Err(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn full_buckets_are_pruned() {
+        let limiter = limiter(0.01, 2);
+        let start = Instant::now();
+
+        // Refilled after 100 seconds
+        assert_eq!(limiter.check(alice(), start), Ok(()));
+        // Still not full after 200 seconds
+        assert_eq!(limiter.check(ip(), start), Ok(()));
+        assert_eq!(limiter.check(ip(), start), Ok(()));
+
+        let later = start + PRUNE_INTERVAL + Duration::from_secs(60);
+        assert_eq!(
+            limiter.check(
+                RateLimitKey::User(user_id!("@bob:example.com").to_owned()),
+                later,
+            ),
+            Ok(()),
+        );
+
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.buckets.len(), 2);
+        assert!(!buckets.buckets.contains_key(&alice()));
+        assert!(buckets.buckets.contains_key(&ip()));
+        assert_eq!(buckets.pruned, later);
+    }
+
+    #[test]
+    fn requests_are_classified_by_path() {
+        for (path, class) in [
+            ("/_matrix/client/v3/login", "login"),
+            ("/_matrix/client/r0/login", "login"),
+            ("/_matrix/client/v3/register", "registration"),
+            ("/_matrix/client/v3/register/available", "client"),
+            ("/_matrix/client/v3/login/sso/redirect", "client"),
+            ("/_matrix/media/v3/upload", "media_upload"),
+            ("/_matrix/media/r0/upload", "media_upload"),
+            ("/_matrix/media/v3/download/:server_name/:media_id", "client"),
+            ("/_matrix/client/v3/sync", "client"),
+        ] {
+            let actual: &str = RateLimitClass::from_path(path).into();
+            assert_eq!(actual, class, "{path}");
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Loading TLS certificates and picking them by the SNI hostname
+
+use std::{collections::HashMap, io, sync::Arc};
+
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, PrivateKey, ServerConfig,
+};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info};
+
+use crate::{config::TlsConfig, error};
+
+/// Picks the certificate for the hostname a client requested via SNI, falling
+/// back to the default certificate
+struct SniResolver {
+    /// Certificates by lowercase hostname
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    /// Certificate used if the client didn't send a known hostname
+    default: Arc<CertifiedKey>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(
+        &self,
+        client_hello: ClientHello<'_>,
+    ) -> Option<Arc<CertifiedKey>> {
+        let cert = client_hello
+            .server_name()
+            .and_then(|name| self.by_name.get(&name.to_ascii_lowercase()))
+            .unwrap_or(&self.default);
+
+        Some(Arc::clone(cert))
+    }
+}
+
+/// Loads all certificates from the config into a rustls config
+pub(crate) async fn load(
+    config: &TlsConfig,
+) -> Result<RustlsConfig, error::Serve> {
+    Ok(RustlsConfig::from_config(Arc::new(server_config(config).await?)))
+}
+
+/// Reloads all certificates from the config whenever `SIGHUP` is received
+///
+/// The old certificates are kept if loading the new ones fails.
+#[cfg(unix)]
+pub(crate) async fn reload_on_sighup(
+    rustls_config: RustlsConfig,
+    config: &'static TlsConfig,
+) {
+    let mut hangup =
+        signal(SignalKind::hangup()).expect("failed to install signal handler");
+
+    while hangup.recv().await.is_some() {
+        match server_config(config).await {
+            Ok(server_config) => {
+                rustls_config.reload_from_config(Arc::new(server_config));
+                info!("Reloaded TLS certificates");
+            }
+            Err(error) => {
+                error!(
+                    error = %error::DisplayWithSources {
+                        error: &error,
+                        infix: ": ",
+                    },
+                    "Failed to reload TLS certificates"
+                );
+            }
+        }
+    }
+}
+
+/// Builds a rustls config selecting between all configured certificates
+async fn server_config(
+    config: &TlsConfig,
+) -> Result<ServerConfig, error::Serve> {
+    let default = load_cert(&config.certs, &config.key).await?;
+
+    let mut by_name = HashMap::new();
+    for sni in &config.sni {
+        let cert = load_cert(&sni.certs, &sni.key).await?;
+        for name in &sni.server_names {
+            by_name.insert(name.to_ascii_lowercase(), Arc::clone(&cert));
+        }
+    }
+
+    let mut server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(SniResolver {
+            by_name,
+            default,
+        }));
+    // Same as what `RustlsConfig::from_pem_file` uses
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(server_config)
+}
+
+/// Reads a certificate chain and its private key from PEM files
+async fn load_cert(
+    certs: &str,
+    key: &str,
+) -> Result<Arc<CertifiedKey>, error::Serve> {
+    read_cert(certs, key).await.map(Arc::new).map_err(|err| {
+        error::Serve::LoadCerts {
+            certs: certs.to_owned(),
+            key: key.to_owned(),
+            err,
+        }
+    })
+}
+
+/// Reads and parses the PEM files of [`load_cert`]
+async fn read_cert(certs: &str, key: &str) -> io::Result<CertifiedKey> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let certs = tokio::fs::read(certs).await?;
+    let key = tokio::fs::read(key).await?;
+
+    let chain = rustls_pemfile::certs(&mut certs.as_slice())
+        .map(|cert| cert.map(|cert| Certificate(cert.to_vec())))
+        .collect::<io::Result<Vec<_>>>()?;
+    if chain.is_empty() {
+        return Err(invalid("no certificates found"));
+    }
+
+    let key = rustls_pemfile::private_key(&mut key.as_slice())?
+        .ok_or_else(|| invalid("no private key found"))?;
+    let key = sign::any_supported_type(&PrivateKey(key.secret_der().to_vec()))
+        .map_err(|_| invalid("unsupported private key type"))?;
+
+    Ok(CertifiedKey::new(chain, key))
+}